@@ -0,0 +1,61 @@
+//! Token the host must send with its requests, when it set one.
+//!
+//! An agent run as a host process listens on a loopback port that the code of
+//! every job of the host can reach. With `AGENT_AUTH_TOKEN`, requests other than
+//! `GET /health` must carry `Authorization: Bearer {token}`. The variable is not
+//! passed on to the code, and the agent is made non-dumpable so that the code,
+//! running as the same user, cannot read it in `/proc`.
+
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+pub const TOKEN_ENV: &str = "AGENT_AUTH_TOKEN";
+
+/// The token of `AGENT_AUTH_TOKEN`, `None` in VMs where the host sets none.
+pub fn token() -> Option<Arc<str>> {
+    let token = std::env::var(TOKEN_ENV).ok().filter(|t| !t.is_empty())?;
+    // SAFETY: prctl takes no pointers with PR_SET_DUMPABLE.
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } < 0 {
+        tracing::warn!(
+            "Cannot make the agent non-dumpable: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Some(token.into())
+}
+
+/// Rejects the requests without the token with `401`.
+pub async fn require(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match presented {
+        Some(presented) if same(presented.as_bytes(), token.as_bytes()) => next.run(request).await,
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+/// Compares in a time that does not depend on where `a` and `b` differ.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same() {
+        assert!(same(b"token", b"token"));
+        assert!(!same(b"token", b"tokem"));
+        assert!(!same(b"token", b"token2"));
+        assert!(!same(b"", b"token"));
+    }
+}
@@ -0,0 +1,16 @@
+#!/bin/sh
+
+mount -t proc proc /proc
+mount -t sysfs sysfs /sys
+mount -t devtmpfs dev /dev
+
+export PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin
+
+cd /lambda
+echo '--- PROGRAM OUTPUT ---'
+node /lambda/code.js
+EXIT_CODE=$?
+echo '--- END OUTPUT ---'
+echo "Exit code: $EXIT_CODE"
+
+poweroff -f 2>/dev/null || exit $EXIT_CODE
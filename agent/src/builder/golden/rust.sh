@@ -0,0 +1,24 @@
+#!/bin/sh
+
+mount -t proc proc /proc
+mount -t sysfs sysfs /sys
+mount -t devtmpfs dev /dev
+
+export PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin
+
+cd /lambda
+rustc -o /lambda/bin /lambda/code.rs
+COMPILE_EXIT=$?
+if [ $COMPILE_EXIT -ne 0 ]; then
+  echo '--- PROGRAM OUTPUT ---'
+  echo '--- END OUTPUT ---'
+  echo "Exit code: $COMPILE_EXIT"
+  poweroff -f 2>/dev/null || exit $COMPILE_EXIT
+fi
+echo '--- PROGRAM OUTPUT ---'
+/lambda/bin
+EXIT_CODE=$?
+echo '--- END OUTPUT ---'
+echo "Exit code: $EXIT_CODE"
+
+poweroff -f 2>/dev/null || exit $EXIT_CODE
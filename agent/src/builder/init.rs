@@ -1,30 +1,174 @@
 use crate::runtimes::LanguageRuntime;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// `PATH` of the script, where the images keep their toolchains.
+const PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+/// Cgroup the job runs in when it has limits.
+const JOB_CGROUP: &str = "/sys/fs/cgroup/job";
+
+/// A filesystem the script mounts before anything else.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    pub fstype: String,
+    pub source: String,
+    pub target: PathBuf,
+    /// Passed to `mount -o`.
+    pub options: Option<String>,
+}
+
+impl Mount {
+    pub fn new(fstype: &str, source: &str, target: impl Into<PathBuf>) -> Self {
+        Self {
+            fstype: fstype.to_string(),
+            source: source.to_string(),
+            target: target.into(),
+            options: None,
+        }
+    }
+
+    pub fn options(mut self, options: &str) -> Self {
+        self.options = Some(options.to_string());
+        self
+    }
+
+    /// proc, sysfs and devtmpfs, which every runtime needs.
+    pub fn defaults() -> Vec<Mount> {
+        vec![
+            Mount::new("proc", "proc", "/proc"),
+            Mount::new("sysfs", "sysfs", "/sys"),
+            Mount::new("devtmpfs", "dev", "/dev"),
+        ]
+    }
+}
+
+/// Limits of the cgroup v2 the job runs in, inside the guest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CgroupLimits {
+    /// `memory.max`, in bytes.
+    pub memory_max_bytes: Option<u64>,
+    /// `pids.max`.
+    pub pids_max: Option<u32>,
+    /// `cpu.max`: microseconds of CPU time per period of 100 ms.
+    pub cpu_max_us: Option<u32>,
+}
+
+impl CgroupLimits {
+    /// Controllers to enable for the limits, and the files to write them to.
+    fn settings(&self) -> Vec<(&'static str, &'static str, String)> {
+        let mut settings = Vec::new();
+        if let Some(bytes) = self.memory_max_bytes {
+            settings.push(("memory", "memory.max", bytes.to_string()));
+        }
+        if let Some(pids) = self.pids_max {
+            settings.push(("pids", "pids.max", pids.to_string()));
+        }
+        if let Some(quota) = self.cpu_max_us {
+            settings.push(("cpu", "cpu.max", format!("{} 100000", quota)));
+        }
+        settings
+    }
+}
 
 /// Generates the `/init` shell script that runs as PID 1 inside the VM.
 ///
-/// The script mounts the essential pseudo-filesystems (proc, sysfs, devtmpfs),
-/// runs the user's code, and shuts the VM down cleanly via `poweroff -f`.
-/// Output is wrapped between `--- PROGRAM OUTPUT ---` / `--- END OUTPUT ---`
-/// markers so the agent can reliably extract it from the serial console stream.
-pub struct InitScriptGenerator;
+/// The script mounts the pseudo-filesystems, runs the user's code, and shuts the
+/// VM down cleanly via `poweroff -f`. Output is wrapped between
+/// `--- PROGRAM OUTPUT ---` / `--- END OUTPUT ---` markers so the agent can
+/// reliably extract it from the serial console stream. Every path and argument
+/// is quoted for the shell.
+pub struct InitScriptGenerator<'a> {
+    runtime: &'a dyn LanguageRuntime,
+    source_path: PathBuf,
+    work_dir: PathBuf,
+    mounts: Vec<Mount>,
+    cgroup: Option<CgroupLimits>,
+}
+
+impl<'a> InitScriptGenerator<'a> {
+    /// Script running the source file at `source_path`, in the directory it is in.
+    pub fn new(runtime: &'a dyn LanguageRuntime, source_path: impl Into<PathBuf>) -> Self {
+        let source_path = source_path.into();
+        let work_dir = source_path.parent().unwrap_or(Path::new("/")).to_path_buf();
+        Self {
+            runtime,
+            source_path,
+            work_dir,
+            mounts: Mount::defaults(),
+            cgroup: None,
+        }
+    }
+
+    /// Mount these filesystems rather than the defaults.
+    pub fn mounts(mut self, mounts: Vec<Mount>) -> Self {
+        self.mounts = mounts;
+        self
+    }
+
+    /// Run the compile and run steps in a cgroup with these limits. Needs a
+    /// kernel with cgroup v2 and the controllers of the limits.
+    pub fn cgroup_limits(mut self, limits: CgroupLimits) -> Self {
+        self.cgroup = Some(limits);
+        self
+    }
 
-impl InitScriptGenerator {
     /// Build the init script for a given runtime and source file path.
-    ///
+    pub fn generate_script(runtime: &dyn LanguageRuntime, code_path: &str) -> String {
+        InitScriptGenerator::new(runtime, code_path).generate()
+    }
+
     /// For compiled languages, a compile step runs first — if it fails the VM
     /// exits immediately without printing misleading output markers.
-    pub fn generate_script(runtime: &dyn LanguageRuntime, code_path: &str) -> String {
+    pub fn generate(&self) -> String {
         let mut script = String::from("#!/bin/sh\n\n");
 
-        script.push_str("mount -t proc proc /proc\n");
-        script.push_str("mount -t sysfs sysfs /sys\n");
-        script.push_str("mount -t devtmpfs dev /dev\n\n");
-        script.push_str(
-            "export PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin\n\n",
-        );
+        for mount in &self.mounts {
+            let options = match &mount.options {
+                Some(options) => format!(" -o {}", shell_quote(options)),
+                None => String::new(),
+            };
+            let _ = writeln!(
+                script,
+                "mount -t {}{} {} {}",
+                shell_quote(&mount.fstype),
+                options,
+                shell_quote(&mount.source),
+                quote_path(&mount.target)
+            );
+        }
+        let _ = writeln!(script, "\nexport PATH={}\n", PATH);
+
+        if let Some(settings) = self.cgroup.as_ref().map(CgroupLimits::settings) {
+            let controllers: Vec<String> = settings
+                .iter()
+                .map(|(name, _, _)| format!("+{}", name))
+                .collect();
+            script.push_str("mkdir -p /sys/fs/cgroup\n");
+            script.push_str("mount -t cgroup2 cgroup2 /sys/fs/cgroup\n");
+            let _ = writeln!(script, "mkdir -p {}", JOB_CGROUP);
+            if !controllers.is_empty() {
+                let _ = writeln!(
+                    script,
+                    "echo {} > /sys/fs/cgroup/cgroup.subtree_control",
+                    shell_quote(&controllers.join(" "))
+                );
+            }
+            for (_, file, value) in &settings {
+                let _ = writeln!(
+                    script,
+                    "echo {} > {}/{}",
+                    shell_quote(value),
+                    JOB_CGROUP,
+                    file
+                );
+            }
+            // The steps are children of the script and start in its cgroup.
+            let _ = writeln!(script, "echo $$ > {}/cgroup.procs\n", JOB_CGROUP);
+        }
 
-        if let Some(compile_cmd) = runtime.compile_command() {
-            script.push_str(&format!("{}\n", compile_cmd));
+        let _ = writeln!(script, "cd {}", quote_path(&self.work_dir));
+        if let Some(step) = self.runtime.compile_step(&self.source_path, &self.work_dir) {
+            let _ = writeln!(script, "{}", command_line(&step));
             script.push_str("COMPILE_EXIT=$?\n");
             script.push_str("if [ $COMPILE_EXIT -ne 0 ]; then\n");
             script.push_str("  echo '--- PROGRAM OUTPUT ---'\n");
@@ -35,14 +179,8 @@ impl InitScriptGenerator {
         }
 
         script.push_str("echo '--- PROGRAM OUTPUT ---'\n");
-
-        let run_cmd = if let Some(exec_path) = runtime.execute_path() {
-            exec_path.to_string()
-        } else {
-            format!("{} {}", runtime.run_command(), code_path)
-        };
-
-        script.push_str(&format!("{}\n", run_cmd));
+        let run = self.runtime.run_step(&self.source_path, &self.work_dir);
+        let _ = writeln!(script, "{}", command_line(&run));
         script.push_str("EXIT_CODE=$?\n");
         script.push_str("echo '--- END OUTPUT ---'\n");
         script.push_str("echo \"Exit code: $EXIT_CODE\"\n\n");
@@ -53,86 +191,104 @@ impl InitScriptGenerator {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::runtimes::{
-        c::CRuntime, cpp::CppRuntime, go::GoRuntime, java::JavaRuntime, node::NodeRuntime,
-        python::PythonRuntime, rust::RustRuntime,
-    };
+/// `program` and `args` as one shell command line.
+fn command_line((program, args): &(String, Vec<String>)) -> String {
+    std::iter::once(program)
+        .chain(args)
+        .map(|word| shell_quote(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-    // Python: interpreted runtime, direct run, no compile step
-    #[test]
-    fn test_python_script_generation() {
-        let runtime = PythonRuntime;
-        let script = InitScriptGenerator::generate_script(&runtime, "/lambda/code.py");
-        assert!(script.contains("python3 /lambda/code.py"));
-        assert!(script.contains("--- PROGRAM OUTPUT ---"));
-    }
+fn quote_path(path: &Path) -> String {
+    shell_quote(&path.to_string_lossy())
+}
 
-    // Node: same as Python, no compile step
-    #[test]
-    fn test_node_script_generation() {
-        let runtime = NodeRuntime;
-        let script = InitScriptGenerator::generate_script(&runtime, "/lambda/code.js");
-        assert!(script.contains("node /lambda/code.js"));
-        assert!(script.contains("--- PROGRAM OUTPUT ---"));
+/// `word` as a single word of a shell command. Words made of safe characters
+/// only are left as they are; the others are single-quoted, with each `'`
+/// closing the quotes, escaped, and reopening them.
+pub fn shell_quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-+./:=@%,".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        return word.to_string();
     }
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
 
-    // Rust: compiled runtime, checks compile exit code before running the binary
-    #[test]
-    fn test_rust_script_generation() {
-        let runtime = RustRuntime;
-        let script = InitScriptGenerator::generate_script(&runtime, "/lambda/code.rs");
-        assert!(script.contains("rustc -o /lambda/bin /lambda/code.rs"));
-        assert!(script.contains("COMPILE_EXIT=$?"));
-        assert!(script.contains("if [ $COMPILE_EXIT -ne 0 ]; then"));
-        assert!(script.contains("/lambda/bin"));
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtimes::runtime_from_language;
 
-    // C: compiled with gcc, same compile-guard pattern as Rust
+    // One golden file per runtime, in `golden/<language>.sh`. A change of the
+    // generator shows up as a diff of these files.
     #[test]
-    fn test_c_script_generation() {
-        let runtime = CRuntime;
-        let script = InitScriptGenerator::generate_script(&runtime, "/lambda/code.c");
-        assert!(script.contains("gcc -o /lambda/bin /lambda/code.c"));
-        assert!(script.contains("COMPILE_EXIT=$?"));
-        assert!(script.contains("if [ $COMPILE_EXIT -ne 0 ]; then"));
-        assert!(script.contains("/lambda/bin"));
+    fn test_golden_scripts() {
+        let goldens = [
+            ("python", "py", include_str!("golden/python.sh")),
+            ("node", "js", include_str!("golden/node.sh")),
+            ("rust", "rs", include_str!("golden/rust.sh")),
+            ("c", "c", include_str!("golden/c.sh")),
+            ("cpp", "cpp", include_str!("golden/cpp.sh")),
+            ("go", "go", include_str!("golden/go.sh")),
+            ("java", "java", include_str!("golden/java.sh")),
+        ];
+        for (language, extension, golden) in goldens {
+            let runtime = runtime_from_language(language).unwrap();
+            let script = InitScriptGenerator::generate_script(
+                runtime.as_ref(),
+                &format!("/lambda/code.{}", extension),
+            );
+            assert_eq!(script, golden, "golden/{}.sh", language);
+        }
     }
 
-    // C++: compiled with g++, same compile-guard pattern as C
     #[test]
-    fn test_cpp_script_generation() {
-        let runtime = CppRuntime;
-        let script = InitScriptGenerator::generate_script(&runtime, "/lambda/code.cpp");
-        assert!(script.contains("g++ -o /lambda/bin /lambda/code.cpp"));
-        assert!(script.contains("COMPILE_EXIT=$?"));
-        assert!(script.contains("if [ $COMPILE_EXIT -ne 0 ]; then"));
-        assert!(script.contains("/lambda/bin"));
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/lambda/code.rs"), "/lambda/code.rs");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("my code.rs"), "'my code.rs'");
+        assert_eq!(shell_quote("$(reboot)"), "'$(reboot)'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
     }
 
-    // Go: compiled with `go build`, runs the resulting binary
+    // Paths come from the outside: they must stay single words, whatever they hold.
     #[test]
-    fn test_go_script_generation() {
-        let runtime = GoRuntime;
-        let script = InitScriptGenerator::generate_script(&runtime, "/lambda/code.go");
-        assert!(script.contains("go build -o /lambda/bin /lambda/code.go"));
-        assert!(script.contains("COMPILE_EXIT=$?"));
-        assert!(script.contains("if [ $COMPILE_EXIT -ne 0 ]; then"));
-        assert!(script.contains("/lambda/bin"));
+    fn test_paths_are_quoted() {
+        let runtime = runtime_from_language("rust").unwrap();
+        let script =
+            InitScriptGenerator::generate_script(runtime.as_ref(), "/lambda/it's $HOME/code.rs");
+        assert!(script.contains(r"cd '/lambda/it'\''s $HOME'"));
+        assert!(
+            script
+                .contains(r"rustc -o '/lambda/it'\''s $HOME/bin' '/lambda/it'\''s $HOME/code.rs'")
+        );
     }
 
-    // Java: compile step renames the file, compiles with javac, packages a jar, runs with `java -jar`
     #[test]
-    fn test_java_script_generation() {
-        let runtime = JavaRuntime;
-        let script = InitScriptGenerator::generate_script(&runtime, "/lambda/code.java");
-        assert!(script.contains("mv /lambda/code.java /lambda/Main.java"));
-        assert!(script.contains("javac -d /lambda /lambda/Main.java"));
-        assert!(script.contains("jar cfe /lambda/bin.jar Main"));
-        assert!(script.contains("COMPILE_EXIT=$?"));
-        assert!(script.contains("if [ $COMPILE_EXIT -ne 0 ]; then"));
-        assert!(script.contains("java -jar /lambda/bin.jar"));
+    fn test_mounts_and_cgroup_limits() {
+        let runtime = runtime_from_language("python").unwrap();
+        let script = InitScriptGenerator::new(runtime.as_ref(), "/lambda/code.py")
+            .mounts(vec![
+                Mount::new("proc", "proc", "/proc"),
+                Mount::new("tmpfs", "tmpfs", "/tmp").options("size=64m,mode=1777"),
+            ])
+            .cgroup_limits(CgroupLimits {
+                memory_max_bytes: Some(256 << 20),
+                pids_max: Some(64),
+                cpu_max_us: None,
+            })
+            .generate();
+
+        assert!(script.contains("mount -t proc proc /proc\n"));
+        assert!(script.contains("mount -t tmpfs -o size=64m,mode=1777 tmpfs /tmp\n"));
+        assert!(!script.contains("/sys "));
+        assert!(script.contains("echo '+memory +pids' > /sys/fs/cgroup/cgroup.subtree_control\n"));
+        assert!(script.contains("echo 268435456 > /sys/fs/cgroup/job/memory.max\n"));
+        assert!(script.contains("echo 64 > /sys/fs/cgroup/job/pids.max\n"));
+        let joined = script
+            .find("echo $$ > /sys/fs/cgroup/job/cgroup.procs")
+            .unwrap();
+        assert!(joined < script.find("python3 /lambda/code.py").unwrap());
     }
 }
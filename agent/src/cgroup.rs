@@ -0,0 +1,228 @@
+//! Memory and process limits of a job inside the guest.
+//!
+//! The processes of a job run in a cgroup v2 of their own, so that a runaway
+//! allocation or a fork bomb hits the limits of the job rather than those of the
+//! guest, where the agent would go down with it. Once over its memory the whole
+//! job is killed at once, and the result of the job tells it was.
+
+use cloude_types::JobLimits;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, sleep};
+use tracing::warn;
+
+/// Where init mounts the cgroup v2 hierarchy.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Tries to remove the cgroup of a job, whose processes take a moment to die.
+const REMOVE_ATTEMPTS: u32 = 20;
+const REMOVE_INTERVAL: Duration = Duration::from_millis(25);
+
+/// Period `cpu.max` counts CPU time over, in microseconds.
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// The cgroup of a job, removed with [`JobCgroup::remove`].
+#[derive(Clone)]
+pub struct JobCgroup {
+    dir: PathBuf,
+    /// `cgroup.procs`, opened before the processes fork: moving one in from
+    /// between fork and exec only takes a write.
+    procs: Arc<File>,
+}
+
+impl JobCgroup {
+    /// Creates the cgroup of job `name` in the hierarchy at `root`, with the
+    /// controllers of `limits` enabled.
+    pub fn create(root: &Path, name: &str, limits: &JobLimits) -> io::Result<Self> {
+        let settings = settings(limits);
+        fs::write(root.join("cgroup.subtree_control"), controllers(&settings))?;
+
+        let dir = root.join(name);
+        fs::create_dir(&dir)?;
+        let configured = settings
+            .iter()
+            .try_for_each(|(_, file, value)| fs::write(dir.join(file), value));
+        let procs =
+            configured.and_then(|()| File::options().write(true).open(dir.join("cgroup.procs")));
+        match procs {
+            Ok(procs) => Ok(JobCgroup {
+                dir,
+                procs: Arc::new(procs),
+            }),
+            Err(e) => {
+                let _ = fs::remove_dir(&dir);
+                Err(e)
+            }
+        }
+    }
+
+    /// Makes `cmd` start in the cgroup, along with everything it forks.
+    pub fn enter(&self, cmd: &mut Command) {
+        let procs = Arc::clone(&self.procs);
+        // SAFETY: the closure runs between fork and exec and only makes a system call.
+        unsafe {
+            cmd.pre_exec(move || {
+                // "0" moves the writing process.
+                if libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1) != 1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Holds the job to `limits.cpu_millis` once its burst is over, in the
+    /// background. `None` without a burst, the cgroup holds it from the start.
+    /// The task is to be aborted when the job ends.
+    pub fn throttle_after_burst(&self, limits: &JobLimits) -> Option<JoinHandle<()>> {
+        let millis = limits.cpu_millis?;
+        let burst = limits.cpu_burst_ms.filter(|&ms| ms > 0)?;
+        let file = self.dir.join("cpu.max");
+        Some(tokio::spawn(async move {
+            sleep(Duration::from_millis(burst)).await;
+            if let Err(e) = fs::write(&file, cpu_max(Some(millis))) {
+                warn!("Cannot throttle {}: {}", file.display(), e);
+            }
+        }))
+    }
+
+    /// Whether the kernel killed processes of the job for going over its memory.
+    pub fn oom_killed(&self) -> bool {
+        fs::read_to_string(self.dir.join("memory.events"))
+            .map(|events| oom_kills(&events) > 0)
+            .unwrap_or(false)
+    }
+
+    /// Kills what the job left running, then removes the cgroup.
+    pub async fn remove(self) -> io::Result<()> {
+        // cgroup.kill needs Linux 5.14; without it, the next reset kills leftovers.
+        let _ = fs::write(self.dir.join("cgroup.kill"), "1");
+        let mut attempts = 0;
+        loop {
+            match fs::remove_dir(&self.dir) {
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) && attempts < REMOVE_ATTEMPTS => {
+                    attempts += 1;
+                    sleep(REMOVE_INTERVAL).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Controllers `limits` need, with the files setting them and their values.
+fn settings(limits: &JobLimits) -> Vec<(&'static str, &'static str, String)> {
+    let mut settings = Vec::new();
+    if let Some(memory_mb) = limits.memory_mb {
+        let bytes = memory_mb as u64 * 1024 * 1024;
+        settings.push(("memory", "memory.max", bytes.to_string()));
+        // The whole job goes, rather than whichever process the kernel picks.
+        settings.push(("memory", "memory.oom.group", "1".to_string()));
+    }
+    if let Some(pids) = limits.pids {
+        settings.push(("pids", "pids.max", pids.to_string()));
+    }
+    if let Some(millis) = limits.cpu_millis {
+        let burst = limits.cpu_burst_ms.is_some_and(|ms| ms > 0);
+        let value = cpu_max((!burst).then_some(millis));
+        settings.push(("cpu", "cpu.max", value));
+    }
+    settings
+}
+
+/// `cpu.max` line giving `millis` of CPU time per second, no limit for `None`.
+fn cpu_max(millis: Option<u32>) -> String {
+    match millis {
+        Some(millis) => format!(
+            "{} {}",
+            u64::from(millis) * CPU_PERIOD_US / 1000,
+            CPU_PERIOD_US
+        ),
+        None => format!("max {}", CPU_PERIOD_US),
+    }
+}
+
+/// `cgroup.subtree_control` line enabling the controllers of `settings`.
+fn controllers(settings: &[(&str, &str, String)]) -> String {
+    let mut controllers: Vec<String> = settings
+        .iter()
+        .map(|(controller, _, _)| format!("+{}", controller))
+        .collect();
+    controllers.dedup();
+    controllers.join(" ")
+}
+
+/// Count of `oom_kill` in the content of a `memory.events` file.
+fn oom_kills(events: &str) -> u64 {
+    events
+        .lines()
+        .filter_map(|line| line.strip_prefix("oom_kill "))
+        .find_map(|count| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oom_kills() {
+        let events = "low 0\nhigh 0\nmax 12\noom 1\noom_kill 3\noom_group_kill 1\n";
+        assert_eq!(oom_kills(events), 3);
+        assert_eq!(oom_kills("low 0\nmax 0\noom 0\noom_kill 0\n"), 0);
+        assert_eq!(oom_kills(""), 0);
+    }
+
+    #[test]
+    fn test_settings() {
+        let limits = JobLimits {
+            memory_mb: Some(256),
+            pids: Some(64),
+            ..JobLimits::default()
+        };
+        assert_eq!(controllers(&settings(&limits)), "+memory +pids");
+        assert_eq!(
+            settings(&limits),
+            vec![
+                ("memory", "memory.max", "268435456".to_string()),
+                ("memory", "memory.oom.group", "1".to_string()),
+                ("pids", "pids.max", "64".to_string()),
+            ]
+        );
+
+        let limits = JobLimits {
+            pids: Some(64),
+            ..JobLimits::default()
+        };
+        assert_eq!(controllers(&settings(&limits)), "+pids");
+    }
+
+    #[test]
+    fn test_cpu_settings() {
+        let limits = JobLimits {
+            cpu_millis: Some(500),
+            ..JobLimits::default()
+        };
+        assert_eq!(
+            settings(&limits),
+            vec![("cpu", "cpu.max", "50000 100000".to_string())]
+        );
+
+        // Unthrottled until the burst is over.
+        let limits = JobLimits {
+            cpu_millis: Some(2000),
+            cpu_burst_ms: Some(3000),
+            ..JobLimits::default()
+        };
+        assert_eq!(
+            settings(&limits),
+            vec![("cpu", "cpu.max", "max 100000".to_string())]
+        );
+        assert_eq!(cpu_max(Some(2000)), "200000 100000");
+    }
+}
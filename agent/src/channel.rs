@@ -0,0 +1,150 @@
+//! Byte streams between the code of jobs in two VMs, relayed by the host.
+//!
+//! VMs have no vsock device: the host opens a channel with `POST /channels/{id}`,
+//! which the agent upgrades to the stream of the channel, on the link the host
+//! reaches it on. The agent binds the socket `{CHANNEL_DIR}/{id}` in the root of
+//! the running job, and relays between the first connection to it and the host,
+//! which splices the streams of the two VMs of the channel.
+
+use cloude_types::CHANNEL_DIR;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UnixListener;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+/// Longest channel id, part of a socket path.
+const MAX_ID_LEN: usize = 64;
+
+/// Relays of the open channels.
+#[derive(Default)]
+pub struct Channels {
+    relays: Mutex<JoinSet<()>>,
+}
+
+impl Channels {
+    /// Binds the socket of channel `id` in `root`, the root of the running job,
+    /// then relays between the first connection to it and the stream `host`
+    /// resolves to, in the background.
+    pub fn open<S>(
+        &self,
+        root: &Path,
+        id: &str,
+        host: impl Future<Output = io::Result<S>> + Send + 'static,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if !is_valid_id(id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "channel ids are up to 64 letters, digits and dashes",
+            ));
+        }
+        let dir = crate::overlay::host_path(root, Path::new(CHANNEL_DIR));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(id);
+        let listener = UnixListener::bind(&path)?;
+
+        let id = id.to_string();
+        let mut relays = self.lock();
+        while relays.try_join_next().is_some() {}
+        relays.spawn(async move {
+            match relay(listener, &path, host).await {
+                Ok((sent, received)) => {
+                    info!(channel = %id, sent, received, "Channel closed")
+                }
+                Err(e) => warn!(channel = %id, "Channel failed: {}", e),
+            }
+            let _ = std::fs::remove_file(&path);
+        });
+        Ok(())
+    }
+
+    /// Ends the relays of every channel, before the next job: the code at their
+    /// end is gone.
+    pub fn close_all(&self) {
+        self.lock().abort_all();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, JoinSet<()>> {
+        self.relays.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Relays between the first connection to `listener`, bound at `path`, and
+/// `host`. Returns the bytes the code sent and received.
+async fn relay<S>(
+    listener: UnixListener,
+    path: &Path,
+    host: impl Future<Output = io::Result<S>>,
+) -> io::Result<(u64, u64)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut host = host.await?;
+    // What the other end sends before the code connects waits for it. A host
+    // that closes its end first closes the channel.
+    let mut early = vec![0u8; 8192];
+    let (mut code, early_len) = tokio::select! {
+        accepted = listener.accept() => (accepted?.0, 0),
+        read = host.read(&mut early) => match read? {
+            0 => return Ok((0, 0)),
+            n => (listener.accept().await?.0, n),
+        },
+    };
+    // One connection per channel: the socket is gone once it is taken.
+    drop(listener);
+    let _ = std::fs::remove_file(path);
+
+    code.write_all(&early[..early_len]).await?;
+    let (sent, received) = tokio::io::copy_bidirectional(&mut code, &mut host).await?;
+    Ok((sent, received + early_len as u64))
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn test_ids() {
+        assert!(is_valid_id("3f1c2d9e-7a4b-4c1e-9f2a-0b1c2d3e4f5a"));
+        assert!(!is_valid_id(""));
+        assert!(!is_valid_id("../escape"));
+        assert!(!is_valid_id(&"a".repeat(MAX_ID_LEN + 1)));
+    }
+
+    #[tokio::test]
+    async fn test_relay_between_code_and_host() {
+        let root = std::env::temp_dir().join(format!("cloude-channel-{}", std::process::id()));
+        let (host, mut backend) = tokio::io::duplex(64);
+        let channels = Channels::default();
+        channels.open(&root, "c1", async move { Ok(host) }).unwrap();
+
+        // Sent before the code connected.
+        backend.write_all(b"early ").await.unwrap();
+        let path = root.join(CHANNEL_DIR.trim_start_matches('/')).join("c1");
+        let mut code = UnixStream::connect(&path).await.unwrap();
+        backend.write_all(b"bird").await.unwrap();
+        let mut received = [0u8; 10];
+        code.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"early bird");
+
+        code.write_all(b"back").await.unwrap();
+        let mut sent = [0u8; 4];
+        backend.read_exact(&mut sent).await.unwrap();
+        assert_eq!(&sent, b"back");
+
+        channels.close_all();
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,54 @@
+//! Guest clock, stepped to the host time on request of the backend.
+//!
+//! The guest keeps time on its own once booted: a VM restored from a snapshot
+//! wakes up at the time of the snapshot, and one a deterministic job ran in is
+//! left near [`crate::determinism::FIXED_EPOCH_SECS`]. Either would fail TLS
+//! handshakes and stamp files with the wrong dates until the clock is stepped.
+
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Sets the guest clock to `unix_ms`, and returns how far behind it was.
+pub fn step_to(unix_ms: u64) -> io::Result<i64> {
+    let before = SystemTime::now();
+    let time = timespec(unix_ms);
+    // SAFETY: `time` is a valid timespec that outlives the call.
+    if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &time) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(offset_ms(
+        before,
+        UNIX_EPOCH + Duration::from_millis(unix_ms),
+    ))
+}
+
+fn timespec(unix_ms: u64) -> libc::timespec {
+    libc::timespec {
+        tv_sec: (unix_ms / 1000) as libc::time_t,
+        tv_nsec: ((unix_ms % 1000) * 1_000_000) as libc::c_long,
+    }
+}
+
+/// Milliseconds from `clock` to `host`, negative when the clock is ahead.
+fn offset_ms(clock: SystemTime, host: SystemTime) -> i64 {
+    match host.duration_since(clock) {
+        Ok(behind) => behind.as_millis() as i64,
+        Err(ahead) => -(ahead.duration().as_millis() as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offset() {
+        let time = timespec(1_760_000_000_250);
+        assert_eq!((time.tv_sec, time.tv_nsec), (1_760_000_000, 250_000_000));
+
+        let host = UNIX_EPOCH + Duration::from_secs(1_760_000_000);
+        let stale = UNIX_EPOCH + Duration::from_secs(1_759_999_000);
+        assert_eq!(offset_ms(stale, host), 1_000_000);
+        assert_eq!(offset_ms(host, stale), -1_000_000);
+    }
+}
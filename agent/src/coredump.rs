@@ -0,0 +1,134 @@
+//! Core dumps of the code of a job that crashed.
+//!
+//! The kernel writes the core of a process killed by a signal like `SIGSEGV` in
+//! its working directory, truncated to its `RLIMIT_CORE`. The agent takes the
+//! core out of the job directory once the code exited, and hands it back with
+//! the result so that native code can be debugged after the VM is gone.
+
+use std::io;
+use std::path::Path;
+use tokio::process::Command;
+
+/// `core_pattern` of the guest: relative, so that chrooted jobs write their
+/// core in their own working directory.
+const CORE_PATTERN: &str = "core.%p";
+const CORE_PATTERN_PATH: &str = "/proc/sys/kernel/core_pattern";
+
+/// Names the cores after the process that dumped them, when the agent runs as init.
+pub fn configure_kernel() -> io::Result<()> {
+    if std::process::id() != 1 {
+        return Ok(());
+    }
+    std::fs::write(CORE_PATTERN_PATH, CORE_PATTERN)
+}
+
+/// Lets `cmd` dump a core of at most `max_bytes`; the kernel cuts larger ones.
+pub fn allow(cmd: &mut Command, max_bytes: u64) {
+    let limit = libc::rlimit {
+        rlim_cur: max_bytes as libc::rlim_t,
+        rlim_max: max_bytes as libc::rlim_t,
+    };
+    // SAFETY: the closure runs between fork and exec and only makes a system call.
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::setrlimit(libc::RLIMIT_CORE, &limit) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Removes the cores left in `dir` and returns the latest, if any.
+pub async fn take(dir: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut latest = None;
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !is_core(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let modified = entry.metadata().await?.modified()?;
+        if latest.as_ref().is_none_or(|(at, _)| modified > *at) {
+            latest = Some((modified, entry.path()));
+        }
+    }
+    let Some((_, path)) = latest else {
+        return Ok(None);
+    };
+    let core = tokio::fs::read(&path).await?;
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if is_core(&entry.file_name().to_string_lossy()) {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(Some(core))
+}
+
+/// Whether `name` is that of a core written with [`CORE_PATTERN`].
+fn is_core(name: &str) -> bool {
+    name.strip_prefix("core.")
+        .is_some_and(|pid| !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Name of signal `signal`, like `SIGSEGV`.
+pub fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGSYS => "SIGSYS",
+        _ => return format!("SIG{}", signal),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal_name() {
+        assert_eq!(signal_name(libc::SIGSEGV), "SIGSEGV");
+        assert_eq!(signal_name(libc::SIGABRT), "SIGABRT");
+        assert_eq!(signal_name(64), "SIG64");
+    }
+
+    #[tokio::test]
+    async fn test_take_latest_core() {
+        let dir = std::env::temp_dir().join(format!("cloude-cores-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(take(&dir).await.unwrap(), None);
+
+        std::fs::write(dir.join("core.12"), b"first").unwrap();
+        std::fs::write(dir.join("core.7"), b"latest").unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join("core.7"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        std::fs::write(dir.join("core.rs"), b"fn main() {}").unwrap();
+
+        assert_eq!(take(&dir).await.unwrap().unwrap(), b"latest");
+        assert!(!dir.join("core.12").exists());
+        // Code of the job named like a core stays.
+        assert!(dir.join("core.rs").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
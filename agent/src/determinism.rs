@@ -0,0 +1,81 @@
+//! Deterministic execution of a job.
+//!
+//! The processes of a deterministic job get no network, a clock that starts at
+//! the same instant on every run and fixed seeds for the runtimes that read one.
+//! Sources the guest cannot pin down, like the kernel RNG behind `getrandom` or
+//! how long the code takes, are listed in docs/backend.md.
+
+use std::io;
+use tokio::process::Command;
+
+/// Time the clock is set to before a deterministic process starts: 2024-01-01T00:00:00Z.
+pub const FIXED_EPOCH_SECS: i64 = 1_704_067_200;
+
+/// Environment of a deterministic process: `CLOUDE_SEED` is for the code to seed
+/// its own generators with, the others pin down runtimes and tools.
+pub const ENVIRONMENT: [(&str, &str); 5] = [
+    ("CLOUDE_SEED", "0"),
+    ("PYTHONHASHSEED", "0"),
+    ("SOURCE_DATE_EPOCH", "1704067200"),
+    ("TZ", "UTC"),
+    ("LC_ALL", "C"),
+];
+
+/// Sets the guest clock to [`FIXED_EPOCH_SECS`]. Best effort: the clock keeps
+/// running from there, so only the start time is the same on every run.
+pub fn reset_clock() -> io::Result<()> {
+    let time = libc::timespec {
+        tv_sec: FIXED_EPOCH_SECS as libc::time_t,
+        tv_nsec: 0,
+    };
+    // SAFETY: `time` is a valid timespec that outlives the call.
+    if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &time) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes `cmd` start deterministically: fixed seeds and environment, no address
+/// space randomization, and a network namespace of its own with nothing in it.
+pub fn configure(cmd: &mut Command) {
+    cmd.envs(ENVIRONMENT);
+    // SAFETY: the closure runs between fork and exec and only makes system calls.
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::personality(libc::ADDR_NO_RANDOMIZE as libc::c_ulong) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    cut_network(cmd);
+}
+
+/// Makes `cmd` start in a network namespace of its own with nothing in it, for
+/// deterministic jobs and those of a sandbox profile without network.
+pub fn cut_network(cmd: &mut Command) {
+    // SAFETY: the closure runs between fork and exec and only makes a system call.
+    unsafe {
+        cmd.pre_exec(|| {
+            // Failing to leave the guest network fails the spawn.
+            if libc::unshare(libc::CLONE_NEWNET) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_date_epoch_matches_clock() {
+        let epoch = ENVIRONMENT
+            .iter()
+            .find(|(name, _)| *name == "SOURCE_DATE_EPOCH")
+            .map(|(_, value)| value.parse::<i64>().unwrap());
+        assert_eq!(epoch, Some(FIXED_EPOCH_SECS));
+    }
+}
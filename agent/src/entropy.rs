@@ -0,0 +1,63 @@
+//! Reseeding the kernel RNG of the guest from the host.
+//!
+//! Clones of a VM snapshot resume with the RNG state it was saved with, and would
+//! hand their jobs the same bytes. The VMM gives every VM a virtio-rng device,
+//! `/dev/hwrng` in the guest, which reads from the host RNG: the bytes read from it
+//! are credited to the kernel pool, then the kernel is made to reseed at once
+//! rather than at its next scheduled reseed.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+
+/// Random bytes of the host, read through the virtio-rng device.
+const HWRNG_PATH: &str = "/dev/hwrng";
+/// Bytes read from the host for each reseed, credited as full entropy.
+pub const SEED_BYTES: usize = 64;
+
+// ioctls of `/dev/random`, see linux/random.h: `_IOW('R', 0x03, int[2])` and `_IO('R', 0x07)`.
+const RNDADDENTROPY: u32 = 0x4008_5203;
+const RNDRESEEDCRNG: u32 = 0x5207;
+
+/// `struct rand_pool_info` with room for a seed.
+#[repr(C)]
+struct PoolInfo {
+    /// Entropy of `buf`, in bits.
+    entropy_count: libc::c_int,
+    buf_size: libc::c_int,
+    buf: [u8; SEED_BYTES],
+}
+
+/// Reads [`SEED_BYTES`] from the host and reseeds the kernel RNG with them.
+/// Needs `CAP_SYS_ADMIN`, and the virtio-rng driver in the guest kernel.
+pub fn reseed() -> io::Result<()> {
+    let mut info = PoolInfo {
+        entropy_count: (SEED_BYTES * 8) as libc::c_int,
+        buf_size: SEED_BYTES as libc::c_int,
+        buf: [0; SEED_BYTES],
+    };
+    File::open(HWRNG_PATH)?.read_exact(&mut info.buf)?;
+
+    let random = OpenOptions::new().write(true).open("/dev/random")?;
+    // SAFETY: `info` is a `rand_pool_info` whose `buf_size` is the size of its buffer.
+    if unsafe { libc::ioctl(random.as_raw_fd(), RNDADDENTROPY as _, &info) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: RNDRESEEDCRNG takes no argument.
+    if unsafe { libc::ioctl(random.as_raw_fd(), RNDRESEEDCRNG as _) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_info_layout() {
+        // Two ints, then the buffer the kernel reads `buf_size` bytes from.
+        assert_eq!(std::mem::offset_of!(PoolInfo, buf), 8);
+        assert_eq!(std::mem::size_of::<PoolInfo>(), 8 + SEED_BYTES);
+    }
+}
@@ -0,0 +1,112 @@
+//! vCPUs hotplugged by the host.
+//!
+//! The kernel announces a new processor with an `add@/devices/system/cpu/cpuN`
+//! uevent but leaves it offline. Nothing else runs in the VM to bring it
+//! online, so the agent does it when it runs as init.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use tracing::{info, warn};
+
+const CPU_ADD_PREFIX: &str = "add@/devices/system/cpu/cpu";
+/// Multicast group of the uevents sent by the kernel.
+const KERNEL_UEVENTS: u32 = 1;
+/// Large enough for any uevent, which the kernel caps at 2 KiB of environment.
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+/// Starts bringing hotplugged vCPUs online in the background, when the agent runs as init.
+pub fn online_new_cpus() {
+    if std::process::id() != 1 {
+        return;
+    }
+    let mut socket = match open_uevent_socket() {
+        Ok(socket) => File::from(socket),
+        Err(e) => {
+            warn!("Cannot listen for hotplugged vCPUs: {}", e);
+            return;
+        }
+    };
+    let spawned = std::thread::Builder::new()
+        .name("cpu-hotplug".to_string())
+        .spawn(move || {
+            let mut buffer = vec![0u8; UEVENT_BUFFER_SIZE];
+            loop {
+                let len = match socket.read(&mut buffer) {
+                    Ok(len) => len,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        warn!("Stopped listening for hotplugged vCPUs: {}", e);
+                        return;
+                    }
+                };
+                if let Some(cpu) = added_cpu(&buffer[..len]) {
+                    let online = format!("/sys/devices/system/cpu/cpu{cpu}/online");
+                    match std::fs::write(&online, "1") {
+                        Ok(()) => info!("vCPU {} hotplugged and online", cpu),
+                        Err(e) => warn!("Cannot bring hotplugged vCPU {} online: {}", cpu, e),
+                    }
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        warn!("Cannot listen for hotplugged vCPUs: {}", e);
+    }
+}
+
+fn open_uevent_socket() -> io::Result<OwnedFd> {
+    // SAFETY: socket takes no pointers; the descriptor is checked, then owned.
+    let socket = unsafe {
+        let fd = libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        OwnedFd::from_raw_fd(fd)
+    };
+
+    // SAFETY: sockaddr_nl is plain data, and bind only reads the size given.
+    unsafe {
+        let mut addr: libc::sockaddr_nl = std::mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = KERNEL_UEVENTS;
+        if libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(socket)
+}
+
+/// The number of the vCPU a uevent announces, if it announces one.
+fn added_cpu(uevent: &[u8]) -> Option<u32> {
+    let summary = uevent.split(|&byte| byte == 0).next()?;
+    std::str::from_utf8(summary)
+        .ok()?
+        .strip_prefix(CPU_ADD_PREFIX)?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_added_cpu() {
+        let uevent =
+            b"add@/devices/system/cpu/cpu3\0ACTION=add\0DEVPATH=/devices/system/cpu/cpu3\0";
+        assert_eq!(added_cpu(uevent), Some(3));
+        // The same CPU going online, and other devices, are ignored.
+        assert_eq!(added_cpu(b"online@/devices/system/cpu/cpu3\0"), None);
+        assert_eq!(added_cpu(b"add@/devices/system/cpu/cpufreq\0"), None);
+        assert_eq!(added_cpu(b"add@/devices/virtual/net/lo\0"), None);
+    }
+}
@@ -0,0 +1,123 @@
+//! Identity tokens of the running jobs, renewed by the host.
+//!
+//! The host signs a token for each job it runs in the VM, valid for minutes,
+//! and sends a new one with `PUT /identity` before the last one expires, for as
+//! long as the job runs. The agent writes the token of each job to a file of
+//! the job directory, named to the code by `CLOUDE_IDENTITY_TOKEN_FILE`, and
+//! renames every new one over it so that the code never reads half a token.
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// File of the token, in the directory of the job.
+pub const TOKEN_FILE: &str = ".cloude-identity-token";
+
+/// Token files of the running jobs, by the `job_id` claim of their tokens.
+#[derive(Default)]
+pub struct Tokens {
+    files: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl Tokens {
+    /// Writes `token`, the first of its job, in `job_dir`, and returns the path
+    /// of its file.
+    pub fn register(&self, job_dir: &Path, token: &str) -> io::Result<PathBuf> {
+        let job = job_of(token)?;
+        let path = job_dir.join(TOKEN_FILE);
+        write(&path, token)?;
+        self.lock().insert(job, path.clone());
+        Ok(path)
+    }
+
+    /// Stops renewing the token of the job of `token`, once it ended.
+    pub fn forget(&self, token: &str) {
+        if let Ok(job) = job_of(token) {
+            self.lock().remove(&job);
+        }
+    }
+
+    /// Replaces the token of the job `token` names, and returns whether that job
+    /// is running.
+    pub fn renew(&self, token: &str) -> io::Result<bool> {
+        let job = job_of(token)?;
+        let files = self.lock();
+        let Some(path) = files.get(&job) else {
+            return Ok(false);
+        };
+        write(path, token)?;
+        Ok(true)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, PathBuf>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The `job_id` claim of `token`. The host signed it, so its signature is not
+/// checked here.
+fn job_of(token: &str) -> io::Result<String> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message.to_string());
+    let claims = token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| invalid("token is not a JWT"))?;
+    let claims = BASE64_URL_SAFE_NO_PAD
+        .decode(claims)
+        .map_err(|_| invalid("claims are not base64url"))?;
+    let claims: serde_json::Value =
+        serde_json::from_slice(&claims).map_err(|_| invalid("claims are not JSON"))?;
+    claims["job_id"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| invalid("token names no job"))
+}
+
+/// Writes `token` to a file next to `path`, readable by its owner only, then
+/// renames it over `path`.
+fn write(path: &Path, token: &str) -> io::Result<()> {
+    let staged = path.with_extension("new");
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&staged)?
+        .write_all(token.as_bytes())?;
+    std::fs::rename(&staged, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(job_id: &str) -> String {
+        let claims = serde_json::json!({ "job_id": job_id, "exp": 1 });
+        format!(
+            "header.{}.signature",
+            BASE64_URL_SAFE_NO_PAD.encode(claims.to_string())
+        )
+    }
+
+    #[test]
+    fn test_tokens_are_renewed_while_the_job_runs() {
+        let dir = std::env::temp_dir().join(format!("cloude-identity-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tokens = Tokens::default();
+        let path = tokens.register(&dir, &token("job-1")).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), token("job-1"));
+
+        let renewed = format!("{}2", token("job-1"));
+        assert!(tokens.renew(&renewed).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), renewed);
+        assert!(!tokens.renew(&token("job-2")).unwrap());
+        assert!(tokens.renew("not a token").is_err());
+
+        tokens.forget(&token("job-1"));
+        assert!(!tokens.renew(&token("job-1")).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
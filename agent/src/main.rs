@@ -1,56 +1,119 @@
 use agent::runtimes::{LanguageRuntime, runtime_from_language};
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use axum::{
-    Json, Router, extract::State, http::StatusCode, response::IntoResponse, routing::get,
+    Json, Router,
+    body::Body,
+    extract::{DefaultBodyLimit, Path as UrlPath, Request, State},
+    http::{StatusCode, header},
+    middleware,
+    response::IntoResponse,
+    routing::get,
     routing::post,
+    routing::put,
 };
-use serde::{Deserialize, Serialize};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use cloude_types::{
+    CHANNEL_PROTOCOL, ClockRequest, ClockResponse, EntropyResponse, ErrorResponse, ExecuteChunk,
+    ExecuteRequest, ExecutionResult, IdentityRequest, JobLimits, MAX_CONCURRENT_EXECUTIONS,
+    NetworkRequest, ResetResponse,
+};
+use futures_util::StreamExt;
 use std::env;
-use std::path::{Path, PathBuf};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Component, Path, PathBuf};
 use std::process::Stdio;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::io::AsyncReadExt;
 use tokio::net::TcpListener;
 use tokio::process::Command;
 use tokio::sync::mpsc;
 use tokio::sync::{OwnedSemaphorePermit, Semaphore};
-use tokio::time::{Duration, timeout};
+use tokio::time::{Duration, Instant, timeout};
 use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
 
+mod auth;
+mod cgroup;
+mod channel;
+mod clock;
+mod coredump;
+mod determinism;
+mod entropy;
+mod hotplug;
+mod identity;
+mod mounts;
+mod network;
+mod overlay;
+mod power;
+
 const MAX_OUTPUT_BYTES: usize = 1024 * 1024;
+/// Default request body limit, large enough for an artifact uploaded to the backend,
+/// which grows by a third when it is a base64-encoded bundle.
+const DEFAULT_MAX_REQUEST_BYTES: usize = 384 * 1024 * 1024;
+
+/// How long `/network` waits before moving the guest, for its answer to leave.
+const READDRESS_DELAY: Duration = Duration::from_millis(100);
+
+/// Whether the guest was moved already. It is once, before its first job: the
+/// code of jobs cannot move it to the address of another VM.
+static READDRESSED: AtomicBool = AtomicBool::new(false);
 
 struct AppState {
     job_counter: AtomicU64,
+    /// A concurrent job holds one permit, any other job and resets hold them all.
     run_limit: Arc<Semaphore>,
     work_dir: PathBuf,
     exec_timeout: Duration,
+    /// Overlay jobs run in since the last reset, if the host asked for one.
+    overlay: Mutex<Option<overlay::Overlay>>,
+    /// Identity token files of the running jobs.
+    identities: identity::Tokens,
+    /// Streams to the code of jobs in other VMs, relayed by the host.
+    channels: channel::Channels,
 }
 
-#[derive(Debug, Deserialize)]
-struct ExecuteRequest {
-    language: String,
-    code: String,
-}
-
-#[derive(Debug, Serialize)]
-struct ExecuteResponse {
-    job_id: String,
+/// Exit status and captured output of a finished runtime process.
+struct ProcessOutput {
     exit_code: i32,
     stdout: String,
     stderr: String,
+    /// Binary the compile step built, for jobs that keep it.
+    binary: Option<Vec<u8>>,
+    /// Signal that killed the process.
+    signal: Option<i32>,
+    /// Core the process dumped when it crashed, for jobs that allow one.
+    core_dump: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Serialize)]
-struct ErrorResponse {
-    error: String,
-}
-
-struct ExecutionResult {
-    exit_code: i32,
-    stdout: String,
-    stderr: String,
+/// What the code of a job reads, and who gets its output as it is written.
+#[derive(Default)]
+struct JobIo {
+    stdin: Option<String>,
+    /// Gets the standard output as it is written, for streamed executions.
+    stdout_tap: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Binary of an earlier run of the same code, run without compiling.
+    binary: Option<Vec<u8>>,
+    /// Hand back the binary the compile step builds.
+    keep_binary: bool,
+    /// Hand it back without running it, for code that runs in another VM.
+    build_only: bool,
+    /// Cgroup holding the processes of the job to its limits.
+    cgroup: Option<cgroup::JobCgroup>,
+    /// Largest core the code can dump when it crashes, `0` for none.
+    max_core_bytes: u64,
+    /// Run the processes of the job without network.
+    offline: bool,
+    /// Identity token of the VM, handed to the code in `CLOUDE_IDENTITY_TOKEN`.
+    identity_token: Option<String>,
+    /// File the host renews the token in, handed to the code in
+    /// `CLOUDE_IDENTITY_TOKEN_FILE`, as the code sees it.
+    identity_token_file: Option<PathBuf>,
+    /// Proxy the code reaches the network through, for `egress-restricted` jobs.
+    egress_proxy: Option<String>,
+    /// Runs beside other jobs: its processes get a group of their own, killed
+    /// once the job ended.
+    concurrent: bool,
 }
 
 struct PreparedJob {
@@ -65,6 +128,7 @@ async fn main() -> Result<()> {
             EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
         )
         .init();
+    mounts::mount_pseudo_filesystems();
 
     let server_addr = env::var("AGENT_SERVER_ADDR").unwrap_or_else(|_| "0.0.0.0:3001".to_string());
     let work_dir = resolve_work_dir(PathBuf::from(
@@ -74,22 +138,52 @@ async fn main() -> Result<()> {
         .ok()
         .and_then(|v| v.parse::<u64>().ok())
         .unwrap_or(30);
+    let max_request_bytes = env::var("AGENT_MAX_REQUEST_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_REQUEST_BYTES);
 
     let state = Arc::new(AppState {
         job_counter: AtomicU64::new(1),
-        run_limit: Arc::new(Semaphore::new(1)),
+        run_limit: Arc::new(Semaphore::new(MAX_CONCURRENT_EXECUTIONS as usize)),
         work_dir,
         exec_timeout: Duration::from_secs(timeout_secs),
+        overlay: Mutex::new(None),
+        identities: identity::Tokens::default(),
+        channels: channel::Channels::default(),
     });
 
     let app = Router::new()
-        .route("/health", get(health))
         .route("/execute", post(execute))
+        .route("/reset", post(reset))
+        .route("/clock", post(set_clock))
+        .route("/entropy", post(reseed_entropy))
+        .route("/identity", put(renew_identity))
+        .route("/network", post(readdress_network))
+        .route("/channels/{id}", post(open_channel));
+    let app = match auth::token() {
+        Some(token) => app.route_layer(middleware::from_fn_with_state(token, auth::require)),
+        None => app,
+    };
+    let app = app
+        .route("/health", get(health))
+        .layer(DefaultBodyLimit::max(max_request_bytes))
         .with_state(state);
 
+    hotplug::online_new_cpus();
+    if let Err(e) = coredump::configure_kernel() {
+        warn!(
+            "Cannot set the core pattern, crashed jobs dump no core: {}",
+            e
+        );
+    }
+
     info!("Starting agent server on {}", server_addr);
     let listener = TcpListener::bind(&server_addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(power::pressed())
+        .await?;
+    power::power_off();
     Ok(())
 }
 
@@ -99,14 +193,37 @@ async fn health() -> &'static str {
 
 async fn execute(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<ExecuteRequest>,
+    Json(mut payload): Json<ExecuteRequest>,
 ) -> impl IntoResponse {
     let id = state.job_counter.fetch_add(1, Ordering::Relaxed);
     let job_id = format!("job-{}", id);
-    let _permit = match acquire_run_permit(&state, &job_id).await {
+    // A deterministic job sets the clock of the whole guest.
+    let concurrent = payload.concurrent && !payload.deterministic;
+    let permit = match acquire_run_permit(&state, &job_id, concurrent).await {
         Ok(permit) => permit,
         Err(response) => return response,
     };
+    let deterministic = payload.deterministic;
+    let offline = payload.offline;
+    let stream = payload.stream;
+    let stdin = payload.stdin.take();
+    let identity_token = payload.identity_token.take();
+    let egress_proxy = payload.egress_proxy.take();
+    let keep_binary = payload.keep_binary;
+    let build_only = payload.build_only;
+    let limits = payload.limits.take();
+    let max_core_bytes = limits.and_then(|l| l.core_dump_bytes).unwrap_or(0);
+    let binary = match payload.binary.take().map(|b| BASE64_STANDARD.decode(b)) {
+        None => None,
+        Some(Ok(binary)) => Some(binary),
+        Some(Err(e)) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("Binary is not valid base64: {}", e),
+            );
+        }
+    };
+    let root = job_root(&state);
 
     let runtime = match runtime_from_language(&payload.language) {
         Some(runtime) => runtime,
@@ -119,10 +236,10 @@ async fn execute(
     };
 
     let prepared_job = match prepare_job(
-        &state.work_dir,
+        &overlay::host_path(&root, &state.work_dir),
         &job_id,
         runtime.source_extension(),
-        payload.code,
+        payload,
     )
     .await
     {
@@ -135,35 +252,312 @@ async fn execute(
         }
     };
 
-    let result = match execute_job(
-        runtime.as_ref(),
-        &prepared_job.source_path,
-        &prepared_job.job_dir,
-        state.exec_timeout,
-    )
-    .await
-    {
-        Ok(result) => result,
-        Err(e) => {
-            schedule_job_cleanup(prepared_job.job_dir.clone());
-            return error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
-        }
+    let identity_token_file = match &identity_token {
+        Some(token) => match state.identities.register(&prepared_job.job_dir, token) {
+            Ok(path) => Some(overlay::job_path(&root, &path)),
+            Err(e) => {
+                schedule_job_cleanup(prepared_job.job_dir);
+                return error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to write the identity token: {}", e),
+                );
+            }
+        },
+        None => None,
     };
 
-    schedule_job_cleanup(prepared_job.job_dir);
-
-    (
-        StatusCode::OK,
-        Json(ExecuteResponse {
+    let cgroup = limits.and_then(|limits| {
+        let root = Path::new(cgroup::CGROUP_ROOT);
+        let created = match cgroup::JobCgroup::create(root, &job_id, &limits) {
+            Err(e) if limits.cpu_millis.is_some() => {
+                // A guest kernel without the cpu controller still holds memory and processes.
+                warn!("Cannot limit the CPU of {}: {}", job_id, e);
+                let limits = JobLimits {
+                    cpu_millis: None,
+                    cpu_burst_ms: None,
+                    ..limits
+                };
+                cgroup::JobCgroup::create(root, &job_id, &limits).map(|cgroup| (cgroup, limits))
+            }
+            created => created.map(|cgroup| (cgroup, limits)),
+        };
+        match created {
+            Ok(created) => Some(created),
+            Err(e) => {
+                warn!("Cannot limit the resources of {}: {}", job_id, e);
+                None
+            }
+        }
+    });
+    let throttle = cgroup
+        .as_ref()
+        .and_then(|(cgroup, limits)| cgroup.throttle_after_burst(limits));
+    let cgroup = cgroup.map(|(cgroup, _)| cgroup);
+    let (stdout_tap, chunks) = match stream {
+        true => {
+            let (tap, chunks) = mpsc::unbounded_channel();
+            (Some(tap), Some(chunks))
+        }
+        false => (None, None),
+    };
+    let io = JobIo {
+        stdin,
+        stdout_tap,
+        binary,
+        keep_binary,
+        build_only,
+        cgroup,
+        max_core_bytes,
+        offline,
+        identity_token,
+        identity_token_file,
+        egress_proxy,
+        concurrent,
+    };
+    let exec_timeout = state.exec_timeout;
+    let identities = Arc::clone(&state);
+    let run = async move {
+        let result = execute_job(
+            runtime.as_ref(),
+            &overlay::job_path(&root, &prepared_job.source_path),
+            &overlay::job_path(&root, &prepared_job.job_dir),
+            exec_timeout,
+            deterministic,
+            &root,
+            &io,
+        )
+        .await;
+        if let Some(throttle) = throttle {
+            throttle.abort();
+        }
+        let oom_killed = match io.cgroup {
+            Some(cgroup) => {
+                let oom_killed = cgroup.oom_killed();
+                if let Err(e) = cgroup.remove().await {
+                    warn!("Cannot remove the cgroup of {}: {}", job_id, e);
+                }
+                oom_killed
+            }
+            None => false,
+        };
+        if let Some(token) = &io.identity_token {
+            identities.identities.forget(token);
+        }
+        schedule_job_cleanup(prepared_job.job_dir);
+        drop(permit);
+        result.map(|result| ExecutionResult {
             job_id,
             exit_code: result.exit_code,
             stdout: result.stdout,
             stderr: result.stderr,
-        }),
+            binary: result.binary.map(|binary| BASE64_STANDARD.encode(binary)),
+            oom_killed,
+            signal: result.signal.map(coredump::signal_name),
+            core_dump: result.core_dump.map(|core| BASE64_STANDARD.encode(core)),
+        })
+    };
+
+    let Some(chunks) = chunks else {
+        return match run.await {
+            Ok(result) => (StatusCode::OK, Json(result)).into_response(),
+            Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        };
+    };
+    // Stdout chunks as the code writes them, then its result once the tap closed.
+    let task = tokio::spawn(run);
+    let lines = futures_util::stream::unfold(Some((chunks, task)), |next| async move {
+        let (mut chunks, task) = next?;
+        if let Some(data) = chunks.recv().await {
+            let chunk = ExecuteChunk::Stdout(BASE64_STANDARD.encode(data));
+            return Some((chunk, Some((chunks, task))));
+        }
+        let chunk = match task.await {
+            Ok(Ok(result)) => ExecuteChunk::Result(result),
+            Ok(Err(e)) => ExecuteChunk::Error(e.to_string()),
+            Err(e) => ExecuteChunk::Error(format!("Execution task failed: {}", e)),
+        };
+        Some((chunk, None))
+    })
+    .map(|chunk| {
+        serde_json::to_vec(&chunk).map(|mut line| {
+            line.push(b'\n');
+            line
+        })
+    });
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
     )
         .into_response()
 }
 
+/// Kills what earlier jobs left running and gives the next ones an empty
+/// overlay upper layer, for a pooled VM about to run a job.
+async fn reset(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let _permit = match acquire_run_permit(&state, "reset", false).await {
+        Ok(permit) => permit,
+        Err(response) => return response,
+    };
+    let started = Instant::now();
+    overlay::kill_leftovers();
+    state.channels.close_all();
+
+    let mut current = state.overlay.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(Err(e)) = current.take().map(overlay::Overlay::unmount) {
+        return error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to unmount overlay: {}", e),
+        );
+    }
+    match overlay::Overlay::mount(Path::new(overlay::OVERLAY_DIR)) {
+        Ok(mounted) => *current = Some(mounted),
+        Err(e) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to mount overlay: {}", e),
+            );
+        }
+    }
+    drop(current);
+
+    let duration_ms = started.elapsed().as_millis() as u64;
+    info!(duration_ms, "Reset job filesystem");
+    (StatusCode::OK, Json(ResetResponse { duration_ms })).into_response()
+}
+
+/// Steps the guest clock to the time of the host. Does not wait for the running
+/// job: the time sent is only right for as long as the request takes.
+async fn set_clock(Json(request): Json<ClockRequest>) -> impl IntoResponse {
+    match clock::step_to(request.unix_ms) {
+        Ok(offset_ms) => {
+            info!(offset_ms, "Stepped the clock to the host time");
+            (StatusCode::OK, Json(ClockResponse { offset_ms })).into_response()
+        }
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to set the clock: {}", e),
+        ),
+    }
+}
+
+/// Replaces the identity token of a running job with the new one of the host.
+async fn renew_identity(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<IdentityRequest>,
+) -> impl IntoResponse {
+    match state.identities.renew(&request.token) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => error_response(
+            StatusCode::NOT_FOUND,
+            "No running job has this identity".to_string(),
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            error_response(StatusCode::BAD_REQUEST, e.to_string())
+        }
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to write the identity token: {}", e),
+        ),
+    }
+}
+
+/// Moves the guest to the address of the request. Answered first: the backend
+/// reaches the agent at the address it had, which is gone once moved. The
+/// backend waits for `/health` at the new address.
+async fn readdress_network(Json(request): Json<NetworkRequest>) -> axum::response::Response {
+    if READDRESSED.swap(true, Ordering::SeqCst) {
+        return error_response(
+            StatusCode::CONFLICT,
+            "The guest was moved to its address already".to_string(),
+        );
+    }
+    tokio::spawn(async move {
+        tokio::time::sleep(READDRESS_DELAY).await;
+        match network::readdress(request.ip, request.prefix_len, request.gateway) {
+            Ok(()) => info!(ip = %request.ip, "Moved the guest to its address"),
+            Err(e) => warn!(ip = %request.ip, "Failed to move the guest address: {}", e),
+        }
+    });
+    StatusCode::ACCEPTED.into_response()
+}
+
+/// Opens channel `id` to the code of the running job, and upgrades the request
+/// to the stream of the channel, which the host relays to the other end.
+async fn open_channel(
+    State(state): State<Arc<AppState>>,
+    UrlPath(id): UrlPath<String>,
+    request: Request,
+) -> axum::response::Response {
+    let upgrading = request
+        .headers()
+        .get(header::UPGRADE)
+        .is_some_and(|protocol| protocol.as_bytes() == CHANNEL_PROTOCOL.as_bytes());
+    if !upgrading {
+        return error_response(
+            StatusCode::UPGRADE_REQUIRED,
+            format!("Channels are opened with Upgrade: {}", CHANNEL_PROTOCOL),
+        );
+    }
+    let upgraded = hyper::upgrade::on(request);
+    let host = async move {
+        upgraded
+            .await
+            .map(hyper_util::rt::TokioIo::new)
+            .map_err(std::io::Error::other)
+    };
+    match state.channels.open(&job_root(&state), &id, host) {
+        Ok(()) => {
+            info!(channel = %id, "Channel opened");
+            (
+                StatusCode::SWITCHING_PROTOCOLS,
+                [
+                    (header::CONNECTION, "upgrade"),
+                    (header::UPGRADE, CHANNEL_PROTOCOL),
+                ],
+            )
+                .into_response()
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            error_response(StatusCode::BAD_REQUEST, e.to_string())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => error_response(
+            StatusCode::CONFLICT,
+            format!("Channel {} is open already", id),
+        ),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to open channel {}: {}", id, e),
+        ),
+    }
+}
+
+/// Reseeds the kernel RNG with bytes of the host, so that a VM cloned from a
+/// snapshot does not hand its jobs the random bytes of the other clones.
+async fn reseed_entropy() -> impl IntoResponse {
+    match entropy::reseed() {
+        Ok(()) => {
+            let bytes = entropy::SEED_BYTES as u32;
+            info!(bytes, "Reseeded the kernel RNG from the host");
+            (StatusCode::OK, Json(EntropyResponse { bytes })).into_response()
+        }
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to reseed the kernel RNG: {}", e),
+        ),
+    }
+}
+
+/// Root the next job runs in: the overlay after a reset, the guest root otherwise.
+fn job_root(state: &AppState) -> PathBuf {
+    state
+        .overlay
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map_or_else(|| PathBuf::from("/"), overlay::Overlay::root)
+}
+
 fn schedule_job_cleanup(job_dir: PathBuf) {
     tokio::spawn(async move {
         if let Err(err) = tokio::fs::remove_dir_all(&job_dir).await {
@@ -173,16 +567,23 @@ fn schedule_job_cleanup(job_dir: PathBuf) {
 }
 
 fn error_response(status: StatusCode, error: String) -> axum::response::Response {
-    (status, Json(ErrorResponse { error })).into_response()
+    (status, Json(ErrorResponse::new(error))).into_response()
 }
 
+/// Waits for the jobs running to let `job_id` run: those that are not
+/// `concurrent` run alone.
 async fn acquire_run_permit(
     state: &Arc<AppState>,
     job_id: &str,
+    concurrent: bool,
 ) -> std::result::Result<OwnedSemaphorePermit, axum::response::Response> {
-    info!(job_id = %job_id, "Waiting for run permit");
+    info!(job_id = %job_id, concurrent, "Waiting for run permit");
+    let permits = match concurrent {
+        true => 1,
+        false => MAX_CONCURRENT_EXECUTIONS,
+    };
     let run_limit = Arc::clone(&state.run_limit);
-    match run_limit.acquire_owned().await {
+    match run_limit.acquire_many_owned(permits).await {
         Ok(permit) => {
             info!(job_id = %job_id, "Acquired run permit");
             Ok(permit)
@@ -194,11 +595,13 @@ async fn acquire_run_permit(
     }
 }
 
+/// Writes the code of a job in its own directory: the single source file, or
+/// every file of a bundle, in which case the entrypoint is the file to run.
 async fn prepare_job(
     work_dir: &Path,
     job_id: &str,
     source_extension: &str,
-    code: String,
+    payload: ExecuteRequest,
 ) -> std::result::Result<PreparedJob, (Option<PathBuf>, String)> {
     let job_dir = work_dir.join(job_id);
 
@@ -206,13 +609,49 @@ async fn prepare_job(
         .await
         .map_err(|e| (None, format!("Failed to create job dir: {}", e)))?;
 
-    let source_path = job_dir.join(format!("code.{}", source_extension));
-    tokio::fs::write(&source_path, code).await.map_err(|e| {
+    let Some(bundle) = payload.bundle else {
+        let source_path = job_dir.join(format!("code.{}", source_extension));
+        tokio::fs::write(&source_path, payload.code)
+            .await
+            .map_err(|e| {
+                (
+                    Some(job_dir.clone()),
+                    format!("Failed to write source code: {}", e),
+                )
+            })?;
+        return Ok(PreparedJob {
+            job_dir,
+            source_path,
+        });
+    };
+
+    let source_path = match payload.entrypoint.as_deref().and_then(entrypoint_path) {
+        Some(entrypoint) => job_dir.join(entrypoint),
+        None => {
+            return Err((
+                Some(job_dir),
+                "A bundle needs a relative entrypoint inside it".to_string(),
+            ));
+        }
+    };
+    unpack_bundle(&bundle, &job_dir).await.map_err(|e| {
         (
             Some(job_dir.clone()),
-            format!("Failed to write source code: {}", e),
+            format!("Failed to unpack bundle: {:#}", e),
         )
     })?;
+    if !tokio::fs::metadata(&source_path)
+        .await
+        .is_ok_and(|m| m.is_file())
+    {
+        return Err((
+            Some(job_dir),
+            format!(
+                "Entrypoint {} is not a file of the bundle",
+                payload.entrypoint.unwrap_or_default()
+            ),
+        ));
+    }
 
     Ok(PreparedJob {
         job_dir,
@@ -220,36 +659,139 @@ async fn prepare_job(
     })
 }
 
+/// `entrypoint` if it only goes down from the bundle root.
+fn entrypoint_path(entrypoint: &str) -> Option<&Path> {
+    let path = Path::new(entrypoint);
+    let mut components = path.components().peekable();
+    components.peek()?;
+    components
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then_some(path)
+}
+
+/// Extracts a base64-encoded gzipped tar into `job_dir`. Entries that would land
+/// outside of it are refused by `tar`.
+async fn unpack_bundle(bundle: &str, job_dir: &Path) -> Result<()> {
+    let archive = BASE64_STANDARD
+        .decode(bundle)
+        .context("Bundle is not valid base64")?;
+    let job_dir = job_dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        tar::Archive::new(flate2::read::GzDecoder::new(archive.as_slice()))
+            .unpack(&job_dir)
+            .context("Bundle is not a valid gzipped tar")
+    })
+    .await
+    .context("Failed to join unpack task")?
+}
+
 async fn execute_job(
     runtime: &dyn LanguageRuntime,
     source_path: &Path,
     work_dir: &Path,
     exec_timeout: Duration,
-) -> Result<ExecutionResult> {
-    if let Some(commands) = runtime.compile_candidates(source_path, work_dir) {
-        let compile_result = run_process_candidates(&commands, work_dir, exec_timeout).await?;
+    deterministic: bool,
+    root: &Path,
+    io: &JobIo,
+) -> Result<ProcessOutput> {
+    let binary_path = runtime
+        .binary_path(work_dir)
+        .map(|path| overlay::host_path(root, &path));
+    if io.build_only && binary_path.is_none() {
+        bail!("This runtime runs the code without compiling it");
+    }
+    let mut binary = None;
+    if let (Some(prebuilt), Some(path)) = (&io.binary, &binary_path) {
+        write_binary(path, prebuilt)
+            .await
+            .with_context(|| format!("Failed to write binary: {}", path.display()))?;
+    } else if let Some(commands) = runtime.compile_candidates(source_path, work_dir) {
+        let mut compile_result = run_process_candidates(
+            &commands,
+            work_dir,
+            exec_timeout,
+            deterministic,
+            root,
+            &JobIo {
+                cgroup: io.cgroup.clone(),
+                offline: io.offline,
+                egress_proxy: io.egress_proxy.clone(),
+                ..JobIo::default()
+            },
+        )
+        .await?;
         if compile_result.exit_code != 0 {
             return Ok(compile_result);
         }
+        // Read before the code runs, which could change it.
+        if let Some(path) = binary_path.as_ref() {
+            if io.build_only {
+                compile_result.binary = Some(
+                    tokio::fs::read(path)
+                        .await
+                        .with_context(|| format!("Failed to read binary: {}", path.display()))?,
+                );
+                return Ok(compile_result);
+            }
+            if io.keep_binary {
+                match tokio::fs::read(path).await {
+                    Ok(built) => binary = Some(built),
+                    Err(e) => warn!(path = %path.display(), error = %e, "Cannot read built binary"),
+                }
+            }
+        }
     }
 
-    run_process_candidates(
+    let mut output = run_process_candidates(
         &runtime.run_candidates(source_path, work_dir),
         work_dir,
         exec_timeout,
+        deterministic,
+        root,
+        io,
     )
-    .await
+    .await?;
+    output.binary = binary;
+    if output.signal.is_some() && io.max_core_bytes > 0 {
+        let dir = overlay::host_path(root, work_dir);
+        match coredump::take(&dir).await {
+            Ok(core) => output.core_dump = core,
+            Err(e) => warn!("Cannot take the core dump of the job: {}", e),
+        }
+    }
+    Ok(output)
+}
+
+/// Writes a binary built earlier where the compile step would have left it.
+async fn write_binary(path: &Path, binary: &[u8]) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    tokio::fs::write(path, binary).await?;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).await
 }
 
 async fn run_process_candidates(
     commands: &[(String, Vec<String>)],
     work_dir: &Path,
     exec_timeout: Duration,
-) -> Result<ExecutionResult> {
+    deterministic: bool,
+    root: &Path,
+    io: &JobIo,
+) -> Result<ProcessOutput> {
     let mut last_error = None;
 
     for (program, args) in commands {
-        match run_process(program, args, work_dir, exec_timeout).await {
+        match run_process(
+            program,
+            args,
+            work_dir,
+            exec_timeout,
+            deterministic,
+            root,
+            io,
+        )
+        .await
+        {
             Ok(result) => return Ok(result),
             Err(err) if err.downcast_ref::<std::io::Error>().is_some() => {
                 last_error = Some((program.clone(), err))
@@ -267,24 +809,81 @@ async fn run_process(
     args: &[String],
     work_dir: &Path,
     exec_timeout: Duration,
-) -> Result<ExecutionResult> {
+    deterministic: bool,
+    root: &Path,
+    io: &JobIo,
+) -> Result<ProcessOutput> {
     let mut cmd = Command::new(program);
     cmd.args(args)
-        .current_dir(work_dir)
-        .stdin(Stdio::null())
+        .env_remove(auth::TOKEN_ENV)
+        .current_dir(overlay::host_path(root, work_dir))
+        .stdin(if io.stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .kill_on_drop(true);
+    if root != Path::new("/") {
+        overlay::enter(&mut cmd, root)
+            .with_context(|| format!("Invalid job root: {}", root.display()))?;
+    }
+    if let Some(cgroup) = &io.cgroup {
+        cgroup.enter(&mut cmd);
+    }
+    if io.concurrent {
+        cmd.process_group(0);
+    }
+    if io.max_core_bytes > 0 {
+        coredump::allow(&mut cmd, io.max_core_bytes);
+    }
+    if let Some(token) = &io.identity_token {
+        cmd.env("CLOUDE_IDENTITY_TOKEN", token);
+    }
+    if let Some(path) = &io.identity_token_file {
+        cmd.env("CLOUDE_IDENTITY_TOKEN_FILE", path);
+    }
+    if let Some(proxy) = &io.egress_proxy {
+        // Tools read one spelling or the other.
+        for name in ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"] {
+            cmd.env(name, proxy);
+        }
+    }
+    if deterministic {
+        determinism::configure(&mut cmd);
+        if let Err(e) = determinism::reset_clock() {
+            warn!("Cannot set the clock of a deterministic job: {}", e);
+        }
+    } else if io.offline {
+        determinism::cut_network(&mut cmd);
+    }
 
     let mut child = cmd
         .spawn()
         .with_context(|| format!("Failed to spawn process: {}", program))?;
+    // Declared after the child, so dropped before it, on every return.
+    let _group = child.id().filter(|_| io.concurrent).map(ProcessGroup);
+    // Written from a task of its own: code that does not read all of it must not
+    // block on a full pipe, and it may exit before reading any.
+    if let (Some(input), Some(mut pipe)) = (&io.stdin, child.stdin.take()) {
+        let input = input.as_bytes().to_vec();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            let _ = pipe.write_all(&input).await;
+        });
+    }
     let stdout = child.stdout.take().context("Child stdout was not piped")?;
     let stderr = child.stderr.take().context("Child stderr was not piped")?;
     let (tx, mut rx) = mpsc::channel(2);
 
-    let stdout_task = tokio::spawn(read_stream_limited(stdout, StreamKind::Stdout, tx.clone()));
-    let stderr_task = tokio::spawn(read_stream_limited(stderr, StreamKind::Stderr, tx));
+    let stdout_task = tokio::spawn(read_stream_limited(
+        stdout,
+        StreamKind::Stdout,
+        tx.clone(),
+        io.stdout_tap.clone(),
+    ));
+    let stderr_task = tokio::spawn(read_stream_limited(stderr, StreamKind::Stderr, tx, None));
     let mut recv_closed = false;
 
     let status = timeout(exec_timeout, async {
@@ -330,13 +929,29 @@ async fn run_process(
         .context("Failed to join stderr reader task")?
         .with_context(|| format!("Failed to read stderr for: {}", program))?;
 
-    Ok(ExecutionResult {
+    Ok(ProcessOutput {
         exit_code: status.code().unwrap_or(1),
         stdout: String::from_utf8_lossy(&stdout).into_owned(),
         stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        binary: None,
+        signal: status.signal(),
+        core_dump: None,
     })
 }
 
+/// Process group of a concurrent job, whose processes are killed on drop. The
+/// other jobs of the guest are in groups of their own.
+struct ProcessGroup(u32);
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        // SAFETY: kill takes no pointers; a negative pid names a process group.
+        unsafe {
+            libc::kill(-(self.0 as libc::pid_t), libc::SIGKILL);
+        }
+    }
+}
+
 fn resolve_work_dir(path: PathBuf) -> Result<PathBuf> {
     if path.is_absolute() {
         return Ok(path);
@@ -368,6 +983,7 @@ async fn read_stream_limited<R>(
     mut reader: R,
     kind: StreamKind,
     tx: mpsc::Sender<StreamResult>,
+    tap: Option<mpsc::UnboundedSender<Vec<u8>>>,
 ) -> Result<Vec<u8>>
 where
     R: tokio::io::AsyncRead + Unpin,
@@ -388,6 +1004,9 @@ where
         let remaining = MAX_OUTPUT_BYTES.saturating_sub(output.len());
         let to_copy = remaining.min(read);
         output.extend_from_slice(&chunk[..to_copy]);
+        if let Some(tap) = &tap {
+            let _ = tap.send(chunk[..to_copy].to_vec());
+        }
 
         if read > remaining {
             let _ = tx.send(StreamResult::Exceeded(kind)).await;
@@ -0,0 +1,71 @@
+//! Pseudo-filesystems of the guest.
+//!
+//! `init.sh` mounts them before it execs the agent. Scratch run images have no
+//! shell to run it: the agent is their `/init`, and mounts them itself.
+
+use std::ffi::CString;
+use tracing::warn;
+
+/// Source, mount point and type of each filesystem, parents first.
+const MOUNTS: &[(&str, &str, &str)] = &[
+    ("proc", "/proc", "proc"),
+    ("sys", "/sys", "sysfs"),
+    ("devtmpfs", "/dev", "devtmpfs"),
+    ("tmpfs", "/run", "tmpfs"),
+    // Jobs run in cgroups of their own, with the limits of their request.
+    ("cgroup2", "/sys/fs/cgroup", "cgroup2"),
+];
+
+/// Mounts what `init.sh` would have, when the agent runs as init. Filesystems
+/// already mounted are left alone.
+pub fn mount_pseudo_filesystems() {
+    if std::process::id() != 1 {
+        return;
+    }
+    for (source, target, fstype) in MOUNTS {
+        if let Err(e) = mount(source, target, fstype) {
+            warn!("Cannot mount {} on {}: {}", fstype, target, e);
+        }
+    }
+}
+
+fn mount(source: &str, target: &str, fstype: &str) -> std::io::Result<()> {
+    std::fs::create_dir_all(target)?;
+    let source = CString::new(source)?;
+    let target = CString::new(target)?;
+    let fstype = CString::new(fstype)?;
+    // SAFETY: the strings outlive the call, and a null data pointer is allowed.
+    let mounted = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            0,
+            std::ptr::null(),
+        )
+    };
+    if mounted < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EBUSY) {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parents_are_mounted_first() {
+        for (i, (_, target, _)) in MOUNTS.iter().enumerate() {
+            for (_, parent, _) in &MOUNTS[i + 1..] {
+                assert!(
+                    !target.starts_with(&format!("{parent}/")),
+                    "{parent} is mounted after {target}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Address of the guest network interface, moved on request of the backend.
+//!
+//! The kernel sets `eth0` up at boot, from the `ip=` parameter. A VM restored
+//! from a template snapshot wakes up with the address the template was baked
+//! with, and the MAC address the driver drew at boot, which all its clones share:
+//! the backend gives each one its own before it runs a job.
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+/// Interface the kernel configured at boot.
+const INTERFACE: &str = "eth0";
+
+/// `struct rtentry` of linux/route.h, which libc only has for some targets.
+#[repr(C)]
+struct RtEntry {
+    rt_pad1: libc::c_ulong,
+    rt_dst: libc::sockaddr,
+    rt_gateway: libc::sockaddr,
+    rt_genmask: libc::sockaddr,
+    rt_flags: libc::c_ushort,
+    rt_pad2: libc::c_short,
+    rt_pad3: libc::c_ulong,
+    rt_pad4: *mut libc::c_void,
+    rt_metric: libc::c_short,
+    rt_dev: *mut libc::c_char,
+    rt_mtu: libc::c_ulong,
+    rt_window: libc::c_ulong,
+    rt_irtt: libc::c_ushort,
+}
+
+/// Gives the interface a new random MAC address, and `ip` in a `/prefix_len`
+/// network with the default route through `gateway`. Changing the address drops
+/// the routes of the old one.
+pub fn readdress(ip: Ipv4Addr, prefix_len: u8, gateway: Ipv4Addr) -> io::Result<()> {
+    if prefix_len > 32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "prefix length over 32",
+        ));
+    }
+    // SAFETY: plain socket creation, the descriptor is owned right after.
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just opened and is not owned elsewhere.
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    set_random_mac(&socket)?;
+    set_interface_addr(&socket, libc::SIOCSIFADDR, ip)?;
+    set_interface_addr(&socket, libc::SIOCSIFNETMASK, netmask(prefix_len))?;
+
+    let mut route = RtEntry {
+        rt_pad1: 0,
+        rt_dst: sockaddr(Ipv4Addr::UNSPECIFIED),
+        rt_gateway: sockaddr(gateway),
+        rt_genmask: sockaddr(Ipv4Addr::UNSPECIFIED),
+        rt_flags: libc::RTF_UP | libc::RTF_GATEWAY,
+        rt_pad2: 0,
+        rt_pad3: 0,
+        rt_pad4: std::ptr::null_mut(),
+        rt_metric: 0,
+        rt_dev: std::ptr::null_mut(),
+        rt_mtu: 0,
+        rt_window: 0,
+        rt_irtt: 0,
+    };
+    // SAFETY: `route` is a valid rtentry that outlives the call.
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCADDRT as _, &mut route) } != 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EEXIST) {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// A random unicast, locally administered MAC address, as the driver draws at boot.
+fn set_random_mac(socket: &OwnedFd) -> io::Result<()> {
+    let mut mac = [0u8; 6];
+    // SAFETY: `mac` is writable for its length.
+    if unsafe { libc::getrandom(mac.as_mut_ptr().cast(), mac.len(), 0) } != mac.len() as isize {
+        return Err(io::Error::last_os_error());
+    }
+    mac[0] = (mac[0] & 0xfe) | 0x02;
+
+    let mut hwaddr: libc::sockaddr = sockaddr(Ipv4Addr::UNSPECIFIED);
+    hwaddr.sa_family = libc::ARPHRD_ETHER;
+    for (dst, &src) in hwaddr.sa_data.iter_mut().zip(&mac) {
+        *dst = src as libc::c_char;
+    }
+    set_interface(socket, libc::SIOCSIFHWADDR, hwaddr)
+}
+
+fn set_interface_addr(socket: &OwnedFd, request: libc::c_ulong, addr: Ipv4Addr) -> io::Result<()> {
+    set_interface(socket, request, sockaddr(addr))
+}
+
+fn set_interface(socket: &OwnedFd, request: libc::c_ulong, addr: libc::sockaddr) -> io::Result<()> {
+    // SAFETY: an all-zero ifreq is valid, a name and an address are set below.
+    let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, &src) in ifreq.ifr_name.iter_mut().zip(INTERFACE.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+    ifreq.ifr_ifru.ifru_addr = addr;
+    // SAFETY: `ifreq` names the interface, and outlives the call.
+    if unsafe { libc::ioctl(socket.as_raw_fd(), request as _, &mut ifreq) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn sockaddr(addr: Ipv4Addr) -> libc::sockaddr {
+    let addr_in = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(addr).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: both are 16 bytes, `sockaddr_in` is the IPv4 form of `sockaddr`.
+    unsafe { std::mem::transmute::<libc::sockaddr_in, libc::sockaddr>(addr_in) }
+}
+
+fn netmask(prefix_len: u8) -> Ipv4Addr {
+    Ipv4Addr::from(
+        u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_netmask() {
+        assert_eq!(netmask(24), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(netmask(30), Ipv4Addr::new(255, 255, 255, 252));
+        assert_eq!(netmask(32), Ipv4Addr::BROADCAST);
+        assert_eq!(netmask(0), Ipv4Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn test_rtentry_layout() {
+        // As in linux/route.h on 64-bit targets.
+        assert_eq!(std::mem::size_of::<RtEntry>(), 120);
+        assert_eq!(std::mem::offset_of!(RtEntry, rt_flags), 56);
+        assert_eq!(std::mem::offset_of!(RtEntry, rt_dev), 88);
+    }
+}
@@ -0,0 +1,175 @@
+//! Clean filesystem between the jobs of a pooled VM.
+//!
+//! Once the host asks for a reset, jobs run chrooted in an overlay of the guest
+//! root: the initramfs is its read-only lower layer, and a tmpfs upper layer
+//! takes every write. A reset kills what earlier jobs left running and swaps the
+//! upper layer for an empty one, which takes milliseconds where a reboot takes
+//! seconds.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// Where the tmpfs holding the upper layer and the merged root is mounted.
+pub const OVERLAY_DIR: &str = "/run/cloude-overlay";
+
+/// Guest filesystems jobs see in the overlay as they are.
+const BOUND_DIRS: [&str; 3] = ["/proc", "/dev", "/sys"];
+
+/// A mounted overlay of the guest root.
+pub struct Overlay {
+    dir: PathBuf,
+}
+
+impl Overlay {
+    /// Mounts a tmpfs at `dir`, then the overlay of `/` in it.
+    pub fn mount(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        mount(Some("tmpfs"), dir, Some("tmpfs"), 0, Some("mode=0755"))?;
+        let overlay = Overlay {
+            dir: dir.to_path_buf(),
+        };
+        if let Err(e) = overlay.mount_layers() {
+            let _ = overlay.unmount();
+            return Err(e);
+        }
+        Ok(overlay)
+    }
+
+    fn mount_layers(&self) -> io::Result<()> {
+        let upper = self.dir.join("upper");
+        let work = self.dir.join("work");
+        let root = self.root();
+        for dir in [&upper, &work, &root] {
+            fs::create_dir(dir)?;
+        }
+        let options = format!(
+            "lowerdir=/,upperdir={},workdir={}",
+            upper.display(),
+            work.display()
+        );
+        mount(Some("overlay"), &root, Some("overlay"), 0, Some(&options))?;
+        for dir in BOUND_DIRS {
+            mount(
+                Some(dir),
+                &host_path(&root, Path::new(dir)),
+                None,
+                libc::MS_BIND | libc::MS_REC,
+                None,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Root jobs are chrooted in.
+    pub fn root(&self) -> PathBuf {
+        self.dir.join("merged")
+    }
+
+    /// Detaches the overlay and its tmpfs, dropping everything jobs wrote.
+    pub fn unmount(self) -> io::Result<()> {
+        let dir = cstring(&self.dir)?;
+        // SAFETY: `dir` is a valid C string that outlives the call.
+        if unsafe { libc::umount2(dir.as_ptr(), libc::MNT_DETACH) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Kills every process but the agent, when it runs as init: what earlier jobs
+/// left running in the background.
+pub fn kill_leftovers() {
+    if std::process::id() != 1 {
+        return;
+    }
+    // SAFETY: kill takes no pointers; -1 spares the calling process.
+    unsafe {
+        libc::kill(-1, libc::SIGKILL);
+    }
+}
+
+/// Where the agent finds `path` of a job chrooted in `root`.
+pub fn host_path(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Where a job chrooted in `root` finds `path` of the agent.
+pub fn job_path(root: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix(root) {
+        Ok(relative) => Path::new("/").join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Makes `cmd` run chrooted in `root`. Its working directory, set before the
+/// chroot, must be inside `root`.
+pub fn enter(cmd: &mut Command, root: &Path) -> io::Result<()> {
+    let root = cstring(root)?;
+    // SAFETY: the closure runs between fork and exec and only makes a system call.
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::chroot(root.as_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+fn mount(
+    source: Option<&str>,
+    target: &Path,
+    fstype: Option<&str>,
+    flags: libc::c_ulong,
+    data: Option<&str>,
+) -> io::Result<()> {
+    let source = source.map(CString::new).transpose()?;
+    let target = cstring(target)?;
+    let fstype = fstype.map(CString::new).transpose()?;
+    let data = data.map(CString::new).transpose()?;
+    let ptr = |s: &Option<CString>| s.as_ref().map_or(std::ptr::null(), |s| s.as_ptr());
+    // SAFETY: every pointer is null or a valid C string that outlives the call.
+    let result = unsafe {
+        libc::mount(
+            ptr(&source),
+            target.as_ptr(),
+            ptr(&fstype),
+            flags,
+            ptr(&data).cast(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_across_the_chroot() {
+        let root = Path::new("/run/cloude-overlay/merged");
+        let host = host_path(root, Path::new("/build/job-1/code.py"));
+        assert_eq!(
+            host,
+            Path::new("/run/cloude-overlay/merged/build/job-1/code.py")
+        );
+        assert_eq!(job_path(root, &host), Path::new("/build/job-1/code.py"));
+
+        // Without an overlay, jobs see the guest root as it is.
+        let root = Path::new("/");
+        assert_eq!(host_path(root, Path::new("/build")), Path::new("/build"));
+        assert_eq!(job_path(root, Path::new("/build")), Path::new("/build"));
+    }
+}
@@ -0,0 +1,116 @@
+//! ACPI power button of the VM.
+//!
+//! The host presses it to ask for a clean shutdown. The kernel reports the
+//! press as a `KEY_POWER` event on the "Power Button" input device; the agent
+//! then stops accepting requests and, as PID 1, syncs and powers the VM off.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+
+const INPUT_CLASS_DIR: &str = "/sys/class/input";
+const POWER_BUTTON_NAME: &str = "Power Button";
+
+/// Size of a `struct input_event` on 64-bit Linux: a timeval, then type, code and value.
+const INPUT_EVENT_SIZE: usize = 24;
+const EV_KEY: u16 = 0x01;
+const KEY_POWER: u16 = 116;
+
+/// Resolves when the power button is pressed, never when the VM has none.
+pub async fn pressed() {
+    let Some(device) = find_device(Path::new(INPUT_CLASS_DIR)) else {
+        info!("No power button, shutdown requests are ignored");
+        return std::future::pending().await;
+    };
+    if let Err(e) = wait_for_press(&device).await {
+        warn!("Cannot read power button {}: {}", device.display(), e);
+        std::future::pending::<()>().await;
+    }
+    info!("Power button pressed, shutting down");
+}
+
+/// Flushes the filesystems and powers the VM off, when the agent runs as init.
+pub fn power_off() {
+    if std::process::id() != 1 {
+        return;
+    }
+    // SAFETY: sync and reboot take no pointers; reboot only returns on failure.
+    unsafe {
+        libc::sync();
+        libc::reboot(libc::RB_POWER_OFF);
+    }
+    warn!("Power off failed: {}", std::io::Error::last_os_error());
+}
+
+/// Finds the event device node of the power button among the input devices.
+fn find_device(input_class: &Path) -> Option<PathBuf> {
+    fs::read_dir(input_class)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("event"))
+        .find(|name| {
+            fs::read_to_string(input_class.join(name).join("device/name"))
+                .is_ok_and(|device| device.trim() == POWER_BUTTON_NAME)
+        })
+        .map(|name| Path::new("/dev/input").join(name))
+}
+
+async fn wait_for_press(device: &Path) -> std::io::Result<()> {
+    let mut file = File::open(device).await?;
+    let mut event = [0u8; INPUT_EVENT_SIZE];
+    loop {
+        file.read_exact(&mut event).await?;
+        if is_power_press(&event) {
+            return Ok(());
+        }
+    }
+}
+
+fn is_power_press(event: &[u8; INPUT_EVENT_SIZE]) -> bool {
+    let kind = u16::from_ne_bytes([event[16], event[17]]);
+    let code = u16::from_ne_bytes([event[18], event[19]]);
+    let value = i32::from_ne_bytes([event[20], event[21], event[22], event[23]]);
+    kind == EV_KEY && code == KEY_POWER && value == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: u16, code: u16, value: i32) -> [u8; INPUT_EVENT_SIZE] {
+        let mut event = [0u8; INPUT_EVENT_SIZE];
+        event[16..18].copy_from_slice(&kind.to_ne_bytes());
+        event[18..20].copy_from_slice(&code.to_ne_bytes());
+        event[20..24].copy_from_slice(&value.to_ne_bytes());
+        event
+    }
+
+    #[test]
+    fn test_is_power_press() {
+        assert!(is_power_press(&event(EV_KEY, KEY_POWER, 1)));
+        // Release, key repeat and the EV_SYN that follows are not presses.
+        assert!(!is_power_press(&event(EV_KEY, KEY_POWER, 0)));
+        assert!(!is_power_press(&event(EV_KEY, KEY_POWER, 2)));
+        assert!(!is_power_press(&event(0, 0, 0)));
+    }
+
+    #[test]
+    fn test_find_device() {
+        let dir = std::env::temp_dir().join(format!("agent-power-{}", std::process::id()));
+        for (event, name) in [
+            ("event0", "AT Translated Set 2 keyboard"),
+            ("event1", "Power Button"),
+        ] {
+            fs::create_dir_all(dir.join(event).join("device")).unwrap();
+            fs::write(dir.join(event).join("device/name"), format!("{name}\n")).unwrap();
+        }
+        fs::create_dir_all(dir.join("input1")).unwrap();
+
+        assert_eq!(find_device(&dir), Some(PathBuf::from("/dev/input/event1")));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(find_device(&dir), None);
+    }
+}
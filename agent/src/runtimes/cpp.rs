@@ -1,5 +1,5 @@
 use super::LanguageRuntime;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub struct CppRuntime;
 
@@ -20,6 +20,10 @@ impl LanguageRuntime for CppRuntime {
         ))
     }
 
+    fn binary_path(&self, work_dir: &Path) -> Option<PathBuf> {
+        Some(work_dir.join("bin"))
+    }
+
     fn run_step(&self, _source_path: &Path, work_dir: &Path) -> (String, Vec<String>) {
         (work_dir.join("bin").display().to_string(), vec![])
     }
@@ -1,5 +1,5 @@
 use super::LanguageRuntime;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub struct JavaRuntime;
 
@@ -21,6 +21,10 @@ impl LanguageRuntime for JavaRuntime {
         ))
     }
 
+    fn binary_path(&self, work_dir: &Path) -> Option<PathBuf> {
+        Some(work_dir.join("bin.jar"))
+    }
+
     fn run_step(&self, _source_path: &Path, work_dir: &Path) -> (String, Vec<String>) {
         (
             "java".to_string(),
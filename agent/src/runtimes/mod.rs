@@ -6,7 +6,7 @@ pub mod node;
 pub mod python;
 pub mod rust;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub trait LanguageRuntime: Send + Sync {
     fn source_extension(&self) -> &'static str;
@@ -24,6 +24,12 @@ pub trait LanguageRuntime: Send + Sync {
             .map(|step| vec![step])
     }
 
+    /// File the compile step builds and the run step runs, which a later run of the
+    /// same code can be handed instead of compiling it again.
+    fn binary_path(&self, _work_dir: &Path) -> Option<PathBuf> {
+        None
+    }
+
     fn run_step(&self, source_path: &Path, work_dir: &Path) -> (String, Vec<String>);
 
     fn run_candidates(&self, source_path: &Path, work_dir: &Path) -> Vec<(String, Vec<String>)> {
@@ -1,6 +1,6 @@
 use super::LanguageRuntime;
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub struct RustRuntime;
 
@@ -62,6 +62,10 @@ impl LanguageRuntime for RustRuntime {
         )
     }
 
+    fn binary_path(&self, work_dir: &Path) -> Option<PathBuf> {
+        Some(work_dir.join("bin"))
+    }
+
     fn run_step(&self, _source_path: &Path, work_dir: &Path) -> (String, Vec<String>) {
         (work_dir.join("bin").display().to_string(), vec![])
     }
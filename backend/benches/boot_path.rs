@@ -0,0 +1,175 @@
+//! Boot path benchmarks: runtime image build and end-to-end job latency.
+//!
+//! ```bash
+//! cargo bench -p backend --bench boot_path -- --save-baseline main   # on the reference commit
+//! cargo bench -p backend --bench boot_path -- --baseline main        # on the change
+//! ```
+//!
+//! Image builds need the agent binary and init script (`AGENT_BINARY_PATH`, `INIT_SCRIPT_PATH`).
+//! End-to-end runs boot real VMs: they need root, `/dev/kvm`, `VM_KERNEL_PATH` and
+//! `CLOUDE_BENCH_E2E=1`, and must not run next to a backend using the same bridge.
+
+use backend::initramfs_manager::{InitramfsLanguage, get_languages_config};
+use backend::ip_manager::IpManager;
+use backend::redaction::Redactor;
+use backend::vm_lifecycle::{VmConfig, VmHandle};
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use std::env;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+const BENCH_BRIDGE: &str = "cloudebench0";
+const BENCH_HOST_IP: Ipv4Addr = Ipv4Addr::new(10, 39, 254, 1);
+const BENCH_IP_MASK: u8 = 24;
+
+fn env_or(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+fn languages() -> Vec<InitramfsLanguage> {
+    get_languages_config(&env_or("LANGUAGES_CONFIG_PATH", "./config/languages.json"))
+        .expect("languages config should be readable")
+}
+
+/// Smallest program printing a line, per runtime.
+fn hello_world(language: &str) -> Option<&'static str> {
+    match language {
+        "python" => Some("print('hello')"),
+        "node" => Some("console.log('hello')"),
+        "rust" => Some("fn main() { println!(\"hello\"); }"),
+        _ => None,
+    }
+}
+
+fn build(runtime: &Runtime, language: &InitramfsLanguage, dir: &Path) {
+    let agent_binary = env_or("AGENT_BINARY_PATH", "./cloude-agentd");
+    let init_script = env_or("INIT_SCRIPT_PATH", "./init.sh");
+    runtime
+        .block_on(language.clone().setup_initramfs(
+            &agent_binary,
+            &init_script,
+            dir.to_str().unwrap(),
+        ))
+        .expect("initramfs build should succeed");
+}
+
+fn initramfs_build(c: &mut Criterion) {
+    if !Path::new(&env_or("AGENT_BINARY_PATH", "./cloude-agentd")).exists() {
+        eprintln!("Skipping initramfs benchmarks: agent binary not found (set AGENT_BINARY_PATH)");
+        return;
+    }
+
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("initramfs_build");
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(60));
+
+    for language in languages() {
+        group.bench_function(format!("{}/cold", language.name), |b| {
+            b.iter_batched(
+                || TempDir::new().unwrap(),
+                |dir| build(&runtime, &language, dir.path()),
+                BatchSize::PerIteration,
+            )
+        });
+
+        let cached = TempDir::new().unwrap();
+        build(&runtime, &language, cached.path());
+        group.bench_function(format!("{}/cached", language.name), |b| {
+            b.iter(|| build(&runtime, &language, cached.path()))
+        });
+    }
+
+    group.finish();
+}
+
+fn hello_world_latency(c: &mut Criterion) {
+    if env::var("CLOUDE_BENCH_E2E").as_deref() != Ok("1") {
+        eprintln!("Skipping end-to-end benchmarks: set CLOUDE_BENCH_E2E=1 to boot VMs");
+        return;
+    }
+
+    let runtime = Runtime::new().unwrap();
+    runtime
+        .block_on(virt::network::setup_bridge(
+            BENCH_BRIDGE.to_string(),
+            BENCH_HOST_IP,
+            BENCH_IP_MASK,
+        ))
+        .expect("bench bridge should be set up");
+
+    let state = TempDir::new().unwrap();
+    let ip_manager = Arc::new(Mutex::new(
+        IpManager::new(
+            state.path().join("ip_allocations.json"),
+            Ipv4Addr::new(10, 39, 254, 2),
+            Ipv4Addr::new(10, 39, 254, 254),
+        )
+        .unwrap(),
+    ));
+    let config = VmConfig {
+        kernel_path: PathBuf::from(env_or("VM_KERNEL_PATH", "./vmlinux")),
+        initramfs_dir: PathBuf::from(env_or("VM_INITRAMFS_DIR", "./tmp")),
+        bridge_name: Some(BENCH_BRIDGE.to_string()),
+        tenant: None,
+        vcpus: 1,
+        memory_mb: 512,
+        log_guest_console: false,
+        serial_log: None,
+        redactor: Redactor::default(),
+        cmdline_extra: None,
+        acpi: false,
+        max_vcpus: 0,
+        max_memory_mb: 0,
+        traffic_accounting: false,
+        mtu: None,
+        merge_memory: false,
+    };
+    let client = reqwest::Client::new();
+
+    let mut group = c.benchmark_group("hello_world");
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(60));
+
+    for language in languages() {
+        let Some(code) = hello_world(&language.name) else {
+            continue;
+        };
+        build(&runtime, &language, &config.initramfs_dir);
+
+        let mut run = 0_u64;
+        group.bench_function(&language.name, |b| {
+            b.iter(|| {
+                run += 1;
+                runtime.block_on(async {
+                    let mut vm = VmHandle::create(
+                        format!("bench-{}-{}", language.name, run),
+                        &language.name,
+                        &config,
+                        Arc::clone(&ip_manager),
+                    )
+                    .await
+                    .expect("VM should boot");
+
+                    let response = client
+                        .post(format!("{}/execute", vm.agent_url()))
+                        .json(&serde_json::json!({ "language": language.name, "code": code }))
+                        .send()
+                        .await
+                        .and_then(|r| r.error_for_status());
+                    vm.destroy().await;
+                    response.expect("hello world should run");
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, initramfs_build, hello_world_latency);
+criterion_main!(benches);
@@ -6,6 +6,8 @@ mount -t proc proc /proc || true
 mount -t sysfs sys /sys || true
 mount -t devtmpfs devtmpfs /dev || true
 mount -t tmpfs tmpfs /run || true
+# Jobs run in cgroups of their own, with the limits of their request
+mount -t cgroup2 cgroup2 /sys/fs/cgroup || true
 
 echo "[initramfs] booting..."
 
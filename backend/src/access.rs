@@ -0,0 +1,609 @@
+//! API keys and the roles they are given.
+//!
+//! With `API_ADMIN_KEY` set, every request to the API needs a key, sent as
+//! `Authorization: Bearer <key>`: that one, or a key an admin created with
+//! `POST /admin/keys`. The role of the key decides which routes it may call,
+//! so that a CI key can only deploy and the key of an app can only invoke.
+//! Without `API_ADMIN_KEY` the API stays open, as it was before keys.
+//!
+//! Keys are kept as their SHA-256 hash: the secret of a key is only shown
+//! when it is created.
+//!
+//! A key also says who its requests come from: they act as its tenant, in its
+//! namespace, and a `X-Cloude-User` or `x-cloude-namespace` header naming
+//! others gets `403`. Only admin keys, which could make a key for anyone, act
+//! as the headers say.
+
+use crate::namespaces::{DEFAULT_NAMESPACE, NAMESPACE_HEADER};
+use axum::Json;
+use axum::extract::{Request, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use cloude_types::{ApiKeyInfo, CreatedApiKey, ErrorResponse, Role};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Id the key of `API_ADMIN_KEY` is known by.
+pub const ADMIN_KEY_ID: &str = "admin";
+
+/// Header naming the tenant of a request.
+pub const USER_HEADER: &str = "x-cloude-user";
+
+/// Tenant named by the `X-Cloude-User` header of a request, if any.
+pub fn header_tenant(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(USER_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+/// Errors that can occur while reading or writing the API keys.
+#[derive(Debug)]
+pub enum ApiKeyError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl std::fmt::Display for ApiKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiKeyError::Io(e) => write!(f, "IO error: {}", e),
+            ApiKeyError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiKeyError {}
+
+impl From<std::io::Error> for ApiKeyError {
+    fn from(err: std::io::Error) -> Self {
+        ApiKeyError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ApiKeyError {
+    fn from(err: serde_json::Error) -> Self {
+        ApiKeyError::Json(err)
+    }
+}
+
+/// What a route does, and so which roles may call it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Reads jobs, functions, VMs and what the backend serves.
+    Read,
+    /// Uploads artifacts and deploys functions.
+    Deploy,
+    /// Runs jobs and functions, and acts on their VMs.
+    Invoke,
+    /// Manages keys and the backend itself.
+    Admin,
+}
+
+impl Permission {
+    pub fn granted_to(self, role: Role) -> bool {
+        match role {
+            Role::Admin => true,
+            Role::Deployer => matches!(self, Permission::Read | Permission::Deploy),
+            Role::Invoker => matches!(self, Permission::Read | Permission::Invoke),
+            Role::Viewer => self == Permission::Read,
+        }
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Permission::Read => "read",
+            Permission::Deploy => "deploy",
+            Permission::Invoke => "invoke",
+            Permission::Admin => "administer",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Key a request was made with, in its extensions once authenticated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub key_id: String,
+    pub role: Role,
+    /// Sandbox profiles the jobs of the key may run with, any when `None`.
+    pub profiles: Option<Vec<String>>,
+    /// Tenant the key acts as, anonymous when `None`.
+    pub tenant: Option<String>,
+    /// Namespace the key acts in, `default` when `None`.
+    pub namespace: Option<String>,
+}
+
+impl Caller {
+    /// Whether the key acts as the tenant and in the namespace its requests
+    /// name, rather than its own.
+    pub fn acts_as_anyone(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// Tenant of a request of the key with `headers`.
+    pub fn tenant(&self, headers: &HeaderMap) -> Option<String> {
+        if self.acts_as_anyone() {
+            header_tenant(headers).map(str::to_string)
+        } else {
+            self.tenant.clone()
+        }
+    }
+
+    /// Namespace of the requests of the key, `None` when they name it.
+    pub fn namespace(&self) -> Option<&str> {
+        (!self.acts_as_anyone()).then(|| self.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE))
+    }
+
+    /// Why the key may not make a request with `headers`: they name another
+    /// tenant or namespace than its own.
+    fn check(&self, headers: &HeaderMap) -> Result<(), String> {
+        if self.acts_as_anyone() {
+            return Ok(());
+        }
+        if let Some(named) = header_tenant(headers)
+            && self.tenant.as_deref() != Some(named)
+        {
+            return Err(match &self.tenant {
+                Some(tenant) => format!("Key {} acts as tenant {tenant}, not {named}", self.key_id),
+                None => format!(
+                    "Key {} has no tenant, it cannot act as {named}",
+                    self.key_id
+                ),
+            });
+        }
+        let namespace = self.namespace().unwrap_or(DEFAULT_NAMESPACE);
+        if let Some(named) = headers.get(NAMESPACE_HEADER)
+            && named.to_str().ok().map(str::trim) != Some(namespace)
+        {
+            return Err(format!(
+                "Key {} acts in namespace {namespace}, not {}",
+                self.key_id,
+                String::from_utf8_lossy(named.as_bytes())
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the key may run jobs with `profile`, `None` for jobs without one.
+    pub fn may_use(&self, profile: Option<&str>) -> bool {
+        match &self.profiles {
+            None => true,
+            Some(allowed) => profile.is_some_and(|profile| allowed.iter().any(|p| p == profile)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredKey {
+    #[serde(flatten)]
+    info: ApiKeyInfo,
+    /// Hex SHA-256 of the key.
+    sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct ApiKeysState {
+    keys: Vec<StoredKey>,
+}
+
+/// The keys of the API, persisted to a JSON file so they survive restarts.
+#[derive(Debug)]
+pub struct ApiKeys {
+    file_path: PathBuf,
+    /// Hash of `API_ADMIN_KEY`. Keys are only checked with one.
+    admin: Option<String>,
+    state: Mutex<ApiKeysState>,
+}
+
+fn sha256(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+impl ApiKeys {
+    /// Keys of `file_path`, checked when there is an `admin_key`.
+    pub fn new<P: AsRef<Path>>(file_path: P, admin_key: Option<&str>) -> Result<Self, ApiKeyError> {
+        let file_path = file_path.as_ref().to_path_buf();
+        let state = match std::fs::read_to_string(&file_path) {
+            Ok(contents) if contents.trim().is_empty() => ApiKeysState::default(),
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ApiKeysState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            file_path,
+            admin: admin_key.map(sha256),
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Whether requests need a key.
+    pub fn enforced(&self) -> bool {
+        self.admin.is_some()
+    }
+
+    /// Who holds `key`, if it is a key of the API.
+    pub fn authenticate(&self, key: &str) -> Option<Caller> {
+        let hash = sha256(key);
+        if self.admin.as_deref() == Some(hash.as_str()) {
+            return Some(Caller {
+                key_id: ADMIN_KEY_ID.to_string(),
+                role: Role::Admin,
+                profiles: None,
+                tenant: None,
+                namespace: None,
+            });
+        }
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state
+            .keys
+            .iter()
+            .find(|key| key.sha256 == hash)
+            .map(|key| Caller {
+                key_id: key.info.id.clone(),
+                role: key.info.role,
+                profiles: key.info.profiles.clone(),
+                tenant: key.info.tenant.clone(),
+                namespace: key.info.namespace.clone(),
+            })
+    }
+
+    /// Creates a key with `role`, whose jobs may only run with `profiles` if
+    /// set, acting as `tenant` in `namespace`. Its secret is only in the answer.
+    pub fn create(
+        &self,
+        role: Role,
+        name: Option<String>,
+        profiles: Option<Vec<String>>,
+        tenant: Option<String>,
+        namespace: Option<String>,
+    ) -> Result<CreatedApiKey, ApiKeyError> {
+        // v4 UUIDs come from the random generator of the OS: 244 random bits.
+        let key = format!(
+            "ck_{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let info = ApiKeyInfo {
+            id: uuid::Uuid::new_v4().to_string(),
+            name,
+            role,
+            profiles,
+            tenant,
+            namespace,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.keys.push(StoredKey {
+            info: info.clone(),
+            sha256: sha256(&key),
+        });
+        if let Err(e) = self.write_state(&state) {
+            state.keys.pop();
+            return Err(e);
+        }
+        Ok(CreatedApiKey { info, key })
+    }
+
+    /// Every key, the oldest first.
+    pub fn list(&self) -> Vec<ApiKeyInfo> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.keys.iter().map(|key| key.info.clone()).collect()
+    }
+
+    /// Gives key `id` `role` and `profiles`, and returns it, if there is such a key.
+    pub fn assign(
+        &self,
+        id: &str,
+        role: Role,
+        profiles: Option<Vec<String>>,
+    ) -> Result<Option<ApiKeyInfo>, ApiKeyError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(index) = state.keys.iter().position(|key| key.info.id == id) else {
+            return Ok(None);
+        };
+        let previous = state.keys[index].info.clone();
+        state.keys[index].info.role = role;
+        state.keys[index].info.profiles = profiles;
+        if let Err(e) = self.write_state(&state) {
+            state.keys[index].info = previous;
+            return Err(e);
+        }
+        Ok(Some(state.keys[index].info.clone()))
+    }
+
+    /// Deletes key `id`, and returns whether there was one.
+    pub fn revoke(&self, id: &str) -> Result<bool, ApiKeyError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(index) = state.keys.iter().position(|key| key.info.id == id) else {
+            return Ok(false);
+        };
+        let removed = state.keys.remove(index);
+        if let Err(e) = self.write_state(&state) {
+            state.keys.insert(index, removed);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    fn write_state(&self, state: &ApiKeysState) -> Result<(), ApiKeyError> {
+        let json = serde_json::to_string_pretty(state)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.file_path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, key) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| key.trim())
+        .filter(|key| !key.is_empty())
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    (status, Json(ErrorResponse::new(message))).into_response()
+}
+
+/// Lets requests with a key of the API through, and tells the routes who made
+/// them with a [`Caller`]. Requests naming another tenant or namespace than
+/// their key get `403`. Lets every request through when keys are not checked.
+pub async fn authenticate(
+    State(keys): State<Arc<ApiKeys>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if !keys.enforced() {
+        return next.run(request).await;
+    }
+    let caller = match bearer(request.headers()) {
+        Some(key) => keys.authenticate(key),
+        None => {
+            let mut response = error(
+                StatusCode::UNAUTHORIZED,
+                "Missing API key, send it as Authorization: Bearer <key>".to_string(),
+            );
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            return response;
+        }
+    };
+    match caller {
+        Some(caller) => {
+            if let Err(message) = caller.check(request.headers()) {
+                return error(StatusCode::FORBIDDEN, message);
+            }
+            request.extensions_mut().insert(caller);
+            next.run(request).await
+        }
+        None => {
+            let mut response = error(StatusCode::UNAUTHORIZED, "Invalid API key".to_string());
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                "Bearer error=\"invalid_token\"".parse().unwrap(),
+            );
+            response
+        }
+    }
+}
+
+/// Lets the requests of callers whose role has the permission through, and
+/// answers the others with `403`. Goes after [`authenticate`].
+pub async fn require(
+    State(permission): State<Permission>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match request.extensions().get::<Caller>() {
+        Some(caller) if !permission.granted_to(caller.role) => error(
+            StatusCode::FORBIDDEN,
+            format!(
+                "Role {} of key {} is not allowed to {}",
+                caller.role, caller.key_id, permission
+            ),
+        ),
+        _ => next.run(request).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::middleware;
+    use axum::routing::{get, post};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_roles() {
+        assert!(Permission::Admin.granted_to(Role::Admin));
+        assert!(Permission::Deploy.granted_to(Role::Deployer));
+        assert!(!Permission::Invoke.granted_to(Role::Deployer));
+        assert!(Permission::Invoke.granted_to(Role::Invoker));
+        assert!(!Permission::Deploy.granted_to(Role::Invoker));
+        assert!(Permission::Read.granted_to(Role::Viewer));
+        assert!(!Permission::Invoke.granted_to(Role::Viewer));
+        assert!(!Permission::Admin.granted_to(Role::Deployer));
+    }
+
+    #[test]
+    fn test_keys_survive_restarts() {
+        let file = NamedTempFile::new().unwrap();
+        let keys = ApiKeys::new(file.path(), Some("root")).unwrap();
+        assert!(keys.enforced());
+        assert_eq!(keys.authenticate("root").unwrap().role, Role::Admin);
+
+        let ci = keys
+            .create(Role::Deployer, Some("ci".to_string()), None, None, None)
+            .unwrap();
+        let app = keys
+            .create(
+                Role::Invoker,
+                None,
+                Some(vec!["strict".to_string()]),
+                None,
+                None,
+            )
+            .unwrap();
+        assert_ne!(ci.key, app.key);
+        assert_eq!(keys.authenticate(&ci.key).unwrap().key_id, ci.info.id);
+        assert_eq!(keys.authenticate("ck_guess"), None);
+
+        let app_caller = keys.authenticate(&app.key).unwrap();
+        assert!(app_caller.may_use(Some("strict")));
+        assert!(!app_caller.may_use(Some("networked")));
+        assert!(!app_caller.may_use(None));
+        assert!(keys.authenticate(&ci.key).unwrap().may_use(None));
+
+        keys.assign(&ci.info.id, Role::Viewer, None)
+            .unwrap()
+            .unwrap();
+        assert!(keys.revoke(&app.info.id).unwrap());
+        assert!(!keys.revoke(&app.info.id).unwrap());
+        assert_eq!(keys.assign("missing", Role::Admin, None).unwrap(), None);
+
+        let keys = ApiKeys::new(file.path(), Some("root")).unwrap();
+        assert_eq!(keys.list().len(), 1);
+        assert_eq!(keys.authenticate(&ci.key).unwrap().role, Role::Viewer);
+        assert_eq!(keys.authenticate(&app.key), None);
+        // Only the hash of a key is written.
+        let stored = std::fs::read_to_string(file.path()).unwrap();
+        assert!(!stored.contains(&ci.key));
+    }
+
+    #[test]
+    fn test_keys_act_as_their_tenant() {
+        let file = NamedTempFile::new().unwrap();
+        let keys = ApiKeys::new(file.path(), Some("root")).unwrap();
+        let team = keys
+            .create(
+                Role::Invoker,
+                None,
+                None,
+                Some("alice".to_string()),
+                Some("team-a".to_string()),
+            )
+            .unwrap();
+        let team = keys.authenticate(&team.key).unwrap();
+        let headers = |user: Option<&str>, namespace: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(user) = user {
+                headers.insert(USER_HEADER, user.parse().unwrap());
+            }
+            if let Some(namespace) = namespace {
+                headers.insert(NAMESPACE_HEADER, namespace.parse().unwrap());
+            }
+            headers
+        };
+
+        assert!(team.check(&headers(None, None)).is_ok());
+        assert!(team.check(&headers(Some("alice"), Some("team-a"))).is_ok());
+        assert!(team.check(&headers(Some("bob"), None)).is_err());
+        assert!(team.check(&headers(None, Some("default"))).is_err());
+        assert_eq!(team.tenant(&headers(None, None)).as_deref(), Some("alice"));
+        assert_eq!(team.namespace(), Some("team-a"));
+
+        // A key without a tenant is anonymous, in the default namespace.
+        let ci = keys.create(Role::Deployer, None, None, None, None).unwrap();
+        let ci = keys.authenticate(&ci.key).unwrap();
+        assert!(ci.check(&headers(Some("alice"), None)).is_err());
+        assert!(ci.check(&headers(None, Some("default"))).is_ok());
+        assert_eq!(ci.tenant(&headers(None, None)), None);
+
+        // The admin key acts as whoever the headers name.
+        let admin = keys.authenticate("root").unwrap();
+        assert!(admin.check(&headers(Some("bob"), Some("team-b"))).is_ok());
+        assert_eq!(
+            admin.tenant(&headers(Some("bob"), None)).as_deref(),
+            Some("bob")
+        );
+        assert_eq!(admin.namespace(), None);
+    }
+
+    async fn call(base: &str, method: reqwest::Method, key: Option<&str>) -> StatusCode {
+        let mut request = reqwest::Client::new().request(method, format!("{base}/functions"));
+        if let Some(key) = key {
+            request = request.bearer_auth(key);
+        }
+        request.send().await.unwrap().status()
+    }
+
+    /// Address of an API with a route to read and one to deploy.
+    async fn serve(keys: ApiKeys) -> String {
+        let app =
+            Router::new()
+                .route(
+                    "/functions",
+                    get(|| async { "functions" })
+                        .route_layer(middleware::from_fn_with_state(Permission::Read, require))
+                        .merge(post(|| async { "deployed" }).route_layer(
+                            middleware::from_fn_with_state(Permission::Deploy, require),
+                        )),
+                )
+                .layer(middleware::from_fn_with_state(Arc::new(keys), authenticate));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        base
+    }
+
+    #[tokio::test]
+    async fn test_routes_check_the_role_of_keys() {
+        let file = NamedTempFile::new().unwrap();
+        let keys = ApiKeys::new(file.path(), Some("root")).unwrap();
+        let ci = keys
+            .create(Role::Deployer, None, None, None, None)
+            .unwrap()
+            .key;
+        let app_key = keys
+            .create(Role::Invoker, None, None, None, None)
+            .unwrap()
+            .key;
+        let base = serve(keys).await;
+        let (get, post) = (reqwest::Method::GET, reqwest::Method::POST);
+
+        assert_eq!(
+            call(&base, get.clone(), None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            call(&base, get.clone(), Some("nope")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(call(&base, get, Some(&app_key)).await, StatusCode::OK);
+        assert_eq!(
+            call(&base, post.clone(), Some(&app_key)).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(call(&base, post.clone(), Some(&ci)).await, StatusCode::OK);
+        assert_eq!(
+            call(&base, post.clone(), Some("root")).await,
+            StatusCode::OK
+        );
+
+        // Without an admin key, the API stays open.
+        let open = serve(ApiKeys::new(file.path(), None).unwrap()).await;
+        assert_eq!(call(&open, post, None).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,206 @@
+//! Versions of the HTTP API, and the deprecation of the paths without one.
+//!
+//! Every endpoint of the API is served under the prefix of its version, `/v1`
+//! for now. A later version gets a router of its own, nested under its prefix
+//! next to the earlier ones, so that their handlers coexist: clients move to it
+//! when they are ready. Answers of a version carry it in `x-cloude-api-version`.
+//!
+//! The paths from before versions answer as `/v1` does, with the `Deprecation`
+//! (RFC 9745) and `Sunset` (RFC 8594) headers of their removal, and a `Link` to
+//! their `/v1` successor. Probes, metrics and the `/f/{name}` URLs of functions
+//! are not part of the API, and have no version.
+
+use axum::Json;
+use axum::extract::{Request, State};
+use axum::http::header::LINK;
+use axum::http::{HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, NaiveDate, Utc};
+use cloude_types::ErrorResponse;
+
+/// Versions of the API this backend serves, the oldest first.
+pub const SUPPORTED: &[&str] = &["v1"];
+
+/// Header answers tell the version that served them in.
+pub const VERSION_HEADER: HeaderName = HeaderName::from_static("x-cloude-api-version");
+
+/// When the paths without a version were deprecated, in seconds since the Unix epoch.
+pub const UNVERSIONED_DEPRECATED_AT: i64 = 1_792_281_600; // 2026-10-18
+
+/// Date after which the paths without a version may be removed.
+pub const DEFAULT_UNVERSIONED_SUNSET: &str = "2027-04-18";
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+/// Deprecation of the paths without a version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Deprecation {
+    /// Seconds since the Unix epoch.
+    pub deprecated_at: i64,
+    pub sunset: DateTime<Utc>,
+    /// Version the deprecated paths are kept for, and that replaces them.
+    pub successor: &'static str,
+}
+
+impl Deprecation {
+    /// Deprecation of the unversioned paths, to be removed after `sunset`, a
+    /// `YYYY-MM-DD` date.
+    pub fn unversioned(sunset: &str) -> Result<Self, String> {
+        let sunset = NaiveDate::parse_from_str(sunset, "%Y-%m-%d")
+            .map_err(|e| format!("{sunset} is not a YYYY-MM-DD date: {e}"))?;
+        Ok(Self {
+            deprecated_at: UNVERSIONED_DEPRECATED_AT,
+            sunset: sunset.and_time(chrono::NaiveTime::MIN).and_utc(),
+            successor: SUPPORTED[0],
+        })
+    }
+
+    /// Headers of an answer to `path_and_query`, a deprecated path.
+    pub fn headers(&self, path_and_query: &str) -> Vec<(HeaderName, String)> {
+        vec![
+            (DEPRECATION, format!("@{}", self.deprecated_at)),
+            (
+                SUNSET,
+                self.sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            ),
+            (
+                LINK,
+                format!(
+                    "</{}{}>; rel=\"successor-version\"",
+                    self.successor, path_and_query
+                ),
+            ),
+        ]
+    }
+}
+
+/// Tags the answers of a version with it.
+pub async fn tag_version(
+    State(version): State<&'static str>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert(VERSION_HEADER, HeaderValue::from_static(version));
+    response
+}
+
+/// Adds the deprecation headers to the answers of the unversioned paths.
+pub async fn deprecate(
+    State(deprecation): State<Deprecation>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_default();
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(
+        VERSION_HEADER,
+        HeaderValue::from_static(deprecation.successor),
+    );
+    for (name, value) in deprecation.headers(&path_and_query) {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+    response
+}
+
+/// Version a path asks for, from its first segment: `v` and a number.
+pub fn requested_version(path: &str) -> Option<&str> {
+    let segment = path.trim_start_matches('/').split('/').next()?;
+    let number = segment.strip_prefix('v')?;
+    (!number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())).then_some(segment)
+}
+
+/// Answer to paths no route matched: a `404` naming the supported versions when
+/// the path asks for one this backend does not serve.
+pub async fn not_found(request: Request) -> Response {
+    let error = match requested_version(request.uri().path()) {
+        Some(version) if !SUPPORTED.contains(&version) => format!(
+            "API version {} is not supported, use one of: {}",
+            version,
+            SUPPORTED.join(", ")
+        ),
+        _ => format!("No endpoint at {}", request.uri().path()),
+    };
+    (StatusCode::NOT_FOUND, Json(ErrorResponse::new(error))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deprecation_headers() {
+        let deprecation = Deprecation::unversioned("2027-04-18").unwrap();
+        let headers = deprecation.headers("/status/42?wait=true");
+        assert_eq!(
+            headers,
+            vec![
+                (DEPRECATION, "@1792281600".to_string()),
+                (SUNSET, "Sun, 18 Apr 2027 00:00:00 GMT".to_string()),
+                (
+                    LINK,
+                    "</v1/status/42?wait=true>; rel=\"successor-version\"".to_string()
+                ),
+            ]
+        );
+        assert!(Deprecation::unversioned("next spring").is_err());
+    }
+
+    #[test]
+    fn test_requested_version() {
+        assert_eq!(requested_version("/v1/run"), Some("v1"));
+        assert_eq!(requested_version("/v12"), Some("v12"));
+        assert_eq!(requested_version("/vms/42"), None);
+        assert_eq!(requested_version("/v/run"), None);
+        assert_eq!(requested_version("/run"), None);
+    }
+
+    #[tokio::test]
+    async fn test_versioned_and_deprecated_paths() {
+        let v1 = axum::Router::new().route("/ping", axum::routing::get(|| async { "pong" }));
+        let deprecation = Deprecation::unversioned(DEFAULT_UNVERSIONED_SUNSET).unwrap();
+        let app = axum::Router::new()
+            .nest(
+                "/v1",
+                v1.clone()
+                    .layer(axum::middleware::from_fn_with_state("v1", tag_version)),
+            )
+            .merge(v1.layer(axum::middleware::from_fn_with_state(deprecation, deprecate)))
+            .fallback(not_found);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let versioned = reqwest::get(format!("{base}/v1/ping")).await.unwrap();
+        assert_eq!(versioned.headers()[VERSION_HEADER], "v1");
+        assert!(versioned.headers().get(DEPRECATION).is_none());
+        assert_eq!(versioned.text().await.unwrap(), "pong");
+
+        let unversioned = reqwest::get(format!("{base}/ping")).await.unwrap();
+        assert_eq!(unversioned.headers()[DEPRECATION], "@1792281600");
+        assert_eq!(
+            unversioned.headers()[LINK],
+            "</v1/ping>; rel=\"successor-version\""
+        );
+        assert_eq!(unversioned.text().await.unwrap(), "pong");
+
+        let unsupported = reqwest::get(format!("{base}/v2/ping")).await.unwrap();
+        assert_eq!(unsupported.status(), StatusCode::NOT_FOUND);
+        let error: ErrorResponse = unsupported.json().await.unwrap();
+        assert_eq!(
+            error.error,
+            "API version v2 is not supported, use one of: v1"
+        );
+    }
+}
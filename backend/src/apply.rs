@@ -0,0 +1,194 @@
+//! Declarative management of functions.
+//!
+//! `POST /apply` takes the functions a namespace should have. The backend
+//! compares them with those it has, plans the creations, updates and, with
+//! `prune`, deletions that make them the same, and makes them all at once.
+//! Applying the same spec again plans nothing, so a repository of specs can be
+//! applied on every change, GitOps-style.
+
+use crate::validation::ValidationErrors;
+use cloude_types::{ApplyAction, ApplySpec, DesiredFunction, FunctionInfo, PlannedChange};
+use std::collections::{HashMap, HashSet};
+
+/// Errors of names the spec gives to more than one function.
+pub fn validate_names(spec: &ApplySpec) -> ValidationErrors {
+    let mut errors = ValidationErrors::default();
+    let mut seen = HashSet::new();
+    for (i, function) in spec.functions.iter().enumerate() {
+        if !seen.insert(function.name.as_str()) {
+            errors.push(
+                &format!("functions[{i}].name"),
+                format!("{} is in the spec twice", function.name),
+            );
+        }
+    }
+    errors
+}
+
+fn same_settings(desired: &DesiredFunction, current: &FunctionInfo) -> bool {
+    desired.language == current.language
+        && desired.artifact == current.artifact
+        && desired.entrypoint == current.entrypoint
+}
+
+/// Changes that make `current`, the functions of a namespace by name, those of
+/// `spec`, with `now` as the time of the changes.
+pub fn plan(
+    spec: &ApplySpec,
+    current: &HashMap<String, FunctionInfo>,
+    now: u64,
+) -> Vec<PlannedChange> {
+    let mut changes: Vec<PlannedChange> = spec
+        .functions
+        .iter()
+        .map(|desired| match current.get(&desired.name) {
+            None => PlannedChange {
+                name: desired.name.clone(),
+                action: ApplyAction::Create,
+                before: None,
+                after: Some(FunctionInfo {
+                    name: desired.name.clone(),
+                    language: desired.language.clone(),
+                    artifact: desired.artifact.clone(),
+                    entrypoint: desired.entrypoint.clone(),
+                    version: 1,
+                    created_at: now,
+                    updated_at: now,
+                }),
+            },
+            Some(function) if same_settings(desired, function) => PlannedChange {
+                name: desired.name.clone(),
+                action: ApplyAction::Unchanged,
+                before: Some(function.clone()),
+                after: Some(function.clone()),
+            },
+            Some(function) => PlannedChange {
+                name: desired.name.clone(),
+                action: ApplyAction::Update,
+                before: Some(function.clone()),
+                after: Some(FunctionInfo {
+                    language: desired.language.clone(),
+                    artifact: desired.artifact.clone(),
+                    entrypoint: desired.entrypoint.clone(),
+                    version: function.version + 1,
+                    updated_at: now,
+                    ..function.clone()
+                }),
+            },
+        })
+        .collect();
+
+    if spec.prune {
+        let mut deleted: Vec<&FunctionInfo> = current
+            .values()
+            .filter(|function| !spec.functions.iter().any(|f| f.name == function.name))
+            .collect();
+        deleted.sort_by(|a, b| a.name.cmp(&b.name));
+        changes.extend(deleted.into_iter().map(|function| PlannedChange {
+            name: function.name.clone(),
+            action: ApplyAction::Delete,
+            before: Some(function.clone()),
+            after: None,
+        }));
+    }
+    changes
+}
+
+/// Makes `changes` to `functions`.
+pub fn converge(functions: &mut HashMap<String, FunctionInfo>, changes: &[PlannedChange]) {
+    for change in changes {
+        match (&change.action, &change.after) {
+            (ApplyAction::Delete, _) => {
+                functions.remove(&change.name);
+            }
+            (ApplyAction::Create | ApplyAction::Update, Some(after)) => {
+                functions.insert(change.name.clone(), after.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desired(name: &str, artifact: &str) -> DesiredFunction {
+        DesiredFunction {
+            name: name.to_string(),
+            language: "node".to_string(),
+            artifact: artifact.to_string(),
+            entrypoint: None,
+        }
+    }
+
+    fn actions(changes: &[PlannedChange]) -> Vec<(&str, ApplyAction)> {
+        changes
+            .iter()
+            .map(|change| (change.name.as_str(), change.action))
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_and_converge() {
+        let mut functions = HashMap::new();
+        let first = ApplySpec {
+            functions: vec![desired("resize", "a"), desired("crop", "a")],
+            prune: false,
+        };
+        let changes = plan(&first, &functions, 100);
+        converge(&mut functions, &changes);
+        assert_eq!(functions["resize"].version, 1);
+
+        // The same spec again changes nothing.
+        let again = plan(&first, &functions, 200);
+        assert!(
+            again
+                .iter()
+                .all(|change| change.action == ApplyAction::Unchanged)
+        );
+
+        let second = ApplySpec {
+            functions: vec![desired("resize", "b"), desired("thumbs", "a")],
+            prune: true,
+        };
+        let changes = plan(&second, &functions, 300);
+        assert_eq!(
+            actions(&changes),
+            [
+                ("resize", ApplyAction::Update),
+                ("thumbs", ApplyAction::Create),
+                ("crop", ApplyAction::Delete),
+            ]
+        );
+        converge(&mut functions, &changes);
+        let resize = &functions["resize"];
+        assert_eq!(
+            (resize.version, resize.created_at, resize.updated_at),
+            (2, 100, 300)
+        );
+        assert!(!functions.contains_key("crop"));
+
+        // Without prune, functions the spec does not name stay.
+        let kept = plan(
+            &ApplySpec {
+                functions: Vec::new(),
+                prune: false,
+            },
+            &functions,
+            400,
+        );
+        assert!(kept.is_empty());
+    }
+
+    #[test]
+    fn test_names_are_unique() {
+        let spec = ApplySpec {
+            functions: vec![desired("resize", "a"), desired("resize", "b")],
+            prune: false,
+        };
+        let errors = validate_names(&spec);
+        assert_eq!(errors.errors.len(), 1);
+        assert_eq!(errors.errors[0].field, "functions[1].name");
+    }
+}
@@ -0,0 +1,484 @@
+use crate::blob_store::{ARTIFACTS_PREFIX, BlobStore, BlobStoreError};
+pub use cloude_types::ArtifactInfo;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Default upper bound for a single artifact (256 MiB).
+pub const DEFAULT_MAX_ARTIFACT_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Default time an upload is kept without receiving a chunk (24 hours).
+pub const DEFAULT_UPLOAD_TTL_SECS: u64 = 24 * 3600;
+
+/// Errors that can occur while storing or uploading artifacts.
+#[derive(Debug)]
+pub enum ArtifactError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    UploadNotFound(String),
+    OffsetMismatch { expected: u64, got: u64 },
+    TooLarge { limit: u64 },
+    Blob(BlobStoreError),
+    Backend(String),
+}
+
+impl std::fmt::Display for ArtifactError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArtifactError::Io(e) => write!(f, "IO error: {}", e),
+            ArtifactError::Json(e) => write!(f, "JSON error: {}", e),
+            ArtifactError::UploadNotFound(id) => write!(f, "Upload {} not found", id),
+            ArtifactError::OffsetMismatch { expected, got } => write!(
+                f,
+                "Upload offset mismatch: expected {}, got {}",
+                expected, got
+            ),
+            ArtifactError::TooLarge { limit } => {
+                write!(f, "Artifact exceeds the {} bytes limit", limit)
+            }
+            ArtifactError::Blob(e) => write!(f, "{}", e),
+            ArtifactError::Backend(e) => write!(f, "Storage backend error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ArtifactError {}
+
+impl From<std::io::Error> for ArtifactError {
+    fn from(err: std::io::Error) -> Self {
+        ArtifactError::Io(err)
+    }
+}
+
+impl From<BlobStoreError> for ArtifactError {
+    fn from(err: BlobStoreError) -> Self {
+        ArtifactError::Blob(err)
+    }
+}
+
+impl From<serde_json::Error> for ArtifactError {
+    fn from(err: serde_json::Error) -> Self {
+        ArtifactError::Json(err)
+    }
+}
+
+/// Hex-encoded SHA-256 digest of a file, streamed so large files are never fully buffered.
+pub async fn sha256_file(path: &Path) -> Result<(String, u64), ArtifactError> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0_u8; 64 * 1024];
+    let mut size = 0_u64;
+
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        size += read as u64;
+    }
+
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
+
+/// Content-addressed storage for uploaded code artifacts, kept in a [`BlobStore`]
+/// under `artifacts/<sha256>`.
+pub struct ArtifactStore {
+    blobs: Arc<dyn BlobStore>,
+}
+
+impl ArtifactStore {
+    pub fn new(blobs: Arc<dyn BlobStore>) -> Self {
+        Self { blobs }
+    }
+
+    fn key(id: &str) -> String {
+        format!("{}{}", ARTIFACTS_PREFIX, id)
+    }
+
+    /// Stores the content of the file at `path` and returns its content address.
+    /// The source file is consumed: it is moved into the store or removed.
+    pub async fn put_file(&self, path: &Path) -> Result<ArtifactInfo, ArtifactError> {
+        let (id, size) = sha256_file(path).await?;
+        let key = Self::key(&id);
+
+        if self.blobs.exists(&key).await? {
+            // Same content already stored, nothing else to keep.
+            tokio::fs::remove_file(path).await?;
+        } else {
+            self.blobs.put_file(&key, path).await?;
+            if tokio::fs::try_exists(path).await? {
+                tokio::fs::remove_file(path).await?;
+            }
+        }
+
+        Ok(ArtifactInfo { id, size })
+    }
+
+    /// Returns the artifact content, or `None` if no artifact has this id.
+    pub async fn get(&self, id: &str) -> Result<Option<Vec<u8>>, ArtifactError> {
+        Ok(self.blobs.get(&Self::key(id)).await?)
+    }
+}
+
+/// Progress of a resumable upload.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UploadStatus {
+    pub upload_id: String,
+    pub offset: u64,
+    pub length: u64,
+    /// Set once every byte has been received and the artifact was stored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact: Option<ArtifactInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UploadMetadata {
+    length: u64,
+}
+
+/// Tracks tus-style resumable uploads: the client declares the total length,
+/// then sends chunks at explicit offsets until the upload is complete.
+/// Partial data lives in `<dir>/<upload_id>.part` until it is handed to the store,
+/// or until [`ArtifactUploads::expire`] removes the upload once abandoned.
+pub struct ArtifactUploads {
+    uploads_dir: PathBuf,
+    max_bytes: u64,
+    /// One lock per upload being changed, so that uploads do not wait on each other.
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl ArtifactUploads {
+    pub async fn new<P: AsRef<Path>>(
+        uploads_dir: P,
+        max_bytes: u64,
+    ) -> Result<Self, ArtifactError> {
+        let uploads_dir = uploads_dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&uploads_dir).await?;
+        Ok(Self {
+            uploads_dir,
+            max_bytes,
+            locks: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Lock of `upload_id`, held while its files change.
+    fn upload_lock(&self, upload_id: &str) -> Arc<Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        Arc::clone(locks.entry(upload_id.to_string()).or_default())
+    }
+
+    /// Gives back a lock of [`Self::upload_lock`], forgotten once no request holds it.
+    fn release(&self, upload_id: &str, lock: Arc<Mutex<()>>) {
+        let mut locks = self.locks.lock().unwrap();
+        drop(lock);
+        if locks
+            .get(upload_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(upload_id);
+        }
+    }
+
+    fn part_path(&self, upload_id: &str) -> PathBuf {
+        self.uploads_dir.join(format!("{}.part", upload_id))
+    }
+
+    fn metadata_path(&self, upload_id: &str) -> PathBuf {
+        self.uploads_dir.join(format!("{}.json", upload_id))
+    }
+
+    async fn read_metadata(&self, upload_id: &str) -> Result<UploadMetadata, ArtifactError> {
+        match tokio::fs::read_to_string(self.metadata_path(upload_id)).await {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(ArtifactError::UploadNotFound(upload_id.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn current_offset(&self, upload_id: &str) -> Result<u64, ArtifactError> {
+        match tokio::fs::metadata(self.part_path(upload_id)).await {
+            Ok(meta) => Ok(meta.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Starts a new upload of `length` bytes.
+    pub async fn create(&self, length: u64) -> Result<UploadStatus, ArtifactError> {
+        if length > self.max_bytes {
+            return Err(ArtifactError::TooLarge {
+                limit: self.max_bytes,
+            });
+        }
+
+        let upload_id = uuid::Uuid::new_v4().to_string();
+        let metadata = serde_json::to_string(&UploadMetadata { length })?;
+        tokio::fs::write(self.metadata_path(&upload_id), metadata).await?;
+        tokio::fs::File::create(self.part_path(&upload_id)).await?;
+
+        Ok(UploadStatus {
+            upload_id,
+            offset: 0,
+            length,
+            artifact: None,
+        })
+    }
+
+    /// Returns how many bytes of the upload were received so far.
+    pub async fn status(&self, upload_id: &str) -> Result<UploadStatus, ArtifactError> {
+        let metadata = self.read_metadata(upload_id).await?;
+        Ok(UploadStatus {
+            upload_id: upload_id.to_string(),
+            offset: self.current_offset(upload_id).await?,
+            length: metadata.length,
+            artifact: None,
+        })
+    }
+
+    /// Appends `chunk` at `offset`. The offset must match the bytes already received,
+    /// which lets clients resume after a dropped connection by asking for the status first.
+    /// When the last byte arrives, the data is moved into `store` and the upload is removed.
+    pub async fn append(
+        &self,
+        store: &ArtifactStore,
+        upload_id: &str,
+        offset: u64,
+        chunk: &[u8],
+    ) -> Result<UploadStatus, ArtifactError> {
+        let lock = self.upload_lock(upload_id);
+        let result = {
+            let _guard = lock.lock().await;
+            self.append_locked(store, upload_id, offset, chunk).await
+        };
+        self.release(upload_id, lock);
+        result
+    }
+
+    async fn append_locked(
+        &self,
+        store: &ArtifactStore,
+        upload_id: &str,
+        offset: u64,
+        chunk: &[u8],
+    ) -> Result<UploadStatus, ArtifactError> {
+        let metadata = self.read_metadata(upload_id).await?;
+        let current = self.current_offset(upload_id).await?;
+
+        if offset != current {
+            return Err(ArtifactError::OffsetMismatch {
+                expected: current,
+                got: offset,
+            });
+        }
+
+        let new_offset = current + chunk.len() as u64;
+        if new_offset > metadata.length {
+            return Err(ArtifactError::TooLarge {
+                limit: metadata.length,
+            });
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(self.part_path(upload_id))
+            .await?;
+        file.write_all(chunk).await?;
+        file.sync_all().await?;
+
+        let mut status = UploadStatus {
+            upload_id: upload_id.to_string(),
+            offset: new_offset,
+            length: metadata.length,
+            artifact: None,
+        };
+
+        if new_offset == metadata.length {
+            status.artifact = Some(store.put_file(&self.part_path(upload_id)).await?);
+            tokio::fs::remove_file(self.metadata_path(upload_id)).await?;
+        }
+
+        Ok(status)
+    }
+
+    /// Removes the uploads that received no chunk for `max_age`, and returns their ids.
+    pub async fn expire(&self, max_age: Duration) -> Result<Vec<String>, ArtifactError> {
+        let mut upload_ids = BTreeSet::new();
+        let mut entries = tokio::fs::read_dir(&self.uploads_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if let Some(upload_id) = name.to_str().and_then(|name| {
+                name.strip_suffix(".part")
+                    .or_else(|| name.strip_suffix(".json"))
+            }) {
+                upload_ids.insert(upload_id.to_string());
+            }
+        }
+
+        let mut expired = Vec::new();
+        for upload_id in upload_ids {
+            let lock = self.upload_lock(&upload_id);
+            let result = {
+                let _guard = lock.lock().await;
+                self.expire_locked(&upload_id, max_age).await
+            };
+            self.release(&upload_id, lock);
+            if result? {
+                expired.push(upload_id);
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Removes `upload_id` if its files were last changed `max_age` ago or more.
+    async fn expire_locked(
+        &self,
+        upload_id: &str,
+        max_age: Duration,
+    ) -> Result<bool, ArtifactError> {
+        let paths = [self.part_path(upload_id), self.metadata_path(upload_id)];
+        let mut last_change = None;
+        for path in &paths {
+            match tokio::fs::metadata(path).await {
+                Ok(meta) => last_change = last_change.max(Some(meta.modified()?)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let idle = last_change
+            .and_then(|changed| SystemTime::now().duration_since(changed).ok())
+            .unwrap_or_default();
+        if last_change.is_none() || idle < max_age {
+            return Ok(false);
+        }
+
+        for path in &paths {
+            match tokio::fs::remove_file(path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::LocalBlobStore;
+    use tempfile::TempDir;
+
+    async fn local_store(dir: &Path) -> ArtifactStore {
+        ArtifactStore::new(Arc::new(LocalBlobStore::new(dir).await.unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_put_is_content_addressed() {
+        let dir = TempDir::new().unwrap();
+        let store = local_store(&dir.path().join("blobs")).await;
+
+        let first = dir.path().join("a");
+        let second = dir.path().join("b");
+        tokio::fs::write(&first, b"print(1)").await.unwrap();
+        tokio::fs::write(&second, b"print(1)").await.unwrap();
+
+        let a = store.put_file(&first).await.unwrap();
+        let b = store.put_file(&second).await.unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.size, 8);
+        assert_eq!(store.get(&a.id).await.unwrap().unwrap(), b"print(1)");
+        assert!(store.get(&"0".repeat(64)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_resumable_upload() {
+        let dir = TempDir::new().unwrap();
+        let store = local_store(&dir.path().join("blobs")).await;
+        let uploads = ArtifactUploads::new(dir.path().join("uploads"), 1024)
+            .await
+            .unwrap();
+
+        let upload = uploads.create(10).await.unwrap();
+        let id = upload.upload_id;
+
+        let status = uploads.append(&store, &id, 0, b"hello").await.unwrap();
+        assert_eq!(status.offset, 5);
+        assert!(status.artifact.is_none());
+
+        // Replaying a chunk at a stale offset is refused.
+        let res = uploads.append(&store, &id, 0, b"hello").await;
+        assert!(matches!(
+            res,
+            Err(ArtifactError::OffsetMismatch {
+                expected: 5,
+                got: 0
+            })
+        ));
+
+        assert_eq!(uploads.status(&id).await.unwrap().offset, 5);
+
+        let status = uploads.append(&store, &id, 5, b"world").await.unwrap();
+        let artifact = status.artifact.unwrap();
+        assert_eq!(
+            store.get(&artifact.id).await.unwrap().unwrap(),
+            b"helloworld"
+        );
+        assert!(matches!(
+            uploads.status(&id).await,
+            Err(ArtifactError::UploadNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expire_abandoned_uploads() {
+        let dir = TempDir::new().unwrap();
+        let store = local_store(&dir.path().join("blobs")).await;
+        let uploads = ArtifactUploads::new(dir.path().join("uploads"), 1024)
+            .await
+            .unwrap();
+
+        let abandoned = uploads.create(10).await.unwrap().upload_id;
+        uploads
+            .append(&store, &abandoned, 0, b"hello")
+            .await
+            .unwrap();
+        assert!(
+            uploads
+                .expire(Duration::from_secs(3600))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(uploads.status(&abandoned).await.unwrap().offset, 5);
+
+        assert_eq!(
+            uploads.expire(Duration::ZERO).await.unwrap(),
+            vec![abandoned.clone()]
+        );
+        assert!(matches!(
+            uploads.status(&abandoned).await,
+            Err(ArtifactError::UploadNotFound(_))
+        ));
+        assert!(uploads.locks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upload_length_limit() {
+        let dir = TempDir::new().unwrap();
+        let uploads = ArtifactUploads::new(dir.path(), 4).await.unwrap();
+        assert!(matches!(
+            uploads.create(5).await,
+            Err(ArtifactError::TooLarge { limit: 4 })
+        ));
+    }
+}
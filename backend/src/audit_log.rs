@@ -0,0 +1,329 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single audit record describing one mutating API call.
+/// Records are stored one JSON object per line, in the order they were appended.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch at which the call was handled.
+    pub timestamp: u64,
+    /// Tenant of the caller, see `X-Cloude-User`, or `anonymous`.
+    pub actor: String,
+    /// API key the call was made with, when keys are checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    /// Address of the peer that issued the request.
+    pub source_ip: String,
+    /// Dotted action name, e.g. `job.submit`.
+    pub action: String,
+    /// Identifier of the resource the action applies to, when there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Whether the call was accepted or rejected by the backend.
+    pub outcome: AuditOutcome,
+    /// Free-form context, e.g. the rejection reason.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Accepted,
+    Rejected,
+}
+
+impl AuditEntry {
+    /// Creates an entry stamped with the current time.
+    pub fn new(actor: &str, source_ip: &str, action: &str, outcome: AuditOutcome) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Self {
+            timestamp,
+            actor: actor.to_string(),
+            key_id: None,
+            source_ip: source_ip.to_string(),
+            action: action.to_string(),
+            target: None,
+            outcome,
+            detail: None,
+        }
+    }
+
+    pub fn with_key_id(mut self, key_id: impl Into<String>) -> Self {
+        self.key_id = Some(key_id.into());
+        self
+    }
+
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// An append-only audit trail persisted as a JSON-lines file.
+/// Every append is flushed to disk before returning so records survive a crash.
+#[derive(Debug)]
+pub struct AuditLog {
+    file_path: PathBuf,
+    lock: Mutex<()>,
+}
+
+/// Errors that can occur while writing or reading the audit trail.
+#[derive(Debug)]
+pub enum AuditLogError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl std::fmt::Display for AuditLogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuditLogError::Io(e) => write!(f, "IO error: {}", e),
+            AuditLogError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AuditLogError {}
+
+impl From<std::io::Error> for AuditLogError {
+    fn from(err: std::io::Error) -> Self {
+        AuditLogError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for AuditLogError {
+    fn from(err: serde_json::Error) -> Self {
+        AuditLogError::Json(err)
+    }
+}
+
+impl AuditLog {
+    /// Opens the audit trail at the given path, creating an empty file if needed.
+    /// Existing records are never rewritten.
+    pub fn new<P: AsRef<Path>>(file_path: P) -> Result<Self, AuditLogError> {
+        let log = Self {
+            file_path: file_path.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        };
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log.file_path)?;
+
+        Ok(log)
+    }
+
+    /// Appends a record to the end of the trail and syncs it to disk.
+    pub fn append(&self, entry: &AuditEntry) -> Result<(), AuditLogError> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file_path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Returns all records with a timestamp greater than or equal to `since`, oldest first.
+    ///
+    /// # Arguments
+    /// * `since` - Lower bound in seconds since the Unix epoch, or `None` for the whole trail.
+    pub fn export(&self, since: Option<u64>) -> Result<Vec<AuditEntry>, AuditLogError> {
+        let _guard = self.lock.lock().unwrap();
+        let file = match File::open(&self.file_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: AuditEntry = serde_json::from_str(&line)?;
+            if since.is_none_or(|since| entry.timestamp >= since) {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Records an [`AuditWriter`] holds in memory before its senders wait for the disk.
+pub const AUDIT_QUEUE_CAPACITY: usize = 1024;
+
+enum Message {
+    Record(AuditEntry),
+    /// Answered once every record queued before it is on disk.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Appends records to an [`AuditLog`] from a thread of its own, in the order
+/// they were sent, so that request handlers do not wait for the disk.
+///
+/// At most [`AUDIT_QUEUE_CAPACITY`] records wait in memory: past that, senders
+/// block until the thread catches up. Should the thread die, records are
+/// appended by their senders again, nothing is dropped.
+#[derive(Clone, Debug)]
+pub struct AuditWriter {
+    sender: mpsc::SyncSender<Message>,
+    log: Arc<AuditLog>,
+}
+
+impl AuditWriter {
+    pub fn spawn(log: Arc<AuditLog>) -> std::io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<Message>(AUDIT_QUEUE_CAPACITY);
+        let thread_log = Arc::clone(&log);
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || {
+                for message in receiver {
+                    match message {
+                        Message::Record(entry) => write_record(&thread_log, &entry),
+                        Message::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })?;
+        Ok(Self { sender, log })
+    }
+
+    /// Queues `entry` to be appended. Failures are logged, never returned.
+    pub fn send(&self, entry: AuditEntry) {
+        if let Err(mpsc::SendError(Message::Record(entry))) =
+            self.sender.send(Message::Record(entry))
+        {
+            tracing::error!(
+                "Audit log thread is gone, appending {} inline",
+                entry.action
+            );
+            write_record(&self.log, &entry);
+        }
+    }
+
+    /// Waits until every record sent so far is synced to disk, e.g. before the
+    /// process exits.
+    pub fn flush(&self) {
+        let (done, flushed) = mpsc::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = flushed.recv();
+        }
+    }
+}
+
+fn write_record(log: &AuditLog, entry: &AuditEntry) {
+    if let Err(e) = log.append(entry) {
+        tracing::error!("Failed to write audit record for {}: {}", entry.action, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_append_and_export() {
+        let file = NamedTempFile::new().unwrap();
+        let log = AuditLog::new(file.path()).unwrap();
+
+        let entry = AuditEntry::new("alice", "127.0.0.1", "job.submit", AuditOutcome::Accepted)
+            .with_target("job-1");
+        log.append(&entry).unwrap();
+
+        let entries = log.export(None).unwrap();
+        assert_eq!(entries, vec![entry]);
+    }
+
+    #[test]
+    fn test_existing_records_are_kept() {
+        let file = NamedTempFile::new().unwrap();
+
+        {
+            let log = AuditLog::new(file.path()).unwrap();
+            log.append(&AuditEntry::new(
+                "alice",
+                "127.0.0.1",
+                "job.submit",
+                AuditOutcome::Accepted,
+            ))
+            .unwrap();
+        }
+
+        let log = AuditLog::new(file.path()).unwrap();
+        log.append(
+            &AuditEntry::new("bob", "127.0.0.1", "job.submit", AuditOutcome::Rejected)
+                .with_detail("Code cannot be empty"),
+        )
+        .unwrap();
+
+        let entries = log.export(None).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].actor, "alice");
+        assert_eq!(entries[1].actor, "bob");
+    }
+
+    #[test]
+    fn test_export_since() {
+        let file = NamedTempFile::new().unwrap();
+        let log = AuditLog::new(file.path()).unwrap();
+
+        let mut old = AuditEntry::new("alice", "127.0.0.1", "job.submit", AuditOutcome::Accepted);
+        old.timestamp = 100;
+        let mut recent = old.clone();
+        recent.timestamp = 200;
+        log.append(&old).unwrap();
+        log.append(&recent).unwrap();
+
+        let entries = log.export(Some(150)).unwrap();
+        assert_eq!(entries, vec![recent]);
+    }
+
+    #[test]
+    fn test_writer_appends_in_order() {
+        let file = NamedTempFile::new().unwrap();
+        let log = Arc::new(AuditLog::new(file.path()).unwrap());
+        let writer = AuditWriter::spawn(log.clone()).unwrap();
+
+        for actor in ["alice", "bob", "carol"] {
+            writer.send(AuditEntry::new(
+                actor,
+                "127.0.0.1",
+                "job.submit",
+                AuditOutcome::Accepted,
+            ));
+        }
+
+        writer.flush();
+        let actors: Vec<String> = log
+            .export(None)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.actor)
+            .collect();
+        assert_eq!(actors, ["alice", "bob", "carol"]);
+    }
+}
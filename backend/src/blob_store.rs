@@ -0,0 +1,551 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+/// Key prefix under which code artifacts are stored.
+pub const ARTIFACTS_PREFIX: &str = "artifacts/";
+/// Key prefix under which VM snapshots are stored.
+pub const SNAPSHOTS_PREFIX: &str = "snapshots/";
+/// Key prefix under which archived logs are stored.
+pub const LOGS_PREFIX: &str = "logs/";
+/// Key prefix under which compiled binaries are stored.
+pub const BINARIES_PREFIX: &str = "binaries/";
+/// Key prefix under which core dumps of crashed jobs are stored.
+pub const CORES_PREFIX: &str = "cores/";
+/// Key prefix under which the outputs of the stages of pipelines are stored.
+pub const PIPELINES_PREFIX: &str = "pipelines/";
+
+/// Errors that can occur while talking to a blob store.
+#[derive(Debug)]
+pub enum BlobStoreError {
+    Io(std::io::Error),
+    Http(reqwest::Error),
+    InvalidKey(String),
+    Backend(String),
+}
+
+impl std::fmt::Display for BlobStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlobStoreError::Io(e) => write!(f, "IO error: {}", e),
+            BlobStoreError::Http(e) => write!(f, "HTTP error: {}", e),
+            BlobStoreError::InvalidKey(key) => write!(f, "Invalid blob key: {}", key),
+            BlobStoreError::Backend(e) => write!(f, "Storage backend error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BlobStoreError {}
+
+impl From<std::io::Error> for BlobStoreError {
+    fn from(err: std::io::Error) -> Self {
+        BlobStoreError::Io(err)
+    }
+}
+
+impl From<reqwest::Error> for BlobStoreError {
+    fn from(err: reqwest::Error) -> Self {
+        BlobStoreError::Http(err)
+    }
+}
+
+/// Flat key/value storage for artifacts, snapshots and logs.
+///
+/// Keys are `/`-separated relative paths such as `artifacts/<sha256>`; they never
+/// start with `/` and never contain `..` segments. Sharing one store between
+/// several backends lets them reuse each other's build caches and snapshots.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Stores `data` under `key`, replacing any previous value.
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), BlobStoreError>;
+
+    /// Stores the content of the file at `path` under `key`.
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), BlobStoreError> {
+        let data = tokio::fs::read(path).await?;
+        self.put(key, data).await
+    }
+
+    /// Returns the value stored under `key`, or `None` if there is none.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobStoreError>;
+
+    async fn exists(&self, key: &str) -> Result<bool, BlobStoreError>;
+
+    /// Removes `key`. Removing a missing key is not an error.
+    async fn delete(&self, key: &str) -> Result<(), BlobStoreError>;
+
+    /// Lists every key starting with `prefix`, sorted.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, BlobStoreError>;
+}
+
+/// Rejects keys that could escape the store root once mapped to a path or URL.
+fn check_key(key: &str) -> Result<(), BlobStoreError> {
+    let valid = !key.is_empty()
+        && !key.starts_with('/')
+        && key
+            .split('/')
+            .all(|segment| !segment.is_empty() && segment != "." && segment != "..")
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'/' | b'-' | b'_' | b'.'));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(BlobStoreError::InvalidKey(key.to_string()))
+    }
+}
+
+/// Stores blobs as plain files below a root directory.
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub async fn new<P: AsRef<Path>>(root: P) -> Result<Self, BlobStoreError> {
+        let root = root.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&root).await?;
+        Ok(Self { root })
+    }
+
+    fn path_for(&self, key: &str) -> Result<PathBuf, BlobStoreError> {
+        check_key(key)?;
+        Ok(self.root.join(key))
+    }
+
+    /// Writes through a temporary file and renames it so readers never see partial blobs.
+    async fn write_atomic(&self, target: &Path, data: &[u8]) -> Result<(), BlobStoreError> {
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp = target.with_extension(format!("tmp-{}", uuid::Uuid::new_v4()));
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, target).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), BlobStoreError> {
+        let target = self.path_for(key)?;
+        self.write_atomic(&target, &data).await
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), BlobStoreError> {
+        let target = self.path_for(key)?;
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        if tokio::fs::rename(path, &target).await.is_err() {
+            // The source lives on another filesystem: copy it instead.
+            let data = tokio::fs::read(path).await?;
+            self.write_atomic(&target, &data).await?;
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobStoreError> {
+        match tokio::fs::read(self.path_for(key)?).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, BlobStoreError> {
+        Ok(tokio::fs::try_exists(self.path_for(key)?).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobStoreError> {
+        match tokio::fs::remove_file(self.path_for(key)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, BlobStoreError> {
+        let mut keys = Vec::new();
+        let mut pending = vec![self.root.clone()];
+
+        while let Some(dir) = pending.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let Ok(relative) = path.strip_prefix(&self.root) else {
+                    continue;
+                };
+                let key = relative.to_string_lossy().replace('\\', "/");
+                if key.starts_with(prefix) && !key.contains(".tmp-") {
+                    keys.push(key);
+                }
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Connection settings for an S3-compatible object store (AWS S3, MinIO, Ceph RGW, ...).
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    /// Base URL of the service, e.g. `https://s3.eu-west-3.amazonaws.com` or `http://minio:9000`.
+    pub endpoint: String,
+    pub bucket: String,
+    /// Prepended to every key, e.g. `cloude/` to share a bucket with other applications.
+    pub prefix: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+/// Stores blobs in an S3 bucket using path-style requests signed with AWS Signature V4.
+pub struct S3BlobStore {
+    config: S3Config,
+    client: reqwest::Client,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+const UNSIGNED_EMPTY_PAYLOAD: &str =
+    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Derives the SigV4 signing key for a given day, region and service.
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let k_date = hmac_sha256(
+        format!("AWS4{}", secret_access_key).as_bytes(),
+        date.as_bytes(),
+    );
+    let k_region = hmac_sha256(&k_date, region.as_bytes());
+    let k_service = hmac_sha256(&k_region, service.as_bytes());
+    hmac_sha256(&k_service, b"aws4_request")
+}
+
+/// Percent-encodes `value` as required by SigV4 canonical requests.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+impl S3BlobStore {
+    pub fn new(config: S3Config) -> Result<Self, BlobStoreError> {
+        if config.bucket.is_empty() {
+            return Err(BlobStoreError::Backend("S3 bucket must be set".to_string()));
+        }
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(300))
+            .build()?;
+        Ok(Self { config, client })
+    }
+
+    fn object_key(&self, key: &str) -> Result<String, BlobStoreError> {
+        check_key(key)?;
+        Ok(format!("{}{}", self.config.prefix, key))
+    }
+
+    /// Sends a signed request. `query` must already be sorted by parameter name.
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        object_key: Option<&str>,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, BlobStoreError> {
+        let endpoint = self.config.endpoint.trim_end_matches('/');
+        let mut canonical_uri = format!("/{}", uri_encode(&self.config.bucket, true));
+        if let Some(object_key) = object_key {
+            canonical_uri.push('/');
+            canonical_uri.push_str(&uri_encode(object_key, false));
+        }
+        let canonical_query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k, true), uri_encode(v, true)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let mut url = format!("{}{}", endpoint, canonical_uri);
+        if !canonical_query.is_empty() {
+            url.push('?');
+            url.push_str(&canonical_query);
+        }
+        let parsed = reqwest::Url::parse(&url)
+            .map_err(|e| BlobStoreError::Backend(format!("invalid S3 URL {}: {}", url, e)))?;
+        let host = match (parsed.host_str(), parsed.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => {
+                return Err(BlobStoreError::Backend(format!(
+                    "S3 endpoint {} has no host",
+                    endpoint
+                )));
+            }
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = if body.is_empty() {
+            UNSIGNED_EMPTY_PAYLOAD.to_string()
+        } else {
+            format!("{:x}", Sha256::digest(&body))
+        };
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method.as_str(),
+            canonical_uri,
+            canonical_query,
+            host,
+            payload_hash,
+            amz_date,
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+        let key = signing_key(
+            &self.config.secret_access_key,
+            &date,
+            &self.config.region,
+            "s3",
+        );
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.config.access_key_id, scope, signed_headers, signature
+        );
+
+        let response = self
+            .client
+            .request(method, parsed)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await?;
+        Ok(response)
+    }
+
+    async fn error_from(response: reqwest::Response) -> BlobStoreError {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        BlobStoreError::Backend(format!("S3 returned HTTP {}: {}", status, body))
+    }
+}
+
+/// Extracts the text of every `<tag>...</tag>` element of a small XML document.
+fn xml_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        values.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+#[async_trait]
+impl BlobStore for S3BlobStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), BlobStoreError> {
+        let object_key = self.object_key(key)?;
+        let response = self
+            .send(reqwest::Method::PUT, Some(&object_key), &[], data)
+            .await?;
+        if !response.status().is_success() {
+            return Err(Self::error_from(response).await);
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobStoreError> {
+        let object_key = self.object_key(key)?;
+        let response = self
+            .send(reqwest::Method::GET, Some(&object_key), &[], Vec::new())
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(Some(response.bytes().await?.to_vec())),
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            _ => Err(Self::error_from(response).await),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, BlobStoreError> {
+        let object_key = self.object_key(key)?;
+        let response = self
+            .send(reqwest::Method::HEAD, Some(&object_key), &[], Vec::new())
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            status => Err(BlobStoreError::Backend(format!(
+                "S3 returned HTTP {} for HEAD {}",
+                status, object_key
+            ))),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobStoreError> {
+        let object_key = self.object_key(key)?;
+        let response = self
+            .send(reqwest::Method::DELETE, Some(&object_key), &[], Vec::new())
+            .await?;
+        if !response.status().is_success() && response.status() != reqwest::StatusCode::NOT_FOUND {
+            return Err(Self::error_from(response).await);
+        }
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, BlobStoreError> {
+        let full_prefix = format!("{}{}", self.config.prefix, prefix);
+        let mut keys = Vec::new();
+        let mut continuation: Option<String> = None;
+
+        loop {
+            // Query parameters must be sorted by name for the signature.
+            let mut query = vec![];
+            if let Some(token) = continuation.as_deref() {
+                query.push(("continuation-token", token));
+            }
+            query.push(("list-type", "2"));
+            query.push(("prefix", full_prefix.as_str()));
+
+            let response = self
+                .send(reqwest::Method::GET, None, &query, Vec::new())
+                .await?;
+            if !response.status().is_success() {
+                return Err(Self::error_from(response).await);
+            }
+            let body = response.text().await?;
+
+            for key in xml_values(&body, "Key") {
+                if let Some(stripped) = key.strip_prefix(self.config.prefix.as_str()) {
+                    keys.push(stripped.to_string());
+                }
+            }
+
+            let truncated = xml_values(&body, "IsTruncated").first() == Some(&"true");
+            continuation = xml_values(&body, "NextContinuationToken")
+                .first()
+                .map(|token| token.to_string());
+            if !truncated || continuation.is_none() {
+                break;
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_key_validation() {
+        assert!(check_key("artifacts/abc").is_ok());
+        assert!(check_key("logs/vm-1/2024.log").is_ok());
+        assert!(check_key("").is_err());
+        assert!(check_key("/etc/passwd").is_err());
+        assert!(check_key("artifacts/../../etc").is_err());
+        assert!(check_key("artifacts//x").is_err());
+        assert!(check_key("with space").is_err());
+    }
+
+    #[test]
+    fn test_sigv4_signing_key() {
+        // Example from the AWS Signature Version 4 documentation.
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(uri_encode("a b/c~d", false), "a%20b/c~d");
+        assert_eq!(uri_encode("a/b", true), "a%2Fb");
+    }
+
+    #[test]
+    fn test_xml_values() {
+        let xml = "<ListBucketResult><IsTruncated>false</IsTruncated>\
+                   <Contents><Key>p/a</Key></Contents><Contents><Key>p/b</Key></Contents>\
+                   </ListBucketResult>";
+        assert_eq!(xml_values(xml, "Key"), vec!["p/a", "p/b"]);
+        assert_eq!(xml_values(xml, "IsTruncated"), vec!["false"]);
+    }
+
+    #[tokio::test]
+    async fn test_local_store_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let store = LocalBlobStore::new(dir.path()).await.unwrap();
+
+        store.put("artifacts/a", b"one".to_vec()).await.unwrap();
+        store.put("snapshots/s/mem", b"two".to_vec()).await.unwrap();
+
+        assert_eq!(store.get("artifacts/a").await.unwrap().unwrap(), b"one");
+        assert!(store.exists("snapshots/s/mem").await.unwrap());
+        assert!(store.get("artifacts/missing").await.unwrap().is_none());
+        assert_eq!(
+            store.list("").await.unwrap(),
+            vec!["artifacts/a".to_string(), "snapshots/s/mem".to_string()]
+        );
+        assert_eq!(
+            store.list(SNAPSHOTS_PREFIX).await.unwrap(),
+            vec!["snapshots/s/mem".to_string()]
+        );
+
+        store.delete("artifacts/a").await.unwrap();
+        store.delete("artifacts/a").await.unwrap();
+        assert!(!store.exists("artifacts/a").await.unwrap());
+    }
+}
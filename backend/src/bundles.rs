@@ -0,0 +1,142 @@
+//! Export of the functions of a namespace to a bundle, and their import into
+//! another namespace or backend.
+//!
+//! A bundle carries the functions with their version, the runtimes they ran on
+//! and, unless left out, the content of their artifacts, so that a backend with
+//! its own blob store can run them. Functions are imported in the namespace of
+//! the request; one whose name is taken by a function with other settings is
+//! skipped, overwritten or renamed, as the request asks.
+
+use crate::validation::MAX_IDENTIFIER_LEN;
+use cloude_types::{FunctionInfo, OnConflict};
+
+/// What to do with a function of a bundle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Plan {
+    /// Add it, under its name.
+    Create,
+    /// A function of that name already has its settings.
+    Unchanged(FunctionInfo),
+    /// Deploy its settings as a new version of the function of that name.
+    Overwrite,
+    /// Add it under this name, as its own is taken.
+    Rename(String),
+    /// A function renamed by an earlier import already has its settings.
+    AlreadyRenamed(FunctionInfo),
+    Skip,
+}
+
+/// Whether `a` and `b` run the same code the same way.
+pub fn same_settings(a: &FunctionInfo, b: &FunctionInfo) -> bool {
+    a.language == b.language && a.artifact == b.artifact && a.entrypoint == b.entrypoint
+}
+
+/// What to do with `incoming`, given the functions already deployed in the
+/// namespace, looked up by name with `existing`.
+pub fn plan(
+    incoming: &FunctionInfo,
+    on_conflict: OnConflict,
+    existing: impl Fn(&str) -> Option<FunctionInfo>,
+) -> Plan {
+    match existing(&incoming.name) {
+        None => return Plan::Create,
+        Some(current) if same_settings(&current, incoming) => return Plan::Unchanged(current),
+        Some(_) => {}
+    }
+    match on_conflict {
+        OnConflict::Skip => Plan::Skip,
+        OnConflict::Overwrite => Plan::Overwrite,
+        OnConflict::Rename => {
+            // Importing the same bundle again finds the copy it renamed before.
+            let mut n = 2;
+            loop {
+                let name = renamed(&incoming.name, n);
+                match existing(&name) {
+                    None => return Plan::Rename(name),
+                    Some(copy) if same_settings(&copy, incoming) => {
+                        return Plan::AlreadyRenamed(copy);
+                    }
+                    Some(_) => n += 1,
+                }
+            }
+        }
+    }
+}
+
+/// `name` with the suffix `-n`, shortened to stay a valid identifier.
+fn renamed(name: &str, n: u32) -> String {
+    let suffix = format!("-{n}");
+    let keep = name.len().min(MAX_IDENTIFIER_LEN - suffix.len());
+    format!("{}{}", &name[..keep], suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn function(name: &str, artifact: &str) -> FunctionInfo {
+        FunctionInfo {
+            name: name.to_string(),
+            language: "python".to_string(),
+            artifact: artifact.to_string(),
+            entrypoint: None,
+            version: 3,
+            created_at: 1760000000,
+            updated_at: 1760000100,
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let deployed: HashMap<String, FunctionInfo> = [
+            ("resize", function("resize", "a")),
+            ("thumbs", function("thumbs", "a")),
+            ("thumbs-2", function("thumbs-2", "b")),
+        ]
+        .into_iter()
+        .map(|(name, function)| (name.to_string(), function))
+        .collect();
+        let existing = |name: &str| deployed.get(name).cloned();
+
+        let new = function("crop", "a");
+        assert_eq!(plan(&new, OnConflict::Skip, existing), Plan::Create);
+        let same = function("resize", "a");
+        assert!(matches!(
+            plan(&same, OnConflict::Overwrite, existing),
+            Plan::Unchanged(_)
+        ));
+
+        let changed = function("resize", "c");
+        assert_eq!(plan(&changed, OnConflict::Skip, existing), Plan::Skip);
+        assert_eq!(
+            plan(&changed, OnConflict::Overwrite, existing),
+            Plan::Overwrite
+        );
+        assert_eq!(
+            plan(&changed, OnConflict::Rename, existing),
+            Plan::Rename("resize-2".to_string())
+        );
+
+        // thumbs-2 is what an earlier import of other settings renamed.
+        let thumbs = function("thumbs", "c");
+        assert_eq!(
+            plan(&thumbs, OnConflict::Rename, existing),
+            Plan::Rename("thumbs-3".to_string())
+        );
+        let earlier = function("thumbs", "b");
+        assert!(matches!(
+            plan(&earlier, OnConflict::Rename, existing),
+            Plan::AlreadyRenamed(copy) if copy.name == "thumbs-2"
+        ));
+    }
+
+    #[test]
+    fn test_renamed_stays_an_identifier() {
+        assert_eq!(renamed("resize", 2), "resize-2");
+        let long = "f".repeat(MAX_IDENTIFIER_LEN);
+        let name = renamed(&long, 10);
+        assert_eq!(name.len(), MAX_IDENTIFIER_LEN);
+        assert!(name.ends_with("f-10"));
+    }
+}
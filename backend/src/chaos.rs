@@ -0,0 +1,102 @@
+//! Failure injection for integration tests, only built with the `chaos` feature.
+//!
+//! Faults are armed through `PUT /admin/chaos` and fire at fixed points of the
+//! job pipeline, so tests can exercise error handling and retries without
+//! breaking the host.
+
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Where a fault is injected.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind {
+    /// Building a runtime initramfs fails.
+    InitramfsBuild,
+    /// A request to the guest agent fails as if the connection was dropped.
+    AgentConnection,
+    /// VM boot is delayed by `delay_ms` before anything is allocated.
+    BootDelay,
+    /// The VM is stopped `delay_ms` after its agent became ready.
+    KillVm,
+}
+
+/// A fault armed by a test.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// Only jobs and images of this language are affected, every language when absent.
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Number of times the fault fires before it is disarmed, unlimited when absent.
+    #[serde(default)]
+    pub times: Option<u32>,
+}
+
+static FAULTS: Mutex<Vec<Fault>> = Mutex::new(Vec::new());
+
+/// Replaces every armed fault.
+pub fn set(faults: Vec<Fault>) {
+    *FAULTS.lock().unwrap() = faults;
+}
+
+/// Faults still armed, with their remaining count.
+pub fn list() -> Vec<Fault> {
+    FAULTS.lock().unwrap().clone()
+}
+
+/// Returns the first armed fault of `kind` matching `language` and counts it as fired.
+pub fn fire(kind: FaultKind, language: &str) -> Option<Fault> {
+    let mut faults = FAULTS.lock().unwrap();
+    let index = faults.iter().position(|fault| {
+        fault.kind == kind && fault.language.as_deref().is_none_or(|l| l == language)
+    })?;
+
+    let fault = faults[index].clone();
+    if let Some(times) = &mut faults[index].times {
+        *times = times.saturating_sub(1);
+        if *times == 0 {
+            faults.remove(index);
+        }
+    }
+
+    warn!(?kind, language = %language, "Injecting fault");
+    Some(fault)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fire_matches_language_and_counts_down() {
+        set(vec![
+            Fault {
+                kind: FaultKind::KillVm,
+                language: Some("python".to_string()),
+                delay_ms: 10,
+                times: Some(2),
+            },
+            Fault {
+                kind: FaultKind::BootDelay,
+                language: None,
+                delay_ms: 5,
+                times: None,
+            },
+        ]);
+
+        assert!(fire(FaultKind::KillVm, "node").is_none());
+        assert_eq!(fire(FaultKind::KillVm, "python").unwrap().delay_ms, 10);
+        assert!(fire(FaultKind::KillVm, "python").is_some());
+        assert!(fire(FaultKind::KillVm, "python").is_none());
+        assert!(fire(FaultKind::BootDelay, "node").is_some());
+        assert!(fire(FaultKind::BootDelay, "node").is_some());
+        assert_eq!(list().len(), 1);
+
+        set(Vec::new());
+    }
+}
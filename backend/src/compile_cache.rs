@@ -0,0 +1,240 @@
+//! Binaries built by earlier runs of compiled code, handed to later runs so that
+//! they skip the compile step.
+//!
+//! A binary is identified by a hash of the code and of the runtime initramfs it
+//! was built in, which holds the toolchain: a runtime upgrade or a rebuilt image
+//! compiles everything again. Binaries built on the host are identified by the
+//! version of its compiler instead. Binaries are kept in the [`BlobStore`] under
+//! `binaries/<language>/<key>`, so backends sharing a store share them too.
+
+use crate::blob_store::{BINARIES_PREFIX, BlobStore, BlobStoreError};
+use crate::image_digests::{ImageDigestError, ImageDigests};
+use crate::result_cache::cache_key;
+use crate::vm_lifecycle::VmConfig;
+use cloude_types::ExecuteRequest;
+use std::sync::Arc;
+
+/// Runtimes of the agent that compile the code before running it.
+pub const COMPILED_LANGUAGES: &[&str] = &["rust", "c", "cpp", "go", "java"];
+
+/// Default upper bound for a stored binary (64 MiB).
+pub const DEFAULT_MAX_BINARY_BYTES: usize = 64 * 1024 * 1024;
+
+/// Errors that can occur while looking up or storing a binary.
+#[derive(Debug)]
+pub enum CompileCacheError {
+    /// The digest of the toolchain image cannot be computed.
+    Image(ImageDigestError),
+    Blob(BlobStoreError),
+}
+
+impl std::fmt::Display for CompileCacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileCacheError::Image(e) => write!(f, "{}", e),
+            CompileCacheError::Blob(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CompileCacheError {}
+
+impl From<BlobStoreError> for CompileCacheError {
+    fn from(err: BlobStoreError) -> Self {
+        CompileCacheError::Blob(err)
+    }
+}
+
+pub struct CompileCache {
+    blobs: Arc<dyn BlobStore>,
+    /// Largest binary stored, `0` stores none.
+    max_binary_bytes: usize,
+    images: Arc<ImageDigests>,
+}
+
+impl CompileCache {
+    pub fn new(
+        blobs: Arc<dyn BlobStore>,
+        images: Arc<ImageDigests>,
+        max_binary_bytes: usize,
+    ) -> Self {
+        Self {
+            blobs,
+            max_binary_bytes,
+            images,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_binary_bytes > 0
+    }
+
+    /// Key of the binary `request` builds in the VMs of `config`, `None` for
+    /// runtimes that compile nothing.
+    pub async fn key(
+        &self,
+        request: &ExecuteRequest,
+        config: &VmConfig,
+    ) -> Result<Option<String>, CompileCacheError> {
+        if !self.is_enabled() || !COMPILED_LANGUAGES.contains(&request.language.as_str()) {
+            return Ok(None);
+        }
+        let toolchain = self
+            .images
+            .digest(&request.language, config)
+            .await
+            .map_err(CompileCacheError::Image)?;
+        Ok(self.key_for(request, &toolchain))
+    }
+
+    /// Key of the binary `request` builds with `toolchain`, for builds outside
+    /// of a runtime image, like those on the host.
+    pub fn key_for(&self, request: &ExecuteRequest, toolchain: &str) -> Option<String> {
+        if !self.is_enabled() || !COMPILED_LANGUAGES.contains(&request.language.as_str()) {
+            return None;
+        }
+        Some(binary_key(request, toolchain))
+    }
+
+    /// The binary stored under `key`, if any.
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CompileCacheError> {
+        Ok(self.blobs.get(key).await?)
+    }
+
+    /// Stores `binary` under `key`, unless it is larger than the limit.
+    pub async fn put(&self, key: &str, binary: Vec<u8>) -> Result<(), CompileCacheError> {
+        if binary.len() > self.max_binary_bytes {
+            return Ok(());
+        }
+        Ok(self.blobs.put(key, binary).await?)
+    }
+}
+
+/// Blob key of the binary built from the code of `request` by `toolchain`.
+fn binary_key(request: &ExecuteRequest, toolchain: &str) -> String {
+    let (kind, content) = match &request.bundle {
+        Some(bundle) => (b"bundle".as_slice(), bundle.as_bytes()),
+        None => (b"source".as_slice(), request.code.as_bytes()),
+    };
+    let key = cache_key(&[
+        toolchain.as_bytes(),
+        kind,
+        content,
+        request.entrypoint.as_deref().unwrap_or_default().as_bytes(),
+    ]);
+    format!("{}{}/{}", BINARIES_PREFIX, request.language, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::LocalBlobStore;
+    use crate::redaction::Redactor;
+    use tempfile::TempDir;
+
+    fn request(code: &str) -> ExecuteRequest {
+        ExecuteRequest {
+            language: "rust".to_string(),
+            code: code.to_string(),
+            bundle: None,
+            entrypoint: None,
+            deterministic: false,
+            offline: false,
+            stdin: None,
+            stream: false,
+            binary: None,
+            keep_binary: false,
+            build_only: false,
+            limits: None,
+            identity_token: None,
+            egress_proxy: None,
+            concurrent: false,
+        }
+    }
+
+    #[test]
+    fn test_key_tracks_code_and_toolchain() {
+        let key = binary_key(&request("fn main() {}"), "a");
+        assert!(key.starts_with("binaries/rust/"));
+        assert_eq!(key, binary_key(&request("fn main() {}"), "a"));
+        assert_ne!(key, binary_key(&request("fn main() {}"), "b"));
+        assert_ne!(key, binary_key(&request("fn main() { }"), "a"));
+
+        let bundle = ExecuteRequest {
+            code: String::new(),
+            bundle: Some("fn main() {}".to_string()),
+            entrypoint: Some("main.rs".to_string()),
+            ..request("")
+        };
+        assert_ne!(key, binary_key(&bundle, "a"));
+        let other_entrypoint = ExecuteRequest {
+            entrypoint: Some("bin.rs".to_string()),
+            ..bundle.clone()
+        };
+        assert_ne!(binary_key(&bundle, "a"), binary_key(&other_entrypoint, "a"));
+    }
+
+    #[tokio::test]
+    async fn test_binaries_by_toolchain() {
+        let dir = TempDir::new().unwrap();
+        let blobs = Arc::new(LocalBlobStore::new(dir.path().join("blobs")).await.unwrap());
+        let cache = CompileCache::new(blobs, Arc::new(ImageDigests::new()), 4);
+        let config = VmConfig {
+            kernel_path: dir.path().join("vmlinux"),
+            initramfs_dir: dir.path().to_path_buf(),
+            bridge_name: None,
+            tenant: None,
+            vcpus: 1,
+            memory_mb: 512,
+            log_guest_console: false,
+            serial_log: None,
+            redactor: Redactor::default(),
+            cmdline_extra: None,
+            acpi: false,
+            max_vcpus: 0,
+            max_memory_mb: 0,
+            traffic_accounting: false,
+            mtu: None,
+            merge_memory: false,
+        };
+        let initramfs = dir.path().join("rust-1.81.cpio.gz");
+        tokio::fs::write(&initramfs, b"toolchain").await.unwrap();
+
+        let mut python = request("print(1)");
+        python.language = "python".to_string();
+        assert_eq!(cache.key(&python, &config).await.unwrap(), None);
+        assert_eq!(cache.key_for(&python, "rustc 1.81.0"), None);
+        assert_eq!(
+            cache.key_for(&request("fn main() {}"), "rustc 1.81.0"),
+            Some(binary_key(&request("fn main() {}"), "rustc 1.81.0"))
+        );
+
+        let key = cache.key(&request("fn main() {}"), &config).await.unwrap();
+        let key = key.unwrap();
+        assert_eq!(cache.get(&key).await.unwrap(), None);
+        cache.put(&key, b"ELF".to_vec()).await.unwrap();
+        assert_eq!(cache.get(&key).await.unwrap().unwrap(), b"ELF");
+
+        // Too large to be kept.
+        let other = cache.key(&request("fn main() { }"), &config).await.unwrap();
+        cache
+            .put(other.as_ref().unwrap(), b"ELF64".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(cache.get(other.as_ref().unwrap()).await.unwrap(), None);
+
+        // A rebuilt runtime image has a toolchain of its own.
+        tokio::fs::write(&initramfs, b"upgraded toolchain")
+            .await
+            .unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&initramfs)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        let rebuilt = cache.key(&request("fn main() {}"), &config).await.unwrap();
+        assert_ne!(rebuilt.unwrap(), key);
+    }
+}
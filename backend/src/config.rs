@@ -0,0 +1,746 @@
+use crate::compile_cache::COMPILED_LANGUAGES;
+use crate::egress_proxy::EgressPolicy;
+use crate::host_builds::HOST_BUILT_LANGUAGES;
+use crate::initramfs_manager::{BuildSite, InitramfsLanguage, get_languages_config};
+use crate::ksm::KsmSettings;
+use crate::profiles::SandboxProfile;
+use crate::redaction::Redactor;
+use crate::runtime_upgrades::{RuntimeVersions, UpgradeError};
+use crate::validation::{RequestLimits, ValidationErrors, validate_identifier, validate_resources};
+use cloude_types::{Resources, VmConfig};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+/// Content of `cloude.toml`. Every setting is optional and falls back to the
+/// value the backend was started with.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    limits: LimitsSection,
+    #[serde(default)]
+    vm: VmSection,
+    #[serde(default)]
+    redaction: RedactionSection,
+    /// Sandbox profiles, by name.
+    #[serde(default)]
+    profiles: BTreeMap<String, SandboxProfile>,
+    #[serde(default)]
+    egress: EgressPolicy,
+    #[serde(default)]
+    ksm: KsmSettings,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct LimitsSection {
+    max_code_bytes: Option<usize>,
+    max_vcpus: Option<u8>,
+    max_memory_mb: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct VmSection {
+    vcpus: Option<u8>,
+    memory_mb: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct RedactionSection {
+    /// Regular expressions of what to redact from the logs of jobs.
+    patterns: Option<Vec<String>>,
+}
+
+/// Settings that can change while the backend runs. They only apply to
+/// requests and VMs created after the change; running VMs keep their shape.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReloadableConfig {
+    pub limits: RequestLimits,
+    pub vcpus: u8,
+    pub memory_mb: usize,
+    pub languages: Vec<InitramfsLanguage>,
+    /// Applied to the logs of jobs before they are stored or streamed.
+    pub redactor: Redactor,
+    /// Sandbox profiles jobs can run with, by name.
+    pub profiles: BTreeMap<String, SandboxProfile>,
+    /// Domains `egress-restricted` jobs may reach, by tenant.
+    pub egress: EgressPolicy,
+    /// Merging of the memory of VMs, and the tuning of `ksmd`.
+    pub ksm: KsmSettings,
+}
+
+impl ReloadableConfig {
+    /// Check the settings against what can be changed without a restart.
+    ///
+    /// # Arguments
+    /// * `max_body_bytes` - Request body limit installed on the router at startup.
+    pub fn validate(&self, max_body_bytes: usize) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        if self.limits.max_code_bytes == 0 {
+            errors.push("limits.max_code_bytes", "must be greater than 0");
+        } else if self.limits.max_body_bytes() > max_body_bytes {
+            errors.push(
+                "limits.max_code_bytes",
+                format!(
+                    "needs a {} bytes body limit, above the {} bytes set at startup; restart to raise it",
+                    self.limits.max_body_bytes(),
+                    max_body_bytes
+                ),
+            );
+        }
+
+        let defaults = Resources {
+            vcpus: Some(self.vcpus),
+            memory_mb: Some(self.memory_mb),
+        };
+        validate_resources(&mut errors, "vm", &defaults, &self.limits);
+
+        if self.languages.is_empty() {
+            errors.push("languages", "at least one runtime must be configured");
+        }
+        for language in &self.languages {
+            validate_identifier(&mut errors, "languages", &language.name);
+            if let Some(resources) = &language.resources {
+                let field = format!("languages.{}.resources", language.name);
+                validate_resources(&mut errors, &field, resources, &self.limits);
+            }
+            if language.run_image.is_some() && !COMPILED_LANGUAGES.contains(&language.name.as_str())
+            {
+                errors.push(
+                    &format!("languages.{}.run_image", language.name),
+                    "only applies to compiled runtimes",
+                );
+            }
+            if language.build == BuildSite::Host {
+                let field = format!("languages.{}.build", language.name);
+                if !HOST_BUILT_LANGUAGES.contains(&language.name.as_str()) {
+                    errors.push(&field, "only the rust runtime can be built on the host");
+                } else if language.run_image.is_none() {
+                    errors.push(
+                        &field,
+                        "host builds need a run_image to run the binaries in",
+                    );
+                }
+            }
+        }
+
+        for (name, profile) in &self.profiles {
+            validate_identifier(&mut errors, "profiles", name);
+            if let Some(resources) = &profile.resources {
+                let field = format!("profiles.{}.resources", name);
+                validate_resources(&mut errors, &field, resources, &self.limits);
+            }
+            for runtime in profile.runtimes.iter().flatten() {
+                if !self.languages.iter().any(|l| l.name == *runtime) {
+                    errors.push(
+                        &format!("profiles.{}.runtimes", name),
+                        format!("{} is not a configured runtime", runtime),
+                    );
+                }
+            }
+        }
+        self.egress.validate(&mut errors);
+        self.ksm.validate(&mut errors);
+
+        errors.into_result(())
+    }
+
+    /// Human-readable list of the settings that differ in `new`.
+    pub fn diff(&self, new: &ReloadableConfig) -> Vec<String> {
+        let mut changes = Vec::new();
+
+        if self.limits.max_code_bytes != new.limits.max_code_bytes {
+            changes.push(format!(
+                "limits.max_code_bytes: {} -> {}",
+                self.limits.max_code_bytes, new.limits.max_code_bytes
+            ));
+        }
+        if self.limits.max_vcpus != new.limits.max_vcpus {
+            changes.push(format!(
+                "limits.max_vcpus: {} -> {}",
+                self.limits.max_vcpus, new.limits.max_vcpus
+            ));
+        }
+        if self.limits.max_memory_mb != new.limits.max_memory_mb {
+            changes.push(format!(
+                "limits.max_memory_mb: {} -> {}",
+                self.limits.max_memory_mb, new.limits.max_memory_mb
+            ));
+        }
+        if self.vcpus != new.vcpus {
+            changes.push(format!("vm.vcpus: {} -> {}", self.vcpus, new.vcpus));
+        }
+        if self.memory_mb != new.memory_mb {
+            changes.push(format!(
+                "vm.memory_mb: {} -> {}",
+                self.memory_mb, new.memory_mb
+            ));
+        }
+
+        if self.redactor != new.redactor {
+            changes.push(format!(
+                "redaction.patterns: {} -> {} patterns",
+                self.redactor.patterns().count(),
+                new.redactor.patterns().count()
+            ));
+        }
+
+        for language in &new.languages {
+            match self.languages.iter().find(|l| l.name == language.name) {
+                None => changes.push(format!(
+                    "runtime {} added ({})",
+                    language.name, language.base_image
+                )),
+                Some(old)
+                    if old.run_image != language.run_image
+                        && old.version == language.version
+                        && old.base_image == language.base_image =>
+                {
+                    changes.push(format!(
+                        "runtime {} run image: {} -> {}",
+                        language.name,
+                        old.run_image.as_deref().unwrap_or("none"),
+                        language.run_image.as_deref().unwrap_or("none")
+                    ))
+                }
+                Some(old)
+                    if old.build != language.build
+                        && old.same_image(&InitramfsLanguage {
+                            build: old.build,
+                            ..language.clone()
+                        }) =>
+                {
+                    changes.push(format!(
+                        "runtime {} build: {} -> {}",
+                        language.name, old.build, language.build
+                    ))
+                }
+                Some(old) if !old.same_image(language) => changes.push(format!(
+                    "runtime {}: {} ({}) -> {} ({})",
+                    language.name,
+                    old.version,
+                    old.base_image,
+                    language.version,
+                    language.base_image
+                )),
+                Some(old) if old.resources != language.resources => changes.push(format!(
+                    "runtime {} resources: {} -> {}",
+                    language.name,
+                    describe_resources(old.resources.as_ref()),
+                    describe_resources(language.resources.as_ref())
+                )),
+                Some(_) => {}
+            }
+        }
+        for language in &self.languages {
+            if !new.languages.iter().any(|l| l.name == language.name) {
+                changes.push(format!("runtime {} removed", language.name));
+            }
+        }
+
+        for (name, profile) in &new.profiles {
+            match self.profiles.get(name) {
+                None => changes.push(format!("profile {} added", name)),
+                Some(old) if old != profile => changes.push(format!("profile {} changed", name)),
+                Some(_) => {}
+            }
+        }
+        for name in self.profiles.keys() {
+            if !new.profiles.contains_key(name) {
+                changes.push(format!("profile {} removed", name));
+            }
+        }
+        if self.egress != new.egress {
+            changes.push("egress allowlists changed".to_string());
+        }
+        if self.ksm != new.ksm {
+            changes.push("ksm settings changed".to_string());
+        }
+
+        changes
+    }
+
+    /// Runtimes that are new or changed in `new` and need their image built.
+    fn runtimes_to_build<'a>(&self, new: &'a ReloadableConfig) -> Vec<&'a InitramfsLanguage> {
+        new.languages
+            .iter()
+            .filter(|language| !self.languages.iter().any(|old| old.same_image(language)))
+            .collect()
+    }
+
+    /// Runtime whose VMs run the compiled code of `language`, when it has a run image.
+    pub fn run_stage(&self, language: &str) -> Option<String> {
+        self.languages
+            .iter()
+            .find(|l| l.name == language)
+            .and_then(InitramfsLanguage::run_stage_name)
+    }
+
+    /// Whether the code of `language` is compiled on the host, see [`BuildSite::Host`].
+    pub fn builds_on_host(&self, language: &str) -> bool {
+        self.languages
+            .iter()
+            .any(|l| l.name == language && l.build == BuildSite::Host)
+    }
+
+    /// VM shape of a job of `language`. What `requested` leaves out comes from the
+    /// runtime's profile, then from `[vm]`. `requested` must have been checked with
+    /// [`validate_resources`]; profiles are checked by [`ReloadableConfig::validate`].
+    pub fn job_vm(&self, language: &str, requested: Option<&Resources>) -> VmConfig {
+        let profile = self
+            .languages
+            .iter()
+            .find(|l| l.name == language)
+            .and_then(|l| l.resources.as_ref());
+        let vcpus = requested
+            .and_then(|r| r.vcpus)
+            .or(profile.and_then(|p| p.vcpus));
+        let memory_mb = requested
+            .and_then(|r| r.memory_mb)
+            .or(profile.and_then(|p| p.memory_mb));
+        VmConfig {
+            vcpus: vcpus.unwrap_or(self.vcpus),
+            memory_mb: memory_mb.unwrap_or(self.memory_mb),
+        }
+    }
+}
+
+/// `resources` as shown in the changes of a reload.
+fn describe_resources(resources: Option<&Resources>) -> String {
+    let Some(resources) = resources else {
+        return "defaults".to_string();
+    };
+    let vcpus = resources
+        .vcpus
+        .map_or("default".to_string(), |v| v.to_string());
+    let memory_mb = resources
+        .memory_mb
+        .map_or("default".to_string(), |m| m.to_string());
+    format!("{} vCPUs, {} MiB", vcpus, memory_mb)
+}
+
+/// Errors that can occur while loading or reloading the configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Runtime(UpgradeError),
+    Invalid(ValidationErrors),
+    Build(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "IO error: {}", e),
+            ConfigError::Toml(e) => write!(f, "Invalid TOML: {}", e),
+            ConfigError::Runtime(e) => write!(f, "Failed to resolve runtime versions: {}", e),
+            ConfigError::Invalid(e) => write!(f, "Invalid configuration: {}", e),
+            ConfigError::Build(e) => write!(f, "Failed to build runtime image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        ConfigError::Toml(err)
+    }
+}
+
+impl From<UpgradeError> for ConfigError {
+    fn from(err: UpgradeError) -> Self {
+        ConfigError::Runtime(err)
+    }
+}
+
+/// Where runtime images are built when a reload adds or changes a runtime.
+pub struct ImageBuildSettings {
+    pub agent_binary: String,
+    pub init_script: String,
+    pub initramfs_dir: String,
+}
+
+/// Holds the current [`ReloadableConfig`] and replaces it on demand from
+/// `cloude.toml` and the runtime manifest.
+pub struct ConfigReloader {
+    config_path: PathBuf,
+    languages_path: String,
+    /// Values used for settings `cloude.toml` leaves out.
+    defaults: ReloadableConfig,
+    max_body_bytes: usize,
+    runtime_versions: Arc<RuntimeVersions>,
+    images: ImageBuildSettings,
+    current: RwLock<Arc<ReloadableConfig>>,
+    reload_lock: tokio::sync::Mutex<()>,
+}
+
+impl ConfigReloader {
+    /// Loads the initial configuration. Runtime images are not built here.
+    ///
+    /// # Arguments
+    /// * `config_path` - Path to `cloude.toml`; a missing file means "use the defaults".
+    /// * `defaults` - Settings from the environment. Its `languages` are ignored.
+    pub fn new(
+        config_path: PathBuf,
+        languages_path: String,
+        defaults: ReloadableConfig,
+        runtime_versions: Arc<RuntimeVersions>,
+        images: ImageBuildSettings,
+    ) -> Result<Self, ConfigError> {
+        let mut reloader = Self {
+            config_path,
+            languages_path,
+            max_body_bytes: defaults.limits.max_body_bytes(),
+            defaults,
+            runtime_versions,
+            images,
+            current: RwLock::new(Arc::new(ReloadableConfig {
+                limits: RequestLimits::default(),
+                vcpus: 0,
+                memory_mb: 0,
+                languages: Vec::new(),
+                redactor: Redactor::default(),
+                profiles: BTreeMap::new(),
+                egress: EgressPolicy::default(),
+                ksm: KsmSettings::default(),
+            })),
+            reload_lock: tokio::sync::Mutex::new(()),
+        };
+
+        let initial = reloader.load()?;
+        // The body limit is fixed at startup: honor the file's code limit from the start.
+        reloader.max_body_bytes = reloader.max_body_bytes.max(initial.limits.max_body_bytes());
+        initial
+            .validate(reloader.max_body_bytes)
+            .map_err(ConfigError::Invalid)?;
+        *reloader.current.write().unwrap() = Arc::new(initial);
+
+        Ok(reloader)
+    }
+
+    /// The configuration in effect. Callers should keep the returned snapshot for
+    /// the whole request so they see consistent settings.
+    pub fn current(&self) -> Arc<ReloadableConfig> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Request body limit to install on the router.
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    /// Reads `cloude.toml` and the runtime manifest without applying them.
+    pub fn load(&self) -> Result<ReloadableConfig, ConfigError> {
+        let file = read_config_file(&self.config_path)?;
+        let languages = self
+            .runtime_versions
+            .resolve(get_languages_config(&self.languages_path)?)?;
+        let redactor = match file.redaction.patterns {
+            Some(patterns) => Redactor::new(&patterns).map_err(|e| {
+                let mut errors = ValidationErrors::default();
+                errors.push("redaction.patterns", e.to_string());
+                ConfigError::Invalid(errors)
+            })?,
+            None => self.defaults.redactor.clone(),
+        };
+
+        Ok(ReloadableConfig {
+            limits: RequestLimits {
+                max_code_bytes: file
+                    .limits
+                    .max_code_bytes
+                    .unwrap_or(self.defaults.limits.max_code_bytes),
+                max_vcpus: file
+                    .limits
+                    .max_vcpus
+                    .unwrap_or(self.defaults.limits.max_vcpus),
+                max_memory_mb: file
+                    .limits
+                    .max_memory_mb
+                    .unwrap_or(self.defaults.limits.max_memory_mb),
+            },
+            vcpus: file.vm.vcpus.unwrap_or(self.defaults.vcpus),
+            memory_mb: file.vm.memory_mb.unwrap_or(self.defaults.memory_mb),
+            languages,
+            redactor,
+            profiles: file.profiles,
+            egress: file.egress,
+            ksm: file.ksm,
+        })
+    }
+
+    /// Re-reads the configuration and applies it if it is valid and every new
+    /// runtime image builds. Otherwise nothing changes. Returns the applied changes.
+    pub async fn reload(&self) -> Result<Vec<String>, ConfigError> {
+        let _guard = self.reload_lock.lock().await;
+
+        let new = self.load()?;
+        new.validate(self.max_body_bytes)
+            .map_err(ConfigError::Invalid)?;
+
+        // New images are built beside the ones in use, which jobs of the current
+        // configuration boot from until the swap.
+        let current = self.current();
+        let built = current
+            .runtimes_to_build(&new)
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        for language in &built {
+            language
+                .stage_initramfs(
+                    &self.images.agent_binary,
+                    &self.images.init_script,
+                    &self.images.initramfs_dir,
+                )
+                .await
+                .map_err(|e| ConfigError::Build(format!("{}: {}", language.name, e)))?;
+        }
+
+        let changes = current.diff(&new);
+        *self.current.write().unwrap() = Arc::new(new);
+
+        for language in &built {
+            if let Err(e) = language.remove_other_versions(&self.images.initramfs_dir) {
+                warn!("Failed to remove old images of {}: {}", language.name, e);
+            }
+        }
+
+        if changes.is_empty() {
+            info!("Configuration reloaded, nothing changed");
+        }
+        for change in &changes {
+            info!("Configuration changed: {}", change);
+        }
+
+        Ok(changes)
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<ConfigFile, ConfigError> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(toml::from_str(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ConfigFile::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn language(name: &str, version: &str) -> InitramfsLanguage {
+        InitramfsLanguage {
+            name: name.to_string(),
+            version: version.to_string(),
+            base_image: format!("{}:{}-alpine", name, version),
+            warmup_code: None,
+            upgrade: None,
+            resources: None,
+            run_image: None,
+            arches: Vec::new(),
+            build: BuildSite::Vm,
+        }
+    }
+
+    fn config() -> ReloadableConfig {
+        ReloadableConfig {
+            limits: RequestLimits {
+                max_code_bytes: 1024,
+                ..RequestLimits::default()
+            },
+            vcpus: 1,
+            memory_mb: 512,
+            languages: vec![language("python", "3.11"), language("node", "20")],
+            redactor: Redactor::default(),
+            profiles: BTreeMap::new(),
+            egress: EgressPolicy::default(),
+            ksm: KsmSettings::default(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let base = config();
+        let max_body_bytes = base.limits.max_body_bytes();
+        assert!(base.validate(max_body_bytes).is_ok());
+
+        let mut invalid = base.clone();
+        invalid.limits.max_code_bytes = max_body_bytes;
+        invalid.vcpus = 0;
+        invalid.languages[1].resources = Some(Resources {
+            vcpus: None,
+            memory_mb: Some(invalid.limits.max_memory_mb * 2),
+        });
+        invalid.languages[0].run_image = Some("alpine:3.20".to_string());
+        invalid.languages[1].build = BuildSite::Host;
+        invalid.languages.push(language("../ruby", "3"));
+        invalid.languages.push(language("rust", "1.81"));
+        invalid.languages[3].build = BuildSite::Host;
+        invalid.profiles.insert(
+            "strict".to_string(),
+            toml::from_str("runtimes = [\"python\", \"cobol\"]").unwrap(),
+        );
+        let errors = invalid.validate(max_body_bytes).unwrap_err();
+        let fields = errors
+            .errors
+            .iter()
+            .map(|e| e.field.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                "limits.max_code_bytes",
+                "vm.vcpus",
+                "languages.python.run_image",
+                "languages.node.resources.memory_mb",
+                "languages.node.build",
+                "languages",
+                "languages.rust.build",
+                "profiles.strict.runtimes",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = config();
+        let mut new = old.clone();
+        assert!(old.diff(&new).is_empty());
+
+        new.vcpus = 2;
+        new.languages = vec![language("python", "3.12"), language("ruby", "3.3")];
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                "vm.vcpus: 1 -> 2",
+                "runtime python: 3.11 (python:3.11-alpine) -> 3.12 (python:3.12-alpine)",
+                "runtime ruby added (ruby:3.3-alpine)",
+                "runtime node removed",
+            ]
+        );
+        assert_eq!(old.runtimes_to_build(&new).len(), 2);
+
+        // A new profile changes no image.
+        let mut new = old.clone();
+        new.languages[1].resources = Some(Resources {
+            vcpus: Some(2),
+            memory_mb: None,
+        });
+        assert_eq!(
+            old.diff(&new),
+            vec!["runtime node resources: defaults -> 2 vCPUs, default MiB"]
+        );
+        assert!(old.runtimes_to_build(&new).is_empty());
+
+        // A run image is one more image to build.
+        let mut old = config();
+        old.languages.push(language("rust", "1.81"));
+        let mut new = old.clone();
+        new.languages[2].run_image = Some("alpine:3.20".to_string());
+        assert_eq!(
+            old.diff(&new),
+            vec!["runtime rust run image: none -> alpine:3.20"]
+        );
+        assert_eq!(old.runtimes_to_build(&new).len(), 1);
+        assert_eq!(new.run_stage("rust").as_deref(), Some("rust_run"));
+        assert_eq!(old.run_stage("rust"), None);
+
+        // Building on the host drops the toolchain image.
+        let old = new.clone();
+        new.languages[2].build = BuildSite::Host;
+        assert_eq!(old.diff(&new), vec!["runtime rust build: vm -> host"]);
+        assert_eq!(old.runtimes_to_build(&new).len(), 1);
+        assert!(new.builds_on_host("rust"));
+        assert!(!old.builds_on_host("rust"));
+    }
+
+    #[test]
+    fn test_job_vm() {
+        let mut config = config();
+        config.languages[1].resources = Some(Resources {
+            vcpus: Some(2),
+            memory_mb: Some(1024),
+        });
+        let shape = |vcpus, memory_mb| VmConfig { vcpus, memory_mb };
+
+        assert_eq!(config.job_vm("python", None), shape(1, 512));
+        assert_eq!(config.job_vm("node", None), shape(2, 1024));
+        let requested = Resources {
+            vcpus: None,
+            memory_mb: Some(256),
+        };
+        assert_eq!(config.job_vm("node", Some(&requested)), shape(2, 256));
+        assert_eq!(config.job_vm("python", Some(&requested)), shape(1, 256));
+    }
+
+    #[test]
+    fn test_load_overrides_defaults() {
+        let dir = TempDir::new().unwrap();
+        let config_path = dir.path().join("cloude.toml");
+        let languages_path = dir.path().join("languages.json");
+        std::fs::write(
+            &languages_path,
+            r#"{ "python": { "version": "3.11", "base_image": "python:3.11-alpine" } }"#,
+        )
+        .unwrap();
+        std::fs::write(&config_path, "[vm]\nmemory_mb = 256\n").unwrap();
+
+        let reloader = ConfigReloader::new(
+            config_path.clone(),
+            languages_path.to_str().unwrap().to_string(),
+            config(),
+            Arc::new(RuntimeVersions::new(dir.path().join("versions.json")).unwrap()),
+            ImageBuildSettings {
+                agent_binary: String::new(),
+                init_script: String::new(),
+                initramfs_dir: String::new(),
+            },
+        )
+        .unwrap();
+
+        let current = reloader.current();
+        assert_eq!(current.memory_mb, 256);
+        assert_eq!(current.vcpus, 1);
+        assert_eq!(current.limits.max_code_bytes, 1024);
+        assert_eq!(current.languages.len(), 1);
+
+        std::fs::write(&config_path, "[vm]\nmemroy_mb = 128\n").unwrap();
+        assert!(matches!(reloader.load(), Err(ConfigError::Toml(_))));
+
+        std::fs::write(&config_path, "[redaction]\npatterns = [\"token=\\\\w+\"]\n").unwrap();
+        assert_eq!(
+            reloader.load().unwrap().redactor.redact("token=abc"),
+            "[REDACTED]"
+        );
+        std::fs::write(&config_path, "[redaction]\npatterns = [\"(\"]\n").unwrap();
+        assert!(matches!(reloader.load(), Err(ConfigError::Invalid(_))));
+
+        std::fs::write(
+            &config_path,
+            "[profiles.strict]\nnetwork = false\nresources = { memory_mb = 128 }\n",
+        )
+        .unwrap();
+        let profiles = reloader.load().unwrap().profiles;
+        assert!(!profiles["strict"].network);
+        assert_eq!(
+            reloader.current().diff(&reloader.load().unwrap()),
+            vec!["vm.memory_mb: 256 -> 512", "profile strict added"]
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Serial console of a VM, shared between the VMM thread and console clients.
+//!
+//! The guest serial port reads from one end of a socket pair and writes into a
+//! [`ConsoleWriter`]. Output is kept in a short scrollback and broadcast to every
+//! attached client; input from any client is written to the other end of the pair.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Console output replayed to a client when it attaches.
+pub const SCROLLBACK_BYTES: usize = 64 * 1024;
+
+/// Output chunks a slow client can fall behind before it misses some.
+const OUTPUT_BACKLOG: usize = 256;
+
+pub struct SerialConsole {
+    input: Mutex<UnixStream>,
+    /// `None` once the VM is gone, which ends every attached session.
+    output: Mutex<Option<broadcast::Sender<Vec<u8>>>>,
+    scrollback: Mutex<VecDeque<u8>>,
+}
+
+impl SerialConsole {
+    /// New console, and the stream the VMM reads guest input from.
+    pub fn new() -> io::Result<(Arc<Self>, UnixStream)> {
+        let (host, guest) = UnixStream::pair()?;
+        // Input is dropped rather than blocking the caller when the guest does not read it.
+        host.set_nonblocking(true)?;
+        let (output, _) = broadcast::channel(OUTPUT_BACKLOG);
+        let console = Arc::new(Self {
+            input: Mutex::new(host),
+            output: Mutex::new(Some(output)),
+            scrollback: Mutex::new(VecDeque::new()),
+        });
+        Ok((console, guest))
+    }
+
+    /// Output sink handed to the VMM.
+    pub fn writer(self: &Arc<Self>) -> ConsoleWriter {
+        ConsoleWriter(Arc::clone(self))
+    }
+
+    /// Recent output and a receiver for what follows it, or `None` if the VM is gone.
+    pub fn attach(&self) -> Option<(Vec<u8>, broadcast::Receiver<Vec<u8>>)> {
+        // Holding the scrollback lock keeps output from slipping between the two.
+        let scrollback = self.scrollback.lock().unwrap_or_else(|e| e.into_inner());
+        let output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        let receiver = output.as_ref()?.subscribe();
+        Some((scrollback.iter().copied().collect(), receiver))
+    }
+
+    /// Sends keystrokes to the guest serial port.
+    pub fn send_input(&self, data: &[u8]) -> io::Result<()> {
+        let mut input = self.input.lock().unwrap_or_else(|e| e.into_inner());
+        input.write_all(data)
+    }
+
+    /// Detaches every client. Called when the VM is destroyed.
+    pub fn close(&self) {
+        self.output.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+
+    fn publish(&self, data: &[u8]) {
+        let mut scrollback = self.scrollback.lock().unwrap_or_else(|e| e.into_inner());
+        scrollback.extend(data);
+        let excess = scrollback.len().saturating_sub(SCROLLBACK_BYTES);
+        scrollback.drain(..excess);
+
+        if let Some(output) = self
+            .output
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+        {
+            // No receiver just means nobody is attached.
+            let _ = output.send(data.to_vec());
+        }
+    }
+}
+
+/// Guest serial output, written by the VMM thread.
+pub struct ConsoleWriter(Arc<SerialConsole>);
+
+impl Write for ConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.publish(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_output_is_replayed_then_streamed() {
+        let (console, _guest) = SerialConsole::new().unwrap();
+        let mut writer = console.writer();
+        writer.write_all(b"boot\n").unwrap();
+
+        let (scrollback, mut output) = console.attach().unwrap();
+        assert_eq!(scrollback, b"boot\n");
+
+        writer.write_all(b"login: ").unwrap();
+        assert_eq!(output.try_recv().unwrap(), b"login: ");
+
+        console.close();
+        assert!(matches!(
+            output.try_recv(),
+            Err(broadcast::error::TryRecvError::Closed)
+        ));
+        assert!(console.attach().is_none());
+    }
+
+    #[test]
+    fn test_scrollback_is_bounded() {
+        let (console, _guest) = SerialConsole::new().unwrap();
+        let mut writer = console.writer();
+        writer.write_all(&vec![b'a'; SCROLLBACK_BYTES]).unwrap();
+        writer.write_all(b"end").unwrap();
+
+        let (scrollback, _) = console.attach().unwrap();
+        assert_eq!(scrollback.len(), SCROLLBACK_BYTES);
+        assert!(scrollback.ends_with(b"end"));
+    }
+
+    #[test]
+    fn test_input_reaches_guest() {
+        let (console, mut guest) = SerialConsole::new().unwrap();
+        console.send_input(b"ls\r").unwrap();
+
+        let mut buf = [0u8; 3];
+        guest.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ls\r");
+    }
+}
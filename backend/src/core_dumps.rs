@@ -0,0 +1,57 @@
+//! Core dumps of the code of crashed jobs, for `GET /core/{id}`.
+//!
+//! The agent hands back the core with the result of the job, cut to the size the
+//! backend asked for. Cores are kept in the [`BlobStore`] under `cores/<job id>`.
+
+use crate::blob_store::{BlobStore, BlobStoreError, CORES_PREFIX};
+use std::sync::Arc;
+
+pub struct CoreDumps {
+    blobs: Arc<dyn BlobStore>,
+    /// Size cores are cut to in the guest, `0` keeps none.
+    max_bytes: u64,
+}
+
+impl CoreDumps {
+    pub fn new(blobs: Arc<dyn BlobStore>, max_bytes: u64) -> Self {
+        Self { blobs, max_bytes }
+    }
+
+    /// Size the guest cuts cores to, `0` when it dumps none.
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// Keeps the core job `job_id` dumped.
+    pub async fn put(&self, job_id: &str, core: Vec<u8>) -> Result<(), BlobStoreError> {
+        self.blobs.put(&core_key(job_id), core).await
+    }
+
+    /// The core job `job_id` dumped, if any.
+    pub async fn get(&self, job_id: &str) -> Result<Option<Vec<u8>>, BlobStoreError> {
+        self.blobs.get(&core_key(job_id)).await
+    }
+}
+
+fn core_key(job_id: &str) -> String {
+    format!("{}{}", CORES_PREFIX, job_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::LocalBlobStore;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_cores_by_job() {
+        let dir = TempDir::new().unwrap();
+        let blobs = Arc::new(LocalBlobStore::new(dir.path()).await.unwrap());
+        let cores = CoreDumps::new(blobs.clone(), 1024);
+
+        cores.put("job-1", b"\x7fELF core".to_vec()).await.unwrap();
+        assert_eq!(cores.get("job-1").await.unwrap().unwrap(), b"\x7fELF core");
+        assert_eq!(cores.get("job-2").await.unwrap(), None);
+        assert_eq!(blobs.list(CORES_PREFIX).await.unwrap(), ["cores/job-1"]);
+    }
+}
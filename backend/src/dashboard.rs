@@ -0,0 +1,674 @@
+//! Aggregates of the invocations of deployed functions, for dashboards.
+//!
+//! Jobs are evicted minutes after they end, so every invocation of a function
+//! is recorded here as it ends, and kept for the retention of the dashboards.
+//! Invocations older than the raw window are rolled up, by a task of the
+//! backend, into a summary per function and minute: counters and a histogram
+//! of latencies. Rollups take far less memory than the invocations, but their
+//! percentiles are approximate, rounded up to the bound of their histogram
+//! bucket. Without a raw window, invocations are kept as they are and every
+//! percentile is exact.
+//!
+//! Concurrency is sampled as invocations start: the high-water mark of a
+//! minute is the most invocations of the function running when one started.
+//!
+//! Invocations are kept apart by the tenant of their job, and a tenant only
+//! sees its own. With a file, what is kept is saved to it by the rollup task and
+//! when the backend stops, and loaded again when it starts.
+
+use cloude_types::{DashboardBucket, FunctionDashboard};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long invocations are kept for the dashboards when the backend does not say.
+pub const DEFAULT_DASHBOARD_RETENTION_SECS: u64 = 24 * 60 * 60;
+/// How long invocations are kept as they are before they are rolled up, when
+/// the backend does not say.
+pub const DEFAULT_DASHBOARD_ROLLUP_AFTER_SECS: u64 = 10 * 60;
+/// Most buckets a query returns per function.
+pub const MAX_DASHBOARD_BUCKETS: u64 = 1440;
+
+const MINUTE_MS: u64 = 60 * 1000;
+/// Buckets of the latency histogram per doubling of the latency.
+const HISTOGRAM_STEPS: f64 = 4.0;
+
+/// Errors that can occur while loading or saving the dashboards.
+#[derive(Debug)]
+pub enum DashboardError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl std::fmt::Display for DashboardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DashboardError::Io(e) => write!(f, "IO error: {}", e),
+            DashboardError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DashboardError {}
+
+impl From<std::io::Error> for DashboardError {
+    fn from(err: std::io::Error) -> Self {
+        DashboardError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for DashboardError {
+    fn from(err: serde_json::Error) -> Self {
+        DashboardError::Json(err)
+    }
+}
+
+/// An invocation of a function that ended.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Invocation {
+    /// Milliseconds since the Unix epoch.
+    pub finished_ms: u64,
+    /// From the submission of its job to its result.
+    pub duration_ms: u64,
+    pub failed: bool,
+    /// Whether it got a VM already up; `None` when it ran in no VM.
+    pub warm: Option<bool>,
+}
+
+/// Counts of latencies in buckets of a quarter of a doubling, so that the
+/// bound of a bucket is at most a fifth over the latencies it counts.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+struct Histogram(BTreeMap<u16, u64>);
+
+impl Histogram {
+    fn add(&mut self, latency_ms: u64, count: u64) {
+        let bucket = match latency_ms {
+            0 => 0,
+            ms => ((ms as f64).log2() * HISTOGRAM_STEPS).floor() as u16 + 1,
+        };
+        *self.0.entry(bucket).or_default() += count;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in &other.0 {
+            *self.0.entry(*bucket).or_default() += count;
+        }
+    }
+
+    /// The largest latency `bucket` counts.
+    fn bound(bucket: u16) -> u64 {
+        match bucket {
+            0 => 0,
+            b => 2f64.powf(b as f64 / HISTOGRAM_STEPS).ceil() as u64 - 1,
+        }
+    }
+
+    /// Bound of the bucket of the `rank`-th latency, from 1.
+    fn nth(&self, rank: u64) -> Option<u64> {
+        let mut seen = 0;
+        for (bucket, count) in &self.0 {
+            seen += count;
+            if seen >= rank {
+                return Some(Self::bound(*bucket));
+            }
+        }
+        None
+    }
+}
+
+/// Invocations of a function in a minute, once rolled up.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+struct Rollup {
+    invocations: u64,
+    errors: u64,
+    cold: u64,
+    warm: u64,
+    latencies: Histogram,
+}
+
+impl Rollup {
+    fn add(&mut self, invocation: &Invocation) {
+        self.count(invocation);
+        self.latencies.add(invocation.duration_ms, 1);
+    }
+
+    /// Adds `invocation` to the counters only.
+    fn count(&mut self, invocation: &Invocation) {
+        self.invocations += 1;
+        self.errors += invocation.failed as u64;
+        match invocation.warm {
+            Some(true) => self.warm += 1,
+            Some(false) => self.cold += 1,
+            None => {}
+        }
+    }
+}
+
+/// The invocations of a function of a namespace by a tenant, `None` for
+/// anonymous jobs.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+struct SeriesKey {
+    namespace: String,
+    function: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+}
+
+impl SeriesKey {
+    fn new(namespace: &str, function: &str, tenant: Option<&str>) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            function: function.to_string(),
+            tenant: tenant.map(str::to_string),
+        }
+    }
+}
+
+/// What is kept of a function.
+#[derive(Serialize, Deserialize, Default)]
+struct Series {
+    /// Invocations of the raw window, in the order they ended.
+    raw: VecDeque<Invocation>,
+    /// Rolled up invocations, by minute.
+    minutes: BTreeMap<u64, Rollup>,
+    /// Concurrency high-water marks, by minute.
+    peaks: BTreeMap<u64, u32>,
+}
+
+impl Series {
+    fn is_empty(&self) -> bool {
+        self.raw.is_empty() && self.minutes.is_empty() && self.peaks.is_empty()
+    }
+}
+
+/// A bucket being aggregated.
+#[derive(Default)]
+struct Aggregate {
+    rollup: Rollup,
+    /// Latencies of raw invocations, exact until a rollup is added.
+    exact: Vec<u64>,
+    max_concurrency: u32,
+}
+
+impl Aggregate {
+    fn add_raw(&mut self, invocation: &Invocation) {
+        self.rollup.count(invocation);
+        self.exact.push(invocation.duration_ms);
+    }
+
+    fn add_rollup(&mut self, rollup: &Rollup) {
+        self.rollup.invocations += rollup.invocations;
+        self.rollup.errors += rollup.errors;
+        self.rollup.cold += rollup.cold;
+        self.rollup.warm += rollup.warm;
+        self.rollup.latencies.merge(&rollup.latencies);
+    }
+
+    fn merge(&mut self, other: &Aggregate) {
+        self.add_rollup(&other.rollup);
+        self.exact.extend_from_slice(&other.exact);
+        self.max_concurrency = self.max_concurrency.max(other.max_concurrency);
+    }
+
+    fn finish(mut self, start: u64) -> DashboardBucket {
+        let invocations = self.rollup.invocations;
+        let mut percentile = |p: u64| {
+            if invocations == 0 {
+                return None;
+            }
+            // Nearest rank: the smallest latency at least `p` percent are under.
+            let rank = (invocations * p).div_ceil(100).max(1);
+            if self.rollup.latencies.0.is_empty() {
+                self.exact.sort_unstable();
+                return self.exact.get(rank as usize - 1).copied();
+            }
+            let mut latencies = self.rollup.latencies.clone();
+            for latency in &self.exact {
+                latencies.add(*latency, 1);
+            }
+            latencies.nth(rank)
+        };
+        let (p50_ms, p95_ms, p99_ms) = (percentile(50), percentile(95), percentile(99));
+        let rollup = &self.rollup;
+        let starts = rollup.cold + rollup.warm;
+        DashboardBucket {
+            start,
+            invocations,
+            errors: rollup.errors,
+            error_rate: match invocations {
+                0 => 0.0,
+                n => rollup.errors as f64 / n as f64,
+            },
+            p50_ms,
+            p95_ms,
+            p99_ms,
+            cold_starts: rollup.cold,
+            warm_starts: rollup.warm,
+            cold_start_ratio: (starts > 0).then(|| rollup.cold as f64 / starts as f64),
+            max_concurrency: self.max_concurrency,
+        }
+    }
+}
+
+/// Invocations of every function, by namespace, name and tenant.
+pub struct Dashboards {
+    retention: Duration,
+    /// How long invocations stay raw, forever within the retention if `None`.
+    rollup_after: Option<Duration>,
+    series: Mutex<HashMap<SeriesKey, Series>>,
+    /// File the dashboards are saved to, if any.
+    file_path: Option<PathBuf>,
+}
+
+impl Dashboards {
+    pub fn new(retention: Duration, rollup_after: Option<Duration>) -> Self {
+        Self {
+            retention,
+            rollup_after,
+            series: Mutex::default(),
+            file_path: None,
+        }
+    }
+
+    /// Saves the dashboards to `file_path` from now on, starting from what it
+    /// holds if it exists.
+    pub fn with_file<P: AsRef<Path>>(mut self, file_path: P) -> Result<Self, DashboardError> {
+        let file_path = file_path.as_ref().to_path_buf();
+        match std::fs::read_to_string(&file_path) {
+            Ok(contents) if !contents.trim().is_empty() => {
+                let saved: Vec<(SeriesKey, Series)> = serde_json::from_str(&contents)?;
+                *self.lock() = saved.into_iter().collect();
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.file_path = Some(file_path);
+        Ok(self)
+    }
+
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    /// Writes the dashboards to their file, if they have one, through a
+    /// temporary file renamed over the previous one.
+    pub fn save(&self) -> Result<(), DashboardError> {
+        let Some(file_path) = &self.file_path else {
+            return Ok(());
+        };
+        let json = {
+            let series = self.lock();
+            let saved: Vec<(&SeriesKey, &Series)> = series.iter().collect();
+            serde_json::to_string(&saved)?
+        };
+        let tmp_path = file_path.with_extension("json.tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, file_path)?;
+        Ok(())
+    }
+
+    /// Records `invocation` of `function` of `namespace`, run for `tenant`.
+    pub fn record(
+        &self,
+        namespace: &str,
+        function: &str,
+        tenant: Option<&str>,
+        invocation: Invocation,
+    ) {
+        self.lock()
+            .entry(SeriesKey::new(namespace, function, tenant))
+            .or_default()
+            .raw
+            .push_back(invocation);
+    }
+
+    /// Records that `running` invocations of `function` ran for `tenant` at `at_ms`.
+    pub fn record_concurrency(
+        &self,
+        namespace: &str,
+        function: &str,
+        tenant: Option<&str>,
+        at_ms: u64,
+        running: u32,
+    ) {
+        let mut series = self.lock();
+        let peak = series
+            .entry(SeriesKey::new(namespace, function, tenant))
+            .or_default()
+            .peaks
+            .entry(at_ms - at_ms % MINUTE_MS)
+            .or_default();
+        *peak = (*peak).max(running);
+    }
+
+    /// Rolls up the invocations that left the raw window at `now_ms`, and
+    /// forgets what is past the retention. Returns how many were rolled up.
+    pub fn roll_up(&self, now_ms: u64) -> usize {
+        let expired = now_ms.saturating_sub(self.retention.as_millis() as u64);
+        let raw_until = self
+            .rollup_after
+            .map(|after| now_ms.saturating_sub(after.as_millis() as u64));
+        let mut rolled = 0;
+        let mut series = self.lock();
+        for function in series.values_mut() {
+            while let Some(invocation) = function.raw.front() {
+                let finished = invocation.finished_ms;
+                if finished >= expired && raw_until.is_none_or(|until| finished >= until) {
+                    break;
+                }
+                let invocation = function.raw.pop_front().unwrap();
+                if finished >= expired {
+                    function
+                        .minutes
+                        .entry(finished - finished % MINUTE_MS)
+                        .or_default()
+                        .add(&invocation);
+                    rolled += 1;
+                }
+            }
+            let first_minute = expired - expired % MINUTE_MS;
+            function.minutes = function.minutes.split_off(&first_minute);
+            function.peaks = function.peaks.split_off(&first_minute);
+        }
+        series.retain(|_, function| !function.is_empty());
+        rolled
+    }
+
+    /// The functions of `namespace`, only `function` if set, with invocations
+    /// run for `tenant` that ended from `from_ms` to `to_ms`, in buckets of
+    /// `bucket_ms` from `from_ms`, which the caller aligns on a minute.
+    pub fn query(
+        &self,
+        namespace: &str,
+        tenant: Option<&str>,
+        function: Option<&str>,
+        from_ms: u64,
+        to_ms: u64,
+        bucket_ms: u64,
+    ) -> Vec<FunctionDashboard> {
+        let buckets = to_ms.saturating_sub(from_ms).div_ceil(bucket_ms) as usize;
+        let index = |at_ms: u64| {
+            (from_ms..to_ms)
+                .contains(&at_ms)
+                .then(|| ((at_ms - from_ms) / bucket_ms) as usize)
+        };
+        let series = self.lock();
+        let mut dashboards: Vec<FunctionDashboard> = series
+            .iter()
+            .filter(|(key, _)| {
+                key.namespace == namespace
+                    && key.tenant.as_deref() == tenant
+                    && function.is_none_or(|f| f == key.function)
+            })
+            .filter_map(|(key, series)| {
+                let mut aggregates: Vec<Aggregate> =
+                    (0..buckets).map(|_| Aggregate::default()).collect();
+                for invocation in &series.raw {
+                    if let Some(i) = index(invocation.finished_ms) {
+                        aggregates[i].add_raw(invocation);
+                    }
+                }
+                for (minute, rollup) in series.minutes.range(from_ms..to_ms) {
+                    aggregates[index(*minute).unwrap()].add_rollup(rollup);
+                }
+                for (minute, peak) in series.peaks.range(from_ms..to_ms) {
+                    let aggregate = &mut aggregates[index(*minute).unwrap()];
+                    aggregate.max_concurrency = aggregate.max_concurrency.max(*peak);
+                }
+                let mut total = Aggregate::default();
+                for aggregate in &aggregates {
+                    total.merge(aggregate);
+                }
+                if total.rollup.invocations == 0 && total.max_concurrency == 0 {
+                    return None;
+                }
+                Some(FunctionDashboard {
+                    function: key.function.clone(),
+                    total: total.finish(from_ms / 1000),
+                    buckets: aggregates
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, aggregate)| {
+                            aggregate.finish((from_ms + i as u64 * bucket_ms) / 1000)
+                        })
+                        .collect(),
+                })
+            })
+            .collect();
+        dashboards.sort_by(|a, b| a.function.cmp(&b.function));
+        dashboards
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<SeriesKey, Series>> {
+        self.series.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T0: u64 = 1_760_000_040_000;
+
+    fn invocation(finished_ms: u64, duration_ms: u64, failed: bool, warm: bool) -> Invocation {
+        Invocation {
+            finished_ms,
+            duration_ms,
+            failed,
+            warm: Some(warm),
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let dashboards = Dashboards::new(Duration::from_secs(3600), None);
+        for i in 0..100 {
+            dashboards.record(
+                "default",
+                "resize",
+                None,
+                invocation(T0 + i, i + 1, i % 10 == 0, i >= 20),
+            );
+        }
+        dashboards.record(
+            "default",
+            "resize",
+            None,
+            invocation(T0 + MINUTE_MS, 500, false, true),
+        );
+        dashboards.record("team-a", "resize", None, invocation(T0, 1, false, true));
+        dashboards.record_concurrency("default", "resize", None, T0 + MINUTE_MS + 5, 3);
+        dashboards.record_concurrency("default", "resize", None, T0 + MINUTE_MS + 9, 2);
+
+        let found = dashboards.query("default", None, None, T0, T0 + 3 * MINUTE_MS, MINUTE_MS);
+        assert_eq!(found.len(), 1);
+        let resize = &found[0];
+        assert_eq!(resize.buckets.len(), 3);
+        let first = &resize.buckets[0];
+        assert_eq!(first.start, T0 / 1000);
+        assert_eq!(first.invocations, 100);
+        assert_eq!(first.errors, 10);
+        assert_eq!(first.error_rate, 0.1);
+        assert_eq!(
+            (first.p50_ms, first.p95_ms, first.p99_ms),
+            (Some(50), Some(95), Some(99))
+        );
+        assert_eq!((first.cold_starts, first.warm_starts), (20, 80));
+        assert_eq!(first.cold_start_ratio, Some(0.2));
+        assert_eq!(resize.buckets[1].max_concurrency, 3);
+        assert_eq!(resize.buckets[1].p50_ms, Some(500));
+        assert_eq!(resize.buckets[2].invocations, 0);
+        assert_eq!(resize.buckets[2].p50_ms, None);
+        assert_eq!(resize.total.invocations, 101);
+        assert_eq!(resize.total.max_concurrency, 3);
+
+        assert!(
+            dashboards
+                .query(
+                    "default",
+                    None,
+                    Some("thumbnail"),
+                    T0,
+                    T0 + MINUTE_MS,
+                    MINUTE_MS
+                )
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_roll_up() {
+        let dashboards = Dashboards::new(Duration::from_secs(3600), Some(Duration::from_secs(600)));
+        for i in 0..100 {
+            dashboards.record(
+                "default",
+                "resize",
+                None,
+                invocation(T0 + i, 100 * (i + 1), false, true),
+            );
+        }
+        dashboards.record_concurrency("default", "resize", None, T0, 4);
+
+        // Still in the raw window: nothing to roll up.
+        assert_eq!(dashboards.roll_up(T0 + 5 * MINUTE_MS), 0);
+        assert_eq!(dashboards.roll_up(T0 + 11 * MINUTE_MS), 100);
+        let bucket = &dashboards.query(
+            "default",
+            None,
+            Some("resize"),
+            T0,
+            T0 + MINUTE_MS,
+            MINUTE_MS,
+        )[0]
+        .buckets[0];
+        assert_eq!(bucket.invocations, 100);
+        assert_eq!(bucket.max_concurrency, 4);
+        // Approximate past the raw window, but never under the exact value.
+        let p99 = bucket.p99_ms.unwrap();
+        assert!((9900..=9900 * 6 / 5).contains(&p99), "{p99}");
+
+        // Past the retention, everything is forgotten.
+        dashboards.roll_up(T0 + 61 * MINUTE_MS);
+        assert!(
+            dashboards
+                .query("default", None, None, T0, T0 + MINUTE_MS, MINUTE_MS)
+                .is_empty()
+        );
+        assert!(dashboards.lock().is_empty());
+    }
+
+    #[test]
+    fn test_tenants_see_their_own_invocations() {
+        let dashboards = Dashboards::new(Duration::from_secs(3600), None);
+        dashboards.record(
+            "default",
+            "resize",
+            Some("alice"),
+            invocation(T0, 10, false, true),
+        );
+        dashboards.record(
+            "default",
+            "resize",
+            Some("bob"),
+            invocation(T0, 20, true, true),
+        );
+        dashboards.record_concurrency("default", "resize", Some("bob"), T0, 7);
+
+        let alice = dashboards.query(
+            "default",
+            Some("alice"),
+            None,
+            T0,
+            T0 + MINUTE_MS,
+            MINUTE_MS,
+        );
+        assert_eq!(alice.len(), 1);
+        assert_eq!(alice[0].total.invocations, 1);
+        assert_eq!(alice[0].total.errors, 0);
+        assert_eq!(alice[0].total.max_concurrency, 0);
+        assert!(
+            dashboards
+                .query("default", None, None, T0, T0 + MINUTE_MS, MINUTE_MS)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_saved_dashboards_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dashboards.json");
+        let retention = Duration::from_secs(3600);
+        let rollup_after = Some(Duration::from_secs(600));
+        let dashboards = Dashboards::new(retention, rollup_after)
+            .with_file(&path)
+            .unwrap();
+        for i in 0..10 {
+            dashboards.record(
+                "team-a",
+                "resize",
+                Some("alice"),
+                invocation(T0 + i, 100, false, true),
+            );
+        }
+        dashboards.record(
+            "team-a",
+            "resize",
+            Some("alice"),
+            invocation(T0 + 20 * MINUTE_MS, 5, false, false),
+        );
+        dashboards.record_concurrency("team-a", "resize", Some("alice"), T0, 2);
+        dashboards.roll_up(T0 + 20 * MINUTE_MS);
+        dashboards.save().unwrap();
+        let before = dashboards.query(
+            "team-a",
+            Some("alice"),
+            None,
+            T0,
+            T0 + 30 * MINUTE_MS,
+            MINUTE_MS,
+        );
+        drop(dashboards); // the backend stops
+
+        let dashboards = Dashboards::new(retention, rollup_after)
+            .with_file(&path)
+            .unwrap();
+        let after = dashboards.query(
+            "team-a",
+            Some("alice"),
+            None,
+            T0,
+            T0 + 30 * MINUTE_MS,
+            MINUTE_MS,
+        );
+        assert_eq!(after, before);
+        assert_eq!(after[0].total.invocations, 11);
+        assert_eq!(after[0].total.max_concurrency, 2);
+    }
+
+    #[test]
+    fn test_histogram_bounds() {
+        let mut histogram = Histogram::default();
+        for latency in [0, 1, 2, 3, 7, 100, 1000, 65_536] {
+            histogram.add(latency, 1);
+        }
+        for (rank, latency) in (1..).zip([0u64, 1, 2, 3, 7, 100, 1000, 65_536]) {
+            let bound = histogram.nth(rank).unwrap();
+            assert!(
+                bound >= latency && bound <= latency + latency / 5 + 1,
+                "{latency} {bound}"
+            );
+        }
+        assert_eq!(histogram.nth(9), None);
+    }
+}
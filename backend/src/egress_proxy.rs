@@ -0,0 +1,719 @@
+//! Egress proxy: the one way out of the VMs of `egress-restricted` jobs.
+//!
+//! The host forwards none of the packets of such a VM, see
+//! [`virt::network::restrict_egress`], and its code gets the proxy in
+//! `HTTP_PROXY` and `HTTPS_PROXY`. The proxy only connects it to the domains the
+//! `[egress]` section of `cloude.toml` allows the tenant of the job, so operators
+//! can let code fetch packages from a registry without letting it send data
+//! anywhere else. Every request is logged in the logs of its job.
+//!
+//! It speaks plain HTTP: `CONNECT host:port` tunnels, for HTTPS, and requests in
+//! absolute form (`GET http://host/path`), sent on with `Connection: close` so
+//! that a connection never reaches a second host. The domain is checked, not what
+//! goes through the tunnel.
+//!
+//! A domain is resolved before the proxy connects to it, and only to addresses
+//! of the internet: an allowed name pointing at the host, the bridge or a private
+//! network reaches none of them. Ports are limited to 80 and 443, or the
+//! `ports` of `[egress]`.
+
+use crate::job_logs::JobLog;
+use crate::validation::ValidationErrors;
+use cloude_types::LogSource;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// Longest request head the proxy reads.
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+/// How long the code has to send its request head, and the proxy to connect to the host.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ports jobs reach when `[egress]` names none: HTTP and HTTPS.
+pub const DEFAULT_PORTS: [u16; 2] = [80, 443];
+
+/// `[egress]` of `cloude.toml`: the domains jobs may reach through the proxy.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct EgressPolicy {
+    /// Domains every tenant may reach.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Ports of these domains jobs may connect to, [`DEFAULT_PORTS`] when empty.
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    /// Domains some tenants may reach besides, by tenant.
+    #[serde(default)]
+    pub tenants: BTreeMap<String, TenantEgress>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TenantEgress {
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+impl EgressPolicy {
+    /// Checks that every domain is a name, or `*.` and a name for its subdomains.
+    pub fn validate(&self, errors: &mut ValidationErrors) {
+        for pattern in &self.allow {
+            validate_pattern(errors, "egress.allow", pattern);
+        }
+        if self.ports.contains(&0) {
+            errors.push("egress.ports", "port 0 cannot be reached");
+        }
+        for (tenant, egress) in &self.tenants {
+            let field = format!("egress.tenants.{}.allow", tenant);
+            for pattern in &egress.allow {
+                validate_pattern(errors, &field, pattern);
+            }
+        }
+    }
+
+    /// What the jobs of `tenant` may reach; anonymous jobs get the common domains.
+    pub fn allowlist(&self, tenant: Option<&str>) -> Allowlist {
+        let tenant = tenant.and_then(|tenant| self.tenants.get(tenant));
+        Allowlist {
+            domains: self
+                .allow
+                .iter()
+                .chain(tenant.into_iter().flat_map(|egress| &egress.allow))
+                .map(|pattern| pattern.to_ascii_lowercase())
+                .collect(),
+            ports: if self.ports.is_empty() {
+                DEFAULT_PORTS.to_vec()
+            } else {
+                self.ports.clone()
+            },
+        }
+    }
+}
+
+fn validate_pattern(errors: &mut ValidationErrors, field: &str, pattern: &str) {
+    let name = pattern.strip_prefix("*.").unwrap_or(pattern);
+    let valid = !name.is_empty()
+        && name.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !valid {
+        errors.push(
+            field,
+            format!("{} is not a domain, nor `*.` and a domain", pattern),
+        );
+    }
+}
+
+/// Domains a job may reach: `example.com` is that name only, `*.example.com` its
+/// subdomains but not itself. And the ports it may reach them on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Allowlist {
+    domains: Vec<String>,
+    ports: Vec<u16>,
+}
+
+impl Allowlist {
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.ports.contains(&port)
+            && self
+                .domains
+                .iter()
+                .any(|pattern| match pattern.strip_prefix("*.") {
+                    Some(domain) => host
+                        .strip_suffix(domain)
+                        .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                    None => host == *pattern,
+                })
+    }
+}
+
+/// Whether the proxy may connect a job to `ip`: an address of the internet, not
+/// one of the host, of `bridge`, the network of the VMs, or of a private, shared
+/// or link-local network.
+fn is_global(ip: IpAddr, bridge: (Ipv4Addr, u8)) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip, bridge),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_global_v4(ip, bridge),
+            None => is_global_v6(ip),
+        },
+    }
+}
+
+fn is_global_v4(ip: Ipv4Addr, (bridge, bridge_len): (Ipv4Addr, u8)) -> bool {
+    let in_network = |network: Ipv4Addr, len: u8| {
+        let mask = u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+        ip.to_bits() & mask == network.to_bits() & mask
+    };
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || in_network(Ipv4Addr::new(0, 0, 0, 0), 8)
+        || in_network(Ipv4Addr::new(100, 64, 0, 0), 10)
+        || in_network(Ipv4Addr::new(192, 0, 0, 0), 24)
+        || in_network(Ipv4Addr::new(198, 18, 0, 0), 15)
+        || in_network(Ipv4Addr::new(240, 0, 0, 0), 4)
+        || in_network(bridge, bridge_len))
+}
+
+fn is_global_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // Documentation, 2001:db8::/32.
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+        // IPv4-compatible and NAT64 addresses, which could hide a private IPv4 one.
+        || ip.to_ipv4().is_some()
+        || (first == 0x0064 && ip.segments()[1] == 0xff9b))
+}
+
+/// What the proxy knows of the job behind a guest address.
+struct Lease {
+    job_id: String,
+    allowlist: Allowlist,
+    log: Arc<JobLog>,
+}
+
+type Leases = Arc<Mutex<HashMap<Ipv4Addr, Lease>>>;
+
+/// The proxy, and the guests it serves.
+pub struct EgressProxy {
+    addr: Ipv4Addr,
+    port: u16,
+    leases: Leases,
+}
+
+/// Addresses the proxy connects jobs to.
+#[derive(Clone, Copy)]
+struct Upstreams {
+    /// Network of the VMs, never reached.
+    bridge: (Ipv4Addr, u8),
+    /// Whether addresses other than those of the internet are reached too.
+    any: bool,
+}
+
+/// A guest served by the proxy, and cut off from the rest of the network, until
+/// this is dropped.
+pub struct EgressLease {
+    guest_ip: Ipv4Addr,
+    leases: Leases,
+}
+
+impl EgressProxy {
+    /// Serves on port `port` of `gateway`, the address of the host on the bridge
+    /// of the VMs, whose network is `gateway`/`bridge_len`. Other networks of the
+    /// host cannot reach it.
+    pub async fn bind(gateway: Ipv4Addr, bridge_len: u8, port: u16) -> std::io::Result<Self> {
+        let upstreams = Upstreams {
+            bridge: (gateway, bridge_len),
+            any: false,
+        };
+        Self::serve_on(TcpListener::bind((gateway, port)).await?, upstreams)
+    }
+
+    fn serve_on(listener: TcpListener, upstreams: Upstreams) -> std::io::Result<Self> {
+        let addr = listener.local_addr()?;
+        let IpAddr::V4(ip) = addr.ip() else {
+            return Err(std::io::Error::other("the proxy serves IPv4 guests"));
+        };
+        let proxy = EgressProxy {
+            addr: ip,
+            port: addr.port(),
+            leases: Leases::default(),
+        };
+        tokio::spawn(serve(listener, Arc::clone(&proxy.leases), upstreams));
+        Ok(proxy)
+    }
+
+    /// URL of the proxy, which the VMs of every segment reach through their
+    /// gateway.
+    pub fn url(&self) -> String {
+        format!("http://{}:{}", self.addr, self.port)
+    }
+
+    /// Cuts `guest_ip` off the network but the proxy, which lets it reach what
+    /// `allowlist` allows for job `job_id`, and logs its requests in `log`.
+    pub fn admit(
+        &self,
+        guest_ip: Ipv4Addr,
+        job_id: &str,
+        allowlist: Allowlist,
+        log: Arc<JobLog>,
+    ) -> Result<EgressLease, String> {
+        virt::network::restrict_egress(guest_ip)
+            .map_err(|e| format!("Failed to restrict the egress of {}: {}", guest_ip, e))?;
+        Ok(self.lease(guest_ip, job_id, allowlist, log))
+    }
+
+    fn lease(
+        &self,
+        guest_ip: Ipv4Addr,
+        job_id: &str,
+        allowlist: Allowlist,
+        log: Arc<JobLog>,
+    ) -> EgressLease {
+        self.leases.lock().unwrap().insert(
+            guest_ip,
+            Lease {
+                job_id: job_id.to_string(),
+                allowlist,
+                log,
+            },
+        );
+        EgressLease {
+            guest_ip,
+            leases: Arc::clone(&self.leases),
+        }
+    }
+}
+
+impl Drop for EgressLease {
+    fn drop(&mut self) {
+        if self.leases.lock().unwrap().remove(&self.guest_ip).is_some()
+            && let Err(e) = virt::network::lift_egress_restriction(self.guest_ip)
+        {
+            warn!(
+                "Failed to lift the egress restriction of {}: {}",
+                self.guest_ip, e
+            );
+        }
+    }
+}
+
+async fn serve(listener: TcpListener, leases: Leases, upstreams: Upstreams) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(handle(stream, peer.ip(), Arc::clone(&leases), upstreams));
+            }
+            Err(e) => {
+                warn!("Egress proxy cannot accept connections: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// A request head the proxy understood.
+#[derive(Debug, PartialEq, Eq)]
+struct ProxyRequest {
+    method: String,
+    host: String,
+    port: u16,
+    /// Head to send the host, for requests other than `CONNECT`.
+    forward_head: Option<Vec<u8>>,
+}
+
+async fn handle(mut client: TcpStream, peer: IpAddr, leases: Leases, upstreams: Upstreams) {
+    let (head, rest) = match tokio::time::timeout(HEAD_TIMEOUT, read_head(&mut client)).await {
+        Ok(Ok(read)) => read,
+        Ok(Err(e)) => {
+            let _ = respond(&mut client, "400 Bad Request", e).await;
+            return;
+        }
+        Err(_) => return,
+    };
+    let request = match parse_head(&head) {
+        Ok(request) => request,
+        Err(e) => {
+            let _ = respond(&mut client, "400 Bad Request", e).await;
+            return;
+        }
+    };
+
+    let lease = match peer {
+        IpAddr::V4(ip) => leases.lock().unwrap().get(&ip).map(|lease| {
+            (
+                lease.job_id.clone(),
+                lease.allowlist.allows(&request.host, request.port),
+                Arc::clone(&lease.log),
+            )
+        }),
+        IpAddr::V6(_) => None,
+    };
+    let Some((job_id, allowed, log)) = lease else {
+        debug!(
+            "Egress proxy refused {}, which runs no restricted job",
+            peer
+        );
+        let _ = respond(&mut client, "403 Forbidden", "not an egress-restricted job").await;
+        return;
+    };
+    let line = format!(
+        "{} {}:{} {}",
+        request.method,
+        request.host,
+        request.port,
+        if allowed { "allowed" } else { "denied" }
+    );
+    info!("Job {} – egress {}", job_id, line);
+    log.push(LogSource::Egress, &line);
+    if !allowed {
+        let _ = respond(
+            &mut client,
+            "403 Forbidden",
+            "domain or port not allowed by the egress policy",
+        )
+        .await;
+        return;
+    }
+
+    let connect = connect(&request.host, request.port, upstreams);
+    let mut upstream = match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(e)) => {
+            log.push(
+                LogSource::Egress,
+                &format!("{}:{} unreachable: {}", request.host, request.port, e),
+            );
+            let status = if e.kind() == std::io::ErrorKind::PermissionDenied {
+                "403 Forbidden"
+            } else {
+                "502 Bad Gateway"
+            };
+            let _ = respond(&mut client, status, "host unreachable").await;
+            return;
+        }
+        Err(_) => {
+            let _ = respond(&mut client, "504 Gateway Timeout", "host unreachable").await;
+            return;
+        }
+    };
+    let sent = match &request.forward_head {
+        Some(forward_head) => upstream.write_all(forward_head).await,
+        None => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+        }
+    };
+    if sent.is_err() || upstream.write_all(&rest).await.is_err() {
+        return;
+    }
+    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+}
+
+/// Resolves `host`, and connects to the first of its addresses `upstreams` lets
+/// the proxy reach. Fails with `PermissionDenied` if it has none.
+async fn connect(host: &str, port: u16, upstreams: Upstreams) -> std::io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    let reachable: Vec<SocketAddr> = addrs
+        .iter()
+        .copied()
+        .filter(|addr| upstreams.any || is_global(addr.ip(), upstreams.bridge))
+        .collect();
+    if reachable.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "resolves to no address of the internet",
+        ));
+    }
+    let mut last_error = None;
+    for addr in reachable {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap())
+}
+
+/// Reads up to the end of the request head. Returns the head, and what the code
+/// sent after it.
+async fn read_head(client: &mut TcpStream) -> Result<(Vec<u8>, Vec<u8>), &'static str> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            return Ok((buf, rest));
+        }
+        if buf.len() > MAX_HEAD_BYTES {
+            return Err("request head too large");
+        }
+        match client.read(&mut chunk).await {
+            Ok(0) | Err(_) => return Err("connection closed before the end of the head"),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+fn parse_head(head: &[u8]) -> Result<ProxyRequest, &'static str> {
+    let head = std::str::from_utf8(head).map_err(|_| "request head is not UTF-8")?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err("malformed request line");
+    };
+
+    if method == "CONNECT" {
+        let (host, port) = split_host_port(target, None)?;
+        return Ok(ProxyRequest {
+            method: method.to_string(),
+            host,
+            port,
+            forward_head: None,
+        });
+    }
+
+    let authority_and_path = target
+        .strip_prefix("http://")
+        .ok_or("only http:// URLs are proxied, use CONNECT for https")?;
+    let (authority, path) = match authority_and_path.find('/') {
+        Some(slash) => authority_and_path.split_at(slash),
+        None => (authority_and_path, "/"),
+    };
+    let (host, port) = split_host_port(authority, Some(80))?;
+
+    let mut forward_head = format!("{} {} {}\r\n", method, path, version);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or_default().trim();
+        if [
+            "connection",
+            "proxy-connection",
+            "proxy-authorization",
+            "keep-alive",
+        ]
+        .iter()
+        .any(|hop| name.eq_ignore_ascii_case(hop))
+        {
+            continue;
+        }
+        forward_head.push_str(line);
+        forward_head.push_str("\r\n");
+    }
+    forward_head.push_str("Connection: close\r\n\r\n");
+    Ok(ProxyRequest {
+        method: method.to_string(),
+        host,
+        port,
+        forward_head: Some(forward_head.into_bytes()),
+    })
+}
+
+/// Host and port of `authority`, `default_port` when it names none.
+fn split_host_port(
+    authority: &str,
+    default_port: Option<u16>,
+) -> Result<(String, u16), &'static str> {
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port.parse().map_err(|_| "invalid port")?)),
+        None => (authority, default_port),
+    };
+    if host.is_empty() || host.contains(['@', '[', ']']) {
+        return Err("invalid host");
+    }
+    Ok((host.to_string(), port.ok_or("missing port")?))
+}
+
+async fn respond(client: &mut TcpStream, status: &str, reason: &str) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        reason.len() + 1,
+        reason
+    );
+    client.write_all(response.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlist() {
+        let policy: EgressPolicy = toml::from_str(
+            r#"
+            allow = ["pypi.org", "*.pythonhosted.org"]
+            tenants.alice.allow = ["Registry.npmjs.org"]
+            "#,
+        )
+        .unwrap();
+        let mut errors = ValidationErrors::default();
+        policy.validate(&mut errors);
+        assert!(errors.is_empty());
+
+        let anonymous = policy.allowlist(None);
+        assert!(anonymous.allows("pypi.org", 443));
+        assert!(anonymous.allows("PyPI.org.", 80));
+        assert!(anonymous.allows("files.pythonhosted.org", 443));
+        assert!(!anonymous.allows("pypi.org", 22));
+        assert!(!anonymous.allows("pythonhosted.org", 443));
+        assert!(!anonymous.allows("evilpythonhosted.org", 443));
+        assert!(!anonymous.allows("pypi.org.evil.com", 443));
+        assert!(!anonymous.allows("registry.npmjs.org", 443));
+        assert!(
+            policy
+                .allowlist(Some("alice"))
+                .allows("registry.npmjs.org", 443)
+        );
+        assert!(
+            !policy
+                .allowlist(Some("bob"))
+                .allows("registry.npmjs.org", 443)
+        );
+
+        let ports = EgressPolicy {
+            allow: vec!["pypi.org".to_string()],
+            ports: vec![8443],
+            ..EgressPolicy::default()
+        };
+        assert!(ports.allowlist(None).allows("pypi.org", 8443));
+        assert!(!ports.allowlist(None).allows("pypi.org", 443));
+
+        let invalid = EgressPolicy {
+            allow: vec!["https://pypi.org".to_string(), "*".to_string()],
+            ports: vec![0],
+            ..EgressPolicy::default()
+        };
+        let mut errors = ValidationErrors::default();
+        invalid.validate(&mut errors);
+        assert_eq!(errors.errors.len(), 3);
+    }
+
+    #[test]
+    fn test_only_global_addresses_are_reached() {
+        let bridge = (Ipv4Addr::new(172, 16, 0, 1), 16);
+        for ip in [
+            "1.1.1.1",
+            "151.101.0.223",
+            "2a04:4e42::223",
+            "::ffff:151.101.0.223",
+        ] {
+            assert!(is_global(ip.parse().unwrap(), bridge), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "0.0.0.0",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "255.255.255.255",
+            "224.0.0.1",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!is_global(ip.parse().unwrap(), bridge), "{}", ip);
+        }
+        // The bridge is not reached even out of the private ranges.
+        assert!(!is_global(
+            "203.1.2.3".parse().unwrap(),
+            (Ipv4Addr::new(203, 1, 0, 1), 16)
+        ));
+    }
+
+    #[test]
+    fn test_parse_head() {
+        let connect = parse_head(b"CONNECT pypi.org:443 HTTP/1.1\r\nHost: pypi.org:443\r\n\r\n");
+        assert_eq!(
+            connect,
+            Ok(ProxyRequest {
+                method: "CONNECT".to_string(),
+                host: "pypi.org".to_string(),
+                port: 443,
+                forward_head: None,
+            })
+        );
+
+        let get = parse_head(
+            b"GET http://pypi.org/simple/ HTTP/1.1\r\nHost: pypi.org\r\nProxy-Connection: keep-alive\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!((get.host.as_str(), get.port), ("pypi.org", 80));
+        assert_eq!(
+            get.forward_head.unwrap(),
+            b"GET /simple/ HTTP/1.1\r\nHost: pypi.org\r\nConnection: close\r\n\r\n"
+        );
+
+        assert!(parse_head(b"GET /simple/ HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_head(b"CONNECT user@pypi.org:443 HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        // The upstream is on the host: the proxy reaches it only when told it may.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = EgressProxy::serve_on(
+            listener,
+            Upstreams {
+                bridge: (Ipv4Addr::new(172, 16, 0, 1), 16),
+                any: true,
+            },
+        )
+        .unwrap();
+        assert_eq!(proxy.url(), format!("http://127.0.0.1:{}", proxy.port));
+        let log = Arc::new(JobLog::new());
+        let lease = proxy.lease(
+            Ipv4Addr::LOCALHOST,
+            "job-1",
+            Allowlist {
+                domains: vec!["localhost".to_string()],
+                ports: vec![upstream_port],
+            },
+            Arc::clone(&log),
+        );
+        let port = proxy.port;
+        let exchange = |request: String| async move {
+            let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let tunneled = exchange(format!(
+            "CONNECT localhost:{upstream_port} HTTP/1.1\r\n\r\n"
+        ))
+        .await;
+        assert_eq!(tunneled, "HTTP/1.1 200 Connection established\r\n\r\nhello");
+        let denied = exchange("CONNECT example.com:443 HTTP/1.1\r\n\r\n".to_string()).await;
+        assert!(denied.starts_with("HTTP/1.1 403"));
+        let port_denied = exchange("CONNECT localhost:22 HTTP/1.1\r\n\r\n".to_string()).await;
+        assert!(port_denied.starts_with("HTTP/1.1 403"));
+        let lines: Vec<_> = log.lines().into_iter().map(|line| line.line).collect();
+        assert_eq!(
+            lines,
+            [
+                format!("CONNECT localhost:{upstream_port} allowed"),
+                "CONNECT example.com:443 denied".to_string(),
+                "CONNECT localhost:22 denied".to_string(),
+            ]
+        );
+
+        // Once the job is over, its guest gets nothing.
+        drop(lease);
+        let refused = exchange("CONNECT localhost:443 HTTP/1.1\r\n\r\n".to_string()).await;
+        assert!(refused.starts_with("HTTP/1.1 403"));
+    }
+}
@@ -0,0 +1,118 @@
+//! Lifecycle events of jobs and VMs, for integrations to react to without polling.
+//!
+//! Events go out on a broadcast channel: `GET /events` streams them to its
+//! clients, and webhooks get each of them POSTed, see [`crate::webhooks`].
+//! Delivery is best effort: a subscriber that falls behind misses events, and
+//! nothing is kept for subscribers that are not there yet.
+
+use cloude_types::{EventKind, LifecycleEvent};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Events a slow subscriber can fall behind before it misses some.
+const EVENT_BACKLOG: usize = 1024;
+
+pub struct EventBus {
+    sender: broadcast::Sender<LifecycleEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BACKLOG);
+        Self { sender }
+    }
+
+    /// Sends `event` to the current subscribers, if any.
+    pub fn publish(&self, event: LifecycleEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Receiver of the events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<LifecycleEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publishes an event of job `job_id`.
+    pub fn job(&self, kind: EventKind, job_id: &str, detail: Option<String>) {
+        self.publish(LifecycleEvent {
+            timestamp_ms: now_ms(),
+            kind,
+            job_id: Some(job_id.to_string()),
+            vm_id: None,
+            detail,
+        });
+    }
+
+    /// Publishes an event of VM `vm_id`, and of the job it runs, if any.
+    pub fn vm(&self, kind: EventKind, vm_id: &str, job_id: Option<&str>) {
+        self.publish(LifecycleEvent {
+            timestamp_ms: now_ms(),
+            kind,
+            job_id: job_id.map(str::to_string),
+            vm_id: Some(vm_id.to_string()),
+            detail: None,
+        });
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribers_get_events_in_order() {
+        let bus = EventBus::new();
+        // Nobody listens yet: the event is dropped.
+        bus.job(EventKind::ExecutionQueued, "job-0", None);
+
+        let mut events = bus.subscribe();
+        bus.job(
+            EventKind::ExecutionQueued,
+            "job-1",
+            Some("language=python".to_string()),
+        );
+        bus.vm(EventKind::VmCreated, "vm-1", Some("job-1"));
+
+        let queued = events.try_recv().unwrap();
+        assert_eq!(queued.kind, EventKind::ExecutionQueued);
+        assert_eq!(queued.job_id.as_deref(), Some("job-1"));
+        assert_eq!(queued.detail.as_deref(), Some("language=python"));
+        let created = events.try_recv().unwrap();
+        assert_eq!(created.kind, EventKind::VmCreated);
+        assert_eq!(created.vm_id.as_deref(), Some("vm-1"));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_event_json() {
+        let event = LifecycleEvent {
+            timestamp_ms: 1760000000123,
+            kind: EventKind::ExecutionFinished,
+            job_id: Some("job-1".to_string()),
+            vm_id: None,
+            detail: Some("exit_code=0".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "timestamp_ms": 1760000000123_u64,
+                "kind": "execution.finished",
+                "job_id": "job-1",
+                "detail": "exit_code=0",
+            })
+        );
+    }
+}
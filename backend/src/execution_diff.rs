@@ -0,0 +1,236 @@
+//! Structured diff of two executions, for `GET /executions/{a}/diff/{b}`.
+//!
+//! Runs of the same code that end differently are what nondeterminism looks
+//! like from the outside: the diff lines up their results, outputs, runtime
+//! images and resource usage so that the odd one out shows.
+
+use cloude_types::{Change, DiffLine, DiffOp, ExecutionDiff, JobStatus, ResourceUsage, UsageDelta};
+
+/// Largest table of a line diff, in cells: outputs with more lines than that
+/// between their common start and end are diffed as wholly replaced.
+const MAX_DIFF_CELLS: usize = 1 << 22;
+
+/// What the backend knows of an execution.
+pub struct Execution<'a> {
+    pub id: &'a str,
+    pub status: JobStatus,
+    pub language: &'a str,
+    /// Digest of the code that ran, `None` when it is not known.
+    pub code_digest: Option<&'a str>,
+    pub image_digest: Option<&'a str>,
+    pub exit_code: Option<i32>,
+    pub signal: Option<&'a str>,
+    pub stdout: &'a str,
+    pub stderr: &'a str,
+    pub usage: ResourceUsage,
+}
+
+pub fn diff(a: &Execution<'_>, b: &Execution<'_>) -> ExecutionDiff {
+    let same_code =
+        a.language == b.language && a.code_digest.is_some() && a.code_digest == b.code_digest;
+    let status = Change {
+        a: a.status,
+        b: b.status,
+    };
+    let exit_code = Change {
+        a: a.exit_code,
+        b: b.exit_code,
+    };
+    let signal = Change {
+        a: a.signal.map(str::to_string),
+        b: b.signal.map(str::to_string),
+    };
+    let stdout = diff_lines(a.stdout, b.stdout);
+    let stderr = diff_lines(a.stderr, b.stderr);
+    let same_result = !status.changed()
+        && !exit_code.changed()
+        && !signal.changed()
+        && stdout.is_empty()
+        && stderr.is_empty();
+    let traffic = |usage: &ResourceUsage, bytes: fn(&cloude_types::TrafficStats) -> u64| {
+        usage.traffic.as_ref().map(bytes)
+    };
+    ExecutionDiff {
+        a: a.id.to_string(),
+        b: b.id.to_string(),
+        same_code,
+        same_result,
+        status,
+        exit_code,
+        signal,
+        stdout,
+        stderr,
+        image_digest: Change {
+            a: a.image_digest.map(str::to_string),
+            b: b.image_digest.map(str::to_string),
+        },
+        usage: Change {
+            a: a.usage,
+            b: b.usage,
+        },
+        usage_delta: UsageDelta {
+            duration_ms: delta(a.usage.duration_ms, b.usage.duration_ms),
+            rx_bytes: delta(
+                traffic(&a.usage, |t| t.rx_bytes),
+                traffic(&b.usage, |t| t.rx_bytes),
+            ),
+            tx_bytes: delta(
+                traffic(&a.usage, |t| t.tx_bytes),
+                traffic(&b.usage, |t| t.tx_bytes),
+            ),
+        },
+    }
+}
+
+fn delta(a: Option<u64>, b: Option<u64>) -> Option<i64> {
+    Some(b? as i64 - a? as i64)
+}
+
+/// Lines of `a` missing from `b` and lines `b` added, in the order of the outputs.
+/// Empty when both are the same.
+pub fn diff_lines(a: &str, b: &str) -> Vec<DiffLine> {
+    let a: Vec<&str> = a.lines().collect();
+    let b: Vec<&str> = b.lines().collect();
+    let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let middle_a = &a[prefix..a.len() - suffix];
+    let middle_b = &b[prefix..b.len() - suffix];
+
+    let line = |op, index: usize, text: &str| DiffLine {
+        op,
+        line: prefix + index + 1,
+        text: text.to_string(),
+    };
+    if (middle_a.len() + 1).saturating_mul(middle_b.len() + 1) > MAX_DIFF_CELLS {
+        let removed = middle_a
+            .iter()
+            .enumerate()
+            .map(|(i, text)| line(DiffOp::Removed, i, text));
+        let added = middle_b
+            .iter()
+            .enumerate()
+            .map(|(i, text)| line(DiffOp::Added, i, text));
+        return removed.chain(added).collect();
+    }
+
+    // Longest common subsequence of the lines after each position.
+    let width = middle_b.len() + 1;
+    let mut lcs = vec![0u32; (middle_a.len() + 1) * width];
+    for i in (0..middle_a.len()).rev() {
+        for j in (0..middle_b.len()).rev() {
+            lcs[i * width + j] = if middle_a[i] == middle_b[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < middle_a.len() || j < middle_b.len() {
+        if i < middle_a.len() && j < middle_b.len() && middle_a[i] == middle_b[j] {
+            i += 1;
+            j += 1;
+        } else if j == middle_b.len()
+            || (i < middle_a.len() && lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+        {
+            lines.push(line(DiffOp::Removed, i, middle_a[i]));
+            i += 1;
+        } else {
+            lines.push(line(DiffOp::Added, j, middle_b[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloude_types::TrafficStats;
+
+    fn removed(line: usize, text: &str) -> DiffLine {
+        DiffLine {
+            op: DiffOp::Removed,
+            line,
+            text: text.to_string(),
+        }
+    }
+
+    fn added(line: usize, text: &str) -> DiffLine {
+        DiffLine {
+            op: DiffOp::Added,
+            line,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        assert!(diff_lines("a\nb\nc\n", "a\nb\nc\n").is_empty());
+        assert_eq!(
+            diff_lines("a\nb\nc\nd\n", "a\nx\nc\nd\ne\n"),
+            [removed(2, "b"), added(2, "x"), added(5, "e")]
+        );
+        assert_eq!(diff_lines("seed 1\nok\n", "ok\n"), [removed(1, "seed 1")]);
+        assert_eq!(diff_lines("", "1\n"), [added(1, "1")]);
+    }
+
+    #[test]
+    fn test_diff_executions() {
+        let a = Execution {
+            id: "a",
+            status: JobStatus::Done,
+            language: "python",
+            code_digest: Some("c0de"),
+            image_digest: Some("1ma9e"),
+            exit_code: Some(0),
+            signal: None,
+            stdout: "0.25\n",
+            stderr: "",
+            usage: ResourceUsage {
+                vcpus: Some(1),
+                memory_mb: Some(512),
+                duration_ms: Some(900),
+                traffic: Some(TrafficStats {
+                    rx_bytes: 100,
+                    ..TrafficStats::default()
+                }),
+            },
+        };
+        let same = diff(&a, &Execution { id: "b", ..a });
+        assert!(same.same_code && same.same_result);
+        assert_eq!(same.usage_delta.duration_ms, Some(0));
+
+        let b = Execution {
+            id: "b",
+            exit_code: Some(1),
+            stdout: "0.75\n",
+            usage: ResourceUsage {
+                duration_ms: Some(700),
+                traffic: None,
+                ..a.usage
+            },
+            ..a
+        };
+        let diff = diff(&a, &b);
+        assert!(diff.same_code);
+        assert!(!diff.same_result);
+        assert!(diff.exit_code.changed() && !diff.status.changed());
+        assert_eq!(diff.stdout, [removed(1, "0.25"), added(1, "0.75")]);
+        assert_eq!(diff.usage_delta.duration_ms, Some(-200));
+        assert_eq!(diff.usage_delta.rx_bytes, None);
+
+        // Code the backend does not know is never the same.
+        let cached = Execution {
+            code_digest: None,
+            ..a
+        };
+        assert!(!super::diff(&cached, &Execution { id: "b", ..cached }).same_code);
+    }
+}
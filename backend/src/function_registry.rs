@@ -0,0 +1,356 @@
+use crate::apply;
+use crate::namespaces::Namespace;
+use cloude_types::{ApplySpec, DeployRequest, FunctionInfo, PlannedChange};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Errors that can occur while reading or writing the function registry.
+#[derive(Debug)]
+pub enum FunctionError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl std::fmt::Display for FunctionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FunctionError::Io(e) => write!(f, "IO error: {}", e),
+            FunctionError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FunctionError {}
+
+impl From<std::io::Error> for FunctionError {
+    fn from(err: std::io::Error) -> Self {
+        FunctionError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for FunctionError {
+    fn from(err: serde_json::Error) -> Self {
+        FunctionError::Json(err)
+    }
+}
+
+/// Serializable state of the registry, mapped directly to the JSON file on disk.
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct FunctionRegistryState {
+    functions: HashMap<String, FunctionInfo>, // name -> function, in the default namespace
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    namespaces: HashMap<String, HashMap<String, FunctionInfo>>, // namespace -> name -> function
+}
+
+impl FunctionRegistryState {
+    /// Functions of `namespace`, those of the default one at the top level as
+    /// they were before namespaces.
+    fn functions(&mut self, namespace: &Namespace) -> &mut HashMap<String, FunctionInfo> {
+        if namespace.is_default() {
+            &mut self.functions
+        } else {
+            self.namespaces
+                .entry(namespace.as_str().to_string())
+                .or_default()
+        }
+    }
+}
+
+/// Deployed functions, persisted to a JSON file so they survive restarts.
+/// The code of a function lives in the artifact store, this only records which artifact to run.
+#[derive(Debug)]
+pub struct FunctionRegistry {
+    file_path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FunctionRegistry {
+    pub fn new<P: AsRef<Path>>(file_path: P) -> Result<Self, FunctionError> {
+        let registry = Self {
+            file_path: file_path.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        };
+
+        if !registry.file_path.exists() {
+            registry.write_state(&FunctionRegistryState::default())?;
+        }
+
+        Ok(registry)
+    }
+
+    fn read_state(&self) -> Result<FunctionRegistryState, FunctionError> {
+        let mut file = match File::open(&self.file_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(FunctionRegistryState::default());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        if contents.trim().is_empty() {
+            return Ok(FunctionRegistryState::default());
+        }
+
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    fn write_state(&self, state: &FunctionRegistryState) -> Result<(), FunctionError> {
+        let json = serde_json::to_string_pretty(state)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.file_path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Returns the function deployed as `name` in `namespace`, if any.
+    pub fn get(
+        &self,
+        namespace: &Namespace,
+        name: &str,
+    ) -> Result<Option<FunctionInfo>, FunctionError> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(self.read_state()?.functions(namespace).remove(name))
+    }
+
+    /// Returns every function deployed in `namespace`, sorted by name.
+    pub fn list(&self, namespace: &Namespace) -> Result<Vec<FunctionInfo>, FunctionError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut functions = std::mem::take(self.read_state()?.functions(namespace))
+            .into_values()
+            .collect::<Vec<_>>();
+        functions.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(functions)
+    }
+
+    /// Creates `name` in `namespace` or points it to the code in `request`, and
+    /// returns the function along with what it was before. Deploying the same
+    /// settings again keeps the current version.
+    pub fn deploy(
+        &self,
+        namespace: &Namespace,
+        name: &str,
+        request: DeployRequest,
+    ) -> Result<(FunctionInfo, Option<FunctionInfo>), FunctionError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut state = self.read_state()?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let functions = state.functions(namespace);
+        let previous = functions.get(name).cloned();
+        let function = match &previous {
+            Some(current)
+                if current.language == request.language
+                    && current.artifact == request.artifact
+                    && current.entrypoint == request.entrypoint =>
+            {
+                return Ok((current.clone(), previous));
+            }
+            Some(current) => FunctionInfo {
+                version: current.version + 1,
+                updated_at: now,
+                language: request.language,
+                artifact: request.artifact,
+                entrypoint: request.entrypoint,
+                ..current.clone()
+            },
+            None => FunctionInfo {
+                name: name.to_string(),
+                language: request.language,
+                artifact: request.artifact,
+                entrypoint: request.entrypoint,
+                version: 1,
+                created_at: now,
+                updated_at: now,
+            },
+        };
+
+        functions.insert(name.to_string(), function.clone());
+        self.write_state(&state)?;
+        Ok((function, previous))
+    }
+
+    /// Plans the changes that make the functions of `namespace` those of `spec`,
+    /// and makes them unless `dry_run`, all in one write.
+    pub fn apply(
+        &self,
+        namespace: &Namespace,
+        spec: &ApplySpec,
+        dry_run: bool,
+    ) -> Result<Vec<PlannedChange>, FunctionError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut state = self.read_state()?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let functions = state.functions(namespace);
+        let changes = apply::plan(spec, functions, now);
+        if !dry_run {
+            apply::converge(functions, &changes);
+            self.write_state(&state)?;
+        }
+        Ok(changes)
+    }
+
+    /// Puts `function` in `namespace` as it is, version and dates included, in
+    /// place of any function of the same name. For imports.
+    pub fn restore(
+        &self,
+        namespace: &Namespace,
+        function: FunctionInfo,
+    ) -> Result<(), FunctionError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut state = self.read_state()?;
+        state
+            .functions(namespace)
+            .insert(function.name.clone(), function);
+        self.write_state(&state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn request(artifact: &str) -> DeployRequest {
+        DeployRequest {
+            language: "node".to_string(),
+            artifact: artifact.to_string(),
+            entrypoint: Some("index.js".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_deploy_creates_then_updates() {
+        let file = NamedTempFile::new().unwrap();
+        let registry = FunctionRegistry::new(file.path()).unwrap();
+        let default = Namespace::default();
+
+        let (created, previous) = registry.deploy(&default, "hello", request("a")).unwrap();
+        assert!(previous.is_none());
+        assert_eq!(created.version, 1);
+
+        let (same, previous) = registry.deploy(&default, "hello", request("a")).unwrap();
+        assert!(previous.is_some());
+        assert_eq!(same.version, 1);
+
+        let (updated, previous) = registry.deploy(&default, "hello", request("b")).unwrap();
+        assert_eq!(previous.unwrap().artifact, "a");
+        assert_eq!(updated.version, 2);
+        assert_eq!(updated.created_at, created.created_at);
+        registry.deploy(&default, "bye", request("c")).unwrap();
+
+        let reopened = FunctionRegistry::new(file.path()).unwrap();
+        assert_eq!(
+            reopened.get(&default, "hello").unwrap().unwrap().artifact,
+            "b"
+        );
+        assert!(reopened.get(&default, "missing").unwrap().is_none());
+        let names = reopened
+            .list(&default)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["bye", "hello"]);
+    }
+
+    #[test]
+    fn test_namespaces_are_apart() {
+        let file = NamedTempFile::new().unwrap();
+        let registry = FunctionRegistry::new(file.path()).unwrap();
+        let team = Namespace::new("team-a").unwrap();
+
+        registry
+            .deploy(&Namespace::default(), "hello", request("a"))
+            .unwrap();
+        let (theirs, previous) = registry.deploy(&team, "hello", request("b")).unwrap();
+        assert!(previous.is_none());
+        assert_eq!(theirs.version, 1);
+
+        assert_eq!(
+            registry
+                .get(&Namespace::default(), "hello")
+                .unwrap()
+                .unwrap()
+                .artifact,
+            "a"
+        );
+        assert_eq!(registry.get(&team, "hello").unwrap().unwrap().artifact, "b");
+        let other = Namespace::new("team-b").unwrap();
+        assert!(registry.get(&other, "hello").unwrap().is_none());
+        assert!(registry.list(&other).unwrap().is_empty());
+
+        // Registries from before namespaces keep their functions in the default one.
+        std::fs::write(
+            file.path(),
+            r#"{"functions":{"old":{"name":"old","language":"node","artifact":"c","version":1,"created_at":0,"updated_at":0}}}"#,
+        )
+        .unwrap();
+        assert!(
+            registry
+                .get(&Namespace::default(), "old")
+                .unwrap()
+                .is_some()
+        );
+        assert!(registry.get(&team, "old").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_apply_dry_run_changes_nothing() {
+        let file = NamedTempFile::new().unwrap();
+        let registry = FunctionRegistry::new(file.path()).unwrap();
+        let team = Namespace::new("team-a").unwrap();
+        let spec = ApplySpec {
+            functions: vec![cloude_types::DesiredFunction {
+                name: "hello".to_string(),
+                language: "node".to_string(),
+                artifact: "a".to_string(),
+                entrypoint: None,
+            }],
+            prune: false,
+        };
+
+        let planned = registry.apply(&team, &spec, true).unwrap();
+        assert_eq!(planned[0].action, cloude_types::ApplyAction::Create);
+        assert!(registry.get(&team, "hello").unwrap().is_none());
+
+        registry.apply(&team, &spec, false).unwrap();
+        assert_eq!(registry.get(&team, "hello").unwrap().unwrap().version, 1);
+        assert!(registry.list(&Namespace::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_restore_keeps_version() {
+        let file = NamedTempFile::new().unwrap();
+        let registry = FunctionRegistry::new(file.path()).unwrap();
+        let team = Namespace::new("team-a").unwrap();
+        let (mut function, _) = registry
+            .deploy(&Namespace::default(), "hello", request("a"))
+            .unwrap();
+        function.version = 7;
+        registry.restore(&team, function).unwrap();
+
+        let restored = registry.get(&team, "hello").unwrap().unwrap();
+        assert_eq!((restored.version, restored.artifact.as_str()), (7, "a"));
+        // Deploying other settings goes on from the restored version.
+        let (deployed, _) = registry.deploy(&team, "hello", request("b")).unwrap();
+        assert_eq!(deployed.version, 8);
+    }
+}
@@ -0,0 +1,181 @@
+//! Liveness of the VMs running jobs, from the heartbeats of their agent.
+//!
+//! The backend asks the agent of every VM running a job for `GET /health` at a
+//! fixed interval; each answer is a heartbeat. A VM whose agent misses its
+//! heartbeats for longer than the timeout is unresponsive, one whose VMM
+//! stopped has exited.
+
+use cloude_types::{TrafficStats, VmInfo, VmLiveness};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 5;
+pub const DEFAULT_HEARTBEAT_TIMEOUT_SECS: u64 = 15;
+
+struct TrackedVm {
+    vm_id: String,
+    ip: Ipv4Addr,
+    last_heartbeat: Instant,
+    exited: bool,
+    traffic: Option<TrafficStats>,
+}
+
+impl TrackedVm {
+    fn liveness(&self, timeout: Duration) -> VmLiveness {
+        if self.exited {
+            VmLiveness::Exited
+        } else if self.last_heartbeat.elapsed() > timeout {
+            VmLiveness::Unresponsive
+        } else {
+            VmLiveness::Running
+        }
+    }
+}
+
+/// Last heartbeat of the VM of every running job, by job id.
+pub struct Heartbeats {
+    vms: Mutex<HashMap<String, TrackedVm>>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Heartbeats {
+    /// Heartbeats every `interval`, and VMs unresponsive after `timeout` without one.
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            vms: Mutex::new(HashMap::new()),
+            interval,
+            timeout,
+        }
+    }
+
+    /// How often the agents are asked for a heartbeat.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// How long an agent can go without a heartbeat before its VM is unresponsive.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Starts tracking the VM of job `job_id`. Its agent just answered, which
+    /// counts as a heartbeat.
+    pub fn track(&self, job_id: &str, vm_id: &str, ip: Ipv4Addr) {
+        self.lock().insert(
+            job_id.to_string(),
+            TrackedVm {
+                vm_id: vm_id.to_string(),
+                ip,
+                last_heartbeat: Instant::now(),
+                exited: false,
+                traffic: None,
+            },
+        );
+    }
+
+    /// Records a heartbeat from the agent of the VM of job `job_id`.
+    pub fn beat(&self, job_id: &str) {
+        if let Some(vm) = self.lock().get_mut(job_id) {
+            vm.last_heartbeat = Instant::now();
+        }
+    }
+
+    /// Records that the VMM of the VM of job `job_id` stopped.
+    pub fn mark_exited(&self, job_id: &str) {
+        if let Some(vm) = self.lock().get_mut(job_id) {
+            vm.exited = true;
+        }
+    }
+
+    /// Records the traffic of the VM of job `job_id` since the job started.
+    pub fn record_traffic(&self, job_id: &str, traffic: TrafficStats) {
+        if let Some(vm) = self.lock().get_mut(job_id) {
+            vm.traffic = Some(traffic);
+        }
+    }
+
+    /// Stops tracking the VM of job `job_id`, once the job is over.
+    pub fn forget(&self, job_id: &str) {
+        self.lock().remove(job_id);
+    }
+
+    /// Liveness of the VM of job `job_id`, `None` when it is not tracked.
+    pub fn liveness(&self, job_id: &str) -> Option<VmLiveness> {
+        self.lock().get(job_id).map(|vm| vm.liveness(self.timeout))
+    }
+
+    /// Every tracked VM, ordered by job id.
+    pub fn list(&self) -> Vec<VmInfo> {
+        let mut vms: Vec<VmInfo> = self
+            .lock()
+            .iter()
+            .map(|(job_id, vm)| VmInfo {
+                id: job_id.clone(),
+                vm_id: vm.vm_id.clone(),
+                ip: vm.ip.to_string(),
+                liveness: vm.liveness(self.timeout),
+                last_heartbeat_ms: vm.last_heartbeat.elapsed().as_millis() as u64,
+                traffic: vm.traffic,
+            })
+            .collect();
+        vms.sort_by(|a, b| a.id.cmp(&b.id));
+        vms
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, TrackedVm>> {
+        self.vms.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Ipv4Addr = Ipv4Addr::new(10, 39, 1, 2);
+
+    #[test]
+    fn test_liveness_follows_heartbeats() {
+        let heartbeats = Heartbeats::new(Duration::from_secs(5), Duration::from_secs(60));
+        assert_eq!(heartbeats.liveness("job-1"), None);
+
+        heartbeats.track("job-1", "vm-1", IP);
+        heartbeats.beat("job-1");
+        assert_eq!(heartbeats.liveness("job-1"), Some(VmLiveness::Running));
+
+        let traffic = TrafficStats {
+            rx_bytes: 1500,
+            rx_packets: 1,
+            ..TrafficStats::default()
+        };
+        heartbeats.record_traffic("job-1", traffic);
+        assert_eq!(heartbeats.list()[0].traffic, Some(traffic));
+
+        heartbeats.mark_exited("job-1");
+        assert_eq!(heartbeats.liveness("job-1"), Some(VmLiveness::Exited));
+
+        heartbeats.forget("job-1");
+        assert_eq!(heartbeats.liveness("job-1"), None);
+        assert!(heartbeats.list().is_empty());
+    }
+
+    #[test]
+    fn test_missed_heartbeats_make_vms_unresponsive() {
+        let heartbeats = Heartbeats::new(Duration::ZERO, Duration::ZERO);
+        heartbeats.track("job-2", "vm-2", IP);
+        heartbeats.track("job-1", "vm-1", IP);
+        std::thread::sleep(Duration::from_millis(2));
+
+        let vms = heartbeats.list();
+        assert_eq!(
+            vms.iter().map(|vm| vm.id.as_str()).collect::<Vec<_>>(),
+            ["job-1", "job-2"]
+        );
+        assert_eq!(vms[0].vm_id, "vm-1");
+        assert_eq!(vms[0].ip, "10.39.1.2");
+        assert_eq!(vms[0].liveness, VmLiveness::Unresponsive);
+        assert!(vms[0].last_heartbeat_ms >= 2);
+    }
+}
@@ -0,0 +1,369 @@
+//! Compiles the code of runtimes with `build: "host"` on the backend host.
+//!
+//! KVM guests run the architecture of the host, so the host toolchain can build
+//! their binaries: `rustc` targets the musl C library of that architecture and
+//! links it statically, and the binary runs in a run image without a toolchain,
+//! down to `scratch`. Each build runs in a directory of its own, in a process
+//! group with resource limits and, unless disabled, new user, mount, network,
+//! IPC and UTS namespaces. The code is only compiled here, never run.
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use cloude_types::{Arch, ExecuteRequest, ExecutionResult};
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+use tracing::warn;
+
+/// Runtimes whose code can be compiled on the host.
+pub const HOST_BUILT_LANGUAGES: &[&str] = &["rust"];
+
+/// Longest a build may take before it is killed.
+pub const DEFAULT_BUILD_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Data the compiler and linker may use, each.
+const MAX_BUILD_MEMORY_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+/// Largest file a build can write.
+const MAX_FILE_BYTES: u64 = 256 * 1024 * 1024;
+const MAX_OPEN_FILES: u64 = 1024;
+
+/// Variables of the backend environment a build keeps, so that the compiler
+/// finds its toolchain and linker. Every other one is cleared.
+const KEPT_ENV: &[&str] = &[
+    "PATH",
+    "HOME",
+    "RUSTUP_HOME",
+    "RUSTUP_TOOLCHAIN",
+    "CARGO_HOME",
+];
+
+/// Errors that can occur while compiling on the host.
+#[derive(Debug)]
+pub enum HostBuildError {
+    Io(std::io::Error),
+    /// The compiler cannot be run, or cannot build for the target.
+    Toolchain(String),
+    /// Bundles are only unpacked by the agent of a build VM.
+    Bundle,
+    TimedOut(Duration),
+}
+
+impl std::fmt::Display for HostBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostBuildError::Io(e) => write!(f, "IO error: {}", e),
+            HostBuildError::Toolchain(reason) => write!(f, "host toolchain unusable: {}", reason),
+            HostBuildError::Bundle => {
+                write!(f, "bundles cannot be built on the host, only single files")
+            }
+            HostBuildError::TimedOut(timeout) => {
+                write!(f, "build timed out after {}s", timeout.as_secs())
+            }
+        }
+    }
+}
+
+impl std::error::Error for HostBuildError {}
+
+impl From<std::io::Error> for HostBuildError {
+    fn from(err: std::io::Error) -> Self {
+        HostBuildError::Io(err)
+    }
+}
+
+pub struct HostBuilder {
+    rustc: PathBuf,
+    /// Parent of the directory of each build.
+    work_dir: PathBuf,
+    /// Target triple of the binaries, e.g. `x86_64-unknown-linux-musl`.
+    target: String,
+    timeout: Duration,
+    /// Whether to unshare namespaces, which some hosts forbid to unprivileged users.
+    namespaces: bool,
+    toolchain: OnceCell<String>,
+}
+
+impl HostBuilder {
+    /// A builder of static binaries for VMs of `arch` with the compiler `rustc`.
+    pub fn new(
+        rustc: PathBuf,
+        work_dir: PathBuf,
+        arch: Arch,
+        timeout: Duration,
+        namespaces: bool,
+    ) -> Self {
+        Self {
+            rustc,
+            work_dir,
+            target: rust_target(arch),
+            timeout,
+            namespaces,
+            toolchain: OnceCell::new(),
+        }
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Fails unless the compiler runs and has the standard library of the target,
+    /// like after `rustup target add x86_64-unknown-linux-musl`.
+    pub async fn check(&self) -> Result<(), HostBuildError> {
+        tokio::fs::create_dir_all(&self.work_dir).await?;
+        let output = self
+            .run(
+                &["--print", "target-libdir", "--target", &self.target],
+                &self.work_dir,
+            )
+            .await?;
+        let libdir = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() || !Path::new(&libdir).is_dir() {
+            return Err(HostBuildError::Toolchain(format!(
+                "{} has no standard library for {}",
+                self.rustc.display(),
+                self.target
+            )));
+        }
+        Ok(())
+    }
+
+    /// Version of the compiler and target, which tells the binaries it builds
+    /// apart in the compile cache.
+    pub async fn toolchain(&self) -> Result<&str, HostBuildError> {
+        let toolchain = self
+            .toolchain
+            .get_or_try_init(|| async {
+                tokio::fs::create_dir_all(&self.work_dir).await?;
+                let output = self.run(&["-vV"], &self.work_dir).await?;
+                if !output.status.success() {
+                    return Err(HostBuildError::Toolchain(
+                        String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                    ));
+                }
+                let version = String::from_utf8_lossy(&output.stdout);
+                Ok(format!("{}target: {}\n", version, self.target))
+            })
+            .await?;
+        Ok(toolchain)
+    }
+
+    /// Compiles the code of `request`, for job `job_id`. Returns the binary,
+    /// base64-encoded as a build VM hands it back, or the output of the failed
+    /// compile with its exit code.
+    pub async fn build(
+        &self,
+        job_id: &str,
+        request: &ExecuteRequest,
+    ) -> Result<ExecutionResult, HostBuildError> {
+        if request.bundle.is_some() {
+            return Err(HostBuildError::Bundle);
+        }
+        let dir = self.work_dir.join(job_id);
+        tokio::fs::create_dir_all(&dir).await?;
+        let built = self.compile(job_id, &dir, &request.code).await;
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+            warn!("Cannot remove {}: {}", dir.display(), e);
+        }
+        built
+    }
+
+    async fn compile(
+        &self,
+        job_id: &str,
+        dir: &Path,
+        code: &str,
+    ) -> Result<ExecutionResult, HostBuildError> {
+        let source = dir.join("main.rs");
+        let binary = dir.join("bin");
+        tokio::fs::write(&source, code).await?;
+        let output = self
+            .run(
+                &[
+                    "--target",
+                    &self.target,
+                    "-C",
+                    "target-feature=+crt-static",
+                    "-o",
+                    &binary.display().to_string(),
+                    &source.display().to_string(),
+                ],
+                dir,
+            )
+            .await?;
+
+        let mut result = ExecutionResult {
+            job_id: job_id.to_string(),
+            exit_code: output.status.code().unwrap_or(-1),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            binary: None,
+            oom_killed: false,
+            signal: None,
+            core_dump: None,
+        };
+        if output.status.success() {
+            result.binary = Some(BASE64_STANDARD.encode(tokio::fs::read(&binary).await?));
+        }
+        Ok(result)
+    }
+
+    /// Runs the compiler with `args` in `dir`, confined, and waits for it at
+    /// most the build timeout.
+    async fn run(&self, args: &[&str], dir: &Path) -> Result<Output, HostBuildError> {
+        let mut cmd = Command::new(&self.rustc);
+        cmd.args(args)
+            .env_clear()
+            .env("TMPDIR", dir)
+            .current_dir(dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        for name in KEPT_ENV {
+            if let Some(value) = std::env::var_os(name) {
+                cmd.env(name, value);
+            }
+        }
+        let namespaces = self.namespaces;
+        // SAFETY: the closure runs in the forked child before exec, and only makes
+        // async-signal-safe system calls.
+        unsafe {
+            cmd.pre_exec(move || confine(namespaces));
+        }
+
+        let child = cmd.spawn().map_err(|e| {
+            HostBuildError::Toolchain(format!("cannot run {}: {}", self.rustc.display(), e))
+        })?;
+        let pid = child.id();
+        match tokio::time::timeout(self.timeout, child.wait_with_output()).await {
+            Ok(output) => Ok(output?),
+            Err(_) => {
+                // The linker the compiler started is in its process group too.
+                if let Some(pid) = pid {
+                    // SAFETY: kill takes no pointers; the compiler leads its process group.
+                    unsafe {
+                        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+                    }
+                }
+                Err(HostBuildError::TimedOut(self.timeout))
+            }
+        }
+    }
+}
+
+/// Target triple of static binaries for VMs of `arch`.
+fn rust_target(arch: Arch) -> String {
+    format!("{}-unknown-linux-musl", arch)
+}
+
+/// Confines a build, between fork and exec: a process group of its own, limits,
+/// and no network.
+fn confine(namespaces: bool) -> std::io::Result<()> {
+    // SAFETY: setsid, setrlimit and unshare only read the values passed to them.
+    unsafe {
+        if libc::setsid() < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        set_limit(libc::RLIMIT_DATA, MAX_BUILD_MEMORY_BYTES)?;
+        set_limit(libc::RLIMIT_FSIZE, MAX_FILE_BYTES)?;
+        set_limit(libc::RLIMIT_NOFILE, MAX_OPEN_FILES)?;
+        set_limit(libc::RLIMIT_CORE, 0)?;
+        if namespaces {
+            let flags = libc::CLONE_NEWUSER
+                | libc::CLONE_NEWNS
+                | libc::CLONE_NEWNET
+                | libc::CLONE_NEWIPC
+                | libc::CLONE_NEWUTS;
+            if libc::unshare(flags) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_env = "gnu")]
+pub(crate) type Resource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+pub(crate) type Resource = libc::c_int;
+
+/// # Safety
+/// Only calls `setrlimit`, safe between fork and exec.
+pub(crate) unsafe fn set_limit(resource: Resource, value: u64) -> std::io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: value,
+        rlim_max: value,
+    };
+    // SAFETY: `limit` outlives the call.
+    if unsafe { libc::setrlimit(resource, &limit) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder(dir: &Path, rustc: &str) -> HostBuilder {
+        HostBuilder::new(
+            PathBuf::from(rustc),
+            dir.join("builds"),
+            Arch::X86_64,
+            DEFAULT_BUILD_TIMEOUT,
+            false,
+        )
+    }
+
+    fn request(bundle: Option<&str>) -> ExecuteRequest {
+        ExecuteRequest {
+            language: "rust".to_string(),
+            code: "fn main() {}".to_string(),
+            bundle: bundle.map(str::to_string),
+            entrypoint: bundle.map(|_| "main.rs".to_string()),
+            deterministic: false,
+            offline: false,
+            stdin: None,
+            stream: false,
+            binary: None,
+            keep_binary: true,
+            build_only: true,
+            limits: None,
+            identity_token: None,
+            egress_proxy: None,
+            concurrent: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_compiler() {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = builder(dir.path(), "/nonexistent/rustc");
+        assert_eq!(builder.target(), "x86_64-unknown-linux-musl");
+        let err = builder.check().await.unwrap_err();
+        assert!(matches!(err, HostBuildError::Toolchain(_)), "{err}");
+
+        let err = builder.build("job-1", &request(None)).await.unwrap_err();
+        assert!(matches!(err, HostBuildError::Toolchain(_)), "{err}");
+        assert!(!dir.path().join("builds/job-1").exists());
+    }
+
+    #[tokio::test]
+    async fn test_failed_compile_and_bundles() {
+        let dir = tempfile::tempdir().unwrap();
+        // Stands in for a compiler that rejects the code.
+        let builder = builder(dir.path(), "/bin/false");
+        let result = builder.build("job-1", &request(None)).await.unwrap();
+        assert_eq!(result.exit_code, 1);
+        assert_eq!(result.binary, None);
+        let err = builder.check().await.unwrap_err();
+        assert!(matches!(err, HostBuildError::Toolchain(_)), "{err}");
+
+        let err = builder
+            .build("job-2", &request(Some("YnVuZGxl")))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HostBuildError::Bundle), "{err}");
+    }
+}
@@ -0,0 +1,242 @@
+//! Pressure on the CPU and memory of the host, for the admission of new VMs.
+//!
+//! Pressure is read from PSI, `/proc/pressure/{cpu,memory}`: the share of the
+//! last 10 seconds some task of the host spent waiting for a CPU, or for memory.
+//! Free memory is `MemAvailable` of `/proc/meminfo`. On kernels without PSI,
+//! only free memory is checked.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CPU_PRESSURE_LIMIT: f64 = 80.0;
+pub const DEFAULT_MEMORY_PRESSURE_LIMIT: f64 = 10.0;
+pub const DEFAULT_MIN_AVAILABLE_MEMORY_MB: u64 = 512;
+pub const DEFAULT_ADMISSION_MAX_WAIT_SECS: u64 = 30;
+
+/// How often a deferred VM looks at the pressure again.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often an idle VM is shut down while memory is short. PSI averages over
+/// 10 seconds, so the pressure takes a while to show what one VM gave back.
+pub const RELIEF_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pressure above which no VM is booted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PressureLimits {
+    /// Percentage of the last 10 seconds some task waited for a CPU.
+    pub cpu: f64,
+    /// Percentage of the last 10 seconds some task waited for memory.
+    pub memory: f64,
+    /// Memory the host must have available.
+    pub min_available_bytes: u64,
+}
+
+/// What the host reports, `None` for what its kernel does not expose.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HostLoad {
+    pub cpu: Option<f64>,
+    pub memory: Option<f64>,
+    pub available_bytes: Option<u64>,
+}
+
+/// Why the host is under pressure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pressure {
+    Cpu(f64),
+    Memory(f64),
+    LowMemory(u64),
+}
+
+impl Pressure {
+    /// Whether shutting VMs down gives the host what it lacks.
+    pub fn is_memory(&self) -> bool {
+        !matches!(self, Pressure::Cpu(_))
+    }
+}
+
+impl fmt::Display for Pressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pressure::Cpu(avg10) => write!(f, "CPU pressure at {avg10:.1}%"),
+            Pressure::Memory(avg10) => write!(f, "memory pressure at {avg10:.1}%"),
+            Pressure::LowMemory(bytes) => {
+                write!(f, "only {} MiB of memory available", bytes / (1024 * 1024))
+            }
+        }
+    }
+}
+
+pub struct HostPressure {
+    /// `/proc`, or a directory laid out like it.
+    proc_dir: PathBuf,
+    limits: PressureLimits,
+}
+
+impl HostPressure {
+    pub fn new(proc_dir: impl Into<PathBuf>, limits: PressureLimits) -> Self {
+        Self {
+            proc_dir: proc_dir.into(),
+            limits,
+        }
+    }
+
+    pub fn limits(&self) -> PressureLimits {
+        self.limits
+    }
+
+    /// Reads the pressure and free memory of the host.
+    pub fn read(&self) -> HostLoad {
+        let read = |path: &str| std::fs::read_to_string(self.proc_dir.join(path)).ok();
+        HostLoad {
+            cpu: read("pressure/cpu").as_deref().and_then(some_avg10),
+            memory: read("pressure/memory").as_deref().and_then(some_avg10),
+            available_bytes: read("meminfo").as_deref().and_then(mem_available),
+        }
+    }
+
+    /// The pressure the host is under, if any. Lack of memory comes first, as
+    /// it is what can be relieved.
+    pub fn check(&self) -> Option<Pressure> {
+        let load = self.read();
+        if let Some(available) = load
+            .available_bytes
+            .filter(|&a| a < self.limits.min_available_bytes)
+        {
+            return Some(Pressure::LowMemory(available));
+        }
+        if let Some(memory) = load.memory.filter(|&m| m > self.limits.memory) {
+            return Some(Pressure::Memory(memory));
+        }
+        load.cpu
+            .filter(|&cpu| cpu > self.limits.cpu)
+            .map(Pressure::Cpu)
+    }
+
+    /// Waits until the host is not under pressure, for `max_wait` at most.
+    /// Returns how long it waited, zero when it was not under pressure, or the
+    /// pressure that outlasted `max_wait`.
+    pub async fn admit(&self, max_wait: Duration) -> Result<Duration, Pressure> {
+        let start = Instant::now();
+        let Some(mut pressure) = self.check() else {
+            return Ok(Duration::ZERO);
+        };
+        while start.elapsed() < max_wait {
+            tokio::time::sleep(RECHECK_INTERVAL).await;
+            match self.check() {
+                None => return Ok(start.elapsed()),
+                Some(still) => pressure = still,
+            }
+        }
+        Err(pressure)
+    }
+}
+
+/// `avg10` of the `some` line of a PSI file.
+fn some_avg10(psi: &str) -> Option<f64> {
+    psi.lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+/// `MemAvailable` of `/proc/meminfo`, in bytes.
+fn mem_available(meminfo: &str) -> Option<u64> {
+    let kib: u64 = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: PressureLimits = PressureLimits {
+        cpu: 80.0,
+        memory: 10.0,
+        min_available_bytes: 512 * 1024 * 1024,
+    };
+
+    fn psi(avg10: f64) -> String {
+        format!(
+            "some avg10={avg10:.2} avg60=1.00 avg300=0.50 total=12345\n\
+             full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n"
+        )
+    }
+
+    fn meminfo(available_mb: u64) -> String {
+        format!(
+            "MemTotal:       16318468 kB\nMemFree:          812340 kB\nMemAvailable:   {} kB\n",
+            available_mb * 1024
+        )
+    }
+
+    fn host(cpu: f64, memory: f64, available_mb: u64) -> (tempfile::TempDir, HostPressure) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("pressure")).unwrap();
+        std::fs::write(dir.path().join("pressure/cpu"), psi(cpu)).unwrap();
+        std::fs::write(dir.path().join("pressure/memory"), psi(memory)).unwrap();
+        std::fs::write(dir.path().join("meminfo"), meminfo(available_mb)).unwrap();
+        let pressure = HostPressure::new(dir.path(), LIMITS);
+        (dir, pressure)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(some_avg10(&psi(12.5)), Some(12.5));
+        assert_eq!(some_avg10("full avg10=3.00"), None);
+        assert_eq!(mem_available(&meminfo(2048)), Some(2048 * 1024 * 1024));
+        assert_eq!(mem_available("MemTotal: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn test_check() {
+        let (_dir, calm) = host(5.0, 0.0, 4096);
+        assert_eq!(calm.check(), None);
+
+        let (_dir, busy) = host(95.0, 0.0, 4096);
+        assert_eq!(busy.check(), Some(Pressure::Cpu(95.0)));
+        assert!(!busy.check().unwrap().is_memory());
+
+        // Memory comes first.
+        let (_dir, swapping) = host(95.0, 40.0, 4096);
+        assert_eq!(swapping.check(), Some(Pressure::Memory(40.0)));
+        let (_dir, full) = host(95.0, 40.0, 256);
+        assert_eq!(full.check(), Some(Pressure::LowMemory(256 * 1024 * 1024)));
+        assert_eq!(
+            full.check().unwrap().to_string(),
+            "only 256 MiB of memory available"
+        );
+    }
+
+    #[test]
+    fn test_missing_psi_is_not_pressure() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("meminfo"), meminfo(4096)).unwrap();
+        let pressure = HostPressure::new(dir.path(), LIMITS);
+        assert_eq!(
+            pressure.read(),
+            HostLoad {
+                cpu: None,
+                memory: None,
+                available_bytes: Some(4096 * 1024 * 1024),
+            }
+        );
+        assert_eq!(pressure.check(), None);
+    }
+
+    #[tokio::test]
+    async fn test_admit() {
+        let (_dir, calm) = host(5.0, 0.0, 4096);
+        assert_eq!(calm.admit(Duration::ZERO).await, Ok(Duration::ZERO));
+        let (_dir, busy) = host(95.0, 0.0, 4096);
+        assert_eq!(busy.admit(Duration::ZERO).await, Err(Pressure::Cpu(95.0)));
+    }
+}
@@ -0,0 +1,307 @@
+//! Identity tokens of the VMs running jobs.
+//!
+//! Each job run in a VM gets a short-lived JWT signed with the Ed25519 key of the
+//! backend, which names the VM, the job, the function and version it runs, the
+//! tenant, and the audience the job asked for. The code presents it to outside
+//! services, which check it with the public key served at
+//! `GET /.well-known/jwks.json` rather than share a secret with the code.
+//!
+//! The backend sends the agent a new token before the last one expires, for as
+//! long as the job runs. The agent keeps the latest in a file whose path is in
+//! `CLOUDE_IDENTITY_TOKEN_FILE`; `CLOUDE_IDENTITY_TOKEN` only has the first.
+//!
+//! The key is kept in a PKCS#8 file, created on the first start, so that tokens
+//! stay valid across restarts. Its id is derived from the public key: a new key
+//! has a new id, which tells verifiers to fetch the key set again.
+
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use cloude_types::{Jwk, Jwks};
+use ring::rand::SystemRandom;
+use ring::signature::{ED25519, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a token is valid by default.
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(15 * 60);
+
+const ALGORITHM: &str = "EdDSA";
+
+#[derive(Debug)]
+pub enum IdentityError {
+    Io(std::io::Error),
+    /// The key file holds no Ed25519 key, or one could not be generated.
+    Key(String),
+    /// The token is not one of ours: malformed, or signed with another key.
+    Invalid(&'static str),
+    Expired,
+}
+
+impl std::fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentityError::Io(e) => write!(f, "IO error: {}", e),
+            IdentityError::Key(e) => write!(f, "Invalid identity key: {}", e),
+            IdentityError::Invalid(e) => write!(f, "Invalid token: {}", e),
+            IdentityError::Expired => write!(f, "Token expired"),
+        }
+    }
+}
+
+impl std::error::Error for IdentityError {}
+
+impl From<std::io::Error> for IdentityError {
+    fn from(err: std::io::Error) -> Self {
+        IdentityError::Io(err)
+    }
+}
+
+/// What a token says about the job it was issued to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JobIdentity {
+    pub vm_id: String,
+    pub job_id: String,
+    /// Deployed function the job runs, none for code sent with `POST /run`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// Caller the job runs for, see `X-Cloude-User`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+/// Claims of a token.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IdentityClaims {
+    pub iss: String,
+    /// `vm:<vm id>`.
+    pub sub: String,
+    /// Issue and expiry times, in seconds since the Unix epoch.
+    pub iat: u64,
+    pub exp: u64,
+    /// Service the token is meant for, as the job asked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(flatten)]
+    pub job: JobIdentity,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+/// Signs the tokens of the VMs, and checks them.
+pub struct IdentityIssuer {
+    key: Ed25519KeyPair,
+    kid: String,
+    issuer: String,
+    ttl: Duration,
+}
+
+impl IdentityIssuer {
+    /// Signs with the key in `path`, created there when the file does not exist.
+    pub fn load_or_create(path: &Path, issuer: &str, ttl: Duration) -> Result<Self, IdentityError> {
+        let pkcs8 = match std::fs::read(path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                    .map_err(|_| IdentityError::Key("cannot generate a key".to_string()))?;
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)?
+                    .write_all(pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Self::from_pkcs8(&pkcs8, issuer, ttl)
+    }
+
+    pub fn from_pkcs8(pkcs8: &[u8], issuer: &str, ttl: Duration) -> Result<Self, IdentityError> {
+        let key =
+            Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|e| IdentityError::Key(e.to_string()))?;
+        let kid = hex::encode(&Sha256::digest(key.public_key().as_ref())[..8]);
+        Ok(IdentityIssuer {
+            key,
+            kid,
+            issuer: issuer.to_string(),
+            ttl,
+        })
+    }
+
+    /// How long the tokens are valid.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// A token for `job` meant for `audience`, valid from `now` for the TTL.
+    pub fn issue(&self, job: JobIdentity, audience: Option<&str>, now: SystemTime) -> String {
+        let iat = unix_secs(now);
+        let claims = IdentityClaims {
+            iss: self.issuer.clone(),
+            sub: format!("vm:{}", job.vm_id),
+            iat,
+            exp: iat + self.ttl.as_secs(),
+            aud: audience.map(str::to_string),
+            job,
+        };
+        let header = Header {
+            alg: ALGORITHM.to_string(),
+            typ: "JWT".to_string(),
+            kid: self.kid.clone(),
+        };
+        let signed = format!("{}.{}", encode_json(&header), encode_json(&claims));
+        let signature = self.key.sign(signed.as_bytes());
+        format!(
+            "{}.{}",
+            signed,
+            BASE64_URL_SAFE_NO_PAD.encode(signature.as_ref())
+        )
+    }
+
+    /// The claims of `token` if this issuer signed it for `audience`, when given,
+    /// and it has not expired at `now`.
+    pub fn verify(
+        &self,
+        token: &str,
+        audience: Option<&str>,
+        now: SystemTime,
+    ) -> Result<IdentityClaims, IdentityError> {
+        let (signed, signature) = token
+            .rsplit_once('.')
+            .ok_or(IdentityError::Invalid("not a JWT"))?;
+        let (header, claims) = signed
+            .split_once('.')
+            .ok_or(IdentityError::Invalid("not a JWT"))?;
+        let header: Header = decode_json(header)?;
+        if header.alg != ALGORITHM || header.kid != self.kid {
+            return Err(IdentityError::Invalid("signed with another key"));
+        }
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| IdentityError::Invalid("signature is not base64url"))?;
+        UnparsedPublicKey::new(&ED25519, self.key.public_key().as_ref())
+            .verify(signed.as_bytes(), &signature)
+            .map_err(|_| IdentityError::Invalid("bad signature"))?;
+
+        let claims: IdentityClaims = decode_json(claims)?;
+        if claims.iss != self.issuer {
+            return Err(IdentityError::Invalid("issued by another backend"));
+        }
+        if audience.is_some_and(|audience| claims.aud.as_deref() != Some(audience)) {
+            return Err(IdentityError::Invalid("meant for another audience"));
+        }
+        if claims.exp <= unix_secs(now) {
+            return Err(IdentityError::Expired);
+        }
+        Ok(claims)
+    }
+
+    /// The key set verifiers check tokens with.
+    pub fn jwks(&self) -> Jwks {
+        Jwks {
+            keys: vec![Jwk {
+                kty: "OKP".to_string(),
+                crv: "Ed25519".to_string(),
+                alg: ALGORITHM.to_string(),
+                use_: "sig".to_string(),
+                kid: self.kid.clone(),
+                x: BASE64_URL_SAFE_NO_PAD.encode(self.key.public_key().as_ref()),
+            }],
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn encode_json<T: Serialize>(value: &T) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).expect("Token parts serialize"))
+}
+
+fn decode_json<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, IdentityError> {
+    let json = BASE64_URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| IdentityError::Invalid("part is not base64url"))?;
+    serde_json::from_slice(&json)
+        .map_err(|_| IdentityError::Invalid("part is not the expected JSON"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job() -> JobIdentity {
+        JobIdentity {
+            vm_id: "vm-1".to_string(),
+            job_id: "job-1".to_string(),
+            function: Some("resize".to_string()),
+            version: Some(3),
+            tenant: Some("alice".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("identity.pk8");
+        let issuer = IdentityIssuer::load_or_create(&path, "cloude", DEFAULT_TOKEN_TTL).unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1_760_000_000);
+        let token = issuer.issue(job(), Some("https://vault.example.com"), now);
+
+        let claims = issuer
+            .verify(&token, Some("https://vault.example.com"), now)
+            .unwrap();
+        assert_eq!(claims.sub, "vm:vm-1");
+        assert_eq!(claims.exp, 1_760_000_900);
+        assert_eq!(claims.aud.as_deref(), Some("https://vault.example.com"));
+        assert_eq!(claims.job, job());
+        assert!(matches!(
+            issuer.verify(&token, Some("https://db.example.com"), now),
+            Err(IdentityError::Invalid(_))
+        ));
+        let anyone = issuer.issue(job(), None, now);
+        assert!(
+            issuer
+                .verify(&anyone, Some("https://vault.example.com"), now)
+                .is_err()
+        );
+
+        // The key is kept: tokens outlive a restart.
+        let restarted = IdentityIssuer::load_or_create(&path, "cloude", DEFAULT_TOKEN_TTL).unwrap();
+        assert_eq!(restarted.jwks(), issuer.jwks());
+        assert!(restarted.verify(&token, None, now).is_ok());
+
+        let later = now + DEFAULT_TOKEN_TTL;
+        assert!(matches!(
+            issuer.verify(&token, None, later),
+            Err(IdentityError::Expired)
+        ));
+        let (signed, _) = token.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", signed, BASE64_URL_SAFE_NO_PAD.encode([0u8; 64]));
+        assert!(matches!(
+            issuer.verify(&forged, None, now),
+            Err(IdentityError::Invalid(_))
+        ));
+
+        let other_dir = tempfile::TempDir::new().unwrap();
+        let other = IdentityIssuer::load_or_create(
+            &other_dir.path().join("k"),
+            "cloude",
+            DEFAULT_TOKEN_TTL,
+        )
+        .unwrap();
+        assert!(other.verify(&token, None, now).is_err());
+    }
+}
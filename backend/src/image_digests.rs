@@ -0,0 +1,76 @@
+//! Digests of the runtime images VMs boot.
+//!
+//! An image is identified by the SHA-256 of its initramfs, which holds the
+//! toolchain of the runtime and the agent. Hashing an initramfs takes a while,
+//! so digests are kept until the file is rebuilt.
+
+use crate::artifact_store::{ArtifactError, sha256_file};
+use crate::vm_lifecycle::{VmConfig, VmError, VmHandle};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Errors that can occur while computing the digest of an image.
+#[derive(Debug)]
+pub enum ImageDigestError {
+    /// The initramfs of the runtime cannot be found.
+    Initramfs(VmError),
+    /// The initramfs of the runtime cannot be hashed.
+    Digest(ArtifactError),
+}
+
+impl std::fmt::Display for ImageDigestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageDigestError::Initramfs(e) => write!(f, "{}", e),
+            ImageDigestError::Digest(e) => write!(f, "Failed to hash the runtime image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImageDigestError {}
+
+#[derive(Default)]
+pub struct ImageDigests {
+    /// Digest of each initramfs, and its modification time when it was hashed.
+    digests: Mutex<HashMap<PathBuf, (SystemTime, String)>>,
+}
+
+impl ImageDigests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Digest of the image VMs of `language` boot with `config`.
+    pub async fn digest(
+        &self,
+        language: &str,
+        config: &VmConfig,
+    ) -> Result<String, ImageDigestError> {
+        let initramfs = VmHandle::build_initramfs_with_agent(language, config)
+            .await
+            .map_err(ImageDigestError::Initramfs)?;
+        self.file_digest(initramfs).await
+    }
+
+    /// Digest of the initramfs at `path`, hashed again only once it changed.
+    async fn file_digest(&self, path: PathBuf) -> Result<String, ImageDigestError> {
+        let modified = tokio::fs::metadata(&path)
+            .await
+            .and_then(|m| m.modified())
+            .map_err(|e| ImageDigestError::Digest(e.into()))?;
+        if let Some((at, digest)) = self.lock().get(&path)
+            && *at == modified
+        {
+            return Ok(digest.clone());
+        }
+        let (digest, _) = sha256_file(&path).await.map_err(ImageDigestError::Digest)?;
+        self.lock().insert(path, (modified, digest.clone()));
+        Ok(digest)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, (SystemTime, String)>> {
+        self.digests.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
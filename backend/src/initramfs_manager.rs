@@ -4,28 +4,193 @@ use std::future::Future;
 use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
 
+use cloude_types::{Arch, Resources};
 use initramfs_builder::{Compression, InitramfsBuilder};
 use serde::Deserialize;
 use serde_json;
 
+use crate::scratch_initramfs::{self, SCRATCH_IMAGE};
+
+/// Appended to the name of a runtime to name its run stage, see `run_image`.
+pub const RUN_STAGE_SUFFIX: &str = "_run";
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct InitramfsLanguage {
-    pub name: String,       // e.g., "python", "rust", "node"
-    pub version: String,    // compatibility/version info
-    pub base_image: String, // docker image to use (e.g., "python:3.11-alpine")
+    pub name: String,                    // e.g., "python", "rust", "node"
+    pub version: String,                 // compatibility/version info
+    pub base_image: String,              // docker image to use (e.g., "python:3.11-alpine")
+    pub warmup_code: Option<String>,     // snippet run before snapshotting a template
+    pub upgrade: Option<RuntimeUpgrade>, // version this runtime should move to
+    pub resources: Option<Resources>,    // VM shape of its jobs when they ask for none
+    pub run_image: Option<String>,       // slim image compiled code runs in, see `run_stage`
+    pub arches: Vec<Arch>,               // what its images are built for, empty for any
+    pub build: BuildSite,                // where its code is compiled, see `run_stage`
+}
+
+/// Where the code of a compiled runtime with a run image is compiled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildSite {
+    /// In a build VM of its toolchain image.
+    #[default]
+    Vm,
+    /// On the backend host, in a build sandbox, for the architecture of its VMs.
+    /// The toolchain image is not built.
+    Host,
+}
+
+impl std::fmt::Display for BuildSite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildSite::Vm => write!(f, "vm"),
+            BuildSite::Host => write!(f, "host"),
+        }
+    }
+}
+
+/// Target of a pending runtime upgrade, e.g. `python: 3.12 -> 3.13`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub struct RuntimeUpgrade {
+    pub version: String,
+    pub base_image: String,
 }
 
 #[derive(Debug, Deserialize)]
 struct LanguageConfig {
     version: String,
     base_image: String,
+    #[serde(default)]
+    warmup: Option<String>,
+    #[serde(default)]
+    upgrade: Option<RuntimeUpgrade>,
+    #[serde(default)]
+    resources: Option<Resources>,
+    #[serde(default)]
+    run_image: Option<String>,
+    #[serde(default)]
+    arches: Vec<Arch>,
+    #[serde(default)]
+    build: BuildSite,
 }
 
 impl InitramfsLanguage {
+    /// Whether `other` runs on the same image, differing at most in its resources.
+    pub fn same_image(&self, other: &InitramfsLanguage) -> bool {
+        InitramfsLanguage {
+            resources: other.resources,
+            ..self.clone()
+        } == *other
+    }
+
+    /// Whether its images run on `arch`.
+    pub fn supports(&self, arch: Arch) -> bool {
+        self.arches.is_empty() || self.arches.contains(&arch)
+    }
+
+    /// Image the code of its jobs is compiled in, the one with the toolchain.
+    pub fn build_image(&self) -> &str {
+        &self.base_image
+    }
+
+    /// Image the code of its jobs runs in: the slim `run_image` if there is one.
+    pub fn run_image(&self) -> &str {
+        self.run_image.as_deref().unwrap_or(&self.base_image)
+    }
+
+    /// Name of the runtime whose initramfs is built from `run_image`, if it has one.
+    pub fn run_stage_name(&self) -> Option<String> {
+        self.run_image
+            .as_ref()
+            .map(|_| format!("{}{}", self.name, RUN_STAGE_SUFFIX))
+    }
+
+    /// The run stage as a runtime of its own: same version, built from `run_image`.
+    fn run_stage(&self) -> Option<InitramfsLanguage> {
+        Some(InitramfsLanguage {
+            name: self.run_stage_name()?,
+            version: self.version.clone(),
+            base_image: self.run_image.clone()?,
+            warmup_code: None,
+            upgrade: None,
+            resources: None,
+            run_image: None,
+            arches: self.arches.clone(),
+            build: BuildSite::Vm,
+        })
+    }
+
+    /// Build the initramfs of the runtime, and the one of its run stage if it has
+    /// a `run_image`, named `{name}_run-{version}.cpio.gz`, then remove the images
+    /// of its other versions. Runtimes built on the host only get the latter.
+    /// Runtimes whose images are not built for this machine get none, their jobs
+    /// are rejected.
+    pub async fn setup_initramfs(
+        self,
+        agent_binary: &str,
+        init_script: &str,
+        initramfs_dir: &str,
+    ) -> Result<(), Error> {
+        self.stage_initramfs(agent_binary, init_script, initramfs_dir)
+            .await?;
+        self.remove_other_versions(initramfs_dir)
+    }
+
+    /// Build the images of [`Self::setup_initramfs`], next to those of its other
+    /// versions: jobs of the configuration in effect still boot from them until
+    /// [`Self::remove_other_versions`].
+    pub async fn stage_initramfs(
+        &self,
+        agent_binary: &str,
+        init_script: &str,
+        initramfs_dir: &str,
+    ) -> Result<(), Error> {
+        if let Some(host) = Arch::host()
+            && !self.supports(host)
+        {
+            println!(
+                "Skipping {} initramfs: its images are not built for {}",
+                self.name, host
+            );
+            return Ok(());
+        }
+        for image in self.images() {
+            image
+                .setup_image(agent_binary, init_script, initramfs_dir)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the images of the other versions of the runtime and of its run stage.
+    pub fn remove_other_versions(&self, initramfs_dir: &str) -> Result<(), Error> {
+        for image in self.images() {
+            let (tmp_dir, _, _, current_filename, current_prefix) =
+                Self::prepare_paths(initramfs_dir, &image.name, &image.version)?;
+            Self::cleanup_old_versions(&tmp_dir, &current_prefix, &current_filename)?;
+        }
+        Ok(())
+    }
+
+    /// The runtime and its run stage, as far as each has an image built on this machine.
+    fn images(&self) -> Vec<InitramfsLanguage> {
+        if let Some(host) = Arch::host()
+            && !self.supports(host)
+        {
+            return Vec::new();
+        }
+        let run_stage = self.run_stage();
+        let mut images = Vec::new();
+        if self.build == BuildSite::Vm || run_stage.is_none() {
+            images.push(self.clone());
+        }
+        images.extend(run_stage);
+        images
+    }
+
     /// Build the initramfs generically from the struct fields.
-    /// Produces an image named `{name}-{version}.cpio.gz` in backend/tmp.
-    /// After a successful build, older versions with the same `name` are removed from tmp/.
-    pub fn setup_initramfs(
+    /// Produces an image named `{name}-{version}.cpio.gz` in backend/tmp. A stale
+    /// image is rebuilt beside it and replaces it once complete.
+    fn setup_image(
         self,
         agent_binary: &str,
         init_script: &str,
@@ -36,6 +201,7 @@ impl InitramfsLanguage {
                 name,
                 version,
                 base_image,
+                ..
             } = self;
 
             println!(
@@ -43,44 +209,57 @@ impl InitramfsLanguage {
                 name, version, base_image
             );
 
-            let (tmp_dir, out_path, out_file, current_filename, current_prefix) =
-                Self::prepare_paths(initramfs_dir, &name, &version)?;
-
-            // Skip rebuild if existing non-empty file is present, but still cleanup old versions.
-            if let Ok(meta) = fs::metadata(&out_path) {
-                if meta.len() > 0 {
-                    if Self::should_rebuild(&out_path, agent_binary, init_script, &base_image)? {
-                        let _ = fs::remove_file(&out_path);
-                    } else {
-                        Self::cleanup_old_versions(
-                            tmp_dir.as_str(),
-                            &current_prefix,
-                            &current_filename,
-                        )?;
-                        return Ok(());
-                    }
-                } else {
-                    let _ = fs::remove_file(&out_path);
-                }
+            #[cfg(feature = "chaos")]
+            if crate::chaos::fire(crate::chaos::FaultKind::InitramfsBuild, &name).is_some() {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("injected initramfs build failure for {}", name),
+                ));
             }
 
-            Self::build_initramfs(&base_image, out_file, &out_path, &agent_binary, init_script)
+            let (_, out_path, _, _, _) = Self::prepare_paths(initramfs_dir, &name, &version)?;
+
+            // Skip rebuild if existing non-empty file is present and up to date.
+            if let Ok(meta) = fs::metadata(&out_path)
+                && meta.len() > 0
+                && !Self::should_rebuild(&out_path, agent_binary, init_script, &base_image)?
+            {
+                return Ok(());
+            }
+
+            let staging_path = PathBuf::from(format!("{}.staging", out_path.display()));
+            if base_image == SCRATCH_IMAGE {
+                scratch_initramfs::write(&staging_path, Path::new(agent_binary)).inspect_err(
+                    |_| {
+                        let _ = fs::remove_file(&staging_path);
+                    },
+                )?;
+            } else {
+                Self::build_initramfs(
+                    &base_image,
+                    staging_path.to_string_lossy().to_string(),
+                    &staging_path,
+                    agent_binary,
+                    init_script,
+                )
                 .await?;
+            }
 
-            let metadata =
-                fs::metadata(&out_path).map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+            let metadata = fs::metadata(&staging_path)
+                .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
             if metadata.len() == 0 {
-                let _ = fs::remove_file(&out_path);
+                let _ = fs::remove_file(&staging_path);
                 return Err(Error::new(
                     ErrorKind::Other,
                     format!("initramfs {} is empty", out_path.display()),
                 ));
             }
 
+            // VMs booting meanwhile read the previous image, whole.
+            fs::rename(&staging_path, &out_path)
+                .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
             Self::write_build_metadata(&out_path, &base_image)?;
 
-            Self::cleanup_old_versions(tmp_dir.as_str(), &current_prefix, &current_filename)?;
-
             Ok(())
         }
     }
@@ -255,7 +434,233 @@ pub fn get_languages_config(path: &str) -> Result<Vec<InitramfsLanguage>, Error>
             name,
             version: cfg.version,
             base_image: cfg.base_image,
+            warmup_code: cfg.warmup,
+            upgrade: cfg.upgrade,
+            resources: cfg.resources,
+            run_image: cfg.run_image,
+            arches: cfg.arches,
+            build: cfg.build,
         })
         .collect();
     Ok(languages)
 }
+
+/// Versions of `name` currently built in `initramfs_dir`, with the disk space
+/// each one uses (image plus build metadata).
+pub fn installed_versions(initramfs_dir: &str, name: &str) -> Result<Vec<(String, u64)>, Error> {
+    let prefix = format!("{name}-");
+    let mut versions = Vec::new();
+
+    let entries = match fs::read_dir(initramfs_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(versions),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(version) = path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .and_then(|fname| fname.strip_prefix(&prefix))
+            .and_then(|rest| rest.strip_suffix(".cpio.gz"))
+        else {
+            continue;
+        };
+
+        let image_size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        let metadata_size = fs::metadata(InitramfsLanguage::metadata_path(&path))
+            .map(|m| m.len())
+            .unwrap_or(0);
+        versions.push((version.to_string(), image_size + metadata_size));
+    }
+
+    versions.sort();
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_installed_versions() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("python-3.12.cpio.gz"), b"12345").unwrap();
+        fs::write(dir.path().join("python-3.12.cpio.gz.meta"), b"img\n").unwrap();
+        fs::write(dir.path().join("python-3.13.cpio.gz"), b"123").unwrap();
+        fs::write(dir.path().join("node-20.cpio.gz"), b"1").unwrap();
+
+        let versions = installed_versions(dir.path().to_str().unwrap(), "python").unwrap();
+        assert_eq!(
+            versions,
+            vec![("3.12".to_string(), 9), ("3.13".to_string(), 3)]
+        );
+    }
+
+    #[test]
+    fn test_languages_config_with_upgrade() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("languages.json");
+        fs::write(
+            &path,
+            r#"{ "python": { "version": "3.12", "base_image": "python:3.12-alpine",
+                 "upgrade": { "version": "3.13", "base_image": "python:3.13-alpine" } } }"#,
+        )
+        .unwrap();
+
+        let languages = get_languages_config(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            languages[0].upgrade,
+            Some(RuntimeUpgrade {
+                version: "3.13".to_string(),
+                base_image: "python:3.13-alpine".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_languages_config_with_resources() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("languages.json");
+        fs::write(
+            &path,
+            r#"{ "rust": { "version": "1.81", "base_image": "rust:1.81-alpine",
+                 "resources": { "vcpus": 2, "memory_mb": 1024 } } }"#,
+        )
+        .unwrap();
+
+        let languages = get_languages_config(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            languages[0].resources,
+            Some(Resources {
+                vcpus: Some(2),
+                memory_mb: Some(1024),
+            })
+        );
+
+        let mut resized = languages[0].clone();
+        resized.resources = None;
+        assert!(resized.same_image(&languages[0]));
+        resized.version = "1.82".to_string();
+        assert!(!resized.same_image(&languages[0]));
+    }
+
+    #[test]
+    fn test_languages_config_with_run_image() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("languages.json");
+        fs::write(
+            &path,
+            r#"{ "rust": { "version": "1.81", "base_image": "rust:1.81-alpine",
+                 "run_image": "alpine:3.20" } }"#,
+        )
+        .unwrap();
+
+        let languages = get_languages_config(path.to_str().unwrap()).unwrap();
+        let rust = &languages[0];
+        assert_eq!(rust.build_image(), "rust:1.81-alpine");
+        assert_eq!(rust.run_image(), "alpine:3.20");
+        assert_eq!(rust.run_stage_name().as_deref(), Some("rust_run"));
+        let run_stage = rust.run_stage().unwrap();
+        assert_eq!(run_stage.version, "1.81");
+        assert_eq!(run_stage.base_image, "alpine:3.20");
+        assert_eq!(run_stage.run_stage(), None);
+
+        let mut single = rust.clone();
+        single.run_image = None;
+        assert_eq!(single.run_image(), "rust:1.81-alpine");
+        assert_eq!(single.run_stage_name(), None);
+        assert!(!single.same_image(rust));
+    }
+
+    #[test]
+    fn test_languages_config_with_host_build() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("languages.json");
+        fs::write(
+            &path,
+            r#"{ "rust": { "version": "1.81", "base_image": "rust:1.81-alpine",
+                 "run_image": "scratch", "build": "host" } }"#,
+        )
+        .unwrap();
+
+        let languages = get_languages_config(path.to_str().unwrap()).unwrap();
+        let rust = &languages[0];
+        assert_eq!(rust.build, BuildSite::Host);
+        assert_eq!(rust.run_image(), SCRATCH_IMAGE);
+        // The run stage is built like any other image, in no build VM of its own.
+        assert_eq!(rust.run_stage().unwrap().build, BuildSite::Vm);
+    }
+
+    #[test]
+    fn test_languages_config_with_arches() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("languages.json");
+        fs::write(
+            &path,
+            r#"{ "go": { "version": "1.22", "base_image": "golang:1.22-alpine", "arches": ["aarch64"] },
+                 "c": { "version": "13", "base_image": "gcc:13" } }"#,
+        )
+        .unwrap();
+
+        let mut languages = get_languages_config(path.to_str().unwrap()).unwrap();
+        languages.sort_by(|a, b| a.name.cmp(&b.name));
+        let (c, go) = (&languages[0], &languages[1]);
+        assert!(c.supports(Arch::X86_64) && c.supports(Arch::Aarch64));
+        assert!(go.supports(Arch::Aarch64));
+        assert!(!go.supports(Arch::X86_64));
+
+        fs::write(
+            &path,
+            r#"{ "go": { "version": "1.22", "base_image": "golang:1.22-alpine", "arches": ["sparc"] } }"#,
+        )
+        .unwrap();
+        assert!(get_languages_config(path.to_str().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_staged_image_keeps_other_versions() {
+        let dir = TempDir::new().unwrap();
+        let agent = dir.path().join("cloude-agentd");
+        let init = dir.path().join("init.sh");
+        fs::write(&agent, b"agent").unwrap();
+        fs::write(&init, b"#!/bin/sh").unwrap();
+        let images = dir.path().join("images");
+        let images = images.to_str().unwrap();
+        let (agent, init) = (agent.to_str().unwrap(), init.to_str().unwrap());
+
+        let mut language = InitramfsLanguage {
+            name: "c".to_string(),
+            version: "13".to_string(),
+            base_image: SCRATCH_IMAGE.to_string(),
+            warmup_code: None,
+            upgrade: None,
+            resources: None,
+            run_image: None,
+            arches: Vec::new(),
+            build: BuildSite::Vm,
+        };
+        language
+            .clone()
+            .setup_initramfs(agent, init, images)
+            .await
+            .unwrap();
+
+        language.version = "14".to_string();
+        language.stage_initramfs(agent, init, images).await.unwrap();
+        let versions = installed_versions(images, "c").unwrap();
+        assert_eq!(
+            versions.iter().map(|(v, _)| v.as_str()).collect::<Vec<_>>(),
+            vec!["13", "14"]
+        );
+
+        language.remove_other_versions(images).unwrap();
+        let versions = installed_versions(images, "c").unwrap();
+        assert_eq!(
+            versions.iter().map(|(v, _)| v.as_str()).collect::<Vec<_>>(),
+            vec!["14"]
+        );
+    }
+}
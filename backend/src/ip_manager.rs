@@ -11,6 +11,8 @@ use std::sync::Mutex;
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct IpManagerState {
     pub allocations: HashMap<String, String>, // vm_id -> ip_address
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub segments: HashMap<String, u32>, // tenant -> segment index
 }
 
 /// A thread-safe manager for allocating and releasing IP addresses for VMs.
@@ -20,15 +22,79 @@ pub struct IpManager {
     file_path: PathBuf,
     start_ip: u32,
     end_ip: u32,
+    segments: Option<SegmentLayout>,
     lock: Mutex<()>,
 }
 
+/// How the network of the pool is split into one segment per tenant.
+#[derive(Debug, Clone, Copy)]
+struct SegmentLayout {
+    network: u32,
+    prefix_len: u8,
+    segment_len: u8,
+}
+
+impl SegmentLayout {
+    fn count(&self) -> u32 {
+        1 << (self.segment_len - self.prefix_len)
+    }
+
+    fn segment(&self, index: u32) -> Segment {
+        Segment {
+            index,
+            network: (self.network + (index << (32 - self.segment_len))).into(),
+            prefix_len: self.segment_len,
+            supernet: self.network.into(),
+            supernet_len: self.prefix_len,
+        }
+    }
+
+    /// Index of the segment `ip` is in, `None` outside the network.
+    fn index_of(&self, ip: u32) -> Option<u32> {
+        let index = ip.checked_sub(self.network)? >> (32 - self.segment_len);
+        (index < self.count()).then_some(index)
+    }
+}
+
+/// Block of addresses of a tenant, carved from the network of the pool. Segment 0
+/// is shared by the VMs of no tenant in particular.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub index: u32,
+    pub network: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Network of the pool the segment is carved from.
+    pub supernet: Ipv4Addr,
+    pub supernet_len: u8,
+}
+
+impl Segment {
+    /// First address of the segment, held by the host: the gateway of its VMs.
+    pub fn gateway(&self) -> Ipv4Addr {
+        (u32::from(self.network) + 1).into()
+    }
+
+    fn broadcast(&self) -> u32 {
+        u32::from(self.network) | (u32::MAX >> self.prefix_len)
+    }
+}
+
+/// Address allocated to a VM, in the segment of its tenant when tenants have one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub ip: Ipv4Addr,
+    pub segment: Option<Segment>,
+}
+
 /// Errors that can occur during IP management operations.
 #[derive(Debug)]
 pub enum IpManagerError {
     Io(std::io::Error),
     Json(serde_json::Error),
     PoolExhausted,
+    /// Every segment has a tenant already.
+    SegmentsExhausted,
+    InvalidSegments(String),
 }
 
 impl std::fmt::Display for IpManagerError {
@@ -37,6 +103,8 @@ impl std::fmt::Display for IpManagerError {
             IpManagerError::Io(e) => write!(f, "IO error: {}", e),
             IpManagerError::Json(e) => write!(f, "JSON error: {}", e),
             IpManagerError::PoolExhausted => write!(f, "IP pool exhausted"),
+            IpManagerError::SegmentsExhausted => write!(f, "No network segment left for a tenant"),
+            IpManagerError::InvalidSegments(e) => write!(f, "Invalid tenant segments: {}", e),
         }
     }
 }
@@ -71,6 +139,7 @@ impl IpManager {
             file_path: file_path.as_ref().to_path_buf(),
             start_ip: u32::from(start_ip),
             end_ip: u32::from(end_ip),
+            segments: None,
             lock: Mutex::new(()),
         };
 
@@ -81,6 +150,32 @@ impl IpManager {
         Ok(manager)
     }
 
+    /// Splits `network`/`prefix_len`, the network of the pool, into segments of
+    /// `segment_len` bits, one per tenant. Addresses are then allocated in the
+    /// segment of the tenant of the VM, or in segment 0 for VMs of no tenant.
+    pub fn with_tenant_segments(
+        mut self,
+        network: Ipv4Addr,
+        prefix_len: u8,
+        segment_len: u8,
+    ) -> Result<Self, IpManagerError> {
+        if segment_len <= prefix_len || segment_len > 30 {
+            return Err(IpManagerError::InvalidSegments(format!(
+                "a /{} segment does not fit in a /{} network with room for a gateway and VMs",
+                segment_len, prefix_len
+            )));
+        }
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0);
+        self.segments = Some(SegmentLayout {
+            network: u32::from(network) & mask,
+            prefix_len,
+            segment_len,
+        });
+        Ok(self)
+    }
+
     /// Reads the current IP allocation state from the JSON file.
     /// If the file does not exist or is empty, it returns a new default state.
     fn read_state(&self) -> Result<IpManagerState, IpManagerError> {
@@ -126,19 +221,68 @@ impl IpManager {
     /// # Arguments
     /// * `vm_id` - A unique identifier for the Virtual Machine.
     pub fn allocate_ip(&self, vm_id: &str) -> Result<String, IpManagerError> {
+        self.allocate(vm_id, None).map(|lease| lease.ip.to_string())
+    }
+
+    /// Allocates an available IP address for the specified VM, in the segment of
+    /// `tenant` with tenant segments, giving the tenant a segment if it has none.
+    /// If the VM already has an allocated IP, the existing lease is returned idempotently.
+    ///
+    /// # Arguments
+    /// * `vm_id` - A unique identifier for the Virtual Machine.
+    /// * `tenant` - Who the VM runs jobs for, `None` for no tenant in particular.
+    pub fn allocate(&self, vm_id: &str, tenant: Option<&str>) -> Result<Lease, IpManagerError> {
         let _guard = self.lock.lock().unwrap();
         let mut state = self.read_state()?;
 
         if let Some(existing_ip) = state.allocations.get(vm_id) {
-            return Ok(existing_ip.clone());
+            let ip: Ipv4Addr = existing_ip.parse().map_err(|e| {
+                IpManagerError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid IP {} of VM {}: {}", existing_ip, vm_id, e),
+                ))
+            })?;
+            let segment = self.segments.and_then(|layout| {
+                layout
+                    .index_of(u32::from(ip))
+                    .map(|index| layout.segment(index))
+            });
+            return Ok(Lease { ip, segment });
         }
 
+        let segment = match (self.segments, tenant) {
+            (None, _) => None,
+            (Some(layout), None) => Some(layout.segment(0)),
+            (Some(layout), Some(tenant)) => {
+                let index = match state.segments.get(tenant) {
+                    Some(&index) => index,
+                    None => {
+                        let taken: HashSet<u32> = state.segments.values().copied().collect();
+                        let index = (1..layout.count())
+                            .find(|index| !taken.contains(index))
+                            .ok_or(IpManagerError::SegmentsExhausted)?;
+                        state.segments.insert(tenant.to_string(), index);
+                        index
+                    }
+                };
+                Some(layout.segment(index))
+            }
+        };
+        // The network and gateway addresses of a segment, and its broadcast, are not for VMs.
+        let (start_ip, end_ip) = match &segment {
+            Some(segment) => (
+                self.start_ip.max(u32::from(segment.gateway()) + 1),
+                self.end_ip.min(segment.broadcast() - 1),
+            ),
+            None => (self.start_ip, self.end_ip),
+        };
+
         let allocated_ips: HashSet<&String> = state.allocations.values().collect();
 
-        let mut current_ip_val = self.start_ip;
+        let mut current_ip_val = start_ip;
         let mut selected_ip = None;
 
-        while current_ip_val <= self.end_ip {
+        while current_ip_val <= end_ip {
             let ip_addr = Ipv4Addr::from(current_ip_val).to_string();
             if !allocated_ips.contains(&ip_addr) {
                 selected_ip = Some(ip_addr);
@@ -152,7 +296,10 @@ impl IpManager {
 
         self.write_state(&state)?;
 
-        Ok(ip)
+        Ok(Lease {
+            ip: Ipv4Addr::from(current_ip_val),
+            segment,
+        })
     }
 
     /// Releases the IP address associated with the given VM, making it available again.
@@ -161,22 +308,45 @@ impl IpManager {
     /// * `vm_id` - The unique identifier of the Virtual Machine.
     ///
     /// Returns `true` if an IP was successfully released, `false` if the VM had no IP allocated.
+    /// The segment of a tenant goes back to the pool with the last IP of its VMs.
     pub fn release_ip(&self, vm_id: &str) -> Result<bool, IpManagerError> {
         let _guard = self.lock.lock().unwrap();
         let mut state = self.read_state()?;
 
-        if state.allocations.remove(vm_id).is_some() {
+        if let Some(ip) = state.allocations.remove(vm_id) {
+            if let Some(layout) = self.segments {
+                let index_of = |ip: &str| {
+                    ip.parse::<Ipv4Addr>()
+                        .ok()
+                        .and_then(|ip| layout.index_of(u32::from(ip)))
+                };
+                let index = index_of(&ip);
+                if !state
+                    .allocations
+                    .values()
+                    .any(|other| index_of(other) == index)
+                {
+                    state.segments.retain(|_, segment| Some(*segment) != index);
+                }
+            }
             self.write_state(&state)?;
             Ok(true)
         } else {
             Ok(false)
         }
     }
+
+    /// Returns every allocated IP address, by VM id.
+    pub fn allocations(&self) -> Result<HashMap<String, String>, IpManagerError> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(self.read_state()?.allocations)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use tempfile::NamedTempFile;
 
     fn test_manager() -> (IpManager, NamedTempFile) {
@@ -249,4 +419,184 @@ mod tests {
             assert_eq!(ip2, "10.0.0.2");
         }
     }
+
+    #[test]
+    fn test_tenant_segments() {
+        let file = NamedTempFile::new().unwrap();
+        let manager = IpManager::new(
+            file.path(),
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 254),
+        )
+        .unwrap()
+        .with_tenant_segments(Ipv4Addr::new(10, 0, 0, 0), 24, 26)
+        .unwrap();
+        let ip = |lease: Lease| lease.ip.to_string();
+
+        let shared = manager.allocate("vm-1", None).unwrap();
+        assert_eq!(ip(shared), "10.0.0.2");
+        assert_eq!(
+            shared.segment.unwrap().gateway(),
+            Ipv4Addr::new(10, 0, 0, 1)
+        );
+
+        let alice = manager.allocate("vm-2", Some("alice")).unwrap();
+        let segment = alice.segment.unwrap();
+        assert_eq!(
+            (segment.index, segment.network),
+            (1, Ipv4Addr::new(10, 0, 0, 64))
+        );
+        assert_eq!(ip(alice), "10.0.0.66");
+        assert_eq!(
+            ip(manager.allocate("vm-3", Some("bob")).unwrap()),
+            "10.0.0.130"
+        );
+        assert_eq!(
+            ip(manager.allocate("vm-4", Some("alice")).unwrap()),
+            "10.0.0.67"
+        );
+        assert_eq!(
+            ip(manager.allocate("vm-5", Some("carol")).unwrap()),
+            "10.0.0.194"
+        );
+        assert!(matches!(
+            manager.allocate("vm-6", Some("dave")),
+            Err(IpManagerError::SegmentsExhausted)
+        ));
+
+        // Alice's segment goes back to the pool with the last of her VMs.
+        manager.release_ip("vm-2").unwrap();
+        assert!(manager.allocate("vm-6", Some("dave")).is_err());
+        manager.release_ip("vm-4").unwrap();
+        assert_eq!(
+            ip(manager.allocate("vm-6", Some("dave")).unwrap()),
+            "10.0.0.66"
+        );
+
+        // Leases survive a restart, segment included.
+        let reloaded = IpManager::new(
+            file.path(),
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 254),
+        )
+        .unwrap()
+        .with_tenant_segments(Ipv4Addr::new(10, 0, 0, 0), 24, 26)
+        .unwrap();
+        assert_eq!(
+            reloaded
+                .allocate("vm-3", Some("bob"))
+                .unwrap()
+                .segment
+                .unwrap()
+                .index,
+            2
+        );
+
+        assert!(matches!(
+            IpManager::new(
+                file.path(),
+                Ipv4Addr::new(10, 0, 0, 2),
+                Ipv4Addr::new(10, 0, 0, 254)
+            )
+            .unwrap()
+            .with_tenant_segments(Ipv4Addr::new(10, 0, 0, 0), 24, 31),
+            Err(IpManagerError::InvalidSegments(_))
+        ));
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Allocate(usize),
+        Release(usize),
+        /// A running VM asking again for its address, e.g. after a backend restart.
+        Renew(usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0..8usize).prop_map(Op::Allocate),
+            (0..8usize).prop_map(Op::Release),
+            (0..8usize).prop_map(Op::Renew),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_allocations_match_model(
+            pool_size in 1u32..6,
+            ops in proptest::collection::vec(op(), 0..40),
+        ) {
+            let file = NamedTempFile::new().unwrap();
+            let start = Ipv4Addr::new(10, 0, 0, 2);
+            let end = Ipv4Addr::from(u32::from(start) + pool_size - 1);
+            let manager = IpManager::new(file.path(), start, end).unwrap();
+            let mut model: HashMap<String, String> = HashMap::new();
+
+            for op in ops {
+                match op {
+                    Op::Allocate(vm) | Op::Renew(vm) => {
+                        let vm_id = format!("vm-{}", vm);
+                        if matches!(op, Op::Renew(_)) && !model.contains_key(&vm_id) {
+                            continue;
+                        }
+                        match manager.allocate_ip(&vm_id) {
+                            Ok(ip) => {
+                                match model.get(&vm_id) {
+                                    Some(previous) => prop_assert_eq!(previous, &ip),
+                                    None => {
+                                        prop_assert!(!model.values().any(|other| other == &ip));
+                                        let addr: Ipv4Addr = ip.parse().unwrap();
+                                        prop_assert!(start <= addr && addr <= end);
+                                    }
+                                }
+                                model.insert(vm_id, ip);
+                            }
+                            Err(IpManagerError::PoolExhausted) => {
+                                prop_assert!(!model.contains_key(&vm_id));
+                                prop_assert_eq!(model.len(), pool_size as usize);
+                            }
+                            Err(e) => return Err(TestCaseError::fail(e.to_string())),
+                        }
+                    }
+                    Op::Release(vm) => {
+                        let vm_id = format!("vm-{}", vm);
+                        prop_assert_eq!(
+                            manager.release_ip(&vm_id).unwrap(),
+                            model.remove(&vm_id).is_some()
+                        );
+                    }
+                }
+
+                prop_assert!(model.len() <= pool_size as usize);
+                // Every operation is persisted before it returns.
+                let reloaded = IpManager::new(file.path(), start, end).unwrap();
+                prop_assert_eq!(&reloaded.read_state().unwrap().allocations, &model);
+            }
+        }
+
+        #[test]
+        fn prop_state_round_trips(
+            allocations in proptest::collection::hash_map("[a-z0-9-]{1,16}", any::<u32>(), 0..16),
+        ) {
+            let state = IpManagerState {
+                allocations: allocations
+                    .into_iter()
+                    .map(|(vm_id, ip)| (vm_id, Ipv4Addr::from(ip).to_string()))
+                    .collect(),
+                segments: HashMap::new(),
+            };
+            let file = NamedTempFile::new().unwrap();
+            let manager = IpManager::new(
+                file.path(),
+                Ipv4Addr::new(10, 0, 0, 2),
+                Ipv4Addr::new(10, 0, 0, 254),
+            )
+            .unwrap();
+
+            manager.write_state(&state).unwrap();
+            prop_assert_eq!(manager.read_state().unwrap().allocations, state.allocations);
+        }
+    }
 }
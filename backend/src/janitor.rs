@@ -0,0 +1,343 @@
+//! Periodic cleanup of what VMs and jobs leave behind on the host.
+//!
+//! VMs and jobs clean up after themselves, but a crash, a panic or a bug can
+//! leave TAP devices, IP leases, scratch directories or whole VMs behind, and
+//! the serial logs of VMs pile up until they are deleted. Every sweep lists
+//! them, and cleans up those a previous sweep found too: a VM still booting or
+//! being torn down is never taken for an orphan.
+
+use crate::ip_manager::IpManager;
+use crate::vm_lifecycle::{generate_tap_device_name, live_vm_ids, stop_live_vm};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+pub const DEFAULT_JANITOR_INTERVAL_SECS: u64 = 60;
+pub const DEFAULT_STALE_DIR_SECS: u64 = 3600;
+pub const DEFAULT_LOG_RETENTION_SECS: u64 = 7 * 24 * 3600;
+
+/// Prefix of the TAP devices of VMs, see [`generate_tap_device_name`].
+const TAP_PREFIX: &str = "tap-";
+
+/// Something left behind that nothing owns anymore.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Orphan {
+    /// A TAP device of no live VM.
+    Tap(String),
+    /// The IP lease of a VM that is gone.
+    Lease(String),
+    /// A VM still running once its job is over, not pooled.
+    Vm(String),
+    /// A scratch directory no job uses, untouched for a while.
+    Dir(PathBuf),
+    /// A serial log, or a rotation of one, of a VM gone for a while.
+    Log(PathBuf),
+}
+
+impl std::fmt::Display for Orphan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Orphan::Tap(name) => write!(f, "TAP device {}", name),
+            Orphan::Lease(vm_id) => write!(f, "IP lease of VM {}", vm_id),
+            Orphan::Vm(vm_id) => write!(f, "VM {}", vm_id),
+            Orphan::Dir(path) => write!(f, "directory {}", path.display()),
+            Orphan::Log(path) => write!(f, "log file {}", path.display()),
+        }
+    }
+}
+
+/// Counters of the janitor since the backend started, for `GET /janitor`.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct JanitorStats {
+    pub sweeps: u64,
+    pub taps_removed: u64,
+    pub leases_released: u64,
+    pub vms_stopped: u64,
+    pub dirs_removed: u64,
+    pub logs_removed: u64,
+    /// Orphans that could not be cleaned up, tried again at the next sweep.
+    pub failures: u64,
+}
+
+pub struct Janitor {
+    /// Orphans found by the last sweep.
+    suspects: Mutex<HashSet<Orphan>>,
+    stats: Mutex<JanitorStats>,
+}
+
+impl Default for Janitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Janitor {
+    pub fn new() -> Self {
+        Self {
+            suspects: Mutex::new(HashSet::new()),
+            stats: Mutex::new(JanitorStats::default()),
+        }
+    }
+
+    pub fn stats(&self) -> JanitorStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Cleans up the orphans in `found` that the previous sweep found as well,
+    /// and keeps the others for the next one.
+    pub async fn sweep(&self, found: HashSet<Orphan>, ip_manager: &Arc<Mutex<IpManager>>) {
+        for orphan in self.confirm(found) {
+            let result = clean(&orphan, ip_manager).await;
+            self.record(&orphan, result);
+        }
+        self.stats.lock().unwrap().sweeps += 1;
+    }
+
+    /// Of the orphans in `found`, the ones the previous sweep found too.
+    fn confirm(&self, found: HashSet<Orphan>) -> Vec<Orphan> {
+        let mut suspects = self.suspects.lock().unwrap();
+        let confirmed = found.intersection(&suspects).cloned().collect();
+        *suspects = found;
+        confirmed
+    }
+
+    fn record(&self, orphan: &Orphan, result: Result<(), String>) {
+        let mut stats = self.stats.lock().unwrap();
+        match result {
+            Ok(()) => {
+                info!("Janitor cleaned up orphan {}", orphan);
+                match orphan {
+                    Orphan::Tap(_) => stats.taps_removed += 1,
+                    Orphan::Lease(_) => stats.leases_released += 1,
+                    Orphan::Vm(_) => stats.vms_stopped += 1,
+                    Orphan::Dir(_) => stats.dirs_removed += 1,
+                    Orphan::Log(_) => stats.logs_removed += 1,
+                }
+            }
+            Err(e) => {
+                warn!("Janitor cannot clean up orphan {}: {}", orphan, e);
+                stats.failures += 1;
+            }
+        }
+    }
+}
+
+async fn clean(orphan: &Orphan, ip_manager: &Arc<Mutex<IpManager>>) -> Result<(), String> {
+    match orphan {
+        Orphan::Tap(name) => virt::network::delete_link(name)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Orphan::Lease(vm_id) => ip_manager
+            .lock()
+            .map_err(|e| format!("Mutex poisoned: {}", e))?
+            .release_ip(vm_id)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Orphan::Vm(vm_id) => {
+            if stop_live_vm(vm_id) {
+                Ok(())
+            } else {
+                Err("its VMM is not running".to_string())
+            }
+        }
+        Orphan::Dir(path) => tokio::fs::remove_dir_all(path)
+            .await
+            .map_err(|e| e.to_string()),
+        Orphan::Log(path) => tokio::fs::remove_file(path)
+            .await
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// TAP devices of no live VM, and IP leases of VMs that are gone.
+pub async fn find_network_orphans(ip_manager: &Arc<Mutex<IpManager>>) -> HashSet<Orphan> {
+    let live = live_vm_ids();
+    let mut orphans = HashSet::new();
+
+    match virt::network::links_with_prefix(TAP_PREFIX).await {
+        Ok(links) => {
+            let owned: HashSet<String> =
+                live.iter().map(|id| generate_tap_device_name(id)).collect();
+            orphans.extend(
+                links
+                    .into_iter()
+                    .filter(|name| is_vm_tap(name) && !owned.contains(name))
+                    .map(Orphan::Tap),
+            );
+        }
+        Err(e) => warn!("Janitor cannot list TAP devices: {}", e),
+    }
+
+    let allocations = ip_manager
+        .lock()
+        .map_err(|e| e.to_string())
+        .and_then(|manager| manager.allocations().map_err(|e| e.to_string()));
+    match allocations {
+        Ok(allocations) => orphans.extend(
+            allocations
+                .into_keys()
+                .filter(|vm_id| !live.contains(vm_id))
+                .map(Orphan::Lease),
+        ),
+        Err(e) => warn!("Janitor cannot read IP leases: {}", e),
+    }
+    orphans
+}
+
+/// Whether `name` is shaped like the TAP devices of VMs, and not another tool's.
+fn is_vm_tap(name: &str) -> bool {
+    name.strip_prefix(TAP_PREFIX).is_some_and(|hash| {
+        hash.len() == 11 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    })
+}
+
+/// Entries of `dir` left untouched for `max_age`, except the ones named after
+/// a job in `running`. A missing `dir` has none.
+pub async fn find_stale_dirs(
+    dir: &Path,
+    max_age: Duration,
+    running: &HashSet<String>,
+) -> HashSet<Orphan> {
+    let mut orphans = HashSet::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return orphans;
+    };
+    let now = SystemTime::now();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if running.contains(entry.file_name().to_string_lossy().as_ref()) {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        let stale = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .is_some_and(|age| age >= max_age);
+        if metadata.is_dir() && stale {
+            orphans.insert(Orphan::Dir(entry.path()));
+        }
+    }
+    orphans
+}
+
+/// Serial logs in `dir`, `{vm_id}.log` and its rotations, left untouched for
+/// `max_age` by a VM that is no longer live. A missing `dir` has none.
+pub async fn find_stale_logs(dir: &Path, max_age: Duration) -> HashSet<Orphan> {
+    let live = live_vm_ids();
+    let mut orphans = HashSet::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return orphans;
+    };
+    let now = SystemTime::now();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some((vm_id, _)) = name.split_once(".log") else {
+            continue;
+        };
+        if live.iter().any(|id| id == vm_id) {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        let stale = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .is_some_and(|age| age >= max_age);
+        if metadata.is_file() && stale {
+            orphans.insert(Orphan::Log(entry.path()));
+        }
+    }
+    orphans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orphans_are_cleaned_up_on_the_second_sweep() {
+        let janitor = Janitor::new();
+        let tap = Orphan::Tap("tap-0123456789a".to_string());
+        let lease = Orphan::Lease("job-1".to_string());
+
+        assert!(janitor.confirm(HashSet::from([tap.clone()])).is_empty());
+        // The TAP device is still there; the lease is new, so it waits a sweep.
+        assert_eq!(
+            janitor.confirm(HashSet::from([tap.clone(), lease.clone()])),
+            [tap]
+        );
+        // Gone in the meantime: its owner cleaned it up after all.
+        assert!(janitor.confirm(HashSet::new()).is_empty());
+        assert!(janitor.confirm(HashSet::from([lease])).is_empty());
+    }
+
+    #[test]
+    fn test_record_counts_each_kind() {
+        let janitor = Janitor::new();
+        janitor.record(&Orphan::Lease("job-1".to_string()), Ok(()));
+        janitor.record(&Orphan::Vm("job-2".to_string()), Err("gone".to_string()));
+        let stats = janitor.stats();
+        assert_eq!((stats.leases_released, stats.failures), (1, 1));
+        assert_eq!(stats.vms_stopped, 0);
+    }
+
+    #[test]
+    fn test_is_vm_tap() {
+        assert!(is_vm_tap(&generate_tap_device_name("job-1")));
+        assert!(!is_vm_tap("tap0"));
+        assert!(!is_vm_tap("tap-vpn-office"));
+    }
+
+    #[tokio::test]
+    async fn test_find_stale_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("job-1")).unwrap();
+        std::fs::create_dir(dir.path().join("job-2")).unwrap();
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+        let running = HashSet::from(["job-2".to_string()]);
+
+        assert!(
+            find_stale_dirs(dir.path(), Duration::from_secs(60), &running)
+                .await
+                .is_empty()
+        );
+        assert_eq!(
+            find_stale_dirs(dir.path(), Duration::ZERO, &running).await,
+            HashSet::from([Orphan::Dir(dir.path().join("job-1"))])
+        );
+        assert!(
+            find_stale_dirs(&dir.path().join("missing"), Duration::ZERO, &running)
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_stale_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("job-1.log"), b"").unwrap();
+        std::fs::write(dir.path().join("job-1.log.1.gz"), b"").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"").unwrap();
+
+        assert!(
+            find_stale_logs(dir.path(), Duration::from_secs(60))
+                .await
+                .is_empty()
+        );
+        assert_eq!(
+            find_stale_logs(dir.path(), Duration::ZERO).await,
+            HashSet::from([
+                Orphan::Log(dir.path().join("job-1.log")),
+                Orphan::Log(dir.path().join("job-1.log.1.gz")),
+            ])
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use cloude_types::{Isolation, JobStatus, TrafficStats, VmConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Why a job recovered as `pending` or `running` is finalized as `interrupted`.
+pub const INTERRUPTED_REASON: &str = "The backend restarted while the job was running";
+
+/// Errors that can occur while reading or writing the job journal.
+#[derive(Debug)]
+pub enum JournalError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl std::fmt::Display for JournalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JournalError::Io(e) => write!(f, "IO error: {}", e),
+            JournalError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+impl From<std::io::Error> for JournalError {
+    fn from(err: std::io::Error) -> Self {
+        JournalError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for JournalError {
+    fn from(err: serde_json::Error) -> Self {
+        JournalError::Json(err)
+    }
+}
+
+/// A job as last recorded, enough to answer `GET /status/{id}` after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub id: String,
+    pub status: JobStatus,
+    pub language: String,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub stdout: Option<String>,
+    #[serde(default)]
+    pub stderr: Option<String>,
+    #[serde(default)]
+    pub isolation: Option<Isolation>,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Caller the job ran for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Namespace of the job, `None` for the default one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Agent process running the job, with `EXECUTOR=process`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_pid: Option<u32>,
+    /// VM that ran the job, whose serial log outlives a restart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vm_id: Option<String>,
+    /// Traffic of the VM while it ran the job.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic: Option<TrafficStats>,
+    /// The code went over its memory and was killed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub oom_killed: bool,
+    /// Signal that killed the code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    /// The code dumped a core, kept in the blob store.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub core_dump: bool,
+    /// Digest of the code, to tell runs of the same code apart from the others.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code_digest: Option<String>,
+    /// Digest of the runtime image the job ran in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shape: Option<VmConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+/// Jobs kept in memory by the backend, persisted at every change of status so
+/// that a restart can tell which ones it interrupted.
+///
+/// Each change is appended as one JSON line, the last line of a job replacing
+/// the ones before it: recording a change costs the size of that job, not the
+/// size of the journal. [`JobJournal::retain`] compacts the journal down to
+/// one line per job.
+#[derive(Debug)]
+pub struct JobJournal {
+    file_path: PathBuf,
+    lock: Mutex<()>,
+}
+
+/// Jobs found in the journal at startup.
+#[derive(Debug, Default)]
+pub struct Recovery {
+    /// Every job, those that were `pending` or `running` now `interrupted`.
+    pub jobs: Vec<JournalEntry>,
+    /// The jobs that were `pending` or `running`, as they were recorded.
+    pub interrupted: Vec<JournalEntry>,
+}
+
+/// Jobs of the journal, and the number of lines they were read from: more
+/// lines than jobs and the journal is due for compaction.
+struct Log {
+    jobs: HashMap<String, JournalEntry>, // job id -> last recorded state
+    lines: usize,
+}
+
+impl JobJournal {
+    pub fn new<P: AsRef<Path>>(file_path: P) -> Result<Self, JournalError> {
+        let journal = Self {
+            file_path: file_path.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        };
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal.file_path)?;
+
+        Ok(journal)
+    }
+
+    /// Reads every line of the journal. A last line cut short, by a crash
+    /// mid-append, is left out: the change it recorded is lost, not the journal.
+    fn read_log(&self) -> Result<Log, JournalError> {
+        let mut file = match File::open(&self.file_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Log {
+                    jobs: HashMap::new(),
+                    lines: 0,
+                });
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        let mut log = Log {
+            jobs: HashMap::new(),
+            lines: 0,
+        };
+        let mut lines = contents.lines().filter(|l| !l.trim().is_empty()).peekable();
+        while let Some(line) = lines.next() {
+            // Counted even if cut short, for the next compaction to drop it.
+            log.lines += 1;
+            let entry: JournalEntry = match serde_json::from_str(line) {
+                Ok(entry) => entry,
+                Err(_) if lines.peek().is_none() && !contents.ends_with('\n') => break,
+                Err(e) => return Err(e.into()),
+            };
+            log.jobs.insert(entry.id.clone(), entry);
+        }
+        Ok(log)
+    }
+
+    /// Rewrites the journal with one line per job, to a temporary file renamed
+    /// over the previous one, so a crash mid-write leaves one or the other.
+    fn write_jobs(&self, jobs: &HashMap<String, JournalEntry>) -> Result<(), JournalError> {
+        let mut contents = String::new();
+        for entry in jobs.values() {
+            contents.push_str(&serde_json::to_string(entry)?);
+            contents.push('\n');
+        }
+        let tmp_path = self.file_path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &self.file_path)?;
+        Ok(())
+    }
+
+    /// Records the current state of a job, replacing the previous one. Blocks
+    /// until the record is on disk.
+    pub fn record(&self, entry: JournalEntry) -> Result<(), JournalError> {
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file_path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Forgets every job for which `keep` returns false, and compacts the
+    /// journal to the last state of the others.
+    pub fn retain(&self, keep: impl Fn(&str) -> bool) -> Result<(), JournalError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut log = self.read_log()?;
+        log.jobs.retain(|id, _| keep(id));
+        if log.jobs.len() != log.lines {
+            self.write_jobs(&log.jobs)?;
+        }
+        Ok(())
+    }
+
+    /// Loads the journal of the previous run. Jobs it left `pending` or `running`
+    /// cannot finish anymore: they are finalized as `interrupted`, and recorded so.
+    pub fn recover(&self) -> Result<Recovery, JournalError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut log = self.read_log()?;
+        let mut recovery = Recovery::default();
+        for entry in log.jobs.values_mut() {
+            if !entry.status.is_terminal() {
+                recovery.interrupted.push(entry.clone());
+                entry.status = JobStatus::Interrupted;
+                entry.stderr = Some(INTERRUPTED_REASON.to_string());
+                entry.agent_pid = None;
+            }
+        }
+        if !recovery.interrupted.is_empty() || log.jobs.len() != log.lines {
+            self.write_jobs(&log.jobs)?;
+        }
+        recovery.jobs = log.jobs.into_values().collect();
+        Ok(recovery)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn entry(id: &str, status: JobStatus) -> JournalEntry {
+        JournalEntry {
+            id: id.to_string(),
+            status,
+            language: "python".to_string(),
+            exit_code: None,
+            stdout: None,
+            stderr: None,
+            isolation: Some(Isolation::Vm),
+            created_at: 1_700_000_000,
+            tenant: None,
+            namespace: None,
+            agent_pid: None,
+            vm_id: None,
+            traffic: None,
+            oom_killed: false,
+            signal: None,
+            core_dump: false,
+            code_digest: None,
+            image_digest: None,
+            shape: None,
+            duration_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_recover_interrupts_unfinished_jobs() {
+        let file = NamedTempFile::new().unwrap();
+        {
+            let journal = JobJournal::new(file.path()).unwrap();
+            journal.record(entry("job-1", JobStatus::Running)).unwrap();
+            journal.record(entry("job-2", JobStatus::Pending)).unwrap();
+            journal
+                .record(JournalEntry {
+                    exit_code: Some(0),
+                    ..entry("job-3", JobStatus::Done)
+                })
+                .unwrap();
+        } // the backend stops
+
+        let journal = JobJournal::new(file.path()).unwrap();
+        let recovery = journal.recover().unwrap();
+        let mut interrupted: Vec<_> = recovery.interrupted.iter().map(|e| &e.id).collect();
+        interrupted.sort();
+        assert_eq!(interrupted, ["job-1", "job-2"]);
+        assert_eq!(recovery.jobs.len(), 3);
+        for job in &recovery.jobs {
+            match job.id.as_str() {
+                "job-3" => assert_eq!(job.status, JobStatus::Done),
+                _ => {
+                    assert_eq!(job.status, JobStatus::Interrupted);
+                    assert_eq!(job.stderr.as_deref(), Some(INTERRUPTED_REASON));
+                }
+            }
+        }
+
+        // Interrupted jobs are recorded as such: a second restart finds nothing to interrupt.
+        assert!(journal.recover().unwrap().interrupted.is_empty());
+    }
+
+    #[test]
+    fn test_retain() {
+        let file = NamedTempFile::new().unwrap();
+        let journal = JobJournal::new(file.path()).unwrap();
+        journal.record(entry("job-1", JobStatus::Done)).unwrap();
+        journal.record(entry("job-2", JobStatus::Running)).unwrap();
+        journal.retain(|id| id == "job-2").unwrap();
+
+        let recovery = journal.recover().unwrap();
+        assert_eq!(recovery.jobs.len(), 1);
+        assert_eq!(recovery.jobs[0].id, "job-2");
+    }
+
+    #[test]
+    fn test_latest_record_wins_and_retain_compacts() {
+        let file = NamedTempFile::new().unwrap();
+        let journal = JobJournal::new(file.path()).unwrap();
+        journal.record(entry("job-1", JobStatus::Running)).unwrap();
+        journal.record(entry("job-1", JobStatus::Done)).unwrap();
+        journal.record(entry("job-2", JobStatus::Done)).unwrap();
+        journal.retain(|_| true).unwrap();
+
+        let contents = std::fs::read_to_string(file.path()).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        let recovery = journal.recover().unwrap();
+        assert!(recovery.interrupted.is_empty());
+        assert!(recovery.jobs.iter().all(|j| j.status == JobStatus::Done));
+    }
+
+    #[test]
+    fn test_recover_skips_a_torn_last_line() {
+        let file = NamedTempFile::new().unwrap();
+        let journal = JobJournal::new(file.path()).unwrap();
+        journal.record(entry("job-1", JobStatus::Done)).unwrap();
+        let mut torn = OpenOptions::new().append(true).open(file.path()).unwrap();
+        torn.write_all(br#"{"id":"job-2","sta"#).unwrap();
+
+        let recovery = journal.recover().unwrap();
+        assert_eq!(recovery.jobs.len(), 1);
+        assert_eq!(recovery.jobs[0].id, "job-1");
+
+        // Recovery compacted the torn line away: the next record is not glued to it.
+        journal.record(entry("job-3", JobStatus::Done)).unwrap();
+        assert_eq!(journal.recover().unwrap().jobs.len(), 2);
+    }
+}
@@ -0,0 +1,222 @@
+//! Logs of a job: the stdout and stderr of its code, and the serial console of its VM.
+//!
+//! Lines are kept until the job is evicted, and broadcast as they arrive so that
+//! clients can follow a running job. Secrets are redacted from each line first.
+
+use crate::redaction::Redactor;
+use cloude_types::{LogLine, LogSource};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Lines kept per job; the oldest ones are dropped beyond that.
+pub const MAX_LOG_LINES: usize = 10_000;
+
+/// Lines a slow follower can fall behind before it misses some.
+const FOLLOW_BACKLOG: usize = 1024;
+
+pub struct JobLog {
+    state: Mutex<LogState>,
+    redactor: Redactor,
+}
+
+struct LogState {
+    lines: VecDeque<LogLine>,
+    /// Console output after the last newline.
+    partial_kernel: Vec<u8>,
+    /// `None` once the job finished, which ends every follower.
+    updates: Option<broadcast::Sender<LogLine>>,
+}
+
+impl Default for JobLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JobLog {
+    pub fn new() -> Self {
+        Self::with_redactor(Redactor::default())
+    }
+
+    /// Logs whose lines go through `redactor` before they are kept or sent.
+    pub fn with_redactor(redactor: Redactor) -> Self {
+        let (updates, _) = broadcast::channel(FOLLOW_BACKLOG);
+        Self {
+            state: Mutex::new(LogState {
+                lines: VecDeque::new(),
+                partial_kernel: Vec::new(),
+                updates: Some(updates),
+            }),
+            redactor,
+        }
+    }
+
+    pub fn redactor(&self) -> &Redactor {
+        &self.redactor
+    }
+
+    /// Appends every line of `text`.
+    pub fn push(&self, source: LogSource, text: &str) {
+        let mut state = self.lock();
+        let timestamp_ms = now_ms();
+        for line in text.lines() {
+            state.append(LogLine {
+                timestamp_ms,
+                source,
+                line: self.redactor.redact(line).into_owned(),
+            });
+        }
+    }
+
+    /// Appends raw console output, which may end in the middle of a line.
+    pub fn push_kernel_output(&self, data: &[u8]) {
+        let mut state = self.lock();
+        state.partial_kernel.extend_from_slice(data);
+        let timestamp_ms = now_ms();
+        while let Some(end) = state.partial_kernel.iter().position(|&b| b == b'\n') {
+            let raw: Vec<u8> = state.partial_kernel.drain(..=end).collect();
+            state.append(kernel_line(&self.redactor, timestamp_ms, &raw[..end]));
+        }
+    }
+
+    /// Every line so far.
+    pub fn lines(&self) -> Vec<LogLine> {
+        self.lock().lines.iter().cloned().collect()
+    }
+
+    /// Every line so far, and a receiver for the next ones while the job runs.
+    pub fn follow(&self) -> (Vec<LogLine>, Option<broadcast::Receiver<LogLine>>) {
+        let state = self.lock();
+        let lines = state.lines.iter().cloned().collect();
+        (
+            lines,
+            state.updates.as_ref().map(|updates| updates.subscribe()),
+        )
+    }
+
+    /// Marks the logs complete: pending console output is flushed and followers stop.
+    pub fn finish(&self) {
+        let mut state = self.lock();
+        if !state.partial_kernel.is_empty() {
+            let raw = std::mem::take(&mut state.partial_kernel);
+            state.append(kernel_line(&self.redactor, now_ms(), &raw));
+        }
+        state.updates = None;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LogState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl LogState {
+    fn append(&mut self, line: LogLine) {
+        if let Some(updates) = &self.updates {
+            // No receiver just means nobody is following.
+            let _ = updates.send(line.clone());
+        }
+        if self.lines.len() == MAX_LOG_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+}
+
+/// The serial console ends lines with `\r\n`.
+fn kernel_line(redactor: &Redactor, timestamp_ms: u64, raw: &[u8]) -> LogLine {
+    let raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+    LogLine {
+        timestamp_ms,
+        source: LogSource::Kernel,
+        line: redactor.redact(&String::from_utf8_lossy(raw)).into_owned(),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(lines: &[LogLine]) -> Vec<(LogSource, &str)> {
+        lines
+            .iter()
+            .map(|line| (line.source, line.line.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_kernel_output_is_split_into_lines() {
+        let log = JobLog::new();
+        log.push_kernel_output(b"Linux version 6.1\r\n[initramfs] boo");
+        log.push_kernel_output(b"ting...\r\n[initramfs] starting");
+        assert_eq!(
+            text(&log.lines()),
+            vec![
+                (LogSource::Kernel, "Linux version 6.1"),
+                (LogSource::Kernel, "[initramfs] booting..."),
+            ]
+        );
+
+        log.finish();
+        assert_eq!(log.lines().last().unwrap().line, "[initramfs] starting");
+    }
+
+    #[test]
+    fn test_followers_get_backlog_then_new_lines() {
+        let log = JobLog::new();
+        log.push(LogSource::Stdout, "hello\n");
+
+        let (backlog, updates) = log.follow();
+        let mut updates = updates.unwrap();
+        assert_eq!(text(&backlog), vec![(LogSource::Stdout, "hello")]);
+
+        log.push(LogSource::Stderr, "oops\nagain");
+        assert_eq!(updates.try_recv().unwrap().line, "oops");
+        assert_eq!(updates.try_recv().unwrap().line, "again");
+
+        log.finish();
+        assert!(matches!(
+            updates.try_recv(),
+            Err(broadcast::error::TryRecvError::Closed)
+        ));
+        assert!(log.follow().1.is_none());
+    }
+
+    #[test]
+    fn test_lines_are_redacted() {
+        let log = JobLog::with_redactor(Redactor::default().with_secrets(["s3cr3t".to_string()]));
+        let (_, updates) = log.follow();
+        let mut updates = updates.unwrap();
+        log.push(LogSource::Stdout, "password=s3cr3t");
+        log.push_kernel_output(b"export TOKEN=s3cr3t\r\n");
+
+        assert_eq!(updates.try_recv().unwrap().line, "password=[REDACTED]");
+        assert_eq!(
+            text(&log.lines()),
+            vec![
+                (LogSource::Stdout, "password=[REDACTED]"),
+                (LogSource::Kernel, "export TOKEN=[REDACTED]"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oldest_lines_are_dropped() {
+        let log = JobLog::new();
+        for i in 0..=MAX_LOG_LINES {
+            log.push(LogSource::Stdout, &i.to_string());
+        }
+
+        let lines = log.lines();
+        assert_eq!(lines.len(), MAX_LOG_LINES);
+        assert_eq!(lines[0].line, "1");
+    }
+}
@@ -0,0 +1,165 @@
+//! Guest kernels, tagged with their CPU architecture.
+//!
+//! KVM only runs guests of the architecture of the host, so a VM boots the
+//! kernel of that architecture. `VM_KERNELS` lists one kernel per architecture,
+//! like `x86_64=./vmlinux,aarch64=./Image`, so that hosts of either can share
+//! a configuration; `VM_KERNEL_PATH` is the kernel of the host otherwise.
+
+use cloude_types::Arch;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Kernel of the host architecture when neither variable sets one.
+pub const DEFAULT_KERNEL_PATH: &str = "./vmlinux";
+
+/// Errors that can occur while reading the kernels of the backend.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KernelError {
+    /// An entry of `VM_KERNELS` is not `arch=path`.
+    InvalidEntry(String),
+    UnknownArch(String),
+    /// No kernel is tagged with the architecture VMs need.
+    Missing {
+        arch: Arch,
+        available: Vec<Arch>,
+    },
+}
+
+impl std::fmt::Display for KernelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KernelError::InvalidEntry(entry) => {
+                write!(f, "Invalid kernel entry '{}', expected arch=path", entry)
+            }
+            KernelError::UnknownArch(arch) => {
+                write!(
+                    f,
+                    "Unknown architecture '{}', expected x86_64 or aarch64",
+                    arch
+                )
+            }
+            KernelError::Missing { arch, available } => {
+                let available: Vec<String> = available.iter().map(Arch::to_string).collect();
+                write!(
+                    f,
+                    "No kernel for {}, kernels are only set for: {}",
+                    arch,
+                    available.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for KernelError {}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Kernels {
+    paths: BTreeMap<Arch, PathBuf>,
+}
+
+impl Kernels {
+    /// Kernels of a comma-separated list of `arch=path`, the format of `VM_KERNELS`.
+    pub fn parse(spec: &str) -> Result<Self, KernelError> {
+        let mut paths = BTreeMap::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (arch, path) = entry
+                .split_once('=')
+                .filter(|(_, path)| !path.trim().is_empty())
+                .ok_or_else(|| KernelError::InvalidEntry(entry.to_string()))?;
+            let arch = Arch::from_name(arch.trim())
+                .ok_or_else(|| KernelError::UnknownArch(arch.trim().to_string()))?;
+            paths.insert(arch, PathBuf::from(path.trim()));
+        }
+        Ok(Self { paths })
+    }
+
+    /// Kernels of `VM_KERNELS`, with `VM_KERNEL_PATH` as the kernel of `host`
+    /// unless `VM_KERNELS` already tags one with it. Without either, the kernel of
+    /// `host` is [`DEFAULT_KERNEL_PATH`].
+    pub fn from_env(
+        kernels: Option<&str>,
+        kernel_path: Option<&str>,
+        host: Arch,
+    ) -> Result<Self, KernelError> {
+        let mut parsed = match kernels {
+            Some(spec) => Self::parse(spec)?,
+            None => Self::default(),
+        };
+        let fallback = match (kernels, kernel_path) {
+            (_, Some(path)) => Some(path),
+            (None, None) => Some(DEFAULT_KERNEL_PATH),
+            (Some(_), None) => None,
+        };
+        if let Some(path) = fallback {
+            parsed
+                .paths
+                .entry(host)
+                .or_insert_with(|| PathBuf::from(path));
+        }
+        Ok(parsed)
+    }
+
+    /// Kernel VMs of `arch` boot.
+    pub fn get(&self, arch: Arch) -> Result<&Path, KernelError> {
+        self.paths
+            .get(&arch)
+            .map(PathBuf::as_path)
+            .ok_or_else(|| KernelError::Missing {
+                arch,
+                available: self.paths.keys().copied().collect(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let kernels = Kernels::parse("x86_64=/boot/vmlinux, aarch64 = /boot/Image,").unwrap();
+        assert_eq!(kernels.get(Arch::X86_64), Ok(Path::new("/boot/vmlinux")));
+        assert_eq!(kernels.get(Arch::Aarch64), Ok(Path::new("/boot/Image")));
+
+        assert_eq!(
+            Kernels::parse("/boot/vmlinux"),
+            Err(KernelError::InvalidEntry("/boot/vmlinux".to_string()))
+        );
+        assert_eq!(
+            Kernels::parse("riscv64=/boot/Image"),
+            Err(KernelError::UnknownArch("riscv64".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_from_env() {
+        let default = Kernels::from_env(None, None, Arch::X86_64).unwrap();
+        assert_eq!(
+            default.get(Arch::X86_64),
+            Ok(Path::new(DEFAULT_KERNEL_PATH))
+        );
+
+        let tagged = Kernels::from_env(
+            Some("x86_64=/k/vmlinux"),
+            Some("/old/vmlinux"),
+            Arch::X86_64,
+        )
+        .unwrap();
+        assert_eq!(tagged.get(Arch::X86_64), Ok(Path::new("/k/vmlinux")));
+
+        // A list without the host kernel does not fall back to the default one.
+        let other = Kernels::from_env(Some("aarch64=/k/Image"), None, Arch::X86_64).unwrap();
+        assert_eq!(
+            other.get(Arch::X86_64),
+            Err(KernelError::Missing {
+                arch: Arch::X86_64,
+                available: vec![Arch::Aarch64],
+            })
+        );
+        assert_eq!(
+            other.get(Arch::X86_64).unwrap_err().to_string(),
+            "No kernel for x86_64, kernels are only set for: aarch64"
+        );
+    }
+}
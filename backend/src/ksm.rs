@@ -0,0 +1,174 @@
+//! Kernel samepage merging (KSM) of the memory of VMs.
+//!
+//! Pooled VMs of a runtime boot the same kernel and initramfs, so much of their
+//! memory holds identical pages. With `[ksm] enabled`, VMs advise the kernel
+//! that their memory is mergeable and the backend starts `ksmd`, which scans
+//! that memory and shares identical pages copy-on-write between VMs. The
+//! scanner is tuned from `cloude.toml` through `/sys/kernel/mm/ksm`.
+
+use crate::validation::ValidationErrors;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const KSM_DIR: &str = "/sys/kernel/mm/ksm";
+
+/// `[ksm]` of `cloude.toml`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct KsmSettings {
+    /// Whether VMs booted from now on have their memory merged.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Pages `ksmd` scans before it sleeps; the kernel's setting if unset.
+    pub pages_to_scan: Option<u32>,
+    /// Milliseconds `ksmd` sleeps between scans; the kernel's setting if unset.
+    pub sleep_millisecs: Option<u32>,
+}
+
+impl KsmSettings {
+    pub fn validate(&self, errors: &mut ValidationErrors) {
+        if self.pages_to_scan == Some(0) {
+            errors.push("ksm.pages_to_scan", "must be greater than 0");
+        }
+        if !self.enabled && (self.pages_to_scan.is_some() || self.sleep_millisecs.is_some()) {
+            errors.push("ksm", "tuning only applies with enabled = true");
+        }
+    }
+}
+
+/// Counters of `ksmd`, in pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KsmStats {
+    /// Shared pages in use.
+    pub pages_shared: u64,
+    /// Pages sharing them, that is how many pages merging saves.
+    pub pages_sharing: u64,
+    /// Mergeable pages that are unique so far.
+    pub pages_unshared: u64,
+    /// Mergeable pages changing too fast to be merged.
+    pub pages_volatile: u64,
+    /// Scans of all mergeable memory done.
+    pub full_scans: u64,
+}
+
+pub struct Ksm {
+    /// `/sys/kernel/mm/ksm`, or a directory laid out like it.
+    dir: PathBuf,
+}
+
+impl Ksm {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Starts `ksmd` with the tuning of `settings` when KSM is enabled.
+    /// Disabling it leaves `ksmd` alone: other processes of the host may use
+    /// it, and VMs already merged keep their pages shared until they stop.
+    pub fn apply(&self, settings: &KsmSettings) -> io::Result<()> {
+        if !settings.enabled {
+            return Ok(());
+        }
+        if let Some(pages) = settings.pages_to_scan {
+            self.write("pages_to_scan", pages)?;
+        }
+        if let Some(millis) = settings.sleep_millisecs {
+            self.write("sleep_millisecs", millis)?;
+        }
+        self.write("run", 1)
+    }
+
+    /// Current counters, an error on kernels without KSM.
+    pub fn stats(&self) -> io::Result<KsmStats> {
+        Ok(KsmStats {
+            pages_shared: self.read("pages_shared")?,
+            pages_sharing: self.read("pages_sharing")?,
+            pages_unshared: self.read("pages_unshared")?,
+            pages_volatile: self.read("pages_volatile")?,
+            full_scans: self.read("full_scans")?,
+        })
+    }
+
+    fn write(&self, name: &str, value: u32) -> io::Result<()> {
+        fs::write(self.dir.join(name), value.to_string())
+    }
+
+    fn read(&self, name: &str) -> io::Result<u64> {
+        fs::read_to_string(self.dir.join(name))?
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", name, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_apply() {
+        let dir = TempDir::new().unwrap();
+        let ksm = Ksm::new(dir.path());
+
+        ksm.apply(&KsmSettings::default()).unwrap();
+        assert!(!dir.path().join("run").exists());
+
+        ksm.apply(&KsmSettings {
+            enabled: true,
+            pages_to_scan: Some(1000),
+            sleep_millisecs: None,
+        })
+        .unwrap();
+        let read = |name| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("run"), "1");
+        assert_eq!(read("pages_to_scan"), "1000");
+        assert!(!dir.path().join("sleep_millisecs").exists());
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = TempDir::new().unwrap();
+        let ksm = Ksm::new(dir.path());
+        assert!(ksm.stats().is_err());
+
+        for (name, value) in [
+            ("pages_shared", "12\n"),
+            ("pages_sharing", "340\n"),
+            ("pages_unshared", "56\n"),
+            ("pages_volatile", "7\n"),
+            ("full_scans", "3\n"),
+        ] {
+            fs::write(dir.path().join(name), value).unwrap();
+        }
+        assert_eq!(
+            ksm.stats().unwrap(),
+            KsmStats {
+                pages_shared: 12,
+                pages_sharing: 340,
+                pages_unshared: 56,
+                pages_volatile: 7,
+                full_scans: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut errors = ValidationErrors::default();
+        KsmSettings {
+            enabled: false,
+            pages_to_scan: Some(100),
+            sleep_millisecs: None,
+        }
+        .validate(&mut errors);
+        KsmSettings {
+            enabled: true,
+            pages_to_scan: Some(0),
+            sleep_millisecs: Some(20),
+        }
+        .validate(&mut errors);
+        assert_eq!(errors.into_result(()).unwrap_err().errors.len(), 2);
+    }
+}
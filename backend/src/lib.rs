@@ -1,3 +1,52 @@
+pub mod access;
+pub mod api_versions;
+pub mod apply;
+pub mod artifact_store;
+pub mod audit_log;
+pub mod blob_store;
+pub mod bundles;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod compile_cache;
+pub mod config;
+pub mod console;
+pub mod core_dumps;
+pub mod dashboard;
+pub mod egress_proxy;
+pub mod events;
+pub mod execution_diff;
+pub mod function_registry;
+pub mod heartbeats;
+pub mod host_builds;
+pub mod host_pressure;
+pub mod identity;
+pub mod image_digests;
 pub mod initramfs_manager;
 pub mod ip_manager;
+pub mod janitor;
+pub mod job_journal;
+pub mod job_logs;
+pub mod kernels;
+pub mod ksm;
+pub mod listener;
+pub mod listing;
+pub mod metrics;
+pub mod namespaces;
+pub mod pipelines;
+pub mod prewarm;
+#[cfg(feature = "process-executor")]
+pub mod process_executor;
+pub mod profiles;
+pub mod publishers;
+pub mod readiness;
+pub mod redaction;
+pub mod result_cache;
+pub mod runtime_upgrades;
+pub mod scratch_initramfs;
+pub mod template_manager;
+pub mod triggers;
+pub mod validation;
 pub mod vm_lifecycle;
+pub mod vm_pool;
+pub mod vm_sharing;
+pub mod webhooks;
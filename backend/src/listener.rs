@@ -0,0 +1,116 @@
+//! Where the API is served: a TCP address, a Unix domain socket, or both.
+//!
+//! A Unix socket lets a local reverse proxy, terminating TLS, reach the API
+//! without any port exposed to the network. Who can connect is decided by the
+//! permissions of the socket file.
+
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::{TcpListener, UnixListener};
+
+/// Permissions of the socket file: the owner and its group can connect.
+pub const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+/// Client of the API, as its connection tells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// Connected to the Unix socket, which has no address to tell.
+    Unix,
+}
+
+impl Peer {
+    /// IP address of the client, `local` over the Unix socket.
+    pub fn source_ip(&self) -> String {
+        match self {
+            Peer::Tcp(addr) => addr.ip().to_string(),
+            Peer::Unix => "local".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix => f.write_str("the Unix socket"),
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Peer::Tcp(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for Peer {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        Peer::Unix
+    }
+}
+
+/// Listens on a Unix socket at `path`, with permissions `mode`. A socket left
+/// there by an earlier run is replaced; any other file is an error.
+pub fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run/backend.sock");
+
+        let listener = bind_unix(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(listener);
+
+        // The socket of an earlier run is in the way, and replaced.
+        bind_unix(&path, DEFAULT_SOCKET_MODE).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            DEFAULT_SOCKET_MODE
+        );
+
+        let file = dir.path().join("backend.toml");
+        fs::write(&file, "").unwrap();
+        let err = bind_unix(&file, DEFAULT_SOCKET_MODE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(file.exists());
+    }
+
+    #[test]
+    fn test_peer() {
+        let tcp = Peer::Tcp("127.0.0.1:4000".parse().unwrap());
+        assert_eq!(tcp.source_ip(), "127.0.0.1");
+        assert_eq!(tcp.to_string(), "127.0.0.1:4000");
+        assert_eq!(Peer::Unix.source_ip(), "local");
+    }
+}
@@ -0,0 +1,386 @@
+//! Pagination, filtering and sorting of the list endpoints.
+//!
+//! `GET /executions`, `GET /vms` and `GET /functions` take the same query
+//! parameters, parsed once by the [`ListQuery`] extractor. Each endpoint turns
+//! what it lists into [`Entry`]s, with the fields they can be filtered and
+//! sorted by, and [`page`] picks the page asked for.
+//!
+//! A cursor is the sort key and id of the last entry of a page: the next page
+//! starts after it, even when entries came or went in between.
+
+use crate::validation::ValidationErrors;
+use axum::Json;
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use cloude_types::ErrorResponse;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+/// Header of a page with the cursor of the next one, absent on the last page.
+pub const NEXT_CURSOR_HEADER: &str = "x-cloude-next-cursor";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    CreatedAt,
+    Id,
+    Status,
+    Runtime,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// A filter of [`ListQuery`], for the endpoints to tell which ones they support.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Status,
+    Runtime,
+    Tenant,
+    /// `since` and `until`.
+    CreatedAt,
+}
+
+/// Query parameters of the list endpoints.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ListQuery {
+    /// Entries per page, [`DEFAULT_LIMIT`] by default.
+    pub limit: Option<usize>,
+    /// Where the page starts, from the previous page.
+    pub cursor: Option<String>,
+    pub sort: Option<SortField>,
+    pub order: Option<Order>,
+    /// Statuses to keep, separated by commas.
+    pub status: Option<String>,
+    pub runtime: Option<String>,
+    pub tenant: Option<String>,
+    /// Oldest creation time kept, in seconds since the Unix epoch.
+    pub since: Option<u64>,
+    /// Creation time from which entries are left out, in seconds since the Unix epoch.
+    pub until: Option<u64>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ListQuery {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<ListQuery>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| {
+                let mut errors = ValidationErrors::default();
+                errors.push("query", e.body_text());
+                rejection(errors)
+            })?;
+        Ok(query)
+    }
+}
+
+/// What the list endpoint supports, and how it sorts by default.
+pub struct Listing {
+    pub filters: &'static [Filter],
+    pub sorts: &'static [SortField],
+    pub default_sort: SortField,
+    pub default_order: Order,
+}
+
+/// Something listed, with the fields it is filtered and sorted by; `None` for
+/// those it does not have.
+pub struct Entry<T> {
+    pub item: T,
+    pub id: String,
+    pub status: Option<String>,
+    pub runtime: Option<String>,
+    pub tenant: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub created_at: Option<u64>,
+}
+
+impl<T> Entry<T> {
+    fn key(&self, field: SortField) -> SortKey {
+        let text = |value: &Option<String>| SortKey::Text(value.clone().unwrap_or_default());
+        match field {
+            SortField::CreatedAt => SortKey::Number(self.created_at.unwrap_or(0)),
+            SortField::Id => SortKey::Text(self.id.clone()),
+            SortField::Status => text(&self.status),
+            SortField::Runtime => text(&self.runtime),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SortKey {
+    Number(u64),
+    Text(String),
+}
+
+/// Position after the last entry of a page.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Cursor {
+    sort: SortField,
+    order: Order,
+    key: SortKey,
+    id: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()
+    }
+}
+
+/// A page of a list, answered as a JSON array with the cursor of the next page
+/// in [`NEXT_CURSOR_HEADER`].
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+impl<T: Serialize> IntoResponse for Page<T> {
+    fn into_response(self) -> Response {
+        let mut response = (StatusCode::OK, Json(self.items)).into_response();
+        if let Some(cursor) = self
+            .next_cursor
+            .and_then(|c| HeaderValue::from_str(&c).ok())
+        {
+            response.headers_mut().insert(NEXT_CURSOR_HEADER, cursor);
+        }
+        response
+    }
+}
+
+/// The page of `entries` `query` asks for, or what is wrong with it for `listing`.
+pub fn page<T>(
+    entries: Vec<Entry<T>>,
+    query: &ListQuery,
+    listing: &Listing,
+) -> Result<Page<T>, ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        errors.push("limit", format!("must be between 1 and {MAX_LIMIT}"));
+    }
+    let sort = query.sort.unwrap_or(listing.default_sort);
+    if !listing.sorts.contains(&sort) {
+        errors.push("sort", "this list cannot be sorted by it");
+    }
+    let order = query.order.unwrap_or(listing.default_order);
+    let requested = [
+        (Filter::Status, "status", query.status.is_some()),
+        (Filter::Runtime, "runtime", query.runtime.is_some()),
+        (Filter::Tenant, "tenant", query.tenant.is_some()),
+        (Filter::CreatedAt, "since", query.since.is_some()),
+        (Filter::CreatedAt, "until", query.until.is_some()),
+    ];
+    for (filter, field, given) in requested {
+        if given && !listing.filters.contains(&filter) {
+            errors.push(field, "this list cannot be filtered by it");
+        }
+    }
+    let cursor = match query.cursor.as_deref().map(Cursor::decode) {
+        None => None,
+        Some(None) => {
+            errors.push("cursor", "is not a cursor of this backend");
+            None
+        }
+        Some(Some(cursor)) if cursor.sort != sort || cursor.order != order => {
+            errors.push("cursor", "comes from a list sorted another way");
+            None
+        }
+        Some(Some(cursor)) => Some(cursor),
+    };
+    errors.into_result(())?;
+
+    let statuses: Option<Vec<&str>> = query.status.as_deref().map(|s| s.split(',').collect());
+    let mut entries: Vec<(SortKey, Entry<T>)> = entries
+        .into_iter()
+        .filter(|e| {
+            statuses
+                .as_ref()
+                .is_none_or(|statuses| e.status.as_deref().is_some_and(|s| statuses.contains(&s)))
+                && query
+                    .runtime
+                    .as_ref()
+                    .is_none_or(|runtime| e.runtime.as_ref() == Some(runtime))
+                && query
+                    .tenant
+                    .as_ref()
+                    .is_none_or(|tenant| e.tenant.as_ref() == Some(tenant))
+                && query
+                    .since
+                    .is_none_or(|since| e.created_at.is_some_and(|at| at >= since))
+                && query
+                    .until
+                    .is_none_or(|until| e.created_at.is_some_and(|at| at < until))
+        })
+        .map(|e| (e.key(sort), e))
+        .collect();
+    let ordering = |a: &SortKey, a_id: &str, b: &SortKey, b_id: &str| {
+        let ordering = a.cmp(b).then_with(|| a_id.cmp(b_id));
+        match order {
+            Order::Asc => ordering,
+            Order::Desc => ordering.reverse(),
+        }
+    };
+    entries.sort_by(|(a, ea), (b, eb)| ordering(a, &ea.id, b, &eb.id));
+    if let Some(cursor) = &cursor {
+        entries
+            .retain(|(key, e)| ordering(key, &e.id, &cursor.key, &cursor.id) == Ordering::Greater);
+    }
+
+    let more = entries.len() > limit;
+    entries.truncate(limit);
+    let next_cursor = entries.last().filter(|_| more).map(|(key, e)| {
+        Cursor {
+            sort,
+            order,
+            key: key.clone(),
+            id: e.id.clone(),
+        }
+        .encode()
+    });
+    Ok(Page {
+        items: entries.into_iter().map(|(_, e)| e.item).collect(),
+        next_cursor,
+    })
+}
+
+/// `422` answer to a list query with `errors`.
+fn rejection(errors: ValidationErrors) -> Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(ErrorResponse {
+            error: errors.to_string(),
+            details: errors.errors,
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LISTING: Listing = Listing {
+        filters: &[Filter::Status, Filter::Runtime, Filter::CreatedAt],
+        sorts: &[SortField::CreatedAt, SortField::Id],
+        default_sort: SortField::CreatedAt,
+        default_order: Order::Desc,
+    };
+
+    fn entries() -> Vec<Entry<&'static str>> {
+        [
+            ("a", "done", "python", 10),
+            ("b", "error", "python", 20),
+            ("c", "done", "node", 30),
+            ("d", "running", "python", 30),
+            ("e", "done", "python", 50),
+        ]
+        .into_iter()
+        .map(|(id, status, runtime, created_at)| Entry {
+            item: id,
+            id: id.to_string(),
+            status: Some(status.to_string()),
+            runtime: Some(runtime.to_string()),
+            tenant: None,
+            created_at: Some(created_at),
+        })
+        .collect()
+    }
+
+    fn query(params: &str) -> ListQuery {
+        let uri: axum::http::Uri = format!("/?{params}").parse().unwrap();
+        Query::try_from_uri(&uri).unwrap().0
+    }
+
+    #[test]
+    fn test_pages_follow_each_other() {
+        let first = page(entries(), &query("limit=2"), &LISTING).unwrap();
+        // The newest first, ties broken by id.
+        assert_eq!(first.items, vec!["e", "d"]);
+        let cursor = first.next_cursor.unwrap();
+
+        let second = page(
+            entries(),
+            &query(&format!("limit=2&cursor={cursor}")),
+            &LISTING,
+        )
+        .unwrap();
+        assert_eq!(second.items, vec!["c", "b"]);
+
+        // An entry that came in before the cursor does not shift the pages.
+        let mut grown = entries();
+        grown.push(Entry {
+            item: "f",
+            id: "f".to_string(),
+            status: None,
+            runtime: None,
+            tenant: None,
+            created_at: Some(60),
+        });
+        let again = page(grown, &query(&format!("limit=2&cursor={cursor}")), &LISTING).unwrap();
+        assert_eq!(again.items, vec!["c", "b"]);
+
+        let last = page(
+            entries(),
+            &query(&format!("limit=2&cursor={}", second.next_cursor.unwrap())),
+            &LISTING,
+        )
+        .unwrap();
+        assert_eq!(last.items, vec!["a"]);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn test_filters_and_sort() {
+        let page = page(
+            entries(),
+            &query("status=done,error&runtime=python&since=10&until=50&sort=id&order=asc"),
+            &LISTING,
+        )
+        .unwrap();
+        assert_eq!(page.items, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_invalid_queries() {
+        let errors = page(
+            entries(),
+            &query("limit=0&sort=runtime&tenant=alice&cursor=nope"),
+            &LISTING,
+        )
+        .unwrap_err();
+        let fields: Vec<&str> = errors.errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["limit", "sort", "tenant", "cursor"]);
+
+        // A cursor only goes with the order it was made for.
+        let cursor = page(entries(), &query("limit=1"), &LISTING)
+            .unwrap()
+            .next_cursor
+            .unwrap();
+        assert!(
+            page(
+                entries(),
+                &query(&format!("order=asc&cursor={cursor}")),
+                &LISTING
+            )
+            .is_err()
+        );
+    }
+}
@@ -1,42 +1,226 @@
 use axum::{
     Json, Router,
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post},
+    body::Bytes,
+    extract::{
+        ConnectInfo, DefaultBodyLimit, Extension, Path, Query, State,
+        rejection::JsonRejection,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, Method, StatusCode, Uri},
+    middleware,
+    response::{
+        IntoResponse,
+        sse::{Event as SseEvent, KeepAlive, Sse},
+    },
+    routing::{MethodRouter, any, get, patch, post, put},
 };
-use backend::initramfs_manager::get_languages_config;
+use backend::access::{self, ApiKeyError, ApiKeys, Caller, Permission};
+use backend::api_versions::{self, DEFAULT_UNVERSIONED_SUNSET, Deprecation};
+use backend::apply;
+use backend::artifact_store::{
+    ArtifactError, ArtifactStore, ArtifactUploads, DEFAULT_MAX_ARTIFACT_BYTES,
+    DEFAULT_UPLOAD_TTL_SECS,
+};
+use backend::audit_log::{AuditEntry, AuditLog, AuditOutcome, AuditWriter};
+use backend::blob_store::{BlobStore, LocalBlobStore, S3BlobStore, S3Config};
+use backend::bundles::{self, Plan};
+use backend::compile_cache::{CompileCache, DEFAULT_MAX_BINARY_BYTES};
+use backend::config::{ConfigError, ConfigReloader, ImageBuildSettings, ReloadableConfig};
+use backend::console::SerialConsole;
+use backend::core_dumps::CoreDumps;
+use backend::dashboard::{
+    DEFAULT_DASHBOARD_RETENTION_SECS, DEFAULT_DASHBOARD_ROLLUP_AFTER_SECS, Dashboards, Invocation,
+    MAX_DASHBOARD_BUCKETS,
+};
+use backend::egress_proxy::{Allowlist, EgressLease, EgressPolicy, EgressProxy};
+use backend::events::EventBus;
+use backend::execution_diff::{Execution, diff};
+use backend::function_registry::{FunctionError, FunctionRegistry};
+use backend::heartbeats::{
+    DEFAULT_HEARTBEAT_INTERVAL_SECS, DEFAULT_HEARTBEAT_TIMEOUT_SECS, Heartbeats,
+};
+use backend::host_builds::{DEFAULT_BUILD_TIMEOUT, HostBuilder};
+use backend::host_pressure::{
+    DEFAULT_ADMISSION_MAX_WAIT_SECS, DEFAULT_CPU_PRESSURE_LIMIT, DEFAULT_MEMORY_PRESSURE_LIMIT,
+    DEFAULT_MIN_AVAILABLE_MEMORY_MB, HostPressure, Pressure, PressureLimits, RELIEF_INTERVAL,
+};
+use backend::identity::{DEFAULT_TOKEN_TTL, IdentityIssuer, JobIdentity};
+use backend::image_digests::ImageDigests;
+use backend::initramfs_manager::BuildSite;
 use backend::ip_manager::IpManager;
-use backend::vm_lifecycle::{VmConfig, VmHandle};
+use backend::janitor::{
+    DEFAULT_JANITOR_INTERVAL_SECS, DEFAULT_LOG_RETENTION_SECS, DEFAULT_STALE_DIR_SECS, Janitor,
+    JanitorStats, Orphan, find_network_orphans, find_stale_dirs, find_stale_logs,
+};
+use backend::job_journal::{JobJournal, JournalEntry};
+use backend::job_logs::JobLog;
+use backend::kernels::{DEFAULT_KERNEL_PATH, Kernels};
+use backend::ksm::{KSM_DIR, Ksm, KsmSettings};
+use backend::listener::{DEFAULT_SOCKET_MODE, Peer, bind_unix};
+use backend::listing::{self, Entry, Filter, ListQuery, Listing, Order, SortField};
+use backend::metrics::Metrics;
+use backend::namespaces::Namespace;
+use backend::pipelines::{self, Pipelines};
+use backend::prewarm::{DEFAULT_PREWARM_INTERVAL_SECS, DEFAULT_PREWARM_MAX_VMS, Forecaster};
+#[cfg(feature = "process-executor")]
+use backend::process_executor::{ProcessExecutor, stop_leftover_agent};
+use backend::profiles::{DEFAULT_PROFILE, Sandbox};
+use backend::publishers::{Publishers, load_publishers};
+use backend::readiness::{DEFAULT_MIN_FREE_DISK_BYTES, ReadinessChecks};
+use backend::redaction::{RedactingStream, Redactor};
+use backend::result_cache::{
+    CachedResult, DEFAULT_CACHE_MAX_ENTRIES, DEFAULT_CACHE_TTL_SECS, ResultCache, cache_key,
+};
+use backend::runtime_upgrades::{RuntimeUpgrader, RuntimeVersions};
+use backend::template_manager::{TemplateBaker, TemplateClones, TemplateRegistry};
+use backend::triggers::{
+    Binding, Invoker, ResponseMode, http_event, load_bindings, parse_response_head, trigger_for,
+};
+use backend::validation::{
+    DEFAULT_MAX_CODE_BYTES, RequestLimits, ValidationErrors, normalize_language_alias,
+    validate_artifact_id, validate_code, validate_identifier, validate_relative_path,
+    validate_resources,
+};
+use backend::vm_lifecycle::{
+    CpuPolicy, DEFAULT_CORE_DUMP_BYTES, DEFAULT_JOB_PIDS_MAX, DEFAULT_MTU, SHUTDOWN_GRACE,
+    SuspendedVm, TrafficMeter, VmConfig, VmError, VmHandle, job_limits, live_vm_ids,
+};
+use backend::vm_pool::{
+    DEFAULT_POOL_IDLE_SECS, DEFAULT_POOL_MAX_IDLE, DEFAULT_POOL_MAX_SUSPENDED,
+    DEFAULT_POOL_SUSPENDED_IDLE_SECS, PoolKey, VmPool,
+};
+use backend::vm_sharing::{DEFAULT_MAX_JOBS_PER_VM, Guest, SharedVms};
+use backend::webhooks::{self, DEFAULT_WEBHOOK_MAX_ATTEMPTS, Webhook};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use cloude_types::{
+    ApiKeyInfo, ApplyAction, ApplyResponse, ApplySpec, Arch, AssignRoleRequest, BUNDLE_FORMAT,
+    Bundle, CHANNEL_DIR, CHANNEL_PROTOCOL, CacheControl, CacheStats, ChannelRequest,
+    ChannelResponse, ClockRequest, ClockResponse, CreateApiKeyRequest, Dashboard, DashboardQuery,
+    DeployRequest, DesiredFunction, EntropyResponse, ErrorResponse, EventKind, ExecuteChunk,
+    ExecuteRequest, ExecutionCompleted, ExecutionResult, ExecutionSummary, FunctionInfo,
+    FunctionSpec, IdentityRequest, ImportOutcome, ImportReport, ImportedFunction, Isolation,
+    JobStatus, Jwks, LifecycleEvent, LogLine, LogSource, MAX_CONCURRENT_EXECUTIONS, Network,
+    OnConflict, PipelineRequest, PipelineResponse, PipelineStatus, ResetResponse, ResizeRequest,
+    ResizeResponse, ResourceUsage, Resources, RunResponse, RuntimeInfo, StageStatus,
+    StatusResponse, TrafficStats, TriggerEvent, UpdateVmRequest, UpdateVmResponse, VmLiveness,
+    WebhookStatus,
+};
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::{self, EnvFilter};
 use virt::network::{setup_bridge, setup_nat};
+use virt::serial_log::{DEFAULT_MAX_BYTES, DEFAULT_MAX_FILES, SerialLogConfig};
+
+mod server;
+
+use server::*;
 
 // ── Shared application state ────────────────────────────────────────
 
 struct AppState {
     jobs: RwLock<HashMap<String, Job>>,
+    /// Every job of `jobs`, persisted so that a restart knows which ones it interrupted.
+    journal: Arc<JobJournal>,
+    /// Serial consoles of the VMs currently running a job, by job id.
+    consoles: RwLock<HashMap<String, Arc<SerialConsole>>>,
+    /// vCPU hotplug of the VMs currently running a job that can grow, by job id.
+    vcpu_hotplugs: RwLock<HashMap<String, vmm::VcpuHotplug>>,
+    /// Memory resize of the VMs currently running a job that can change size, by job id.
+    memory_resizes: RwLock<HashMap<String, vmm::MemoryResize>>,
+    /// TAP devices of the VMs currently running a job, by job id.
+    taps: RwLock<HashMap<String, JobTap>>,
+    /// Bounds of the packet captures of `GET /vms/{id}/pcap`.
+    pcap_max_bytes: u64,
+    pcap_max_duration: std::time::Duration,
+    /// Logs of every job still in `jobs`.
+    logs: RwLock<HashMap<String, Arc<JobLog>>>,
     client: reqwest::Client,
-    supported_languages: Vec<backend::initramfs_manager::InitramfsLanguage>,
+    /// Limits, VM shape and runtimes; can change at runtime through `/admin/reload`.
+    config: ConfigReloader,
     vm_config: VmConfig,
     ip_manager: Arc<Mutex<IpManager>>,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
-enum JobStatus {
-    Pending,
-    Running,
-    Done,
-    Error,
+    audit_log: Arc<AuditLog>,
+    audit_writer: AuditWriter,
+    artifact_store: ArtifactStore,
+    artifact_uploads: ArtifactUploads,
+    templates: Arc<TemplateRegistry>,
+    /// Snapshots of the templates new pooled VMs are restored from, `None` unless
+    /// templates are baked.
+    template_clones: Option<TemplateClones>,
+    functions: FunctionRegistry,
+    readiness: ReadinessChecks,
+    /// Results of earlier runs, for requests that opt in with `cache`.
+    result_cache: ResultCache,
+    /// Binaries of earlier runs of compiled code, run instead of compiling it again.
+    compile_cache: CompileCache,
+    /// Compiles the code of runtimes built on the host, `None` when jobs run in host processes.
+    host_builder: Option<HostBuilder>,
+    /// Digests of the runtime images, recorded with the jobs that ran in them.
+    images: Arc<ImageDigests>,
+    /// Architecture of the VMs jobs run in, `None` when they run in host processes.
+    vm_arch: Option<Arch>,
+    /// Processes and threads the code of a job can run at once in its VM, `0` for no limit.
+    job_pids_max: u32,
+    /// CPU the code of a job gets in its VM, from a burst to its share.
+    job_cpu: CpuPolicy,
+    /// Cores the code of crashed jobs dumped.
+    core_dumps: CoreDumps,
+    /// Pipelines and the outputs of their stages.
+    pipelines: Pipelines,
+    /// Idle VMs of `pooled-vm` jobs, waiting for the next job of their tenant.
+    vm_pool: VmPool<VmHandle>,
+    /// Replace the VM of a `pooled-vm` job by a pristine one before it goes back to
+    /// the pool, for jobs of any tenant, with `VM_POOL_SCRUB`.
+    vm_pool_scrub: bool,
+    /// Pooled VMs suspended to disk once idle for `VM_POOL_SUSPEND_AFTER_SECS`,
+    /// waiting for the next job of their tenant.
+    suspended_vms: VmPool<SuspendedVm>,
+    /// Where the snapshots of suspended VMs are kept.
+    suspended_vms_dir: PathBuf,
+    /// VMs running a function invocation that concurrent ones can join.
+    shared_vms: SharedVms<SharedVm>,
+    /// Invocation history of the functions, to boot their VMs ahead of them.
+    forecaster: Forecaster,
+    /// Latencies, errors, starts and concurrency of the functions, for `GET /dashboard/functions`.
+    dashboards: Dashboards,
+    /// Idle VMs booted ahead of a forecast the pool may hold at once.
+    prewarm_max_vms: usize,
+    /// CPU and memory pressure of the host, checked before VMs are booted.
+    /// `None` when VMs are booted whatever the pressure.
+    pressure: Option<Arc<HostPressure>>,
+    /// Longest a VM waits for the host to be out of pressure.
+    admission_max_wait: std::time::Duration,
+    /// Kernel samepage merging of the memory of VMs, `None` when no VM runs.
+    ksm: Option<Ksm>,
+    /// Liveness of the VMs currently running a job, by job id.
+    heartbeats: Heartbeats,
+    /// Lifecycle events of jobs and VMs, for `GET /events` and the events webhooks.
+    events: EventBus,
+    /// Webhooks the events are POSTed to, from `EVENTS_WEBHOOK_URL`.
+    webhooks: Vec<Arc<Webhook>>,
+    /// Keys of the API and their roles, checked with `API_ADMIN_KEY`.
+    api_keys: Arc<ApiKeys>,
+    /// Signs the identity tokens of the VMs, with the key in `IDENTITY_KEY_PATH`.
+    identity: IdentityIssuer,
+    /// Proxy of the `egress-restricted` jobs, `None` without `EGRESS_PROXY_PORT`.
+    egress_proxy: Option<EgressProxy>,
+    /// Cleans up orphan TAP devices, IP leases, VMs and scratch directories.
+    janitor: Janitor,
+    /// Served on `GET /metrics`.
+    metrics: Metrics,
+    /// Functions bound to an `http` trigger, served on `/f/{name}`.
+    http_triggers: HashMap<String, ResponseMode>,
+    /// Runs jobs in host processes instead of VMs, with `EXECUTOR=process`.
+    #[cfg(feature = "process-executor")]
+    process_executor: Option<ProcessExecutor>,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -50,38 +234,74 @@ struct Job {
     stdout: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     stderr: Option<String>,
+    /// `None` for jobs answered from the result cache, which ran in no VM.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    isolation: Option<Isolation>,
     #[serde(skip)]
     created_at: std::time::Instant,
+    /// Caller the job runs for, see `X-Cloude-User`.
+    #[serde(skip)]
+    tenant: Option<String>,
+    /// Only requests of this namespace see the job.
+    #[serde(skip)]
+    namespace: Namespace,
+    /// Agent process running the job, with `EXECUTOR=process`.
+    #[serde(skip)]
+    agent_pid: Option<u32>,
+    /// VM that ran the job, whose serial output is in `{VM_LOG_DIR}/{vm_id}.log`.
+    #[serde(skip)]
+    vm_id: Option<String>,
+    /// Traffic of the VM while it ran the job, once it finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    traffic: Option<TrafficStats>,
+    /// The code went over the memory of its VM and was killed.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    oom_killed: bool,
+    /// Signal that killed the code, like `SIGSEGV`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signal: Option<String>,
+    /// The code dumped a core, kept in `core_dumps`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    core_dump: bool,
+    /// Digest of the code, `None` for jobs answered from the result cache.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_digest: Option<String>,
+    /// Digest of the runtime image of its VM.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_digest: Option<String>,
+    /// Shape of its VM, or the memory limit of its agent process.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shape: Option<cloude_types::VmConfig>,
+    /// From the submission of the job to its result.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    /// Deployed function the job runs and its version, for its publishers.
+    #[serde(skip)]
+    function: Option<(String, u32)>,
+    /// Whether its VM was already up, pooled or shared; `None` until it has one.
+    #[serde(skip)]
+    warm: Option<bool>,
 }
 
-// ── Request / Response DTOs ─────────────────────────────────────────
-
-#[derive(Deserialize)]
-struct RunRequest {
-    language: String,
-    code: String,
-}
-
-#[derive(Serialize)]
-struct RunResponse {
-    id: String,
-}
-
-// ── Agent DTOs (for forwarding to the agent) ────────────────────────
-
-#[derive(Serialize)]
-struct AgentExecuteRequest {
-    language: String,
-    code: String,
-}
-
-#[derive(Deserialize)]
-struct AgentExecuteResponse {
-    #[allow(dead_code)]
-    job_id: String,
-    exit_code: i32,
-    stdout: String,
-    stderr: String,
+impl Job {
+    /// The event published once the job ended, `None` unless it ran a function.
+    fn completed(&self, timestamp_ms: u64) -> Option<ExecutionCompleted> {
+        let (function, version) = self.function.clone()?;
+        Some(ExecutionCompleted {
+            function,
+            version,
+            job_id: self.id.clone(),
+            status: self.status,
+            exit_code: self.exit_code,
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+            result_omitted: false,
+            oom_killed: self.oom_killed,
+            signal: self.signal.clone(),
+            core_dump: self.core_dump,
+            timestamp_ms,
+        })
+    }
 }
 
 // ── Main ────────────────────────────────────────────────────────────
@@ -96,9 +316,35 @@ async fn main() -> Result<(), std::io::Error> {
         .init();
     log::debug!("Debug logging enabled");
 
-    // Get the server address from the environment variable or use a default
+    // Get the server address from the environment variable or use a default; empty
+    // to serve the API on the Unix socket only
     let server_addr =
         env::var("BACKEND_SERVER_ADDR").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    let unix_socket = env::var("BACKEND_UNIX_SOCKET")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from);
+    let unix_socket_mode: u32 = match env::var("BACKEND_UNIX_SOCKET_MODE") {
+        Ok(v) => u32::from_str_radix(&v, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "BACKEND_UNIX_SOCKET_MODE env variable is invalid: {:?} is not an octal mode like 660",
+                        v
+                    ),
+                )
+            })?,
+        Err(_) => DEFAULT_SOCKET_MODE,
+    };
+    if server_addr.is_empty() && unix_socket.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "BACKEND_SERVER_ADDR is empty and BACKEND_UNIX_SOCKET is not set: the API would be served nowhere",
+        ));
+    }
     let bridge_name = env::var("BRIDGE_NAME").unwrap_or_else(|_| "cloudebr0".to_string());
 
     let languages_config_path =
@@ -107,13 +353,85 @@ async fn main() -> Result<(), std::io::Error> {
     let agent_binary =
         env::var("AGENT_BINARY_PATH").unwrap_or_else(|_| "./cloude-agentd".to_string());
 
+    // Jobs run in VMs, or in host processes on machines without KVM.
+    let run_vms = match env::var("EXECUTOR").as_deref() {
+        Ok("vm") | Err(_) => true,
+        Ok("process") if cfg!(feature = "process-executor") => false,
+        Ok("process") => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "EXECUTOR=process needs a backend built with the process-executor feature",
+            ));
+        }
+        Ok(other) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("EXECUTOR env variable is invalid: {other}, expected vm or process"),
+            ));
+        }
+    };
+
     let init_script = env::var("INIT_SCRIPT_PATH").unwrap_or_else(|_| "./init.sh".to_string());
     let vm_initramfs_dir = env::var("VM_INITRAMFS_DIR").unwrap_or_else(|_| "./tmp".to_string());
 
-    let available_languages: Vec<backend::initramfs_manager::InitramfsLanguage> =
-        get_languages_config(&languages_config_path)?;
+    let runtime_versions_path = env::var("RUNTIME_VERSIONS_PATH")
+        .unwrap_or_else(|_| "./tmp/runtime_versions.json".to_string());
+    if let Some(parent) = PathBuf::from(&runtime_versions_path).parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let runtime_versions = Arc::new(RuntimeVersions::new(&runtime_versions_path).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("Failed to initialize runtime versions: {}", e),
+        )
+    })?);
+
+    let max_code_bytes: usize = match env::var("MAX_CODE_BYTES") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("MAX_CODE_BYTES env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_MAX_CODE_BYTES,
+    };
+
+    let config_path = PathBuf::from(
+        env::var("CLOUDE_CONFIG_PATH").unwrap_or_else(|_| "./config/cloude.toml".to_string()),
+    );
+    let config = ConfigReloader::new(
+        config_path,
+        languages_config_path,
+        ReloadableConfig {
+            limits: RequestLimits {
+                max_code_bytes,
+                ..RequestLimits::default()
+            },
+            vcpus: 1,
+            memory_mb: 512,
+            languages: Vec::new(),
+            redactor: Redactor::default(),
+            profiles: BTreeMap::new(),
+            egress: EgressPolicy::default(),
+            ksm: KsmSettings::default(),
+        },
+        Arc::clone(&runtime_versions),
+        ImageBuildSettings {
+            agent_binary: agent_binary.clone(),
+            init_script: init_script.clone(),
+            initramfs_dir: vm_initramfs_dir.clone(),
+        },
+    )
+    .map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("Failed to load configuration: {}", e),
+        )
+    })?;
+    let available_languages = config.current().languages.clone();
 
-    for language in available_languages.clone() {
+    // Host processes run the runtimes installed on the host, not the VM images.
+    for language in available_languages.clone().into_iter().filter(|_| run_vms) {
         log::debug!("Available language: {}", language.name);
         log::debug!("  version: {}", language.version);
         log::debug!("  base_image: {}", language.base_image);
@@ -160,22 +478,52 @@ async fn main() -> Result<(), std::io::Error> {
         ));
     }
 
-    // Set up the bridge and NAT rules
-    let host_ip: Ipv4Addr = (ip_range.to_bits() + 1).into();
-    if let Err(e) = setup_bridge(bridge_name.clone(), host_ip, ip_mask).await {
-        eprintln!("Failed to set up bridge: {}", e);
+    let vm_mtu: u16 = match env::var("VM_MTU") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_MTU env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_MTU,
+    };
+    if !(vmm::MIN_MTU..=vmm::MAX_MTU).contains(&vm_mtu) {
         return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            e.to_string(),
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "VM_MTU must be in range {}..={}, got {}",
+                vmm::MIN_MTU,
+                vmm::MAX_MTU,
+                vm_mtu
+            ),
         ));
     }
 
-    if let Err(e) = setup_nat(ip_range, ip_mask) {
-        eprintln!("Failed to set up NAT: {}", e);
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            e.to_string(),
-        ));
+    // Set up the bridge and NAT rules
+    let host_ip: Ipv4Addr = (ip_range.to_bits() + 1).into();
+    if run_vms {
+        if let Err(e) = setup_bridge(bridge_name.clone(), host_ip, ip_mask).await {
+            eprintln!("Failed to set up bridge: {}", e);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e.to_string(),
+            ));
+        }
+        // The TAPs get the MTU of their guest, and must match the bridge they join.
+        if let Err(e) = virt::network::set_link_mtu(&bridge_name, vm_mtu).await {
+            return Err(std::io::Error::other(format!(
+                "Failed to set the MTU of bridge {} to {}: {}",
+                bridge_name, vm_mtu, e
+            )));
+        }
+
+        if let Err(e) = setup_nat(ip_range, ip_mask) {
+            eprintln!("Failed to set up NAT: {}", e);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e.to_string(),
+            ));
+        }
     }
 
     // Build a shared HTTP client with a timeout for agent calls
@@ -184,13 +532,154 @@ async fn main() -> Result<(), std::io::Error> {
         .build()
         .expect("Failed to build HTTP client");
 
-    let vm_kernel_path = env::var("VM_KERNEL_PATH").unwrap_or_else(|_| "./vmlinux".to_string());
+    // KVM only runs guests of the host architecture: VMs boot the kernel tagged with it.
+    let vm_arch = match Arch::host() {
+        Some(arch) => run_vms.then_some(arch),
+        None if run_vms => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!(
+                    "VMs need an x86_64 or aarch64 host, this one is {}",
+                    std::env::consts::ARCH
+                ),
+            ));
+        }
+        None => None,
+    };
+    let vm_kernel_path = match vm_arch {
+        Some(arch) => Kernels::from_env(
+            env::var("VM_KERNELS").ok().as_deref(),
+            env::var("VM_KERNEL_PATH").ok().as_deref(),
+            arch,
+        )
+        .and_then(|kernels| kernels.get(arch).map(|path| path.display().to_string()))
+        .map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_KERNELS env variable is invalid: {}", e),
+            )
+        })?,
+        None => env::var("VM_KERNEL_PATH").unwrap_or_else(|_| DEFAULT_KERNEL_PATH.to_string()),
+    };
+    // Runtimes with images for these VMs, the only ones templates and upgrades are for.
+    let vm_languages: Vec<_> = available_languages
+        .iter()
+        .filter(|language| vm_arch.is_some_and(|arch| language.supports(arch)))
+        .cloned()
+        .collect();
+    // Runtimes built on the host compile there, for the architecture of the VMs.
+    let host_build_timeout: u64 = match env::var("HOST_BUILD_TIMEOUT_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("HOST_BUILD_TIMEOUT_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_BUILD_TIMEOUT.as_secs(),
+    };
+    let host_builder = match vm_arch {
+        Some(arch) => {
+            let namespaces = env::var("HOST_BUILD_NAMESPACES")
+                .map(|v| {
+                    let normalized = v.trim().to_ascii_lowercase();
+                    matches!(normalized.as_str(), "1" | "true" | "yes" | "on")
+                })
+                .unwrap_or(true);
+            let builder = HostBuilder::new(
+                PathBuf::from(env::var("HOST_BUILD_RUSTC").unwrap_or_else(|_| "rustc".to_string())),
+                PathBuf::from(
+                    env::var("HOST_BUILD_DIR").unwrap_or_else(|_| "./tmp/host-builds".to_string()),
+                ),
+                arch,
+                std::time::Duration::from_secs(host_build_timeout.max(1)),
+                namespaces,
+            );
+            if vm_languages.iter().any(|l| l.build == BuildSite::Host) {
+                builder.check().await.map_err(|e| {
+                    std::io::Error::other(format!("Cannot build runtimes on the host: {}", e))
+                })?;
+                info!("Building binaries for {} on the host", builder.target());
+            }
+            Some(builder)
+        }
+        None => None,
+    };
     let vm_log_guest_console = env::var("VM_LOG_GUEST_CONSOLE")
         .map(|v| {
             let normalized = v.trim().to_ascii_lowercase();
             matches!(normalized.as_str(), "1" | "true" | "yes" | "on")
         })
         .unwrap_or(false);
+    // Empty to keep no log of the serial output of VMs.
+    let vm_log_dir = env::var("VM_LOG_DIR").unwrap_or_else(|_| "./logs".to_string());
+    let vm_log_max_bytes: u64 = match env::var("VM_LOG_MAX_BYTES") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_LOG_MAX_BYTES env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_MAX_BYTES,
+    };
+    let vm_log_max_files: usize = match env::var("VM_LOG_MAX_FILES") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_LOG_MAX_FILES env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_MAX_FILES,
+    };
+    let serial_log = (!vm_log_dir.is_empty()).then(|| SerialLogConfig {
+        dir: PathBuf::from(&vm_log_dir),
+        max_bytes: vm_log_max_bytes,
+        max_files: vm_log_max_files,
+    });
+    let vm_acpi = env::var("VM_ACPI")
+        .map(|v| {
+            let normalized = v.trim().to_ascii_lowercase();
+            matches!(normalized.as_str(), "1" | "true" | "yes" | "on")
+        })
+        .unwrap_or(false);
+    let vm_traffic_accounting = env::var("VM_TRAFFIC_ACCOUNTING")
+        .map(|v| {
+            let normalized = v.trim().to_ascii_lowercase();
+            matches!(normalized.as_str(), "1" | "true" | "yes" | "on")
+        })
+        .unwrap_or(true);
+    let vm_max_vcpus: u8 = match env::var("VM_MAX_VCPUS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_MAX_VCPUS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => 0,
+    };
+    if vm_max_vcpus > 0 && !vm_acpi {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "VM_MAX_VCPUS needs VM_ACPI: vCPUs are hotplugged through ACPI",
+        ));
+    }
+    let vm_max_memory_mb: usize = match env::var("VM_MAX_MEMORY_MB") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_MAX_MEMORY_MB env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => 0,
+    };
+    let vm_kernel_cmdline = env::var("VM_KERNEL_CMDLINE").ok();
+    if let Some(cmdline) = &vm_kernel_cmdline {
+        vmm::cmdline::parse(cmdline).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_KERNEL_CMDLINE env variable is invalid: {}", e),
+            )
+        })?;
+    }
     tokio::fs::create_dir_all(&vm_initramfs_dir).await?;
 
     let ip_allocations_path =
@@ -250,276 +739,1425 @@ async fn main() -> Result<(), std::io::Error> {
     }
     let pool_start: Ipv4Addr = pool_start_u32.into();
     let pool_end: Ipv4Addr = pool_end_u32.into();
-    let ip_manager = Arc::new(Mutex::new(
+    let mut ip_manager =
         IpManager::new(&ip_allocations_path, pool_start, pool_end).map_err(|e| {
             std::io::Error::new(
                 std::io::ErrorKind::Other,
                 format!("Failed to initialize IP manager: {}", e),
             )
-        })?,
-    ));
-
-    let state = Arc::new(AppState {
-        jobs: RwLock::new(HashMap::new()),
-        client,
-        supported_languages: available_languages.clone(),
-        vm_config: VmConfig {
-            kernel_path: PathBuf::from(vm_kernel_path),
-            initramfs_dir: PathBuf::from(vm_initramfs_dir),
-            bridge_name: bridge_name.clone(),
-            vcpus: 1,
-            memory_mb: 512,
-            log_guest_console: vm_log_guest_console,
-        },
-        ip_manager,
-    });
-
-    // Background task: evict terminal jobs older than 5 mins to prevent unbounded memory growth.
-    const JOB_TTL: std::time::Duration = std::time::Duration::from_secs(300);
-    let cleanup_state = Arc::clone(&state);
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
-        loop {
-            interval.tick().await;
-            let mut jobs = cleanup_state.jobs.write().await;
-            let before = jobs.len();
-            jobs.retain(|_, j| {
-                !matches!(j.status, JobStatus::Done | JobStatus::Error)
-                    || j.created_at.elapsed() < JOB_TTL
-            });
-            let removed = before - jobs.len();
-            if removed > 0 {
-                info!("Evicted {} expired jobs", removed);
-            }
-        }
-    });
-
-    let app = Router::new()
-        .route("/", get(root))
-        .route("/health", get(health_check))
-        .route("/run", post(run_job))
-        .route("/status/{id}", get(get_status))
-        .with_state(state);
-
-    info!("Starting Backend server on {}", &server_addr);
-    let listener = TcpListener::bind(&server_addr).await?;
-    axum::serve(listener, app).await?;
-
-    Ok(())
-}
-
-async fn root() -> &'static str {
-    "Welcome to the Backend server!"
-}
-
-async fn health_check() -> &'static str {
-    "Backend server is healthy!"
-}
-
-// ── POST /run  –  submit a new job ──────────────────────────────────
-
-async fn run_job(
-    State(state): State<Arc<AppState>>,
-    Json(payload): Json<RunRequest>,
-) -> axum::response::Response {
-    let requested_language = payload.language.trim().to_ascii_lowercase();
-    let language = normalize_language_alias(&requested_language);
-
-    let mut supported_languages = state
-        .supported_languages
-        .iter()
-        .map(|lang| lang.name.to_ascii_lowercase())
-        .collect::<Vec<_>>();
-    supported_languages.sort();
-    supported_languages.dedup();
-    if payload.code.trim().is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "Code cannot be empty"
-            })),
-        )
-            .into_response();
-    }
-
-    let code = payload.code.clone();
+        })?;
 
-    if !supported_languages.iter().any(|name| name == &language) {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": format!(
-                    "Unsupported language: {}. Supported languages: {}",
-                    payload.language,
-                    supported_languages.join(", ")
+    // Give each tenant its own segment of the IP range, e.g. a /26
+    let tenant_segment_prefix: Option<u8> = match env::var("TENANT_SEGMENT_PREFIX") {
+        Ok(v) if !v.is_empty() => Some(v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("TENANT_SEGMENT_PREFIX env variable is invalid: {}", e),
+            )
+        })?),
+        _ => None,
+    };
+    if let Some(prefix) = tenant_segment_prefix.filter(|_| run_vms) {
+        ip_manager = ip_manager
+            .with_tenant_segments(ip_range, ip_mask, prefix)
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "TENANT_SEGMENT_PREFIX is invalid for IP_MASK={}: {}",
+                        ip_mask, e
+                    ),
                 )
-            })),
-        )
-            .into_response();
+            })?;
+        info!(
+            "Tenant segments enabled – /{} per tenant in {}/{}",
+            prefix, ip_range, ip_mask
+        );
     }
+    let ip_manager = Arc::new(Mutex::new(ip_manager));
 
-    let id = uuid::Uuid::new_v4().to_string();
-
-    let job = Job {
-        id: id.clone(),
-        status: JobStatus::Pending,
-        language: language.clone(),
-        exit_code: None,
-        stdout: None,
-        stderr: None,
-        created_at: std::time::Instant::now(),
+    let max_artifact_bytes: u64 = match env::var("MAX_ARTIFACT_BYTES") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("MAX_ARTIFACT_BYTES env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_MAX_ARTIFACT_BYTES,
     };
-
-    // Store the job
+    let unversioned_deprecation = Deprecation::unversioned(
+        &env::var("API_UNVERSIONED_SUNSET")
+            .unwrap_or_else(|_| DEFAULT_UNVERSIONED_SUNSET.to_string()),
+    )
+    .map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("API_UNVERSIONED_SUNSET env variable is invalid: {}", e),
+        )
+    })?;
+    let artifacts_dir =
+        PathBuf::from(env::var("ARTIFACTS_DIR").unwrap_or_else(|_| "./tmp/artifacts".to_string()));
+    let blob_store: Arc<dyn BlobStore> = match env::var("BLOB_STORE")
+        .unwrap_or_else(|_| "local".to_string())
+        .as_str()
     {
-        let mut jobs = state.jobs.write().await;
-        jobs.insert(id.clone(), job);
-    }
-
-    info!("Job {} created – language={}", id, language);
-
-    // Spawn a background task that creates a VM and forwards the request to its agent
-    let job_id = id.clone();
-    let language = language.clone();
-    let code = code.clone();
-    let state = Arc::clone(&state);
-
-    tokio::spawn(async move {
-        // Mark as running
-        {
-            let mut jobs = state.jobs.write().await;
-            if let Some(j) = jobs.get_mut(&job_id) {
-                j.status = JobStatus::Running;
-            }
+        "local" => {
+            let blob_store_dir =
+                env::var("BLOB_STORE_DIR").unwrap_or_else(|_| "./tmp/blobs".to_string());
+            Arc::new(LocalBlobStore::new(&blob_store_dir).await.map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Failed to initialize local blob store: {}", e),
+                )
+            })?)
         }
-
-        let mut vm = match VmHandle::create(
-            job_id.clone(),
-            &language,
-            &state.vm_config,
-            Arc::clone(&state.ip_manager),
-        )
+        "s3" => {
+            let required = |name: &str| {
+                env::var(name).map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("{} env variable is required when BLOB_STORE=s3", name),
+                    )
+                })
+            };
+            let config = S3Config {
+                endpoint: required("S3_ENDPOINT")?,
+                bucket: required("S3_BUCKET")?,
+                prefix: env::var("S3_PREFIX").unwrap_or_default(),
+                region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+                access_key_id: required("S3_ACCESS_KEY_ID")
+                    .or_else(|_| required("AWS_ACCESS_KEY_ID"))?,
+                secret_access_key: required("S3_SECRET_ACCESS_KEY")
+                    .or_else(|_| required("AWS_SECRET_ACCESS_KEY"))?,
+            };
+            info!(
+                "Using S3 blob store: bucket={} endpoint={}",
+                config.bucket, config.endpoint
+            );
+            Arc::new(S3BlobStore::new(config).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Failed to initialize S3 blob store: {}", e),
+                )
+            })?)
+        }
+        other => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("BLOB_STORE must be 'local' or 's3', got '{}'", other),
+            ));
+        }
+    };
+    let artifact_store = ArtifactStore::new(Arc::clone(&blob_store));
+    let artifact_uploads = ArtifactUploads::new(artifacts_dir.join("uploads"), max_artifact_bytes)
         .await
-        {
-            Ok(vm) => vm,
-            Err(e) => {
-                let mut jobs = state.jobs.write().await;
-                if let Some(j) = jobs.get_mut(&job_id) {
-                    j.status = JobStatus::Error;
-                    j.stderr = Some(format!("Failed to create VM: {e}"));
-                }
-                error!("Job {} – failed to create VM: {}", job_id, e);
-                return;
-            }
-        };
-
-        let execute_url = format!("{}/execute", vm.agent_url().trim_end_matches('/'));
-        let request_payload = AgentExecuteRequest { language, code };
-
-        let mut execution_result: Result<AgentExecuteResponse, String> =
-            Err("VM agent execute request did not run".to_string());
-
-        for attempt in 1..=5 {
-            let result = state
-                .client
-                .post(&execute_url)
-                .json(&request_payload)
-                .send()
-                .await;
+        .map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Failed to initialize artifact uploads: {}", e),
+            )
+        })?;
 
-            match result {
-                Ok(resp) if resp.status().is_success() => {
-                    execution_result = resp
-                        .json::<AgentExecuteResponse>()
-                        .await
-                        .map_err(|e| format!("Failed to parse agent response: {e}"));
-                    break;
-                }
-                Ok(resp) => {
-                    let status = resp.status();
-                    let body = resp.text().await.unwrap_or_default();
-                    execution_result = Err(format!("Agent returned HTTP {status}: {body}"));
-                    break;
-                }
-                Err(e) => {
-                    if attempt == 5 {
-                        execution_result = Err(format!("Cannot reach VM agent: {e}"));
-                        break;
-                    }
+    let audit_log_path =
+        env::var("AUDIT_LOG_PATH").unwrap_or_else(|_| "./tmp/audit_log.jsonl".to_string());
+    if let Some(parent) = PathBuf::from(&audit_log_path).parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let audit_log =
+        Arc::new(AuditLog::new(&audit_log_path).map_err(|e| {
+            std::io::Error::other(format!("Failed to initialize audit log: {}", e))
+        })?);
+    let audit_writer = AuditWriter::spawn(audit_log.clone())?;
 
-                    info!(
-                        "Job {} – execute call failed on attempt {}/5, retrying: {}",
-                        job_id, attempt, e
-                    );
-                    tokio::time::sleep(std::time::Duration::from_millis(150)).await;
-                }
-            }
+    let templates_registry_path =
+        env::var("TEMPLATES_REGISTRY_PATH").unwrap_or_else(|_| "./tmp/templates.json".to_string());
+    if let Some(parent) = PathBuf::from(&templates_registry_path).parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let templates = Arc::new(
+        TemplateRegistry::new(&templates_registry_path).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Failed to initialize template registry: {}", e),
+            )
+        })?,
+    );
+    let functions_registry_path =
+        env::var("FUNCTIONS_REGISTRY_PATH").unwrap_or_else(|_| "./tmp/functions.json".to_string());
+    if let Some(parent) = PathBuf::from(&functions_registry_path).parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let functions = FunctionRegistry::new(&functions_registry_path).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("Failed to initialize function registry: {}", e),
+        )
+    })?;
+    let api_keys_path =
+        env::var("API_KEYS_PATH").unwrap_or_else(|_| "./tmp/api_keys.json".to_string());
+    if let Some(parent) = PathBuf::from(&api_keys_path).parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let admin_key = env::var("API_ADMIN_KEY")
+        .ok()
+        .filter(|key| !key.trim().is_empty());
+    let api_keys = Arc::new(
+        ApiKeys::new(&api_keys_path, admin_key.as_deref()).map_err(|e| {
+            std::io::Error::other(format!(
+                "Failed to load API keys from {}: {}",
+                api_keys_path, e
+            ))
+        })?,
+    );
+    if api_keys.enforced() {
+        info!(
+            "API requests need a key, {} keys besides the admin one",
+            api_keys.list().len()
+        );
+    }
+    let identity_key_path =
+        env::var("IDENTITY_KEY_PATH").unwrap_or_else(|_| "./tmp/identity_key.pk8".to_string());
+    if let Some(parent) = PathBuf::from(&identity_key_path).parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let identity_ttl = match env::var("IDENTITY_TOKEN_TTL_SECS") {
+        Ok(v) => std::time::Duration::from_secs(v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("IDENTITY_TOKEN_TTL_SECS env variable is invalid: {}", e),
+            )
+        })?),
+        Err(_) => DEFAULT_TOKEN_TTL,
+    };
+    let identity = IdentityIssuer::load_or_create(
+        std::path::Path::new(&identity_key_path),
+        &env::var("IDENTITY_ISSUER").unwrap_or_else(|_| "cloude".to_string()),
+        identity_ttl,
+    )
+    .map_err(|e| {
+        std::io::Error::other(format!(
+            "Failed to load the identity key from {}: {}",
+            identity_key_path, e
+        ))
+    })?;
+    // The proxy serves VMs only, on the bridge they reach the host through.
+    let egress_proxy = match env::var("EGRESS_PROXY_PORT") {
+        Ok(v) if run_vms => {
+            let port: u16 = v.parse().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("EGRESS_PROXY_PORT env variable is invalid: {}", e),
+                )
+            })?;
+            let proxy = EgressProxy::bind(host_ip, ip_mask, port)
+                .await
+                .map_err(|e| {
+                    std::io::Error::other(format!(
+                        "Failed to start the egress proxy on {}:{}: {}",
+                        host_ip, port, e
+                    ))
+                })?;
+            info!("Egress proxy listening on {}:{}", host_ip, port);
+            Some(proxy)
         }
+        _ => None,
+    };
+    let template_prebake = env::var("TEMPLATE_PREBAKE")
+        .map(|v| {
+            let normalized = v.trim().to_ascii_lowercase();
+            matches!(normalized.as_str(), "1" | "true" | "yes" | "on")
+        })
+        .unwrap_or(false);
+    let template_refresh_interval: u64 = match env::var("TEMPLATE_REFRESH_INTERVAL_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "TEMPLATE_REFRESH_INTERVAL_SECS env variable is invalid: {}",
+                    e
+                ),
+            )
+        })?,
+        Err(_) => 300,
+    };
+    let templates_dir =
+        PathBuf::from(env::var("TEMPLATES_DIR").unwrap_or_else(|_| "./tmp/templates".to_string()));
+    let template_cache_dir = PathBuf::from(
+        env::var("TEMPLATE_CACHE_DIR").unwrap_or_else(|_| "./tmp/template-cache".to_string()),
+    );
 
-        let mut jobs = state.jobs.write().await;
-        match execution_result {
-            Ok(agent_resp) => {
-                if let Some(j) = jobs.get_mut(&job_id) {
-                    j.status = JobStatus::Done;
-                    j.exit_code = Some(agent_resp.exit_code);
-                    j.stdout = Some(agent_resp.stdout);
-                    j.stderr = Some(agent_resp.stderr);
-                }
-                info!("Job {} completed", job_id);
+    let min_free_disk_bytes: u64 = match env::var("MIN_FREE_DISK_BYTES") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("MIN_FREE_DISK_BYTES env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_MIN_FREE_DISK_BYTES,
+    };
+    let result_cache_ttl: u64 = match env::var("RESULT_CACHE_TTL_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("RESULT_CACHE_TTL_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_CACHE_TTL_SECS,
+    };
+    let result_cache_max_entries: usize = match env::var("RESULT_CACHE_MAX_ENTRIES") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("RESULT_CACHE_MAX_ENTRIES env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_CACHE_MAX_ENTRIES,
+    };
+    let compile_cache_max_bytes: usize = match env::var("COMPILE_CACHE_MAX_BYTES") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("COMPILE_CACHE_MAX_BYTES env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_MAX_BINARY_BYTES,
+    };
+
+    let job_pids_max: u32 = match env::var("JOB_PIDS_MAX") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("JOB_PIDS_MAX env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_JOB_PIDS_MAX,
+    };
+    let job_cpu_burst_ms: u64 = match env::var("JOB_CPU_BURST_MS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("JOB_CPU_BURST_MS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => 0,
+    };
+    let job_cpu_share_percent: u32 = match env::var("JOB_CPU_SHARE_PERCENT") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("JOB_CPU_SHARE_PERCENT env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => 100,
+    };
+    if !(1..=100).contains(&job_cpu_share_percent) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "JOB_CPU_SHARE_PERCENT must be in range 1..=100, got {}",
+                job_cpu_share_percent
+            ),
+        ));
+    }
+
+    let core_dump_max_bytes: u64 = match env::var("CORE_DUMP_MAX_BYTES") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("CORE_DUMP_MAX_BYTES env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_CORE_DUMP_BYTES,
+    };
+
+    let vm_pool_max_idle: usize = match env::var("VM_POOL_MAX_IDLE") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_POOL_MAX_IDLE env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_POOL_MAX_IDLE,
+    };
+    let function_max_jobs_per_vm: usize = match env::var("FUNCTION_MAX_JOBS_PER_VM") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("FUNCTION_MAX_JOBS_PER_VM env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_MAX_JOBS_PER_VM,
+    };
+    if !(1..=MAX_CONCURRENT_EXECUTIONS as usize).contains(&function_max_jobs_per_vm) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "FUNCTION_MAX_JOBS_PER_VM must be in range 1..={}, got {}",
+                MAX_CONCURRENT_EXECUTIONS, function_max_jobs_per_vm
+            ),
+        ));
+    }
+    let vm_pool_idle_secs: u64 = match env::var("VM_POOL_IDLE_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_POOL_IDLE_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_POOL_IDLE_SECS,
+    };
+    let vm_pool_cold_after_secs: u64 = match env::var("VM_POOL_COLD_AFTER_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_POOL_COLD_AFTER_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => 0,
+    };
+    let vm_pool_suspend_after_secs: u64 = match env::var("VM_POOL_SUSPEND_AFTER_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_POOL_SUSPEND_AFTER_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => 0,
+    };
+    let vm_pool_max_suspended: usize = match env::var("VM_POOL_MAX_SUSPENDED") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_POOL_MAX_SUSPENDED env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_POOL_MAX_SUSPENDED,
+    };
+    let vm_pool_suspended_idle_secs: u64 = match env::var("VM_POOL_SUSPENDED_IDLE_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_POOL_SUSPENDED_IDLE_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_POOL_SUSPENDED_IDLE_SECS,
+    };
+    let suspended_vms_dir = PathBuf::from(
+        env::var("VM_POOL_SUSPEND_DIR").unwrap_or_else(|_| "./tmp/suspended-vms".to_string()),
+    );
+    let vm_pool_scrub = env::var("VM_POOL_SCRUB")
+        .map(|v| {
+            let normalized = v.trim().to_ascii_lowercase();
+            matches!(normalized.as_str(), "1" | "true" | "yes" | "on")
+        })
+        .unwrap_or(false);
+    let dashboard_retention_secs: u64 = match env::var("DASHBOARD_RETENTION_SECS") {
+        Ok(v) => match v.parse::<u64>() {
+            Ok(secs) if secs >= 60 => secs,
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "DASHBOARD_RETENTION_SECS env variable must be at least 60",
+                ));
             }
             Err(e) => {
-                if let Some(j) = jobs.get_mut(&job_id) {
-                    j.status = JobStatus::Error;
-                    j.stderr = Some(e.clone());
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("DASHBOARD_RETENTION_SECS env variable is invalid: {}", e),
+                ));
+            }
+        },
+        Err(_) => DEFAULT_DASHBOARD_RETENTION_SECS,
+    };
+    let dashboard_rollup_after_secs: u64 = match env::var("DASHBOARD_ROLLUP_AFTER_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("DASHBOARD_ROLLUP_AFTER_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_DASHBOARD_ROLLUP_AFTER_SECS,
+    };
+    let dashboards_path =
+        env::var("DASHBOARDS_PATH").unwrap_or_else(|_| "./tmp/dashboards.json".to_string());
+    if let Some(parent) = PathBuf::from(&dashboards_path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let dashboards = Dashboards::new(
+        std::time::Duration::from_secs(dashboard_retention_secs),
+        (dashboard_rollup_after_secs > 0)
+            .then(|| std::time::Duration::from_secs(dashboard_rollup_after_secs)),
+    )
+    .with_file(&dashboards_path)
+    .map_err(|e| {
+        std::io::Error::other(format!(
+            "Failed to load the dashboards from {}: {}",
+            dashboards_path, e
+        ))
+    })?;
+    let prewarm_aggressiveness: f64 = match env::var("PREWARM_AGGRESSIVENESS") {
+        Ok(v) => match v.parse::<f64>() {
+            Ok(value) if value.is_finite() && value >= 0.0 => value,
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "PREWARM_AGGRESSIVENESS env variable must be a number >= 0",
+                ));
+            }
+            Err(e) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("PREWARM_AGGRESSIVENESS env variable is invalid: {}", e),
+                ));
+            }
+        },
+        Err(_) => 0.0,
+    };
+    let prewarm_max_vms: usize = match env::var("PREWARM_MAX_VMS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("PREWARM_MAX_VMS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_PREWARM_MAX_VMS,
+    };
+    let prewarm_interval: u64 = match env::var("PREWARM_INTERVAL_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("PREWARM_INTERVAL_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_PREWARM_INTERVAL_SECS,
+    };
+    let host_cpu_pressure_limit: f64 = match env::var("HOST_CPU_PRESSURE_LIMIT") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("HOST_CPU_PRESSURE_LIMIT env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_CPU_PRESSURE_LIMIT,
+    };
+    let host_memory_pressure_limit: f64 = match env::var("HOST_MEMORY_PRESSURE_LIMIT") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("HOST_MEMORY_PRESSURE_LIMIT env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_MEMORY_PRESSURE_LIMIT,
+    };
+    let host_min_available_memory_mb: u64 = match env::var("HOST_MIN_AVAILABLE_MEMORY_MB") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "HOST_MIN_AVAILABLE_MEMORY_MB env variable is invalid: {}",
+                    e
+                ),
+            )
+        })?,
+        Err(_) => DEFAULT_MIN_AVAILABLE_MEMORY_MB,
+    };
+    let admission_max_wait: u64 = match env::var("ADMISSION_MAX_WAIT_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("ADMISSION_MAX_WAIT_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_ADMISSION_MAX_WAIT_SECS,
+    };
+    let webhook_max_attempts: u32 = match env::var("EVENTS_WEBHOOK_MAX_ATTEMPTS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("EVENTS_WEBHOOK_MAX_ATTEMPTS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_WEBHOOK_MAX_ATTEMPTS,
+    };
+    let webhook_secret = env::var("EVENTS_WEBHOOK_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .map(String::into_bytes);
+    let webhooks: Vec<Arc<Webhook>> = env::var("EVENTS_WEBHOOK_URL")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(|url| {
+            Arc::new(Webhook::new(
+                url,
+                webhook_secret.clone(),
+                webhook_max_attempts,
+            ))
+        })
+        .collect();
+    let vm_heartbeat_interval: u64 = match env::var("VM_HEARTBEAT_INTERVAL_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_HEARTBEAT_INTERVAL_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_HEARTBEAT_INTERVAL_SECS,
+    };
+    let vm_heartbeat_timeout: u64 = match env::var("VM_HEARTBEAT_TIMEOUT_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_HEARTBEAT_TIMEOUT_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_HEARTBEAT_TIMEOUT_SECS,
+    };
+    let janitor_interval: u64 = match env::var("JANITOR_INTERVAL_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("JANITOR_INTERVAL_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_JANITOR_INTERVAL_SECS,
+    };
+    let janitor_stale_dir_secs: u64 = match env::var("JANITOR_STALE_DIR_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("JANITOR_STALE_DIR_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_STALE_DIR_SECS,
+    };
+    let upload_ttl_secs: u64 = match env::var("UPLOAD_TTL_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("UPLOAD_TTL_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_UPLOAD_TTL_SECS,
+    };
+    let pcap_max_bytes: u64 = match env::var("PCAP_MAX_BYTES") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("PCAP_MAX_BYTES env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_PCAP_MAX_BYTES,
+    };
+    let pcap_max_duration_secs: u64 = match env::var("PCAP_MAX_DURATION_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("PCAP_MAX_DURATION_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_PCAP_MAX_DURATION_SECS,
+    };
+    let vm_log_retention_secs: u64 = match env::var("VM_LOG_RETENTION_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VM_LOG_RETENTION_SECS env variable is invalid: {}", e),
+            )
+        })?,
+        Err(_) => DEFAULT_LOG_RETENTION_SECS,
+    };
+
+    let triggers_config_path = PathBuf::from(
+        env::var("TRIGGERS_CONFIG_PATH").unwrap_or_else(|_| "./config/triggers.toml".to_string()),
+    );
+    let bindings = load_bindings(&triggers_config_path).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Failed to load triggers from {}: {}",
+                triggers_config_path.display(),
+                e
+            ),
+        )
+    })?;
+    let mut http_triggers = HashMap::new();
+    let mut triggers = Vec::new();
+    for binding in &bindings {
+        if let Binding::Http { function, response } = binding {
+            http_triggers.insert(function.clone(), *response);
+            continue;
+        }
+        let trigger = trigger_for(binding, &blob_store).await.map_err(|e| {
+            std::io::Error::other(format!(
+                "Failed to set up trigger of function {}: {}",
+                binding.function(),
+                e
+            ))
+        })?;
+        triggers.extend(trigger);
+    }
+    if !bindings.is_empty() {
+        info!(
+            "Loaded {} triggers from {}",
+            bindings.len(),
+            triggers_config_path.display()
+        );
+    }
+
+    let publishers_config_path = PathBuf::from(
+        env::var("PUBLISHERS_CONFIG_PATH")
+            .unwrap_or_else(|_| "./config/publishers.toml".to_string()),
+    );
+    let publisher_bindings = load_publishers(&publishers_config_path).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Failed to load publishers from {}: {}",
+                publishers_config_path.display(),
+                e
+            ),
+        )
+    })?;
+    if !publisher_bindings.is_empty() {
+        info!(
+            "Loaded {} publishers from {}",
+            publisher_bindings.len(),
+            publishers_config_path.display()
+        );
+    }
+
+    #[cfg(feature = "process-executor")]
+    let process_executor = (!run_vms).then(|| {
+        let work_dir =
+            env::var("PROCESS_WORK_DIR").unwrap_or_else(|_| "./tmp/processes".to_string());
+        let namespaces = env::var("PROCESS_NAMESPACES")
+            .map(|v| {
+                let normalized = v.trim().to_ascii_lowercase();
+                matches!(normalized.as_str(), "1" | "true" | "yes" | "on")
+            })
+            .unwrap_or(true);
+        info!("Running jobs in host processes, not VMs: only run trusted code");
+        ProcessExecutor::new(
+            PathBuf::from(&agent_binary),
+            PathBuf::from(work_dir),
+            namespaces,
+        )
+    });
+
+    // Jobs of the previous run. VMMs ran in its process and died with it, so the
+    // jobs it left pending or running cannot finish: they are interrupted.
+    let jobs_journal_path =
+        env::var("JOBS_JOURNAL_PATH").unwrap_or_else(|_| "./tmp/jobs.jsonl".to_string());
+    if let Some(parent) = PathBuf::from(&jobs_journal_path).parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let journal = JobJournal::new(&jobs_journal_path)
+        .map_err(|e| std::io::Error::other(format!("Failed to initialize job journal: {}", e)))?;
+    let recovery = journal.recover().map_err(|e| {
+        std::io::Error::other(format!(
+            "Failed to recover jobs from {}: {}",
+            jobs_journal_path, e
+        ))
+    })?;
+    for entry in &recovery.interrupted {
+        warn!(
+            "Job {} – interrupted by the restart, it was {}",
+            entry.id, entry.status
+        );
+        // An agent process outlives the backend, but the request it answers does not.
+        #[cfg(feature = "process-executor")]
+        if let Some(pid) = entry
+            .agent_pid
+            .filter(|&pid| stop_leftover_agent(std::path::Path::new(&agent_binary), pid))
+        {
+            info!("Job {} – stopped its agent process {}", entry.id, pid);
+        }
+    }
+    if !recovery.jobs.is_empty() {
+        info!(
+            "Recovered {} jobs from {}, {} of them interrupted",
+            recovery.jobs.len(),
+            jobs_journal_path,
+            recovery.interrupted.len()
+        );
+    }
+    let (jobs, logs): (HashMap<_, _>, HashMap<_, _>) = recovery
+        .jobs
+        .into_iter()
+        .map(|entry| {
+            let log_id = entry.id.clone();
+            let (job, log) = recovered_job(entry);
+            ((job.id.clone(), job), (log_id, log))
+        })
+        .unzip();
+
+    // Snapshots of templates being baked, and working directories of jobs run in processes.
+    #[allow(unused_mut)]
+    let mut scratch_dirs = vec![templates_dir.clone()];
+    #[cfg(feature = "process-executor")]
+    scratch_dirs.extend(
+        process_executor
+            .iter()
+            .map(|executor| executor.work_dir().to_path_buf()),
+    );
+
+    // Host processes are not VMs the pressure could defer.
+    let pressure = (run_vms && admission_max_wait > 0).then(|| {
+        Arc::new(HostPressure::new(
+            "/proc",
+            PressureLimits {
+                cpu: host_cpu_pressure_limit,
+                memory: host_memory_pressure_limit,
+                min_available_bytes: host_min_available_memory_mb * 1024 * 1024,
+            },
+        ))
+    });
+
+    let readiness = ReadinessChecks {
+        vms: run_vms,
+        kvm_device: PathBuf::from("/dev/kvm"),
+        kernel_path: PathBuf::from(&vm_kernel_path),
+        bridge_name: bridge_name.clone(),
+        ip_range,
+        ip_mask,
+        build_dir: PathBuf::from(&vm_initramfs_dir),
+        min_free_disk_bytes,
+        blobs: Arc::clone(&blob_store),
+        pressure: pressure.clone(),
+    };
+
+    let images = Arc::new(ImageDigests::new());
+    let state = Arc::new(AppState {
+        jobs: RwLock::new(jobs),
+        journal: Arc::new(journal),
+        consoles: RwLock::new(HashMap::new()),
+        vcpu_hotplugs: RwLock::new(HashMap::new()),
+        memory_resizes: RwLock::new(HashMap::new()),
+        taps: RwLock::new(HashMap::new()),
+        pcap_max_bytes,
+        pcap_max_duration: std::time::Duration::from_secs(pcap_max_duration_secs),
+        logs: RwLock::new(logs),
+        client,
+        config,
+        vm_config: VmConfig {
+            kernel_path: PathBuf::from(vm_kernel_path),
+            initramfs_dir: PathBuf::from(&vm_initramfs_dir),
+            bridge_name: Some(bridge_name.clone()),
+            tenant: None,
+            vcpus: 1,
+            memory_mb: 512,
+            log_guest_console: vm_log_guest_console,
+            serial_log,
+            cmdline_extra: vm_kernel_cmdline,
+            acpi: vm_acpi,
+            max_vcpus: vm_max_vcpus,
+            max_memory_mb: vm_max_memory_mb,
+            traffic_accounting: vm_traffic_accounting,
+            mtu: Some(vm_mtu),
+            merge_memory: false,
+            redactor: Redactor::default(),
+        },
+        ip_manager,
+        audit_log,
+        audit_writer,
+        artifact_store,
+        artifact_uploads,
+        templates: Arc::clone(&templates),
+        template_clones: (template_prebake && run_vms).then(|| {
+            TemplateClones::new(
+                Arc::clone(&templates),
+                Arc::clone(&blob_store),
+                template_cache_dir,
+            )
+        }),
+        functions,
+        readiness,
+        result_cache: ResultCache::new(
+            result_cache_max_entries,
+            std::time::Duration::from_secs(result_cache_ttl),
+        ),
+        compile_cache: CompileCache::new(
+            Arc::clone(&blob_store),
+            Arc::clone(&images),
+            compile_cache_max_bytes,
+        ),
+        images,
+        host_builder,
+        vm_arch,
+        job_pids_max,
+        job_cpu: CpuPolicy {
+            burst: std::time::Duration::from_millis(job_cpu_burst_ms),
+            share_percent: job_cpu_share_percent,
+        },
+        core_dumps: CoreDumps::new(Arc::clone(&blob_store), core_dump_max_bytes),
+        pipelines: Pipelines::new(Arc::clone(&blob_store)),
+        vm_pool: {
+            let pool = VmPool::new(
+                vm_pool_max_idle,
+                std::time::Duration::from_secs(vm_pool_idle_secs),
+            );
+            let pool = match vm_pool_cold_after_secs {
+                0 => pool,
+                secs => pool.with_cold_after(std::time::Duration::from_secs(secs)),
+            };
+            // Suspended VMs are restored from a snapshot, which VMs run in host
+            // processes have none of.
+            match vm_pool_suspend_after_secs {
+                secs if secs > 0 && vm_pool_max_suspended > 0 && run_vms => {
+                    pool.with_suspend_after(std::time::Duration::from_secs(secs))
+                }
+                _ => pool,
+            }
+        },
+        vm_pool_scrub,
+        suspended_vms: VmPool::new(
+            vm_pool_max_suspended,
+            std::time::Duration::from_secs(vm_pool_suspended_idle_secs),
+        ),
+        suspended_vms_dir,
+        shared_vms: SharedVms::new(function_max_jobs_per_vm),
+        forecaster: Forecaster::new(prewarm_aggressiveness),
+        dashboards,
+        prewarm_max_vms,
+        pressure: pressure.clone(),
+        admission_max_wait: std::time::Duration::from_secs(admission_max_wait),
+        ksm: run_vms.then(|| Ksm::new(KSM_DIR)),
+        heartbeats: Heartbeats::new(
+            std::time::Duration::from_secs(vm_heartbeat_interval.max(1)),
+            std::time::Duration::from_secs(vm_heartbeat_timeout),
+        ),
+        events: EventBus::new(),
+        webhooks,
+        api_keys,
+        identity,
+        egress_proxy,
+        janitor: Janitor::new(),
+        metrics: Metrics::new(),
+        http_triggers,
+        #[cfg(feature = "process-executor")]
+        process_executor,
+    });
+
+    apply_ksm(&state, &state.config.current().ksm);
+
+    // Background tasks: one per trigger watching its source for events.
+    let invoker: Arc<dyn Invoker> = Arc::new(StateInvoker(Arc::clone(&state)));
+    for trigger in triggers {
+        tokio::spawn(trigger.run(Arc::clone(&invoker)));
+    }
+
+    // Background tasks: one per webhook, POSTing it the lifecycle events.
+    for webhook in &state.webhooks {
+        info!(
+            "Publishing lifecycle events to {}{}",
+            webhook.url(),
+            if webhook_secret.is_some() {
+                ", signed"
+            } else {
+                ""
+            }
+        );
+        webhooks::spawn(Arc::clone(webhook), &state.events, state.client.clone());
+    }
+
+    // Background task: publish the results of functions bound to message queues.
+    let publishers = Publishers::start(&publisher_bindings);
+    if !publishers.is_empty() {
+        let publish_state = Arc::clone(&state);
+        let mut events = state.events.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Publishers fell behind, {} events dropped", missed);
+                        continue;
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                };
+                if !matches!(
+                    event.kind,
+                    EventKind::ExecutionFinished | EventKind::ExecutionFailed
+                ) {
+                    continue;
                 }
-                error!("Job {} – execution failed: {}", job_id, e);
+                let Some(job_id) = &event.job_id else {
+                    continue;
+                };
+                let completed = publish_state
+                    .jobs
+                    .read()
+                    .await
+                    .get(job_id)
+                    .and_then(|job| job.completed(event.timestamp_ms));
+                if let Some(completed) = completed.filter(|c| publishers.has(&c.function)) {
+                    publishers.publish(completed);
+                }
+            }
+        });
+    }
+
+    // Background task: record how invocations of functions start and end, for
+    // the dashboards.
+    let dashboard_state = Arc::clone(&state);
+    let mut events = state.events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Dashboards fell behind, {} events dropped", missed);
+                    continue;
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+            };
+            if let Some(job_id) = &event.job_id {
+                record_invocation(&dashboard_state, &event, job_id).await;
             }
         }
+    });
 
-        // Teardown after job state is finalized so polling clients are never stuck in "running"
-        // if VM shutdown blocks longer than expected.
-        drop(jobs);
-        vm.destroy().await;
+    // Background task: roll up the invocations that left the raw window of the
+    // dashboards, forget those past their retention, and save the rest.
+    let rollup_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let rolled = rollup_state.dashboards.roll_up(unix_millis());
+            if rolled > 0 {
+                info!("Rolled up {} invocations for the dashboards", rolled);
+            }
+            if let Err(e) = rollup_state.dashboards.save() {
+                warn!("Cannot save the dashboards: {}", e);
+            }
+        }
+    });
+
+    // Background task: shut down pooled VMs left idle for too long, suspend to disk
+    // or page out the memory of those idle for long enough.
+    let pool_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        // The VMs of the snapshots an earlier run left are gone.
+        let _ = tokio::fs::remove_dir_all(&pool_state.suspended_vms_dir).await;
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            for vm in pool_state.vm_pool.expire() {
+                shut_down_vm(&pool_state, vm, None).await;
+            }
+            for vm in pool_state.suspended_vms.expire() {
+                pool_state
+                    .events
+                    .vm(EventKind::VmDestroyed, &vm.vm_id, None);
+            }
+            for (key, vm) in pool_state.vm_pool.suspend(VmHandle::can_suspend) {
+                suspend_vm(&pool_state, key, vm).await;
+            }
+            for (vm_id, paging) in pool_state
+                .vm_pool
+                .cool(|vm| (vm.vm_id.clone(), vm.paging().clone()))
+            {
+                let paged_out = tokio::task::spawn_blocking(move || paging.page_out())
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|result| result.map_err(|e| e.to_string()));
+                match paged_out {
+                    Ok(()) => info!("Idle VM {} went cold, its memory paged out", vm_id),
+                    Err(e) => warn!("Cannot page out the memory of idle VM {}: {}", vm_id, e),
+                }
+            }
+        }
+    });
+
+    // Background task: while memory is short, shut down idle pooled VMs to give the
+    // host its memory back.
+    if let Some(pressure) = pressure {
+        let relief_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RELIEF_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(short) = pressure.check().filter(Pressure::is_memory) else {
+                    continue;
+                };
+                if let Some(vm) = relief_state.vm_pool.shed() {
+                    warn!("Host under {}: shutting down idle VM {}", short, vm.vm_id);
+                    shut_down_vm(&relief_state, vm, None).await;
+                }
+            }
+        });
+    }
+
+    // Background task: boot VMs ahead of the invocations functions are expected to get.
+    if prewarms_functions(&state) {
+        info!(
+            "Pre-warming function VMs every {}s (aggressiveness {}, at most {} VMs)",
+            prewarm_interval, prewarm_aggressiveness, prewarm_max_vms
+        );
+        let prewarm_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(std::time::Duration::from_secs(prewarm_interval.max(1)));
+            loop {
+                interval.tick().await;
+                prewarm_functions(&prewarm_state).await;
+            }
+        });
+    }
+
+    // Background task: clean up what VMs and jobs left behind on the host.
+    if janitor_interval > 0 {
+        let janitor_state = Arc::clone(&state);
+        let stale_after = std::time::Duration::from_secs(janitor_stale_dir_secs);
+        let upload_ttl = std::time::Duration::from_secs(upload_ttl_secs);
+        let log_retention = (vm_log_retention_secs > 0)
+            .then(|| std::time::Duration::from_secs(vm_log_retention_secs));
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(std::time::Duration::from_secs(janitor_interval));
+            loop {
+                interval.tick().await;
+                sweep_host(
+                    &janitor_state,
+                    run_vms,
+                    &scratch_dirs,
+                    stale_after,
+                    upload_ttl,
+                    log_retention,
+                )
+                .await;
+            }
+        });
+    }
+
+    // Background task: keep a warm snapshot of every runtime, baked again when its base image changes.
+    let baker = (template_prebake && run_vms).then(|| {
+        Arc::new(TemplateBaker::new(
+            state.vm_config.clone(),
+            Arc::clone(&state.ip_manager),
+            Arc::clone(&blob_store),
+            Arc::clone(&templates),
+            templates_dir,
+            state.client.clone(),
+        ))
     });
+    if let Some(baker) = &baker {
+        // Runtimes built on the host have no toolchain image to boot.
+        Arc::clone(baker).spawn(
+            vm_languages
+                .iter()
+                .filter(|language| language.build == BuildSite::Vm)
+                .cloned()
+                .collect(),
+            std::time::Duration::from_secs(template_refresh_interval.max(1)),
+        );
+    }
 
-    (StatusCode::ACCEPTED, Json(RunResponse { id })).into_response()
+    // Background task: move runtimes to the version their manifest upgrades them to.
+    let runtime_upgrade_interval: u64 = match env::var("RUNTIME_UPGRADE_INTERVAL_SECS") {
+        Ok(v) => v.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "RUNTIME_UPGRADE_INTERVAL_SECS env variable is invalid: {}",
+                    e
+                ),
+            )
+        })?,
+        Err(_) => 3600,
+    };
+    if run_vms {
+        Arc::new(RuntimeUpgrader::new(
+            runtime_versions,
+            vm_initramfs_dir,
+            agent_binary,
+            init_script,
+            templates,
+            baker,
+        ))
+        .spawn(
+            vm_languages.clone(),
+            std::time::Duration::from_secs(runtime_upgrade_interval.max(1)),
+        );
+    }
+
+    // Background task: reload the configuration on SIGHUP.
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let reload_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading configuration");
+            let outcome = match reload_state.config.reload().await {
+                Ok(changes) => {
+                    apply_ksm(&reload_state, &reload_state.config.current().ksm);
+                    AuditEntry::new("sighup", "local", "config.reload", AuditOutcome::Accepted)
+                        .with_detail(changes.join("; "))
+                }
+                Err(e) => {
+                    error!("Configuration reload rejected: {}", e);
+                    AuditEntry::new("sighup", "local", "config.reload", AuditOutcome::Rejected)
+                        .with_detail(e.to_string())
+                }
+            };
+            record_audit(&reload_state, outcome);
+        }
+    });
+
+    // Background task: evict terminal jobs older than 5 mins to prevent unbounded memory growth.
+    const JOB_TTL: std::time::Duration = std::time::Duration::from_secs(300);
+    let cleanup_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let mut jobs = cleanup_state.jobs.write().await;
+            let expired: HashSet<String> = jobs
+                .iter()
+                .filter(|(_, j)| j.status.is_terminal() && j.created_at.elapsed() >= JOB_TTL)
+                .map(|(id, _)| id.clone())
+                .collect();
+            jobs.retain(|id, _| !expired.contains(id));
+            cleanup_state
+                .logs
+                .write()
+                .await
+                .retain(|id, _| jobs.contains_key(id));
+            drop(jobs);
+            if !expired.is_empty() {
+                info!("Evicted {} expired jobs", expired.len());
+            }
+            // Off the jobs lock, and by the ids evicted rather than those left:
+            // jobs submitted meanwhile are in the journal too.
+            let journal = Arc::clone(&cleanup_state.journal);
+            let pruned =
+                tokio::task::spawn_blocking(move || journal.retain(|id| !expired.contains(id)))
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|result| result.map_err(|e| e.to_string()));
+            if let Err(e) = pruned {
+                warn!("Cannot evict expired jobs from the journal: {}", e);
+            }
+            let removed = cleanup_state.pipelines.evict(JOB_TTL).await;
+            if removed > 0 {
+                info!("Evicted {} expired pipelines", removed);
+            }
+        }
+    });
+
+    let max_body_bytes = state.config.max_body_bytes();
+    // The API is served under its version, and at the paths from before versions
+    // until their sunset. Probes, metrics and function URLs have no version.
+    let v1 = api_v1(max_artifact_bytes).layer(middleware::from_fn_with_state(
+        Arc::clone(&state.api_keys),
+        access::authenticate,
+    ));
+    let router = Router::new()
+        .route("/", get(root))
+        .route("/health", get(livez))
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .route("/.well-known/jwks.json", get(identity_jwks))
+        .route("/f/{name}", any(invoke_http_function))
+        .route("/f/{name}/{*rest}", any(invoke_http_function))
+        .nest(
+            "/v1",
+            v1.clone().layer(middleware::from_fn_with_state(
+                "v1",
+                api_versions::tag_version,
+            )),
+        )
+        .merge(v1.layer(middleware::from_fn_with_state(
+            unversioned_deprecation,
+            api_versions::deprecate,
+        )))
+        .fallback(api_versions::not_found);
+    let audit_writer = state.audit_writer.clone();
+    let app = router
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .with_state(Arc::clone(&state))
+        .into_make_service_with_connect_info::<Peer>();
+
+    // Both listeners are bound before serving, so that either failing stops the start
+    let tcp_listener = match server_addr.as_str() {
+        "" => None,
+        addr => {
+            info!("Starting Backend server on {}", addr);
+            Some(TcpListener::bind(addr).await?)
+        }
+    };
+    let unix_listener = match &unix_socket {
+        Some(path) => {
+            info!(
+                "Serving the API on Unix socket {} (mode {:o})",
+                path.display(),
+                unix_socket_mode
+            );
+            Some(bind_unix(path, unix_socket_mode)?)
+        }
+        None => None,
+    };
+    let serve_tcp = async {
+        match tcp_listener {
+            Some(listener) => {
+                axum::serve(listener, app.clone())
+                    .with_graceful_shutdown(shutdown_signal())
+                    .await
+            }
+            None => Ok(()),
+        }
+    };
+    let serve_unix = async {
+        match unix_listener {
+            Some(listener) => {
+                axum::serve(listener, app.clone())
+                    .with_graceful_shutdown(shutdown_signal())
+                    .await
+            }
+            None => Ok(()),
+        }
+    };
+    let served = tokio::try_join!(serve_tcp, serve_unix);
+    // The invocations since the last rollup are saved with the others.
+    if let Err(e) = state.dashboards.save() {
+        warn!("Cannot save the dashboards: {}", e);
+    }
+    // Records still queued reach the disk before the process exits.
+    tokio::task::spawn_blocking(move || audit_writer.flush())
+        .await
+        .map_err(std::io::Error::other)?;
+    served?;
+
+    Ok(())
 }
 
-fn normalize_language_alias(input: &str) -> String {
-    match input {
-        "py" => "python".to_string(),
-        "js" | "javascript" => "node".to_string(),
-        "rs" => "rust".to_string(),
-        "golang" => "go".to_string(),
-        "c++" => "cpp".to_string(),
-        _ => input.to_string(),
+/// Resolves on SIGINT or SIGTERM, for the listeners to stop accepting requests.
+async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
     }
+    info!("Shutting down");
 }
 
-// ── GET /status/:id  –  query job result ────────────────────────────
-
-async fn get_status(
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<String>,
-) -> impl IntoResponse {
-    let jobs = state.jobs.read().await;
-
-    match jobs.get(&id) {
-        Some(job) => (
-            StatusCode::OK,
-            Json(serde_json::json!({
-                "id": job.id,
-                "status": job.status,
-                "exit_code": job.exit_code,
-                "stdout": job.stdout,
-                "stderr": job.stderr,
-            })),
-        ),
-        None => (
-            StatusCode::NOT_FOUND,
-            Json(serde_json::json!({
-                "error": format!("Job {id} not found"),
-            })),
+/// Endpoints of version 1 of the API. A later version gets a function of its own,
+/// reusing the handlers that did not change.
+fn api_v1(max_artifact_bytes: u64) -> Router<Arc<AppState>> {
+    use Permission::{Admin, Deploy, Invoke, Read};
+    let router = Router::new()
+        .route("/run", allow(Invoke, post(run_job)))
+        .route("/status/{id}", allow(Read, get(get_status)))
+        .route("/console/{id}", allow(Invoke, get(attach_console)))
+        .route("/logs/{id}", allow(Read, get(get_logs)))
+        .route("/logs/{id}/vm", allow(Read, get(get_vm_log)))
+        .route("/core/{id}", allow(Read, get(get_core_dump)))
+        .route("/executions", allow(Read, get(list_executions)))
+        .route(
+            "/executions/{a}/diff/{b}",
+            allow(Read, get(diff_executions)),
+        )
+        .route("/vcpus/{id}", allow(Invoke, put(resize_vcpus)))
+        .route("/events", allow(Read, get(stream_events)))
+        .route("/vms", allow(Read, get(list_vms)))
+        .route("/vms/{id}", allow(Invoke, patch(update_vm)))
+        .route("/vms/{id}/pcap", allow(Invoke, get(capture_vm_packets)))
+        .route("/channels", allow(Invoke, post(open_channel)))
+        .route("/audit", allow(Admin, get(export_audit_log)))
+        .route("/templates", allow(Read, get(list_templates)))
+        .route("/runtimes", allow(Read, get(list_runtimes)))
+        .route("/cache", allow(Read, get(cache_stats)))
+        .route(
+            "/dashboard/functions",
+            allow(Read, get(function_dashboards)),
+        )
+        .route(
+            "/dashboard/functions/{name}",
+            allow(Read, get(function_dashboard)),
+        )
+        .route("/janitor", allow(Read, get(janitor_stats)))
+        .route("/functions", allow(Read, get(list_functions)))
+        .route(
+            "/functions/{name}",
+            allow(Deploy, put(deploy_function)).merge(allow(Read, get(get_function))),
+        )
+        .route("/functions/{name}/run", allow(Invoke, post(run_function)))
+        .route("/apply", allow(Deploy, post(apply_functions)))
+        .route("/pipelines", allow(Invoke, post(run_pipeline)))
+        .route("/pipelines/{id}", allow(Read, get(get_pipeline)))
+        .route(
+            "/pipelines/{id}/stages/{stage}/output",
+            allow(Read, get(get_stage_output)),
+        )
+        .route("/export", allow(Deploy, get(export_bundle)))
+        .route(
+            "/import",
+            allow(
+                Deploy,
+                post(import_bundle).layer(DefaultBodyLimit::max(max_artifact_bytes as usize)),
+            ),
+        )
+        .route("/admin/reload", allow(Admin, post(reload_config)))
+        .route("/admin/webhooks", allow(Admin, get(webhook_status)))
+        .route(
+            "/admin/keys",
+            allow(Admin, get(list_api_keys).post(create_api_key)),
+        )
+        .route(
+            "/admin/keys/{id}",
+            allow(Admin, put(assign_api_key_role).delete(revoke_api_key)),
+        )
+        .route(
+            "/artifacts",
+            allow(
+                Deploy,
+                post(upload_artifact).layer(DefaultBodyLimit::max(max_artifact_bytes as usize)),
+            ),
+        )
+        .route(
+            "/artifacts/uploads",
+            allow(Deploy, post(create_artifact_upload)),
+        )
+        .route(
+            "/artifacts/uploads/{id}",
+            allow(
+                Deploy,
+                get(get_artifact_upload)
+                    .patch(append_artifact_upload)
+                    .layer(DefaultBodyLimit::max(max_artifact_bytes as usize)),
+            ),
+        );
+    #[cfg(feature = "chaos")]
+    let router = router.route(
+        "/admin/chaos",
+        allow(
+            Admin,
+            get(chaos::list_faults)
+                .put(chaos::set_faults)
+                .delete(chaos::clear_faults),
         ),
-    }
+    );
+    router
+}
+
+/// `route`, for the callers whose key has `permission`. Every route of the API
+/// says who may call it.
+fn allow(
+    permission: Permission,
+    route: MethodRouter<Arc<AppState>>,
+) -> MethodRouter<Arc<AppState>> {
+    route.route_layer(middleware::from_fn_with_state(permission, access::require))
 }
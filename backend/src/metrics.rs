@@ -0,0 +1,380 @@
+//! Metrics of the backend for Prometheus, served on `GET /metrics` in the text
+//! exposition format.
+//!
+//! They cover the network traffic of VMs, counted on their TAP device: for each
+//! VM running a job, since the job started, as of its last heartbeat; and in
+//! total over the jobs that finished since the backend started. And for each
+//! bucket of the VM pool, its requests, hits, idle VMs and scrubs. And, when the host
+//! merges the memory of VMs, the pages kernel samepage merging shares.
+
+use crate::ksm::KsmStats;
+use crate::vm_pool::BucketStats;
+use cloude_types::{TrafficStats, VmInfo};
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// `Content-Type` of [`Metrics::render`].
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Traffic of the VMs of the jobs that finished.
+    finished_traffic: Mutex<TrafficStats>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the traffic of the VM of a job that finished to the totals.
+    pub fn record_job_traffic(&self, traffic: &TrafficStats) {
+        let mut total = self
+            .finished_traffic
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        total.rx_bytes += traffic.rx_bytes;
+        total.rx_packets += traffic.rx_packets;
+        total.tx_bytes += traffic.tx_bytes;
+        total.tx_packets += traffic.tx_packets;
+    }
+
+    /// Every metric, with the traffic of `vms`, the VMs running a job, the
+    /// buckets of the VM pool and, if the host merges memory, `ksm`.
+    pub fn render(&self, vms: &[VmInfo], pool: &[BucketStats], ksm: Option<&KsmStats>) -> String {
+        let total = *self
+            .finished_traffic
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+        write_traffic(&mut out, "bytes", vms, &total, |t| (t.rx_bytes, t.tx_bytes));
+        write_traffic(&mut out, "packets", vms, &total, |t| {
+            (t.rx_packets, t.tx_packets)
+        });
+        write_pool(&mut out, pool);
+        if let Some(ksm) = ksm {
+            write_ksm(&mut out, ksm);
+        }
+        out
+    }
+}
+
+/// Pages kernel samepage merging shares, and its scans.
+fn write_ksm(out: &mut String, ksm: &KsmStats) {
+    for (name, kind, help, value) in [
+        (
+            "cloude_ksm_pages_shared",
+            "gauge",
+            "Shared pages of merged memory in use.",
+            ksm.pages_shared,
+        ),
+        (
+            "cloude_ksm_pages_sharing",
+            "gauge",
+            "Pages of merged memory mapped to a shared page, that is pages saved.",
+            ksm.pages_sharing,
+        ),
+        (
+            "cloude_ksm_pages_unshared",
+            "gauge",
+            "Pages of mergeable memory unique so far.",
+            ksm.pages_unshared,
+        ),
+        (
+            "cloude_ksm_pages_volatile",
+            "gauge",
+            "Pages of mergeable memory changing too fast to be merged.",
+            ksm.pages_volatile,
+        ),
+        (
+            "cloude_ksm_full_scans_total",
+            "counter",
+            "Scans of all mergeable memory done.",
+            ksm.full_scans,
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        let _ = writeln!(out, "{name} {value}");
+    }
+}
+
+/// Requests, hits, hit ratio, idle VMs, target and scrubs of each bucket of the VM pool.
+fn write_pool(out: &mut String, pool: &[BucketStats]) {
+    write_bucket_series(
+        out,
+        pool,
+        "cloude_vm_pool_requests_total",
+        "counter",
+        "pooled-vm jobs that asked for a VM of the runtime and shape.",
+        |s| Some(s.requests.to_string()),
+    );
+    write_bucket_series(
+        out,
+        pool,
+        "cloude_vm_pool_hits_total",
+        "counter",
+        "pooled-vm jobs of the runtime and shape that got an idle VM, of that shape or larger.",
+        |s| Some(s.hits.to_string()),
+    );
+    write_bucket_series(
+        out,
+        pool,
+        "cloude_vm_pool_hit_ratio",
+        "gauge",
+        "Share of the pooled-vm jobs of the runtime and shape that got an idle VM.",
+        |s| (s.requests > 0).then(|| (s.hits as f64 / s.requests as f64).to_string()),
+    );
+    write_bucket_series(
+        out,
+        pool,
+        "cloude_vm_pool_idle_vms",
+        "gauge",
+        "Idle VMs of the runtime and shape.",
+        |s| Some(s.idle.to_string()),
+    );
+    write_bucket_series(
+        out,
+        pool,
+        "cloude_vm_pool_target_vms",
+        "gauge",
+        "Idle VMs of the runtime and shape kept when the pool is full, from the recent demand.",
+        |s| Some(s.target.to_string()),
+    );
+    write_bucket_series(
+        out,
+        pool,
+        "cloude_vm_pool_speculative_vms",
+        "gauge",
+        "Idle VMs of the runtime and shape booted ahead of forecast function invocations.",
+        |s| Some(s.speculative.to_string()),
+    );
+    write_bucket_series(
+        out,
+        pool,
+        "cloude_vm_pool_cold_vms",
+        "gauge",
+        "Idle VMs of the runtime and shape whose memory was paged out.",
+        |s| Some(s.cold.to_string()),
+    );
+    write_bucket_series(
+        out,
+        pool,
+        "cloude_vm_pool_scrubs_total",
+        "counter",
+        "VMs of the runtime and shape scrubbed after their job.",
+        |s| Some(s.scrubs.to_string()),
+    );
+    write_bucket_series(
+        out,
+        pool,
+        "cloude_vm_pool_scrub_seconds_total",
+        "counter",
+        "Time the scrubs of VMs of the runtime and shape took.",
+        |s| Some(s.scrub_time.as_secs_f64().to_string()),
+    );
+}
+
+/// The metric `name`, of type `kind`, of each bucket of `pool` that `value` has one for.
+fn write_bucket_series(
+    out: &mut String,
+    pool: &[BucketStats],
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&BucketStats) -> Option<String>,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for stats in pool {
+        if let Some(value) = value(stats) {
+            let _ = writeln!(
+                out,
+                "{name}{{runtime=\"{}\",vcpus=\"{}\",memory_mb=\"{}\"}} {value}",
+                escape(&stats.bucket.language),
+                stats.bucket.vcpus,
+                stats.bucket.memory_mb
+            );
+        }
+    }
+}
+
+/// The `unit` of traffic `pick` takes, received and sent, of `vms` then `total`.
+fn write_traffic(
+    out: &mut String,
+    unit: &str,
+    vms: &[VmInfo],
+    total: &TrafficStats,
+    pick: impl Fn(&TrafficStats) -> (u64, u64),
+) {
+    let _ = writeln!(
+        out,
+        "# HELP cloude_vm_network_{unit}_total Network {unit} of the VM of a running job since the job started."
+    );
+    let _ = writeln!(out, "# TYPE cloude_vm_network_{unit}_total counter");
+    // A VM whose traffic is not counted has no series rather than zeroes.
+    for vm in vms {
+        let Some(traffic) = &vm.traffic else {
+            continue;
+        };
+        let (rx, tx) = pick(traffic);
+        for (direction, value) in [("rx", rx), ("tx", tx)] {
+            let _ = writeln!(
+                out,
+                "cloude_vm_network_{unit}_total{{job_id=\"{}\",vm_id=\"{}\",direction=\"{direction}\"}} {value}",
+                escape(&vm.id),
+                escape(&vm.vm_id),
+            );
+        }
+    }
+
+    let (rx, tx) = pick(total);
+    let _ = writeln!(
+        out,
+        "# HELP cloude_network_{unit}_total Network {unit} of the VMs of the jobs that finished."
+    );
+    let _ = writeln!(out, "# TYPE cloude_network_{unit}_total counter");
+    let _ = writeln!(out, "cloude_network_{unit}_total{{direction=\"rx\"}} {rx}");
+    let _ = writeln!(out, "cloude_network_{unit}_total{{direction=\"tx\"}} {tx}");
+}
+
+/// `value` as a label value: backslashes, quotes and newlines escaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm_pool::Bucket;
+    use cloude_types::VmLiveness;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        let job = TrafficStats {
+            rx_bytes: 1000,
+            rx_packets: 10,
+            tx_bytes: 200,
+            tx_packets: 2,
+        };
+        metrics.record_job_traffic(&job);
+        metrics.record_job_traffic(&job);
+        let vms = [
+            VmInfo {
+                id: "job-1".to_string(),
+                vm_id: "vm-1".to_string(),
+                ip: "10.39.1.2".to_string(),
+                liveness: VmLiveness::Running,
+                last_heartbeat_ms: 0,
+                traffic: Some(job),
+            },
+            VmInfo {
+                id: "job-2".to_string(),
+                vm_id: "vm-2".to_string(),
+                ip: "10.39.1.3".to_string(),
+                liveness: VmLiveness::Running,
+                last_heartbeat_ms: 0,
+                traffic: None,
+            },
+        ];
+
+        let text = metrics.render(&vms, &[], None);
+        let samples: Vec<&str> = text.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            samples,
+            [
+                r#"cloude_vm_network_bytes_total{job_id="job-1",vm_id="vm-1",direction="rx"} 1000"#,
+                r#"cloude_vm_network_bytes_total{job_id="job-1",vm_id="vm-1",direction="tx"} 200"#,
+                r#"cloude_network_bytes_total{direction="rx"} 2000"#,
+                r#"cloude_network_bytes_total{direction="tx"} 400"#,
+                r#"cloude_vm_network_packets_total{job_id="job-1",vm_id="vm-1",direction="rx"} 10"#,
+                r#"cloude_vm_network_packets_total{job_id="job-1",vm_id="vm-1",direction="tx"} 2"#,
+                r#"cloude_network_packets_total{direction="rx"} 20"#,
+                r#"cloude_network_packets_total{direction="tx"} 4"#,
+            ]
+        );
+        assert!(text.contains("# TYPE cloude_network_bytes_total counter\n"));
+        assert_eq!(escape("a\"b\\c\nd"), r#"a\"b\\c\nd"#);
+    }
+
+    #[test]
+    fn test_render_pool() {
+        let bucket = |memory_mb| Bucket {
+            language: "python".to_string(),
+            vcpus: 1,
+            memory_mb,
+        };
+        let pool = [
+            BucketStats {
+                bucket: bucket(512),
+                idle: 1,
+                target: 3,
+                requests: 4,
+                hits: 3,
+                speculative: 0,
+                cold: 1,
+                scrubs: 2,
+                scrub_time: std::time::Duration::from_millis(1500),
+            },
+            BucketStats {
+                bucket: bucket(1024),
+                idle: 1,
+                target: 0,
+                requests: 0,
+                hits: 0,
+                speculative: 1,
+                cold: 0,
+                scrubs: 0,
+                scrub_time: std::time::Duration::ZERO,
+            },
+        ];
+        let text = Metrics::new().render(&[], &pool, None);
+        let samples: Vec<&str> = text
+            .lines()
+            .filter(|l| l.starts_with("cloude_vm_pool"))
+            .collect();
+        assert_eq!(
+            samples,
+            [
+                r#"cloude_vm_pool_requests_total{runtime="python",vcpus="1",memory_mb="512"} 4"#,
+                r#"cloude_vm_pool_requests_total{runtime="python",vcpus="1",memory_mb="1024"} 0"#,
+                r#"cloude_vm_pool_hits_total{runtime="python",vcpus="1",memory_mb="512"} 3"#,
+                r#"cloude_vm_pool_hits_total{runtime="python",vcpus="1",memory_mb="1024"} 0"#,
+                r#"cloude_vm_pool_hit_ratio{runtime="python",vcpus="1",memory_mb="512"} 0.75"#,
+                r#"cloude_vm_pool_idle_vms{runtime="python",vcpus="1",memory_mb="512"} 1"#,
+                r#"cloude_vm_pool_idle_vms{runtime="python",vcpus="1",memory_mb="1024"} 1"#,
+                r#"cloude_vm_pool_target_vms{runtime="python",vcpus="1",memory_mb="512"} 3"#,
+                r#"cloude_vm_pool_target_vms{runtime="python",vcpus="1",memory_mb="1024"} 0"#,
+                r#"cloude_vm_pool_speculative_vms{runtime="python",vcpus="1",memory_mb="512"} 0"#,
+                r#"cloude_vm_pool_speculative_vms{runtime="python",vcpus="1",memory_mb="1024"} 1"#,
+                r#"cloude_vm_pool_cold_vms{runtime="python",vcpus="1",memory_mb="512"} 1"#,
+                r#"cloude_vm_pool_cold_vms{runtime="python",vcpus="1",memory_mb="1024"} 0"#,
+                r#"cloude_vm_pool_scrubs_total{runtime="python",vcpus="1",memory_mb="512"} 2"#,
+                r#"cloude_vm_pool_scrubs_total{runtime="python",vcpus="1",memory_mb="1024"} 0"#,
+                r#"cloude_vm_pool_scrub_seconds_total{runtime="python",vcpus="1",memory_mb="512"} 1.5"#,
+                r#"cloude_vm_pool_scrub_seconds_total{runtime="python",vcpus="1",memory_mb="1024"} 0"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_ksm() {
+        let ksm = KsmStats {
+            pages_shared: 12,
+            pages_sharing: 340,
+            pages_unshared: 56,
+            pages_volatile: 7,
+            full_scans: 3,
+        };
+        let text = Metrics::new().render(&[], &[], Some(&ksm));
+        assert!(text.contains("\ncloude_ksm_pages_sharing 340\n"));
+        assert!(text.contains(
+            "# TYPE cloude_ksm_full_scans_total counter\ncloude_ksm_full_scans_total 3\n"
+        ));
+        assert!(!Metrics::new().render(&[], &[], None).contains("cloude_ksm"));
+    }
+}
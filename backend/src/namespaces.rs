@@ -0,0 +1,146 @@
+//! Namespaces, to keep the teams sharing a backend apart.
+//!
+//! Every request acts in the namespace of its `x-cloude-namespace` header, or
+//! in `default` without one. Functions are deployed in a namespace and jobs
+//! submitted in one, and a request only sees those of its own: the others
+//! answer `404`, as if they did not exist. Namespaces only separate what the
+//! API shows, they do not authenticate: without API keys anyone can name any
+//! namespace. With them, a request acts in the namespace of its key, see
+//! `access`.
+
+use crate::access::Caller;
+use crate::validation::{ValidationErrors, validate_identifier};
+use axum::Json;
+use axum::extract::FromRequestParts;
+use axum::http::StatusCode;
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use cloude_types::ErrorResponse;
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Header naming the namespace of a request.
+pub const NAMESPACE_HEADER: &str = "x-cloude-namespace";
+
+/// Namespace a request acts in.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Namespace(String);
+
+impl Namespace {
+    /// `name`, if it is a valid namespace: the same rules as job ids.
+    pub fn new(name: &str) -> Result<Self, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        validate_identifier(&mut errors, "namespace", name);
+        errors.into_result(Self(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.0 == DEFAULT_NAMESPACE
+    }
+
+    /// `name` made unique across namespaces, for what is shared by all of them
+    /// like the VM pool: `name` itself in the default namespace, so that keys
+    /// from before namespaces stay the same.
+    pub fn scope(&self, name: &str) -> String {
+        if self.is_default() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.0, name)
+        }
+    }
+
+    /// The namespace and name of a key made by [`Namespace::scope`].
+    pub fn unscope(key: &str) -> (Self, &str) {
+        match key.split_once('/') {
+            Some((namespace, name)) => (Self(namespace.to_string()), name),
+            None => (Self::default(), key),
+        }
+    }
+}
+
+impl Default for Namespace {
+    fn default() -> Self {
+        Self(DEFAULT_NAMESPACE.to_string())
+    }
+}
+
+impl std::fmt::Display for Namespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Namespace {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // The namespace of a key was validated when the key was created.
+        if let Some(namespace) = parts.extensions.get::<Caller>().and_then(Caller::namespace) {
+            return Ok(Self(namespace.to_string()));
+        }
+        let Some(value) = parts.headers.get(NAMESPACE_HEADER) else {
+            return Ok(Self::default());
+        };
+        let mut errors = ValidationErrors::default();
+        let namespace = match value.to_str() {
+            Ok(name) => Namespace::new(name.trim()),
+            Err(_) => {
+                errors.push("namespace", "must be ASCII");
+                Err(errors)
+            }
+        };
+        namespace.map_err(|errors| {
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(ErrorResponse {
+                    error: errors.to_string(),
+                    details: errors.errors,
+                }),
+            )
+                .into_response()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn extract(header: Option<&str>) -> Result<Namespace, StatusCode> {
+        let mut request = axum::http::Request::builder();
+        if let Some(header) = header {
+            request = request.header(NAMESPACE_HEADER, header);
+        }
+        let (mut parts, ()) = request.body(()).unwrap().into_parts();
+        Namespace::from_request_parts(&mut parts, &())
+            .await
+            .map_err(|response| response.status())
+    }
+
+    #[tokio::test]
+    async fn test_namespace_of_requests() {
+        assert_eq!(extract(None).await, Ok(Namespace::default()));
+        let team = extract(Some("team-a")).await.unwrap();
+        assert_eq!(team.as_str(), "team-a");
+        assert_eq!(
+            extract(Some("Team A")).await,
+            Err(StatusCode::UNPROCESSABLE_ENTITY)
+        );
+    }
+
+    #[test]
+    fn test_scope() {
+        assert_eq!(Namespace::default().scope("resize"), "resize");
+        assert_eq!(
+            Namespace::new("team-a").unwrap().scope("resize"),
+            "team-a/resize"
+        );
+        let (namespace, name) = Namespace::unscope("team-a/resize");
+        assert_eq!((namespace.as_str(), name), ("team-a", "resize"));
+        assert_eq!(Namespace::unscope("resize").0, Namespace::default());
+    }
+}
@@ -0,0 +1,430 @@
+//! Pipelines: deployed functions run as a DAG, the outputs of stages feeding
+//! the inputs of those that come after them.
+//!
+//! `POST /pipelines` names the stages and what each comes after. A stage starts
+//! once every stage it comes after is done, with their outputs, in the order it
+//! lists them, as standard input; stages that come after no other get the input
+//! of the pipeline. Stages that do not depend on each other run at once. A
+//! failed stage skips those that depend on it, the other branches run on.
+//!
+//! The output of each stage is kept in the [`BlobStore`] under
+//! `pipelines/<id>/<stage>` until the pipeline is evicted, like jobs are.
+
+use crate::blob_store::{BlobStore, BlobStoreError, PIPELINES_PREFIX};
+use crate::namespaces::Namespace;
+use crate::validation::{ValidationErrors, validate_identifier};
+use cloude_types::{JobStatus, PipelineRequest, PipelineStatus, StageResult, StageStatus};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Most stages a pipeline has.
+pub const MAX_STAGES: usize = 64;
+
+/// Errors of a pipeline whose stages cannot all run: duplicate names, unknown
+/// or cyclic dependencies.
+pub fn validate(request: &PipelineRequest) -> ValidationErrors {
+    let mut errors = ValidationErrors::default();
+    if request.stages.is_empty() {
+        errors.push("stages", "must not be empty");
+    }
+    if request.stages.len() > MAX_STAGES {
+        errors.push("stages", format!("must be at most {MAX_STAGES}"));
+    }
+
+    let mut names = HashSet::new();
+    for (i, stage) in request.stages.iter().enumerate() {
+        validate_identifier(&mut errors, &format!("stages[{i}].name"), &stage.name);
+        validate_identifier(
+            &mut errors,
+            &format!("stages[{i}].function"),
+            &stage.function,
+        );
+        if !names.insert(stage.name.as_str()) {
+            errors.push(
+                &format!("stages[{i}].name"),
+                format!("{} is in the pipeline twice", stage.name),
+            );
+        }
+    }
+
+    let mut cyclic = false;
+    for (i, stage) in request.stages.iter().enumerate() {
+        let mut seen = HashSet::new();
+        for before in &stage.after {
+            let field = format!("stages[{i}].after");
+            if *before == stage.name {
+                errors.push(&field, format!("{} cannot come after itself", stage.name));
+                cyclic = true;
+            } else if !names.contains(before.as_str()) {
+                errors.push(&field, format!("no stage named {before}"));
+            } else if !seen.insert(before.as_str()) {
+                errors.push(&field, format!("{before} is listed twice"));
+            }
+        }
+    }
+    if !cyclic && errors.is_empty() && order(request).is_none() {
+        errors.push("stages", "the stages come after each other in a cycle");
+    }
+    errors
+}
+
+/// Indexes of the stages of `request` in an order where each comes after those
+/// it depends on, `None` if they depend on each other in a cycle.
+fn order(request: &PipelineRequest) -> Option<Vec<usize>> {
+    let index: HashMap<&str, usize> = request
+        .stages
+        .iter()
+        .enumerate()
+        .map(|(i, stage)| (stage.name.as_str(), i))
+        .collect();
+    let mut waiting: Vec<usize> = request.stages.iter().map(|s| s.after.len()).collect();
+    let mut next: Vec<usize> = (0..waiting.len()).filter(|&i| waiting[i] == 0).collect();
+    let mut order = Vec::with_capacity(waiting.len());
+    while let Some(i) = next.pop() {
+        order.push(i);
+        for (j, stage) in request.stages.iter().enumerate() {
+            if stage
+                .after
+                .iter()
+                .any(|b| index.get(b.as_str()) == Some(&i))
+            {
+                waiting[j] -= 1;
+                if waiting[j] == 0 {
+                    next.push(j);
+                }
+            }
+        }
+    }
+    (order.len() == request.stages.len()).then_some(order)
+}
+
+/// Status of pipeline `id` before any of its stages started.
+pub fn pending(id: &str, request: &PipelineRequest) -> PipelineStatus {
+    PipelineStatus {
+        id: id.to_string(),
+        status: JobStatus::Pending,
+        stages: request
+            .stages
+            .iter()
+            .map(|stage| StageResult {
+                name: stage.name.clone(),
+                function: stage.function.clone(),
+                status: StageStatus::Pending,
+                job_id: None,
+                exit_code: None,
+                output_bytes: None,
+                error: None,
+            })
+            .collect(),
+    }
+}
+
+fn stage_status(request: &PipelineRequest, status: &PipelineStatus, name: &str) -> StageStatus {
+    request
+        .stages
+        .iter()
+        .position(|stage| stage.name == name)
+        .map_or(StageStatus::Error, |i| status.stages[i].status)
+}
+
+/// Indexes of the pending stages every stage they come after is done with.
+pub fn ready(request: &PipelineRequest, status: &PipelineStatus) -> Vec<usize> {
+    request
+        .stages
+        .iter()
+        .enumerate()
+        .filter(|(i, stage)| {
+            status.stages[*i].status == StageStatus::Pending
+                && stage
+                    .after
+                    .iter()
+                    .all(|before| stage_status(request, status, before) == StageStatus::Done)
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Skips the pending stages that come, directly or not, after a failed one.
+pub fn skip_dependents(request: &PipelineRequest, status: &mut PipelineStatus) {
+    loop {
+        let skipped: Vec<usize> = request
+            .stages
+            .iter()
+            .enumerate()
+            .filter(|(i, stage)| {
+                status.stages[*i].status == StageStatus::Pending
+                    && stage.after.iter().any(|before| {
+                        matches!(
+                            stage_status(request, status, before),
+                            StageStatus::Error | StageStatus::Skipped
+                        )
+                    })
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if skipped.is_empty() {
+            return;
+        }
+        for i in skipped {
+            status.stages[i].status = StageStatus::Skipped;
+        }
+    }
+}
+
+/// Sets the status of the pipeline from those of its stages: `done` once all
+/// are, `error` once one failed and none is left to run.
+pub fn settle(status: &mut PipelineStatus) {
+    let stages = || status.stages.iter().map(|stage| stage.status);
+    status.status = if stages().all(|s| s == StageStatus::Done) {
+        JobStatus::Done
+    } else if stages().any(|s| matches!(s, StageStatus::Pending | StageStatus::Running)) {
+        if stages().all(|s| s == StageStatus::Pending) {
+            JobStatus::Pending
+        } else {
+            JobStatus::Running
+        }
+    } else {
+        JobStatus::Error
+    };
+}
+
+struct Run {
+    namespace: Namespace,
+    status: PipelineStatus,
+    /// When the pipeline ended, for eviction.
+    finished_at: Option<Instant>,
+}
+
+/// Pipelines by id and the outputs of their stages.
+pub struct Pipelines {
+    blobs: Arc<dyn BlobStore>,
+    runs: Mutex<HashMap<String, Run>>,
+}
+
+impl Pipelines {
+    pub fn new(blobs: Arc<dyn BlobStore>) -> Self {
+        Self {
+            blobs,
+            runs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the status of a pipeline of `namespace`, replacing the previous one.
+    pub fn update(&self, namespace: &Namespace, status: &PipelineStatus) {
+        let finished_at = status.status.is_terminal().then(Instant::now);
+        self.runs.lock().unwrap().insert(
+            status.id.clone(),
+            Run {
+                namespace: namespace.clone(),
+                status: status.clone(),
+                finished_at,
+            },
+        );
+    }
+
+    /// Status of pipeline `id`, if `namespace` has it.
+    pub fn get(&self, namespace: &Namespace, id: &str) -> Option<PipelineStatus> {
+        let runs = self.runs.lock().unwrap();
+        runs.get(id)
+            .filter(|run| run.namespace == *namespace)
+            .map(|run| run.status.clone())
+    }
+
+    /// Keeps the output of stage `stage` of pipeline `id`.
+    pub async fn put_output(
+        &self,
+        id: &str,
+        stage: &str,
+        output: Vec<u8>,
+    ) -> Result<(), BlobStoreError> {
+        self.blobs.put(&output_key(id, stage), output).await
+    }
+
+    /// Output of stage `stage` of pipeline `id`, once it is done.
+    pub async fn output(&self, id: &str, stage: &str) -> Result<Option<Vec<u8>>, BlobStoreError> {
+        self.blobs.get(&output_key(id, stage)).await
+    }
+
+    /// Standard input of stage `index` of pipeline `id`: the input of the
+    /// pipeline if it comes after no stage, else the outputs of those it does.
+    pub async fn input(
+        &self,
+        id: &str,
+        request: &PipelineRequest,
+        index: usize,
+    ) -> Result<Option<String>, BlobStoreError> {
+        let stage = &request.stages[index];
+        if stage.after.is_empty() {
+            return Ok(request.input.clone());
+        }
+        let mut input = Vec::new();
+        for before in &stage.after {
+            input.extend(self.output(id, before).await?.unwrap_or_default());
+        }
+        Ok(Some(String::from_utf8_lossy(&input).into_owned()))
+    }
+
+    /// Forgets the pipelines that ended more than `ttl` ago, with the outputs
+    /// of their stages. Returns how many.
+    pub async fn evict(&self, ttl: Duration) -> usize {
+        let expired: Vec<String> = {
+            let mut runs = self.runs.lock().unwrap();
+            let expired: Vec<String> = runs
+                .iter()
+                .filter(|(_, run)| run.finished_at.is_some_and(|t| t.elapsed() > ttl))
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in &expired {
+                runs.remove(id);
+            }
+            expired
+        };
+        for id in &expired {
+            let keys = match self.blobs.list(&format!("{PIPELINES_PREFIX}{id}/")).await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    tracing::warn!("Cannot list the outputs of pipeline {}: {}", id, e);
+                    continue;
+                }
+            };
+            for key in keys {
+                if let Err(e) = self.blobs.delete(&key).await {
+                    tracing::warn!("Cannot delete {}: {}", key, e);
+                }
+            }
+        }
+        expired.len()
+    }
+}
+
+fn output_key(id: &str, stage: &str) -> String {
+    format!("{PIPELINES_PREFIX}{id}/{stage}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::LocalBlobStore;
+    use cloude_types::PipelineStage;
+    use tempfile::TempDir;
+
+    fn pipeline(stages: &[(&str, &[&str])]) -> PipelineRequest {
+        PipelineRequest {
+            stages: stages
+                .iter()
+                .map(|(name, after)| PipelineStage {
+                    name: name.to_string(),
+                    function: "f".to_string(),
+                    after: after.iter().map(|s| s.to_string()).collect(),
+                })
+                .collect(),
+            input: Some("in".to_string()),
+        }
+    }
+
+    fn fields(errors: &ValidationErrors) -> Vec<&str> {
+        errors.errors.iter().map(|e| e.field.as_str()).collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        let diamond = pipeline(&[("a", &[]), ("b", &["a"]), ("c", &["a"]), ("d", &["b", "c"])]);
+        assert!(validate(&diamond).is_empty());
+
+        assert_eq!(fields(&validate(&pipeline(&[]))), ["stages"]);
+        assert_eq!(
+            fields(&validate(&pipeline(&[("a", &[]), ("a", &[])]))),
+            ["stages[1].name"]
+        );
+        assert_eq!(
+            fields(&validate(&pipeline(&[("a", &["a"])]))),
+            ["stages[0].after"]
+        );
+        assert_eq!(
+            fields(&validate(&pipeline(&[("a", &["x"])]))),
+            ["stages[0].after"]
+        );
+        assert_eq!(
+            fields(&validate(&pipeline(&[("a", &[]), ("b", &["a", "a"])]))),
+            ["stages[1].after"]
+        );
+        assert_eq!(
+            fields(&validate(&pipeline(&[
+                ("a", &["c"]),
+                ("b", &["a"]),
+                ("c", &["b"])
+            ]))),
+            ["stages"]
+        );
+    }
+
+    #[test]
+    fn test_ready_and_skip() {
+        let request = pipeline(&[("a", &[]), ("b", &["a"]), ("c", &["a"]), ("d", &["b", "c"])]);
+        let mut status = pending("p", &request);
+        assert_eq!(ready(&request, &status), [0]);
+
+        status.stages[0].status = StageStatus::Done;
+        assert_eq!(ready(&request, &status), [1, 2]);
+        status.stages[1].status = StageStatus::Running;
+        status.stages[2].status = StageStatus::Running;
+        settle(&mut status);
+        assert_eq!(status.status, JobStatus::Running);
+
+        status.stages[1].status = StageStatus::Error;
+        skip_dependents(&request, &mut status);
+        assert_eq!(status.stages[3].status, StageStatus::Skipped);
+        assert_eq!(status.stages[2].status, StageStatus::Running);
+        settle(&mut status);
+        assert_eq!(status.status, JobStatus::Running);
+
+        status.stages[2].status = StageStatus::Done;
+        settle(&mut status);
+        assert_eq!(status.status, JobStatus::Error);
+
+        for stage in &mut status.stages {
+            stage.status = StageStatus::Done;
+        }
+        settle(&mut status);
+        assert_eq!(status.status, JobStatus::Done);
+    }
+
+    #[tokio::test]
+    async fn test_inputs_and_eviction() {
+        let dir = TempDir::new().unwrap();
+        let blobs = Arc::new(LocalBlobStore::new(dir.path()).await.unwrap());
+        let pipelines = Pipelines::new(blobs.clone());
+        let request = pipeline(&[("a", &[]), ("b", &[]), ("c", &["b", "a"])]);
+        let namespace = Namespace::default();
+
+        assert_eq!(
+            pipelines.input("p", &request, 0).await.unwrap().as_deref(),
+            Some("in")
+        );
+        pipelines
+            .put_output("p", "a", b"A\n".to_vec())
+            .await
+            .unwrap();
+        pipelines
+            .put_output("p", "b", b"B\n".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            pipelines.input("p", &request, 2).await.unwrap().as_deref(),
+            Some("B\nA\n")
+        );
+
+        let mut status = pending("p", &request);
+        pipelines.update(&namespace, &status);
+        assert!(pipelines.get(&namespace, "p").is_some());
+        assert_eq!(pipelines.evict(Duration::ZERO).await, 0);
+
+        status.status = JobStatus::Done;
+        pipelines.update(&namespace, &status);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(pipelines.evict(Duration::ZERO).await, 1);
+        assert!(pipelines.get(&namespace, "p").is_none());
+        assert!(blobs.list(PIPELINES_PREFIX).await.unwrap().is_empty());
+    }
+}
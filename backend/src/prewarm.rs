@@ -0,0 +1,255 @@
+//! Forecasts of function invocations, to boot their VMs ahead of them.
+//!
+//! Every invocation of a function is recorded. A function is expected to get as
+//! many invocations per slot as it got over the last minute, or, when its bursts
+//! come at a steady period like those of a cron job, as many as the peak of its
+//! earlier bursts once the next one is due. The share of the forecast that gets
+//! a warm VM is the aggressiveness: `1.0` boots a VM per invocation expected in
+//! the next slot, `0.5` one for every other.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PREWARM_INTERVAL_SECS: u64 = 10;
+pub const DEFAULT_PREWARM_MAX_VMS: usize = 2;
+
+/// How long an invocation is taken to keep its VM: those of one slot each need a VM.
+const SLOT: Duration = Duration::from_secs(10);
+/// Window of the recent rate.
+const RECENT: Duration = Duration::from_secs(60);
+/// Time without invocations after which the next one starts a burst.
+const QUIET: Duration = Duration::from_secs(60);
+/// How long before a burst is due its VMs are booted.
+const LEAD: Duration = Duration::from_secs(30);
+/// Bursts kept per function to tell their period.
+const BURSTS: usize = 8;
+/// How far the periods between bursts may stray from their mean, in percent.
+const PERIOD_TOLERANCE: u32 = 10;
+/// Longest period looked for: functions idle for longer are forgotten.
+const MAX_PERIOD: Duration = Duration::from_secs(24 * 3600);
+
+struct Burst {
+    start: Instant,
+    /// Most invocations in a slot during the burst.
+    peak: usize,
+}
+
+#[derive(Default)]
+struct History {
+    /// Invocations of the last minute, the oldest first.
+    recent: VecDeque<Instant>,
+    /// The latest bursts, the oldest first.
+    bursts: VecDeque<Burst>,
+    last: Option<Instant>,
+}
+
+impl History {
+    fn record(&mut self, at: Instant) {
+        if self
+            .last
+            .is_none_or(|last| at.duration_since(last) >= QUIET)
+        {
+            self.bursts.push_back(Burst { start: at, peak: 0 });
+            if self.bursts.len() > BURSTS {
+                self.bursts.pop_front();
+            }
+        }
+        self.last = Some(at);
+        self.recent.push_back(at);
+        self.prune(at);
+        let in_slot = self
+            .recent
+            .iter()
+            .filter(|&&t| at.duration_since(t) < SLOT)
+            .count();
+        if let Some(burst) = self.bursts.back_mut() {
+            burst.peak = burst.peak.max(in_slot);
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|&t| now.saturating_duration_since(t) >= RECENT)
+        {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Invocations expected in the next slot.
+    fn expected(&mut self, now: Instant) -> usize {
+        self.prune(now);
+        let per_slot =
+            (self.recent.len() * SLOT.as_secs() as usize).div_ceil(RECENT.as_secs() as usize);
+        let due = self
+            .period()
+            .map(|period| self.bursts.back().expect("bursts have a period").start + period)
+            .filter(|&next| now + LEAD >= next && now < next + RECENT);
+        let burst = match due {
+            Some(_) => self.bursts.iter().map(|b| b.peak).max().unwrap_or(0),
+            None => 0,
+        };
+        per_slot.max(burst)
+    }
+
+    /// The period of the bursts, when there are at least three and they are evenly spaced.
+    fn period(&self) -> Option<Duration> {
+        if self.bursts.len() < 3 {
+            return None;
+        }
+        let gaps: Vec<Duration> = self
+            .bursts
+            .iter()
+            .zip(self.bursts.iter().skip(1))
+            .map(|(a, b)| b.start.duration_since(a.start))
+            .collect();
+        let mean = gaps.iter().sum::<Duration>() / gaps.len() as u32;
+        let tolerance = mean * PERIOD_TOLERANCE / 100;
+        gaps.iter()
+            .all(|&gap| gap.abs_diff(mean) <= tolerance)
+            .then_some(mean)
+    }
+
+    fn is_stale(&self, now: Instant) -> bool {
+        self.last
+            .is_none_or(|last| now.saturating_duration_since(last) > MAX_PERIOD)
+    }
+}
+
+/// Invocation history of the functions, and what it forecasts.
+pub struct Forecaster {
+    histories: Mutex<BTreeMap<String, History>>,
+    aggressiveness: f64,
+}
+
+impl Forecaster {
+    /// A forecaster asking for `aggressiveness` VMs per expected invocation; `0` disables it.
+    pub fn new(aggressiveness: f64) -> Self {
+        Self {
+            histories: Mutex::new(BTreeMap::new()),
+            aggressiveness,
+        }
+    }
+
+    /// Whether VMs are booted ahead of invocations at all.
+    pub fn is_enabled(&self) -> bool {
+        self.aggressiveness > 0.0
+    }
+
+    /// Records an invocation of `function`, now.
+    pub fn record(&self, function: &str) {
+        self.record_at(function, Instant::now());
+    }
+
+    /// Warm VMs each function should have for the next slot, for the functions
+    /// that should have any, ordered by name.
+    pub fn forecast(&self) -> Vec<(String, usize)> {
+        self.forecast_at(Instant::now())
+    }
+
+    fn record_at(&self, function: &str, at: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.lock()
+            .entry(function.to_string())
+            .or_default()
+            .record(at);
+    }
+
+    fn forecast_at(&self, now: Instant) -> Vec<(String, usize)> {
+        let mut histories = self.lock();
+        histories.retain(|_, history| !history.is_stale(now));
+        histories
+            .iter_mut()
+            .filter_map(|(name, history)| {
+                let wanted = (history.expected(now) as f64 * self.aggressiveness).ceil() as usize;
+                (wanted > 0).then(|| (name.clone(), wanted))
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, History>> {
+        self.histories.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn test_recent_rate_is_forecast() {
+        let forecaster = Forecaster::new(1.0);
+        let start = Instant::now();
+        // 18 invocations over the last minute: 3 per slot.
+        for i in 0..18 {
+            forecaster.record_at("resize", start + secs(i * 3));
+        }
+        forecaster.record_at("thumbnail", start + secs(50));
+        let now = start + secs(55);
+        assert_eq!(
+            forecaster.forecast_at(now),
+            vec![("resize".to_string(), 3), ("thumbnail".to_string(), 1)]
+        );
+        // Once quiet, they are not expected anymore.
+        assert!(forecaster.forecast_at(now + secs(120)).is_empty());
+    }
+
+    #[test]
+    fn test_periodic_bursts_are_anticipated() {
+        let forecaster = Forecaster::new(1.0);
+        let start = Instant::now();
+        // Bursts of 5 invocations at once, every 10 minutes.
+        for burst in 0..3 {
+            for _ in 0..5 {
+                forecaster.record_at("report", start + secs(burst * 600));
+            }
+        }
+        // Between bursts, nothing is expected.
+        assert!(forecaster.forecast_at(start + secs(1500)).is_empty());
+        // Shortly before the next one, its peak is.
+        assert_eq!(
+            forecaster.forecast_at(start + secs(1790)),
+            vec![("report".to_string(), 5)]
+        );
+        // One that does not come is given up on.
+        assert!(forecaster.forecast_at(start + secs(1900)).is_empty());
+    }
+
+    #[test]
+    fn test_irregular_bursts_are_not_anticipated() {
+        let forecaster = Forecaster::new(1.0);
+        let start = Instant::now();
+        for at in [0, 600, 1500] {
+            for _ in 0..5 {
+                forecaster.record_at("report", start + secs(at));
+            }
+        }
+        assert!(forecaster.forecast_at(start + secs(2390)).is_empty());
+        assert!(forecaster.forecast_at(start + secs(2090)).is_empty());
+    }
+
+    #[test]
+    fn test_aggressiveness_scales_the_forecast() {
+        let start = Instant::now();
+        let half = Forecaster::new(0.5);
+        let disabled = Forecaster::new(0.0);
+        for i in 0..30 {
+            half.record_at("resize", start + secs(i * 2));
+            disabled.record_at("resize", start + secs(i * 2));
+        }
+        // 5 invocations per slot.
+        assert_eq!(
+            half.forecast_at(start + secs(59)),
+            vec![("resize".to_string(), 3)]
+        );
+        assert!(disabled.forecast_at(start + secs(59)).is_empty());
+    }
+}
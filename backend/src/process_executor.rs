@@ -0,0 +1,335 @@
+//! Jobs run in host processes instead of VMs, for machines without KVM.
+//!
+//! Each job gets its own guest agent, started as a host process that listens on
+//! a loopback port, so the job goes through the same `POST /execute` as in a VM.
+//! The port is open to every process of the host, the code of other jobs
+//! included, so each agent gets a token of its own that requests must carry.
+//! The agent and the code it runs share resource limits and new user, mount, IPC
+//! and UTS namespaces. This is not a sandbox: the code sees the host filesystem
+//! and network with the rights of the backend user, so only trusted code should
+//! run this way.
+
+use crate::host_builds::set_limit;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use tracing::{info, warn};
+
+/// How long a new agent gets to answer its first health check.
+const AGENT_START_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a request to an agent may take, as for the agents of VMs.
+const AGENT_REQUEST_TIMEOUT: Duration = Duration::from_secs(MAX_CPU_SECS);
+/// Variable of the token the agent requires, see `agent/src/auth.rs`.
+const AUTH_TOKEN_ENV: &str = "AGENT_AUTH_TOKEN";
+/// Largest file the code can write.
+const MAX_FILE_BYTES: u64 = 256 * 1024 * 1024;
+const MAX_OPEN_FILES: u64 = 1024;
+/// CPU time of each process, as long as the backend waits for a job.
+const MAX_CPU_SECS: u64 = 300;
+
+/// Errors that can occur while starting an agent process.
+#[derive(Debug)]
+pub enum ProcessError {
+    Io(std::io::Error),
+    /// The agent exited or did not answer in time.
+    NotReady(String),
+}
+
+impl std::fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessError::Io(e) => write!(f, "IO error: {}", e),
+            ProcessError::NotReady(reason) => write!(f, "agent not ready: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {}
+
+impl From<std::io::Error> for ProcessError {
+    fn from(err: std::io::Error) -> Self {
+        ProcessError::Io(err)
+    }
+}
+
+pub struct ProcessExecutor {
+    agent_binary: PathBuf,
+    /// Parent of the working directory of each job.
+    work_dir: PathBuf,
+    /// Whether to unshare namespaces, which some hosts forbid to unprivileged users.
+    namespaces: bool,
+}
+
+impl ProcessExecutor {
+    pub fn new(agent_binary: PathBuf, work_dir: PathBuf, namespaces: bool) -> Self {
+        Self {
+            agent_binary,
+            work_dir,
+            namespaces,
+        }
+    }
+
+    /// Parent of the working directory of each job, named after the job.
+    pub fn work_dir(&self) -> &Path {
+        &self.work_dir
+    }
+
+    /// Starts the agent of job `job_id`, and waits for it to answer. Its processes
+    /// and those of the code get `memory_mb` of data each, and can run `pids_max`
+    /// processes and threads at once, `0` for no limit.
+    pub async fn spawn(
+        &self,
+        job_id: &str,
+        memory_mb: usize,
+        pids_max: u32,
+        client: &reqwest::Client,
+    ) -> Result<AgentProcess, ProcessError> {
+        let work_dir = self.work_dir.join(job_id);
+        tokio::fs::create_dir_all(&work_dir).await?;
+        let port = free_port()?;
+        let token = format!(
+            "{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+
+        let mut cmd = Command::new(&self.agent_binary);
+        cmd.env("AGENT_SERVER_ADDR", format!("127.0.0.1:{}", port))
+            .env("AGENT_WORK_DIR", &work_dir)
+            .env(AUTH_TOKEN_ENV, &token)
+            .current_dir(&work_dir)
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        let limits = Limits {
+            memory_bytes: (memory_mb as u64).saturating_mul(1024 * 1024),
+            // Without a user namespace of its own, the limit would count every
+            // process of the backend user.
+            processes: (self.namespaces && pids_max > 0).then_some(u64::from(pids_max)),
+            namespaces: self.namespaces,
+        };
+        // SAFETY: the closure runs in the forked child before exec, and only makes
+        // async-signal-safe system calls.
+        unsafe {
+            cmd.pre_exec(move || limits.apply());
+        }
+
+        let child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                remove_work_dir(&work_dir).await;
+                return Err(e.into());
+            }
+        };
+        let mut process = AgentProcess {
+            pid: child.id().unwrap_or(0),
+            port,
+            token,
+            work_dir,
+            child,
+        };
+        if let Err(e) = process.wait_ready(client).await {
+            process.stop().await;
+            return Err(e);
+        }
+        info!(
+            "Job {} – agent process {} listening on port {}",
+            job_id, process.pid, port
+        );
+        Ok(process)
+    }
+}
+
+/// An agent running as a host process, in a process group of its own with the
+/// code it runs.
+pub struct AgentProcess {
+    pub pid: u32,
+    port: u16,
+    /// Sent by every request to the agent but `GET /health`.
+    token: String,
+    work_dir: PathBuf,
+    child: Child,
+}
+
+impl AgentProcess {
+    pub fn agent_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// A client of the agent, sending its token with every request.
+    pub fn client(&self) -> Result<reqwest::Client, ProcessError> {
+        let mut authorization =
+            reqwest::header::HeaderValue::from_str(&format!("Bearer {}", self.token))
+                .map_err(|e| ProcessError::NotReady(e.to_string()))?;
+        authorization.set_sensitive(true);
+        let headers = reqwest::header::HeaderMap::from_iter([(
+            reqwest::header::AUTHORIZATION,
+            authorization,
+        )]);
+        reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(AGENT_REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| ProcessError::NotReady(e.to_string()))
+    }
+
+    /// Resolves when the agent exits.
+    pub async fn exited(&mut self) -> std::io::Result<std::process::ExitStatus> {
+        self.child.wait().await
+    }
+
+    /// Kills the agent and what the code left running, and removes the working directory.
+    pub async fn stop(mut self) {
+        if self.pid != 0 {
+            // SAFETY: kill takes no pointers; the negative pid is the process group.
+            unsafe {
+                libc::kill(-(self.pid as libc::pid_t), libc::SIGKILL);
+            }
+        }
+        if let Err(e) = self.child.wait().await {
+            warn!("Cannot reap agent process {}: {}", self.pid, e);
+        }
+        remove_work_dir(&self.work_dir).await;
+    }
+
+    async fn wait_ready(&mut self, client: &reqwest::Client) -> Result<(), ProcessError> {
+        let health_url = format!("{}/health", self.agent_url());
+        let deadline = tokio::time::Instant::now() + AGENT_START_TIMEOUT;
+        while tokio::time::Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait()? {
+                return Err(ProcessError::NotReady(format!(
+                    "agent exited with {}",
+                    status
+                )));
+            }
+            let answered = client
+                .get(&health_url)
+                .timeout(Duration::from_millis(500))
+                .send()
+                .await
+                .is_ok_and(|resp| resp.status().is_success());
+            if answered {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Err(ProcessError::NotReady(format!(
+            "no answer within {} seconds",
+            AGENT_START_TIMEOUT.as_secs()
+        )))
+    }
+}
+
+/// Stops the agent process `pid` left by a backend that died, with what its code
+/// left running. Does nothing unless `pid` still runs `agent_binary`: it may be
+/// another process by now.
+pub fn stop_leftover_agent(agent_binary: &Path, pid: u32) -> bool {
+    let exe = std::fs::read_link(format!("/proc/{}/exe", pid));
+    match (exe, std::fs::canonicalize(agent_binary)) {
+        (Ok(exe), Ok(agent_binary)) if exe == agent_binary => {
+            // SAFETY: kill takes no pointers; the agent leads its process group.
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// What an agent process is confined to, applied between fork and exec.
+#[derive(Clone, Copy)]
+struct Limits {
+    memory_bytes: u64,
+    /// Processes and threads of its user namespace, `None` for no limit.
+    processes: Option<u64>,
+    namespaces: bool,
+}
+
+impl Limits {
+    fn apply(self) -> std::io::Result<()> {
+        // A process group of its own, so that stopping the agent stops the code too.
+        // SAFETY: setsid, setrlimit and unshare only read the values passed to them.
+        unsafe {
+            if libc::setsid() < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            set_limit(libc::RLIMIT_DATA, self.memory_bytes)?;
+            set_limit(libc::RLIMIT_FSIZE, MAX_FILE_BYTES)?;
+            set_limit(libc::RLIMIT_NOFILE, MAX_OPEN_FILES)?;
+            set_limit(libc::RLIMIT_CORE, 0)?;
+            set_limit(libc::RLIMIT_CPU, MAX_CPU_SECS)?;
+            if let Some(processes) = self.processes {
+                set_limit(libc::RLIMIT_NPROC, processes)?;
+            }
+            if self.namespaces {
+                let flags = libc::CLONE_NEWUSER
+                    | libc::CLONE_NEWNS
+                    | libc::CLONE_NEWIPC
+                    | libc::CLONE_NEWUTS;
+                if libc::unshare(flags) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A loopback port nothing listens on. Another process could take it before the
+/// agent binds it; the agent then exits and the job fails to start.
+fn free_port() -> std::io::Result<u16> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+async fn remove_work_dir(work_dir: &Path) {
+    if let Err(e) = tokio::fs::remove_dir_all(work_dir).await {
+        warn!("Cannot remove {}: {}", work_dir.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_spawn_fails_without_agent() {
+        let dir = tempfile::tempdir().unwrap();
+        let executor = ProcessExecutor::new(
+            dir.path().join("missing-agent"),
+            dir.path().join("jobs"),
+            false,
+        );
+        let err = executor
+            .spawn("job-1", 512, 0, &reqwest::Client::new())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, ProcessError::Io(_)), "{err}");
+        assert!(!dir.path().join("jobs/job-1").exists());
+    }
+
+    #[test]
+    fn test_leftover_agent_must_run_the_agent_binary() {
+        // This test process is no agent: it must survive.
+        assert!(!stop_leftover_agent(
+            Path::new("/bin/true"),
+            std::process::id()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_agent_that_exits_is_not_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let executor =
+            ProcessExecutor::new(PathBuf::from("/bin/true"), dir.path().to_path_buf(), false);
+        let err = executor
+            .spawn("job-1", 512, 0, &reqwest::Client::new())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, ProcessError::NotReady(_)), "{err}");
+        assert!(!dir.path().join("job-1").exists());
+    }
+}
@@ -0,0 +1,172 @@
+//! Sandbox profiles: named sets of job settings, like `strict` or `networked`,
+//! defined in `[profiles]` of `cloude.toml`.
+//!
+//! A job names a profile rather than spelling out its isolation, network,
+//! resources and runtime, and the profile also bounds what the job may ask for:
+//! a job of a `strict` profile cannot ask for a pooled VM, nor for more memory
+//! than the profile gives. API keys can be limited to some profiles, see
+//! [`crate::access::Caller::may_use`].
+
+use crate::validation::ValidationErrors;
+use cloude_types::{Isolation, Resources};
+use serde::Deserialize;
+
+/// Profile of the jobs that name none, when one is configured under this name.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// A profile, as configured.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SandboxProfile {
+    /// Isolation of the jobs; they may not ask for another one.
+    #[serde(default)]
+    pub isolation: Option<Isolation>,
+    /// Whether the code of the jobs reaches the network.
+    #[serde(default = "network_default")]
+    pub network: bool,
+    /// VM shape of the jobs, and the most they may ask for.
+    #[serde(default)]
+    pub resources: Option<Resources>,
+    /// Runtimes the jobs may use, any when unset.
+    #[serde(default)]
+    pub runtimes: Option<Vec<String>>,
+}
+
+fn network_default() -> bool {
+    true
+}
+
+/// How a job runs once its profile is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sandbox {
+    pub isolation: Isolation,
+    /// What the job asked for, completed by the profile.
+    pub resources: Option<Resources>,
+    /// Cut the code off the network.
+    pub offline: bool,
+}
+
+impl SandboxProfile {
+    /// How a job of `language` asking for `isolation` and `resources` runs with
+    /// this profile, or what the profile does not allow, by field of the request.
+    pub fn apply(
+        &self,
+        language: &str,
+        isolation: Isolation,
+        resources: Option<&Resources>,
+    ) -> Result<Sandbox, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        if let Some(runtimes) = &self.runtimes
+            && !runtimes.iter().any(|runtime| runtime == language)
+        {
+            errors.push(
+                "language",
+                format!(
+                    "{} is not allowed by the profile, which allows {}",
+                    language,
+                    runtimes.join(", ")
+                ),
+            );
+        }
+
+        let isolation = match self.isolation {
+            // `vm` is also what requests that leave it out get.
+            Some(fixed) if isolation != Isolation::Vm && isolation != fixed => {
+                errors.push("isolation", format!("the profile runs jobs in {}", fixed));
+                fixed
+            }
+            Some(fixed) => fixed,
+            None => isolation,
+        };
+
+        let mut shape = resources.copied().unwrap_or_default();
+        if let Some(bounds) = &self.resources {
+            match (shape.vcpus, bounds.vcpus) {
+                (Some(vcpus), Some(max)) if vcpus > max => errors.push(
+                    "resources.vcpus",
+                    format!("must be at most {}, the vCPUs of the profile", max),
+                ),
+                (None, max) => shape.vcpus = max,
+                _ => {}
+            }
+            match (shape.memory_mb, bounds.memory_mb) {
+                (Some(memory_mb), Some(max)) if memory_mb > max => errors.push(
+                    "resources.memory_mb",
+                    format!("must be at most {}, the memory of the profile", max),
+                ),
+                (None, max) => shape.memory_mb = max,
+                _ => {}
+            }
+        }
+
+        errors.into_result(Sandbox {
+            isolation,
+            resources: (shape != Resources::default()).then_some(shape),
+            offline: !self.network,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strict() -> SandboxProfile {
+        toml::from_str(
+            r#"
+            isolation = "vm"
+            network = false
+            resources = { vcpus = 1, memory_mb = 256 }
+            runtimes = ["python", "node"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_apply() {
+        let strict = strict();
+        let sandbox = strict.apply("python", Isolation::Vm, None).unwrap();
+        assert_eq!(
+            sandbox,
+            Sandbox {
+                isolation: Isolation::Vm,
+                resources: Some(Resources {
+                    vcpus: Some(1),
+                    memory_mb: Some(256),
+                }),
+                offline: true,
+            }
+        );
+
+        // Jobs may ask for less than the profile gives, not more.
+        let smaller = Resources {
+            vcpus: None,
+            memory_mb: Some(128),
+        };
+        let sandbox = strict.apply("node", Isolation::Vm, Some(&smaller)).unwrap();
+        assert_eq!(sandbox.resources.unwrap().memory_mb, Some(128));
+        let larger = Resources {
+            vcpus: Some(4),
+            memory_mb: None,
+        };
+        let errors = strict
+            .apply("rust", Isolation::PooledVm, Some(&larger))
+            .unwrap_err();
+        let fields: Vec<_> = errors.errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["language", "isolation", "resources.vcpus"]);
+
+        // A profile without settings leaves the job as it asked.
+        let open: SandboxProfile = toml::from_str("").unwrap();
+        let sandbox = open.apply("rust", Isolation::PooledVm, None).unwrap();
+        assert_eq!(
+            sandbox,
+            Sandbox {
+                isolation: Isolation::PooledVm,
+                resources: None,
+                offline: false,
+            }
+        );
+    }
+}
@@ -0,0 +1,460 @@
+//! `kafka` publishers: records produced to a partition of a Kafka topic.
+//!
+//! Speaks just enough of the Kafka protocol to produce, over plain TCP and
+//! without authentication: a `Metadata` request (v4) to the first bootstrap
+//! broker that answers finds the leader of the partition, then `Produce`
+//! requests (v3) send it one record per message, in a v2 record batch without
+//! compression, with `acks=1`. The connection to the leader is kept between
+//! messages; after a failure the leader is looked up again, since leadership
+//! may have moved.
+
+use super::Publisher;
+use async_trait::async_trait;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const API_PRODUCE: i16 = 0;
+const API_METADATA: i16 = 3;
+const CLIENT_ID: &str = "cloude";
+/// Longest wait for a broker to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest response read, far more than the answers to these requests.
+const MAX_RESPONSE_BYTES: usize = 16 * 1024 * 1024;
+
+pub struct KafkaPublisher {
+    brokers: Vec<String>,
+    topic: String,
+    partition: i32,
+    leader: Option<TcpStream>,
+    correlation_id: i32,
+}
+
+impl KafkaPublisher {
+    pub fn new(brokers: Vec<String>, topic: String, partition: i32) -> Self {
+        Self {
+            brokers,
+            topic,
+            partition,
+            leader: None,
+            correlation_id: 0,
+        }
+    }
+
+    /// Sends request `api_key` and returns the body of its response.
+    async fn request(
+        &mut self,
+        stream: &mut TcpStream,
+        api_key: i16,
+        api_version: i16,
+        body: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let request = frame(api_key, api_version, self.correlation_id, body);
+        let exchange = async {
+            stream.write_all(&request).await?;
+            let size = stream.read_i32().await?;
+            if size < 4 || size as usize > MAX_RESPONSE_BYTES {
+                return Err(invalid("response size out of range"));
+            }
+            let mut response = vec![0; size as usize];
+            stream.read_exact(&mut response).await?;
+            Ok(response)
+        };
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        let (correlation_id, body) = response.split_at(4);
+        if i32::from_be_bytes(correlation_id.try_into().unwrap()) != self.correlation_id {
+            return Err(invalid("response to another request"));
+        }
+        Ok(body.to_vec())
+    }
+
+    /// Connects to the leader of the partition, asking the bootstrap brokers in turn.
+    async fn connect_leader(&mut self) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no broker");
+        for broker in self.brokers.clone() {
+            let lookup = async {
+                let mut stream = TcpStream::connect(&broker).await?;
+                let response = self
+                    .request(&mut stream, API_METADATA, 4, &metadata_request(&self.topic))
+                    .await?;
+                parse_metadata(&response, &self.topic, self.partition)
+            };
+            match lookup.await {
+                Ok(leader) => return TcpStream::connect(leader).await,
+                Err(e) => last_error = io::Error::new(e.kind(), format!("{}: {}", broker, e)),
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[async_trait]
+impl Publisher for KafkaPublisher {
+    fn target(&self) -> String {
+        format!("kafka topic {} partition {}", self.topic, self.partition)
+    }
+
+    async fn publish(&mut self, key: &str, payload: &[u8]) -> io::Result<()> {
+        let mut leader = match self.leader.take() {
+            Some(leader) => leader,
+            None => self.connect_leader().await?,
+        };
+        let batch = record_batch(key.as_bytes(), payload, now_ms());
+        let body = produce_request(&self.topic, self.partition, &batch);
+        let response = self.request(&mut leader, API_PRODUCE, 3, &body).await?;
+        parse_produce(&response)?;
+        self.leader = Some(leader);
+        Ok(())
+    }
+}
+
+/// A request: its size, the v1 request header, then `body`.
+fn frame(api_key: i16, api_version: i16, correlation_id: i32, body: &[u8]) -> Vec<u8> {
+    let mut request = vec![0; 4];
+    request.extend_from_slice(&api_key.to_be_bytes());
+    request.extend_from_slice(&api_version.to_be_bytes());
+    request.extend_from_slice(&correlation_id.to_be_bytes());
+    put_string(&mut request, CLIENT_ID);
+    request.extend_from_slice(body);
+    let size = (request.len() - 4) as i32;
+    request[..4].copy_from_slice(&size.to_be_bytes());
+    request
+}
+
+/// `Metadata` v4 of `topic`, without creating it.
+fn metadata_request(topic: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&1i32.to_be_bytes());
+    put_string(&mut body, topic);
+    body.push(0);
+    body
+}
+
+/// `host:port` of the leader of `partition` of `topic`, from a `Metadata` v4 response.
+fn parse_metadata(response: &[u8], topic: &str, partition: i32) -> io::Result<String> {
+    let mut reader = Reader(response);
+    reader.i32()?; // throttle_time_ms
+    let mut brokers = Vec::new();
+    for _ in 0..reader.count()? {
+        let node_id = reader.i32()?;
+        let host = reader.string()?;
+        let port = reader.i32()?;
+        reader.nullable_string()?; // rack
+        brokers.push((node_id, format!("{}:{}", host, port)));
+    }
+    reader.nullable_string()?; // cluster_id
+    reader.i32()?; // controller_id
+    for _ in 0..reader.count()? {
+        let error_code = reader.i16()?;
+        let name = reader.string()?;
+        reader.bool()?; // is_internal
+        let partitions = reader.count()?;
+        if name == topic {
+            check(error_code)?;
+        }
+        for _ in 0..partitions {
+            let error_code = reader.i16()?;
+            let index = reader.i32()?;
+            let leader = reader.i32()?;
+            for _ in 0..2 {
+                // replica_nodes and isr_nodes
+                for _ in 0..reader.count()? {
+                    reader.i32()?;
+                }
+            }
+            if name == topic && index == partition {
+                check(error_code)?;
+                return brokers
+                    .into_iter()
+                    .find(|(node_id, _)| *node_id == leader)
+                    .map(|(_, address)| address)
+                    .ok_or_else(|| invalid("leader not among the brokers"));
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no partition {} of topic {}", partition, topic),
+    ))
+}
+
+/// `Produce` v3 of `batch` to `partition` of `topic`, acknowledged by the leader.
+fn produce_request(topic: &str, partition: i32, batch: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(-1i16).to_be_bytes()); // transactional_id: null
+    body.extend_from_slice(&1i16.to_be_bytes()); // acks
+    body.extend_from_slice(&(REQUEST_TIMEOUT.as_millis() as i32).to_be_bytes());
+    body.extend_from_slice(&1i32.to_be_bytes());
+    put_string(&mut body, topic);
+    body.extend_from_slice(&1i32.to_be_bytes());
+    body.extend_from_slice(&partition.to_be_bytes());
+    body.extend_from_slice(&(batch.len() as i32).to_be_bytes());
+    body.extend_from_slice(batch);
+    body
+}
+
+/// The error of a `Produce` v3 response, if it has one.
+fn parse_produce(response: &[u8]) -> io::Result<()> {
+    let mut reader = Reader(response);
+    for _ in 0..reader.count()? {
+        reader.string()?;
+        for _ in 0..reader.count()? {
+            reader.i32()?; // index
+            check(reader.i16()?)?;
+            reader.i64()?; // base_offset
+            reader.i64()?; // log_append_time_ms
+        }
+    }
+    Ok(())
+}
+
+/// A v2 record batch of one record, `value` keyed by `key`.
+fn record_batch(key: &[u8], value: &[u8], timestamp_ms: i64) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.push(0); // attributes
+    put_varint(&mut record, 0); // timestamp_delta
+    put_varint(&mut record, 0); // offset_delta
+    put_varint(&mut record, key.len() as i64);
+    record.extend_from_slice(key);
+    put_varint(&mut record, value.len() as i64);
+    record.extend_from_slice(value);
+    put_varint(&mut record, 0); // headers
+
+    // From the attributes on, covered by the CRC.
+    let mut tail = Vec::new();
+    tail.extend_from_slice(&0i16.to_be_bytes()); // attributes: no compression
+    tail.extend_from_slice(&0i32.to_be_bytes()); // last_offset_delta
+    tail.extend_from_slice(&timestamp_ms.to_be_bytes()); // base_timestamp
+    tail.extend_from_slice(&timestamp_ms.to_be_bytes()); // max_timestamp
+    tail.extend_from_slice(&(-1i64).to_be_bytes()); // producer_id
+    tail.extend_from_slice(&(-1i16).to_be_bytes()); // producer_epoch
+    tail.extend_from_slice(&(-1i32).to_be_bytes()); // base_sequence
+    tail.extend_from_slice(&1i32.to_be_bytes()); // records
+    put_varint(&mut tail, record.len() as i64);
+    tail.extend_from_slice(&record);
+
+    let mut batch = Vec::with_capacity(tail.len() + 21);
+    batch.extend_from_slice(&0i64.to_be_bytes()); // base_offset
+    // Length of what follows: leader epoch, magic, CRC and the tail.
+    batch.extend_from_slice(&((tail.len() + 9) as i32).to_be_bytes());
+    batch.extend_from_slice(&(-1i32).to_be_bytes()); // partition_leader_epoch
+    batch.push(2); // magic
+    batch.extend_from_slice(&crc32c(&tail).to_be_bytes());
+    batch.extend_from_slice(&tail);
+    batch
+}
+
+/// CRC-32C (Castagnoli) of `data`, the checksum of record batches.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82F6_3B78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Appends `value` as a zigzag varint, the integers of records.
+fn put_varint(buf: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(&(value.len() as i16).to_be_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// Fails on a non-zero error code of a response.
+fn check(error_code: i16) -> io::Result<()> {
+    match error_code {
+        0 => Ok(()),
+        code => Err(io::Error::other(format!("broker error {}", code))),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Reads the big-endian fields of a response in turn.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(invalid("truncated response"));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.take(1)?[0] != 0)
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Length of an array, a null array being empty.
+    fn count(&mut self) -> io::Result<usize> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+
+    fn nullable_string(&mut self) -> io::Result<Option<String>> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        let bytes = self.take(len as usize)?;
+        String::from_utf8(bytes.to_vec())
+            .map(Some)
+            .map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.nullable_string()?
+            .ok_or_else(|| invalid("null string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn test_put_varint() {
+        for (value, encoded) in [
+            (0, vec![0x00]),
+            (-1, vec![0x01]),
+            (1, vec![0x02]),
+            (63, vec![0x7e]),
+            (-64, vec![0x7f]),
+            (300, vec![0xd8, 0x04]),
+        ] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, value);
+            assert_eq!(buf, encoded, "{value}");
+        }
+    }
+
+    #[test]
+    fn test_record_batch() {
+        let batch = record_batch(b"job-1", b"{}", 1_760_000_000_000);
+        assert_eq!(&batch[..8], &[0; 8]);
+        let length = i32::from_be_bytes(batch[8..12].try_into().unwrap()) as usize;
+        assert_eq!(length, batch.len() - 12);
+        assert_eq!(batch[16], 2);
+        let crc = u32::from_be_bytes(batch[17..21].try_into().unwrap());
+        assert_eq!(crc, crc32c(&batch[21..]));
+        // The record: its length, attributes, deltas, then key and value.
+        assert!(batch.ends_with(&[0x0a, b'j', b'o', b'b', b'-', b'1', 0x04, b'{', b'}', 0]));
+    }
+
+    #[test]
+    fn test_frame() {
+        let request = frame(API_METADATA, 4, 7, &[0xff]);
+        assert_eq!(
+            request,
+            [
+                &[0, 0, 0, 17][..],
+                &[0, 3, 0, 4, 0, 0, 0, 7],
+                &[0, 6],
+                b"cloude",
+                &[0xff],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let mut response = Vec::new();
+        response.extend_from_slice(&0i32.to_be_bytes());
+        response.extend_from_slice(&2i32.to_be_bytes());
+        for (node_id, host) in [(1i32, "kafka-1"), (2, "kafka-2")] {
+            response.extend_from_slice(&node_id.to_be_bytes());
+            put_string(&mut response, host);
+            response.extend_from_slice(&9092i32.to_be_bytes());
+            response.extend_from_slice(&(-1i16).to_be_bytes());
+        }
+        put_string(&mut response, "cluster");
+        response.extend_from_slice(&1i32.to_be_bytes());
+        response.extend_from_slice(&1i32.to_be_bytes());
+        response.extend_from_slice(&0i16.to_be_bytes());
+        put_string(&mut response, "results");
+        response.push(0);
+        response.extend_from_slice(&2i32.to_be_bytes());
+        for (index, leader) in [(0i32, 1i32), (1, 2)] {
+            response.extend_from_slice(&0i16.to_be_bytes());
+            response.extend_from_slice(&index.to_be_bytes());
+            response.extend_from_slice(&leader.to_be_bytes());
+            for _ in 0..2 {
+                response.extend_from_slice(&1i32.to_be_bytes());
+                response.extend_from_slice(&leader.to_be_bytes());
+            }
+        }
+
+        assert_eq!(
+            parse_metadata(&response, "results", 1).unwrap(),
+            "kafka-2:9092"
+        );
+        assert_eq!(
+            parse_metadata(&response, "results", 2).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(parse_metadata(&response[..20], "results", 0).is_err());
+    }
+
+    #[test]
+    fn test_parse_produce() {
+        let response = |error_code: i16| {
+            let mut response = Vec::new();
+            response.extend_from_slice(&1i32.to_be_bytes());
+            put_string(&mut response, "results");
+            response.extend_from_slice(&1i32.to_be_bytes());
+            response.extend_from_slice(&0i32.to_be_bytes());
+            response.extend_from_slice(&error_code.to_be_bytes());
+            response.extend_from_slice(&42i64.to_be_bytes());
+            response.extend_from_slice(&(-1i64).to_be_bytes());
+            response.extend_from_slice(&0i32.to_be_bytes());
+            response
+        };
+        parse_produce(&response(0)).unwrap();
+        // NOT_LEADER_OR_FOLLOWER
+        assert!(parse_produce(&response(6)).is_err());
+    }
+}
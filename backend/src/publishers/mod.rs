@@ -0,0 +1,428 @@
+//! Results of deployed functions published to message queues, besides webhooks.
+//!
+//! Bindings are read from `publishers.toml` at startup. Every invocation of a
+//! bound function that ends, done or failed, is published to each queue bound
+//! to the function as an [`ExecutionCompleted`] in JSON, so that downstream
+//! processing needs no HTTP endpoint. Each binding is a [`Publisher`] with a
+//! task of its own, sending its messages one at a time and in order; a message
+//! that fails is sent again after the backoff of webhooks, up to
+//! [`MAX_ATTEMPTS`] times, and is then given up on.
+//!
+//! Each kind of queue is a feature of the backend: `nats` and `kafka`.
+
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "nats")]
+mod nats;
+
+#[cfg(feature = "kafka")]
+pub use self::kafka::KafkaPublisher;
+#[cfg(feature = "nats")]
+pub use self::nats::NatsPublisher;
+
+use crate::triggers::nats_address;
+use crate::validation::{ValidationErrors, validate_identifier};
+use crate::webhooks::{backoff, jitter};
+use async_trait::async_trait;
+use cloude_types::ExecutionCompleted;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Largest message published, the default `max_payload` of NATS servers and
+/// `message.max.bytes` of Kafka brokers, give or take their framing.
+pub const MAX_MESSAGE_BYTES: usize = 1000 * 1000;
+/// Attempts to publish a message before it is given up on.
+pub const MAX_ATTEMPTS: u32 = 5;
+/// Messages of a binding waiting to be published before new ones are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Errors that can occur while loading the publisher bindings.
+#[derive(Debug)]
+pub enum PublisherError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Invalid(ValidationErrors),
+}
+
+impl std::fmt::Display for PublisherError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PublisherError::Io(e) => write!(f, "IO error: {}", e),
+            PublisherError::Toml(e) => write!(f, "TOML error: {}", e),
+            PublisherError::Invalid(errors) => write!(f, "invalid publishers: {}", errors),
+        }
+    }
+}
+
+impl std::error::Error for PublisherError {}
+
+impl From<std::io::Error> for PublisherError {
+    fn from(err: std::io::Error) -> Self {
+        PublisherError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for PublisherError {
+    fn from(err: toml::de::Error) -> Self {
+        PublisherError::Toml(err)
+    }
+}
+
+/// A queue messages are published to.
+#[async_trait]
+pub trait Publisher: Send {
+    /// The queue, for logs.
+    fn target(&self) -> String;
+
+    /// Publishes `payload`, keyed by `key` on queues that have keys.
+    async fn publish(&mut self, key: &str, payload: &[u8]) -> std::io::Result<()>;
+}
+
+/// Content of `publishers.toml`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct PublishersFile {
+    #[serde(default, rename = "publisher")]
+    publishers: Vec<Binding>,
+}
+
+/// A function whose results are published to a queue.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Binding {
+    /// Results are published to `subject` of the NATS server at `url`.
+    Nats {
+        function: String,
+        url: String,
+        subject: String,
+    },
+    /// Results are produced to `partition` of `topic`, keyed by job id. The
+    /// leader of the partition is looked up from the first of `brokers` that
+    /// answers.
+    Kafka {
+        function: String,
+        brokers: Vec<String>,
+        topic: String,
+        #[serde(default)]
+        partition: i32,
+    },
+}
+
+impl Binding {
+    pub fn function(&self) -> &str {
+        match self {
+            Binding::Nats { function, .. } | Binding::Kafka { function, .. } => function,
+        }
+    }
+}
+
+/// Bindings read from `path`; none when the file does not exist.
+pub fn load_publishers(path: &Path) -> Result<Vec<Binding>, PublisherError> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let bindings = toml::from_str::<PublishersFile>(&content)?.publishers;
+    validate_bindings(&bindings).map_err(PublisherError::Invalid)?;
+    Ok(bindings)
+}
+
+fn validate_bindings(bindings: &[Binding]) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    for (index, binding) in bindings.iter().enumerate() {
+        let field = |name: &str| format!("publisher[{index}].{name}");
+        validate_identifier(&mut errors, &field("function"), binding.function());
+        match binding {
+            Binding::Nats { url, subject, .. } => {
+                if !cfg!(feature = "nats") {
+                    errors.push(
+                        &field("type"),
+                        "needs a backend built with the nats feature",
+                    );
+                }
+                if nats_address(url).is_none() {
+                    errors.push(&field("url"), "must be nats://host:port");
+                }
+                if subject.is_empty()
+                    || subject.contains(|c: char| c.is_whitespace() || c == '*' || c == '>')
+                {
+                    errors.push(
+                        &field("subject"),
+                        "must be a subject without spaces or wildcards",
+                    );
+                }
+            }
+            Binding::Kafka {
+                brokers,
+                topic,
+                partition,
+                ..
+            } => {
+                if !cfg!(feature = "kafka") {
+                    errors.push(
+                        &field("type"),
+                        "needs a backend built with the kafka feature",
+                    );
+                }
+                if brokers.is_empty() {
+                    errors.push(&field("brokers"), "must not be empty");
+                }
+                for broker in brokers {
+                    let valid = broker.rsplit_once(':').is_some_and(|(host, port)| {
+                        !host.is_empty() && port.parse::<u16>().is_ok()
+                    });
+                    if !valid {
+                        errors.push(&field("brokers"), format!("{broker} must be host:port"));
+                    }
+                }
+                let valid_topic = !topic.is_empty()
+                    && topic.len() <= 249
+                    && topic
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+                if !valid_topic {
+                    errors.push(
+                        &field("topic"),
+                        "must be 1 to 249 letters, digits, '.', '_' or '-'",
+                    );
+                }
+                if *partition < 0 {
+                    errors.push(&field("partition"), "must not be negative");
+                }
+            }
+        }
+    }
+    errors.into_result(())
+}
+
+/// The publisher of `binding`, `None` when the backend was built without its kind.
+pub fn publisher_for(binding: &Binding) -> Option<Box<dyn Publisher>> {
+    match binding.clone() {
+        #[cfg(feature = "nats")]
+        Binding::Nats { url, subject, .. } => Some(Box::new(NatsPublisher::new(url, subject))),
+        #[cfg(feature = "kafka")]
+        Binding::Kafka {
+            brokers,
+            topic,
+            partition,
+            ..
+        } => Some(Box::new(KafkaPublisher::new(brokers, topic, partition))),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+/// `event` in JSON, without its output when that makes it too large for a
+/// message: consumers then read it with `GET /status/{job_id}`.
+pub fn encode(mut event: ExecutionCompleted) -> Vec<u8> {
+    // It's safe to unwrap because the event only holds strings and numbers.
+    let payload = serde_json::to_vec(&event).unwrap();
+    if payload.len() <= MAX_MESSAGE_BYTES {
+        return payload;
+    }
+    event.stdout = None;
+    event.stderr = None;
+    event.result_omitted = true;
+    serde_json::to_vec(&event).unwrap()
+}
+
+/// A message waiting to be published.
+#[derive(Clone)]
+struct Message {
+    job_id: String,
+    payload: Arc<[u8]>,
+}
+
+/// The queues of every bound function, each fed by its task.
+#[derive(Default)]
+pub struct Publishers {
+    queues: HashMap<String, Vec<(String, mpsc::Sender<Message>)>>,
+}
+
+impl Publishers {
+    /// Starts a task per binding. Bindings of a kind the backend was built
+    /// without are skipped, [`load_publishers`] rejects them.
+    pub fn start(bindings: &[Binding]) -> Self {
+        let mut queues: HashMap<_, Vec<_>> = HashMap::new();
+        for binding in bindings {
+            let Some(publisher) = publisher_for(binding) else {
+                continue;
+            };
+            let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+            queues
+                .entry(binding.function().to_string())
+                .or_default()
+                .push((publisher.target(), sender));
+            tokio::spawn(run(publisher, receiver));
+        }
+        Self { queues }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    /// Whether results of `function` are published.
+    pub fn has(&self, function: &str) -> bool {
+        self.queues.contains_key(function)
+    }
+
+    /// Hands `event` to the publishers of its function. A queue that fell too
+    /// far behind drops it.
+    pub fn publish(&self, event: ExecutionCompleted) {
+        let Some(queues) = self.queues.get(&event.function) else {
+            return;
+        };
+        let message = Message {
+            job_id: event.job_id.clone(),
+            payload: encode(event).into(),
+        };
+        for (target, sender) in queues {
+            if sender.try_send(message.clone()).is_err() {
+                warn!(
+                    "Publisher {} fell behind, result of job {} dropped",
+                    target, message.job_id
+                );
+            }
+        }
+    }
+}
+
+/// Publishes the messages of `receiver` in order, until the backend stops.
+async fn run(mut publisher: Box<dyn Publisher>, mut receiver: mpsc::Receiver<Message>) {
+    while let Some(message) = receiver.recv().await {
+        let mut attempt = 1;
+        while let Err(e) = publisher.publish(&message.job_id, &message.payload).await {
+            if attempt >= MAX_ATTEMPTS {
+                warn!(
+                    "Result of job {} not published to {}, given up after {} attempts: {}",
+                    message.job_id,
+                    publisher.target(),
+                    attempt,
+                    e
+                );
+                break;
+            }
+            warn!(
+                "Result of job {} not published to {}: {}",
+                message.job_id,
+                publisher.target(),
+                e
+            );
+            tokio::time::sleep(backoff(attempt, jitter())).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloude_types::JobStatus;
+
+    #[test]
+    fn test_load_publishers() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("publishers.toml");
+        assert_eq!(load_publishers(&path).unwrap(), Vec::new());
+
+        std::fs::write(
+            &path,
+            r#"
+            [[publisher]]
+            type = "nats"
+            function = "resize"
+            url = "nats://127.0.0.1:4222"
+            subject = "results.resize"
+
+            [[publisher]]
+            type = "kafka"
+            function = "resize"
+            brokers = ["127.0.0.1:9092"]
+            topic = "results"
+            "#,
+        )
+        .unwrap();
+        let loaded = load_publishers(&path);
+        if cfg!(all(feature = "nats", feature = "kafka")) {
+            let bindings = loaded.unwrap();
+            assert_eq!(bindings.len(), 2);
+            assert_eq!(
+                bindings[1],
+                Binding::Kafka {
+                    function: "resize".to_string(),
+                    brokers: vec!["127.0.0.1:9092".to_string()],
+                    topic: "results".to_string(),
+                    partition: 0,
+                }
+            );
+        } else {
+            assert!(matches!(loaded, Err(PublisherError::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn test_validate_bindings() {
+        let invalid = [
+            Binding::Nats {
+                function: "resize".to_string(),
+                url: "nats://127.0.0.1".to_string(),
+                subject: "results.>".to_string(),
+            },
+            Binding::Kafka {
+                function: "Resize!".to_string(),
+                brokers: vec!["127.0.0.1".to_string()],
+                topic: "re sults".to_string(),
+                partition: -1,
+            },
+        ];
+        let errors = validate_bindings(&invalid).unwrap_err().errors;
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        for field in [
+            "publisher[0].url",
+            "publisher[0].subject",
+            "publisher[1].function",
+            "publisher[1].brokers",
+            "publisher[1].topic",
+            "publisher[1].partition",
+        ] {
+            assert!(fields.contains(&field), "{field} not in {fields:?}");
+        }
+    }
+
+    #[test]
+    fn test_encode_omits_large_output() {
+        let event = ExecutionCompleted {
+            function: "resize".to_string(),
+            version: 1,
+            job_id: "job-1".to_string(),
+            status: JobStatus::Done,
+            exit_code: Some(0),
+            stdout: Some("ok".to_string()),
+            stderr: Some(String::new()),
+            result_omitted: false,
+            oom_killed: false,
+            signal: None,
+            core_dump: false,
+            timestamp_ms: 1_760_000_000_000,
+        };
+        let small: ExecutionCompleted = serde_json::from_slice(&encode(event.clone())).unwrap();
+        assert_eq!(small, event);
+
+        let large = ExecutionCompleted {
+            stdout: Some("x".repeat(MAX_MESSAGE_BYTES)),
+            ..event
+        };
+        let payload = encode(large);
+        assert!(payload.len() <= MAX_MESSAGE_BYTES);
+        let omitted: ExecutionCompleted = serde_json::from_slice(&payload).unwrap();
+        assert!(omitted.result_omitted);
+        assert_eq!(omitted.stdout, None);
+        assert_eq!(omitted.exit_code, Some(0));
+    }
+}
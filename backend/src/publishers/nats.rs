@@ -0,0 +1,164 @@
+//! `nats` publishers: messages published to a subject of a NATS server.
+//!
+//! Speaks the client protocol like the `nats` trigger, over plain TCP and
+//! without authentication: `CONNECT` once, then for every message a `PUB`
+//! followed by a `PING`. The server answers messages in order, so its `PONG`
+//! tells that it took the message, and a `-ERR` that it did not. The connection
+//! is kept between messages and opened again after a failure.
+
+use super::Publisher;
+use crate::triggers::nats_address;
+use async_trait::async_trait;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Longest wait for the server to take a message.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct NatsPublisher {
+    url: String,
+    subject: String,
+    connection: Option<BufReader<TcpStream>>,
+}
+
+impl NatsPublisher {
+    pub fn new(url: String, subject: String) -> Self {
+        Self {
+            url,
+            subject,
+            connection: None,
+        }
+    }
+
+    async fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let address = nats_address(&self.url)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid NATS URL"))?;
+        let mut stream = TcpStream::connect(address).await?;
+        stream
+            .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"cloude\"}\r\n")
+            .await?;
+        Ok(BufReader::new(stream))
+    }
+
+    /// Publishes `payload` on `connection`, and waits for the server to take it.
+    async fn send(&self, connection: &mut BufReader<TcpStream>, payload: &[u8]) -> io::Result<()> {
+        let mut frame = format!("PUB {} {}\r\n", self.subject, payload.len()).into_bytes();
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(b"\r\nPING\r\n");
+        connection.get_mut().write_all(&frame).await?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if connection.read_line(&mut line).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim_end();
+            if line == "PONG" {
+                return Ok(());
+            } else if line == "PING" {
+                connection.get_mut().write_all(b"PONG\r\n").await?;
+            } else if let Some(error) = line.strip_prefix("-ERR") {
+                return Err(io::Error::other(format!("server error:{}", error)));
+            }
+            // INFO and +OK need no answer.
+        }
+    }
+}
+
+#[async_trait]
+impl Publisher for NatsPublisher {
+    fn target(&self) -> String {
+        format!("{} {}", self.url, self.subject)
+    }
+
+    async fn publish(&mut self, _key: &str, payload: &[u8]) -> io::Result<()> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.connect().await?,
+        };
+        match tokio::time::timeout(PUBLISH_TIMEOUT, self.send(&mut connection, payload)).await {
+            Ok(Ok(())) => {
+                self.connection = Some(connection);
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream
+                .get_mut()
+                .write_all(b"INFO {\"max_payload\":1048576}\r\n")
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                if line.starts_with("CONNECT ") {
+                    line.clear();
+                    stream.read_line(&mut line).await.unwrap();
+                }
+                let size: usize = line.trim_end().rsplit(' ').next().unwrap().parse().unwrap();
+                let mut payload = vec![0; size + 2];
+                stream.read_exact(&mut payload).await.unwrap();
+                payload.truncate(size);
+                received.push((line.trim_end().to_string(), payload));
+
+                line.clear();
+                stream.read_line(&mut line).await.unwrap();
+                assert_eq!(line, "PING\r\n");
+                stream.get_mut().write_all(b"PONG\r\n").await.unwrap();
+            }
+            received
+        });
+
+        let mut publisher = NatsPublisher::new(url, "results.resize".to_string());
+        publisher.publish("job-1", b"{\"a\":1}").await.unwrap();
+        publisher.publish("job-2", b"{}").await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                ("PUB results.resize 7".to_string(), b"{\"a\":1}".to_vec()),
+                ("PUB results.resize 2".to_string(), b"{}".to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"-ERR 'Permissions Violation for Publish'\r\n")
+                .await
+                .unwrap();
+            // Keep the connection until the publisher read the error.
+            let _ = stream.read(&mut [0; 1024]).await;
+        });
+
+        let mut publisher = NatsPublisher::new(url, "results".to_string());
+        let error = publisher.publish("job-1", b"{}").await.unwrap_err();
+        assert!(error.to_string().contains("Permissions Violation"));
+        assert!(publisher.connection.is_none());
+    }
+}
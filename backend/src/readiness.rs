@@ -0,0 +1,227 @@
+use crate::blob_store::BlobStore;
+use crate::host_pressure::HostPressure;
+use serde::Serialize;
+use std::ffi::CString;
+use std::net::Ipv4Addr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Default minimum free space required in the build directory (1 GiB).
+pub const DEFAULT_MIN_FREE_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Key probed to check that the blob store answers. It never needs to exist.
+const BLOB_STORE_PROBE_KEY: &str = "readyz/probe";
+
+/// Result of a single readiness check.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl CheckResult {
+    fn new(name: &str, ok: bool, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            ok,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Outcome of every readiness check, ready only when all of them passed.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+/// Everything the backend needs to be able to start VMs.
+pub struct ReadinessChecks {
+    /// Whether jobs run in VMs; without them, KVM, the kernel and the network are not checked.
+    pub vms: bool,
+    pub kvm_device: PathBuf,
+    pub kernel_path: PathBuf,
+    pub bridge_name: String,
+    pub ip_range: Ipv4Addr,
+    pub ip_mask: u8,
+    /// Directory where initramfs images and snapshots are built.
+    pub build_dir: PathBuf,
+    pub min_free_disk_bytes: u64,
+    pub blobs: Arc<dyn BlobStore>,
+    /// CPU and memory pressure of the host, not checked when `None`. Under
+    /// pressure, load balancers send new jobs to other backends.
+    pub pressure: Option<Arc<HostPressure>>,
+}
+
+impl ReadinessChecks {
+    /// Runs every check and collects their results, including the failed ones.
+    pub async fn run(&self) -> ReadinessReport {
+        let mut checks = Vec::new();
+        if self.vms {
+            checks.extend([
+                check_kvm(&self.kvm_device),
+                check_kernel(&self.kernel_path),
+                self.check_bridge().await,
+                self.check_nat().await,
+            ]);
+        }
+        if let Some(pressure) = &self.pressure {
+            checks.push(check_pressure(pressure));
+        }
+        checks.extend([
+            self.check_blob_store().await,
+            check_free_disk(&self.build_dir, self.min_free_disk_bytes),
+        ]);
+
+        ReadinessReport {
+            ready: checks.iter().all(|check| check.ok),
+            checks,
+        }
+    }
+
+    async fn check_bridge(&self) -> CheckResult {
+        match virt::network::bridge_is_up(&self.bridge_name).await {
+            Ok(true) => CheckResult::new("bridge", true, format!("{} is up", self.bridge_name)),
+            Ok(false) => CheckResult::new(
+                "bridge",
+                false,
+                format!("{} is missing or down", self.bridge_name),
+            ),
+            Err(e) => CheckResult::new("bridge", false, e.to_string()),
+        }
+    }
+
+    /// Lists the nftables ruleset by running `nft`, on a blocking thread.
+    async fn check_nat(&self) -> CheckResult {
+        let range = format!("{}/{}", self.ip_range, self.ip_mask);
+        let (ip_range, ip_mask) = (self.ip_range, self.ip_mask);
+        let configured = tokio::task::spawn_blocking(move || {
+            virt::network::nat_is_configured(ip_range, ip_mask).map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+        match configured {
+            Ok(true) => CheckResult::new("nat", true, format!("masquerading {}", range)),
+            Ok(false) => CheckResult::new(
+                "nat",
+                false,
+                format!(
+                    "IPv4 forwarding or masquerade rule for {} is missing",
+                    range
+                ),
+            ),
+            Err(e) => CheckResult::new("nat", false, e),
+        }
+    }
+
+    async fn check_blob_store(&self) -> CheckResult {
+        match self.blobs.exists(BLOB_STORE_PROBE_KEY).await {
+            Ok(_) => CheckResult::new("blob_store", true, "reachable"),
+            Err(e) => CheckResult::new("blob_store", false, e.to_string()),
+        }
+    }
+}
+
+/// KVM must exist and be usable by the backend process.
+fn check_kvm(kvm_device: &Path) -> CheckResult {
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(kvm_device)
+    {
+        Ok(_) => CheckResult::new(
+            "kvm",
+            true,
+            format!("{} is accessible", kvm_device.display()),
+        ),
+        Err(e) => CheckResult::new("kvm", false, format!("{}: {}", kvm_device.display(), e)),
+    }
+}
+
+fn check_kernel(kernel_path: &Path) -> CheckResult {
+    match std::fs::metadata(kernel_path) {
+        Ok(meta) if meta.is_file() && meta.len() > 0 => CheckResult::new(
+            "kernel",
+            true,
+            format!("{} ({} bytes)", kernel_path.display(), meta.len()),
+        ),
+        Ok(_) => CheckResult::new(
+            "kernel",
+            false,
+            format!("{} is not a non-empty file", kernel_path.display()),
+        ),
+        Err(e) => CheckResult::new("kernel", false, format!("{}: {}", kernel_path.display(), e)),
+    }
+}
+
+fn check_pressure(pressure: &HostPressure) -> CheckResult {
+    match pressure.check() {
+        None => CheckResult::new("pressure", true, "no CPU or memory pressure"),
+        Some(pressure) => CheckResult::new("pressure", false, pressure.to_string()),
+    }
+}
+
+fn check_free_disk(build_dir: &Path, min_free_bytes: u64) -> CheckResult {
+    match free_disk_bytes(build_dir) {
+        Ok(free) if free >= min_free_bytes => CheckResult::new(
+            "disk",
+            true,
+            format!("{} bytes free in {}", free, build_dir.display()),
+        ),
+        Ok(free) => CheckResult::new(
+            "disk",
+            false,
+            format!(
+                "{} bytes free in {}, at least {} required",
+                free,
+                build_dir.display(),
+                min_free_bytes
+            ),
+        ),
+        Err(e) => CheckResult::new("disk", false, format!("{}: {}", build_dir.display(), e)),
+    }
+}
+
+/// Space available to unprivileged users on the filesystem holding `path`.
+fn free_disk_bytes(path: &Path) -> std::io::Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stat` is a valid out pointer.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_kernel_check() {
+        let dir = TempDir::new().unwrap();
+        let kernel = dir.path().join("vmlinux");
+
+        assert!(!check_kernel(&kernel).ok);
+        std::fs::write(&kernel, b"").unwrap();
+        assert!(!check_kernel(&kernel).ok);
+        std::fs::write(&kernel, b"kernel").unwrap();
+        assert!(check_kernel(&kernel).ok);
+    }
+
+    #[test]
+    fn test_free_disk_check() {
+        let dir = TempDir::new().unwrap();
+
+        assert!(check_free_disk(dir.path(), 0).ok);
+        assert!(!check_free_disk(dir.path(), u64::MAX).ok);
+        assert!(!check_free_disk(&dir.path().join("missing"), 0).ok);
+    }
+}
@@ -0,0 +1,194 @@
+//! Results of earlier runs, handed back to identical runs that opt in.
+//!
+//! A run is identified by a hash of everything that decides its output: the
+//! runtime and its version, the code and the execution flags. Results are kept
+//! in memory, for a limited time and up to a number of entries.
+
+use cloude_types::CacheStats;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CACHE_TTL_SECS: u64 = 3600;
+pub const DEFAULT_CACHE_MAX_ENTRIES: usize = 1024;
+
+/// Exit code and output of a finished run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedResult {
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+struct Entry {
+    result: CachedResult,
+    stored_at: Instant,
+    ttl: Duration,
+}
+
+impl Entry {
+    fn age(&self) -> Duration {
+        self.stored_at.elapsed()
+    }
+}
+
+pub struct ResultCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    max_entries: usize,
+    max_ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ResultCache {
+    /// A cache of up to `max_entries` results, each kept `max_ttl` at most.
+    pub fn new(max_entries: usize, max_ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+            max_ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// How long a result may be kept or reused when a run asks for `requested_secs`.
+    pub fn ttl(&self, requested_secs: Option<u64>) -> Duration {
+        requested_secs
+            .map(Duration::from_secs)
+            .unwrap_or(self.max_ttl)
+            .min(self.max_ttl)
+    }
+
+    /// The result stored under `key`, if it is younger than both its own TTL and `max_age`.
+    pub fn get(&self, key: &str, max_age: Duration) -> Option<CachedResult> {
+        let result = self
+            .lock()
+            .get(key)
+            .filter(|entry| entry.age() < entry.ttl.min(max_age))
+            .map(|entry| entry.result.clone());
+        let counter = match result {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    /// Keeps `result` under `key` for `ttl`. When the cache is full, expired
+    /// results go first, then the oldest one.
+    pub fn insert(&self, key: String, result: CachedResult, ttl: Duration) {
+        if self.max_entries == 0 || ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.lock();
+        if !entries.contains_key(&key) && entries.len() >= self.max_entries {
+            entries.retain(|_, entry| entry.age() < entry.ttl);
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.stored_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                result,
+                stored_at: Instant::now(),
+                ttl,
+            },
+        );
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.lock().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Key of a run made of `parts`, e.g. the runtime, its version and the code.
+/// Each part is length-prefixed, so moving bytes between parts changes the key.
+pub fn cache_key(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(stdout: &str) -> CachedResult {
+        CachedResult {
+            exit_code: 0,
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_hits_and_misses() {
+        let cache = ResultCache::new(8, Duration::from_secs(60));
+        assert_eq!(cache.get("a", Duration::from_secs(60)), None);
+
+        cache.insert("a".to_string(), result("1"), Duration::from_secs(60));
+        assert_eq!(cache.get("a", Duration::from_secs(60)), Some(result("1")));
+        // A run can ask for a fresher result than the one stored.
+        assert_eq!(cache.get("a", Duration::ZERO), None);
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                entries: 1,
+                hits: 1,
+                misses: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_ttl_is_capped() {
+        let cache = ResultCache::new(8, Duration::from_secs(60));
+        assert_eq!(cache.ttl(None), Duration::from_secs(60));
+        assert_eq!(cache.ttl(Some(5)), Duration::from_secs(5));
+        assert_eq!(cache.ttl(Some(3600)), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_full_cache_evicts_expired_then_oldest() {
+        let cache = ResultCache::new(2, Duration::from_secs(60));
+        let max_age = Duration::from_secs(60);
+        cache.insert("a".to_string(), result("1"), Duration::from_secs(60));
+        cache.insert("b".to_string(), result("2"), Duration::from_nanos(1));
+        cache.insert("c".to_string(), result("3"), Duration::from_secs(60));
+        assert_eq!(cache.get("a", max_age), Some(result("1")));
+        assert_eq!(cache.stats().entries, 2);
+
+        cache.insert("d".to_string(), result("4"), Duration::from_secs(60));
+        assert_eq!(cache.get("a", max_age), None);
+        assert_eq!(cache.get("c", max_age), Some(result("3")));
+        assert_eq!(cache.get("d", max_age), Some(result("4")));
+    }
+
+    #[test]
+    fn test_key_separates_parts() {
+        assert_eq!(cache_key(&[b"ab", b"c"]), cache_key(&[b"ab", b"c"]));
+        assert_ne!(cache_key(&[b"ab", b"c"]), cache_key(&[b"a", b"bc"]));
+    }
+}
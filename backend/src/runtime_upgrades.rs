@@ -0,0 +1,377 @@
+use crate::initramfs_manager::{BuildSite, InitramfsLanguage, installed_versions};
+use crate::template_manager::{TemplateBaker, TemplateError, TemplateRegistry};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+/// The version a runtime was moved to by the maintenance task.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RuntimeVersion {
+    pub language: String,
+    pub version: String,
+    pub base_image: String,
+    /// Version that was replaced by this upgrade.
+    pub upgraded_from: String,
+    /// Seconds since the Unix epoch at which the upgrade completed.
+    pub upgraded_at: u64,
+    /// Disk and blob store space freed by removing the previous version.
+    pub reclaimed_bytes: u64,
+}
+
+/// Errors that can occur while upgrading runtimes.
+#[derive(Debug)]
+pub enum UpgradeError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Template(TemplateError),
+}
+
+impl std::fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpgradeError::Io(e) => write!(f, "IO error: {}", e),
+            UpgradeError::Json(e) => write!(f, "JSON error: {}", e),
+            UpgradeError::Template(e) => write!(f, "Template migration failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UpgradeError {}
+
+impl From<std::io::Error> for UpgradeError {
+    fn from(err: std::io::Error) -> Self {
+        UpgradeError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for UpgradeError {
+    fn from(err: serde_json::Error) -> Self {
+        UpgradeError::Json(err)
+    }
+}
+
+impl From<TemplateError> for UpgradeError {
+    fn from(err: TemplateError) -> Self {
+        UpgradeError::Template(err)
+    }
+}
+
+/// Serializable state of completed upgrades, mapped directly to the JSON file on disk.
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct RuntimeVersionsState {
+    runtimes: HashMap<String, RuntimeVersion>, // language -> active version
+}
+
+/// Records which runtimes were upgraded, so the new version stays active
+/// across restarts even though the manifest still lists the old one.
+#[derive(Debug)]
+pub struct RuntimeVersions {
+    file_path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl RuntimeVersions {
+    pub fn new<P: AsRef<Path>>(file_path: P) -> Result<Self, UpgradeError> {
+        let versions = Self {
+            file_path: file_path.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        };
+
+        if !versions.file_path.exists() {
+            versions.write_state(&RuntimeVersionsState::default())?;
+        }
+
+        Ok(versions)
+    }
+
+    fn read_state(&self) -> Result<RuntimeVersionsState, UpgradeError> {
+        let mut file = match File::open(&self.file_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(RuntimeVersionsState::default());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        if contents.trim().is_empty() {
+            return Ok(RuntimeVersionsState::default());
+        }
+
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    fn write_state(&self, state: &RuntimeVersionsState) -> Result<(), UpgradeError> {
+        let json = serde_json::to_string_pretty(state)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.file_path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    pub fn get(&self, language: &str) -> Result<Option<RuntimeVersion>, UpgradeError> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(self.read_state()?.runtimes.remove(language))
+    }
+
+    pub fn record(&self, version: RuntimeVersion) -> Result<(), UpgradeError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut state = self.read_state()?;
+        state.runtimes.insert(version.language.clone(), version);
+        self.write_state(&state)
+    }
+
+    /// Returns whether the upgrade listed in the manifest for `language` already completed.
+    pub fn is_upgraded(&self, language: &InitramfsLanguage) -> Result<bool, UpgradeError> {
+        let Some(target) = &language.upgrade else {
+            return Ok(false);
+        };
+        Ok(self
+            .get(&language.name)?
+            .is_some_and(|active| active.version == target.version))
+    }
+
+    /// Applies completed upgrades to the runtimes read from the manifest:
+    /// an upgraded runtime runs its new version and has no pending upgrade left.
+    pub fn resolve(
+        &self,
+        languages: Vec<InitramfsLanguage>,
+    ) -> Result<Vec<InitramfsLanguage>, UpgradeError> {
+        let mut resolved = Vec::with_capacity(languages.len());
+        for language in languages {
+            if self.is_upgraded(&language)? {
+                resolved.push(upgraded(&language));
+            } else {
+                resolved.push(language);
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+/// The runtime as it looks once its pending upgrade is applied.
+fn upgraded(language: &InitramfsLanguage) -> InitramfsLanguage {
+    let mut upgraded = language.clone();
+    if let Some(target) = upgraded.upgrade.take() {
+        upgraded.version = target.version;
+        upgraded.base_image = target.base_image;
+    }
+    upgraded
+}
+
+/// Maintenance task moving runtimes to the version their manifest entry
+/// upgrades to: rebuilds the image, migrates the template, then deletes
+/// everything that belonged to the previous version.
+pub struct RuntimeUpgrader {
+    versions: Arc<RuntimeVersions>,
+    initramfs_dir: String,
+    agent_binary: String,
+    init_script: String,
+    templates: Arc<TemplateRegistry>,
+    baker: Option<Arc<TemplateBaker>>,
+}
+
+impl RuntimeUpgrader {
+    /// # Arguments
+    /// * `baker` - Used to migrate templates, `None` when template pre-baking is disabled.
+    pub fn new(
+        versions: Arc<RuntimeVersions>,
+        initramfs_dir: String,
+        agent_binary: String,
+        init_script: String,
+        templates: Arc<TemplateRegistry>,
+        baker: Option<Arc<TemplateBaker>>,
+    ) -> Self {
+        Self {
+            versions,
+            initramfs_dir,
+            agent_binary,
+            init_script,
+            templates,
+            baker,
+        }
+    }
+
+    /// Upgrades `language` if its manifest entry has a pending upgrade.
+    /// Returns the recorded version, or `None` when there was nothing to do.
+    pub async fn upgrade(
+        &self,
+        language: &InitramfsLanguage,
+    ) -> Result<Option<RuntimeVersion>, UpgradeError> {
+        if language.upgrade.is_none() || self.versions.is_upgraded(language)? {
+            return Ok(None);
+        }
+
+        let target = upgraded(language);
+        info!(
+            language = %language.name,
+            from = %language.version,
+            to = %target.version,
+            "Upgrading runtime"
+        );
+
+        // Building the new image removes the images of every other version.
+        let before = installed_versions(&self.initramfs_dir, &language.name)?;
+        target
+            .clone()
+            .setup_initramfs(&self.agent_binary, &self.init_script, &self.initramfs_dir)
+            .await?;
+        let after = installed_versions(&self.initramfs_dir, &language.name)?;
+
+        let mut reclaimed_bytes = before
+            .iter()
+            .filter(|(version, _)| !after.iter().any(|(kept, _)| kept == version))
+            .map(|(_, size)| size)
+            .sum::<u64>();
+
+        // Runtimes built on the host have no toolchain image, nor a template of it.
+        if let Some(baker) = &self.baker
+            && target.build == BuildSite::Vm
+        {
+            let previous = self.templates.get(&language.name)?;
+            let template = baker.ensure_template(&target).await?;
+            if let Some(previous) = previous
+                && previous.snapshot_key != template.snapshot_key
+            {
+                reclaimed_bytes += previous.size_bytes;
+            }
+        }
+
+        let version = RuntimeVersion {
+            language: language.name.clone(),
+            version: target.version.clone(),
+            base_image: target.base_image.clone(),
+            upgraded_from: language.version.clone(),
+            upgraded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            reclaimed_bytes,
+        };
+        self.versions.record(version.clone())?;
+
+        info!(
+            language = %version.language,
+            version = %version.version,
+            reclaimed_bytes = version.reclaimed_bytes,
+            "Runtime upgraded"
+        );
+        Ok(Some(version))
+    }
+
+    /// Applies every pending upgrade. A failure for one runtime is logged and
+    /// the runtime keeps its current version until the next run.
+    pub async fn run_pending(&self, languages: &[InitramfsLanguage]) -> Vec<RuntimeVersion> {
+        let mut upgraded = Vec::new();
+        for language in languages {
+            match self.upgrade(language).await {
+                Ok(Some(version)) => upgraded.push(version),
+                Ok(None) => {}
+                Err(e) => error!(language = %language.name, "Runtime upgrade failed: {}", e),
+            }
+        }
+        upgraded
+    }
+
+    /// Runs [`Self::run_pending`] now and then every `interval`.
+    pub fn spawn(self: Arc<Self>, languages: Vec<InitramfsLanguage>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let upgraded = self.run_pending(&languages).await;
+                if !upgraded.is_empty() {
+                    let reclaimed = upgraded.iter().map(|v| v.reclaimed_bytes).sum::<u64>();
+                    info!(
+                        "Upgraded {} runtime(s), reclaimed {} bytes",
+                        upgraded.len(),
+                        reclaimed
+                    );
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initramfs_manager::RuntimeUpgrade;
+    use tempfile::NamedTempFile;
+
+    fn python(upgrade: Option<&str>) -> InitramfsLanguage {
+        InitramfsLanguage {
+            name: "python".to_string(),
+            version: "3.12".to_string(),
+            base_image: "python:3.12-alpine".to_string(),
+            warmup_code: None,
+            upgrade: upgrade.map(|version| RuntimeUpgrade {
+                version: version.to_string(),
+                base_image: format!("python:{}-alpine", version),
+            }),
+            resources: None,
+            run_image: None,
+            arches: Vec::new(),
+            build: BuildSite::Vm,
+        }
+    }
+
+    fn record(versions: &RuntimeVersions, version: &str) {
+        versions
+            .record(RuntimeVersion {
+                language: "python".to_string(),
+                version: version.to_string(),
+                base_image: format!("python:{}-alpine", version),
+                upgraded_from: "3.12".to_string(),
+                upgraded_at: 0,
+                reclaimed_bytes: 0,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_resolve_pending_upgrade_keeps_current_version() {
+        let file = NamedTempFile::new().unwrap();
+        let versions = RuntimeVersions::new(file.path()).unwrap();
+
+        let resolved = versions.resolve(vec![python(Some("3.13"))]).unwrap();
+        assert_eq!(resolved[0].version, "3.12");
+        assert!(resolved[0].upgrade.is_some());
+    }
+
+    #[test]
+    fn test_resolve_completed_upgrade() {
+        let file = NamedTempFile::new().unwrap();
+        let versions = RuntimeVersions::new(file.path()).unwrap();
+        record(&versions, "3.13");
+
+        let resolved = RuntimeVersions::new(file.path())
+            .unwrap()
+            .resolve(vec![python(Some("3.13"))])
+            .unwrap();
+        assert_eq!(resolved[0].version, "3.13");
+        assert_eq!(resolved[0].base_image, "python:3.13-alpine");
+        assert!(resolved[0].upgrade.is_none());
+    }
+
+    #[test]
+    fn test_new_upgrade_target_is_pending_again() {
+        let file = NamedTempFile::new().unwrap();
+        let versions = RuntimeVersions::new(file.path()).unwrap();
+        record(&versions, "3.13");
+
+        assert!(!versions.is_upgraded(&python(Some("3.14"))).unwrap());
+        assert!(!versions.is_upgraded(&python(None)).unwrap());
+    }
+}
@@ -0,0 +1,160 @@
+//! Initramfs of the `scratch` run image: the agent and nothing else.
+//!
+//! Static binaries, like the musl ones of the Rust runtime, need no C library at
+//! run time, so their run image needs no base image either. Without a shell to
+//! run `init.sh`, the agent is `/init` and mounts the pseudo-filesystems itself.
+//! The archive is written here instead of being unpacked from a registry image.
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// `run_image` of runtimes whose binaries run in an image holding only the agent.
+pub const SCRATCH_IMAGE: &str = "scratch";
+
+/// Where the agent is installed, as in the images built from a registry.
+const AGENT_PATH: &str = "usr/bin/cloude-agentd";
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+
+/// Writes the gzipped initramfs of the scratch image to `out_path`, with
+/// `agent_binary` as its agent. The agent must be linked statically.
+pub fn write(out_path: &Path, agent_binary: &Path) -> std::io::Result<()> {
+    let agent = std::fs::read(agent_binary)?;
+    let file = BufWriter::new(File::create(out_path)?);
+    let mut archive = Archive {
+        out: GzEncoder::new(file, Compression::default()),
+        next_ino: 1,
+    };
+    for dir in ["dev", "proc", "sys", "run", "tmp", "usr", "usr/bin"] {
+        archive.entry(dir, S_IFDIR | 0o755, (0, 0), &[])?;
+    }
+    // The kernel opens it as the standard streams of init before running it.
+    archive.entry("dev/console", S_IFCHR | 0o600, (5, 1), &[])?;
+    archive.entry(AGENT_PATH, S_IFREG | 0o755, (0, 0), &agent)?;
+    archive.entry(
+        "init",
+        S_IFLNK | 0o777,
+        (0, 0),
+        format!("/{AGENT_PATH}").as_bytes(),
+    )?;
+    archive.entry("TRAILER!!!", 0, (0, 0), &[])?;
+    archive.out.finish()?.flush()
+}
+
+/// A cpio archive in the `newc` format, the one the kernel unpacks.
+struct Archive<W: Write> {
+    out: W,
+    next_ino: u32,
+}
+
+impl<W: Write> Archive<W> {
+    /// Appends `name` with `mode`, the device numbers of a device node, and the
+    /// content of a file or the target of a symlink.
+    fn entry(
+        &mut self,
+        name: &str,
+        mode: u32,
+        rdev: (u32, u32),
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let nlink = if mode & S_IFDIR == S_IFDIR { 2 } else { 1 };
+        let header = format!(
+            "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            ino,
+            mode,
+            0, // uid
+            0, // gid
+            nlink,
+            0, // mtime, so that the archive only changes with the agent
+            data.len(),
+            0, // devmajor
+            0, // devminor
+            rdev.0,
+            rdev.1,
+            name.len() + 1,
+            0, // check
+        );
+        self.out.write_all(header.as_bytes())?;
+        self.out.write_all(name.as_bytes())?;
+        self.out.write_all(&[0])?;
+        self.pad(header.len() + name.len() + 1)?;
+        self.out.write_all(data)?;
+        self.pad(data.len())
+    }
+
+    /// Pads what was just written, `len` bytes, to a multiple of 4.
+    fn pad(&mut self, len: usize) -> std::io::Result<()> {
+        self.out.write_all(&[0; 3][..(4 - len % 4) % 4])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    /// Name, mode and content of each entry of a `newc` archive.
+    fn entries(archive: &[u8]) -> Vec<(String, u32, Vec<u8>)> {
+        let field = |at: usize, i: usize| {
+            let start = at + 6 + i * 8;
+            u32::from_str_radix(std::str::from_utf8(&archive[start..start + 8]).unwrap(), 16)
+                .unwrap() as usize
+        };
+        let align = |n: usize| n.div_ceil(4) * 4;
+        let mut entries = Vec::new();
+        let mut at = 0;
+        while at < archive.len() {
+            assert_eq!(&archive[at..at + 6], b"070701");
+            let (mode, size, name_len) = (field(at, 1), field(at, 6), field(at, 11));
+            let name = &archive[at + 110..at + 110 + name_len - 1];
+            let data_at = align(at + 110 + name_len);
+            entries.push((
+                String::from_utf8(name.to_vec()).unwrap(),
+                mode as u32,
+                archive[data_at..data_at + size].to_vec(),
+            ));
+            at = align(data_at + size);
+        }
+        entries
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let agent = dir.path().join("cloude-agentd");
+        std::fs::write(&agent, b"\x7fELF agent").unwrap();
+        let out = dir.path().join("rust_run-1.81.cpio.gz");
+        write(&out, &agent).unwrap();
+
+        let mut archive = Vec::new();
+        GzDecoder::new(File::open(&out).unwrap())
+            .read_to_end(&mut archive)
+            .unwrap();
+        let entries = entries(&archive);
+        let find = |name: &str| entries.iter().find(|(n, _, _)| n == name).unwrap();
+
+        assert_eq!(find("usr/bin/cloude-agentd").2, b"\x7fELF agent");
+        assert_eq!(find("usr/bin/cloude-agentd").1, S_IFREG | 0o755);
+        assert_eq!(find("init").2, b"/usr/bin/cloude-agentd");
+        assert_eq!(find("dev/console").1, S_IFCHR | 0o600);
+        assert_eq!(entries.last().unwrap().0, "TRAILER!!!");
+        // Parents come before what they hold.
+        let position = |name: &str| entries.iter().position(|(n, _, _)| n == name).unwrap();
+        assert!(position("usr/bin") < position("usr/bin/cloude-agentd"));
+        assert!(position("dev") < position("dev/console"));
+
+        // The same agent gives the same archive.
+        let again = dir.path().join("again.cpio.gz");
+        write(&again, &agent).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), std::fs::read(&again).unwrap());
+    }
+}
@@ -0,0 +1,331 @@
+//! Operators' endpoints: audit trail, templates, caches, webhooks, keys, metrics
+//! and configuration reloads.
+
+use super::*;
+
+#[derive(Deserialize)]
+pub(crate) struct AuditQuery {
+    since: Option<u64>,
+}
+
+// ── GET /audit  –  export the audit trail ───────────────────────────
+
+pub(crate) async fn export_audit_log(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AuditQuery>,
+) -> impl IntoResponse {
+    let audit_log = state.audit_log.clone();
+    let exported = tokio::task::spawn_blocking(move || audit_log.export(query.since))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()));
+    match exported {
+        Ok(entries) => (StatusCode::OK, Json(serde_json::json!(entries))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({
+                "error": format!("Failed to read audit log: {e}"),
+            })),
+        ),
+    }
+}
+
+// ── GET /templates  –  list pre-baked VM templates ──────────────────
+
+pub(crate) async fn list_templates(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match state.templates.list() {
+        Ok(templates) => (StatusCode::OK, Json(serde_json::json!(templates))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({
+                "error": format!("Failed to read template registry: {e}"),
+            })),
+        ),
+    }
+}
+
+// ── GET /runtimes  –  configured runtimes ───────────────────────────
+
+pub(crate) async fn list_runtimes(State(state): State<Arc<AppState>>) -> Json<Vec<RuntimeInfo>> {
+    Json(runtimes(&state.config.current()))
+}
+
+/// Runtimes of `config`, by name.
+pub(crate) fn runtimes(config: &ReloadableConfig) -> Vec<RuntimeInfo> {
+    let mut runtimes: Vec<RuntimeInfo> = config
+        .languages
+        .iter()
+        .map(|lang| RuntimeInfo {
+            name: lang.name.to_ascii_lowercase(),
+            version: lang.version.clone(),
+            arches: lang.arches.clone(),
+        })
+        .collect();
+    runtimes.sort_by(|a, b| a.name.cmp(&b.name));
+    runtimes.dedup_by(|a, b| a.name == b.name);
+    runtimes
+}
+
+pub(crate) async fn cache_stats(State(state): State<Arc<AppState>>) -> Json<CacheStats> {
+    Json(state.result_cache.stats())
+}
+
+// ── GET /janitor  –  host cleanup counters ──────────────────────────
+
+pub(crate) async fn janitor_stats(State(state): State<Arc<AppState>>) -> Json<JanitorStats> {
+    Json(state.janitor.stats())
+}
+
+// ── GET /admin/webhooks  –  delivery of events to webhooks ──────────
+
+pub(crate) async fn webhook_status(State(state): State<Arc<AppState>>) -> Json<Vec<WebhookStatus>> {
+    Json(
+        state
+            .webhooks
+            .iter()
+            .map(|webhook| webhook.status())
+            .collect(),
+    )
+}
+
+// ── /admin/keys  –  API keys and their roles ────────────────────────
+
+pub(crate) fn api_key_error(e: ApiKeyError) -> axum::response::Response {
+    error!("API key store error: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(ErrorResponse::new(format!("Failed to store API keys: {e}"))),
+    )
+        .into_response()
+}
+
+pub(crate) fn api_key_not_found(id: &str) -> axum::response::Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse::new(format!("API key {id} not found"))),
+    )
+        .into_response()
+}
+
+pub(crate) async fn list_api_keys(State(state): State<Arc<AppState>>) -> Json<Vec<ApiKeyInfo>> {
+    Json(state.api_keys.list())
+}
+
+/// Role, profiles, tenant and namespace of `key`, for logs and the audit trail.
+pub(crate) fn key_detail(key: &ApiKeyInfo) -> String {
+    let mut detail = format!("role={}", key.role);
+    if let Some(profiles) = &key.profiles {
+        detail.push_str(&format!(" profiles={}", profiles.join(",")));
+    }
+    if let Some(tenant) = &key.tenant {
+        detail.push_str(&format!(" tenant={tenant}"));
+    }
+    if let Some(namespace) = &key.namespace {
+        detail.push_str(&format!(" namespace={namespace}"));
+    }
+    detail
+}
+
+pub(crate) async fn create_api_key(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    payload: Result<Json<CreateApiKeyRequest>, JsonRejection>,
+) -> axum::response::Response {
+    let source_ip = peer.source_ip();
+    let mut errors = ValidationErrors::default();
+    match &payload {
+        Ok(Json(request)) => {
+            if let Some(name) = &request.name {
+                validate_identifier(&mut errors, "name", name);
+            }
+            for profile in request.profiles.iter().flatten() {
+                validate_identifier(&mut errors, "profiles", profile);
+            }
+            if let Some(tenant) = &request.tenant
+                && (tenant.is_empty() || tenant.trim() != tenant)
+            {
+                errors.push("tenant", "cannot be empty or start or end with spaces");
+            }
+            if let Some(namespace) = &request.namespace {
+                validate_identifier(&mut errors, "namespace", namespace);
+            }
+        }
+        Err(rejection) => errors.push("body", rejection.body_text()),
+    }
+    let request = match payload {
+        Ok(Json(request)) if errors.is_empty() => request,
+        _ => {
+            record_audit(
+                &state,
+                actor
+                    .audit(&source_ip, "key.create", AuditOutcome::Rejected)
+                    .with_detail(errors.to_string()),
+            );
+            return validation_error_response(errors);
+        }
+    };
+
+    let created = match state.api_keys.create(
+        request.role,
+        request.name,
+        request.profiles,
+        request.tenant,
+        request.namespace,
+    ) {
+        Ok(created) => created,
+        Err(e) => return api_key_error(e),
+    };
+    let detail = key_detail(&created.info);
+    info!("API key {} created – {}", created.info.id, detail);
+    record_audit(
+        &state,
+        actor
+            .audit(&source_ip, "key.create", AuditOutcome::Accepted)
+            .with_target(created.info.id.clone())
+            .with_detail(detail),
+    );
+    (StatusCode::CREATED, Json(created)).into_response()
+}
+
+pub(crate) async fn assign_api_key_role(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    Path(id): Path<String>,
+    payload: Result<Json<AssignRoleRequest>, JsonRejection>,
+) -> axum::response::Response {
+    let source_ip = peer.source_ip();
+    let mut errors = ValidationErrors::default();
+    match &payload {
+        Ok(Json(request)) => {
+            for profile in request.profiles.iter().flatten() {
+                validate_identifier(&mut errors, "profiles", profile);
+            }
+        }
+        Err(rejection) => errors.push("body", rejection.body_text()),
+    }
+    let request = match payload {
+        Ok(Json(request)) if errors.is_empty() => request,
+        _ => return validation_error_response(errors),
+    };
+
+    match state.api_keys.assign(&id, request.role, request.profiles) {
+        Ok(Some(key)) => {
+            let detail = key_detail(&key);
+            info!("API key {} assigned {}", id, detail);
+            record_audit(
+                &state,
+                actor
+                    .audit(&source_ip, "key.assign", AuditOutcome::Accepted)
+                    .with_target(id)
+                    .with_detail(detail),
+            );
+            Json(key).into_response()
+        }
+        Ok(None) => api_key_not_found(&id),
+        Err(e) => api_key_error(e),
+    }
+}
+
+pub(crate) async fn revoke_api_key(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    Path(id): Path<String>,
+) -> axum::response::Response {
+    match state.api_keys.revoke(&id) {
+        Ok(true) => {
+            info!("API key {} revoked", id);
+            record_audit(
+                &state,
+                actor
+                    .audit(&peer.source_ip(), "key.revoke", AuditOutcome::Accepted)
+                    .with_target(id),
+            );
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => api_key_not_found(&id),
+        Err(e) => api_key_error(e),
+    }
+}
+
+// ── GET /.well-known/jwks.json  –  keys of the VM identity tokens ───
+
+pub(crate) async fn identity_jwks(State(state): State<Arc<AppState>>) -> Json<Jwks> {
+    Json(state.identity.jwks())
+}
+
+// ── GET /metrics  –  Prometheus metrics ─────────────────────────────
+
+pub(crate) async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    // Hosts that merge no memory of VMs, or cannot, export no KSM metrics.
+    let ksm = state
+        .ksm
+        .as_ref()
+        .filter(|_| state.config.current().ksm.enabled)
+        .and_then(|ksm| ksm.stats().ok());
+    let text = state.metrics.render(
+        &state.heartbeats.list(),
+        &state.vm_pool.buckets(),
+        ksm.as_ref(),
+    );
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            backend::metrics::CONTENT_TYPE,
+        )],
+        text,
+    )
+}
+
+// ── POST /admin/reload  –  apply configuration changes ──────────────
+
+/// Re-reads `cloude.toml` and the runtime manifest. Running VMs are left untouched;
+/// an invalid configuration is rejected as a whole and the current one stays active.
+pub(crate) async fn reload_config(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+) -> axum::response::Response {
+    let source_ip = peer.source_ip();
+
+    match state.config.reload().await {
+        Ok(changes) => {
+            apply_ksm(&state, &state.config.current().ksm);
+            record_audit(
+                &state,
+                actor
+                    .audit(&source_ip, "config.reload", AuditOutcome::Accepted)
+                    .with_detail(changes.join("; ")),
+            );
+            (
+                StatusCode::OK,
+                Json(serde_json::json!({ "changes": changes })),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            error!("Configuration reload rejected: {}", e);
+            record_audit(
+                &state,
+                actor
+                    .audit(&source_ip, "config.reload", AuditOutcome::Rejected)
+                    .with_detail(e.to_string()),
+            );
+            match e {
+                ConfigError::Invalid(errors) => validation_error_response(errors),
+                ConfigError::Toml(_) => (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(serde_json::json!({ "error": e.to_string() })),
+                )
+                    .into_response(),
+                ConfigError::Io(_) | ConfigError::Runtime(_) | ConfigError::Build(_) => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(serde_json::json!({ "error": e.to_string() })),
+                )
+                    .into_response(),
+            }
+        }
+    }
+}
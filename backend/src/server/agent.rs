@@ -0,0 +1,223 @@
+//! Requests to the agent in the VM of a job, and its liveness.
+
+use super::*;
+
+/// Ask the agent of the VM running job `job_id` for a heartbeat every interval, and
+/// notify `vm_lost` once the VM exits or misses its heartbeats for the timeout.
+/// The traffic of the VM since the job started, if counted, is read at every heartbeat.
+pub(crate) async fn watch_heartbeats(
+    state: Arc<AppState>,
+    job_id: String,
+    agent_url: String,
+    vmm_running: vmm::StopHandle,
+    traffic: Option<(TrafficMeter, TrafficStats)>,
+    vm_lost: Arc<tokio::sync::Notify>,
+) {
+    let health_url = format!("{}/health", agent_url.trim_end_matches('/'));
+    let interval = state.heartbeats.interval();
+    loop {
+        tokio::time::sleep(interval).await;
+        if !vmm_running.is_running() {
+            state.heartbeats.mark_exited(&job_id);
+            warn!("Job {} – VM exited while running the job", job_id);
+            vm_lost.notify_one();
+            return;
+        }
+
+        let answered = state
+            .client
+            .get(&health_url)
+            .timeout(interval)
+            .send()
+            .await
+            .is_ok_and(|resp| resp.status().is_success());
+        if let Some(job_traffic) = traffic.as_ref().and_then(traffic_since) {
+            state.heartbeats.record_traffic(&job_id, job_traffic);
+        }
+        if answered {
+            state.heartbeats.beat(&job_id);
+        } else if state.heartbeats.liveness(&job_id) == Some(VmLiveness::Unresponsive) {
+            warn!(
+                "Job {} – VM agent missed its heartbeats for {:?}, stopping the job",
+                job_id,
+                state.heartbeats.timeout()
+            );
+            vm_lost.notify_one();
+            return;
+        }
+    }
+}
+
+/// Ask the agent of a pooled VM to kill what earlier jobs left running and to
+/// give the next job a clean filesystem.
+pub(crate) async fn reset_agent(
+    client: &reqwest::Client,
+    agent_url: &str,
+) -> Result<ResetResponse, String> {
+    let reset_url = format!("{}/reset", agent_url.trim_end_matches('/'));
+    let resp = client
+        .post(&reset_url)
+        .send()
+        .await
+        .map_err(|e| format!("Cannot reach VM agent: {e}"))?;
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(format!("Agent returned HTTP {status}: {body}"));
+    }
+    resp.json::<ResetResponse>()
+        .await
+        .map_err(|e| format!("Failed to parse agent response: {e}"))
+}
+
+/// Ask the agent at `agent_url` to open channel `id` to the code of its job, and
+/// return the stream of the channel its answer is upgraded to.
+pub(crate) async fn open_agent_channel(
+    client: &reqwest::Client,
+    agent_url: &str,
+    id: &str,
+) -> Result<reqwest::Upgraded, String> {
+    let channel_url = format!("{}/channels/{}", agent_url.trim_end_matches('/'), id);
+    let resp = client
+        .post(&channel_url)
+        .header(reqwest::header::CONNECTION, "upgrade")
+        .header(reqwest::header::UPGRADE, CHANNEL_PROTOCOL)
+        .send()
+        .await
+        .map_err(|e| format!("Cannot reach VM agent: {e}"))?;
+    let status = resp.status();
+    if status != StatusCode::SWITCHING_PROTOCOLS {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(format!("Agent returned HTTP {status}: {body}"));
+    }
+    resp.upgrade()
+        .await
+        .map_err(|e| format!("Agent did not upgrade to the channel: {e}"))
+}
+
+/// Ask the agent to step the guest clock to the host time, for guests that lost
+/// track of it: pooled VMs, and VMs restored from a snapshot.
+pub(crate) async fn sync_clock(
+    client: &reqwest::Client,
+    agent_url: &str,
+) -> Result<ClockResponse, String> {
+    let clock_url = format!("{}/clock", agent_url.trim_end_matches('/'));
+    let unix_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|e| format!("Host clock is before the Unix epoch: {e}"))?
+        .as_millis() as u64;
+    let resp = client
+        .post(&clock_url)
+        .json(&ClockRequest { unix_ms })
+        .send()
+        .await
+        .map_err(|e| format!("Cannot reach VM agent: {e}"))?;
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(format!("Agent returned HTTP {status}: {body}"));
+    }
+    resp.json::<ClockResponse>()
+        .await
+        .map_err(|e| format!("Failed to parse agent response: {e}"))
+}
+
+/// Hand the agent a new identity token for the running job it names.
+pub(crate) async fn send_identity(
+    client: &reqwest::Client,
+    agent_url: &str,
+    token: String,
+) -> Result<(), String> {
+    let identity_url = format!("{}/identity", agent_url.trim_end_matches('/'));
+    let resp = client
+        .put(&identity_url)
+        .json(&IdentityRequest { token })
+        .send()
+        .await
+        .map_err(|e| format!("Cannot reach VM agent: {e}"))?;
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(format!("Agent returned HTTP {status}: {body}"));
+    }
+    Ok(())
+}
+
+/// Ask the agent to reseed the guest kernel RNG from the host, through the
+/// virtio-rng device, so that VMs cloned from one snapshot do not share its state.
+pub(crate) async fn reseed_entropy(
+    client: &reqwest::Client,
+    agent_url: &str,
+) -> Result<EntropyResponse, String> {
+    let entropy_url = format!("{}/entropy", agent_url.trim_end_matches('/'));
+    let resp = client
+        .post(&entropy_url)
+        .send()
+        .await
+        .map_err(|e| format!("Cannot reach VM agent: {e}"))?;
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(format!("Agent returned HTTP {status}: {body}"));
+    }
+    resp.json::<EntropyResponse>()
+        .await
+        .map_err(|e| format!("Failed to parse agent response: {e}"))
+}
+
+/// Forward a job to the agent. Errors mean the agent could not be reached.
+pub(crate) async fn send_to_agent(
+    client: &reqwest::Client,
+    execute_url: &str,
+    request: &ExecuteRequest,
+) -> Result<reqwest::Response, String> {
+    #[cfg(feature = "chaos")]
+    if backend::chaos::fire(
+        backend::chaos::FaultKind::AgentConnection,
+        &request.language,
+    )
+    .is_some()
+    {
+        return Err("connection dropped by injected fault".to_string());
+    }
+
+    client
+        .post(execute_url)
+        .json(request)
+        .send()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Reads the [`ExecuteChunk`] lines of a streamed execution, handing the standard
+/// output to `tap` as it comes, until the result.
+pub(crate) async fn read_streamed_result(
+    mut resp: reqwest::Response,
+    tap: &tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+) -> Result<ExecutionResult, String> {
+    let mut pending = Vec::new();
+    loop {
+        let chunk = resp
+            .chunk()
+            .await
+            .map_err(|e| format!("Failed to read agent response: {e}"))?
+            .ok_or_else(|| "Agent response ended before the result".to_string())?;
+        pending.extend_from_slice(&chunk);
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let chunk = serde_json::from_slice::<ExecuteChunk>(&line)
+                .map_err(|e| format!("Failed to parse agent response: {e}"))?;
+            match chunk {
+                ExecuteChunk::Stdout(data) => {
+                    let data = BASE64_STANDARD
+                        .decode(data)
+                        .map_err(|e| format!("Failed to parse agent response: {e}"))?;
+                    // The caller may have gone: the job still runs to the end.
+                    let _ = tap.send(data);
+                }
+                ExecuteChunk::Result(result) => return Ok(result),
+                ExecuteChunk::Error(e) => return Err(format!("Agent failed to run the job: {e}")),
+            }
+        }
+    }
+}
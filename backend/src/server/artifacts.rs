@@ -0,0 +1,188 @@
+//! `/artifacts`: code too large to inline, uploaded whole or resumably.
+
+use super::*;
+
+// ── Artifacts  –  upload code too large to inline ───────────────────
+
+pub(crate) fn artifact_error_response(e: ArtifactError) -> axum::response::Response {
+    let status = match e {
+        ArtifactError::UploadNotFound(_) => StatusCode::NOT_FOUND,
+        ArtifactError::OffsetMismatch { .. } => StatusCode::CONFLICT,
+        ArtifactError::TooLarge { .. } => {
+            let mut errors = ValidationErrors::default();
+            errors.push("body", e.to_string());
+            return validation_error_response(errors);
+        }
+        ArtifactError::Io(_)
+        | ArtifactError::Json(_)
+        | ArtifactError::Blob(_)
+        | ArtifactError::Backend(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (
+        status,
+        Json(serde_json::json!({
+            "error": e.to_string(),
+        })),
+    )
+        .into_response()
+}
+
+/// Parse a numeric upload header, reporting a field error when it is missing or invalid.
+pub(crate) fn upload_header(headers: &HeaderMap, name: &str) -> Result<u64, ValidationErrors> {
+    match headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+    {
+        Some(value) => Ok(value),
+        None => {
+            let mut errors = ValidationErrors::default();
+            errors.push(
+                name,
+                "header is required and must be a non-negative integer",
+            );
+            Err(errors)
+        }
+    }
+}
+
+pub(crate) fn upload_response(
+    status: StatusCode,
+    upload: backend::artifact_store::UploadStatus,
+) -> axum::response::Response {
+    (
+        status,
+        [
+            ("Upload-Offset", upload.offset.to_string()),
+            ("Upload-Length", upload.length.to_string()),
+        ],
+        Json(upload),
+    )
+        .into_response()
+}
+
+// POST /artifacts  –  upload a whole artifact in one request
+pub(crate) async fn upload_artifact(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    body: Bytes,
+) -> axum::response::Response {
+    let upload = match state.artifact_uploads.create(body.len() as u64).await {
+        Ok(upload) => upload,
+        Err(e) => return artifact_error_response(e),
+    };
+
+    let status = match state
+        .artifact_uploads
+        .append(&state.artifact_store, &upload.upload_id, 0, &body)
+        .await
+    {
+        Ok(status) => status,
+        Err(e) => return artifact_error_response(e),
+    };
+
+    match status.artifact {
+        Some(artifact) => {
+            record_audit(
+                &state,
+                actor
+                    .audit(&peer.source_ip(), "artifact.upload", AuditOutcome::Accepted)
+                    .with_target(artifact.id.clone()),
+            );
+            (StatusCode::CREATED, Json(artifact)).into_response()
+        }
+        None => artifact_error_response(ArtifactError::Backend(
+            "upload did not complete".to_string(),
+        )),
+    }
+}
+
+// POST /artifacts/uploads  –  start a resumable upload (`Upload-Length` header)
+pub(crate) async fn create_artifact_upload(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let length = match upload_header(&headers, "Upload-Length") {
+        Ok(length) => length,
+        Err(errors) => return validation_error_response(errors),
+    };
+
+    match state.artifact_uploads.create(length).await {
+        Ok(upload) => {
+            record_audit(
+                &state,
+                actor
+                    .audit(
+                        &peer.source_ip(),
+                        "artifact.upload.create",
+                        AuditOutcome::Accepted,
+                    )
+                    .with_target(upload.upload_id.clone())
+                    .with_detail(format!("length={}", length)),
+            );
+            upload_response(StatusCode::CREATED, upload)
+        }
+        Err(e) => artifact_error_response(e),
+    }
+}
+
+// GET|HEAD /artifacts/uploads/{id}  –  resume point of an upload
+pub(crate) async fn get_artifact_upload(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    match state.artifact_uploads.status(&id).await {
+        Ok(upload) => upload_response(StatusCode::OK, upload),
+        Err(e) => artifact_error_response(e),
+    }
+}
+
+// PATCH /artifacts/uploads/{id}  –  append a chunk at `Upload-Offset`
+pub(crate) async fn append_artifact_upload(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    let offset = match upload_header(&headers, "Upload-Offset") {
+        Ok(offset) => offset,
+        Err(errors) => return validation_error_response(errors),
+    };
+
+    match state
+        .artifact_uploads
+        .append(&state.artifact_store, &id, offset, &body)
+        .await
+    {
+        Ok(upload) => {
+            if let Some(artifact) = &upload.artifact {
+                record_audit(
+                    &state,
+                    actor
+                        .audit(&peer.source_ip(), "artifact.upload", AuditOutcome::Accepted)
+                        .with_target(artifact.id.clone()),
+                );
+            }
+            upload_response(StatusCode::OK, upload)
+        }
+        Err(e) => artifact_error_response(e),
+    }
+}
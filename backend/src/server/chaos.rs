@@ -0,0 +1,51 @@
+//! `/admin/chaos`: failure injection, with the `chaos` feature only.
+
+use super::*;
+use backend::chaos::Fault;
+
+// GET /admin/chaos  –  faults still armed
+pub(crate) async fn list_faults() -> impl IntoResponse {
+    (StatusCode::OK, Json(backend::chaos::list()))
+}
+
+// PUT /admin/chaos  –  replace the armed faults
+pub(crate) async fn set_faults(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    payload: Result<Json<Vec<Fault>>, JsonRejection>,
+) -> axum::response::Response {
+    let faults = match payload {
+        Ok(Json(faults)) => faults,
+        Err(rejection) => {
+            let mut errors = ValidationErrors::default();
+            errors.push("body", rejection.body_text());
+            return validation_error_response(errors);
+        }
+    };
+
+    record_audit(
+        &state,
+        actor
+            .audit(&peer.source_ip(), "chaos.set", AuditOutcome::Accepted)
+            .with_detail(format!("{} fault(s)", faults.len())),
+    );
+    backend::chaos::set(faults);
+    (StatusCode::OK, Json(backend::chaos::list())).into_response()
+}
+
+// DELETE /admin/chaos  –  disarm every fault
+pub(crate) async fn clear_faults(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+) -> impl IntoResponse {
+    record_audit(
+        &state,
+        actor
+            .audit(&peer.source_ip(), "chaos.clear", AuditOutcome::Accepted)
+            .with_detail(format!("{} fault(s)", backend::chaos::list().len())),
+    );
+    backend::chaos::set(Vec::new());
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,159 @@
+//! `GET /dashboard/functions`, and the invocations recorded for it.
+
+use super::*;
+
+// ── GET /dashboard/functions  –  aggregates of the invocations ──────
+
+/// Records the start or the end of job `job_id` in the dashboards, when it runs
+/// a function.
+pub(crate) async fn record_invocation(state: &AppState, event: &LifecycleEvent, job_id: &str) {
+    let jobs = state.jobs.read().await;
+    let Some(job) = jobs.get(job_id) else {
+        return;
+    };
+    let Some((function, _)) = &job.function else {
+        return;
+    };
+    match event.kind {
+        EventKind::ExecutionStarted => {
+            let running = jobs
+                .values()
+                .filter(|j| {
+                    j.status == JobStatus::Running
+                        && j.namespace == job.namespace
+                        && j.tenant == job.tenant
+                        && j.function.as_ref().is_some_and(|(f, _)| f == function)
+                })
+                .count();
+            state.dashboards.record_concurrency(
+                job.namespace.as_str(),
+                function,
+                job.tenant.as_deref(),
+                event.timestamp_ms,
+                running as u32,
+            );
+        }
+        EventKind::ExecutionFinished | EventKind::ExecutionFailed => {
+            let invocation = Invocation {
+                finished_ms: event.timestamp_ms,
+                duration_ms: job
+                    .duration_ms
+                    .unwrap_or_else(|| job.created_at.elapsed().as_millis() as u64),
+                failed: job.status != JobStatus::Done || job.exit_code != Some(0),
+                warm: job.warm,
+            };
+            state.dashboards.record(
+                job.namespace.as_str(),
+                function,
+                job.tenant.as_deref(),
+                invocation,
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Bounds and buckets of `query` in milliseconds, checked.
+pub(crate) fn dashboard_range(
+    query: &DashboardQuery,
+    retention: std::time::Duration,
+) -> Result<(u64, u64, u64), ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    let bucket_secs = query.bucket_secs.unwrap_or(60);
+    if bucket_secs == 0 || !bucket_secs.is_multiple_of(60) {
+        errors.push("bucket_secs", "must be a multiple of 60");
+    }
+    let to = query.to.unwrap_or_else(|| unix_millis() / 1000);
+    let from = query.from.unwrap_or(to.saturating_sub(3600));
+    if from >= to {
+        errors.push("from", "must be before to");
+    } else if to - from > retention.as_secs() {
+        errors.push(
+            "from",
+            format!(
+                "must be at most {} seconds before to, the retention of the dashboards",
+                retention.as_secs()
+            ),
+        );
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let from = from - from % bucket_secs;
+    if (to - from).div_ceil(bucket_secs) > MAX_DASHBOARD_BUCKETS {
+        errors.push(
+            "bucket_secs",
+            format!("must make at most {MAX_DASHBOARD_BUCKETS} buckets"),
+        );
+        return Err(errors);
+    }
+    Ok((from * 1000, to * 1000, bucket_secs * 1000))
+}
+
+/// Latency percentiles, error rates, cold and warm starts and concurrency of the
+/// functions of the namespace over time, as the caller invoked them.
+pub(crate) async fn function_dashboards(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    namespace: Namespace,
+    Query(query): Query<DashboardQuery>,
+) -> axum::response::Response {
+    let (from_ms, to_ms, bucket_ms) = match dashboard_range(&query, state.dashboards.retention()) {
+        Ok(range) => range,
+        Err(errors) => return validation_error_response(errors),
+    };
+    let functions = state.dashboards.query(
+        namespace.as_str(),
+        actor.tenant.as_deref(),
+        None,
+        from_ms,
+        to_ms,
+        bucket_ms,
+    );
+    Json(Dashboard {
+        from: from_ms / 1000,
+        to: to_ms / 1000,
+        bucket_secs: bucket_ms / 1000,
+        functions,
+    })
+    .into_response()
+}
+
+/// The dashboard of function `name` alone, `404` when it is not deployed.
+pub(crate) async fn function_dashboard(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    namespace: Namespace,
+    Path(name): Path<String>,
+    Query(query): Query<DashboardQuery>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "name", &name);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+    match state.functions.get(&namespace, &name) {
+        Ok(Some(_)) => {}
+        Ok(None) => return function_not_found(&name),
+        Err(e) => return function_registry_error(e),
+    }
+    let (from_ms, to_ms, bucket_ms) = match dashboard_range(&query, state.dashboards.retention()) {
+        Ok(range) => range,
+        Err(errors) => return validation_error_response(errors),
+    };
+    let found = state.dashboards.query(
+        namespace.as_str(),
+        actor.tenant.as_deref(),
+        Some(&name),
+        from_ms,
+        to_ms,
+        bucket_ms,
+    );
+    Json(Dashboard {
+        from: from_ms / 1000,
+        to: to_ms / 1000,
+        bucket_secs: bucket_ms / 1000,
+        functions: found,
+    })
+    .into_response()
+}
@@ -0,0 +1,305 @@
+//! Jobs as they run and once they ended: status, events, logs and core dumps.
+
+use super::*;
+
+#[derive(Deserialize)]
+pub(crate) struct LogsQuery {
+    #[serde(default)]
+    follow: bool,
+}
+
+// ── GET /status/:id  –  query job result ────────────────────────────
+
+pub(crate) async fn get_status(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    Path(id): Path<String>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    let jobs = state.jobs.read().await;
+
+    match jobs.get(&id).filter(|job| job.namespace == namespace) {
+        Some(job) => (
+            StatusCode::OK,
+            Json(StatusResponse {
+                id: job.id.clone(),
+                status: job.status,
+                exit_code: job.exit_code,
+                stdout: job.stdout.clone(),
+                stderr: job.stderr.clone(),
+                isolation: job.isolation,
+                traffic: job.traffic,
+                oom_killed: job.oom_killed,
+                signal: job.signal.clone(),
+                core_dump: job.core_dump,
+            }),
+        )
+            .into_response(),
+        None => job_not_found(&id),
+    }
+}
+
+// ── GET /events  –  lifecycle events as server-sent events ──────────
+
+/// Streams the events published from now on, one server-sent event each, named
+/// after its kind with the event as JSON data. Clients that fall behind skip
+/// the events they missed.
+pub(crate) async fn stream_events(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+) -> Sse<impl futures_util::Stream<Item = Result<SseEvent, axum::Error>>> {
+    let events = futures_util::stream::unfold(state.events.subscribe(), |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => return Some((event, events)),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    // Events of the jobs of other namespaces are left out, and those of no job,
+    // like an idle VM shut down, only go to the default namespace.
+    let events = events.filter(move |event| {
+        let (state, namespace) = (Arc::clone(&state), namespace.clone());
+        let job_id = event.job_id.clone();
+        async move {
+            match job_id {
+                Some(id) => job_in(&state, &namespace, &id).await,
+                None => namespace.is_default(),
+            }
+        }
+    });
+    Sse::new(events.map(|event| {
+        SseEvent::default()
+            .event(event.kind.to_string())
+            .json_data(&event)
+    }))
+    .keep_alive(KeepAlive::default())
+}
+
+// ── GET /logs/:id  –  stored or live logs of a job ──────────────────
+
+/// Returns the logs of a job as a JSON array. With `?follow=true`, streams
+/// them instead as one JSON line per log line (NDJSON), including new lines
+/// as they arrive, until the job finishes.
+pub(crate) async fn get_logs(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    Path(id): Path<String>,
+    Query(query): Query<LogsQuery>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+    if !job_in(&state, &namespace, &id).await {
+        return job_not_found(&id);
+    }
+
+    let log = state.logs.read().await.get(&id).cloned();
+    let Some(log) = log else {
+        return job_not_found(&id);
+    };
+
+    if !query.follow {
+        return (StatusCode::OK, Json(log.lines())).into_response();
+    }
+
+    let (backlog, updates) = log.follow();
+    let live = futures_util::stream::unfold(updates, |updates| async move {
+        let mut updates = updates?;
+        loop {
+            match updates.recv().await {
+                Ok(line) => return Some((line, Some(updates))),
+                // A slow follower misses some lines rather than holding them all in memory.
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    let body = futures_util::stream::iter(backlog)
+        .chain(live)
+        .map(|line: LogLine| {
+            let mut json = serde_json::to_vec(&line)?;
+            json.push(b'\n');
+            Ok::<_, serde_json::Error>(json)
+        });
+
+    (
+        StatusCode::OK,
+        [(axum::http::header::CONTENT_TYPE, "application/x-ndjson")],
+        axum::body::Body::from_stream(body),
+    )
+        .into_response()
+}
+
+// ── GET /core/:id  –  core dump of a crashed job ────────────────────
+
+/// Returns the core the code of a job dumped when it crashed.
+pub(crate) async fn get_core_dump(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    Path(id): Path<String>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    let core_dump = match state
+        .jobs
+        .read()
+        .await
+        .get(&id)
+        .filter(|job| job.namespace == namespace)
+    {
+        Some(job) => job.core_dump,
+        None => return job_not_found(&id),
+    };
+    if !core_dump {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new(format!("Job {id} dumped no core"))),
+        )
+            .into_response();
+    }
+
+    match state.core_dumps.get(&id).await {
+        Ok(Some(core)) => (
+            StatusCode::OK,
+            [(axum::http::header::CONTENT_TYPE, "application/octet-stream")],
+            core,
+        )
+            .into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new(format!("Core dump of job {id} is gone"))),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse::new(format!(
+                "Cannot read core dump of job {id}: {e}"
+            ))),
+        )
+            .into_response(),
+    }
+}
+
+// ── GET /executions  –  jobs, a page at a time ──────────────────────
+
+pub(crate) const EXECUTIONS: Listing = Listing {
+    filters: &[
+        Filter::Status,
+        Filter::Runtime,
+        Filter::Tenant,
+        Filter::CreatedAt,
+    ],
+    sorts: &[
+        SortField::CreatedAt,
+        SortField::Id,
+        SortField::Status,
+        SortField::Runtime,
+    ],
+    default_sort: SortField::CreatedAt,
+    default_order: Order::Desc,
+};
+
+/// Lists the jobs of the namespace, the latest first, see [`backend::listing`].
+pub(crate) async fn list_executions(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    query: ListQuery,
+) -> axum::response::Response {
+    let entries = state
+        .jobs
+        .read()
+        .await
+        .values()
+        .filter(|job| job.namespace == namespace)
+        .map(|job| {
+            let created_at = submitted_at(job);
+            Entry {
+                id: job.id.clone(),
+                status: Some(job.status.to_string()),
+                runtime: Some(job.language.clone()),
+                tenant: job.tenant.clone(),
+                created_at: Some(created_at),
+                item: ExecutionSummary {
+                    id: job.id.clone(),
+                    status: job.status,
+                    language: job.language.clone(),
+                    isolation: job.isolation,
+                    exit_code: job.exit_code,
+                    tenant: job.tenant.clone(),
+                    created_at,
+                    duration_ms: job.duration_ms,
+                },
+            }
+        })
+        .collect();
+    match listing::page(entries, &query, &EXECUTIONS) {
+        Ok(page) => page.into_response(),
+        Err(errors) => validation_error_response(errors),
+    }
+}
+
+// ── GET /executions/:a/diff/:b  –  compare two executions ───────────
+
+/// Returns how two finished jobs differ, see [`cloude_types::ExecutionDiff`].
+pub(crate) async fn diff_executions(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    Path((a, b)): Path<(String, String)>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "a", &a);
+    validate_identifier(&mut errors, "b", &b);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    let jobs = state.jobs.read().await;
+    let mut executions = Vec::with_capacity(2);
+    for id in [&a, &b] {
+        let Some(job) = jobs.get(id).filter(|job| job.namespace == namespace) else {
+            return job_not_found(id);
+        };
+        if !job.status.is_terminal() {
+            return (
+                StatusCode::CONFLICT,
+                Json(ErrorResponse::new(format!("Job {id} is not finished"))),
+            )
+                .into_response();
+        }
+        executions.push(execution(job));
+    }
+    (StatusCode::OK, Json(diff(&executions[0], &executions[1]))).into_response()
+}
+
+pub(crate) fn execution(job: &Job) -> Execution<'_> {
+    Execution {
+        id: &job.id,
+        status: job.status,
+        language: &job.language,
+        code_digest: job.code_digest.as_deref(),
+        image_digest: job.image_digest.as_deref(),
+        exit_code: job.exit_code,
+        signal: job.signal.as_deref(),
+        stdout: job.stdout.as_deref().unwrap_or_default(),
+        stderr: job.stderr.as_deref().unwrap_or_default(),
+        usage: ResourceUsage {
+            vcpus: job.shape.map(|shape| shape.vcpus),
+            memory_mb: job.shape.map(|shape| shape.memory_mb),
+            duration_ms: job.duration_ms,
+            traffic: job.traffic,
+        },
+    }
+}
@@ -0,0 +1,762 @@
+//! Named functions deployed from artifacts, their bundles and declarative specs.
+
+use super::*;
+
+/// Check a `DeployRequest` like a run request, and normalize its language.
+pub(crate) fn validate_deploy_request(
+    name: &str,
+    request: DeployRequest,
+) -> Result<DeployRequest, ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "name", name);
+
+    let language = normalize_language_alias(&request.language.trim().to_ascii_lowercase());
+    validate_identifier(&mut errors, "language", &language);
+    validate_artifact_id(&mut errors, "artifact", &request.artifact);
+    if let Some(entrypoint) = &request.entrypoint {
+        validate_relative_path(&mut errors, "entrypoint", entrypoint);
+    }
+
+    errors.into_result(DeployRequest {
+        language,
+        ..request
+    })
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ExportQuery {
+    /// Whether the bundle carries the content of the artifacts, by default.
+    artifacts: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ImportQuery {
+    #[serde(default)]
+    on_conflict: OnConflict,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ApplyQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+// ── /functions  –  named functions deployed from artifacts ──────────
+
+pub(crate) fn function_registry_error(e: FunctionError) -> axum::response::Response {
+    error!("Function registry error: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(ErrorResponse::new(format!(
+            "Failed to access function registry: {e}"
+        ))),
+    )
+        .into_response()
+}
+
+pub(crate) fn function_not_found(name: &str) -> axum::response::Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse::new(format!("Function {name} not found"))),
+    )
+        .into_response()
+}
+
+// PUT /functions/{name}  –  create a function or point it to new code
+pub(crate) async fn deploy_function(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    namespace: Namespace,
+    Path(name): Path<String>,
+    payload: Result<Json<DeployRequest>, JsonRejection>,
+) -> axum::response::Response {
+    let source_ip = peer.source_ip();
+    let rejected = |detail: String| {
+        actor
+            .audit(&source_ip, "function.deploy", AuditOutcome::Rejected)
+            .with_target(name.clone())
+            .with_detail(detail)
+    };
+
+    let payload = match payload {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => {
+            let mut errors = ValidationErrors::default();
+            errors.push("body", rejection.body_text());
+            record_audit(&state, rejected(errors.to_string()));
+            return validation_error_response(errors);
+        }
+    };
+
+    let requested_language = payload.language.clone();
+    let request = match validate_deploy_request(&name, payload) {
+        Ok(request) => request,
+        Err(errors) => {
+            record_audit(&state, rejected(errors.to_string()));
+            return validation_error_response(errors);
+        }
+    };
+    if let Some(response) = unsupported_language(
+        &state.config.current(),
+        &request.language,
+        &requested_language,
+    ) {
+        record_audit(
+            &state,
+            rejected(format!("Unsupported language: {}", requested_language)),
+        );
+        return response;
+    }
+    if let Some((detail, response)) =
+        unsupported_arch(&state.config.current(), &request.language, state.vm_arch)
+    {
+        record_audit(&state, rejected(detail));
+        return response;
+    }
+
+    // Catches a missing artifact or entrypoint now rather than on the first run.
+    if let Err((detail, response)) =
+        load_artifact_code(&state, &request.artifact, request.entrypoint.as_deref()).await
+    {
+        record_audit(&state, rejected(detail));
+        return response;
+    }
+
+    let (function, previous) = match state.functions.deploy(&namespace, &name, request) {
+        Ok(deployed) => deployed,
+        Err(e) => return function_registry_error(e),
+    };
+
+    info!(
+        "Function {} deployed – namespace={} version={} artifact={}",
+        name, namespace, function.version, function.artifact
+    );
+    record_audit(
+        &state,
+        actor
+            .audit(&source_ip, "function.deploy", AuditOutcome::Accepted)
+            .with_target(namespace.scope(&name))
+            .with_detail(format!(
+                "version={} artifact={}",
+                function.version, function.artifact
+            )),
+    );
+
+    let status = if previous.is_some() {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+    (status, Json(function)).into_response()
+}
+
+pub(crate) const FUNCTIONS: Listing = Listing {
+    filters: &[Filter::Runtime, Filter::CreatedAt],
+    sorts: &[SortField::CreatedAt, SortField::Id, SortField::Runtime],
+    default_sort: SortField::Id,
+    default_order: Order::Asc,
+};
+
+// GET /functions  –  functions deployed in the namespace, by name
+pub(crate) async fn list_functions(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    query: ListQuery,
+) -> axum::response::Response {
+    let functions = match state.functions.list(&namespace) {
+        Ok(functions) => functions,
+        Err(e) => return function_registry_error(e),
+    };
+    let entries = functions
+        .into_iter()
+        .map(|function| Entry {
+            id: function.name.clone(),
+            status: None,
+            runtime: Some(function.language.clone()),
+            tenant: None,
+            created_at: Some(function.created_at),
+            item: function,
+        })
+        .collect();
+    match listing::page(entries, &query, &FUNCTIONS) {
+        Ok(page) => page.into_response(),
+        Err(errors) => validation_error_response(errors),
+    }
+}
+
+// GET /functions/{name}  –  current version of a function
+pub(crate) async fn get_function(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    Path(name): Path<String>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "name", &name);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    match state.functions.get(&namespace, &name) {
+        Ok(Some(function)) => (StatusCode::OK, Json(function)).into_response(),
+        Ok(None) => function_not_found(&name),
+        Err(e) => function_registry_error(e),
+    }
+}
+
+// POST /functions/{name}/run  –  submit a job running the current version
+pub(crate) async fn run_function(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    namespace: Namespace,
+    Path(name): Path<String>,
+) -> axum::response::Response {
+    let source_ip = peer.source_ip();
+
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "name", &name);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+    let function = match state.functions.get(&namespace, &name) {
+        Ok(Some(function)) => function,
+        Ok(None) => return function_not_found(&name),
+        Err(e) => return function_registry_error(e),
+    };
+
+    let options = JobOptions {
+        namespace,
+        ..JobOptions::default()
+    };
+    let id = match start_function(&state, &function, options).await {
+        Ok(id) => id,
+        Err((detail, response)) => {
+            record_audit(
+                &state,
+                actor
+                    .audit(&source_ip, "job.submit", AuditOutcome::Rejected)
+                    .with_detail(format!("function={} {}", name, detail)),
+            );
+            return response;
+        }
+    };
+    record_audit(
+        &state,
+        actor
+            .audit(&source_ip, "job.submit", AuditOutcome::Accepted)
+            .with_target(id.clone())
+            .with_detail(format!(
+                "function={} version={} language={}",
+                name, function.version, function.language
+            )),
+    );
+
+    (
+        StatusCode::ACCEPTED,
+        Json(RunResponse { id, cached: false }),
+    )
+        .into_response()
+}
+
+/// Starts a job running the current version of `function`, of the namespace of
+/// `options`. Returns the job id; on failure, a short reason for the audit trail
+/// and the response to send.
+pub(crate) async fn start_function(
+    state: &Arc<AppState>,
+    function: &FunctionInfo,
+    options: JobOptions,
+) -> Result<String, (String, axum::response::Response)> {
+    let config = state.config.current();
+    if let Some(response) = unsupported_language(&config, &function.language, &function.language) {
+        return Err((
+            format!("Unsupported language: {}", function.language),
+            response,
+        ));
+    }
+    if let Some(rejection) = unsupported_arch(&config, &function.language, state.vm_arch) {
+        return Err(rejection);
+    }
+    let code =
+        load_artifact_code(state, &function.artifact, function.entrypoint.as_deref()).await?;
+    // Functions whose VMs are booted ahead of them run in pooled VMs, to claim those.
+    let mut options = options;
+    options.function = Some((function.name.clone(), function.version));
+    if prewarms_functions(state) {
+        state
+            .forecaster
+            .record(&options.namespace.scope(&function.name));
+        let key = function_pool_key(&config, &options.namespace, function);
+        options.vm = Some(config.job_vm(&function.language, None));
+        options.pool = Some(key);
+    }
+    if state.shared_vms.is_enabled() && !runs_processes(state) {
+        options.share = Some(function_pool_key(&config, &options.namespace, function));
+    }
+    Ok(start_job(state, config, function.language.clone(), code, options).await)
+}
+
+/// Whether the VMs of functions are booted ahead of their invocations.
+pub(crate) fn prewarms_functions(state: &AppState) -> bool {
+    state.forecaster.is_enabled() && state.vm_pool.is_enabled() && !runs_processes(state)
+}
+
+/// Pool of the VMs of `function`: their own tenant, so that they only run it.
+pub(crate) fn function_pool_key(
+    config: &ReloadableConfig,
+    namespace: &Namespace,
+    function: &FunctionInfo,
+) -> PoolKey {
+    let shape = config.job_vm(&function.language, None);
+    PoolKey {
+        tenant: format!("function:{}", namespace.scope(&function.name)),
+        language: function.language.clone(),
+        vcpus: shape.vcpus,
+        memory_mb: shape.memory_mb,
+    }
+}
+
+/// Boots pooled VMs for the invocations functions are expected to get, beyond
+/// the idle VMs they have. Stops at the cap on speculative VMs, and when the pool
+/// is full, so that no VM that ran a job is pushed out, and while the host is
+/// under pressure.
+pub(crate) async fn prewarm_functions(state: &Arc<AppState>) {
+    if state
+        .pressure
+        .as_ref()
+        .is_some_and(|pressure| pressure.check().is_some())
+    {
+        return;
+    }
+    let config = state.config.current();
+    for (key, wanted) in state.forecaster.forecast() {
+        let (namespace, name) = Namespace::unscope(&key);
+        let function = match state.functions.get(&namespace, name) {
+            Ok(Some(function)) => function,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Cannot pre-warm function {}: {}", name, e);
+                continue;
+            }
+        };
+        if unsupported_language(&config, &function.language, &function.language).is_some()
+            || unsupported_arch(&config, &function.language, state.vm_arch).is_some()
+        {
+            continue;
+        }
+        let key = function_pool_key(&config, &namespace, &function);
+        let missing = wanted.saturating_sub(state.vm_pool.idle_for(&key));
+        let vm_language = config
+            .run_stage(&function.language)
+            .unwrap_or_else(|| function.language.clone());
+        let vm_config = VmConfig {
+            vcpus: key.vcpus,
+            memory_mb: key.memory_mb,
+            redactor: config.redactor.clone(),
+            merge_memory: config.ksm.enabled,
+            ..state.vm_config.clone()
+        };
+        for _ in 0..missing {
+            let allowed = state
+                .prewarm_max_vms
+                .saturating_sub(state.vm_pool.speculative())
+                .min(state.vm_pool.room());
+            if allowed == 0 {
+                return;
+            }
+            let vm_id = format!("prewarm-{}", uuid::Uuid::new_v4());
+            let vm = match create_pooled_vm(state, vm_id, &vm_language, &vm_config).await {
+                Ok(vm) => vm,
+                Err(e) => {
+                    warn!("Cannot pre-warm a VM for function {}: {}", name, e);
+                    break;
+                }
+            };
+            info!(
+                "Pre-warmed VM {} for function {} ({} expected)",
+                vm.vm_id, name, wanted
+            );
+            state.events.vm(EventKind::VmCreated, &vm.vm_id, None);
+            for evicted in state.vm_pool.prewarm(key.clone(), vm) {
+                shut_down_vm(state, evicted, None).await;
+            }
+        }
+    }
+}
+
+// ── GET /export, POST /import  –  bundles of functions ──────────────
+
+pub(crate) async fn export_bundle(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    Query(query): Query<ExportQuery>,
+) -> axum::response::Response {
+    let functions = match state.functions.list(&namespace) {
+        Ok(functions) => functions,
+        Err(e) => return function_registry_error(e),
+    };
+    let runtimes = runtimes(&state.config.current())
+        .into_iter()
+        .filter(|runtime| functions.iter().any(|f| f.language == runtime.name))
+        .collect();
+
+    let mut artifacts = BTreeMap::new();
+    if query.artifacts.unwrap_or(true) {
+        for function in &functions {
+            if artifacts.contains_key(&function.artifact) {
+                continue;
+            }
+            match state.artifact_store.get(&function.artifact).await {
+                Ok(Some(data)) => {
+                    artifacts.insert(function.artifact.clone(), BASE64_STANDARD.encode(data));
+                }
+                // Imports of the function fail, unless the other backend has the artifact.
+                Ok(None) => warn!(
+                    "Artifact {} of function {} is missing, it is left out of the export",
+                    function.artifact, function.name
+                ),
+                Err(e) => return artifact_error_response(e),
+            }
+        }
+    }
+
+    Json(Bundle {
+        format: BUNDLE_FORMAT,
+        exported_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        namespace: namespace.to_string(),
+        functions,
+        runtimes,
+        artifacts,
+    })
+    .into_response()
+}
+
+pub(crate) async fn import_bundle(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    namespace: Namespace,
+    Query(query): Query<ImportQuery>,
+    payload: Result<Json<Bundle>, JsonRejection>,
+) -> axum::response::Response {
+    let source_ip = peer.source_ip();
+    let mut errors = ValidationErrors::default();
+    let bundle = match payload {
+        Ok(Json(bundle)) if bundle.format == BUNDLE_FORMAT => bundle,
+        Ok(Json(bundle)) => {
+            errors.push(
+                "format",
+                format!(
+                    "bundle format {} is not supported, only {}",
+                    bundle.format, BUNDLE_FORMAT
+                ),
+            );
+            return validation_error_response(errors);
+        }
+        Err(rejection) => {
+            errors.push("body", rejection.body_text());
+            return validation_error_response(errors);
+        }
+    };
+
+    let config = state.config.current();
+    let local_runtimes = runtimes(&config);
+    let warnings = bundle
+        .runtimes
+        .iter()
+        .filter_map(
+            |runtime| match local_runtimes.iter().find(|r| r.name == runtime.name) {
+                None => Some(format!("Runtime {} is not served here", runtime.name)),
+                Some(local) if local.version != runtime.version => Some(format!(
+                    "Runtime {} is {} here, {} in the bundle",
+                    runtime.name, local.version, runtime.version
+                )),
+                Some(_) => None,
+            },
+        )
+        .collect();
+
+    // Artifacts are content-addressed: one already stored is the same.
+    let mut missing_artifacts = HashMap::new();
+    for (id, content) in &bundle.artifacts {
+        if !bundle.functions.iter().any(|f| &f.artifact == id) {
+            continue;
+        }
+        if let Err(e) = store_bundled_artifact(&state, id, content).await {
+            warn!("Artifact {} of the bundle is not imported: {}", id, e);
+            missing_artifacts.insert(id.clone(), e);
+        }
+    }
+
+    let mut report = ImportReport {
+        functions: Vec::new(),
+        warnings,
+    };
+    for incoming in bundle.functions {
+        let name = incoming.name.clone();
+        let imported = match import_function(
+            &state,
+            &config,
+            &namespace,
+            incoming,
+            query.on_conflict,
+            &missing_artifacts,
+        )
+        .await
+        {
+            Ok((outcome, function)) => ImportedFunction {
+                name,
+                outcome,
+                function,
+                error: None,
+            },
+            Err(error) => ImportedFunction {
+                name,
+                outcome: ImportOutcome::Failed,
+                function: None,
+                error: Some(error),
+            },
+        };
+        let changed = matches!(
+            imported.outcome,
+            ImportOutcome::Created | ImportOutcome::Overwritten | ImportOutcome::Renamed
+        );
+        if let Some(function) = imported.function.as_ref().filter(|_| changed) {
+            record_audit(
+                &state,
+                actor
+                    .audit(&source_ip, "function.import", AuditOutcome::Accepted)
+                    .with_target(namespace.scope(&function.name))
+                    .with_detail(format!(
+                        "version={} artifact={}",
+                        function.version, function.artifact
+                    )),
+            );
+        }
+        report.functions.push(imported);
+    }
+
+    info!(
+        "Imported {} functions of namespace {} into {} – on_conflict={:?}",
+        report.functions.len(),
+        bundle.namespace,
+        namespace,
+        query.on_conflict
+    );
+    Json(report).into_response()
+}
+
+/// Stores the base64 `content` of artifact `id` from a bundle.
+pub(crate) async fn store_bundled_artifact(
+    state: &AppState,
+    id: &str,
+    content: &str,
+) -> Result<(), String> {
+    let data = BASE64_STANDARD
+        .decode(content)
+        .map_err(|e| format!("Artifact {id} is not valid base64: {e}"))?;
+    let upload = state
+        .artifact_uploads
+        .create(data.len() as u64)
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = state
+        .artifact_uploads
+        .append(&state.artifact_store, &upload.upload_id, 0, &data)
+        .await
+        .map_err(|e| e.to_string())?;
+    match status.artifact {
+        Some(artifact) if artifact.id == id => Ok(()),
+        Some(artifact) => Err(format!(
+            "Content of artifact {id} has another digest, {}",
+            artifact.id
+        )),
+        None => Err("Upload did not complete".to_string()),
+    }
+}
+
+/// Imports `incoming` into `namespace`, and returns what was done and the
+/// function as it is now.
+pub(crate) async fn import_function(
+    state: &AppState,
+    config: &ReloadableConfig,
+    namespace: &Namespace,
+    incoming: FunctionInfo,
+    on_conflict: OnConflict,
+    missing_artifacts: &HashMap<String, String>,
+) -> Result<(ImportOutcome, Option<FunctionInfo>), String> {
+    let request = validate_deploy_request(
+        &incoming.name,
+        DeployRequest {
+            language: incoming.language.clone(),
+            artifact: incoming.artifact.clone(),
+            entrypoint: incoming.entrypoint.clone(),
+        },
+    )
+    .map_err(|errors| errors.to_string())?;
+    if unsupported_language(config, &request.language, &request.language).is_some() {
+        return Err(format!("Unsupported language: {}", request.language));
+    }
+    if let Some(e) = missing_artifacts.get(&request.artifact) {
+        return Err(e.clone());
+    }
+    load_artifact_code(state, &request.artifact, request.entrypoint.as_deref())
+        .await
+        .map_err(|(detail, _)| detail)?;
+
+    let incoming = FunctionInfo {
+        language: request.language.clone(),
+        ..incoming
+    };
+    let plan = bundles::plan(&incoming, on_conflict, |name| {
+        state.functions.get(namespace, name).ok().flatten()
+    });
+    let registry_error = |e: FunctionError| format!("Failed to access function registry: {e}");
+    match plan {
+        Plan::Create => {
+            state
+                .functions
+                .restore(namespace, incoming.clone())
+                .map_err(registry_error)?;
+            Ok((ImportOutcome::Created, Some(incoming)))
+        }
+        Plan::Rename(name) => {
+            let function = FunctionInfo { name, ..incoming };
+            state
+                .functions
+                .restore(namespace, function.clone())
+                .map_err(registry_error)?;
+            Ok((ImportOutcome::Renamed, Some(function)))
+        }
+        Plan::Overwrite => {
+            let (function, _) = state
+                .functions
+                .deploy(namespace, &incoming.name, request)
+                .map_err(registry_error)?;
+            Ok((ImportOutcome::Overwritten, Some(function)))
+        }
+        Plan::Unchanged(function) | Plan::AlreadyRenamed(function) => {
+            Ok((ImportOutcome::Unchanged, Some(function)))
+        }
+        Plan::Skip => Ok((ImportOutcome::Skipped, None)),
+    }
+}
+
+// ── POST /apply  –  converge functions to a declarative spec ────────
+
+pub(crate) async fn apply_functions(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    namespace: Namespace,
+    Query(query): Query<ApplyQuery>,
+    payload: Result<Json<ApplySpec>, JsonRejection>,
+) -> axum::response::Response {
+    let source_ip = peer.source_ip();
+    let rejected = |errors: &ValidationErrors| {
+        actor
+            .audit(&source_ip, "functions.apply", AuditOutcome::Rejected)
+            .with_target(namespace.to_string())
+            .with_detail(errors.to_string())
+    };
+
+    let spec = match payload {
+        Ok(Json(spec)) => spec,
+        Err(rejection) => {
+            let mut errors = ValidationErrors::default();
+            errors.push("body", rejection.body_text());
+            record_audit(&state, rejected(&errors));
+            return validation_error_response(errors);
+        }
+    };
+
+    // Every function is checked as a deployment before anything changes.
+    let config = state.config.current();
+    let mut errors = apply::validate_names(&spec);
+    let mut functions = Vec::with_capacity(spec.functions.len());
+    for (i, desired) in spec.functions.into_iter().enumerate() {
+        let request = DeployRequest {
+            language: desired.language,
+            artifact: desired.artifact,
+            entrypoint: desired.entrypoint,
+        };
+        let request = match validate_deploy_request(&desired.name, request) {
+            Ok(request) => request,
+            Err(invalid) => {
+                for e in invalid.errors {
+                    errors.push(&format!("functions[{i}].{}", e.field), e.message);
+                }
+                continue;
+            }
+        };
+        if unsupported_language(&config, &request.language, &request.language).is_some() {
+            errors.push(
+                &format!("functions[{i}].language"),
+                format!("Unsupported language: {}", request.language),
+            );
+            continue;
+        }
+        if let Err((detail, _)) =
+            load_artifact_code(&state, &request.artifact, request.entrypoint.as_deref()).await
+        {
+            errors.push(&format!("functions[{i}].artifact"), detail);
+            continue;
+        }
+        functions.push(DesiredFunction {
+            name: desired.name,
+            language: request.language,
+            artifact: request.artifact,
+            entrypoint: request.entrypoint,
+        });
+    }
+    if !errors.is_empty() {
+        record_audit(&state, rejected(&errors));
+        return validation_error_response(errors);
+    }
+    let spec = ApplySpec {
+        functions,
+        prune: spec.prune,
+    };
+
+    let changes = match state.functions.apply(&namespace, &spec, query.dry_run) {
+        Ok(changes) => changes,
+        Err(e) => return function_registry_error(e),
+    };
+    if !query.dry_run {
+        for change in &changes {
+            if change.action == ApplyAction::Unchanged {
+                continue;
+            }
+            let version = change.after.as_ref().or(change.before.as_ref());
+            record_audit(
+                &state,
+                actor
+                    .audit(&source_ip, "function.apply", AuditOutcome::Accepted)
+                    .with_target(namespace.scope(&change.name))
+                    .with_detail(format!(
+                        "action={} version={}",
+                        change.action,
+                        version.map(|f| f.version).unwrap_or_default()
+                    )),
+            );
+        }
+        let changed = changes
+            .iter()
+            .filter(|change| change.action != ApplyAction::Unchanged)
+            .count();
+        info!(
+            "Applied a spec of {} functions to namespace {} – {} changes",
+            spec.functions.len(),
+            namespace,
+            changed
+        );
+    }
+    Json(ApplyResponse {
+        applied: !query.dry_run,
+        changes,
+    })
+    .into_response()
+}
@@ -0,0 +1,253 @@
+//! `/f/{name}`: functions bound to an `http` trigger, and the other triggers.
+
+use super::*;
+
+// ── /f/{name}  –  functions bound to an http trigger ────────────────
+
+/// Header of `/f/{name}` responses with the id of the job that answered.
+pub(crate) const JOB_ID_HEADER: &str = "x-cloude-job-id";
+
+/// Runs an `http`-bound function with the request as its event, and answers with
+/// its output, see [`ResponseMode`].
+pub(crate) async fn invoke_http_function(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    Path(params): Path<HashMap<String, String>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> axum::response::Response {
+    // Function URLs take no key.
+    let actor = Actor::new(&state, None, &headers);
+    let source_ip = peer.source_ip();
+    let name = params.get("name").cloned().unwrap_or_default();
+
+    let Some(&mode) = state.http_triggers.get(&name) else {
+        return function_not_found(&name);
+    };
+    // Triggers are set up by the operator, for functions of the default namespace.
+    let function = match state.functions.get(&Namespace::default(), &name) {
+        Ok(Some(function)) => function,
+        Ok(None) => return function_not_found(&name),
+        Err(e) => return function_registry_error(e),
+    };
+
+    let path = params.get("rest").map(String::as_str).unwrap_or_default();
+    let event = http_event(&method, path, uri.query(), &headers, body.to_vec());
+    let stdin = match serde_json::to_string(&event) {
+        Ok(stdin) => stdin,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse::new(format!("Failed to encode event: {e}"))),
+            )
+                .into_response();
+        }
+    };
+    let (stdout_tap, stdout) = match mode {
+        ResponseMode::Stdout => (None, None),
+        ResponseMode::Http => {
+            let (tap, stdout) = tokio::sync::mpsc::unbounded_channel();
+            (Some(tap), Some(stdout))
+        }
+    };
+    let options = JobOptions {
+        stdin: Some(stdin),
+        stdout_tap,
+        ..JobOptions::default()
+    };
+
+    // Subscribe before the job starts, so that its end cannot be missed.
+    let mut events = state.events.subscribe();
+    let id = match start_function(&state, &function, options).await {
+        Ok(id) => id,
+        Err((detail, response)) => {
+            record_audit(
+                &state,
+                actor
+                    .audit(&source_ip, "job.submit", AuditOutcome::Rejected)
+                    .with_detail(format!("function={} trigger=http {}", name, detail)),
+            );
+            return response;
+        }
+    };
+    record_audit(
+        &state,
+        actor
+            .audit(&source_ip, "job.submit", AuditOutcome::Accepted)
+            .with_target(id.clone())
+            .with_detail(format!(
+                "function={} version={} trigger=http",
+                name, function.version
+            )),
+    );
+
+    match stdout {
+        Some(stdout) => relay_http_response(state, id, events, stdout).await,
+        None => {
+            let job = wait_for_job(&state, &id, &mut events).await;
+            match job {
+                Some(job) if job.exit_code == Some(0) => (
+                    StatusCode::OK,
+                    [(JOB_ID_HEADER, id)],
+                    job.stdout.unwrap_or_default(),
+                )
+                    .into_response(),
+                job => function_failed(id, job),
+            }
+        }
+    }
+}
+
+/// Answers with the response a function in `http` response mode writes on
+/// `stdout`: its head once complete, then its body as it comes.
+pub(crate) async fn relay_http_response(
+    state: Arc<AppState>,
+    id: String,
+    mut events: tokio::sync::broadcast::Receiver<LifecycleEvent>,
+    mut stdout: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+) -> axum::response::Response {
+    let mut output = Vec::new();
+    let (head, head_len) = loop {
+        let chunk = stdout.recv().await;
+        let complete = chunk.is_none();
+        match chunk {
+            Some(chunk) => output.extend_from_slice(&chunk),
+            // The output is only a response if the function exited 0.
+            None => {
+                let job = wait_for_job(&state, &id, &mut events).await;
+                if job.as_ref().and_then(|job| job.exit_code) != Some(0) {
+                    return function_failed(id, job);
+                }
+            }
+        }
+        match parse_response_head(&output, complete) {
+            Ok(Some(parsed)) => break parsed,
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Job {} – invalid HTTP response head: {}", id, e);
+                return (
+                    StatusCode::BAD_GATEWAY,
+                    [(JOB_ID_HEADER, id)],
+                    format!("Invalid response head: {e}"),
+                )
+                    .into_response();
+            }
+        }
+    };
+
+    let body_start = output.split_off(head_len);
+    let first = (!body_start.is_empty()).then(|| Ok(Bytes::from(body_start)));
+    let job_id = id.clone();
+    let rest =
+        futures_util::stream::unfold(Some((stdout, events, state, job_id)), |next| async move {
+            let (mut stdout, mut events, state, id) = next?;
+            if let Some(chunk) = stdout.recv().await {
+                return Some((Ok(Bytes::from(chunk)), Some((stdout, events, state, id))));
+            }
+            // Cutting the body short is the only way left to tell the caller.
+            let job = wait_for_job(&state, &id, &mut events).await;
+            match job.and_then(|job| job.exit_code) {
+                Some(0) => None,
+                _ => Some((
+                    Err(std::io::Error::other(
+                        "function failed while sending its body",
+                    )),
+                    None,
+                )),
+            }
+        });
+    (
+        head.status,
+        head.headers,
+        [(JOB_ID_HEADER, id)],
+        axum::body::Body::from_stream(futures_util::stream::iter(first).chain(rest)),
+    )
+        .into_response()
+}
+
+/// `502` with the standard error of a job that did not exit 0.
+pub(crate) fn function_failed(id: String, job: Option<Job>) -> axum::response::Response {
+    let stderr = job.and_then(|job| job.stderr).unwrap_or_default();
+    (StatusCode::BAD_GATEWAY, [(JOB_ID_HEADER, id)], stderr).into_response()
+}
+
+/// Waits for job `id` to end, with `events` subscribed before it started, and
+/// returns it; `None` once it was evicted.
+pub(crate) async fn wait_for_job(
+    state: &AppState,
+    id: &str,
+    events: &mut tokio::sync::broadcast::Receiver<LifecycleEvent>,
+) -> Option<Job> {
+    loop {
+        match events.recv().await {
+            Ok(event)
+                if event.job_id.as_deref() == Some(id)
+                    && matches!(
+                        event.kind,
+                        EventKind::ExecutionFinished | EventKind::ExecutionFailed
+                    ) =>
+            {
+                break;
+            }
+            Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
+                // The job may have ended among the missed events.
+                if state
+                    .jobs
+                    .read()
+                    .await
+                    .get(id)
+                    .is_none_or(|job| job.status.is_terminal())
+                {
+                    break;
+                }
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        }
+    }
+    state.jobs.read().await.get(id).cloned()
+}
+
+/// Runs functions for the triggers that are not served by the router.
+pub(crate) struct StateInvoker(pub(crate) Arc<AppState>);
+
+#[async_trait::async_trait]
+impl Invoker for StateInvoker {
+    async fn invoke(&self, name: &str, event: TriggerEvent) -> Result<String, String> {
+        let state = &self.0;
+        let function = state
+            .functions
+            .get(&Namespace::default(), name)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Function {name} not found"))?;
+        let stdin = serde_json::to_string(&event).map_err(|e| e.to_string())?;
+        let trigger = event.trigger;
+        let options = JobOptions {
+            stdin: Some(stdin),
+            ..JobOptions::default()
+        };
+        match start_function(state, &function, options).await {
+            Ok(id) => {
+                record_audit(
+                    state,
+                    AuditEntry::new("trigger", "local", "job.submit", AuditOutcome::Accepted)
+                        .with_target(id.clone())
+                        .with_detail(format!(
+                            "function={} version={} trigger={} source={}",
+                            name, function.version, trigger, event.source
+                        )),
+                );
+                Ok(id)
+            }
+            Err((detail, _)) => {
+                record_audit(
+                    state,
+                    AuditEntry::new("trigger", "local", "job.submit", AuditOutcome::Rejected)
+                        .with_detail(format!("function={} trigger={} {}", name, trigger, detail)),
+                );
+                Err(detail)
+            }
+        }
+    }
+}
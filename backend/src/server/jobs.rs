@@ -0,0 +1,1996 @@
+//! `POST /run`, and running jobs: in a VM booted, pooled or shared, or in a
+//! host process, up to their result.
+
+use super::*;
+
+// ── Run requests ────────────────────────────────────────────────────
+
+/// Where the source code of a job comes from.
+pub(crate) enum JobSource {
+    Inline(String),
+    Artifact {
+        id: String,
+        /// File to run when the artifact is a directory bundle.
+        entrypoint: Option<String>,
+    },
+}
+
+/// Code a job runs, once read from the request or the artifact store.
+pub(crate) enum JobCode {
+    Source(String),
+    /// A gzipped tar of a directory, and the file to run in it.
+    Bundle {
+        archive: Vec<u8>,
+        entrypoint: String,
+    },
+}
+
+impl JobCode {
+    /// What identifies the code: its kind, its content and the file to run.
+    fn parts(&self) -> (&[u8], &[u8], &str) {
+        match self {
+            JobCode::Source(code) => (b"source", code.as_bytes(), ""),
+            JobCode::Bundle {
+                archive,
+                entrypoint,
+            } => (b"bundle", archive, entrypoint),
+        }
+    }
+
+    /// Digest of the code, the same for every job running it.
+    fn digest(&self) -> String {
+        let (kind, content, entrypoint) = self.parts();
+        cache_key(&[kind, content, entrypoint.as_bytes()])
+    }
+
+    fn into_execute_request(
+        self,
+        language: String,
+        deterministic: bool,
+        stdin: Option<String>,
+    ) -> ExecuteRequest {
+        match self {
+            JobCode::Source(code) => ExecuteRequest {
+                language,
+                code,
+                bundle: None,
+                entrypoint: None,
+                deterministic,
+                offline: false,
+                stdin,
+                stream: false,
+                binary: None,
+                keep_binary: false,
+                build_only: false,
+                limits: None,
+                identity_token: None,
+                egress_proxy: None,
+                concurrent: false,
+            },
+            JobCode::Bundle {
+                archive,
+                entrypoint,
+            } => ExecuteRequest {
+                language,
+                code: String::new(),
+                bundle: Some(BASE64_STANDARD.encode(archive)),
+                entrypoint: Some(entrypoint),
+                deterministic,
+                offline: false,
+                stdin,
+                stream: false,
+                binary: None,
+                keep_binary: false,
+                build_only: false,
+                limits: None,
+                identity_token: None,
+                egress_proxy: None,
+                concurrent: false,
+            },
+        }
+    }
+}
+
+/// A `FunctionSpec` whose fields passed validation: the language is normalized
+/// and safe to use in file names, and exactly one code source is set.
+pub(crate) struct ValidatedRunRequest {
+    language: String,
+    source: JobSource,
+    deterministic: bool,
+    cache: Option<CacheControl>,
+    isolation: Isolation,
+    resources: Option<Resources>,
+    stdin: Option<String>,
+    profile: Option<String>,
+    network: Network,
+    identity_audience: Option<String>,
+}
+
+pub(crate) fn validate_run_request(
+    spec: FunctionSpec,
+    limits: &RequestLimits,
+) -> Result<ValidatedRunRequest, ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+
+    let requested_language = spec.language.trim().to_ascii_lowercase();
+    let language = normalize_language_alias(&requested_language);
+    validate_identifier(&mut errors, "language", &language);
+
+    if spec.code.is_some() && spec.entrypoint.is_some() {
+        errors.push("entrypoint", "only applies to artifacts");
+    }
+    let source = match (spec.code, spec.artifact) {
+        (Some(_), Some(_)) => {
+            errors.push("artifact", "cannot be combined with inline code");
+            JobSource::Inline(String::new())
+        }
+        (Some(code), None) => {
+            validate_code(&mut errors, "code", &code, limits);
+            JobSource::Inline(code)
+        }
+        (None, Some(artifact)) => {
+            validate_artifact_id(&mut errors, "artifact", &artifact);
+            if let Some(entrypoint) = &spec.entrypoint {
+                validate_relative_path(&mut errors, "entrypoint", entrypoint);
+            }
+            JobSource::Artifact {
+                id: artifact,
+                entrypoint: spec.entrypoint,
+            }
+        }
+        (None, None) => {
+            errors.push("code", "Code cannot be empty");
+            JobSource::Inline(String::new())
+        }
+    };
+
+    if spec.cache.and_then(|cache| cache.ttl_secs) == Some(0) {
+        errors.push("cache.ttl_secs", "must be at least 1");
+    }
+    if let Some(resources) = &spec.resources {
+        validate_resources(&mut errors, "resources", resources, limits);
+    }
+    if let Some(audience) = &spec.identity_audience
+        && (audience.is_empty() || audience.len() > 256 || audience.contains(char::is_whitespace))
+    {
+        errors.push(
+            "identity_audience",
+            "must be 1 to 256 characters, without spaces",
+        );
+    }
+    if let Some(stdin) = &spec.stdin
+        && stdin.len() > limits.max_code_bytes
+    {
+        errors.push(
+            "stdin",
+            format!(
+                "is {} bytes, larger than the {} bytes allowed inline",
+                stdin.len(),
+                limits.max_code_bytes
+            ),
+        );
+    }
+
+    errors.into_result(ValidatedRunRequest {
+        language,
+        source,
+        deterministic: spec.deterministic,
+        cache: spec.cache,
+        isolation: spec.isolation,
+        resources: spec.resources,
+        stdin: spec.stdin,
+        profile: spec.profile,
+        network: spec.network,
+        identity_audience: spec.identity_audience,
+    })
+}
+
+// ── POST /run  –  submit a new job ──────────────────────────────────
+
+pub(crate) async fn run_job(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    namespace: Namespace,
+    caller: Option<Extension<Caller>>,
+    payload: Result<Json<FunctionSpec>, JsonRejection>,
+) -> axum::response::Response {
+    let source_ip = peer.source_ip();
+
+    let payload = match payload {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => {
+            let mut errors = ValidationErrors::default();
+            errors.push("body", rejection.body_text());
+            record_audit(
+                &state,
+                actor
+                    .audit(&source_ip, "job.submit", AuditOutcome::Rejected)
+                    .with_detail(errors.to_string()),
+            );
+            return validation_error_response(errors);
+        }
+    };
+
+    // One snapshot for the whole job, so a concurrent reload cannot mix settings.
+    let config = state.config.current();
+
+    let requested_language = payload.language.clone();
+    let ValidatedRunRequest {
+        language,
+        source,
+        deterministic,
+        cache,
+        isolation,
+        resources,
+        stdin,
+        profile,
+        network,
+        identity_audience,
+    } = match validate_run_request(payload, &config.limits) {
+        Ok(request) => request,
+        Err(errors) => {
+            record_audit(
+                &state,
+                actor
+                    .audit(&source_ip, "job.submit", AuditOutcome::Rejected)
+                    .with_detail(errors.to_string()),
+            );
+            return validation_error_response(errors);
+        }
+    };
+
+    if let Some(response) = unsupported_language(&config, &language, &requested_language) {
+        record_audit(
+            &state,
+            actor
+                .audit(&source_ip, "job.submit", AuditOutcome::Rejected)
+                .with_detail(format!("Unsupported language: {}", requested_language)),
+        );
+        return response;
+    }
+    if let Some((detail, response)) = unsupported_arch(&config, &language, state.vm_arch) {
+        record_audit(
+            &state,
+            actor
+                .audit(&source_ip, "job.submit", AuditOutcome::Rejected)
+                .with_detail(detail),
+        );
+        return response;
+    }
+    // Jobs that name no profile get the `default` one, when there is one.
+    let profile = profile.or_else(|| {
+        config
+            .profiles
+            .contains_key(DEFAULT_PROFILE)
+            .then(|| DEFAULT_PROFILE.to_string())
+    });
+    let caller = caller.map(|Extension(caller)| caller);
+    if let Some((detail, response)) = forbidden_profile(caller.as_ref(), profile.as_deref()) {
+        record_audit(
+            &state,
+            actor
+                .audit(&source_ip, "job.submit", AuditOutcome::Rejected)
+                .with_detail(detail),
+        );
+        return response;
+    }
+    let sandbox = match job_sandbox(&config, profile.as_deref(), &language, isolation, resources) {
+        Ok(sandbox) => sandbox,
+        Err(errors) => {
+            record_audit(
+                &state,
+                actor
+                    .audit(&source_ip, "job.submit", AuditOutcome::Rejected)
+                    .with_detail(errors.to_string()),
+            );
+            return validation_error_response(errors);
+        }
+    };
+    let Sandbox {
+        isolation,
+        resources,
+        offline,
+    } = sandbox;
+
+    let shape = config.job_vm(&language, resources.as_ref());
+
+    // Backends without VMs run every job in a host process, and say so in its isolation.
+    let processes = runs_processes(&state);
+    let isolation = match isolation {
+        Isolation::Vm if processes => Isolation::Process,
+        isolation => isolation,
+    };
+    let mut errors = ValidationErrors::default();
+    let pool = match isolation {
+        Isolation::Vm => None,
+        Isolation::Process => {
+            if !processes {
+                errors.push("isolation", "process isolation is disabled on this backend");
+            } else if deterministic {
+                errors.push(
+                    "deterministic",
+                    "needs a VM, and this backend runs jobs in host processes",
+                );
+            } else if offline {
+                errors.push(
+                    "profile",
+                    "cuts the network, which needs a VM, and this backend runs jobs in host processes",
+                );
+            } else if network == Network::EgressRestricted {
+                errors.push(
+                    "network",
+                    "egress-restricted needs a VM, and this backend runs jobs in host processes",
+                );
+            }
+            None
+        }
+        // Pooled VMs are only shared within a tenant, so the caller must say who it is.
+        Isolation::PooledVm => {
+            if processes || !state.vm_pool.is_enabled() {
+                errors.push("isolation", "pooled VMs are disabled on this backend");
+            } else if actor.tenant.is_none() {
+                errors.push("isolation", "pooled-vm needs a tenant, set X-Cloude-User");
+            }
+            Some(PoolKey {
+                tenant: namespace.scope(&actor.name),
+                language: language.clone(),
+                vcpus: shape.vcpus,
+                memory_mb: shape.memory_mb,
+            })
+        }
+    };
+    if network == Network::EgressRestricted && state.egress_proxy.is_none() {
+        errors.push("network", "the egress proxy is disabled on this backend");
+    }
+    if !errors.is_empty() {
+        record_audit(
+            &state,
+            actor
+                .audit(&source_ip, "job.submit", AuditOutcome::Rejected)
+                .with_detail(errors.to_string()),
+        );
+        return validation_error_response(errors);
+    }
+    let tenant = actor.tenant.clone();
+    // Jobs without network need no proxy to keep them in.
+    let egress = (network == Network::EgressRestricted && !deterministic && !offline)
+        .then(|| config.egress.allowlist(tenant.as_deref()));
+
+    let code = match source {
+        JobSource::Inline(code) => JobCode::Source(code),
+        JobSource::Artifact { id, entrypoint } => {
+            match load_artifact_code(&state, &id, entrypoint.as_deref()).await {
+                Ok(code) => code,
+                Err((detail, response)) => {
+                    record_audit(
+                        &state,
+                        actor
+                            .audit(&source_ip, "job.submit", AuditOutcome::Rejected)
+                            .with_detail(detail),
+                    );
+                    return response;
+                }
+            }
+        }
+    };
+
+    // Namespaces do not share results, not even of the same code.
+    let cache_key = cache.map(|_| {
+        namespace.scope(&result_cache_key(
+            &config,
+            shape,
+            &language,
+            &code,
+            deterministic,
+            (!offline).then_some(network),
+            stdin.as_deref(),
+        ))
+    });
+    let cached = cache.zip(cache_key.as_deref()).and_then(|(cache, key)| {
+        state
+            .result_cache
+            .get(key, state.result_cache.ttl(cache.ttl_secs))
+    });
+    if let Some(result) = cached {
+        let id = finish_cached_job(&state, language.clone(), tenant, namespace, result).await;
+        record_audit(
+            &state,
+            actor
+                .audit(&source_ip, "job.submit", AuditOutcome::Accepted)
+                .with_target(id.clone())
+                .with_detail(format!("language={} cached", language)),
+        );
+        return (StatusCode::ACCEPTED, Json(RunResponse { id, cached: true })).into_response();
+    }
+    let store = cache
+        .filter(|cache| !cache.no_store)
+        .zip(cache_key)
+        .map(|(cache, key)| CacheStore {
+            key,
+            ttl: state.result_cache.ttl(cache.ttl_secs),
+        });
+
+    let options = JobOptions {
+        deterministic,
+        offline,
+        store,
+        pool,
+        vm: Some(shape),
+        tenant,
+        namespace,
+        stdin,
+        stdout_tap: None,
+        function: None,
+        identity_audience,
+        egress,
+        share: None,
+    };
+    let id = start_job(&state, config, language.clone(), code, options).await;
+    let mut detail = format!("language={} isolation={}", language, isolation);
+    if let Some(profile) = &profile {
+        detail.push_str(&format!(" profile={}", profile));
+    }
+    if deterministic {
+        detail.push_str(" deterministic");
+    }
+    record_audit(
+        &state,
+        actor
+            .audit(&source_ip, "job.submit", AuditOutcome::Accepted)
+            .with_target(id.clone())
+            .with_detail(detail),
+    );
+
+    (
+        StatusCode::ACCEPTED,
+        Json(RunResponse { id, cached: false }),
+    )
+        .into_response()
+}
+
+/// `403` when the key of `caller` may not run jobs with `profile`, with a short
+/// reason for the audit trail.
+pub(crate) fn forbidden_profile(
+    caller: Option<&Caller>,
+    profile: Option<&str>,
+) -> Option<(String, axum::response::Response)> {
+    let caller = caller.filter(|caller| !caller.may_use(profile))?;
+    let allowed = caller.profiles.as_deref().unwrap_or_default().join(", ");
+    let response = (
+        StatusCode::FORBIDDEN,
+        Json(ErrorResponse::new(format!(
+            "Key {} may only run jobs with the profiles {}",
+            caller.key_id, allowed
+        ))),
+    )
+        .into_response();
+    Some((
+        format!("Profile {} not allowed", profile.unwrap_or("none")),
+        response,
+    ))
+}
+
+/// How a job runs with `profile`, or as it asked without one. Fails for an
+/// unknown profile, and for settings the profile does not allow.
+pub(crate) fn job_sandbox(
+    config: &ReloadableConfig,
+    profile: Option<&str>,
+    language: &str,
+    isolation: Isolation,
+    resources: Option<Resources>,
+) -> Result<Sandbox, ValidationErrors> {
+    let Some(name) = profile else {
+        return Ok(Sandbox {
+            isolation,
+            resources,
+            offline: false,
+        });
+    };
+    match config.profiles.get(name) {
+        Some(profile) => profile.apply(language, isolation, resources.as_ref()),
+        None => {
+            let known: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+            let mut errors = ValidationErrors::default();
+            errors.push(
+                "profile",
+                format!("Unknown profile {}, profiles: {}", name, known.join(", ")),
+            );
+            Err(errors)
+        }
+    }
+}
+
+/// `400` listing the configured runtimes, when `language` is not one of them.
+pub(crate) fn unsupported_language(
+    config: &ReloadableConfig,
+    language: &str,
+    requested_language: &str,
+) -> Option<axum::response::Response> {
+    let mut supported_languages = config
+        .languages
+        .iter()
+        .map(|lang| lang.name.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    supported_languages.sort();
+    supported_languages.dedup();
+
+    if supported_languages.iter().any(|name| name == language) {
+        return None;
+    }
+    Some(
+        (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": format!(
+                    "Unsupported language: {}. Supported languages: {}",
+                    requested_language,
+                    supported_languages.join(", ")
+                )
+            })),
+        )
+            .into_response(),
+    )
+}
+
+/// `400` when the images of `language` are not built for `vm_arch`, the
+/// architecture of the VMs of this backend, with a short reason for the audit trail.
+pub(crate) fn unsupported_arch(
+    config: &ReloadableConfig,
+    language: &str,
+    vm_arch: Option<Arch>,
+) -> Option<(String, axum::response::Response)> {
+    let arch = vm_arch?;
+    let runtime = config
+        .languages
+        .iter()
+        .find(|lang| lang.name.eq_ignore_ascii_case(language))?;
+    if runtime.supports(arch) {
+        return None;
+    }
+    let arches: Vec<String> = runtime.arches.iter().map(Arch::to_string).collect();
+    let response = (
+        StatusCode::BAD_REQUEST,
+        Json(ErrorResponse::new(format!(
+            "Runtime {} has images for {} only, and this backend runs {} VMs",
+            language,
+            arches.join(", "),
+            arch
+        ))),
+    )
+        .into_response();
+    Some((format!("No {} image of {}", arch, language), response))
+}
+
+/// Key of the result cache for a job: what decides its output, from the runtime
+/// version and VM shape to the code and execution flags. `network` is `None` for
+/// code cut off the network.
+pub(crate) fn result_cache_key(
+    config: &ReloadableConfig,
+    shape: cloude_types::VmConfig,
+    language: &str,
+    code: &JobCode,
+    deterministic: bool,
+    network: Option<Network>,
+    stdin: Option<&str>,
+) -> String {
+    let (version, base_image) = config
+        .languages
+        .iter()
+        .find(|lang| lang.name.eq_ignore_ascii_case(language))
+        .map(|lang| (lang.version.as_str(), lang.base_image.as_str()))
+        .unwrap_or_default();
+    let (kind, content, entrypoint) = code.parts();
+    cache_key(&[
+        language.as_bytes(),
+        version.as_bytes(),
+        base_image.as_bytes(),
+        &[shape.vcpus],
+        &shape.memory_mb.to_le_bytes(),
+        kind,
+        content,
+        entrypoint.as_bytes(),
+        &[u8::from(deterministic)],
+        network
+            .map_or("offline".to_string(), |network| network.to_string())
+            .as_bytes(),
+        stdin.unwrap_or_default().as_bytes(),
+    ])
+}
+
+/// Where the result of a job is kept for later identical runs.
+pub(crate) struct CacheStore {
+    key: String,
+    ttl: std::time::Duration,
+}
+
+/// How a job runs, beside its runtime and code.
+#[derive(Default)]
+pub(crate) struct JobOptions {
+    /// Without network, with a fixed clock and seeds.
+    pub(crate) deterministic: bool,
+    /// Without network, as its sandbox profile asks.
+    pub(crate) offline: bool,
+    /// Where the result is kept in the result cache, if it is.
+    pub(crate) store: Option<CacheStore>,
+    /// Pool the VM is taken from and returned to, for `pooled-vm` jobs.
+    pub(crate) pool: Option<PoolKey>,
+    /// Shape of the VM, the runtime's default when `None`.
+    pub(crate) vm: Option<cloude_types::VmConfig>,
+    /// Caller the job runs for, see `X-Cloude-User`. `None` for anonymous callers,
+    /// whose VMs share the first segment when tenants have their own.
+    pub(crate) tenant: Option<String>,
+    /// Namespace the job is submitted in.
+    pub(crate) namespace: Namespace,
+    /// Standard input of the code: the event of the trigger that started it, or
+    /// the one the caller gave.
+    pub(crate) stdin: Option<String>,
+    /// Gets the standard output as the code writes it, rather than once it exited.
+    pub(crate) stdout_tap: Option<tokio::sync::mpsc::UnboundedSender<Vec<u8>>>,
+    /// Deployed function the job runs and its version, named in the identity token.
+    pub(crate) function: Option<(String, u32)>,
+    /// `aud` of the identity tokens of the job.
+    pub(crate) identity_audience: Option<String>,
+    /// Domains the job reaches through the egress proxy, for `egress-restricted` jobs.
+    pub(crate) egress: Option<Allowlist>,
+    /// Key of the VMs the job shares with concurrent invocations of its function,
+    /// when they do.
+    pub(crate) share: Option<PoolKey>,
+}
+
+/// What a job needs of the VM of another to run in it, see `vm_sharing`.
+pub(crate) struct SharedVm {
+    vm_id: String,
+    ip: Ipv4Addr,
+    agent_url: String,
+    stop: vmm::StopHandle,
+}
+
+/// Registers a job that is already done, with the result of an identical earlier run.
+/// Returns the job id.
+pub(crate) async fn finish_cached_job(
+    state: &Arc<AppState>,
+    language: String,
+    tenant: Option<String>,
+    namespace: Namespace,
+    result: CachedResult,
+) -> String {
+    let id = uuid::Uuid::new_v4().to_string();
+    info!(
+        "Job {} answered from the result cache – language={}",
+        id, language
+    );
+
+    let log = Arc::new(JobLog::new());
+    log.push(LogSource::Stdout, &result.stdout);
+    log.push(LogSource::Stderr, &result.stderr);
+    log.finish();
+
+    let job = Job {
+        id: id.clone(),
+        status: JobStatus::Done,
+        language,
+        exit_code: Some(result.exit_code),
+        stdout: Some(result.stdout),
+        stderr: Some(result.stderr),
+        isolation: None,
+        created_at: std::time::Instant::now(),
+        tenant,
+        namespace,
+        agent_pid: None,
+        vm_id: None,
+        traffic: None,
+        oom_killed: false,
+        signal: None,
+        core_dump: false,
+        code_digest: None,
+        image_digest: None,
+        shape: None,
+        duration_ms: None,
+        function: None,
+        warm: None,
+    };
+    state.jobs.write().await.insert(id.clone(), job);
+    state.logs.write().await.insert(id.clone(), log);
+    persist_job(state, &id).await;
+    state.events.job(
+        EventKind::ExecutionFinished,
+        &id,
+        Some(format!("exit_code={} cached=true", result.exit_code)),
+    );
+    id
+}
+
+/// Registers a pending job and runs it in the background, in a new VM or an idle one
+/// of its pool. Returns the job id.
+pub(crate) async fn start_job(
+    state: &Arc<AppState>,
+    config: Arc<ReloadableConfig>,
+    language: String,
+    code: JobCode,
+    mut options: JobOptions,
+) -> String {
+    let id = uuid::Uuid::new_v4().to_string();
+
+    let isolation = match options.pool {
+        Some(_) => Isolation::PooledVm,
+        None if runs_processes(state) => Isolation::Process,
+        None => Isolation::Vm,
+    };
+    let job = Job {
+        id: id.clone(),
+        status: JobStatus::Pending,
+        language: language.clone(),
+        exit_code: None,
+        stdout: None,
+        stderr: None,
+        isolation: Some(isolation),
+        created_at: std::time::Instant::now(),
+        tenant: options.tenant.clone(),
+        namespace: options.namespace.clone(),
+        agent_pid: None,
+        vm_id: None,
+        traffic: None,
+        oom_killed: false,
+        signal: None,
+        core_dump: false,
+        code_digest: Some(code.digest()),
+        image_digest: None,
+        shape: None,
+        duration_ms: None,
+        function: options.function.clone(),
+        warm: None,
+    };
+
+    // Store the job
+    let log = Arc::new(JobLog::with_redactor(config.redactor.clone()));
+    {
+        let mut jobs = state.jobs.write().await;
+        jobs.insert(id.clone(), job);
+    }
+    persist_job(state, &id).await;
+    state
+        .logs
+        .write()
+        .await
+        .insert(id.clone(), Arc::clone(&log));
+
+    info!("Job {} created – language={}", id, language);
+    state.events.job(
+        EventKind::ExecutionQueued,
+        &id,
+        Some(format!("language={language}")),
+    );
+
+    // Spawn a background task that creates a VM and forwards the request to its agent
+    let job_id = id.clone();
+    let state = Arc::clone(state);
+
+    tokio::spawn(async move {
+        // Mark as running
+        {
+            let mut jobs = state.jobs.write().await;
+            if let Some(j) = jobs.get_mut(&job_id) {
+                j.status = JobStatus::Running;
+            }
+        }
+        persist_job(&state, &job_id).await;
+        state.events.job(EventKind::ExecutionStarted, &job_id, None);
+
+        let shape = options.vm.unwrap_or_else(|| config.job_vm(&language, None));
+        if let Some(j) = state.jobs.write().await.get_mut(&job_id) {
+            j.shape = Some(shape);
+        }
+        let mut request_payload = code.into_execute_request(
+            language.clone(),
+            options.deterministic,
+            options.stdin.take(),
+        );
+        request_payload.offline = options.offline;
+        request_payload.stream = options.stdout_tap.is_some();
+
+        #[cfg(feature = "process-executor")]
+        if let Some(executor) = &state.process_executor {
+            let execution_result = run_in_process(
+                &state,
+                executor,
+                &job_id,
+                shape.memory_mb,
+                &request_payload,
+                options.stdout_tap.as_ref(),
+            )
+            .await;
+            drop(options.stdout_tap);
+            record_result(&state, &job_id, &log, options.store, execution_result).await;
+            log.finish();
+            return;
+        }
+
+        // Inside the VM, the code gets its memory but what the guest keeps for itself,
+        // and its share of the vCPUs it asked for once its CPU burst is over.
+        request_payload.limits = Some(job_limits(
+            shape.vcpus,
+            shape.memory_mb,
+            state.job_pids_max,
+            state.core_dumps.max_bytes(),
+            &state.job_cpu,
+        ));
+
+        // Compiled code runs the binary an earlier run built with the same toolchain,
+        // or hands back the one it builds for the next runs.
+        let host_builder = state
+            .host_builder
+            .as_ref()
+            .filter(|_| config.builds_on_host(&language));
+        let binary_key = match host_builder {
+            Some(builder) => builder
+                .toolchain()
+                .await
+                .map(|toolchain| state.compile_cache.key_for(&request_payload, toolchain))
+                .map_err(|e| e.to_string()),
+            None => state
+                .compile_cache
+                .key(&request_payload, &state.vm_config)
+                .await
+                .map_err(|e| e.to_string()),
+        };
+        let binary_key = match binary_key {
+            Ok(key) => key,
+            Err(e) => {
+                warn!("Job {} – compile cache unavailable: {}", job_id, e);
+                None
+            }
+        };
+        if let Some(key) = &binary_key {
+            match state.compile_cache.get(key).await {
+                Ok(Some(binary)) => {
+                    info!("Job {} – running the binary of an earlier build", job_id);
+                    request_payload.binary = Some(BASE64_STANDARD.encode(binary));
+                }
+                Ok(None) => request_payload.keep_binary = true,
+                Err(e) => warn!("Job {} – cannot read compiled binary: {}", job_id, e),
+            }
+        }
+
+        let vm_config = VmConfig {
+            vcpus: shape.vcpus,
+            memory_mb: shape.memory_mb,
+            redactor: config.redactor.clone(),
+            tenant: options.tenant.clone(),
+            merge_memory: config.ksm.enabled,
+            ..state.vm_config.clone()
+        };
+        // Runtimes with a run image compile in a VM of their toolchain image, or on the
+        // host, unless an earlier build is at hand, and run the binary in a VM of the
+        // slim image.
+        let vm_language = match config.run_stage(&language) {
+            Some(run_stage) => {
+                if request_payload.binary.is_none() {
+                    let built = match host_builder {
+                        Some(builder) => {
+                            info!(
+                                "Job {} – compiling on the host for {}",
+                                job_id,
+                                builder.target()
+                            );
+                            builder
+                                .build(&job_id, &request_payload)
+                                .await
+                                .map_err(|e| format!("Failed to build on the host: {e}"))
+                        }
+                        None => {
+                            let egress = options.egress.as_ref().map(|allowlist| (allowlist, &log));
+                            build_in_vm(
+                                &state,
+                                &job_id,
+                                &language,
+                                &vm_config,
+                                &request_payload,
+                                egress,
+                            )
+                            .await
+                        }
+                    };
+                    match built {
+                        Ok(ExecutionResult {
+                            binary: Some(binary),
+                            ..
+                        }) => {
+                            if let Some(key) = binary_key.clone() {
+                                store_binary(
+                                    Arc::clone(&state),
+                                    job_id.clone(),
+                                    key,
+                                    binary.clone(),
+                                );
+                            }
+                            request_payload.binary = Some(binary);
+                            request_payload.keep_binary = false;
+                        }
+                        // The compile step failed: its output is the result of the job.
+                        built => {
+                            record_result(&state, &job_id, &log, options.store, built).await;
+                            log.finish();
+                            return;
+                        }
+                    }
+                }
+                run_stage
+            }
+            None => language.clone(),
+        };
+        let image_digest = match state.images.digest(&vm_language, &vm_config).await {
+            Ok(digest) => Some(digest),
+            Err(e) => {
+                warn!("Job {} – cannot tell the runtime image: {}", job_id, e);
+                None
+            }
+        };
+        if let Some(j) = state.jobs.write().await.get_mut(&job_id) {
+            j.image_digest = image_digest;
+        }
+        // An invocation of a function joins a VM of a concurrent one that has room.
+        if let Some(guest) = options
+            .share
+            .as_ref()
+            .and_then(|key| state.shared_vms.join(key))
+        {
+            run_as_guest(
+                &state,
+                &job_id,
+                guest,
+                request_payload,
+                options,
+                &log,
+                binary_key,
+            )
+            .await;
+            return;
+        }
+        // A pooled VM can be larger than the job asks for, and goes back to the pool
+        // with its own shape.
+        let (vm_pool_key, pooled_vm) = match options.pool.as_ref() {
+            Some(key) => match state.vm_pool.take(key) {
+                Some((vm_key, vm)) => (Some(vm_key), Some(vm)),
+                None => match resume_vm(&state, &job_id, key, &vm_language, &vm_config).await {
+                    Some((vm_key, vm)) => (Some(vm_key), Some(vm)),
+                    None => (Some(key.clone()), None),
+                },
+            },
+            None => (None, None),
+        };
+        if let (Some(vm), Some(key)) = (&pooled_vm, &vm_pool_key) {
+            info!(
+                "Job {} – reusing pooled VM {} ({} vCPUs, {} MiB)",
+                job_id, vm.vm_id, key.vcpus, key.memory_mb
+            );
+            warm_up(&job_id, vm).await;
+        }
+        let reused = pooled_vm.is_some();
+        // A new VM waits for the host to have room for it; a pooled one adds no load.
+        // One for a pool can be restored from a template, and is readied below.
+        let created = match pooled_vm {
+            Some(vm) => Ok(vm),
+            None => match admit_vm(&state, &job_id).await {
+                Ok(()) if options.pool.is_some() => {
+                    create_pooled_vm(&state, job_id.clone(), &vm_language, &vm_config)
+                        .await
+                        .map_err(|e| format!("Failed to create VM: {e}"))
+                }
+                Ok(()) => VmHandle::create(
+                    job_id.clone(),
+                    &vm_language,
+                    &vm_config,
+                    Arc::clone(&state.ip_manager),
+                )
+                .await
+                .map_err(|e| format!("Failed to create VM: {e}")),
+                Err(e) => Err(e),
+            },
+        };
+        let vm = match created {
+            Ok(vm) => vm,
+            Err(e) => {
+                if let Some(j) = state.jobs.write().await.get_mut(&job_id) {
+                    j.status = JobStatus::Error;
+                    j.stderr = Some(e.clone());
+                }
+                persist_job(&state, &job_id).await;
+                log.push(LogSource::Stderr, &e);
+                log.finish();
+                error!("Job {} – {}", job_id, e);
+                state
+                    .events
+                    .job(EventKind::ExecutionFailed, &job_id, Some(e));
+                return;
+            }
+        };
+        if !reused {
+            state
+                .events
+                .vm(EventKind::VmCreated, &vm.vm_id, Some(&job_id));
+        }
+        if let Some(j) = state.jobs.write().await.get_mut(&job_id) {
+            j.vm_id = Some(vm.vm_id.clone());
+            j.warm = Some(reused);
+        }
+        persist_job(&state, &job_id).await;
+        let (function, version) = options.function.clone().unzip();
+        let identity = JobIdentity {
+            vm_id: vm.vm_id.clone(),
+            job_id: job_id.clone(),
+            function,
+            version,
+            tenant: options.tenant.clone(),
+        };
+        request_payload.identity_token = Some(state.identity.issue(
+            identity.clone(),
+            options.identity_audience.as_deref(),
+            std::time::SystemTime::now(),
+        ));
+        let egress_lease = match admit_egress(&state, &job_id, &vm, options.egress, &log) {
+            Ok(lease) => lease,
+            Err(e) => {
+                if let Some(j) = state.jobs.write().await.get_mut(&job_id) {
+                    j.status = JobStatus::Error;
+                    j.stderr = Some(e.clone());
+                }
+                persist_job(&state, &job_id).await;
+                log.push(LogSource::Stderr, &e);
+                log.finish();
+                error!("Job {} – {}", job_id, e);
+                state
+                    .events
+                    .job(EventKind::ExecutionFailed, &job_id, Some(e));
+                shut_down_vm(&state, vm, Some(&job_id)).await;
+                return;
+            }
+        };
+        request_payload.egress_proxy = egress_lease
+            .as_ref()
+            .and(state.egress_proxy.as_ref())
+            .map(EgressProxy::url);
+
+        // Record the guest console for the job logs until the VM is destroyed, or goes
+        // back to its pool. The console of earlier jobs in a pooled VM is theirs.
+        let kernel_log = vm.console().attach().map(|(scrollback, mut output)| {
+            if !reused {
+                log.push_kernel_output(&scrollback);
+            }
+            let log = Arc::clone(&log);
+            tokio::spawn(async move {
+                loop {
+                    match output.recv().await {
+                        Ok(chunk) => log.push_kernel_output(&chunk),
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            })
+        });
+
+        state
+            .consoles
+            .write()
+            .await
+            .insert(job_id.clone(), Arc::clone(vm.console()));
+        if let Some(hotplug) = vm.vcpu_hotplug() {
+            state
+                .vcpu_hotplugs
+                .write()
+                .await
+                .insert(job_id.clone(), hotplug.clone());
+        }
+        if let Some(resize) = vm.memory_resize() {
+            state
+                .memory_resizes
+                .write()
+                .await
+                .insert(job_id.clone(), resize.clone());
+        }
+        state.taps.write().await.insert(
+            job_id.clone(),
+            JobTap {
+                device: vm.tap_device.clone(),
+                running: tokio::sync::watch::channel(()).0,
+            },
+        );
+
+        // Watch the agent while the job runs: a VM that stops answering or exits
+        // fails its job, and is shut down rather than pooled.
+        state.heartbeats.track(&job_id, &vm.vm_id, vm.ip);
+        // A pooled VM counts from its boot: the traffic of the job is what it adds.
+        let traffic = vm
+            .traffic()
+            .and_then(|meter| Some((meter.clone(), meter.read().ok()?)));
+        let vm_lost = Arc::new(tokio::sync::Notify::new());
+        let heartbeat_watch = tokio::spawn(watch_heartbeats(
+            Arc::clone(&state),
+            job_id.clone(),
+            vm.agent_url(),
+            vm.stop_handle(),
+            traffic.clone(),
+            Arc::clone(&vm_lost),
+        ));
+
+        // A pooled VM starts each job with the filesystem it booted with. One the
+        // agent could not reset runs its job as is, but is not pooled again.
+        let mut clean = true;
+        if options.pool.is_some() {
+            match reset_agent(&state.client, &vm.agent_url()).await {
+                Ok(reset) => info!(
+                    "Job {} – reset VM {} in {} ms",
+                    job_id, vm.vm_id, reset.duration_ms
+                ),
+                Err(e) => {
+                    warn!("Job {} – cannot reset VM {}: {}", job_id, vm.vm_id, e);
+                    clean = false;
+                }
+            }
+            // Its clock drifted while it idled, or was set back by a deterministic job.
+            match sync_clock(&state.client, &vm.agent_url()).await {
+                Ok(clock) if clock.offset_ms.abs() >= 1000 => info!(
+                    "Job {} – stepped the clock of VM {} by {} ms",
+                    job_id, vm.vm_id, clock.offset_ms
+                ),
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "Job {} – cannot set the clock of VM {}: {}",
+                    job_id, vm.vm_id, e
+                ),
+            }
+            if let Err(e) = reseed_entropy(&state.client, &vm.agent_url()).await {
+                warn!(
+                    "Job {} – cannot reseed the RNG of VM {}: {}",
+                    job_id, vm.vm_id, e
+                );
+            }
+        }
+
+        // Concurrent invocations of the function can join the VM from now on.
+        let host = options.share.clone().map(|key| {
+            request_payload.concurrent = true;
+            state.shared_vms.host(
+                key,
+                SharedVm {
+                    vm_id: vm.vm_id.clone(),
+                    ip: vm.ip,
+                    agent_url: vm.agent_url(),
+                    stop: vm.stop_handle(),
+                },
+            )
+        });
+
+        let agent_url = vm.agent_url();
+        let renewal = renew_identity(
+            Arc::clone(&state),
+            agent_url.clone(),
+            identity,
+            options.identity_audience.clone(),
+        );
+        let execute = execute_on_agent(
+            &state.client,
+            &job_id,
+            &agent_url,
+            &request_payload,
+            options.stdout_tap.as_ref(),
+        );
+        let execution_result = tokio::select! {
+            result = execute => result,
+            _ = vm_lost.notified() => Err(vm_lost_reason(&state, &job_id)),
+        };
+        renewal.abort();
+        heartbeat_watch.abort();
+        state.heartbeats.forget(&job_id);
+        // Before the VM can go back to its pool, for a job that may not be restricted.
+        drop(egress_lease);
+        let execution_result = execution_result.map(|mut result| {
+            if let (Some(binary), Some(key)) = (result.binary.take(), binary_key) {
+                store_binary(Arc::clone(&state), job_id.clone(), key, binary);
+            }
+            result
+        });
+        if let Some(job_traffic) = traffic.as_ref().and_then(traffic_since) {
+            state.metrics.record_job_traffic(&job_traffic);
+            if let Some(j) = state.jobs.write().await.get_mut(&job_id) {
+                j.traffic = Some(job_traffic);
+            }
+        }
+        // The output is all there: the caller it streams to is done with it.
+        drop(options.stdout_tap);
+
+        // A VM goes back to its pool if its agent answered and reset it, and its job
+        // did not resize it.
+        let pool_key = vm_pool_key
+            .filter(|key| clean && execution_result.is_ok() && vm.has_boot_shape(key.vcpus));
+
+        record_result(&state, &job_id, &log, options.store, execution_result).await;
+        // The jobs that joined the VM run in it until they end.
+        if let Some(host) = host {
+            let guests = state.shared_vms.close(host).await;
+            if guests > 0 {
+                info!(
+                    "Job {} – waited for {} jobs sharing VM {}",
+                    job_id, guests, vm.vm_id
+                );
+            }
+        }
+
+        // Teardown after job state is finalized so polling clients are never stuck in "running"
+        // if VM shutdown blocks longer than expected.
+        state.consoles.write().await.remove(&job_id);
+        state.vcpu_hotplugs.write().await.remove(&job_id);
+        state.memory_resizes.write().await.remove(&job_id);
+        // Ends the captures of the job before its VM carries the traffic of another.
+        state.taps.write().await.remove(&job_id);
+        match pool_key {
+            Some(key) if state.vm_pool_scrub => {
+                if let Some(kernel_log) = kernel_log {
+                    kernel_log.abort();
+                }
+                // The next job may be of another tenant: it gets a pristine VM of the
+                // same shape, made ready once the logs of this job are complete.
+                let vm_config = VmConfig {
+                    vcpus: key.vcpus,
+                    memory_mb: key.memory_mb,
+                    ..vm_config
+                };
+                let state = Arc::clone(&state);
+                let job_id = job_id.clone();
+                tokio::spawn(async move {
+                    scrub_vm(&state, &job_id, key, vm, &vm_language, &vm_config).await;
+                });
+            }
+            Some(key) => {
+                if let Some(kernel_log) = kernel_log {
+                    kernel_log.abort();
+                }
+                for evicted in state.vm_pool.put(key, vm) {
+                    shut_down_vm(&state, evicted, None).await;
+                }
+            }
+            None => {
+                shut_down_vm(&state, vm, Some(&job_id)).await;
+                // Destroying the VM closed its console, so the last kernel lines are in.
+                if let Some(kernel_log) = kernel_log {
+                    let _ = kernel_log.await;
+                }
+            }
+        }
+        log.finish();
+    });
+
+    id
+}
+
+/// Runs job `job_id` in the VM of a concurrent invocation of the same function,
+/// which keeps the VM: the job gets no console, traffic or packet capture of its own.
+pub(crate) async fn run_as_guest(
+    state: &Arc<AppState>,
+    job_id: &str,
+    guest: Guest<SharedVm>,
+    mut request: ExecuteRequest,
+    options: JobOptions,
+    log: &JobLog,
+    binary_key: Option<String>,
+) {
+    let vm = guest.vm();
+    info!(
+        "Job {} – joining VM {} of a concurrent invocation",
+        job_id, vm.vm_id
+    );
+    if let Some(j) = state.jobs.write().await.get_mut(job_id) {
+        j.vm_id = Some(vm.vm_id.clone());
+        j.warm = Some(true);
+    }
+    persist_job(state, job_id).await;
+    let (function, version) = options.function.clone().unzip();
+    let identity = JobIdentity {
+        vm_id: vm.vm_id.clone(),
+        job_id: job_id.to_string(),
+        function,
+        version,
+        tenant: options.tenant.clone(),
+    };
+    request.identity_token = Some(state.identity.issue(
+        identity.clone(),
+        options.identity_audience.as_deref(),
+        std::time::SystemTime::now(),
+    ));
+    request.concurrent = true;
+
+    state.heartbeats.track(job_id, &vm.vm_id, vm.ip);
+    let vm_lost = Arc::new(tokio::sync::Notify::new());
+    let heartbeat_watch = tokio::spawn(watch_heartbeats(
+        Arc::clone(state),
+        job_id.to_string(),
+        vm.agent_url.clone(),
+        vm.stop.clone(),
+        None,
+        Arc::clone(&vm_lost),
+    ));
+    let renewal = renew_identity(
+        Arc::clone(state),
+        vm.agent_url.clone(),
+        identity,
+        options.identity_audience.clone(),
+    );
+    let execute = execute_on_agent(
+        &state.client,
+        job_id,
+        &vm.agent_url,
+        &request,
+        options.stdout_tap.as_ref(),
+    );
+    let execution_result = tokio::select! {
+        result = execute => result,
+        _ = vm_lost.notified() => Err(vm_lost_reason(state, job_id)),
+    };
+    renewal.abort();
+    heartbeat_watch.abort();
+    state.heartbeats.forget(job_id);
+    // The host waits for its guests before the VM goes.
+    drop(guest);
+    let execution_result = execution_result.map(|mut result| {
+        if let (Some(binary), Some(key)) = (result.binary.take(), binary_key) {
+            store_binary(Arc::clone(state), job_id.to_string(), key, binary);
+        }
+        result
+    });
+    drop(options.stdout_tap);
+    record_result(state, job_id, log, options.store, execution_result).await;
+    log.finish();
+}
+
+/// Sends the agent at `agent_url` a new identity token for `identity` every third
+/// of their life, so that one failed attempt still leaves the code a valid token.
+/// Runs until aborted, once the job ended.
+pub(crate) fn renew_identity(
+    state: Arc<AppState>,
+    agent_url: String,
+    identity: JobIdentity,
+    audience: Option<String>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let every = (state.identity.ttl() / 3).max(std::time::Duration::from_secs(1));
+        loop {
+            tokio::time::sleep(every).await;
+            let token = state.identity.issue(
+                identity.clone(),
+                audience.as_deref(),
+                std::time::SystemTime::now(),
+            );
+            if let Err(e) = send_identity(&state.client, &agent_url, token).await {
+                warn!(
+                    "Job {} – cannot renew the identity token: {}",
+                    identity.job_id, e
+                );
+            }
+        }
+    })
+}
+
+/// Why the VM of job `job_id` is lost, once its heartbeat watch said so.
+pub(crate) fn vm_lost_reason(state: &AppState, job_id: &str) -> String {
+    match state.heartbeats.liveness(job_id) {
+        Some(VmLiveness::Exited) => "VM exited while running the job".to_string(),
+        _ => format!(
+            "VM agent missed its heartbeats for {} seconds",
+            state.heartbeats.timeout().as_secs()
+        ),
+    }
+}
+
+/// A new VM for a pool: restored from the template of `language` when one fits
+/// `vm_config`, booted otherwise. The agent of a restored VM must be readied like
+/// the one of a VM taken from a pool, before it runs a job.
+pub(crate) async fn create_pooled_vm(
+    state: &AppState,
+    vm_id: String,
+    language: &str,
+    vm_config: &VmConfig,
+) -> Result<VmHandle, VmError> {
+    if let Some(clones) = &state.template_clones {
+        match clones.snapshot_for(language, vm_config).await {
+            Ok(Some(snapshot_dir)) => match VmHandle::restore(
+                vm_id.clone(),
+                language,
+                &snapshot_dir,
+                vm_config,
+                Arc::clone(&state.ip_manager),
+            )
+            .await
+            {
+                Ok(vm) => {
+                    info!("Restored VM {} from the template of {}", vm.vm_id, language);
+                    return Ok(vm);
+                }
+                Err(e) => warn!(
+                    "Cannot restore VM {} from the template of {}, booting it: {}",
+                    vm_id, language, e
+                ),
+            },
+            Ok(None) => {}
+            Err(e) => warn!("Cannot fetch the template of {}: {}", language, e),
+        }
+    }
+    VmHandle::create(vm_id, language, vm_config, Arc::clone(&state.ip_manager)).await
+}
+
+/// Scrubs `vm`, the VM of `key` that ran job `job_id`, before it goes back to the
+/// pool: it is shut down, and a VM restored from the template of `language` or
+/// booted takes its place. Nothing of the job is left, in guest memory or in the
+/// tmpfs its files were written to, which is in guest memory too: the memory of a
+/// VMM goes with it, and the host zeroes it before it hands it out again. The time
+/// it took is in the metrics of the pool. No VM takes its place when none can be had.
+pub(crate) async fn scrub_vm(
+    state: &AppState,
+    job_id: &str,
+    key: PoolKey,
+    vm: VmHandle,
+    language: &str,
+    vm_config: &VmConfig,
+) {
+    let started = std::time::Instant::now();
+    shut_down_vm(state, vm, Some(job_id)).await;
+
+    if let Err(e) = admit_vm(state, job_id).await {
+        warn!("Job {} – no VM takes the place of its VM: {}", job_id, e);
+        return;
+    }
+    let vm_id = format!("pool-{}", uuid::Uuid::new_v4());
+    let vm = match create_pooled_vm(state, vm_id, language, vm_config).await {
+        Ok(vm) => vm,
+        Err(e) => {
+            warn!(
+                "Job {} – cannot create a VM in place of its VM: {}",
+                job_id, e
+            );
+            return;
+        }
+    };
+    let took = started.elapsed();
+    info!(
+        "Job {} – scrubbed its VM, VM {} took its place in {} ms",
+        job_id,
+        vm.vm_id,
+        took.as_millis()
+    );
+    state.events.vm(EventKind::VmCreated, &vm.vm_id, None);
+    for evicted in state.vm_pool.put_scrubbed(key, vm, took) {
+        shut_down_vm(state, evicted, None).await;
+    }
+}
+
+/// Waits for the host to be out of CPU and memory pressure before a VM is booted
+/// for job `job_id`, for `ADMISSION_MAX_WAIT_SECS` at most. Returns why the VM
+/// cannot be booted when the pressure outlasts the wait.
+pub(crate) async fn admit_vm(state: &AppState, job_id: &str) -> Result<(), String> {
+    let Some(pressure) = &state.pressure else {
+        return Ok(());
+    };
+    match pressure.admit(state.admission_max_wait).await {
+        Ok(waited) => {
+            if !waited.is_zero() {
+                info!(
+                    "Job {} – VM deferred {} ms by host pressure",
+                    job_id,
+                    waited.as_millis()
+                );
+            }
+            Ok(())
+        }
+        Err(e) => Err(format!(
+            "Host under pressure for {} seconds, no VM booted: {}",
+            state.admission_max_wait.as_secs(),
+            e
+        )),
+    }
+}
+
+/// Compiles the code of `request` in a VM of the toolchain image of `language`, and
+/// returns what the agent answered: the binary, or the output of a failed compile.
+pub(crate) async fn build_in_vm(
+    state: &Arc<AppState>,
+    job_id: &str,
+    language: &str,
+    vm_config: &VmConfig,
+    request: &ExecuteRequest,
+    egress: Option<(&Allowlist, &Arc<JobLog>)>,
+) -> Result<ExecutionResult, String> {
+    admit_vm(state, job_id).await?;
+    let vm = VmHandle::create(
+        format!("{job_id}-build"),
+        language,
+        vm_config,
+        Arc::clone(&state.ip_manager),
+    )
+    .await
+    .map_err(|e| format!("Failed to create build VM: {e}"))?;
+    state
+        .events
+        .vm(EventKind::VmCreated, &vm.vm_id, Some(job_id));
+    info!("Job {} – compiling in VM {}", job_id, vm.vm_id);
+
+    // Build scripts are code of the job too: they get the same network.
+    let admitted = match egress {
+        Some((allowlist, log)) => admit_egress(state, job_id, &vm, Some(allowlist.clone()), log),
+        None => Ok(None),
+    };
+    let egress_lease = match admitted {
+        Ok(lease) => lease,
+        Err(e) => {
+            shut_down_vm(state, vm, Some(job_id)).await;
+            return Err(e);
+        }
+    };
+    let request = ExecuteRequest {
+        stdin: None,
+        stream: false,
+        keep_binary: true,
+        build_only: true,
+        egress_proxy: egress_lease
+            .as_ref()
+            .and(state.egress_proxy.as_ref())
+            .map(EgressProxy::url),
+        ..request.clone()
+    };
+    let built = execute_on_agent(&state.client, job_id, &vm.agent_url(), &request, None).await;
+    drop(egress_lease);
+    shut_down_vm(state, vm, Some(job_id)).await;
+    built
+}
+
+/// Cuts `vm` off the network but the egress proxy, for a job that reaches the
+/// domains of `allowlist` only. Does nothing without one.
+pub(crate) fn admit_egress(
+    state: &AppState,
+    job_id: &str,
+    vm: &VmHandle,
+    allowlist: Option<Allowlist>,
+    log: &Arc<JobLog>,
+) -> Result<Option<EgressLease>, String> {
+    let (Some(allowlist), Some(proxy)) = (allowlist, state.egress_proxy.as_ref()) else {
+        return Ok(None);
+    };
+    proxy
+        .admit(vm.ip, job_id, allowlist, Arc::clone(log))
+        .map(Some)
+}
+
+/// Stores in the background the binary job `job_id` built, base64-encoded by its agent.
+pub(crate) fn store_binary(state: Arc<AppState>, job_id: String, key: String, binary: String) {
+    tokio::spawn(async move {
+        let binary = match BASE64_STANDARD.decode(binary) {
+            Ok(binary) => binary,
+            Err(e) => {
+                warn!("Job {} – agent sent an invalid binary: {}", job_id, e);
+                return;
+            }
+        };
+        if let Err(e) = state.compile_cache.put(&key, binary).await {
+            warn!("Job {} – cannot store compiled binary: {}", job_id, e);
+        }
+    });
+}
+
+/// Sends `request` to the agent at `agent_url`, retrying while it cannot be
+/// reached, and returns its result. With `stdout_tap`, the agent streams the
+/// standard output, handed to the tap as it comes.
+pub(crate) async fn execute_on_agent(
+    client: &reqwest::Client,
+    job_id: &str,
+    agent_url: &str,
+    request: &ExecuteRequest,
+    stdout_tap: Option<&tokio::sync::mpsc::UnboundedSender<Vec<u8>>>,
+) -> Result<ExecutionResult, String> {
+    let execute_url = format!("{}/execute", agent_url.trim_end_matches('/'));
+    let mut execution_result: Result<ExecutionResult, String> =
+        Err("VM agent execute request did not run".to_string());
+
+    for attempt in 1..=5 {
+        let result = send_to_agent(client, &execute_url, request).await;
+
+        match result {
+            Ok(resp) if resp.status().is_success() => {
+                execution_result = match stdout_tap {
+                    Some(tap) => read_streamed_result(resp, tap).await,
+                    None => resp
+                        .json::<ExecutionResult>()
+                        .await
+                        .map_err(|e| format!("Failed to parse agent response: {e}")),
+                };
+                break;
+            }
+            Ok(resp) => {
+                let status = resp.status();
+                let body = resp.text().await.unwrap_or_default();
+                execution_result = Err(format!("Agent returned HTTP {status}: {body}"));
+                break;
+            }
+            Err(e) => {
+                if attempt == 5 {
+                    execution_result = Err(format!("Cannot reach VM agent: {e}"));
+                    break;
+                }
+
+                info!(
+                    "Job {} – execute call failed on attempt {}/5, retrying: {}",
+                    job_id, attempt, e
+                );
+                tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+            }
+        }
+    }
+    execution_result
+}
+
+/// Records how job `job_id` ended, in its status, logs and events, and in the
+/// result cache when `store` says so.
+pub(crate) async fn record_result(
+    state: &AppState,
+    job_id: &str,
+    log: &JobLog,
+    store: Option<CacheStore>,
+    execution_result: Result<ExecutionResult, String>,
+) {
+    let core_dump = match &execution_result {
+        Ok(agent_resp) => agent_resp.core_dump.as_deref(),
+        Err(_) => None,
+    };
+    let core_dump = match core_dump {
+        Some(core) => store_core_dump(state, job_id, core).await,
+        None => false,
+    };
+    // Kept, cached and journaled redacted, like the logs.
+    let execution_result = match execution_result {
+        Ok(mut agent_resp) => {
+            agent_resp.stdout = log.redactor().redact(&agent_resp.stdout).into_owned();
+            agent_resp.stderr = log.redactor().redact(&agent_resp.stderr).into_owned();
+            Ok(agent_resp)
+        }
+        Err(e) => Err(log.redactor().redact(&e).into_owned()),
+    };
+    let mut jobs = state.jobs.write().await;
+    if let Some(j) = jobs.get_mut(job_id) {
+        j.duration_ms = Some(j.created_at.elapsed().as_millis() as u64);
+    }
+    match execution_result {
+        Ok(agent_resp) => {
+            // A run killed for its memory or by a signal is not worth replaying.
+            let killed = agent_resp.oom_killed || agent_resp.signal.is_some();
+            if let Some(store) = store.filter(|_| !killed) {
+                let result = CachedResult {
+                    exit_code: agent_resp.exit_code,
+                    stdout: agent_resp.stdout.clone(),
+                    stderr: agent_resp.stderr.clone(),
+                };
+                state.result_cache.insert(store.key, result, store.ttl);
+            }
+            log.push(LogSource::Stdout, &agent_resp.stdout);
+            log.push(LogSource::Stderr, &agent_resp.stderr);
+            state.events.job(
+                EventKind::ExecutionFinished,
+                job_id,
+                Some(format!("exit_code={}", agent_resp.exit_code)),
+            );
+            if let Some(j) = jobs.get_mut(job_id) {
+                j.status = JobStatus::Done;
+                j.exit_code = Some(agent_resp.exit_code);
+                j.stdout = Some(agent_resp.stdout);
+                j.stderr = Some(agent_resp.stderr);
+                j.oom_killed = agent_resp.oom_killed;
+                j.signal = agent_resp.signal.clone();
+                j.core_dump = core_dump;
+            }
+            if let Some(signal) = &agent_resp.signal {
+                warn!("Job {} – killed by {}", job_id, signal);
+            }
+            if agent_resp.oom_killed {
+                warn!("Job {} – killed for going over its memory", job_id);
+            }
+            info!("Job {} completed", job_id);
+        }
+        Err(e) => {
+            log.push(LogSource::Stderr, &e);
+            if let Some(j) = jobs.get_mut(job_id) {
+                j.status = JobStatus::Error;
+                j.stderr = Some(e.clone());
+            }
+            error!("Job {} – execution failed: {}", job_id, e);
+            state
+                .events
+                .job(EventKind::ExecutionFailed, job_id, Some(e));
+        }
+    }
+    drop(jobs);
+    persist_job(state, job_id).await;
+}
+
+/// Keeps the base64 core the code of job `job_id` dumped, and tells whether it was kept.
+pub(crate) async fn store_core_dump(state: &AppState, job_id: &str, core: &str) -> bool {
+    let core = match BASE64_STANDARD.decode(core) {
+        Ok(core) => core,
+        Err(e) => {
+            warn!("Job {} – invalid core dump from the agent: {}", job_id, e);
+            return false;
+        }
+    };
+    match state.core_dumps.put(job_id, core).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Job {} – cannot keep its core dump: {}", job_id, e);
+            false
+        }
+    }
+}
+
+/// Records the current state of job `job_id` in the journal, for a restart to
+/// find. A job that cannot be recorded still runs.
+pub(crate) async fn persist_job(state: &AppState, job_id: &str) {
+    let Some(entry) = state.jobs.read().await.get(job_id).map(journal_entry) else {
+        return;
+    };
+    let journal = Arc::clone(&state.journal);
+    let recorded = tokio::task::spawn_blocking(move || journal.record(entry))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()));
+    if let Err(e) = recorded {
+        warn!("Job {} – cannot record it in the journal: {}", job_id, e);
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub(crate) fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// When `job` was submitted, in seconds since the Unix epoch.
+pub(crate) fn submitted_at(job: &Job) -> u64 {
+    (std::time::SystemTime::now() - job.created_at.elapsed())
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub(crate) fn journal_entry(job: &Job) -> JournalEntry {
+    JournalEntry {
+        id: job.id.clone(),
+        status: job.status,
+        language: job.language.clone(),
+        exit_code: job.exit_code,
+        stdout: job.stdout.clone(),
+        stderr: job.stderr.clone(),
+        isolation: job.isolation,
+        created_at: submitted_at(job),
+        tenant: job.tenant.clone(),
+        namespace: (!job.namespace.is_default()).then(|| job.namespace.to_string()),
+        agent_pid: job.agent_pid,
+        vm_id: job.vm_id.clone(),
+        traffic: job.traffic,
+        oom_killed: job.oom_killed,
+        signal: job.signal.clone(),
+        core_dump: job.core_dump,
+        code_digest: job.code_digest.clone(),
+        image_digest: job.image_digest.clone(),
+        shape: job.shape,
+        duration_ms: job.duration_ms,
+    }
+}
+
+/// Rebuilds a job and its logs from the journal of a previous run. Only the
+/// output of the code was recorded: the console of its VM is left in its serial
+/// log, see `GET /logs/{id}/vm`.
+pub(crate) fn recovered_job(entry: JournalEntry) -> (Job, Arc<JobLog>) {
+    let age = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH + std::time::Duration::from_secs(entry.created_at))
+        .unwrap_or_default();
+    let log = Arc::new(JobLog::new());
+    if let Some(stdout) = &entry.stdout {
+        log.push(LogSource::Stdout, stdout);
+    }
+    if let Some(stderr) = &entry.stderr {
+        log.push(LogSource::Stderr, stderr);
+    }
+    log.finish();
+    let job = Job {
+        id: entry.id,
+        status: entry.status,
+        language: entry.language,
+        exit_code: entry.exit_code,
+        stdout: entry.stdout,
+        stderr: entry.stderr,
+        isolation: entry.isolation,
+        created_at: std::time::Instant::now()
+            .checked_sub(age)
+            .unwrap_or_else(std::time::Instant::now),
+        tenant: entry.tenant,
+        namespace: entry
+            .namespace
+            .as_deref()
+            .and_then(|namespace| Namespace::new(namespace).ok())
+            .unwrap_or_default(),
+        agent_pid: None,
+        vm_id: entry.vm_id,
+        traffic: entry.traffic,
+        oom_killed: entry.oom_killed,
+        signal: entry.signal,
+        core_dump: entry.core_dump,
+        code_digest: entry.code_digest,
+        image_digest: entry.image_digest,
+        shape: entry.shape,
+        duration_ms: entry.duration_ms,
+        function: None,
+        warm: None,
+    };
+    (job, log)
+}
+
+/// Runs `request` in a new agent process, for backends without VMs.
+#[cfg(feature = "process-executor")]
+pub(crate) async fn run_in_process(
+    state: &AppState,
+    executor: &ProcessExecutor,
+    job_id: &str,
+    memory_mb: usize,
+    request: &ExecuteRequest,
+    stdout_tap: Option<&tokio::sync::mpsc::UnboundedSender<Vec<u8>>>,
+) -> Result<ExecutionResult, String> {
+    let mut process = executor
+        .spawn(job_id, memory_mb, state.job_pids_max, &state.client)
+        .await
+        .map_err(|e| format!("Failed to start agent process: {e}"))?;
+    // Recorded for a restart to stop it, should the backend die first.
+    if let Some(j) = state.jobs.write().await.get_mut(job_id) {
+        j.agent_pid = Some(process.pid);
+    }
+    persist_job(state, job_id).await;
+    let agent_url = process.agent_url();
+    let client = match process.client() {
+        Ok(client) => client,
+        Err(e) => {
+            process.stop().await;
+            return Err(format!("Failed to start agent process: {e}"));
+        }
+    };
+    let execute = execute_on_agent(&client, job_id, &agent_url, request, stdout_tap);
+    let result = tokio::select! {
+        result = execute => result,
+        status = process.exited() => Err(match status {
+            Ok(status) => format!("Agent process exited with {status} while running the job"),
+            Err(e) => format!("Agent process lost: {e}"),
+        }),
+    };
+    process.stop().await;
+    result
+}
+
+/// Pages the memory of a pooled VM back in if it went cold, so that the job does
+/// not fault it in page by page. A VM that cannot be warmed up runs all the same.
+pub(crate) async fn warm_up(job_id: &str, vm: &VmHandle) {
+    let paging = vm.paging().clone();
+    let started = std::time::Instant::now();
+    let paged_in = tokio::task::spawn_blocking(move || paging.page_in())
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()));
+    match paged_in {
+        Ok(true) => info!(
+            "Job {} – paged the memory of VM {} back in ({} ms)",
+            job_id,
+            vm.vm_id,
+            started.elapsed().as_millis()
+        ),
+        Ok(false) => {}
+        Err(e) => warn!(
+            "Job {} – cannot page VM {} back in: {}",
+            job_id, vm.vm_id, e
+        ),
+    }
+}
+
+/// Starts and tunes `ksmd` as `settings` say, when the backend runs VMs. A host
+/// that cannot merge memory still runs them, unmerged.
+pub(crate) fn apply_ksm(state: &AppState, settings: &KsmSettings) {
+    if let Some(ksm) = &state.ksm
+        && let Err(e) = ksm.apply(settings)
+    {
+        warn!(
+            "Cannot configure kernel samepage merging in {}: {}",
+            KSM_DIR, e
+        );
+    }
+}
+
+/// Suspends `vm`, idle in the pool under `key`, to disk, and keeps it for the
+/// next job of `key`. A VM that cannot be suspended is gone.
+pub(crate) async fn suspend_vm(state: &AppState, key: PoolKey, vm: VmHandle) {
+    let vm_id = vm.vm_id.clone();
+    let started = std::time::Instant::now();
+    match vm.suspend(&state.suspended_vms_dir.join(&vm_id)).await {
+        Ok(suspended) => {
+            info!(
+                "Idle VM {} suspended to disk in {} ms",
+                vm_id,
+                started.elapsed().as_millis()
+            );
+            for evicted in state.suspended_vms.put(key, suspended) {
+                state
+                    .events
+                    .vm(EventKind::VmDestroyed, &evicted.vm_id, None);
+            }
+        }
+        Err(e) => {
+            warn!("Cannot suspend idle VM {}, destroyed it: {}", vm_id, e);
+            state.events.vm(EventKind::VmDestroyed, &vm_id, None);
+        }
+    }
+}
+
+/// Resumes a VM suspended to disk that can run job `job_id`, asking for `key`,
+/// with the key it goes back to the pool with. `None` when there is none, or it
+/// cannot be resumed: the job gets a new VM.
+pub(crate) async fn resume_vm(
+    state: &AppState,
+    job_id: &str,
+    key: &PoolKey,
+    language: &str,
+    vm_config: &VmConfig,
+) -> Option<(PoolKey, VmHandle)> {
+    let (vm_key, suspended) = state.suspended_vms.take(key)?;
+    // Its memory comes back as the guest touches it, like the one of a new VM.
+    if let Err(e) = admit_vm(state, job_id).await {
+        warn!("Job {} – no suspended VM resumed: {}", job_id, e);
+        for evicted in state.suspended_vms.put(vm_key, suspended) {
+            state
+                .events
+                .vm(EventKind::VmDestroyed, &evicted.vm_id, None);
+        }
+        return None;
+    }
+    let vm_id = suspended.vm_id.clone();
+    // The VM as it was pooled: of its own shape, with no room to hotplug.
+    let vm_config = VmConfig {
+        vcpus: vm_key.vcpus,
+        memory_mb: vm_key.memory_mb,
+        max_vcpus: 0,
+        max_memory_mb: 0,
+        ..vm_config.clone()
+    };
+    let started = std::time::Instant::now();
+    match VmHandle::resume(suspended, language, &vm_config).await {
+        Ok(vm) => {
+            info!(
+                "Job {} – resumed suspended VM {} in {} ms",
+                job_id,
+                vm_id,
+                started.elapsed().as_millis()
+            );
+            Some((vm_key, vm))
+        }
+        Err(e) => {
+            warn!(
+                "Job {} – cannot resume suspended VM {}: {}",
+                job_id, vm_id, e
+            );
+            state.events.vm(EventKind::VmDestroyed, &vm_id, None);
+            None
+        }
+    }
+}
+
+/// Shut `vm` down, and tell subscribers it is gone. `job_id` is the job it ran
+/// last, unless it sat idle in its pool.
+pub(crate) async fn shut_down_vm(state: &AppState, mut vm: VmHandle, job_id: Option<&str>) {
+    vm.shutdown(SHUTDOWN_GRACE).await;
+    state.events.vm(EventKind::VmDestroyed, &vm.vm_id, job_id);
+}
+
+/// Traffic of a VM since `start`, an earlier reading of `meter`.
+pub(crate) fn traffic_since((meter, start): &(TrafficMeter, TrafficStats)) -> Option<TrafficStats> {
+    Some(meter.read().ok()?.since(start))
+}
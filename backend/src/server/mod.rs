@@ -0,0 +1,165 @@
+//! Handlers of the API and the orchestration of the jobs they submit.
+//!
+//! `main` sets up the state and the router; each module here serves a part of
+//! the API, and `jobs` runs the code of the jobs in VMs or host processes.
+
+mod admin;
+mod agent;
+mod artifacts;
+#[cfg(feature = "chaos")]
+pub(crate) mod chaos;
+mod dashboards;
+mod executions;
+mod functions;
+mod http_functions;
+mod jobs;
+mod pipeline_runs;
+mod probes;
+mod vms;
+
+pub(crate) use admin::*;
+pub(crate) use agent::*;
+pub(crate) use artifacts::*;
+pub(crate) use dashboards::*;
+pub(crate) use executions::*;
+pub(crate) use functions::*;
+pub(crate) use http_functions::*;
+pub(crate) use jobs::*;
+pub(crate) use pipeline_runs::*;
+pub(crate) use probes::*;
+pub(crate) use vms::*;
+
+use super::*;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+
+/// Structured 422 response listing every invalid field.
+pub(crate) fn validation_error_response(errors: ValidationErrors) -> axum::response::Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(ErrorResponse {
+            error: errors.to_string(),
+            details: errors.errors,
+        }),
+    )
+        .into_response()
+}
+
+/// First bytes of a gzip stream, which no source file starts with.
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Read the code of a job from the artifact store: a source file, or a
+/// directory bundle when `entrypoint` names the file to run in it.
+/// On failure, returns a short reason for the audit trail and the response to send.
+pub(crate) async fn load_artifact_code(
+    state: &AppState,
+    artifact_id: &str,
+    entrypoint: Option<&str>,
+) -> Result<JobCode, (String, axum::response::Response)> {
+    let mut errors = ValidationErrors::default();
+    match state.artifact_store.get(artifact_id).await {
+        Ok(Some(data)) if data.starts_with(&GZIP_MAGIC) => match entrypoint {
+            Some(entrypoint) => {
+                return Ok(JobCode::Bundle {
+                    archive: data,
+                    entrypoint: entrypoint.to_string(),
+                });
+            }
+            None => errors.push(
+                "artifact",
+                "is a directory bundle, set `entrypoint` to the file to run",
+            ),
+        },
+        Ok(Some(_)) if entrypoint.is_some() => {
+            errors.push("entrypoint", "only applies to directory bundles")
+        }
+        Ok(Some(data)) => match String::from_utf8(data) {
+            Ok(code) if !code.trim().is_empty() => return Ok(JobCode::Source(code)),
+            Ok(_) => errors.push("artifact", "Code cannot be empty"),
+            Err(_) => errors.push("artifact", "must contain UTF-8 source code"),
+        },
+        Ok(None) => errors.push("artifact", format!("Artifact {artifact_id} not found")),
+        Err(e) => {
+            error!("Failed to read artifact {}: {}", artifact_id, e);
+            return Err((e.to_string(), artifact_error_response(e)));
+        }
+    }
+    Err((errors.to_string(), validation_error_response(errors)))
+}
+
+/// Who made a request: the tenant of its API key when keys are checked, else
+/// the one of its `X-Cloude-User` header.
+#[derive(Clone, Debug)]
+pub(crate) struct Actor {
+    /// The tenant, or `anonymous`.
+    pub(crate) name: String,
+    /// `None` for anonymous callers.
+    pub(crate) tenant: Option<String>,
+    pub(crate) key_id: Option<String>,
+}
+
+impl Actor {
+    /// Who made a request with `caller`'s key, if any, and `headers`.
+    pub(crate) fn new(state: &AppState, caller: Option<&Caller>, headers: &HeaderMap) -> Self {
+        let tenant = match caller {
+            Some(caller) => caller.tenant(headers),
+            // Routes open to requests without a key, like `/f/{name}`, cannot
+            // trust a header when keys are checked.
+            None if state.api_keys.enforced() => None,
+            None => access::header_tenant(headers).map(str::to_string),
+        };
+        Self {
+            name: tenant.clone().unwrap_or_else(|| "anonymous".to_string()),
+            tenant,
+            key_id: caller.map(|caller| caller.key_id.clone()),
+        }
+    }
+
+    /// An audit entry of what the actor did from `source_ip`.
+    pub(crate) fn audit(&self, source_ip: &str, action: &str, outcome: AuditOutcome) -> AuditEntry {
+        let entry = AuditEntry::new(&self.name, source_ip, action, outcome);
+        match &self.key_id {
+            Some(key_id) => entry.with_key_id(key_id.clone()),
+            None => entry,
+        }
+    }
+}
+
+impl FromRequestParts<Arc<AppState>> for Actor {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::new(
+            state,
+            parts.extensions.get::<Caller>(),
+            &parts.headers,
+        ))
+    }
+}
+
+/// Append a record to the audit trail. Failures are logged but never fail the request.
+pub(crate) fn record_audit(state: &AppState, entry: AuditEntry) {
+    state.audit_writer.send(entry);
+}
+
+pub(crate) fn job_not_found(id: &str) -> axum::response::Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse::new(format!("Job {id} not found"))),
+    )
+        .into_response()
+}
+
+/// Whether job `id` is one of `namespace`. Jobs of other namespaces are
+/// answered as if they did not exist.
+pub(crate) async fn job_in(state: &AppState, namespace: &Namespace, id: &str) -> bool {
+    state
+        .jobs
+        .read()
+        .await
+        .get(id)
+        .is_some_and(|job| &job.namespace == namespace)
+}
@@ -0,0 +1,277 @@
+//! `POST /pipelines`: functions run as a DAG of stages.
+
+use super::*;
+
+// ── POST /pipelines  –  run functions as a DAG ──────────────────────
+
+pub(crate) async fn run_pipeline(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    namespace: Namespace,
+    payload: Result<Json<PipelineRequest>, JsonRejection>,
+) -> axum::response::Response {
+    let source_ip = peer.source_ip();
+    let rejected = |errors: &ValidationErrors| {
+        actor
+            .audit(&source_ip, "pipeline.submit", AuditOutcome::Rejected)
+            .with_detail(errors.to_string())
+    };
+
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(rejection) => {
+            let mut errors = ValidationErrors::default();
+            errors.push("body", rejection.body_text());
+            record_audit(&state, rejected(&errors));
+            return validation_error_response(errors);
+        }
+    };
+
+    // Every function is looked up before any stage starts.
+    let mut errors = pipelines::validate(&request);
+    let mut functions = Vec::with_capacity(request.stages.len());
+    if errors.is_empty() {
+        for (i, stage) in request.stages.iter().enumerate() {
+            match state.functions.get(&namespace, &stage.function) {
+                Ok(Some(function)) => functions.push(function),
+                Ok(None) => errors.push(
+                    &format!("stages[{i}].function"),
+                    format!("no function named {}", stage.function),
+                ),
+                Err(e) => return function_registry_error(e),
+            }
+        }
+    }
+    if !errors.is_empty() {
+        record_audit(&state, rejected(&errors));
+        return validation_error_response(errors);
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let status = pipelines::pending(&id, &request);
+    state.pipelines.update(&namespace, &status);
+    record_audit(
+        &state,
+        actor
+            .audit(&source_ip, "pipeline.submit", AuditOutcome::Accepted)
+            .with_target(id.clone())
+            .with_detail(format!("stages={}", request.stages.len())),
+    );
+    info!(
+        "Pipeline {} submitted – {} stages",
+        id,
+        request.stages.len()
+    );
+    tokio::spawn(run_stages(
+        Arc::clone(&state),
+        actor,
+        source_ip,
+        namespace,
+        request,
+        functions,
+        status,
+    ));
+
+    (StatusCode::ACCEPTED, Json(PipelineResponse { id })).into_response()
+}
+
+/// Starts the stages of a pipeline as the stages they come after are done, until
+/// none is left to run. `functions` are those of the stages, in their order.
+pub(crate) async fn run_stages(
+    state: Arc<AppState>,
+    actor: Actor,
+    source_ip: String,
+    namespace: Namespace,
+    request: PipelineRequest,
+    functions: Vec<FunctionInfo>,
+    mut status: PipelineStatus,
+) {
+    let id = status.id.clone();
+    let mut running = tokio::task::JoinSet::new();
+    loop {
+        for i in pipelines::ready(&request, &status) {
+            let stage = &request.stages[i];
+            let stdin = match state.pipelines.input(&id, &request, i).await {
+                Ok(stdin) => stdin,
+                Err(e) => {
+                    status.stages[i].status = StageStatus::Error;
+                    status.stages[i].error = Some(format!("Cannot read the input: {e}"));
+                    continue;
+                }
+            };
+            let options = JobOptions {
+                namespace: namespace.clone(),
+                stdin,
+                ..JobOptions::default()
+            };
+            // Subscribed before the job starts, so that its end is not missed.
+            let mut events = state.events.subscribe();
+            match start_function(&state, &functions[i], options).await {
+                Ok(job_id) => {
+                    record_audit(
+                        &state,
+                        actor
+                            .audit(&source_ip, "job.submit", AuditOutcome::Accepted)
+                            .with_target(job_id.clone())
+                            .with_detail(format!(
+                                "function={} version={} pipeline={} stage={}",
+                                stage.function, functions[i].version, id, stage.name
+                            )),
+                    );
+                    status.stages[i].status = StageStatus::Running;
+                    status.stages[i].job_id = Some(job_id.clone());
+                    let state = Arc::clone(&state);
+                    running.spawn(async move {
+                        let job = wait_for_job(&state, &job_id, &mut events).await;
+                        (i, job)
+                    });
+                }
+                Err((detail, _)) => {
+                    record_audit(
+                        &state,
+                        actor
+                            .audit(&source_ip, "job.submit", AuditOutcome::Rejected)
+                            .with_detail(format!(
+                                "function={} pipeline={} stage={} {}",
+                                stage.function, id, stage.name, detail
+                            )),
+                    );
+                    status.stages[i].status = StageStatus::Error;
+                    status.stages[i].error = Some(detail);
+                }
+            }
+        }
+        pipelines::skip_dependents(&request, &mut status);
+        pipelines::settle(&mut status);
+        state.pipelines.update(&namespace, &status);
+
+        let (i, job) = match running.join_next().await {
+            Some(Ok(ended)) => ended,
+            Some(Err(e)) => {
+                error!("A stage of pipeline {} panicked: {}", id, e);
+                continue;
+            }
+            None => break,
+        };
+        let stage = &mut status.stages[i];
+        match job {
+            Some(job) if job.exit_code == Some(0) => {
+                let output = job.stdout.unwrap_or_default().into_bytes();
+                let len = output.len() as u64;
+                stage.exit_code = job.exit_code;
+                match state.pipelines.put_output(&id, &stage.name, output).await {
+                    Ok(()) => {
+                        stage.status = StageStatus::Done;
+                        stage.output_bytes = Some(len);
+                    }
+                    Err(e) => {
+                        stage.status = StageStatus::Error;
+                        stage.error = Some(format!("Cannot keep the output: {e}"));
+                    }
+                }
+            }
+            Some(job) => {
+                stage.status = StageStatus::Error;
+                stage.exit_code = job.exit_code;
+            }
+            None => {
+                stage.status = StageStatus::Error;
+                stage.error = Some("The job was evicted before it ended".to_string());
+            }
+        }
+    }
+    info!("Pipeline {} ended – status={}", id, status.status);
+}
+
+// GET /pipelines/{id}  –  status of a pipeline and of its stages
+pub(crate) async fn get_pipeline(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    Path(id): Path<String>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    match state.pipelines.get(&namespace, &id) {
+        Some(status) => (StatusCode::OK, Json(status)).into_response(),
+        None => pipeline_not_found(&id),
+    }
+}
+
+// GET /pipelines/{id}/stages/{stage}/output  –  output a stage passed on
+pub(crate) async fn get_stage_output(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    Path((id, stage)): Path<(String, String)>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    validate_identifier(&mut errors, "stage", &stage);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    let Some(status) = state.pipelines.get(&namespace, &id) else {
+        return pipeline_not_found(&id);
+    };
+    let Some(result) = status.stages.iter().find(|s| s.name == stage) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new(format!(
+                "Pipeline {id} has no stage {stage}"
+            ))),
+        )
+            .into_response();
+    };
+    if result.status != StageStatus::Done {
+        return (
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::new(format!(
+                "Stage {stage} of pipeline {id} is {}",
+                result.status
+            ))),
+        )
+            .into_response();
+    }
+
+    match state.pipelines.output(&id, &stage).await {
+        Ok(Some(output)) => (
+            StatusCode::OK,
+            [(axum::http::header::CONTENT_TYPE, "application/octet-stream")],
+            output,
+        )
+            .into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new(format!(
+                "Output of stage {stage} of pipeline {id} is gone"
+            ))),
+        )
+            .into_response(),
+        Err(e) => {
+            error!(
+                "Cannot read the output of stage {} of pipeline {}: {}",
+                stage, id, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse::new(format!(
+                    "Failed to read the output: {e}"
+                ))),
+            )
+                .into_response()
+        }
+    }
+}
+
+pub(crate) fn pipeline_not_found(id: &str) -> axum::response::Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse::new(format!("Pipeline {id} not found"))),
+    )
+        .into_response()
+}
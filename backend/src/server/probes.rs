@@ -0,0 +1,98 @@
+//! Probes of the server: liveness, readiness and cleanup of the host.
+
+use super::*;
+
+pub(crate) async fn root() -> &'static str {
+    "Welcome to the Backend server!"
+}
+
+/// The process is up and serving requests. Dependencies are checked by `/readyz`.
+pub(crate) async fn livez() -> impl IntoResponse {
+    (StatusCode::OK, Json(serde_json::json!({ "status": "ok" })))
+}
+
+/// Looks for what VMs and jobs left behind on the host, and cleans up what the
+/// previous sweep found too. Without VMs, there are no TAP devices or IP leases.
+/// Artifact uploads are removed once they received no chunk for `upload_ttl`.
+/// Serial logs of VMs are kept for `log_retention`, forever if `None`.
+pub(crate) async fn sweep_host(
+    state: &AppState,
+    run_vms: bool,
+    scratch_dirs: &[PathBuf],
+    stale_after: std::time::Duration,
+    upload_ttl: std::time::Duration,
+    log_retention: Option<std::time::Duration>,
+) {
+    let mut found = HashSet::new();
+    if run_vms {
+        found.extend(find_network_orphans(&state.ip_manager).await);
+    }
+
+    let mut finished = HashSet::new();
+    let mut running = HashSet::new();
+    for job in state.jobs.read().await.values() {
+        if job.status.is_terminal() {
+            finished.insert(job.id.clone());
+        } else {
+            running.insert(job.id.clone());
+        }
+    }
+
+    // A VM is named after the job it was created for. One still up once that job
+    // is over is stray, unless it runs another job or waits for one in its pool,
+    // running or suspended.
+    let in_use: HashSet<String> = state
+        .heartbeats
+        .list()
+        .into_iter()
+        .map(|vm| vm.vm_id)
+        .chain(state.vm_pool.map_idle(|vm| vm.vm_id.clone()))
+        .chain(state.suspended_vms.map_idle(|vm| vm.vm_id.clone()))
+        .collect();
+    found.extend(
+        live_vm_ids()
+            .into_iter()
+            .filter(|vm_id| finished.contains(vm_id) && !in_use.contains(vm_id))
+            .map(Orphan::Vm),
+    );
+
+    for dir in scratch_dirs {
+        found.extend(find_stale_dirs(dir, stale_after, &running).await);
+    }
+    if let (Some(serial_log), Some(retention)) = (&state.vm_config.serial_log, log_retention) {
+        found.extend(find_stale_logs(&serial_log.dir, retention).await);
+    }
+    state.janitor.sweep(found, &state.ip_manager).await;
+
+    match state.artifact_uploads.expire(upload_ttl).await {
+        Ok(expired) => {
+            for upload_id in expired {
+                info!("Janitor removed abandoned upload {}", upload_id);
+            }
+        }
+        Err(e) => warn!("Janitor cannot expire artifact uploads: {}", e),
+    }
+}
+
+/// Whether jobs run in host processes rather than VMs, see `EXECUTOR`.
+pub(crate) fn runs_processes(state: &AppState) -> bool {
+    #[cfg(feature = "process-executor")]
+    return state.process_executor.is_some();
+    #[cfg(not(feature = "process-executor"))]
+    {
+        let _ = state;
+        false
+    }
+}
+
+/// Checks every dependency needed to run jobs and reports each result,
+/// with `503` as long as one of them fails.
+pub(crate) async fn readyz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let report = state.readiness.run().await;
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
@@ -0,0 +1,698 @@
+//! VMs running jobs: their console, vCPUs, memory, traffic and serial log.
+
+use super::*;
+
+#[derive(Deserialize)]
+pub(crate) struct PcapQuery {
+    max_bytes: Option<u64>,
+    duration_secs: Option<u64>,
+    snaplen: Option<u32>,
+}
+
+// ── GET /console/:id  –  attach to the serial console of a job VM ───
+
+/// Upgrades to a WebSocket carrying the guest serial console: binary frames
+/// from the VM are console output, frames from the client are typed into it.
+/// Recent output is replayed first, and the socket is closed when the VM stops.
+pub(crate) async fn attach_console(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    namespace: Namespace,
+    Path(id): Path<String>,
+    ws: WebSocketUpgrade,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+    if !job_in(&state, &namespace, &id).await {
+        return job_not_found(&id);
+    }
+
+    let console = state.consoles.read().await.get(&id).cloned();
+    let Some(console) = console else {
+        let (status, error) = if state.jobs.read().await.contains_key(&id) {
+            (StatusCode::CONFLICT, format!("Job {id} has no running VM"))
+        } else {
+            (StatusCode::NOT_FOUND, format!("Job {id} not found"))
+        };
+        return (status, Json(ErrorResponse::new(error))).into_response();
+    };
+
+    record_audit(
+        &state,
+        actor
+            .audit(&peer.source_ip(), "vm.console", AuditOutcome::Accepted)
+            .with_target(id.clone()),
+    );
+    info!("Console attached to job {} from {}", id, peer);
+    let redactor = state.config.current().redactor.clone();
+    ws.on_upgrade(move |socket| bridge_console(socket, console, redactor))
+}
+
+/// How long console output stays quiet before a partial line, such as a prompt,
+/// is redacted and sent without waiting for its end.
+pub(crate) const CONSOLE_REDACTION_IDLE: std::time::Duration = std::time::Duration::from_millis(50);
+
+pub(crate) async fn bridge_console(
+    mut socket: WebSocket,
+    console: Arc<SerialConsole>,
+    redactor: Redactor,
+) {
+    let Some((scrollback, mut output)) = console.attach() else {
+        let _ = socket.send(Message::Close(None)).await;
+        return;
+    };
+    // Without redaction, output is sent as it comes; with it, a line at a time.
+    let mut redaction = (!redactor.is_empty()).then(|| RedactingStream::new(redactor));
+    let scrollback = match &mut redaction {
+        Some(stream) => stream.push(&scrollback),
+        None => scrollback,
+    };
+    if !scrollback.is_empty()
+        && socket
+            .send(Message::Binary(scrollback.into()))
+            .await
+            .is_err()
+    {
+        return;
+    }
+
+    loop {
+        let idle = tokio::time::sleep(CONSOLE_REDACTION_IDLE);
+        tokio::select! {
+            _ = idle, if redaction.as_ref().is_some_and(RedactingStream::has_partial) => {
+                let partial = redaction.as_mut().map(RedactingStream::take_partial).unwrap_or_default();
+                if socket.send(Message::Binary(partial.into())).await.is_err() {
+                    break;
+                }
+            }
+            chunk = output.recv() => match chunk {
+                Ok(chunk) => {
+                    let chunk = match &mut redaction {
+                        Some(stream) => stream.push(&chunk),
+                        None => chunk,
+                    };
+                    if !chunk.is_empty() && socket.send(Message::Binary(chunk.into())).await.is_err() {
+                        break;
+                    }
+                }
+                // A slow client misses some output rather than slowing down the guest.
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                    if let Some(partial) = redaction
+                        .as_mut()
+                        .map(RedactingStream::take_partial)
+                        .filter(|partial| !partial.is_empty())
+                    {
+                        let _ = socket.send(Message::Binary(partial.into())).await;
+                    }
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Binary(data))) => {
+                    if let Err(e) = console.send_input(&data) {
+                        info!("Dropped console input: {}", e);
+                    }
+                }
+                Some(Ok(Message::Text(text))) => {
+                    if let Err(e) = console.send_input(text.as_bytes()) {
+                        info!("Dropped console input: {}", e);
+                    }
+                }
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+            },
+        }
+    }
+}
+
+// ── PUT /vcpus/:id  –  vCPUs of a running VM ────────────────────────
+
+/// Grow the VM of a running job to `vcpus` vCPUs. vCPUs cannot be removed.
+pub(crate) async fn resize_vcpus(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    namespace: Namespace,
+    Path(id): Path<String>,
+    Json(request): Json<ResizeRequest>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+    if !job_in(&state, &namespace, &id).await {
+        return job_not_found(&id);
+    }
+
+    let hotplug = state.vcpu_hotplugs.read().await.get(&id).cloned();
+    let Some(hotplug) = hotplug else {
+        let (status, error) = if state.consoles.read().await.contains_key(&id) {
+            (
+                StatusCode::CONFLICT,
+                format!("The VM of job {id} cannot get more vCPUs (see VM_MAX_VCPUS)"),
+            )
+        } else if state.jobs.read().await.contains_key(&id) {
+            (StatusCode::CONFLICT, format!("Job {id} has no running VM"))
+        } else {
+            (StatusCode::NOT_FOUND, format!("Job {id} not found"))
+        };
+        return (status, Json(ErrorResponse::new(error))).into_response();
+    };
+
+    let vcpu_count = hotplug.vcpu_count();
+    if request.vcpus < vcpu_count {
+        errors.push(
+            "vcpus",
+            format!("cannot go below the {vcpu_count} vCPUs the VM has"),
+        );
+    } else if request.vcpus > hotplug.max_vcpus() {
+        errors.push("vcpus", format!("must be at most {}", hotplug.max_vcpus()));
+    }
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    record_audit(
+        &state,
+        actor
+            .audit(&peer.source_ip(), "vm.resize", AuditOutcome::Accepted)
+            .with_target(id.clone()),
+    );
+
+    let mut result = Ok(());
+    while result.is_ok() && hotplug.vcpu_count() < request.vcpus {
+        result = hotplug.add_vcpu().map(|_| ());
+    }
+
+    let response = ResizeResponse {
+        vcpus: hotplug.vcpu_count(),
+        max_vcpus: hotplug.max_vcpus(),
+    };
+    match result {
+        Ok(()) => {
+            info!("Job {} VM resized to {} vCPUs", id, response.vcpus);
+            (StatusCode::OK, Json(response)).into_response()
+        }
+        Err(e) => {
+            error!("Job {} – failed to add a vCPU: {:?}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse::new(format!(
+                    "Failed to add a vCPU, the VM has {}: {:?}",
+                    response.vcpus, e
+                ))),
+            )
+                .into_response()
+        }
+    }
+}
+
+// ── GET /vms  –  VMs running jobs, with their liveness ──────────────
+
+pub(crate) const VMS: Listing = Listing {
+    filters: &[
+        Filter::Status,
+        Filter::Runtime,
+        Filter::Tenant,
+        Filter::CreatedAt,
+    ],
+    sorts: &[
+        SortField::CreatedAt,
+        SortField::Id,
+        SortField::Status,
+        SortField::Runtime,
+    ],
+    default_sort: SortField::Id,
+    default_order: Order::Asc,
+};
+
+/// Lists the VMs running jobs of the namespace. Their status is their liveness,
+/// their runtime, tenant and creation time those of their job.
+pub(crate) async fn list_vms(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    query: ListQuery,
+) -> axum::response::Response {
+    let vms = state.heartbeats.list();
+    let entries = {
+        let jobs = state.jobs.read().await;
+        vms.into_iter()
+            .filter_map(|vm| {
+                let job = jobs.get(&vm.id).filter(|job| job.namespace == namespace)?;
+                Some(Entry {
+                    id: vm.id.clone(),
+                    status: serde_json::to_value(vm.liveness)
+                        .ok()
+                        .and_then(|v| v.as_str().map(str::to_string)),
+                    runtime: Some(job.language.clone()),
+                    tenant: job.tenant.clone(),
+                    created_at: Some(submitted_at(job)),
+                    item: vm,
+                })
+            })
+            .collect()
+    };
+    match listing::page(entries, &query, &VMS) {
+        Ok(page) => page.into_response(),
+        Err(errors) => validation_error_response(errors),
+    }
+}
+
+// ── POST /channels  –  byte stream between the VMs of two jobs ──────
+
+/// Opens a channel between the VMs of running jobs `from_vm` and `to_vm` of the
+/// tenant of the caller: the host relays what the code of either job writes to
+/// the socket `path` of its guest to the socket of the other. The channel closes
+/// when the code at either end does, or with either job.
+pub(crate) async fn open_channel(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    namespace: Namespace,
+    Json(request): Json<ChannelRequest>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "from_vm", &request.from_vm);
+    validate_identifier(&mut errors, "to_vm", &request.to_vm);
+    if request.from_vm == request.to_vm {
+        errors.push("to_vm", "must be another VM than from_vm");
+    }
+    // Channels only join VMs of one tenant, so the caller must say who it is.
+    if actor.tenant.is_none() {
+        errors.push("tenant", "channels need a tenant, set X-Cloude-User");
+    }
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    let mut agent_urls = Vec::new();
+    for id in [&request.from_vm, &request.to_vm] {
+        let tenant = state
+            .jobs
+            .read()
+            .await
+            .get(id)
+            .filter(|job| job.namespace == namespace)
+            .map(|job| job.tenant.clone());
+        let Some(tenant) = tenant else {
+            return job_not_found(id);
+        };
+        if tenant != actor.tenant {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(ErrorResponse::new(format!(
+                    "Job {id} runs for another tenant"
+                ))),
+            )
+                .into_response();
+        }
+        let vm = state
+            .heartbeats
+            .list()
+            .into_iter()
+            .find(|vm| &vm.id == id && vm.liveness == VmLiveness::Running);
+        let Some(vm) = vm else {
+            return (
+                StatusCode::CONFLICT,
+                Json(ErrorResponse::new(format!("Job {id} has no running VM"))),
+            )
+                .into_response();
+        };
+        agent_urls.push(format!("http://{}:3001", vm.ip));
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let (from, to) = tokio::join!(
+        open_agent_channel(&state.client, &agent_urls[0], &id),
+        open_agent_channel(&state.client, &agent_urls[1], &id),
+    );
+    // An end opened alone closes as it is dropped.
+    let (mut from, mut to) = match (from, to) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("Cannot open channel {}: {}", id, e);
+            return (
+                StatusCode::BAD_GATEWAY,
+                Json(ErrorResponse::new(format!("Cannot open the channel: {e}"))),
+            )
+                .into_response();
+        }
+    };
+
+    record_audit(
+        &state,
+        actor
+            .audit(&peer.source_ip(), "vm.channel", AuditOutcome::Accepted)
+            .with_target(format!("{} {}", request.from_vm, request.to_vm)),
+    );
+    info!(
+        "Channel {} opened between jobs {} and {}",
+        id, request.from_vm, request.to_vm
+    );
+    let channel_id = id.clone();
+    tokio::spawn(async move {
+        match tokio::io::copy_bidirectional(&mut from, &mut to).await {
+            Ok((forward, back)) => info!(
+                "Channel {} closed after relaying {} bytes forward and {} back",
+                channel_id, forward, back
+            ),
+            Err(e) => warn!("Channel {} closed: {}", channel_id, e),
+        }
+    });
+
+    let path = format!("{}/{}", CHANNEL_DIR, id);
+    (
+        StatusCode::CREATED,
+        Json(ChannelResponse {
+            id,
+            from_vm: request.from_vm,
+            to_vm: request.to_vm,
+            path,
+        }),
+    )
+        .into_response()
+}
+
+// ── PATCH /vms/:id  –  memory of a running VM ───────────────────────
+
+/// Grow or shrink the memory of the VM of a running job to `memory_mb` MiB.
+///
+/// Answers once the guest is asked to; it plugs or unplugs the memory in the
+/// background, `plugged_memory_mb` tells how far it got.
+pub(crate) async fn update_vm(
+    State(state): State<Arc<AppState>>,
+    actor: Actor,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    namespace: Namespace,
+    Path(id): Path<String>,
+    Json(request): Json<UpdateVmRequest>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+    if !job_in(&state, &namespace, &id).await {
+        return job_not_found(&id);
+    }
+
+    let resize = state.memory_resizes.read().await.get(&id).cloned();
+    let Some(resize) = resize else {
+        let (status, error) = if state.consoles.read().await.contains_key(&id) {
+            (
+                StatusCode::CONFLICT,
+                format!("The VM of job {id} cannot change memory size (see VM_MAX_MEMORY_MB)"),
+            )
+        } else if state.jobs.read().await.contains_key(&id) {
+            (StatusCode::CONFLICT, format!("Job {id} has no running VM"))
+        } else {
+            (StatusCode::NOT_FOUND, format!("Job {id} not found"))
+        };
+        return (status, Json(ErrorResponse::new(error))).into_response();
+    };
+
+    if request.memory_mb < resize.boot_mib() {
+        errors.push(
+            "memory_mb",
+            format!(
+                "cannot go below the {} MiB the VM boots with",
+                resize.boot_mib()
+            ),
+        );
+    } else if request.memory_mb > resize.max_mib() {
+        errors.push("memory_mb", format!("must be at most {}", resize.max_mib()));
+    }
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    record_audit(
+        &state,
+        actor
+            .audit(&peer.source_ip(), "vm.update", AuditOutcome::Accepted)
+            .with_target(id.clone()),
+    );
+
+    if let Err(e) = resize.resize(request.memory_mb) {
+        error!("Job {} – failed to resize memory: {:?}", id, e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse::new(format!(
+                "Failed to resize memory: {:?}",
+                e
+            ))),
+        )
+            .into_response();
+    }
+
+    info!(
+        "Job {} VM asked for {} MiB of memory",
+        id,
+        resize.requested_mib()
+    );
+    let response = UpdateVmResponse {
+        memory_mb: resize.requested_mib(),
+        plugged_memory_mb: resize.plugged_mib(),
+        max_memory_mb: resize.max_mib(),
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+// ── GET /vms/:id/pcap  –  packet capture of the VM of a job ─────────
+
+pub(crate) const DEFAULT_PCAP_MAX_BYTES: u64 = 64 * 1024 * 1024;
+pub(crate) const DEFAULT_PCAP_MAX_DURATION_SECS: u64 = 300;
+/// How often a capture with no traffic checks whether its caller is gone.
+pub(crate) const PCAP_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// TAP device of the VM of a running job. Dropping it ends the captures of its
+/// traffic.
+pub(crate) struct JobTap {
+    pub(crate) device: String,
+    pub(crate) running: tokio::sync::watch::Sender<()>,
+}
+
+/// Streams the frames the VM of running job `id` sends and receives as a pcap
+/// file, until `max_bytes` are sent, `duration_secs` pass or the job finishes.
+/// Both default to, and cannot go past, `PCAP_MAX_BYTES` and `PCAP_MAX_DURATION_SECS`.
+pub(crate) async fn capture_vm_packets(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    Path(id): Path<String>,
+    Query(query): Query<PcapQuery>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    let max_bytes = query.max_bytes.unwrap_or(state.pcap_max_bytes);
+    if max_bytes < virt::packet_capture::PCAP_HEADER_LEN as u64 {
+        errors.push(
+            "max_bytes",
+            format!("must be at least {}", virt::packet_capture::PCAP_HEADER_LEN),
+        );
+    } else if max_bytes > state.pcap_max_bytes {
+        errors.push(
+            "max_bytes",
+            format!("must be at most {}", state.pcap_max_bytes),
+        );
+    }
+    let duration = query
+        .duration_secs
+        .map(std::time::Duration::from_secs)
+        .unwrap_or(state.pcap_max_duration);
+    if duration.is_zero() {
+        errors.push("duration_secs", "must be positive");
+    } else if duration > state.pcap_max_duration {
+        errors.push(
+            "duration_secs",
+            format!("must be at most {}", state.pcap_max_duration.as_secs()),
+        );
+    }
+    let snaplen = query.snaplen.unwrap_or(virt::packet_capture::MAX_SNAPLEN);
+    if !(1..=virt::packet_capture::MAX_SNAPLEN).contains(&snaplen) {
+        errors.push(
+            "snaplen",
+            format!(
+                "must be between 1 and {}",
+                virt::packet_capture::MAX_SNAPLEN
+            ),
+        );
+    }
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+    if !job_in(&state, &namespace, &id).await {
+        return job_not_found(&id);
+    }
+
+    let tap = state
+        .taps
+        .read()
+        .await
+        .get(&id)
+        .map(|tap| (tap.device.clone(), tap.running.subscribe()));
+    let Some((device, running)) = tap else {
+        let (status, error) = if state.jobs.read().await.contains_key(&id) {
+            (StatusCode::CONFLICT, format!("Job {id} has no running VM"))
+        } else {
+            (StatusCode::NOT_FOUND, format!("Job {id} not found"))
+        };
+        return (status, Json(ErrorResponse::new(error))).into_response();
+    };
+    let capture = match virt::packet_capture::PacketCapture::open(&device, snaplen) {
+        Ok(capture) => capture,
+        Err(e) => {
+            error!("Cannot capture the traffic of {}: {}", device, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse::new(format!(
+                    "Cannot capture the traffic of the VM of job {id}"
+                ))),
+            )
+                .into_response();
+        }
+    };
+    info!(
+        "Capturing the traffic of the VM of job {} ({} bytes, {}s at most)",
+        id,
+        max_bytes,
+        duration.as_secs()
+    );
+
+    let (tx, rx) = tokio::sync::mpsc::channel(64);
+    let deadline = std::time::Instant::now() + duration;
+    tokio::task::spawn_blocking(move || capture_packets(capture, max_bytes, deadline, tx));
+    let body = futures_util::stream::unfold((rx, running), |(mut rx, mut running)| async move {
+        tokio::select! {
+            biased;
+            // The job finished: what its VM carries next is not its traffic.
+            _ = running.changed() => None,
+            record = rx.recv() => {
+                record.map(|record| (Ok::<_, std::convert::Infallible>(record), (rx, running)))
+            }
+        }
+    });
+
+    (
+        StatusCode::OK,
+        [
+            (
+                axum::http::header::CONTENT_TYPE,
+                "application/vnd.tcpdump.pcap".to_string(),
+            ),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{id}.pcap\""),
+            ),
+        ],
+        axum::body::Body::from_stream(body),
+    )
+        .into_response()
+}
+
+/// Sends the pcap header then a record per frame of `capture` to `tx`, until
+/// `max_bytes` are sent, `deadline` passes, the TAP goes away or `tx` is closed.
+pub(crate) fn capture_packets(
+    mut capture: virt::packet_capture::PacketCapture,
+    max_bytes: u64,
+    deadline: std::time::Instant,
+    tx: tokio::sync::mpsc::Sender<Vec<u8>>,
+) {
+    let header = capture.header().to_vec();
+    let mut sent = header.len() as u64;
+    if tx.blocking_send(header).is_err() {
+        return;
+    }
+    loop {
+        let Some(left) = deadline.checked_duration_since(std::time::Instant::now()) else {
+            return;
+        };
+        let record = match capture.next_record(left.min(PCAP_POLL_INTERVAL)) {
+            Ok(Some(record)) => record,
+            Ok(None) if tx.is_closed() => return,
+            Ok(None) => continue,
+            // The TAP is gone with its VM.
+            Err(e) if e.raw_os_error() == Some(libc::ENETDOWN) => return,
+            Err(e) => {
+                warn!("Packet capture failed: {}", e);
+                return;
+            }
+        };
+        sent += record.len() as u64;
+        // A file cut in the middle of a record would not read.
+        if sent > max_bytes || tx.blocking_send(record).is_err() {
+            return;
+        }
+    }
+}
+
+// ── GET /logs/:id/vm  –  serial log of the VM of a job ──────────────
+
+/// Returns the current serial log of the VM that ran a job, as plain text.
+/// Rotated parts are left on disk, next to it.
+pub(crate) async fn get_vm_log(
+    State(state): State<Arc<AppState>>,
+    namespace: Namespace,
+    Path(id): Path<String>,
+) -> axum::response::Response {
+    let mut errors = ValidationErrors::default();
+    validate_identifier(&mut errors, "id", &id);
+    if !errors.is_empty() {
+        return validation_error_response(errors);
+    }
+
+    let Some(serial_log) = &state.vm_config.serial_log else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new("VM logs are disabled")),
+        )
+            .into_response();
+    };
+    let vm_id = match state
+        .jobs
+        .read()
+        .await
+        .get(&id)
+        .filter(|job| job.namespace == namespace)
+    {
+        Some(job) => job.vm_id.clone(),
+        None => return job_not_found(&id),
+    };
+    let Some(vm_id) = vm_id else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new(format!("Job {id} ran in no VM"))),
+        )
+            .into_response();
+    };
+
+    match tokio::fs::read(serial_log.path(&vm_id)).await {
+        Ok(contents) => (
+            StatusCode::OK,
+            [(
+                axum::http::header::CONTENT_TYPE,
+                "text/plain; charset=utf-8",
+            )],
+            contents,
+        )
+            .into_response(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new(format!("No log for VM {vm_id}"))),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse::new(format!(
+                "Cannot read log of VM {vm_id}: {e}"
+            ))),
+        )
+            .into_response(),
+    }
+}
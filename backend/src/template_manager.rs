@@ -0,0 +1,743 @@
+use crate::artifact_store::sha256_file;
+use crate::blob_store::{BlobStore, BlobStoreError, SNAPSHOTS_PREFIX};
+use crate::initramfs_manager::InitramfsLanguage;
+use crate::ip_manager::IpManager;
+use crate::vm_lifecycle::{DEFAULT_MTU, TEMPLATE_GUEST_IP, VmConfig, VmError, VmHandle};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+/// Snapshot files stored for every template, as written by the VMM.
+const SNAPSHOT_FILES: [&str; 2] = [vmm::MEMORY_FILE, vmm::STATE_FILE];
+
+/// A VM snapshot taken once a runtime is booted and its interpreter is warm.
+/// New VMs for the same runtime can be cloned from it instead of booting from scratch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Template {
+    pub language: String,
+    pub version: String,
+    /// Digest of everything the snapshot was built from, see [`base_digest`].
+    pub base_digest: String,
+    /// Blob store key prefix under which the `memory` and `state` files live.
+    pub snapshot_key: String,
+    /// Shape of the VM the snapshot was taken from; clones must use the same.
+    #[serde(flatten)]
+    pub vm: cloude_types::VmConfig,
+    /// Total size of the snapshot files.
+    #[serde(default)]
+    pub size_bytes: u64,
+    /// Seconds since the Unix epoch at which the snapshot was taken.
+    pub created_at: u64,
+}
+
+impl Template {
+    /// Whether VMs of `config` can be restored from the template: they have its shape,
+    /// and no room to hotplug vCPUs or memory, which a snapshot cannot give back.
+    pub fn fits(&self, config: &VmConfig) -> bool {
+        self.vm.vcpus == config.vcpus
+            && self.vm.memory_mb == config.memory_mb
+            && config.max_vcpus <= config.vcpus
+            && config.max_memory_mb <= config.memory_mb
+    }
+
+    /// Blob store keys of the snapshot files.
+    pub fn snapshot_keys(&self) -> Vec<String> {
+        SNAPSHOT_FILES
+            .iter()
+            .map(|file| format!("{}{}", self.snapshot_key, file))
+            .collect()
+    }
+}
+
+/// Errors that can occur while baking or registering templates.
+#[derive(Debug)]
+pub enum TemplateError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Blob(BlobStoreError),
+    Vm(VmError),
+    Digest(String),
+    Warmup(String),
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::Io(e) => write!(f, "IO error: {}", e),
+            TemplateError::Json(e) => write!(f, "JSON error: {}", e),
+            TemplateError::Blob(e) => write!(f, "{}", e),
+            TemplateError::Vm(e) => write!(f, "{}", e),
+            TemplateError::Digest(e) => write!(f, "Failed to compute base digest: {}", e),
+            TemplateError::Warmup(e) => write!(f, "Warm-up execution failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl From<std::io::Error> for TemplateError {
+    fn from(err: std::io::Error) -> Self {
+        TemplateError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for TemplateError {
+    fn from(err: serde_json::Error) -> Self {
+        TemplateError::Json(err)
+    }
+}
+
+impl From<BlobStoreError> for TemplateError {
+    fn from(err: BlobStoreError) -> Self {
+        TemplateError::Blob(err)
+    }
+}
+
+impl From<VmError> for TemplateError {
+    fn from(err: VmError) -> Self {
+        TemplateError::Vm(err)
+    }
+}
+
+/// Digest identifying what a template was baked from: the kernel, the runtime
+/// initramfs, the VM shape and the warm-up snippet. Any change means the
+/// snapshot is stale and must be baked again.
+pub async fn base_digest(
+    kernel_path: &Path,
+    initramfs_path: &Path,
+    language: &InitramfsLanguage,
+    config: &VmConfig,
+) -> Result<String, TemplateError> {
+    let (kernel_sha, _) = sha256_file(kernel_path)
+        .await
+        .map_err(|e| TemplateError::Digest(format!("{}: {}", kernel_path.display(), e)))?;
+    let (initramfs_sha, _) = sha256_file(initramfs_path)
+        .await
+        .map_err(|e| TemplateError::Digest(format!("{}: {}", initramfs_path.display(), e)))?;
+
+    let mut hasher = Sha256::new();
+    for part in [
+        kernel_sha.as_str(),
+        initramfs_sha.as_str(),
+        &config.vcpus.to_string(),
+        &config.memory_mb.to_string(),
+        language.warmup_code.as_deref().unwrap_or_default(),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    // Templates are restored onto their link: those baked on a leased address cannot be.
+    hasher.update(TEMPLATE_GUEST_IP.to_string().as_bytes());
+    hasher.update([0]);
+    // Kernel parameters are part of the booted state, and templates baked without any stay valid.
+    if let Some(extra) = &config.cmdline_extra {
+        hasher.update(extra.as_bytes());
+        hasher.update([0]);
+    }
+    // So are the ACPI tables, same for templates baked before they existed.
+    if config.acpi {
+        hasher.update(b"acpi");
+        hasher.update([0]);
+    }
+    // Room for hotplugged vCPUs changes them too.
+    if config.max_vcpus > config.vcpus {
+        hasher.update(format!("max_vcpus={}", config.max_vcpus).as_bytes());
+        hasher.update([0]);
+    }
+    // And so does the region memory can grow into.
+    if config.max_memory_mb > config.memory_mb {
+        hasher.update(format!("max_memory_mb={}", config.max_memory_mb).as_bytes());
+        hasher.update([0]);
+    }
+    // The guest sizes its interface from the MTU at boot; templates baked at
+    // Ethernet's stay valid.
+    if let Some(mtu) = config.mtu.filter(|&mtu| mtu != DEFAULT_MTU) {
+        hasher.update(format!("mtu={}", mtu).as_bytes());
+        hasher.update([0]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Serializable state of the registry, mapped directly to the JSON file on disk.
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct TemplateRegistryState {
+    templates: HashMap<String, Template>, // language -> template
+}
+
+/// The current template of each runtime, persisted to a JSON file so templates
+/// survive restarts and are only baked again when their base digest changes.
+#[derive(Debug)]
+pub struct TemplateRegistry {
+    file_path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl TemplateRegistry {
+    pub fn new<P: AsRef<Path>>(file_path: P) -> Result<Self, TemplateError> {
+        let registry = Self {
+            file_path: file_path.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        };
+
+        if !registry.file_path.exists() {
+            registry.write_state(&TemplateRegistryState::default())?;
+        }
+
+        Ok(registry)
+    }
+
+    fn read_state(&self) -> Result<TemplateRegistryState, TemplateError> {
+        let mut file = match File::open(&self.file_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(TemplateRegistryState::default());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        if contents.trim().is_empty() {
+            return Ok(TemplateRegistryState::default());
+        }
+
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    fn write_state(&self, state: &TemplateRegistryState) -> Result<(), TemplateError> {
+        let json = serde_json::to_string_pretty(state)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.file_path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Returns the current template of `language`, if one was baked.
+    pub fn get(&self, language: &str) -> Result<Option<Template>, TemplateError> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(self.read_state()?.templates.remove(language))
+    }
+
+    /// Returns every registered template, sorted by language.
+    pub fn list(&self) -> Result<Vec<Template>, TemplateError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut templates = self
+            .read_state()?
+            .templates
+            .into_values()
+            .collect::<Vec<_>>();
+        templates.sort_by(|a, b| a.language.cmp(&b.language));
+        Ok(templates)
+    }
+
+    /// Makes `template` the current one for its language and returns the template it replaces.
+    pub fn register(&self, template: Template) -> Result<Option<Template>, TemplateError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut state = self.read_state()?;
+        let previous = state.templates.insert(template.language.clone(), template);
+        self.write_state(&state)?;
+        Ok(previous)
+    }
+}
+
+/// Boots each runtime up to the point where its interpreter is warm, snapshots
+/// the VM and registers the snapshot as the runtime's template.
+pub struct TemplateBaker {
+    vm_config: VmConfig,
+    ip_manager: Arc<Mutex<IpManager>>,
+    blobs: Arc<dyn BlobStore>,
+    registry: Arc<TemplateRegistry>,
+    work_dir: PathBuf,
+    client: reqwest::Client,
+}
+
+impl TemplateBaker {
+    /// Templates are baked without room to hotplug, whatever `vm_config` leaves.
+    ///
+    /// # Arguments
+    /// * `work_dir` - Local scratch directory where snapshots are written before upload.
+    pub fn new(
+        vm_config: VmConfig,
+        ip_manager: Arc<Mutex<IpManager>>,
+        blobs: Arc<dyn BlobStore>,
+        registry: Arc<TemplateRegistry>,
+        work_dir: PathBuf,
+        client: reqwest::Client,
+    ) -> Self {
+        Self {
+            vm_config: VmConfig {
+                max_vcpus: 0,
+                max_memory_mb: 0,
+                ..vm_config
+            },
+            ip_manager,
+            blobs,
+            registry,
+            work_dir,
+            client,
+        }
+    }
+
+    /// Returns the template of `language`, baking a new one first if there is
+    /// none yet or if its base digest changed since it was baked.
+    pub async fn ensure_template(
+        &self,
+        language: &InitramfsLanguage,
+    ) -> Result<Template, TemplateError> {
+        let initramfs_path =
+            VmHandle::build_initramfs_with_agent(&language.name, &self.vm_config).await?;
+        let digest = base_digest(
+            &self.vm_config.kernel_path,
+            &initramfs_path,
+            language,
+            &self.vm_config,
+        )
+        .await?;
+
+        if let Some(current) = self.registry.get(&language.name)?
+            && current.base_digest == digest
+            && self.snapshot_exists(&current).await?
+        {
+            return Ok(current);
+        }
+
+        info!(language = %language.name, digest = %digest, "Baking VM template");
+        let template = self.bake(language, &digest).await?;
+
+        if let Some(previous) = self.registry.register(template.clone())?
+            && previous.snapshot_key != template.snapshot_key
+        {
+            for key in previous.snapshot_keys() {
+                self.blobs.delete(&key).await?;
+            }
+        }
+
+        info!(language = %language.name, key = %template.snapshot_key, "VM template registered");
+        Ok(template)
+    }
+
+    /// Brings the template of every runtime up to date. A failure for one
+    /// runtime is logged and does not prevent the others from being baked.
+    pub async fn refresh_all(&self, languages: &[InitramfsLanguage]) {
+        for language in languages {
+            if let Err(e) = self.ensure_template(language).await {
+                error!(language = %language.name, "Failed to bake VM template: {}", e);
+            }
+        }
+    }
+
+    /// Runs [`Self::refresh_all`] now and then every `interval`, so templates are
+    /// baked again whenever a kernel or runtime image changes.
+    pub fn spawn(self: Arc<Self>, languages: Vec<InitramfsLanguage>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                self.refresh_all(&languages).await;
+            }
+        });
+    }
+
+    async fn snapshot_exists(&self, template: &Template) -> Result<bool, TemplateError> {
+        for key in template.snapshot_keys() {
+            if !self.blobs.exists(&key).await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn bake(
+        &self,
+        language: &InitramfsLanguage,
+        digest: &str,
+    ) -> Result<Template, TemplateError> {
+        let snapshot_dir = self.work_dir.join(format!("{}-{}", language.name, digest));
+        let mut vm = VmHandle::create_template(
+            format!("template-{}", language.name),
+            &language.name,
+            &self.vm_config,
+            Arc::clone(&self.ip_manager),
+        )
+        .await?;
+
+        let result = async {
+            self.warm_up(&vm, language).await?;
+            vm.snapshot(&snapshot_dir).await?;
+            Ok::<_, TemplateError>(())
+        }
+        .await;
+        vm.destroy().await;
+
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_dir_all(&snapshot_dir).await;
+            return Err(e);
+        }
+
+        let mut template = Template {
+            language: language.name.clone(),
+            version: language.version.clone(),
+            base_digest: digest.to_string(),
+            snapshot_key: format!(
+                "{}templates/{}/{}/",
+                SNAPSHOTS_PREFIX, language.name, digest
+            ),
+            vm: cloude_types::VmConfig {
+                vcpus: self.vm_config.vcpus,
+                memory_mb: self.vm_config.memory_mb,
+            },
+            size_bytes: 0,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+
+        let upload = async {
+            for (key, file) in template.snapshot_keys().iter().zip(SNAPSHOT_FILES) {
+                let path = snapshot_dir.join(file);
+                template.size_bytes += tokio::fs::metadata(&path).await?.len();
+                self.blobs.put_file(key, &path).await?;
+            }
+            Ok::<_, TemplateError>(())
+        }
+        .await;
+        let _ = tokio::fs::remove_dir_all(&snapshot_dir).await;
+        upload?;
+
+        Ok(template)
+    }
+
+    /// Runs the runtime's warm-up snippet so the interpreter and its caches are
+    /// loaded in memory when the snapshot is taken.
+    async fn warm_up(
+        &self,
+        vm: &VmHandle,
+        language: &InitramfsLanguage,
+    ) -> Result<(), TemplateError> {
+        let Some(code) = &language.warmup_code else {
+            return Ok(());
+        };
+
+        let execute_url = format!("{}/execute", vm.agent_url().trim_end_matches('/'));
+        let resp = self
+            .client
+            .post(&execute_url)
+            .json(&serde_json::json!({ "language": language.name, "code": code }))
+            .send()
+            .await
+            .map_err(|e| TemplateError::Warmup(e.to_string()))?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            return Err(TemplateError::Warmup(format!(
+                "agent returned HTTP {}: {}",
+                status, body
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Local copies of the template snapshots, which VMs are restored from instead of
+/// booted, see [`VmHandle::restore`].
+pub struct TemplateClones {
+    registry: Arc<TemplateRegistry>,
+    blobs: Arc<dyn BlobStore>,
+    dir: PathBuf,
+    /// Held while a snapshot is downloaded, for it to be downloaded once.
+    fetch: tokio::sync::Mutex<()>,
+}
+
+impl TemplateClones {
+    /// # Arguments
+    /// * `dir` - Local directory the snapshots are downloaded to.
+    pub fn new(registry: Arc<TemplateRegistry>, blobs: Arc<dyn BlobStore>, dir: PathBuf) -> Self {
+        Self {
+            registry,
+            blobs,
+            dir,
+            fetch: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Directory of the snapshot of the current template of `language`, downloaded
+    /// first unless it is there already, or `None` when it has no template that
+    /// [fits](Template::fits) VMs of `config`.
+    pub async fn snapshot_for(
+        &self,
+        language: &str,
+        config: &VmConfig,
+    ) -> Result<Option<PathBuf>, TemplateError> {
+        let Some(template) = self.registry.get(language)? else {
+            return Ok(None);
+        };
+        if !template.fits(config) {
+            return Ok(None);
+        }
+
+        let dir = self.dir.join(snapshot_dir_name(&template));
+        let _fetch = self.fetch.lock().await;
+        if tokio::fs::try_exists(&dir).await? {
+            return Ok(Some(dir));
+        }
+
+        info!(language = %language, key = %template.snapshot_key, "Downloading VM template");
+        let partial = self.dir.join(format!(".{}", snapshot_dir_name(&template)));
+        tokio::fs::create_dir_all(&partial).await?;
+        for (key, file) in template.snapshot_keys().iter().zip(SNAPSHOT_FILES) {
+            let Some(data) = self.blobs.get(key).await? else {
+                warn!(language = %language, key = %key, "VM template snapshot is missing");
+                tokio::fs::remove_dir_all(&partial).await?;
+                return Ok(None);
+            };
+            tokio::fs::write(partial.join(file), data).await?;
+        }
+        tokio::fs::rename(&partial, &dir).await?;
+
+        self.remove_stale(&template).await;
+        Ok(Some(dir))
+    }
+
+    /// Removes the snapshots of the templates `template` replaced. Restored VMs
+    /// keep the memory they mapped.
+    async fn remove_stale(&self, template: &Template) {
+        let current = snapshot_dir_name(template);
+        let Ok(mut entries) = tokio::fs::read_dir(&self.dir).await else {
+            return;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let stale = name
+                .strip_prefix(&template.language)
+                .and_then(|rest| rest.strip_prefix('-'))
+                .is_some_and(|digest| {
+                    digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit())
+                });
+            if stale
+                && name != current
+                && let Err(e) = tokio::fs::remove_dir_all(entry.path()).await
+            {
+                warn!(path = %entry.path().display(), "Failed to remove stale VM template: {}", e);
+            }
+        }
+    }
+}
+
+fn snapshot_dir_name(template: &Template) -> String {
+    format!("{}-{}", template.language, template.base_digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::LocalBlobStore;
+    use crate::initramfs_manager::BuildSite;
+    use crate::redaction::Redactor;
+    use tempfile::{NamedTempFile, TempDir};
+
+    fn vm_config(dir: &Path) -> VmConfig {
+        VmConfig {
+            kernel_path: dir.join("vmlinux"),
+            initramfs_dir: dir.to_path_buf(),
+            bridge_name: Some("cloudebr0".to_string()),
+            tenant: None,
+            vcpus: 1,
+            memory_mb: 512,
+            log_guest_console: false,
+            serial_log: None,
+            redactor: Redactor::default(),
+            cmdline_extra: None,
+            acpi: false,
+            max_vcpus: 0,
+            max_memory_mb: 0,
+            traffic_accounting: false,
+            mtu: None,
+            merge_memory: false,
+        }
+    }
+
+    fn template(language: &str, digest: &str) -> Template {
+        Template {
+            language: language.to_string(),
+            version: "1".to_string(),
+            base_digest: digest.to_string(),
+            snapshot_key: format!("snapshots/templates/{}/{}/", language, digest),
+            vm: cloude_types::VmConfig {
+                vcpus: 1,
+                memory_mb: 512,
+            },
+            size_bytes: 0,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_register_replaces_previous_template() {
+        let file = NamedTempFile::new().unwrap();
+        let registry = TemplateRegistry::new(file.path()).unwrap();
+
+        assert!(
+            registry
+                .register(template("python", "a"))
+                .unwrap()
+                .is_none()
+        );
+        let previous = registry.register(template("python", "b")).unwrap();
+        assert_eq!(previous.unwrap().base_digest, "a");
+        registry.register(template("node", "c")).unwrap();
+
+        let reopened = TemplateRegistry::new(file.path()).unwrap();
+        assert_eq!(reopened.get("python").unwrap().unwrap().base_digest, "b");
+        let languages = reopened
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|t| t.language)
+            .collect::<Vec<_>>();
+        assert_eq!(languages, vec!["node", "python"]);
+    }
+
+    #[tokio::test]
+    async fn test_base_digest_tracks_inputs() {
+        let dir = TempDir::new().unwrap();
+        let kernel = dir.path().join("vmlinux");
+        let initramfs = dir.path().join("python-3.11.cpio.gz");
+        tokio::fs::write(&kernel, b"kernel").await.unwrap();
+        tokio::fs::write(&initramfs, b"rootfs v1").await.unwrap();
+
+        let mut language = InitramfsLanguage {
+            name: "python".to_string(),
+            version: "3.11".to_string(),
+            base_image: "python:3.11-alpine".to_string(),
+            warmup_code: None,
+            upgrade: None,
+            resources: None,
+            run_image: None,
+            arches: Vec::new(),
+            build: BuildSite::Vm,
+        };
+        let config = vm_config(dir.path());
+
+        let first = base_digest(&kernel, &initramfs, &language, &config)
+            .await
+            .unwrap();
+        assert_eq!(
+            first,
+            base_digest(&kernel, &initramfs, &language, &config)
+                .await
+                .unwrap()
+        );
+
+        tokio::fs::write(&initramfs, b"rootfs v2").await.unwrap();
+        let second = base_digest(&kernel, &initramfs, &language, &config)
+            .await
+            .unwrap();
+        assert_ne!(first, second);
+
+        language.warmup_code = Some("import json".to_string());
+        let third = base_digest(&kernel, &initramfs, &language, &config)
+            .await
+            .unwrap();
+        assert_ne!(second, third);
+
+        // Ethernet's MTU is the one templates were baked at before it was set.
+        let mut config = config;
+        config.mtu = Some(DEFAULT_MTU);
+        assert_eq!(
+            third,
+            base_digest(&kernel, &initramfs, &language, &config)
+                .await
+                .unwrap()
+        );
+        config.mtu = Some(9000);
+        assert_ne!(
+            third,
+            base_digest(&kernel, &initramfs, &language, &config)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clones_fetch_fitting_snapshots() {
+        let dir = TempDir::new().unwrap();
+        let registry_file = NamedTempFile::new().unwrap();
+        let registry = Arc::new(TemplateRegistry::new(registry_file.path()).unwrap());
+        let blobs: Arc<dyn BlobStore> =
+            Arc::new(LocalBlobStore::new(dir.path().join("blobs")).await.unwrap());
+        let clones = TemplateClones::new(
+            Arc::clone(&registry),
+            Arc::clone(&blobs),
+            dir.path().join("cache"),
+        );
+        let config = vm_config(dir.path());
+
+        let digest = |c: char| c.to_string().repeat(64);
+        for digest in [digest('a'), digest('b')] {
+            for key in template("python", &digest).snapshot_keys() {
+                blobs.put(&key, digest.as_bytes().to_vec()).await.unwrap();
+            }
+        }
+        assert!(
+            clones
+                .snapshot_for("python", &config)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        registry.register(template("python", &digest('a'))).unwrap();
+        let first = clones
+            .snapshot_for("python", &config)
+            .await
+            .unwrap()
+            .unwrap();
+        let memory = tokio::fs::read(first.join(vmm::MEMORY_FILE)).await.unwrap();
+        assert_eq!(memory, digest('a').as_bytes());
+
+        // VMs of another shape, or that can grow, are booted.
+        let larger = VmConfig {
+            vcpus: 2,
+            ..vm_config(dir.path())
+        };
+        let growing = VmConfig {
+            max_vcpus: 2,
+            ..vm_config(dir.path())
+        };
+        for config in [larger, growing] {
+            assert!(
+                clones
+                    .snapshot_for("python", &config)
+                    .await
+                    .unwrap()
+                    .is_none()
+            );
+        }
+
+        // The snapshot of a replaced template goes.
+        registry.register(template("python", &digest('b'))).unwrap();
+        let second = clones
+            .snapshot_for("python", &config)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(first, second);
+        assert!(second.join(vmm::STATE_FILE).exists());
+        assert!(!first.exists());
+    }
+}
@@ -0,0 +1,170 @@
+//! `http` triggers: requests to `/f/{name}` run the function, which the router
+//! serves directly rather than through a [`super::Trigger`] task.
+
+use super::event;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header};
+use cloude_types::{TriggerEvent, TriggerKind};
+use serde::Deserialize;
+
+/// Headers kept from the function: credentials meant for the backend.
+const WITHHELD_HEADERS: [header::HeaderName; 2] = [header::AUTHORIZATION, header::COOKIE];
+/// Headers of a response head that the backend sets itself, as it streams the body.
+const FRAMING_HEADERS: [header::HeaderName; 3] = [
+    header::CONNECTION,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+];
+/// Largest response head a function can write before its body.
+const MAX_RESPONSE_HEAD_BYTES: usize = 64 * 1024;
+
+/// What the answer to a request to `/f/{name}` is made of.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseMode {
+    /// `200` with the standard output once the function exited 0.
+    #[default]
+    Stdout,
+    /// The response the function writes on its standard output: a head of
+    /// `Name: value` lines, `Status: 201` among them, a blank line, then the body,
+    /// relayed as it is written.
+    Http,
+}
+
+/// Status and headers of a response written by a function in `http` mode.
+#[derive(Debug)]
+pub struct ResponseHead {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+}
+
+/// The event of a request to `/f/{name}{path}`. Headers that are not UTF-8 are dropped.
+pub fn http_event(
+    method: &Method,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    body: Vec<u8>,
+) -> TriggerEvent {
+    let mut event = event(TriggerKind::Http, format!("/{path}"), body);
+    event.method = Some(method.to_string());
+    event.query = query.map(str::to_string);
+    event.headers = headers
+        .iter()
+        .filter(|(name, _)| !WITHHELD_HEADERS.contains(name))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    event
+}
+
+/// Parses the response head at the start of `output`, and returns it with the
+/// number of bytes it took. `Ok(None)` until the blank line ending it, unless the
+/// output is `complete`: a function can write a head and no body.
+pub fn parse_response_head(
+    output: &[u8],
+    complete: bool,
+) -> Result<Option<(ResponseHead, usize)>, String> {
+    let mut head = ResponseHead {
+        status: StatusCode::OK,
+        headers: HeaderMap::new(),
+    };
+    let mut start = 0;
+    loop {
+        let (line, next) = match output[start..].iter().position(|&b| b == b'\n') {
+            Some(end) => (&output[start..start + end], start + end + 1),
+            None if complete => (&output[start..], output.len()),
+            None if output.len() > MAX_RESPONSE_HEAD_BYTES => {
+                return Err(format!(
+                    "no blank line in the first {} bytes",
+                    MAX_RESPONSE_HEAD_BYTES
+                ));
+            }
+            None => return Ok(None),
+        };
+        let line = std::str::from_utf8(line)
+            .map_err(|_| "head is not UTF-8".to_string())?
+            .trim_end_matches('\r');
+        if line.is_empty() {
+            return Ok(Some((head, next)));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("`{line}` is not a `Name: value` header"))?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("status") {
+            // `Status: 404 Not Found`: the reason phrase is the client's business.
+            head.status = value
+                .split_whitespace()
+                .next()
+                .and_then(|code| code.parse().ok())
+                .and_then(|code| StatusCode::from_u16(code).ok())
+                .ok_or_else(|| format!("`{value}` is not a status code"))?;
+        } else {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("`{name}` is not a header name"))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| format!("invalid value for header {name}"))?;
+            if !FRAMING_HEADERS.contains(&name) {
+                head.headers.append(name, value);
+            }
+        }
+        if next == output.len() && complete {
+            return Ok(Some((head, next)));
+        }
+        start = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_event() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+
+        let event = http_event(
+            &Method::POST,
+            "orders/42",
+            Some("dry_run=1"),
+            &headers,
+            b"{}".to_vec(),
+        );
+        assert_eq!(event.trigger, TriggerKind::Http);
+        assert_eq!(event.source, "/orders/42");
+        assert_eq!(event.method.as_deref(), Some("POST"));
+        assert_eq!(event.query.as_deref(), Some("dry_run=1"));
+        assert_eq!(event.body.as_deref(), Some("{}"));
+        assert_eq!(
+            event.headers.into_iter().collect::<Vec<_>>(),
+            [("content-type".to_string(), "application/json".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_parse_response_head() {
+        let output = b"Status: 201 Created\r\nContent-Type: text/html\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\nContent-Length: 3\r\n\r\n<p>";
+        let (head, len) = parse_response_head(output, false).unwrap().unwrap();
+        assert_eq!(head.status, StatusCode::CREATED);
+        assert_eq!(head.headers[header::CONTENT_TYPE], "text/html");
+        assert_eq!(head.headers.get_all(header::SET_COOKIE).iter().count(), 2);
+        assert!(!head.headers.contains_key(header::CONTENT_LENGTH));
+        assert_eq!(&output[len..], b"<p>");
+
+        // The blank line may still be on its way, unless the function exited.
+        assert!(
+            parse_response_head(b"Content-Type: text/plain\n", false)
+                .unwrap()
+                .is_none()
+        );
+        let (head, len) = parse_response_head(b"Status: 204\n", true)
+            .unwrap()
+            .unwrap();
+        assert_eq!((head.status, len), (StatusCode::NO_CONTENT, 12));
+
+        assert!(parse_response_head(b"hello world\n\n", false).is_err());
+        assert!(parse_response_head(b"Status: teapot\n\n", false).is_err());
+        assert!(parse_response_head(&[b'a'; MAX_RESPONSE_HEAD_BYTES + 1], false).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+//! Sources that run deployed functions, besides `POST /functions/{name}/run`.
+//!
+//! Bindings are read from `triggers.toml` at startup. An `http` binding serves
+//! the function on `/f/{name}`; the other kinds are a [`Trigger`] each, a task
+//! that watches its source and runs the function once per event. Every run gets
+//! its [`TriggerEvent`] as JSON on its standard input.
+
+mod http;
+mod nats;
+mod objects;
+
+pub use self::http::{ResponseHead, ResponseMode, http_event, parse_response_head};
+pub use self::nats::NatsTrigger;
+pub(crate) use self::nats::address as nats_address;
+pub use self::objects::ObjectTrigger;
+
+use crate::blob_store::{ARTIFACTS_PREFIX, BlobStore, LocalBlobStore, SNAPSHOTS_PREFIX};
+use crate::validation::{ValidationErrors, validate_identifier};
+use async_trait::async_trait;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use cloude_types::{TriggerEvent, TriggerKind};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often directories and buckets are listed when the binding does not say.
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 10;
+
+/// Errors that can occur while loading the trigger bindings.
+#[derive(Debug)]
+pub enum TriggerError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Invalid(ValidationErrors),
+}
+
+impl std::fmt::Display for TriggerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TriggerError::Io(e) => write!(f, "IO error: {}", e),
+            TriggerError::Toml(e) => write!(f, "TOML error: {}", e),
+            TriggerError::Invalid(errors) => write!(f, "invalid triggers: {}", errors),
+        }
+    }
+}
+
+impl std::error::Error for TriggerError {}
+
+impl From<std::io::Error> for TriggerError {
+    fn from(err: std::io::Error) -> Self {
+        TriggerError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for TriggerError {
+    fn from(err: toml::de::Error) -> Self {
+        TriggerError::Toml(err)
+    }
+}
+
+/// Runs functions for triggers. Implemented by the backend, which knows how to
+/// start jobs.
+#[async_trait]
+pub trait Invoker: Send + Sync {
+    /// Starts a run of function `name` with `event` on its standard input, and
+    /// returns the id of its job.
+    async fn invoke(&self, name: &str, event: TriggerEvent) -> Result<String, String>;
+}
+
+/// A source of events that runs a function for each of them.
+#[async_trait]
+pub trait Trigger: Send {
+    /// Function the trigger runs.
+    fn function(&self) -> &str;
+
+    /// Watches the source and hands every event to `invoker`, until the backend stops.
+    async fn run(self: Box<Self>, invoker: Arc<dyn Invoker>);
+}
+
+/// Content of `triggers.toml`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct TriggersFile {
+    #[serde(default, rename = "trigger")]
+    triggers: Vec<Binding>,
+}
+
+/// A function bound to a source of events.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Binding {
+    /// Requests to `/f/{function}` run the function and get its output.
+    Http {
+        function: String,
+        #[serde(default)]
+        response: ResponseMode,
+    },
+    /// Messages of `subject` run the function; with `queue_group`, backends of
+    /// the same group share the messages rather than each getting all of them.
+    Nats {
+        function: String,
+        url: String,
+        subject: String,
+        queue_group: Option<String>,
+    },
+    /// Files put in `path` run the function, and are deleted once it started.
+    Directory {
+        function: String,
+        path: PathBuf,
+        interval_secs: Option<u64>,
+    },
+    /// Objects put under `prefix` in the blob store run the function, and are
+    /// deleted once it started.
+    Bucket {
+        function: String,
+        prefix: String,
+        interval_secs: Option<u64>,
+    },
+}
+
+impl Binding {
+    pub fn function(&self) -> &str {
+        match self {
+            Binding::Http { function, .. }
+            | Binding::Nats { function, .. }
+            | Binding::Directory { function, .. }
+            | Binding::Bucket { function, .. } => function,
+        }
+    }
+}
+
+/// Bindings read from `path`; none when the file does not exist.
+pub fn load_bindings(path: &Path) -> Result<Vec<Binding>, TriggerError> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let bindings = toml::from_str::<TriggersFile>(&content)?.triggers;
+    validate_bindings(&bindings).map_err(TriggerError::Invalid)?;
+    Ok(bindings)
+}
+
+fn validate_bindings(bindings: &[Binding]) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    for (index, binding) in bindings.iter().enumerate() {
+        let field = |name: &str| format!("trigger[{index}].{name}");
+        validate_identifier(&mut errors, &field("function"), binding.function());
+        match binding {
+            Binding::Http { .. } => {}
+            Binding::Nats { url, subject, .. } => {
+                if nats::address(url).is_none() {
+                    errors.push(&field("url"), "must be nats://host:port");
+                }
+                if subject.is_empty() || subject.contains(char::is_whitespace) {
+                    errors.push(&field("subject"), "must be a subject without spaces");
+                }
+            }
+            Binding::Directory {
+                path,
+                interval_secs,
+                ..
+            } => {
+                if !path.is_absolute() {
+                    errors.push(&field("path"), "must be an absolute path");
+                }
+                if *interval_secs == Some(0) {
+                    errors.push(&field("interval_secs"), "must be at least 1");
+                }
+            }
+            Binding::Bucket {
+                prefix,
+                interval_secs,
+                ..
+            } => {
+                if !prefix.ends_with('/')
+                    || prefix.starts_with(ARTIFACTS_PREFIX)
+                    || prefix.starts_with(SNAPSHOTS_PREFIX)
+                {
+                    errors.push(
+                        &field("prefix"),
+                        format!(
+                            "must end with / and stay out of {} and {}",
+                            ARTIFACTS_PREFIX, SNAPSHOTS_PREFIX
+                        ),
+                    );
+                }
+                if *interval_secs == Some(0) {
+                    errors.push(&field("interval_secs"), "must be at least 1");
+                }
+            }
+        }
+    }
+    errors.into_result(())
+}
+
+/// The task of `binding`, `None` for `http` bindings which the router serves.
+/// Buckets are prefixes of `blobs`, the blob store of the backend.
+pub async fn trigger_for(
+    binding: &Binding,
+    blobs: &Arc<dyn BlobStore>,
+) -> Result<Option<Box<dyn Trigger>>, TriggerError> {
+    let interval =
+        |secs: &Option<u64>| Duration::from_secs(secs.unwrap_or(DEFAULT_POLL_INTERVAL_SECS));
+    Ok(match binding.clone() {
+        Binding::Http { .. } => None,
+        Binding::Nats {
+            function,
+            url,
+            subject,
+            queue_group,
+        } => Some(Box::new(NatsTrigger::new(
+            function,
+            url,
+            subject,
+            queue_group,
+        ))),
+        Binding::Directory {
+            function,
+            path,
+            interval_secs,
+        } => {
+            let store = LocalBlobStore::new(&path)
+                .await
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            Some(Box::new(ObjectTrigger::new(
+                function,
+                TriggerKind::Directory,
+                Arc::new(store),
+                String::new(),
+                interval(&interval_secs),
+            )))
+        }
+        Binding::Bucket {
+            function,
+            prefix,
+            interval_secs,
+        } => Some(Box::new(ObjectTrigger::new(
+            function,
+            TriggerKind::Bucket,
+            Arc::clone(blobs),
+            prefix,
+            interval(&interval_secs),
+        ))),
+    })
+}
+
+/// An event of `trigger` from `source`, carrying `payload` as text when it is UTF-8.
+fn event(trigger: TriggerKind, source: String, payload: Vec<u8>) -> TriggerEvent {
+    let (body, body_base64) = match String::from_utf8(payload) {
+        Ok(text) => (Some(text), None),
+        Err(e) => (None, Some(BASE64_STANDARD.encode(e.into_bytes()))),
+    };
+    TriggerEvent {
+        trigger,
+        source,
+        method: None,
+        query: None,
+        headers: Default::default(),
+        body,
+        body_base64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_bindings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("triggers.toml");
+        assert!(load_bindings(&path).unwrap().is_empty());
+
+        std::fs::write(
+            &path,
+            r#"
+            [[trigger]]
+            type = "http"
+            function = "echo"
+
+            [[trigger]]
+            type = "http"
+            function = "page"
+            response = "http"
+
+            [[trigger]]
+            type = "nats"
+            function = "ingest"
+            url = "nats://127.0.0.1:4222"
+            subject = "uploads.>"
+            queue_group = "cloude"
+
+            [[trigger]]
+            type = "bucket"
+            function = "report"
+            prefix = "inbox/"
+            "#,
+        )
+        .unwrap();
+        let bindings = load_bindings(&path).unwrap();
+        assert_eq!(
+            bindings[0],
+            Binding::Http {
+                function: "echo".to_string(),
+                response: ResponseMode::Stdout,
+            }
+        );
+        assert!(matches!(
+            &bindings[1],
+            Binding::Http {
+                response: ResponseMode::Http,
+                ..
+            }
+        ));
+        assert_eq!(bindings[2].function(), "ingest");
+        assert!(matches!(
+            &bindings[3],
+            Binding::Bucket { prefix, interval_secs: None, .. } if prefix == "inbox/"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_bindings() {
+        let bindings = [
+            Binding::Nats {
+                function: "bad name".to_string(),
+                url: "http://127.0.0.1".to_string(),
+                subject: "a b".to_string(),
+                queue_group: None,
+            },
+            Binding::Directory {
+                function: "f".to_string(),
+                path: PathBuf::from("inbox"),
+                interval_secs: Some(0),
+            },
+            Binding::Bucket {
+                function: "f".to_string(),
+                prefix: "artifacts/".to_string(),
+                interval_secs: None,
+            },
+        ];
+        let errors = validate_bindings(&bindings).unwrap_err().to_string();
+        for field in [
+            "trigger[0].function",
+            "trigger[0].url",
+            "trigger[0].subject",
+            "trigger[1].path",
+            "trigger[1].interval_secs",
+            "trigger[2].prefix",
+        ] {
+            assert!(errors.contains(field), "{field} missing from: {errors}");
+        }
+    }
+
+    #[test]
+    fn test_event_payloads() {
+        let text = event(TriggerKind::Nats, "a".to_string(), b"hello".to_vec());
+        assert_eq!(text.body.as_deref(), Some("hello"));
+        assert_eq!(text.body_base64, None);
+
+        let binary = event(TriggerKind::Nats, "a".to_string(), vec![0xff, 0x00]);
+        assert_eq!(binary.body, None);
+        assert_eq!(binary.body_base64.as_deref(), Some("/wA="));
+    }
+}
@@ -0,0 +1,154 @@
+//! `directory` and `bucket` triggers: new objects of a blob store run the function.
+//!
+//! A watched directory is a local blob store rooted at it, a bucket a prefix of
+//! the backend's own blob store. They are listed at an interval; each object
+//! found runs the function once, then is deleted, so the store works as an inbox.
+
+use super::{Invoker, Trigger, event};
+use crate::blob_store::BlobStore;
+use async_trait::async_trait;
+use cloude_types::TriggerKind;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub struct ObjectTrigger {
+    function: String,
+    kind: TriggerKind,
+    store: Arc<dyn BlobStore>,
+    prefix: String,
+    interval: Duration,
+}
+
+impl ObjectTrigger {
+    pub fn new(
+        function: String,
+        kind: TriggerKind,
+        store: Arc<dyn BlobStore>,
+        prefix: String,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            function,
+            kind,
+            store,
+            prefix,
+            interval,
+        }
+    }
+
+    /// Runs the function for each object under the prefix, and deletes the
+    /// objects it started for. Returns how many there were.
+    pub async fn poll(&self, invoker: &dyn Invoker) -> Result<usize, String> {
+        let keys = self
+            .store
+            .list(&self.prefix)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut started = 0;
+        for key in keys {
+            let payload = match self.store.get(&key).await {
+                Ok(Some(payload)) => payload,
+                // Removed since it was listed.
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Cannot read {} for function {}: {}", key, self.function, e);
+                    continue;
+                }
+            };
+            let event = event(self.kind, key.clone(), payload);
+            match invoker.invoke(&self.function, event).await {
+                Ok(job_id) => {
+                    info!(
+                        "Function {} started as job {} for {}",
+                        self.function, job_id, key
+                    );
+                    started += 1;
+                    if let Err(e) = self.store.delete(&key).await {
+                        warn!("Cannot delete {} once handled: {}", key, e);
+                    }
+                }
+                // Left in place, to be tried again at the next poll.
+                Err(e) => warn!("Function {} not started for {}: {}", self.function, key, e),
+            }
+        }
+        Ok(started)
+    }
+}
+
+#[async_trait]
+impl Trigger for ObjectTrigger {
+    fn function(&self) -> &str {
+        &self.function
+    }
+
+    async fn run(self: Box<Self>, invoker: Arc<dyn Invoker>) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.poll(invoker.as_ref()).await {
+                warn!(
+                    "Cannot list {} for function {}: {}",
+                    self.prefix, self.function, e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::LocalBlobStore;
+    use cloude_types::TriggerEvent;
+    use std::sync::Mutex;
+
+    /// Accepts every event but those whose body is `fail`.
+    #[derive(Default)]
+    struct RecordingInvoker {
+        events: Mutex<Vec<TriggerEvent>>,
+    }
+
+    #[async_trait]
+    impl Invoker for RecordingInvoker {
+        async fn invoke(&self, _name: &str, event: TriggerEvent) -> Result<String, String> {
+            if event.body.as_deref() == Some("fail") {
+                return Err("rejected".to_string());
+            }
+            let mut events = self.events.lock().unwrap();
+            events.push(event);
+            Ok(format!("job-{}", events.len()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_poll_runs_and_deletes_new_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn BlobStore> = Arc::new(LocalBlobStore::new(dir.path()).await.unwrap());
+        store.put("inbox/a.json", b"{}".to_vec()).await.unwrap();
+        store.put("inbox/b.txt", b"fail".to_vec()).await.unwrap();
+        store.put("other/c.txt", b"x".to_vec()).await.unwrap();
+
+        let trigger = ObjectTrigger::new(
+            "ingest".to_string(),
+            TriggerKind::Bucket,
+            Arc::clone(&store),
+            "inbox/".to_string(),
+            Duration::from_secs(1),
+        );
+        let invoker = RecordingInvoker::default();
+        assert_eq!(trigger.poll(&invoker).await, Ok(1));
+
+        let events = invoker.events.lock().unwrap().clone();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].trigger, TriggerKind::Bucket);
+        assert_eq!(events[0].source, "inbox/a.json");
+        assert_eq!(events[0].body.as_deref(), Some("{}"));
+        // The rejected object stays for the next poll, other prefixes are left alone.
+        assert_eq!(
+            store.list("").await.unwrap(),
+            ["inbox/b.txt", "other/c.txt"]
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use cloude_types::Resources;
+use serde::Serialize;
+
+/// Default maximum size of inline source code accepted by the API (5 MiB).
+pub const DEFAULT_MAX_CODE_BYTES: usize = 5 * 1024 * 1024;
+
+/// Default maximum vCPUs and memory a job can ask for.
+pub const DEFAULT_MAX_VCPUS: u8 = 4;
+pub const DEFAULT_MAX_MEMORY_MB: usize = 4096;
+
+/// Smallest guest memory size accepted for new VMs.
+const MIN_MEMORY_MB: usize = 64;
+
+/// Maximum length of identifiers that end up in file paths and interface names.
+pub const MAX_IDENTIFIER_LEN: usize = 63;
+
+/// Extra room allowed on top of the code limit for the JSON envelope and escaping.
+const BODY_OVERHEAD_BYTES: usize = 64 * 1024;
+
+/// Size limits applied to incoming requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestLimits {
+    pub max_code_bytes: usize,
+    /// Largest VM a job can ask for, or a runtime give its jobs.
+    pub max_vcpus: u8,
+    pub max_memory_mb: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_code_bytes: DEFAULT_MAX_CODE_BYTES,
+            max_vcpus: DEFAULT_MAX_VCPUS,
+            max_memory_mb: DEFAULT_MAX_MEMORY_MB,
+        }
+    }
+}
+
+impl RequestLimits {
+    /// Upper bound for a whole request body, used to reject oversized payloads
+    /// before they are buffered and parsed.
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_code_bytes.saturating_mul(2) + BODY_OVERHEAD_BYTES
+    }
+}
+
+pub use cloude_types::FieldError;
+
+/// All problems found while validating a request, reported together
+/// so clients can fix every field in one round-trip.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn push(&mut self, field: &str, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Turns the collected errors into a `Result`, `Ok(value)` when nothing was reported.
+    pub fn into_result<T>(self, value: T) -> Result<T, ValidationErrors> {
+        if self.is_empty() {
+            Ok(value)
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let messages = self
+            .errors
+            .iter()
+            .map(|e| format!("{}: {}", e.field, e.message))
+            .collect::<Vec<_>>();
+        write!(f, "{}", messages.join("; "))
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// Map the usual short names of a runtime to the name it is configured under.
+pub fn normalize_language_alias(input: &str) -> String {
+    match input {
+        "py" => "python".to_string(),
+        "js" | "javascript" => "node".to_string(),
+        "rs" => "rust".to_string(),
+        "golang" => "go".to_string(),
+        "c++" => "cpp".to_string(),
+        _ => input.to_string(),
+    }
+}
+
+/// Check that an identifier is safe to embed in file paths and TAP device names:
+/// 1 to 63 characters of lowercase ASCII letters, digits, `-` or `_`,
+/// starting with a letter or digit.
+pub fn validate_identifier(errors: &mut ValidationErrors, field: &str, value: &str) {
+    if value.is_empty() {
+        errors.push(field, "must not be empty");
+        return;
+    }
+
+    if value.len() > MAX_IDENTIFIER_LEN {
+        errors.push(
+            field,
+            format!("must be at most {} characters", MAX_IDENTIFIER_LEN),
+        );
+    }
+
+    if !value
+        .bytes()
+        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+    {
+        errors.push(
+            field,
+            "may only contain lowercase letters, digits, '-' and '_'",
+        );
+    } else if !value.as_bytes()[0].is_ascii_alphanumeric() {
+        errors.push(field, "must start with a letter or a digit");
+    }
+}
+
+/// Check that inline source code is present and within the configured size limit.
+pub fn validate_code(
+    errors: &mut ValidationErrors,
+    field: &str,
+    code: &str,
+    limits: &RequestLimits,
+) {
+    if code.trim().is_empty() {
+        errors.push(field, "Code cannot be empty");
+    } else if code.len() > limits.max_code_bytes {
+        errors.push(
+            field,
+            format!(
+                "is {} bytes, larger than the {} bytes allowed inline",
+                code.len(),
+                limits.max_code_bytes
+            ),
+        );
+    }
+}
+
+/// Check that the vCPUs and memory set in `resources` are within the limits.
+/// Errors are reported as `{field}.vcpus` and `{field}.memory_mb`.
+pub fn validate_resources(
+    errors: &mut ValidationErrors,
+    field: &str,
+    resources: &Resources,
+    limits: &RequestLimits,
+) {
+    match resources.vcpus {
+        Some(0) => errors.push(&format!("{field}.vcpus"), "must be at least 1"),
+        Some(vcpus) if vcpus > limits.max_vcpus => errors.push(
+            &format!("{field}.vcpus"),
+            format!("must be at most {}", limits.max_vcpus),
+        ),
+        _ => {}
+    }
+    match resources.memory_mb {
+        Some(memory_mb) if memory_mb < MIN_MEMORY_MB => errors.push(
+            &format!("{field}.memory_mb"),
+            format!("must be at least {}", MIN_MEMORY_MB),
+        ),
+        Some(memory_mb) if memory_mb > limits.max_memory_mb => errors.push(
+            &format!("{field}.memory_mb"),
+            format!("must be at most {}", limits.max_memory_mb),
+        ),
+        _ => {}
+    }
+}
+
+/// Check that an artifact id is a hex-encoded SHA-256 digest.
+pub fn validate_artifact_id(errors: &mut ValidationErrors, field: &str, value: &str) {
+    if value.len() != 64
+        || !value
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    {
+        errors.push(field, "must be a lowercase hex SHA-256 digest");
+    }
+}
+
+/// Check that a path stays inside the directory it is relative to:
+/// no root, no `.` or `..` component, and no empty component.
+pub fn validate_relative_path(errors: &mut ValidationErrors, field: &str, value: &str) {
+    if value.is_empty() {
+        errors.push(field, "must not be empty");
+    } else if value.starts_with('/')
+        || value.contains(['\\', '\0'])
+        || value
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..")
+    {
+        errors.push(
+            field,
+            "must be a relative path without '.' or '..' components",
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identifier_errors(value: &str) -> ValidationErrors {
+        let mut errors = ValidationErrors::default();
+        validate_identifier(&mut errors, "id", value);
+        errors
+    }
+
+    #[test]
+    fn test_valid_identifiers() {
+        assert!(identifier_errors("python").is_empty());
+        assert!(identifier_errors("job-1_a").is_empty());
+        assert!(identifier_errors("3f2a0c4e-1d2b-4c6e-9a1b-0f9e8d7c6b5a").is_empty());
+    }
+
+    #[test]
+    fn test_invalid_identifiers() {
+        assert!(!identifier_errors("").is_empty());
+        assert!(!identifier_errors("../etc/passwd").is_empty());
+        assert!(!identifier_errors("Upper").is_empty());
+        assert!(!identifier_errors("-leading").is_empty());
+        assert!(!identifier_errors("with space").is_empty());
+        assert!(!identifier_errors(&"a".repeat(MAX_IDENTIFIER_LEN + 1)).is_empty());
+    }
+
+    #[test]
+    fn test_artifact_ids() {
+        let mut errors = ValidationErrors::default();
+        validate_artifact_id(&mut errors, "artifact", &"ab".repeat(32));
+        assert!(errors.is_empty());
+
+        validate_artifact_id(&mut errors, "artifact", &"AB".repeat(32));
+        validate_artifact_id(&mut errors, "artifact", "../objects");
+        assert_eq!(errors.errors.len(), 2);
+    }
+
+    #[test]
+    fn test_relative_paths() {
+        let mut errors = ValidationErrors::default();
+        validate_relative_path(&mut errors, "entrypoint", "index.js");
+        validate_relative_path(&mut errors, "entrypoint", "src/main.rs");
+        assert!(errors.is_empty());
+
+        for path in [
+            "",
+            "/etc/passwd",
+            "../x.py",
+            "src/../../x.py",
+            "./x.py",
+            "a//b",
+        ] {
+            validate_relative_path(&mut errors, "entrypoint", path);
+        }
+        assert_eq!(errors.errors.len(), 6);
+    }
+
+    #[test]
+    fn test_code_limits() {
+        let limits = RequestLimits {
+            max_code_bytes: 8,
+            ..RequestLimits::default()
+        };
+
+        let mut errors = ValidationErrors::default();
+        validate_code(&mut errors, "code", "print(1)", &limits);
+        assert!(errors.is_empty());
+
+        validate_code(&mut errors, "code", "  \n", &limits);
+        validate_code(&mut errors, "code", "print(12)", &limits);
+        assert_eq!(errors.errors.len(), 2);
+        assert!(errors.errors.iter().all(|e| e.field == "code"));
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let limits = RequestLimits::default();
+        let mut errors = ValidationErrors::default();
+        validate_resources(&mut errors, "resources", &Resources::default(), &limits);
+        let fits = Resources {
+            vcpus: Some(limits.max_vcpus),
+            memory_mb: Some(MIN_MEMORY_MB),
+        };
+        validate_resources(&mut errors, "resources", &fits, &limits);
+        assert!(errors.is_empty());
+
+        for resources in [
+            Resources {
+                vcpus: Some(0),
+                memory_mb: Some(MIN_MEMORY_MB - 1),
+            },
+            Resources {
+                vcpus: Some(limits.max_vcpus + 1),
+                memory_mb: Some(limits.max_memory_mb + 1),
+            },
+        ] {
+            validate_resources(&mut errors, "resources", &resources, &limits);
+        }
+        let fields = errors
+            .errors
+            .iter()
+            .map(|e| e.field.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [
+                "resources.vcpus",
+                "resources.memory_mb",
+                "resources.vcpus",
+                "resources.memory_mb"
+            ]
+        );
+    }
+}
@@ -1,21 +1,187 @@
-use crate::ip_manager::IpManager;
+use crate::console::SerialConsole;
+use crate::ip_manager::{IpManager, Segment};
+use crate::redaction::{RedactingWriter, Redactor};
+use cloude_types::{JobLimits, NetworkRequest, TrafficStats};
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::net::Ipv4Addr;
-use std::path::PathBuf;
-use std::sync::atomic::Ordering;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use tracing::{debug, error, info, warn};
+use virt::serial_log::{SerialLog, SerialLogConfig};
+use virt::traffic::TrafficCounter;
+
+/// How long [`VmHandle::shutdown`] lets the guest power off by itself before stopping it.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Memory of a VM its kernel and agent keep out of reach of the code of the job.
+pub const GUEST_RESERVED_MEMORY_MB: usize = 32;
+
+/// Default processes and threads the code of a job can run at once, enough for
+/// build tools and thread pools, too few for a fork bomb to wedge the guest.
+pub const DEFAULT_JOB_PIDS_MAX: u32 = 1024;
+
+/// Default size a core dump of crashed code is cut to (16 MiB).
+pub const DEFAULT_CORE_DUMP_BYTES: u64 = 16 * 1024 * 1024;
+
+/// MTU of Ethernet, which guests get unless told otherwise.
+pub const DEFAULT_MTU: u16 = 1500;
+
+/// Address template VMs are baked with, and which the VMs restored from their
+/// snapshot wake up with, on a host-only link. Link-local: no lease or bridge has it.
+pub const TEMPLATE_GUEST_IP: Ipv4Addr = Ipv4Addr::new(169, 254, 100, 2);
+/// Address of the host on the link of a template VM.
+pub const TEMPLATE_GATEWAY: Ipv4Addr = Ipv4Addr::new(169, 254, 100, 1);
+const TEMPLATE_PREFIX_LEN: u8 = 24;
+
+/// How long a VM restored from a snapshot takes to answer on the template link.
+const TEMPLATE_LINK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The host end of the template link, which one TAP at a time can have: the one of
+/// a template VM being baked, or of a VM restored from a snapshot until it is moved.
+static TEMPLATE_LINK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// CPU the code of a job gets: every vCPU of its VM for `burst`, then
+/// `share_percent` of the vCPUs it asked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuPolicy {
+    pub burst: Duration,
+    pub share_percent: u32,
+}
+
+impl Default for CpuPolicy {
+    /// Every vCPU of the VM, all along.
+    fn default() -> Self {
+        Self {
+            burst: Duration::ZERO,
+            share_percent: 100,
+        }
+    }
+}
+
+impl CpuPolicy {
+    /// Whether the code is ever held to less than every vCPU of its VM.
+    pub fn is_enabled(&self) -> bool {
+        !self.burst.is_zero() || self.share_percent < 100
+    }
+}
+
+/// Limits of the code of a job asking for `vcpus` in a VM of `memory_mb`: the
+/// memory the guest does not keep, or half of it in the smallest VMs, `pids_max`
+/// processes and cores of `core_dump_bytes`, unless those are `0`, and its CPU
+/// as `cpu` says.
+pub fn job_limits(
+    vcpus: u8,
+    memory_mb: usize,
+    pids_max: u32,
+    core_dump_bytes: u64,
+    cpu: &CpuPolicy,
+) -> JobLimits {
+    let cpu_millis = u32::from(vcpus) * 10 * cpu.share_percent;
+    JobLimits {
+        memory_mb: Some(
+            memory_mb
+                .saturating_sub(GUEST_RESERVED_MEMORY_MB)
+                .max(memory_mb / 2),
+        ),
+        pids: Some(pids_max).filter(|&pids| pids > 0),
+        core_dump_bytes: Some(core_dump_bytes).filter(|&bytes| bytes > 0),
+        cpu_millis: Some(cpu_millis).filter(|_| cpu.is_enabled()),
+        cpu_burst_ms: Some(cpu.burst.as_millis() as u64).filter(|&ms| ms > 0),
+    }
+}
+
+/// VMs created and not dropped yet, by VM id, with the handle that stops their VMM
+/// once it runs. The janitor tells their TAP devices and IP leases from orphans.
+static LIVE_VMS: Mutex<BTreeMap<String, Option<vmm::StopHandle>>> = Mutex::new(BTreeMap::new());
+
+/// Ids of the VMs created and not dropped yet, including those still booting.
+pub fn live_vm_ids() -> Vec<String> {
+    LIVE_VMS.lock().unwrap().keys().cloned().collect()
+}
+
+/// Stops the VMM of the live VM `vm_id`, without the handle its owner keeps.
+/// Returns false when there is no such VM, or its VMM is not running yet.
+pub fn stop_live_vm(vm_id: &str) -> bool {
+    match LIVE_VMS.lock().unwrap().get(vm_id) {
+        Some(Some(stop)) => {
+            stop.stop();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Entry of a VM in [`LIVE_VMS`], removed when dropped: when the VM fails to
+/// start, or with its handle.
+struct LiveVm(String);
+
+impl LiveVm {
+    fn register(vm_id: &str) -> Self {
+        LIVE_VMS.lock().unwrap().insert(vm_id.to_string(), None);
+        Self(vm_id.to_string())
+    }
+
+    fn set_stop(&self, stop: vmm::StopHandle) {
+        LIVE_VMS.lock().unwrap().insert(self.0.clone(), Some(stop));
+    }
+}
+
+impl Drop for LiveVm {
+    fn drop(&mut self) {
+        LIVE_VMS.lock().unwrap().remove(&self.0);
+    }
+}
 
 /// Represents an active VM with allocated resources
 pub struct VmHandle {
     pub vm_id: String,
     pub ip: Ipv4Addr,
+    /// Address of the host on the link of the VM.
+    pub gateway: Ipv4Addr,
     pub tap_device: String,
     vm_thread: Option<thread::JoinHandle<()>>,
-    vmm_stop: Arc<std::sync::atomic::AtomicBool>,
+    vmm_stop: vmm::StopHandle,
+    snapshot_handle: vmm::SnapshotHandle,
+    /// `None` when the VM has no ACPI tables, see `VmConfig::acpi`.
+    power_button: Option<vmm::PowerButton>,
+    /// `None` when the VM cannot get more vCPUs, see `VmConfig::max_vcpus`.
+    vcpu_hotplug: Option<vmm::VcpuHotplug>,
+    /// `None` when the VM cannot change memory size, see `VmConfig::max_memory_mb`.
+    memory_resize: Option<vmm::MemoryResize>,
+    paging: vmm::MemoryPaging,
+    /// `None` when the traffic of the VM is not counted, see `VmConfig::traffic_accounting`.
+    traffic: Option<TrafficMeter>,
+    console: Arc<SerialConsole>,
     ip_manager: Arc<Mutex<IpManager>>,
+    /// Held by template VMs, which keep the template link until destroyed.
+    _template_link: Option<tokio::sync::MutexGuard<'static, ()>>,
+    _live: LiveVm,
+}
+
+/// What a VM is started from.
+#[derive(Clone, Copy)]
+enum Origin<'a> {
+    /// A boot of the runtime image, on a leased address.
+    Boot,
+    /// A boot of the runtime image to snapshot, on the template link.
+    Template,
+    /// A template snapshot in a local directory, moved to a leased address once restored.
+    Snapshot(&'a Path),
+    /// The snapshot of a suspended VM, which wakes up on the address it kept.
+    Suspended(&'a Path),
+}
+
+/// Handles on a VMM, sent back by its thread once the VM is configured.
+struct VmmHandles {
+    stop: vmm::StopHandle,
+    snapshot: vmm::SnapshotHandle,
+    power_button: Option<vmm::PowerButton>,
+    vcpu_hotplug: Option<vmm::VcpuHotplug>,
+    memory_resize: Option<vmm::MemoryResize>,
+    paging: vmm::MemoryPaging,
 }
 
 #[derive(Debug)]
@@ -26,6 +192,7 @@ pub enum VmError {
     VmmCreation(String),
     VmmConfiguration(String),
     AgentTimeout,
+    Snapshot(String),
     Cleanup(String),
 }
 
@@ -38,6 +205,7 @@ impl std::fmt::Display for VmError {
             VmError::VmmCreation(e) => write!(f, "VMM creation failed: {}", e),
             VmError::VmmConfiguration(e) => write!(f, "VMM configuration failed: {}", e),
             VmError::AgentTimeout => write!(f, "Agent in VM did not respond in time"),
+            VmError::Snapshot(e) => write!(f, "Snapshot failed: {}", e),
             VmError::Cleanup(e) => write!(f, "Cleanup failed: {}", e),
         }
     }
@@ -46,19 +214,66 @@ impl std::fmt::Display for VmError {
 impl std::error::Error for VmError {}
 
 /// Configuration for launching a VM
+#[derive(Clone)]
 pub struct VmConfig {
     pub kernel_path: PathBuf,
     pub initramfs_dir: PathBuf,
-    pub bridge_name: String,
+    /// Bridge the VM tap is attached to. `None` links the VM to the host only:
+    /// the tap gets the gateway address and nothing is routed beyond the host.
+    pub bridge_name: Option<String>,
+    /// Tenant the VM runs jobs of. With tenant segments, its address comes from
+    /// the segment of the tenant, see `IpManager::with_tenant_segments`.
+    pub tenant: Option<String>,
     pub vcpus: u8,
     pub memory_mb: usize,
     pub log_guest_console: bool,
+    /// Write the serial output of each VM to its own rotated log file.
+    pub serial_log: Option<SerialLogConfig>,
+    /// Applied to the serial output of the VM before it is written to the terminal
+    /// or to its log file. Readers of the console redact it themselves.
+    pub redactor: Redactor,
+    /// Kernel parameters added to the ones the VMM generates, see `vmm::VMM::append_cmdline`.
+    pub cmdline_extra: Option<String>,
+    /// Describe the VM with ACPI tables as well as the MP table, see `vmm::VMM::set_acpi`.
+    pub acpi: bool,
+    /// vCPUs a running VM can grow to, see `vmm::VMM::set_max_vcpus`. Needs `acpi`;
+    /// 0, or no more than `vcpus`, keeps VMs at their boot size.
+    pub max_vcpus: u8,
+    /// Memory a running VM can grow to, in MiB, see `vmm::VMM::add_memory_device`.
+    /// 0, or no more than `memory_mb`, keeps VMs at their boot size.
+    pub max_memory_mb: usize,
+    /// Count the traffic of each VM with eBPF programs on its TAP, see `virt::traffic`.
+    pub traffic_accounting: bool,
+    /// MTU of the link of each VM, given to the guest and to its TAP, see
+    /// `vmm::NetConfig::mtu`. Must be the one of `bridge_name`. `None` leaves
+    /// Ethernet's [`DEFAULT_MTU`].
+    pub mtu: Option<u16>,
+    /// Let kernel samepage merging share the memory of the VM with other VMs,
+    /// see `vmm::VMM::set_mergeable`.
+    pub merge_memory: bool,
+}
+
+/// Traffic counters of the TAP of a VM, shared with whoever watches the VM.
+#[derive(Clone)]
+pub struct TrafficMeter(Arc<TrafficCounter>);
+
+impl TrafficMeter {
+    /// Traffic of the VM since it booted.
+    pub fn read(&self) -> std::io::Result<TrafficStats> {
+        let counters = self.0.read()?;
+        Ok(TrafficStats {
+            rx_bytes: counters.rx_bytes,
+            rx_packets: counters.rx_packets,
+            tx_bytes: counters.tx_bytes,
+            tx_packets: counters.tx_packets,
+        })
+    }
 }
 
 /// Generate a unique tap device name from VM ID using a hash
 /// Linux interface names are limited to 15 characters (IFNAMSIZ - 1)
 /// Format: tap-{11_hex_chars} (total 15 chars)
-fn generate_tap_device_name(vm_id: &str) -> String {
+pub fn generate_tap_device_name(vm_id: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(vm_id.as_bytes());
     let hash = hasher.finalize();
@@ -68,6 +283,18 @@ fn generate_tap_device_name(vm_id: &str) -> String {
     format!("tap-{}", &hex[..11])
 }
 
+/// `sink`, behind a [`RedactingWriter`] unless `redactor` has nothing to redact.
+fn redacted<W: std::io::Write + Send + 'static>(
+    redactor: &Redactor,
+    sink: W,
+) -> Box<dyn std::io::Write + Send> {
+    if redactor.is_empty() {
+        Box::new(sink)
+    } else {
+        Box::new(RedactingWriter::new(redactor.clone(), sink))
+    }
+}
+
 impl VmHandle {
     /// Creates and starts a new VM using VMM library
     pub async fn create(
@@ -75,74 +302,164 @@ impl VmHandle {
         language: &str,
         config: &VmConfig,
         ip_manager: Arc<Mutex<IpManager>>,
+    ) -> Result<Self, VmError> {
+        Self::start(vm_id, language, config, ip_manager, Origin::Boot).await
+    }
+
+    /// Boots a VM to snapshot as the template of `language`, on the template link
+    /// rather than a leased address; see [`Self::restore`]. No other template VM
+    /// runs until it is destroyed.
+    pub async fn create_template(
+        vm_id: String,
+        language: &str,
+        config: &VmConfig,
+        ip_manager: Arc<Mutex<IpManager>>,
+    ) -> Result<Self, VmError> {
+        Self::start(vm_id, language, config, ip_manager, Origin::Template).await
+    }
+
+    /// Restores a VM from the template snapshot in `snapshot_dir`, and moves it from
+    /// the template address to a leased one. `config` must be the one of the template
+    /// VM but for its TAP, serial log and redactor, and leave no room to hotplug.
+    ///
+    /// The VM keeps the clock, RNG and processes of the template: its agent must sync
+    /// the clock and reseed the RNG before it runs a job.
+    pub async fn restore(
+        vm_id: String,
+        language: &str,
+        snapshot_dir: &Path,
+        config: &VmConfig,
+        ip_manager: Arc<Mutex<IpManager>>,
+    ) -> Result<Self, VmError> {
+        let origin = Origin::Snapshot(snapshot_dir);
+        Self::start(vm_id, language, config, ip_manager, origin).await
+    }
+
+    async fn start(
+        vm_id: String,
+        language: &str,
+        config: &VmConfig,
+        ip_manager: Arc<Mutex<IpManager>>,
+        origin: Origin<'_>,
     ) -> Result<Self, VmError> {
         info!(vm_id = %vm_id, "Creating new VM");
 
-        // Allocate IP from pool
-        let ip = {
-            let manager = ip_manager
-                .lock()
-                .map_err(|e| VmError::IpAllocation(format!("Mutex poisoned: {}", e)))?;
-            manager
-                .allocate_ip(&vm_id)
-                .map_err(|e| VmError::IpAllocation(e.to_string()))?
-        };
+        #[cfg(feature = "chaos")]
+        if let Some(fault) = crate::chaos::fire(crate::chaos::FaultKind::BootDelay, language) {
+            tokio::time::sleep(Duration::from_millis(fault.delay_ms)).await;
+        }
 
-        let ip_addr: Ipv4Addr = ip
-            .parse()
-            .map_err(|e| VmError::IpAllocation(format!("Invalid IP format: {}", e)))?;
+        // Before anything is allocated, so that the janitor never takes it for an orphan.
+        let live = LiveVm::register(&vm_id);
 
-        info!(vm_id = %vm_id, ip = %ip_addr, "Allocated IP for VM");
+        let (console, guest_input) = SerialConsole::new()
+            .map_err(|e| VmError::VmmCreation(format!("Failed to create serial console: {}", e)))?;
+
+        // A template VM has the template link to itself, and no lease.
+        let template_link = match origin {
+            Origin::Template => Some(TEMPLATE_LINK.lock().await),
+            _ => None,
+        };
+        let (ip_addr, segment) = if template_link.is_some() {
+            (TEMPLATE_GUEST_IP, None)
+        } else {
+            // Allocate IP from pool, in the segment of the tenant if tenants have their own
+            let lease = {
+                let manager = ip_manager
+                    .lock()
+                    .map_err(|e| VmError::IpAllocation(format!("Mutex poisoned: {}", e)))?;
+                manager
+                    .allocate(&vm_id, config.tenant.as_deref())
+                    .map_err(|e| VmError::IpAllocation(e.to_string()))?
+            };
+            info!(vm_id = %vm_id, ip = %lease.ip, "Allocated IP for VM");
+            (lease.ip, lease.segment)
+        };
 
         // Generate unique tap device name
         let tap_device = generate_tap_device_name(&vm_id);
         debug!(vm_id = %vm_id, tap = %tap_device, "Generated tap device name");
 
-        // Build initramfs with agent
-        let initramfs_path = match Self::build_initramfs_with_agent(language, config).await {
-            Ok(path) => path,
-            Err(e) => {
-                let _ = Self::release_ip_internal(&vm_id, &ip_manager);
-                return Err(e);
-            }
+        // A restored VM has its kernel and initramfs in the memory of the snapshot.
+        let snapshot_dir = match origin {
+            Origin::Snapshot(dir) | Origin::Suspended(dir) => Some(dir.to_path_buf()),
+            _ => None,
         };
+        let initramfs_path = if snapshot_dir.is_none() {
+            // Build initramfs with agent
+            let initramfs_path = match Self::build_initramfs_with_agent(language, config).await {
+                Ok(path) => path,
+                Err(e) => {
+                    let _ = Self::release_ip_internal(&vm_id, &ip_manager);
+                    return Err(e);
+                }
+            };
 
-        info!(vm_id = %vm_id, initramfs = %initramfs_path.display(), "Built initramfs");
+            info!(vm_id = %vm_id, initramfs = %initramfs_path.display(), "Built initramfs");
 
-        if !config.kernel_path.exists() {
-            let _ = Self::release_ip_internal(&vm_id, &ip_manager);
-            return Err(VmError::VmmConfiguration(format!(
-                "Kernel not found at {} (set VM_KERNEL_PATH)",
-                config.kernel_path.display()
-            )));
-        }
+            if !config.kernel_path.exists() {
+                let _ = Self::release_ip_internal(&vm_id, &ip_manager);
+                return Err(VmError::VmmConfiguration(format!(
+                    "Kernel not found at {} (set VM_KERNEL_PATH)",
+                    config.kernel_path.display()
+                )));
+            }
+            Some(initramfs_path)
+        } else {
+            None
+        };
 
         // Spawn VMM in a dedicated thread
-        let (vm_setup_tx, vm_setup_rx) =
-            std::sync::mpsc::channel::<Result<Arc<std::sync::atomic::AtomicBool>, VmError>>();
+        let (vm_setup_tx, vm_setup_rx) = std::sync::mpsc::channel::<Result<VmmHandles, VmError>>();
 
         let kernel_path = config.kernel_path.clone();
         let tap_device_clone = tap_device.clone();
         let vcpus = config.vcpus;
         let memory_mb = config.memory_mb;
+        let cmdline_extra = config.cmdline_extra.clone();
+        let acpi = config.acpi;
+        let max_vcpus = config.max_vcpus;
+        let max_memory_mb = config.max_memory_mb;
+        let mtu = config.mtu;
+        let merge_memory = config.merge_memory;
+        let console_output = console.writer();
         let log_guest_console = config.log_guest_console;
-        let host_ip: Ipv4Addr = (u32::from(ip_addr) - 1).into();
-        let netmask = Ipv4Addr::new(255, 255, 255, 0);
+        let redactor = config.redactor.clone();
+        // A VM without its log file still runs.
+        let serial_log = config.serial_log.as_ref().and_then(|log_config| {
+            match SerialLog::open(log_config, &vm_id) {
+                Ok(log) => Some(log),
+                Err(e) => {
+                    warn!(vm_id = %vm_id, error = %e, "Failed to open serial log");
+                    None
+                }
+            }
+        });
+        let (host_ip, prefix_len) = match &segment {
+            Some(segment) => (segment.gateway(), segment.prefix_len),
+            None if template_link.is_some() => (TEMPLATE_GATEWAY, TEMPLATE_PREFIX_LEN),
+            None => ((u32::from(ip_addr) - 1).into(), 24),
+        };
+        let netmask: Ipv4Addr = (u32::MAX << (32 - u32::from(prefix_len))).into();
+        // A VM restored from a template wakes up with the address of its template, moved
+        // below; a suspended one with its own.
+        let readdressing = matches!(origin, Origin::Snapshot(_));
+        let boot_addresses = snapshot_dir
+            .is_none()
+            .then_some((ip_addr, host_ip, netmask));
 
         let vm_thread = thread::spawn(move || {
-            // Create dummy stdin/stdout for VMM
-            let stdin = Box::new(
-                std::fs::File::open("/dev/null").expect("Failed to open /dev/null for stdin"),
-            );
-            let stdout: Box<dyn std::io::Write + Send> = if log_guest_console {
-                Box::new(std::io::stdout())
-            } else {
-                Box::new(std::io::sink())
-            };
+            // The serial port is wired to the console, which clients can attach to
+            let stdin: Box<dyn vmm::VMInput> = Box::new(guest_input);
+            let stdout: Box<dyn std::io::Write + Send> = Box::new(console_output);
             let memory_size = (memory_mb as usize) << 20; // Convert MB to bytes
 
             // Create VMM
-            let mut vmm = match vmm::VMM::new(stdin, stdout, memory_size) {
+            let created = match &snapshot_dir {
+                Some(dir) => vmm::VMM::from_snapshot(Some(stdin), stdout, dir),
+                None => vmm::VMM::new(Some(stdin), stdout, memory_size),
+            };
+            let mut vmm = match created {
                 Ok(v) => v,
                 Err(e) => {
                     let _ = vm_setup_tx.send(Err(VmError::VmmCreation(format!("{:?}", e))));
@@ -150,13 +467,35 @@ impl VmHandle {
                     return;
                 }
             };
+            // A host that cannot merge memory still runs the VM, unmerged.
+            if let Some(Err(e)) = merge_memory.then(|| vmm.set_mergeable()) {
+                warn!("Failed to make guest memory mergeable: {:?}", e);
+            }
+            if log_guest_console {
+                vmm.add_serial_sink(redacted(&redactor, std::io::stdout()));
+            }
+            if let Some(serial_log) = serial_log {
+                vmm.add_serial_sink(redacted(&redactor, serial_log));
+            }
+
+            // Before the network device, which must see the memory region it adds.
+            if let Some(Err(e)) =
+                (max_memory_mb > memory_mb).then(|| vmm.add_memory_device(max_memory_mb))
+            {
+                error!("Failed to add memory device: {:?}", e);
+                let _ = vm_setup_tx.send(Err(VmError::VmmConfiguration(format!("{:?}", e))));
+                return;
+            }
 
             // Add network device (this creates the tap device)
             if let Err(e) = vmm.add_net_device(
-                tap_device_clone.clone(),
-                Some(ip_addr),
-                Some(host_ip),
-                Some(netmask),
+                vmm::NetConfig {
+                    backend: vmm::NetBackend::Tap(tap_device_clone.clone()),
+                    mtu,
+                },
+                boot_addresses.map(|(ip, _, _)| ip),
+                boot_addresses.map(|(_, host_ip, _)| host_ip),
+                boot_addresses.map(|(_, _, netmask)| netmask),
             ) {
                 error!("Failed to add network device: {:?}", e);
                 let _ = vm_setup_tx.send(Err(VmError::NetworkSetup(format!("{:?}", e))));
@@ -165,13 +504,35 @@ impl VmHandle {
 
             info!("Network device added, tap created");
 
-            // Configure VMM with kernel and initramfs
-            if let Err(e) = vmm.configure(
-                vcpus,
-                kernel_path.to_str().unwrap(),
-                initramfs_path.to_str().unwrap(),
-                None,
-            ) {
+            // Fresh entropy for the guest, which the agent reseeds from before pooled jobs.
+            if let Err(e) = vmm.add_rng_device() {
+                error!("Failed to add entropy device: {:?}", e);
+                let _ = vm_setup_tx.send(Err(VmError::VmmConfiguration(format!("{:?}", e))));
+                return;
+            }
+
+            if let Some(Err(e)) = cmdline_extra
+                .as_deref()
+                .map(|extra| vmm.append_cmdline(extra))
+            {
+                error!("Invalid kernel parameters: {:?}", e);
+                let _ = vm_setup_tx.send(Err(VmError::VmmConfiguration(format!("{:?}", e))));
+                return;
+            }
+            vmm.set_acpi(acpi);
+            vmm.set_max_vcpus(max_vcpus);
+
+            // Configure VMM with kernel and initramfs, or put it back in the state of the snapshot
+            let configured = match &initramfs_path {
+                Some(initramfs_path) => vmm.configure(
+                    vcpus,
+                    kernel_path.to_str().unwrap(),
+                    Some(initramfs_path.to_str().unwrap()),
+                    None,
+                ),
+                None => vmm.restore(),
+            };
+            if let Err(e) = configured {
                 error!("Failed to configure VMM: {:?}", e);
                 let _ = vm_setup_tx.send(Err(VmError::VmmConfiguration(format!("{:?}", e))));
                 return;
@@ -180,21 +541,37 @@ impl VmHandle {
             info!("VMM configured, starting vCPUs");
 
             // Send signal that VMM was fully setup before running
-            let _ = vm_setup_tx.send(Ok(vmm.stop_handle()));
+            let _ = vm_setup_tx.send(Ok(VmmHandles {
+                stop: vmm.stop_handle(),
+                snapshot: vmm.snapshot_handle(),
+                power_button: vmm.power_button_handle(),
+                vcpu_hotplug: vmm.vcpu_hotplug_handle(),
+                memory_resize: vmm.memory_resize_handle(),
+                paging: vmm.paging_handle(),
+            }));
 
             // Run VMM (this blocks until VM stops)
             vmm.run();
 
-            info!("VMM stopped");
+            match vmm.failure() {
+                Some(reason) => error!("VMM stopped: {}", reason),
+                None => info!("VMM stopped"),
+            }
         });
 
         // Wait for tap device to be created
-        let vmm_stop = match vm_setup_rx.recv() {
+        let vmm_handles = match vm_setup_rx.recv() {
             Ok(Err(vm_err)) => {
+                // The thread is returning: its TAP is closed once joined, for another
+                // VM of the same id to open it.
+                let _ = vm_thread.join();
                 let _ = Self::release_ip_internal(&vm_id, &ip_manager);
                 return Err(vm_err);
             }
-            Ok(Ok(handle)) => handle,
+            Ok(Ok(handle)) => {
+                live.set_stop(handle.stop.clone());
+                handle
+            }
             Err(std::sync::mpsc::RecvError) => {
                 if vm_thread.is_finished() {
                     let _ = Self::release_ip_internal(&vm_id, &ip_manager);
@@ -209,22 +586,78 @@ impl VmHandle {
             }
         };
 
-        // Attach tap to bridge
-        if let Err(e) = virt::network::setup_guest_iface(&tap_device, &config.bridge_name).await {
-            error!(vm_id = %vm_id, "Failed to attach tap to bridge: {}", e);
+        // Move a restored VM to its address before the TAP is attached
+        if readdressing {
+            let request = NetworkRequest {
+                ip: ip_addr,
+                prefix_len,
+                gateway: host_ip,
+            };
+            if let Err(e) = Self::readdress_clone(&tap_device, request).await {
+                error!(vm_id = %vm_id, "Failed to move restored VM to its address: {}", e);
+                vmm_handles.stop.stop();
+                let _ = vm_thread.join();
+                let _ = Self::release_ip_internal(&vm_id, &ip_manager);
+                return Err(VmError::NetworkSetup(e));
+            }
+        }
+
+        // Keep the segment of the VM apart from the others before attaching it
+        let link = match (&config.bridge_name, &segment) {
+            (Some(bridge_name), Some(segment)) => Self::setup_segment(bridge_name, segment)
+                .await
+                .map_err(|e| e.to_string()),
+            _ => Ok(()),
+        };
+        // Attach tap to bridge, or give it the gateway address for a host-only link
+        let link = match link {
+            Ok(()) => match &config.bridge_name {
+                Some(bridge_name) if template_link.is_none() => {
+                    virt::network::setup_guest_iface(&tap_device, bridge_name).await
+                }
+                _ => virt::network::setup_host_link(&tap_device, host_ip, prefix_len).await,
+            }
+            .map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = link {
+            error!(vm_id = %vm_id, "Failed to set up tap link: {}", e);
             let _ = Self::release_ip_internal(&vm_id, &ip_manager);
             return Err(VmError::NetworkSetup(e.to_string()));
         }
 
         info!(vm_id = %vm_id, "Network setup complete");
 
+        // A VM whose traffic cannot be counted runs all the same
+        let traffic = if config.traffic_accounting {
+            match TrafficCounter::attach(&tap_device) {
+                Ok(counter) => Some(TrafficMeter(Arc::new(counter))),
+                Err(e) => {
+                    warn!(vm_id = %vm_id, "Cannot count the traffic of {}: {}", tap_device, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
         let mut handle = VmHandle {
             vm_id: vm_id.clone(),
             ip: ip_addr,
+            gateway: host_ip,
             tap_device,
             vm_thread: Some(vm_thread),
-            vmm_stop,
+            vmm_stop: vmm_handles.stop,
+            snapshot_handle: vmm_handles.snapshot,
+            power_button: vmm_handles.power_button,
+            vcpu_hotplug: vmm_handles.vcpu_hotplug,
+            memory_resize: vmm_handles.memory_resize,
+            paging: vmm_handles.paging,
+            traffic,
+            console,
             ip_manager,
+            _template_link: template_link,
+            _live: live,
         };
 
         // Wait for agent to be ready
@@ -234,11 +667,21 @@ impl VmHandle {
         }
 
         info!(vm_id = %vm_id, ip = %ip_addr, "VM is ready with agent responding");
+
+        #[cfg(feature = "chaos")]
+        if let Some(fault) = crate::chaos::fire(crate::chaos::FaultKind::KillVm, language) {
+            let vmm_stop = handle.vmm_stop.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(fault.delay_ms)).await;
+                vmm_stop.stop();
+            });
+        }
+
         Ok(handle)
     }
 
     /// Build initramfs with embedded agent binary
-    async fn build_initramfs_with_agent(
+    pub(crate) async fn build_initramfs_with_agent(
         language: &str,
         config: &VmConfig,
     ) -> Result<PathBuf, VmError> {
@@ -326,7 +769,7 @@ impl VmHandle {
         let start = std::time::Instant::now();
         let mut attempt = 1_u32;
         while start.elapsed() < Duration::from_secs(30) {
-            if !self.vmm_stop.load(Ordering::SeqCst) {
+            if !self.vmm_stop.is_running() {
                 error!(
                     vm_id = %self.vm_id,
                     "VM exited before agent became ready (check guest console logs above)"
@@ -359,18 +802,197 @@ impl VmHandle {
         Err(VmError::AgentTimeout)
     }
 
+    /// Moves a VM restored from a template snapshot from the template address to
+    /// the one of `request`, with the template link to itself for the time it takes.
+    async fn readdress_clone(tap_device: &str, request: NetworkRequest) -> Result<(), String> {
+        let _link = TEMPLATE_LINK.lock().await;
+        virt::network::setup_host_link(tap_device, TEMPLATE_GATEWAY, TEMPLATE_PREFIX_LEN)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let moved = Self::request_readdress(&request).await;
+        // Whether moved or not, for the next VM to get the template link.
+        let released = virt::network::remove_host_link_address(
+            tap_device,
+            TEMPLATE_GATEWAY,
+            TEMPLATE_PREFIX_LEN,
+        )
+        .await
+        .map_err(|e| e.to_string());
+        moved.and(released)
+    }
+
+    /// Asks the agent at the template address to move the guest, until it answers.
+    async fn request_readdress(request: &NetworkRequest) -> Result<(), String> {
+        let network_url = format!("http://{}:3001/network", TEMPLATE_GUEST_IP);
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(500))
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let start = std::time::Instant::now();
+        loop {
+            match client.post(&network_url).json(request).send().await {
+                Ok(resp) if resp.status().is_success() => return Ok(()),
+                Ok(resp) => {
+                    let status = resp.status();
+                    let body = resp.text().await.unwrap_or_default();
+                    return Err(format!("Agent returned HTTP {}: {}", status, body));
+                }
+                Err(e) if start.elapsed() >= TEMPLATE_LINK_TIMEOUT => {
+                    return Err(format!("Agent not reachable on the template link: {}", e));
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        }
+    }
+
     /// Get the agent URL for this VM
     pub fn agent_url(&self) -> String {
         format!("http://{}:3001", self.ip)
     }
 
+    /// Stops the VMM, and tells whether the VM still runs, whoever stopped it.
+    pub fn stop_handle(&self) -> vmm::StopHandle {
+        self.vmm_stop.clone()
+    }
+
+    /// Serial console of the guest.
+    pub fn console(&self) -> &Arc<SerialConsole> {
+        &self.console
+    }
+
+    /// Adds vCPUs to the running guest, `None` when it cannot get more.
+    pub fn vcpu_hotplug(&self) -> Option<&vmm::VcpuHotplug> {
+        self.vcpu_hotplug.as_ref()
+    }
+
+    /// Resizes the memory of the running guest, `None` when it has a fixed size.
+    pub fn memory_resize(&self) -> Option<&vmm::MemoryResize> {
+        self.memory_resize.as_ref()
+    }
+
+    /// Pages the memory of the guest out to swap and back in.
+    pub fn paging(&self) -> &vmm::MemoryPaging {
+        &self.paging
+    }
+
+    /// Counts the traffic of the guest, `None` when it is not counted.
+    pub fn traffic(&self) -> Option<&TrafficMeter> {
+        self.traffic.as_ref()
+    }
+
+    /// Whether the guest still has the memory it booted with and `vcpus` vCPUs,
+    /// i.e. nothing resized it while it ran.
+    pub fn has_boot_shape(&self, vcpus: u8) -> bool {
+        self.vcpu_hotplug
+            .as_ref()
+            .is_none_or(|hotplug| hotplug.vcpu_count() == vcpus)
+            && self.memory_resize.as_ref().is_none_or(|resize| {
+                resize.requested_mib() == resize.boot_mib()
+                    && resize.plugged_mib() == resize.boot_mib()
+            })
+    }
+
+    /// Snapshot guest memory and vCPU state into `dir` while the VM keeps its resources.
+    /// The guest is paused for the duration of the snapshot, then resumed.
+    pub async fn snapshot(&self, dir: &Path) -> Result<(), VmError> {
+        let handle = self.snapshot_handle.clone();
+        let dir = dir.to_path_buf();
+        tokio::task::spawn_blocking(move || handle.snapshot(&dir))
+            .await
+            .map_err(|e| VmError::Snapshot(e.to_string()))?
+            .map_err(|e| VmError::Snapshot(format!("{:?}", e)))
+    }
+
+    /// Whether the VM can be suspended: it has no room to hotplug, which snapshots
+    /// do not keep.
+    pub fn can_suspend(&self) -> bool {
+        self.vcpu_hotplug.is_none() && self.memory_resize.is_none()
+    }
+
+    /// Snapshots the VM into `dir` and stops its VMM, which gives the host its
+    /// memory back. The VM keeps its id and its address until resumed with
+    /// [`Self::resume`], or dropped. A VM that cannot be snapshotted is destroyed.
+    pub async fn suspend(mut self, dir: &Path) -> Result<SuspendedVm, VmError> {
+        if let Err(e) = self.snapshot(dir).await {
+            self.destroy().await;
+            let _ = tokio::fs::remove_dir_all(dir).await;
+            return Err(e);
+        }
+        self.vmm_stop.stop();
+        self.console.close();
+        if let Some(thread) = self.vm_thread.take() {
+            let _ = thread.join();
+        }
+        let vm_id = self.vm_id.clone();
+        let ip_manager = Arc::clone(&self.ip_manager);
+        // Before the VM is live again, as a suspended one.
+        drop(self);
+        info!(vm_id = %vm_id, "VM suspended");
+        Ok(SuspendedVm {
+            live: Some(LiveVm::register(&vm_id)),
+            vm_id,
+            snapshot_dir: dir.to_path_buf(),
+            ip_manager,
+        })
+    }
+
+    /// Restores `suspended` on the address it kept, with a new TAP and VMM.
+    /// `config` must be the one of the VM suspended but for its TAP, serial log
+    /// and redactor, and leave no room to hotplug.
+    ///
+    /// The VM keeps its clock and RNG state of the time it was suspended: its agent
+    /// must sync the clock and reseed the RNG before it runs a job.
+    pub async fn resume(
+        mut suspended: SuspendedVm,
+        language: &str,
+        config: &VmConfig,
+    ) -> Result<Self, VmError> {
+        // The restored VM is live under the same id, with the same lease.
+        drop(suspended.live.take());
+        let vm_id = suspended.vm_id.clone();
+        let ip_manager = Arc::clone(&suspended.ip_manager);
+        let origin = Origin::Suspended(&suspended.snapshot_dir);
+        let resumed = Self::start(vm_id, language, config, ip_manager, origin).await;
+        // The memory of the snapshot is mapped, and stays readable once its files are removed.
+        drop(suspended);
+        resumed
+    }
+
+    /// Ask the guest to shut down cleanly with the ACPI power button, then destroy the VM.
+    ///
+    /// The guest gets up to `grace` to finish its work, flush its filesystems and power
+    /// off. Without a power button, or when it does not power off in time, the vCPUs
+    /// are stopped like [`Self::destroy`] does.
+    pub async fn shutdown(&mut self, grace: Duration) {
+        if let Some(power_button) = &self.power_button {
+            match power_button.press() {
+                Ok(()) => {
+                    let deadline = tokio::time::Instant::now() + grace;
+                    while !self.vm_thread.as_ref().is_none_or(|t| t.is_finished()) {
+                        if tokio::time::Instant::now() >= deadline {
+                            warn!(vm_id = %self.vm_id, "Guest did not power off in time");
+                            break;
+                        }
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                    }
+                }
+                Err(e) => {
+                    warn!(vm_id = %self.vm_id, error = %e, "Failed to press the power button")
+                }
+            }
+        }
+        self.destroy().await;
+    }
+
     /// Destroy the VM and cleanup all resources
     pub async fn destroy(&mut self) {
         info!(vm_id = %self.vm_id, "Destroying VM");
 
         // Signal VMM to stop
-        self.vmm_stop
-            .store(false, std::sync::atomic::Ordering::SeqCst);
+        self.vmm_stop.stop();
+        self.console.close();
 
         // Wait for VMM thread to finish
         if let Some(thread) = self.vm_thread.take() {
@@ -407,12 +1029,100 @@ impl VmHandle {
         manager.release_ip(vm_id).map_err(|e| e.to_string())?;
         Ok(())
     }
+
+    /// Gives the bridge the gateway address of `segment`, and drops what the VMs
+    /// of the segment send to the other segments of the supernet.
+    async fn setup_segment(
+        bridge_name: &str,
+        segment: &Segment,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Segment 0 is the start of the supernet: its gateway is the bridge address.
+        if segment.index != 0 {
+            virt::network::setup_segment_gateway(
+                bridge_name,
+                segment.gateway(),
+                segment.prefix_len,
+            )
+            .await?;
+        }
+        virt::network::isolate_segment(
+            segment.network,
+            segment.prefix_len,
+            segment.supernet,
+            segment.supernet_len,
+        )?;
+        Ok(())
+    }
 }
 
 impl Drop for VmHandle {
     fn drop(&mut self) {
         // Ensure VM is stopped when handle is dropped
-        self.vmm_stop
-            .store(false, std::sync::atomic::Ordering::SeqCst);
+        self.vmm_stop.stop();
+    }
+}
+
+/// A VM suspended to disk, see [`VmHandle::suspend`]: its snapshot, and the lease
+/// of its address. Its VMM is gone, with the memory it held.
+pub struct SuspendedVm {
+    pub vm_id: String,
+    snapshot_dir: PathBuf,
+    ip_manager: Arc<Mutex<IpManager>>,
+    /// Keeps the lease of the VM from the janitor until it is resumed.
+    live: Option<LiveVm>,
+}
+
+impl Drop for SuspendedVm {
+    fn drop(&mut self) {
+        // A VM dropped rather than resumed is gone, with its address.
+        if self.live.take().is_some()
+            && let Err(e) = VmHandle::release_ip_internal(&self.vm_id, &self.ip_manager)
+        {
+            error!(vm_id = %self.vm_id, error = %e, "Failed to release IP");
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.snapshot_dir) {
+            warn!(vm_id = %self.vm_id, error = %e, "Failed to remove the snapshot of the VM");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_limits() {
+        let cpu = CpuPolicy::default();
+        let limits = job_limits(1, 512, DEFAULT_JOB_PIDS_MAX, DEFAULT_CORE_DUMP_BYTES, &cpu);
+        assert_eq!(limits.memory_mb, Some(512 - GUEST_RESERVED_MEMORY_MB));
+        assert_eq!(limits.pids, Some(DEFAULT_JOB_PIDS_MAX));
+        assert_eq!(limits.core_dump_bytes, Some(DEFAULT_CORE_DUMP_BYTES));
+        assert_eq!(limits.cpu_millis, None);
+        assert_eq!(limits.cpu_burst_ms, None);
+
+        // The smallest VMs still leave the code half of their memory.
+        let limits = job_limits(1, 48, 0, 0, &cpu);
+        assert_eq!(limits.memory_mb, Some(24));
+        assert_eq!(limits.pids, None);
+        assert_eq!(limits.core_dump_bytes, None);
+    }
+
+    #[test]
+    fn test_job_cpu_limits() {
+        let burst = CpuPolicy {
+            burst: Duration::from_secs(2),
+            share_percent: 100,
+        };
+        let limits = job_limits(2, 512, 0, 0, &burst);
+        assert_eq!(limits.cpu_millis, Some(2000));
+        assert_eq!(limits.cpu_burst_ms, Some(2000));
+
+        let share = CpuPolicy {
+            burst: Duration::ZERO,
+            share_percent: 50,
+        };
+        let limits = job_limits(1, 512, 0, 0, &share);
+        assert_eq!(limits.cpu_millis, Some(500));
+        assert_eq!(limits.cpu_burst_ms, None);
     }
 }
@@ -0,0 +1,647 @@
+//! Warm VMs kept after their job, for the next `pooled-vm` job of the same tenant.
+//!
+//! A VM is only handed to a job of the same tenant and runtime, with at least
+//! the vCPUs and memory the job asks for: the smallest such VM goes first.
+//! Tenants never share a VM, jobs of one tenant do. VMs left idle too long are
+//! handed back to the caller to shut down.
+//!
+//! Idle VMs are grouped in buckets by runtime and VM shape. The pool size is
+//! split between the buckets by their share of the recent requests: beyond the
+//! pool size, a VM of the bucket furthest above its share is shut down first.
+//!
+//! VMs can also be booted ahead of the jobs expected to claim them, see
+//! `prewarm`. They are speculative until a job claims them, and only fill room
+//! the pool has: they never push out a VM that ran a job.
+//!
+//! VMs idle for long enough can go cold, see `with_cold_after`: their memory is
+//! paged out to swap until a job claims them, so the pool can hold more VMs
+//! than the host has memory for.
+//!
+//! VMs idle for longer can be suspended to disk, see `with_suspend_after`: they
+//! leave the pool for the caller to snapshot and stop, and to keep in a pool of
+//! suspended VMs, which gives the host all their memory back.
+//!
+//! A VM scrubbed after its job, see `put_scrubbed`, is a new one that ran no job:
+//! it serves jobs of any tenant.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_POOL_MAX_IDLE: usize = 4;
+pub const DEFAULT_POOL_IDLE_SECS: u64 = 300;
+pub const DEFAULT_POOL_MAX_SUSPENDED: usize = 8;
+pub const DEFAULT_POOL_SUSPENDED_IDLE_SECS: u64 = 3600;
+
+/// Requests the share of each bucket is computed over, the latest ones.
+const DEMAND_WINDOW: usize = 128;
+
+/// What a pooled VM can be reused for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    /// Caller the VM ran jobs for, see `X-Cloude-User`.
+    pub tenant: String,
+    pub language: String,
+    pub vcpus: u8,
+    pub memory_mb: usize,
+}
+
+impl PoolKey {
+    pub fn bucket(&self) -> Bucket {
+        Bucket {
+            language: self.language.clone(),
+            vcpus: self.vcpus,
+            memory_mb: self.memory_mb,
+        }
+    }
+
+    /// Whether a VM with this key can run a job asking for `request`.
+    fn fits(&self, request: &PoolKey) -> bool {
+        self.tenant == request.tenant && self.fits_shape(request)
+    }
+
+    /// Whether a VM with this key can run a job asking for `request`, whatever its tenant.
+    fn fits_shape(&self, request: &PoolKey) -> bool {
+        self.language == request.language
+            && self.vcpus >= request.vcpus
+            && self.memory_mb >= request.memory_mb
+    }
+}
+
+/// Runtime and VM shape of pooled VMs, whatever their tenant.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Bucket {
+    pub language: String,
+    pub vcpus: u8,
+    pub memory_mb: usize,
+}
+
+/// How a bucket is used, for `GET /metrics`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BucketStats {
+    pub bucket: Bucket,
+    /// VMs waiting for a job.
+    pub idle: usize,
+    /// Idle VMs it may keep when the pool is full, from its share of the recent requests.
+    pub target: usize,
+    /// Jobs that asked for a VM of this shape.
+    pub requests: u64,
+    /// Those that got an idle VM, of this shape or a larger one.
+    pub hits: u64,
+    /// Idle VMs booted ahead of a forecast, that ran no job yet.
+    pub speculative: usize,
+    /// Idle VMs whose memory was paged out.
+    pub cold: usize,
+    /// VMs of this shape scrubbed after their job, and the time it took them.
+    pub scrubs: u64,
+    pub scrub_time: Duration,
+}
+
+struct IdleVm<T> {
+    key: PoolKey,
+    vm: T,
+    since: Instant,
+    speculative: bool,
+    cold: bool,
+    /// Ran no job since it was scrubbed, and serves any tenant.
+    scrubbed: bool,
+}
+
+impl<T> IdleVm<T> {
+    fn serves(&self, request: &PoolKey) -> bool {
+        self.key.fits(request) || self.scrubbed && self.key.fits_shape(request)
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Counters {
+    requests: u64,
+    hits: u64,
+    scrubs: u64,
+    scrub_time: Duration,
+}
+
+struct PoolState<T> {
+    idle: Vec<IdleVm<T>>,
+    /// Buckets of the latest requests, the oldest first.
+    demand: VecDeque<Bucket>,
+    counters: BTreeMap<Bucket, Counters>,
+}
+
+pub struct VmPool<T> {
+    state: Mutex<PoolState<T>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+    cold_after: Option<Duration>,
+    suspend_after: Option<Duration>,
+}
+
+impl<T> VmPool<T> {
+    /// A pool of up to `max_idle` VMs, each kept `idle_timeout` at most between jobs.
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        Self {
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                demand: VecDeque::new(),
+                counters: BTreeMap::new(),
+            }),
+            max_idle,
+            idle_timeout,
+            cold_after: None,
+            suspend_after: None,
+        }
+    }
+
+    /// Lets VMs idle for `after` go cold, see `cool`.
+    pub fn with_cold_after(mut self, after: Duration) -> Self {
+        self.cold_after = Some(after);
+        self
+    }
+
+    /// Lets VMs idle for `after` be suspended, see `suspend`.
+    pub fn with_suspend_after(mut self, after: Duration) -> Self {
+        self.suspend_after = Some(after);
+        self
+    }
+
+    /// Whether VMs are pooled at all.
+    pub fn is_enabled(&self) -> bool {
+        self.max_idle > 0
+    }
+
+    /// The smallest idle VM that can run a job asking for `key`, with the key it
+    /// goes back to the pool with. Of VMs of the same shape, the one that ran a
+    /// job last.
+    pub fn take(&self, key: &PoolKey) -> Option<(PoolKey, T)> {
+        let mut state = self.lock();
+        let bucket = key.bucket();
+        state.demand.push_back(bucket.clone());
+        if state.demand.len() > DEMAND_WINDOW {
+            state.demand.pop_front();
+        }
+        let position = state
+            .idle
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.serves(key))
+            .min_by_key(|(_, entry)| {
+                (
+                    entry.key.memory_mb,
+                    entry.key.vcpus,
+                    std::cmp::Reverse(entry.since),
+                )
+            })
+            .map(|(position, _)| position);
+        let counters = state.counters.entry(bucket).or_default();
+        counters.requests += 1;
+        counters.hits += u64::from(position.is_some());
+        let entry = state.idle.remove(position?);
+        // A scrubbed VM becomes one of the tenant it runs a job for.
+        let vm_key = PoolKey {
+            tenant: key.tenant.clone(),
+            ..entry.key
+        };
+        Some((vm_key, entry.vm))
+    }
+
+    /// Keeps `vm` for the next job that fits `key`. Returns the VMs to shut down:
+    /// the ones idle for too long, and beyond the pool size, the oldest ones of
+    /// the buckets furthest above their target.
+    pub fn put(&self, key: PoolKey, vm: T) -> Vec<T> {
+        self.keep(key, vm, false, false)
+    }
+
+    /// Keeps `vm`, booted for the jobs expected to ask for `key`. Returns the VMs
+    /// to shut down, as `put` does.
+    pub fn prewarm(&self, key: PoolKey, vm: T) -> Vec<T> {
+        self.keep(key, vm, true, false)
+    }
+
+    /// Keeps `vm`, which took the place of a VM of `key` that ran a job and
+    /// `took` to be ready, for the next job of any tenant that fits the shape of
+    /// `key`. Returns the VMs to shut down, as `put` does.
+    pub fn put_scrubbed(&self, key: PoolKey, vm: T, took: Duration) -> Vec<T> {
+        {
+            let mut state = self.lock();
+            let counters = state.counters.entry(key.bucket()).or_default();
+            counters.scrubs += 1;
+            counters.scrub_time += took;
+        }
+        self.keep(key, vm, false, true)
+    }
+
+    fn keep(&self, key: PoolKey, vm: T, speculative: bool, scrubbed: bool) -> Vec<T> {
+        let mut evicted = self.expire();
+        let mut state = self.lock();
+        state.idle.push(IdleVm {
+            key,
+            vm,
+            since: Instant::now(),
+            speculative,
+            cold: false,
+            scrubbed,
+        });
+        while state.idle.len() > self.max_idle {
+            let targets = targets(&state.demand, self.max_idle);
+            let mut idle: BTreeMap<Bucket, usize> = BTreeMap::new();
+            for entry in &state.idle {
+                *idle.entry(entry.key.bucket()).or_default() += 1;
+            }
+            // The idle VMs are in the order they came back: the first of a bucket is its oldest.
+            let position = state
+                .idle
+                .iter()
+                .enumerate()
+                .max_by_key(|(position, entry)| {
+                    let bucket = entry.key.bucket();
+                    let above = idle[&bucket] as isize
+                        - targets.get(&bucket).copied().unwrap_or(0) as isize;
+                    (above, std::cmp::Reverse(*position))
+                })
+                .map(|(position, _)| position)
+                .expect("the pool is not empty");
+            evicted.push(state.idle.remove(position).vm);
+        }
+        evicted
+    }
+
+    /// Removes the VMs idle for longer than the timeout, and returns them to shut down.
+    pub fn expire(&self) -> Vec<T> {
+        let mut state = self.lock();
+        let (expired, kept) = state
+            .idle
+            .drain(..)
+            .partition::<Vec<_>, _>(|entry| entry.since.elapsed() >= self.idle_timeout);
+        state.idle = kept;
+        expired.into_iter().map(|entry| entry.vm).collect()
+    }
+
+    /// Marks the VMs idle for long enough as cold, and returns `f` of each, for
+    /// the caller to page their memory out. VMs stay in the pool meanwhile.
+    pub fn cool<R>(&self, f: impl Fn(&T) -> R) -> Vec<R> {
+        let Some(after) = self.cold_after else {
+            return Vec::new();
+        };
+        self.lock()
+            .idle
+            .iter_mut()
+            .filter(|entry| !entry.cold && entry.since.elapsed() >= after)
+            .map(|entry| {
+                entry.cold = true;
+                f(&entry.vm)
+            })
+            .collect()
+    }
+
+    /// Removes the VMs idle for long enough to be suspended, of those `can_suspend`,
+    /// and returns them with their key, for the caller to suspend.
+    pub fn suspend(&self, can_suspend: impl Fn(&T) -> bool) -> Vec<(PoolKey, T)> {
+        let Some(after) = self.suspend_after else {
+            return Vec::new();
+        };
+        let mut state = self.lock();
+        let (due, kept) = state.idle.drain(..).partition::<Vec<_>, _>(|entry| {
+            entry.since.elapsed() >= after && can_suspend(&entry.vm)
+        });
+        state.idle = kept;
+        due.into_iter().map(|entry| (entry.key, entry.vm)).collect()
+    }
+
+    /// Removes an idle VM to shut down, to give the host its memory back: of
+    /// the VMs still in memory, a speculative one if any, else the one idle for
+    /// the longest. Cold VMs hold little memory, they go last.
+    pub fn shed(&self) -> Option<T> {
+        let mut state = self.lock();
+        let position = state
+            .idle
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, entry)| (entry.cold, !entry.speculative, entry.since))
+            .map(|(position, _)| position)?;
+        Some(state.idle.remove(position).vm)
+    }
+
+    /// `f` of every VM waiting for a job.
+    pub fn map_idle<R>(&self, f: impl Fn(&T) -> R) -> Vec<R> {
+        self.lock().idle.iter().map(|entry| f(&entry.vm)).collect()
+    }
+
+    /// Every bucket that was asked for or holds an idle VM, ordered by runtime then shape.
+    pub fn buckets(&self) -> Vec<BucketStats> {
+        let state = self.lock();
+        let targets = targets(&state.demand, self.max_idle);
+        let mut stats = BTreeMap::new();
+        for (bucket, counters) in &state.counters {
+            let stat = bucket_stats(&mut stats, &targets, bucket);
+            stat.requests = counters.requests;
+            stat.hits = counters.hits;
+            stat.scrubs = counters.scrubs;
+            stat.scrub_time = counters.scrub_time;
+        }
+        for entry in &state.idle {
+            let stat = bucket_stats(&mut stats, &targets, &entry.key.bucket());
+            stat.idle += 1;
+            stat.speculative += usize::from(entry.speculative);
+            stat.cold += usize::from(entry.cold);
+        }
+        stats.into_values().collect()
+    }
+
+    /// VMs waiting for a job.
+    pub fn len(&self) -> usize {
+        self.lock().idle.len()
+    }
+
+    /// Idle VMs that can run a job asking for `key`.
+    pub fn idle_for(&self, key: &PoolKey) -> usize {
+        self.lock()
+            .idle
+            .iter()
+            .filter(|entry| entry.serves(key))
+            .count()
+    }
+
+    /// Idle VMs booted ahead of a forecast, that ran no job yet.
+    pub fn speculative(&self) -> usize {
+        self.lock()
+            .idle
+            .iter()
+            .filter(|entry| entry.speculative)
+            .count()
+    }
+
+    /// VMs the pool can take before it is full.
+    pub fn room(&self) -> usize {
+        self.max_idle.saturating_sub(self.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolState<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The stats of `bucket` in `stats`, added without requests nor idle VMs.
+fn bucket_stats<'a>(
+    stats: &'a mut BTreeMap<Bucket, BucketStats>,
+    targets: &BTreeMap<Bucket, usize>,
+    bucket: &Bucket,
+) -> &'a mut BucketStats {
+    stats.entry(bucket.clone()).or_insert_with(|| BucketStats {
+        bucket: bucket.clone(),
+        idle: 0,
+        target: targets.get(bucket).copied().unwrap_or(0),
+        requests: 0,
+        hits: 0,
+        speculative: 0,
+        cold: 0,
+        scrubs: 0,
+        scrub_time: Duration::ZERO,
+    })
+}
+
+/// Share of `max_idle` of each bucket in `demand`, rounded so that the shares
+/// add up to `max_idle`: the largest remainders get the VMs left over.
+fn targets(demand: &VecDeque<Bucket>, max_idle: usize) -> BTreeMap<Bucket, usize> {
+    let mut requests: BTreeMap<&Bucket, usize> = BTreeMap::new();
+    for bucket in demand {
+        *requests.entry(bucket).or_default() += 1;
+    }
+    let total = demand.len().max(1);
+    let mut targets: Vec<(&Bucket, usize, usize)> = requests
+        .into_iter()
+        .map(|(bucket, count)| {
+            let share = count * max_idle;
+            (bucket, share / total, share % total)
+        })
+        .collect();
+    let mut left = max_idle.saturating_sub(targets.iter().map(|(_, target, _)| target).sum());
+    let mut by_remainder: Vec<usize> = (0..targets.len()).collect();
+    by_remainder.sort_by_key(|&i| std::cmp::Reverse(targets[i].2));
+    for i in by_remainder {
+        if left == 0 || targets[i].2 == 0 {
+            break;
+        }
+        targets[i].1 += 1;
+        left -= 1;
+    }
+    targets
+        .into_iter()
+        .map(|(bucket, target, _)| (bucket.clone(), target))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(tenant: &str) -> PoolKey {
+        PoolKey {
+            tenant: tenant.to_string(),
+            language: "python".to_string(),
+            vcpus: 1,
+            memory_mb: 512,
+        }
+    }
+
+    fn sized(tenant: &str, vcpus: u8, memory_mb: usize) -> PoolKey {
+        PoolKey {
+            vcpus,
+            memory_mb,
+            ..key(tenant)
+        }
+    }
+
+    #[test]
+    fn test_vms_stay_with_their_tenant() {
+        let pool = VmPool::new(4, Duration::from_secs(60));
+        assert!(pool.put(key("alice"), 1).is_empty());
+        assert!(pool.put(key("bob"), 2).is_empty());
+
+        assert_eq!(pool.take(&key("carol")), None);
+        assert_eq!(pool.take(&key("alice")), Some((key("alice"), 1)));
+        assert_eq!(pool.take(&key("alice")), None);
+
+        let larger = sized("bob", 1, 1024);
+        assert_eq!(pool.take(&larger), None);
+        assert_eq!(pool.take(&key("bob")), Some((key("bob"), 2)));
+    }
+
+    #[test]
+    fn test_smallest_adequate_vm_is_claimed() {
+        let pool = VmPool::new(4, Duration::from_secs(60));
+        pool.put(sized("alice", 4, 2048), 1);
+        pool.put(sized("alice", 2, 1024), 2);
+        pool.put(sized("alice", 1, 1024), 3);
+
+        // Too small for it: 2 vCPUs are asked for.
+        assert_eq!(
+            pool.take(&sized("alice", 2, 512)),
+            Some((sized("alice", 2, 1024), 2))
+        );
+        assert_eq!(pool.take(&key("alice")), Some((sized("alice", 1, 1024), 3)));
+        assert_eq!(pool.take(&sized("alice", 8, 512)), None);
+
+        let stats = pool.buckets();
+        let small = stats
+            .iter()
+            .find(|s| s.bucket == key("alice").bucket())
+            .unwrap();
+        assert_eq!((small.requests, small.hits, small.idle), (1, 1, 0));
+        let large = stats
+            .iter()
+            .find(|s| s.bucket == sized("alice", 4, 2048).bucket())
+            .unwrap();
+        assert_eq!((large.requests, large.hits, large.idle), (0, 0, 1));
+    }
+
+    #[test]
+    fn test_full_pool_evicts_oldest() {
+        let pool = VmPool::new(2, Duration::from_secs(60));
+        pool.put(key("alice"), 1);
+        pool.put(key("alice"), 2);
+        assert_eq!(pool.put(key("alice"), 3), vec![1]);
+        // The most recently used VM goes first.
+        assert_eq!(pool.take(&key("alice")), Some((key("alice"), 3)));
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn test_full_pool_follows_demand() {
+        let pool = VmPool::new(2, Duration::from_secs(60));
+        let large = sized("alice", 2, 2048);
+        // Small VMs are asked for three times as often as large ones.
+        for _ in 0..3 {
+            pool.take(&key("alice"));
+        }
+        pool.take(&large);
+        assert_eq!(
+            pool.buckets().iter().map(|s| s.target).collect::<Vec<_>>(),
+            vec![2, 0]
+        );
+
+        pool.put(key("alice"), 1);
+        pool.put(large.clone(), 2);
+        // The large VM goes, though the small one is older.
+        assert_eq!(pool.put(key("alice"), 3), vec![2]);
+
+        // Once large VMs are all that is asked for, small ones go first.
+        for _ in 0..DEMAND_WINDOW {
+            pool.take(&sized("bob", 2, 2048));
+        }
+        assert_eq!(pool.put(large, 4), vec![1]);
+    }
+
+    #[test]
+    fn test_prewarmed_vms_are_speculative_until_claimed() {
+        let pool = VmPool::new(2, Duration::from_secs(60));
+        assert!(pool.prewarm(key("function:resize"), 1).is_empty());
+        assert_eq!(pool.room(), 1);
+        assert_eq!(pool.idle_for(&key("function:resize")), 1);
+        assert_eq!(pool.idle_for(&key("alice")), 0);
+        assert_eq!(pool.speculative(), 1);
+        assert_eq!(pool.buckets()[0].speculative, 1);
+
+        let (vm_key, vm) = pool.take(&key("function:resize")).unwrap();
+        pool.put(vm_key, vm);
+        assert_eq!(pool.speculative(), 0);
+        assert_eq!(pool.buckets()[0].hits, 1);
+    }
+
+    #[test]
+    fn test_scrubbed_vms_serve_any_tenant() {
+        let pool = VmPool::new(4, Duration::from_secs(60));
+        pool.put(key("alice"), 1);
+        pool.put_scrubbed(key("alice"), 2, Duration::from_millis(300));
+        assert_eq!(pool.idle_for(&key("bob")), 1);
+
+        // The scrubbed VM becomes one of bob's, as it goes back to the pool.
+        assert_eq!(pool.take(&key("bob")), Some((key("bob"), 2)));
+        assert_eq!(pool.take(&key("bob")), None);
+        assert_eq!(pool.take(&sized("carol", 2, 512)), None);
+
+        pool.put_scrubbed(key("bob"), 3, Duration::from_millis(200));
+        let stats = &pool.buckets()[0];
+        assert_eq!(stats.scrubs, 2);
+        assert_eq!(stats.scrub_time, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_shed_speculative_vms_first() {
+        let pool = VmPool::new(4, Duration::from_secs(60));
+        pool.put(key("alice"), 1);
+        pool.put(key("bob"), 2);
+        pool.prewarm(key("function:resize"), 3);
+        assert_eq!(pool.shed(), Some(3));
+        assert_eq!(pool.shed(), Some(1));
+        assert_eq!(pool.shed(), Some(2));
+        assert_eq!(pool.shed(), None);
+    }
+
+    #[test]
+    fn test_idle_vms_go_cold() {
+        let pool = VmPool::new(4, Duration::from_secs(60));
+        pool.put(key("alice"), 1);
+        assert!(pool.cool(|vm| *vm).is_empty());
+
+        let pool = pool.with_cold_after(Duration::ZERO);
+        pool.put(key("bob"), 2);
+        assert_eq!(pool.cool(|vm| *vm), vec![1, 2]);
+        // Cold VMs are paged out once.
+        assert!(pool.cool(|vm| *vm).is_empty());
+        assert_eq!(pool.buckets()[0].cold, 2);
+
+        // VMs still in memory are shed first.
+        pool.put(key("carol"), 3);
+        assert_eq!(pool.shed(), Some(3));
+        assert_eq!(pool.shed(), Some(1));
+
+        let (_, vm) = pool.take(&key("bob")).unwrap();
+        assert_eq!(vm, 2);
+        assert_eq!(pool.buckets()[0].cold, 0);
+    }
+
+    #[test]
+    fn test_idle_vms_are_suspended() {
+        let pool = VmPool::new(4, Duration::from_secs(60));
+        pool.put(key("alice"), 1);
+        assert!(pool.suspend(|_| true).is_empty());
+
+        let pool = pool.with_suspend_after(Duration::ZERO);
+        pool.put(sized("bob", 2, 1024), 2);
+        pool.put(key("carol"), 3);
+        // VMs that cannot be suspended stay in the pool.
+        assert_eq!(
+            pool.suspend(|vm| *vm != 3),
+            vec![(key("alice"), 1), (sized("bob", 2, 1024), 2)]
+        );
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.take(&key("carol")), Some((key("carol"), 3)));
+    }
+
+    #[test]
+    fn test_targets() {
+        let small = key("alice").bucket();
+        let large = sized("alice", 2, 2048).bucket();
+        let demand: VecDeque<Bucket> = [&small, &small, &large].into_iter().cloned().collect();
+        let targets = targets(&demand, 4);
+        assert_eq!(targets[&small], 3);
+        assert_eq!(targets[&large], 1);
+        assert!(super::targets(&VecDeque::new(), 4).is_empty());
+    }
+
+    #[test]
+    fn test_idle_vms_expire() {
+        let pool = VmPool::new(4, Duration::ZERO);
+        pool.put(key("alice"), 1);
+        assert_eq!(pool.expire(), vec![1]);
+        assert!(pool.is_empty());
+
+        let disabled = VmPool::new(0, Duration::from_secs(60));
+        assert!(!disabled.is_enabled());
+        assert_eq!(disabled.put(key("alice"), 1), vec![1]);
+    }
+}
@@ -0,0 +1,189 @@
+//! Running VMs of a function shared by its concurrent invocations.
+//!
+//! The job that booted a VM, or took it from the pool, hosts it. While it runs,
+//! other jobs asking for a VM of the same key join it as guests, up to
+//! `max_jobs` jobs at once, rather than booting a VM each. The agent runs them
+//! side by side, each in a process group and cgroup of its own. Keys are those
+//! of the pool of the VMs of a function: only its invocations, which trust each
+//! other, share a VM.
+//!
+//! Once its own job ended, the host stops taking guests and waits for those it
+//! has before the VM goes back to the pool or is shut down.
+
+use crate::vm_pool::PoolKey;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+pub const DEFAULT_MAX_JOBS_PER_VM: usize = 1;
+
+struct Shared<T> {
+    vm: T,
+    /// Jobs running in the VM, its host included.
+    jobs: watch::Sender<usize>,
+}
+
+/// A VM its job shares, see [`SharedVms::host`].
+pub struct Host<T> {
+    key: PoolKey,
+    shared: Arc<Shared<T>>,
+}
+
+/// A job running in the VM of another, see [`SharedVms::join`].
+pub struct Guest<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Guest<T> {
+    /// What the job needs of the VM to run in it.
+    pub fn vm(&self) -> &T {
+        &self.shared.vm
+    }
+}
+
+impl<T> Drop for Guest<T> {
+    fn drop(&mut self) {
+        self.shared.jobs.send_modify(|jobs| *jobs -= 1);
+    }
+}
+
+pub struct SharedVms<T> {
+    max_jobs: usize,
+    vms: Mutex<HashMap<PoolKey, Vec<Arc<Shared<T>>>>>,
+}
+
+impl<T> SharedVms<T> {
+    /// Shares VMs between up to `max_jobs` jobs, `1` shares none.
+    pub fn new(max_jobs: usize) -> Self {
+        Self {
+            max_jobs,
+            vms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether VMs are shared at all.
+    pub fn is_enabled(&self) -> bool {
+        self.max_jobs > 1
+    }
+
+    /// Lets the jobs of `key` join `vm`, whose job runs in it.
+    pub fn host(&self, key: PoolKey, vm: T) -> Host<T> {
+        let shared = Arc::new(Shared {
+            vm,
+            jobs: watch::channel(1).0,
+        });
+        self.lock()
+            .entry(key.clone())
+            .or_default()
+            .push(Arc::clone(&shared));
+        Host { key, shared }
+    }
+
+    /// Joins the VM of `key` running the fewest jobs, if one has room.
+    pub fn join(&self, key: &PoolKey) -> Option<Guest<T>> {
+        let vms = self.lock();
+        let shared = vms
+            .get(key)?
+            .iter()
+            .filter(|shared| *shared.jobs.borrow() < self.max_jobs)
+            .min_by_key(|shared| *shared.jobs.borrow())?;
+        shared.jobs.send_modify(|jobs| *jobs += 1);
+        Some(Guest {
+            shared: Arc::clone(shared),
+        })
+    }
+
+    /// Stops `host` from taking guests and waits for those it has to end.
+    /// Returns how many it waited for.
+    pub async fn close(&self, host: Host<T>) -> usize {
+        {
+            let mut vms = self.lock();
+            if let Some(shared) = vms.get_mut(&host.key) {
+                shared.retain(|shared| !Arc::ptr_eq(shared, &host.shared));
+                if shared.is_empty() {
+                    vms.remove(&host.key);
+                }
+            }
+        }
+        let guests = *host.shared.jobs.borrow() - 1;
+        let mut jobs = host.shared.jobs.subscribe();
+        // The sender lives in `host`, so the wait only ends with the guests.
+        let _ = jobs.wait_for(|jobs| *jobs <= 1).await;
+        guests
+    }
+
+    /// Jobs running as guests, over every VM.
+    pub fn guests(&self) -> usize {
+        self.lock()
+            .values()
+            .flatten()
+            .map(|shared| *shared.jobs.borrow() - 1)
+            .sum()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PoolKey, Vec<Arc<Shared<T>>>>> {
+        self.vms.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn key(tenant: &str) -> PoolKey {
+        PoolKey {
+            tenant: tenant.to_string(),
+            language: "node".to_string(),
+            vcpus: 1,
+            memory_mb: 512,
+        }
+    }
+
+    #[test]
+    fn test_join_up_to_max_jobs() {
+        let vms = SharedVms::new(3);
+        assert!(vms.join(&key("function:a")).is_none());
+
+        let _host = vms.host(key("function:a"), "vm-1");
+        let first = vms.join(&key("function:a")).unwrap();
+        assert_eq!(*first.vm(), "vm-1");
+        let _second = vms.join(&key("function:a")).unwrap();
+        assert!(vms.join(&key("function:a")).is_none());
+        assert!(vms.join(&key("function:b")).is_none());
+        assert_eq!(vms.guests(), 2);
+
+        drop(first);
+        assert_eq!(vms.guests(), 1);
+        assert!(vms.join(&key("function:a")).is_some());
+    }
+
+    #[test]
+    fn test_join_least_busy() {
+        let vms = SharedVms::new(4);
+        let _one = vms.host(key("function:a"), "vm-1");
+        let _guest = vms.join(&key("function:a")).unwrap();
+        let _two = vms.host(key("function:a"), "vm-2");
+        assert_eq!(*vms.join(&key("function:a")).unwrap().vm(), "vm-2");
+    }
+
+    #[tokio::test]
+    async fn test_close_waits_for_guests() {
+        let vms = Arc::new(SharedVms::new(2));
+        let host = vms.host(key("function:a"), "vm-1");
+        let guest = vms.join(&key("function:a")).unwrap();
+
+        let closing = {
+            let vms = Arc::clone(&vms);
+            tokio::spawn(async move { vms.close(host).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!closing.is_finished());
+        // Closed VMs take no more guests.
+        assert!(vms.join(&key("function:a")).is_none());
+
+        drop(guest);
+        assert_eq!(closing.await.unwrap(), 1);
+        assert_eq!(vms.guests(), 0);
+    }
+}
@@ -0,0 +1,396 @@
+//! Delivery of lifecycle events to webhooks.
+//!
+//! Each webhook gets every event POSTed as JSON, one at a time and in order. An
+//! attempt that fails for a reason that may pass, a network error, a `408`, a
+//! `429` or a `5xx`, is made again after an exponential backoff with jitter,
+//! up to the maximum attempts; the event is then given up on and kept in the
+//! failures of the webhook, for `GET /admin/webhooks`.
+//!
+//! Events wait for their turn in a queue of each webhook, of [`QUEUE_CAPACITY`]
+//! events. One arriving while the queue is full is given up on at once, and one
+//! the webhook missed altogether is counted in `dropped`: either way, it is
+//! counted as failed.
+//!
+//! With a secret, every attempt is signed: `x-cloude-signature` is `v1=` and
+//! the hex HMAC-SHA256 of `{id}.{timestamp}.{nonce}.{body}`. The id is that of
+//! the delivery, the same for all its attempts so that receivers can tell them
+//! apart from other events; the timestamp, in seconds since the Unix epoch, and
+//! the nonce are new for every attempt, for receivers to reject replays, see
+//! `cloude_client::webhook`.
+
+use crate::events::EventBus;
+use cloude_types::{FailedDelivery, LifecycleEvent, WebhookStatus};
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use sha2::Sha256;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, mpsc};
+use tracing::warn;
+
+pub const DEFAULT_WEBHOOK_MAX_ATTEMPTS: u32 = 5;
+
+pub const ID_HEADER: &str = "x-cloude-webhook-id";
+pub const TIMESTAMP_HEADER: &str = "x-cloude-webhook-timestamp";
+pub const NONCE_HEADER: &str = "x-cloude-webhook-nonce";
+pub const SIGNATURE_HEADER: &str = "x-cloude-signature";
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How long an attempt waits for the webhook to answer.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Events given up on kept per webhook.
+const FAILURES_KEPT: usize = 100;
+/// Events of a webhook waiting to be delivered before new ones are given up on.
+pub const QUEUE_CAPACITY: usize = 1024;
+
+/// `x-cloude-signature` of an attempt of delivery `id`, made at `timestamp` with `nonce`.
+pub fn signature(secret: &[u8], id: &str, timestamp: u64, nonce: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(format!("{id}.{timestamp}.{nonce}.").as_bytes());
+    mac.update(body);
+    format!("v1={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Delay before the attempt following `attempt` (starting at 1). It doubles
+/// with every attempt, up to [`MAX_BACKOFF`], and `jitter`, between 0 and 1,
+/// takes up to half of it off, so that webhooks failing together are not all
+/// retried at once.
+pub fn backoff(attempt: u32, jitter: f64) -> Duration {
+    let factor = 1u32
+        .checked_shl(attempt.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    let delay = INITIAL_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF);
+    delay.mul_f64(1.0 - jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// A random number between 0 and 1, from the random bits of a v4 UUID.
+pub(crate) fn jitter() -> f64 {
+    (uuid::Uuid::new_v4().as_u128() as u64 >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Whether an attempt answered with `status` may succeed if made again.
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Why an attempt failed, and whether to make another.
+struct AttemptError {
+    error: String,
+    retryable: bool,
+}
+
+pub struct Webhook {
+    url: String,
+    secret: Option<Vec<u8>>,
+    max_attempts: u32,
+    status: Mutex<WebhookStatus>,
+}
+
+impl Webhook {
+    /// A webhook at `url`, whose attempts are signed with `secret`, if any.
+    pub fn new(url: impl Into<String>, secret: Option<Vec<u8>>, max_attempts: u32) -> Self {
+        let url = url.into();
+        Self {
+            status: Mutex::new(WebhookStatus {
+                url: url.clone(),
+                delivered: 0,
+                failed: 0,
+                retries: 0,
+                dropped: 0,
+                last_delivered_ms: None,
+                last_error: None,
+                failures: Vec::new(),
+            }),
+            url,
+            secret,
+            max_attempts: max_attempts.max(1),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Counters of the webhook, and the events it was last given up on.
+    pub fn status(&self) -> WebhookStatus {
+        self.lock().clone()
+    }
+
+    /// POSTs `event`, again on failures that may pass. Returns whether the
+    /// webhook accepted it.
+    pub async fn deliver(&self, client: &reqwest::Client, event: &LifecycleEvent) -> bool {
+        let id = uuid::Uuid::new_v4().to_string();
+        let body = serde_json::to_vec(event).unwrap_or_default();
+        let mut attempt = 1;
+        loop {
+            let error = match self.attempt(client, &id, &body).await {
+                Ok(()) => {
+                    let mut status = self.lock();
+                    status.delivered += 1;
+                    status.last_delivered_ms = Some(now_ms());
+                    return true;
+                }
+                Err(error) => error,
+            };
+            self.lock().last_error = Some(error.error.clone());
+            if !error.retryable || attempt >= self.max_attempts {
+                warn!(
+                    "Giving up on {} event to webhook {} after {} attempts: {}",
+                    event.kind, self.url, attempt, error.error
+                );
+                self.give_up(FailedDelivery {
+                    id,
+                    event: event.clone(),
+                    attempts: attempt,
+                    error: error.error,
+                    failed_at_ms: now_ms(),
+                });
+                return false;
+            }
+            tokio::time::sleep(backoff(attempt, jitter())).await;
+            self.lock().retries += 1;
+            attempt += 1;
+        }
+    }
+
+    async fn attempt(
+        &self,
+        client: &reqwest::Client,
+        id: &str,
+        body: &[u8],
+    ) -> Result<(), AttemptError> {
+        let mut request = client
+            .post(&self.url)
+            .timeout(ATTEMPT_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(ID_HEADER, id);
+        if let Some(secret) = &self.secret {
+            let timestamp = now_ms() / 1000;
+            let nonce = uuid::Uuid::new_v4().simple().to_string();
+            request = request
+                .header(TIMESTAMP_HEADER, timestamp)
+                .header(
+                    SIGNATURE_HEADER,
+                    signature(secret, id, timestamp, &nonce, body),
+                )
+                .header(NONCE_HEADER, nonce);
+        }
+        match request.body(body.to_vec()).send().await {
+            Ok(resp) if resp.status().is_success() => Ok(()),
+            Ok(resp) => Err(AttemptError {
+                error: format!("HTTP {}", resp.status()),
+                retryable: is_retryable(resp.status()),
+            }),
+            Err(e) => Err(AttemptError {
+                error: e.to_string(),
+                retryable: true,
+            }),
+        }
+    }
+
+    /// Gives up on `event` without an attempt, its queue being full.
+    fn overflow(&self, event: LifecycleEvent) {
+        let error = format!("{} events already queued", QUEUE_CAPACITY);
+        warn!(
+            "Webhook {} fell behind, {} event given up on",
+            self.url, event.kind
+        );
+        self.lock().last_error = Some(error.clone());
+        self.give_up(FailedDelivery {
+            id: uuid::Uuid::new_v4().to_string(),
+            event,
+            attempts: 0,
+            error,
+            failed_at_ms: now_ms(),
+        });
+    }
+
+    /// Counts `missed` events that never reached the queue, whose content is lost.
+    fn drop_missed(&self, missed: u64) {
+        warn!(
+            "Webhook {} fell behind, {} events dropped",
+            self.url, missed
+        );
+        let mut status = self.lock();
+        status.dropped += missed;
+        status.failed += missed;
+        status.last_error = Some(format!("{missed} events dropped"));
+    }
+
+    fn give_up(&self, failure: FailedDelivery) {
+        let mut status = self.lock();
+        status.failed += 1;
+        if status.failures.len() >= FAILURES_KEPT {
+            status.failures.remove(0);
+        }
+        status.failures.push(failure);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WebhookStatus> {
+        self.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Delivers every event published from now on to `webhook`, from its queue.
+pub fn spawn(
+    webhook: Arc<Webhook>,
+    bus: &EventBus,
+    client: reqwest::Client,
+) -> tokio::task::JoinHandle<()> {
+    let mut events = bus.subscribe();
+    let (queue, mut queued) = mpsc::channel(QUEUE_CAPACITY);
+    let delivering = Arc::clone(&webhook);
+    tokio::spawn(async move {
+        while let Some(event) = queued.recv().await {
+            delivering.deliver(&client, &event).await;
+        }
+    });
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    webhook.drop_missed(missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if let Err(mpsc::error::TrySendError::Full(event)) = queue.try_send(event) {
+                webhook.overflow(event);
+            }
+        }
+    })
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderMap;
+    use cloude_types::EventKind;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn event() -> LifecycleEvent {
+        LifecycleEvent {
+            timestamp_ms: 1760000000123,
+            kind: EventKind::ExecutionFinished,
+            job_id: Some("job-1".to_string()),
+            vm_id: None,
+            detail: None,
+        }
+    }
+
+    #[test]
+    fn test_signature() {
+        // Same vector as the verification of `cloude_client::webhook`.
+        assert_eq!(
+            signature(b"secret", "delivery-1", 1760000000, "nonce-1", b"{}"),
+            "v1=912c4190f0b390964f5c2ba81b8daf68683f71eb1fe692cb0870c997cc0dc213"
+        );
+    }
+
+    #[test]
+    fn test_backoff_doubles_with_jitter() {
+        assert_eq!(backoff(1, 0.0), Duration::from_secs(1));
+        assert_eq!(backoff(3, 0.0), Duration::from_secs(4));
+        assert_eq!(backoff(3, 1.0), Duration::from_secs(2));
+        assert_eq!(backoff(64, 0.0), MAX_BACKOFF);
+        let random = backoff(2, jitter());
+        assert!(random >= Duration::from_secs(1) && random <= Duration::from_secs(2));
+    }
+
+    /// A webhook answering `503` to the first `failures` attempts, and
+    /// recording the headers of the others.
+    async fn spawn_receiver(
+        failures: u32,
+    ) -> (String, Arc<AtomicU32>, Arc<Mutex<Vec<(HeaderMap, String)>>>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let (c, r) = (Arc::clone(&calls), Arc::clone(&received));
+        let app = axum::Router::new().route(
+            "/hook",
+            axum::routing::post(move |headers: HeaderMap, body: String| async move {
+                if c.fetch_add(1, Ordering::SeqCst) < failures {
+                    return StatusCode::SERVICE_UNAVAILABLE;
+                }
+                r.lock().unwrap().push((headers, body));
+                StatusCode::NO_CONTENT
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, calls, received)
+    }
+
+    #[tokio::test]
+    async fn test_failed_attempts_are_retried_and_signed() {
+        let (url, calls, received) = spawn_receiver(1).await;
+        let webhook = Webhook::new(url, Some(b"secret".to_vec()), 5);
+
+        assert!(webhook.deliver(&reqwest::Client::new(), &event()).await);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let status = webhook.status();
+        assert_eq!((status.delivered, status.failed, status.retries), (1, 0, 1));
+        assert_eq!(
+            status.last_error.as_deref(),
+            Some("HTTP 503 Service Unavailable")
+        );
+
+        let received = received.lock().unwrap();
+        let (headers, body) = &received[0];
+        let header = |name: &str| headers[name].to_str().unwrap().to_string();
+        let timestamp: u64 = header(TIMESTAMP_HEADER).parse().unwrap();
+        assert_eq!(
+            header(SIGNATURE_HEADER),
+            signature(
+                b"secret",
+                &header(ID_HEADER),
+                timestamp,
+                &header(NONCE_HEADER),
+                body.as_bytes()
+            )
+        );
+        assert_eq!(
+            serde_json::from_str::<LifecycleEvent>(body).unwrap(),
+            event()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_are_given_up_on() {
+        let (url, calls, _) = spawn_receiver(u32::MAX).await;
+        let webhook = Webhook::new(url, None, 2);
+
+        assert!(!webhook.deliver(&reqwest::Client::new(), &event()).await);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let status = webhook.status();
+        assert_eq!((status.delivered, status.failed, status.retries), (0, 1, 1));
+        assert_eq!(status.failures.len(), 1);
+        assert_eq!(status.failures[0].attempts, 2);
+        assert_eq!(status.failures[0].event, event());
+    }
+
+    #[tokio::test]
+    async fn test_events_past_the_queue_are_failed() {
+        let webhook = Webhook::new("http://127.0.0.1:9/hook", None, 1);
+        webhook.overflow(event());
+        webhook.drop_missed(3);
+
+        let status = webhook.status();
+        assert_eq!((status.failed, status.dropped), (4, 3));
+        assert_eq!(status.failures.len(), 1);
+        assert_eq!(status.failures[0].attempts, 0);
+        assert_eq!(status.failures[0].event, event());
+        assert_eq!(status.last_error.as_deref(), Some("3 events dropped"));
+    }
+}
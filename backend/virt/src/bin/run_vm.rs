@@ -1,15 +1,40 @@
 // Usage:
 // KERNEL_PATH=/path/to/kernel INITRAMFS_PATH=/path/to/initramfs cargo run --bin test
-// SERIAL_OUTPUT=/path/to/output.log - optional, to capture serial output
+// ROOT_DISK=/path/to/rootfs.ext4 - optional, attached as /dev/vda and used as root filesystem
+//                                  when INITRAMFS_PATH is not set
+// ROOT_DISK_READONLY=1 - optional, attach ROOT_DISK read-only
+// ACPI=1 - optional, write ACPI tables as well as the MP table
+// SERIAL_LOG_DIR=/path/to/logs - optional, to capture serial output in {dir}/{VM_ID}.log,
+//                                 rotated past SERIAL_LOG_MAX_BYTES (default 10 MiB)
+// VM_ID=<id> - optional, name of the serial log, `run-vm` by default
 // TAP_DEVICE=<device_name> - optional, to enable networking with a specific tap device
+// TAP_FD_SOCKET=/path/to/helper.sock - optional, with TAP_DEVICE: receive the open TAP from
+//                                      a privileged helper on this Unix socket, instead of
+//                                      opening it
 // GUEST_IP=<ip_address> - optional, guest IP address
 // HOST_IP=<ip_address> - optional, host IP address
 // NETMASK=<mask> - optional, network mask
+// MTU=<bytes> - optional, with TAP_DEVICE: MTU of the guest, its TAP and the test bridge; a TAP
+//               received from TAP_FD_SOCKET must have it already
+// CLOUD_INIT_USER_DATA=/path/to/user-data - optional, with ROOT_DISK: cloud-init user data,
+//                                          given to the guest on a NoCloud seed disk
+// CLOUD_INIT_SSH_KEY=/path/to/key.pub - optional, with ROOT_DISK: create CLOUD_INIT_USER (default
+//                                      `cloude`) with this key instead
+// NAMESERVER=<ip_address> - optional, DNS server of the cloud-init network configuration
+// SSH_PORT=<port> - optional, with GUEST_IP: forward this TCP port of the host to port 22 of the
+//                   guest while the VM runs, and print the ssh command to connect
+// SSH_HOST=<host> - optional, host name printed in the ssh command, the host's name by default
+// HEADLESS=1 - optional, give the guest no console input, for a VM run in the background
+// API_SOCKET=/path/to/api.sock - optional, configure and start the VM through the
+//                                Firecracker-compatible API instead; the other variables are ignored
 
-use std::{env, net::Ipv4Addr};
+use std::os::fd::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::{env, net::Ipv4Addr, path::Path};
 use tracing_subscriber::EnvFilter;
-use vmm::{VMInput, VMM};
-use vmm_sys_util::terminal::Terminal;
+use virt::cloud_init::{NoCloudSeed, StaticNetwork, User};
+use virt::serial_log::{DEFAULT_MAX_BYTES, DEFAULT_MAX_FILES, SerialLog, SerialLogConfig};
+use vmm::{NetBackend, NetConfig, VMInput, VMM};
 
 /// Check if IPv4 are in the same subnet
 fn same_subnet(ip1: Ipv4Addr, ip2: Ipv4Addr, prefix_len: u8) -> bool {
@@ -32,6 +57,84 @@ fn get_env_ip(var_name: &str) -> Result<Option<Ipv4Addr>, std::io::Error> {
         )),
     }
 }
+/// MTU of `MTU`, `None` when it is not set.
+fn get_mtu() -> Result<Option<u16>, std::io::Error> {
+    match env::var("MTU") {
+        Ok(val) => val.parse().map(Some).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("MTU env variable is unvalid: {}", e),
+            )
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Port of `SSH_PORT`, `None` when it is not set.
+fn get_ssh_port() -> Result<Option<u16>, std::io::Error> {
+    match env::var("SSH_PORT") {
+        Ok(val) => val.parse().map(Some).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("SSH_PORT env variable is unvalid: {}", e),
+            )
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+/// How to reach the guest's SSH server through the forward of `port`.
+fn ssh_command(port: u16) -> String {
+    let user = env::var("CLOUD_INIT_USER").unwrap_or_else(|_| "cloude".to_string());
+    let host = env::var("SSH_HOST")
+        .ok()
+        .or_else(|| {
+            std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .ok()
+                .map(|name| name.trim().to_string())
+        })
+        .unwrap_or_else(|| "localhost".to_string());
+    format!("ssh {}@{} -p {}", user, host, port)
+}
+
+/// NoCloud seed from the `CLOUD_INIT_*` variables, `None` when none is set.
+/// The guest gets the address of `GUEST_IP` when networking is enabled.
+fn cloud_init_seed() -> Result<Option<NoCloudSeed>, std::io::Error> {
+    let user_data = match (
+        env::var("CLOUD_INIT_USER_DATA").ok(),
+        env::var("CLOUD_INIT_SSH_KEY").ok(),
+    ) {
+        (Some(path), _) => std::fs::read_to_string(path)?,
+        (None, Some(path)) => virt::cloud_init::cloud_config(&[User {
+            name: env::var("CLOUD_INIT_USER").unwrap_or_else(|_| "cloude".to_string()),
+            ssh_authorized_keys: vec![std::fs::read_to_string(path)?.trim().to_string()],
+        }]),
+        (None, None) => return Ok(None),
+    };
+
+    let network = match (
+        env::var("TAP_DEVICE").ok(),
+        get_env_ip("GUEST_IP")?,
+        get_env_ip("HOST_IP")?,
+        get_env_ip("NETMASK")?,
+    ) {
+        (Some(_), Some(address), Some(gateway), Some(netmask)) => Some(StaticNetwork {
+            address,
+            prefix_len: u32::from(netmask).leading_ones() as u8,
+            gateway,
+            nameservers: get_env_ip("NAMESERVER")?.into_iter().collect(),
+        }),
+        _ => None,
+    };
+
+    Ok(Some(NoCloudSeed {
+        instance_id: format!("run-vm-{}", std::process::id()),
+        hostname: "cloude-vm".to_string(),
+        user_data,
+        network,
+    }))
+}
+
 #[tokio::main]
 async fn main() {
     // init logging
@@ -42,37 +145,59 @@ async fn main() {
         .init();
     log::debug!("Debug logging enabled");
 
+    // Not the tables of a backend running on the same host, which this would replace
+    virt::network::set_table_name("cloude_run_vm");
+
+    if let Ok(api_socket) = env::var("API_SOCKET") {
+        println!("Serving the VM API on {}", api_socket);
+        if let Err(e) = vmm::api::ApiServer::new(Path::new(&api_socket)).run() {
+            eprintln!("Error serving the VM API: {}", e);
+        }
+        return;
+    }
+
     let kernel_path = match env::var("KERNEL_PATH") {
         Ok(val) => val,
         Err(e) => return eprintln!("Error getting KERNEL_PATH: {}", e),
     };
 
-    let initramfs_path = match env::var("INITRAMFS_PATH") {
-        Ok(val) => val,
-        Err(e) => return eprintln!("Error getting INITRAMFS_PATH: {}", e),
-    };
+    let initramfs_path = env::var("INITRAMFS_PATH").ok();
+    let root_disk = env::var("ROOT_DISK").ok();
+    if initramfs_path.is_none() && root_disk.is_none() {
+        return eprintln!("Either INITRAMFS_PATH or ROOT_DISK must be set");
+    }
 
     let vcpus: u8 = 2;
     let memory: usize = 1024 << 20; // convert from 1024 MB to bytes
 
     // Configure serial output
-    let writer: Box<dyn std::io::Write + Send> =
-        if let Ok(serial_output) = env::var("SERIAL_OUTPUT") {
-            println!("Serial output will be written to: {}", serial_output);
-            Box::new(
-                std::fs::File::create(&serial_output).expect("Failed to create serial output file"),
-            )
-        } else {
-            Box::new(std::io::stdout())
+    let writer: Box<dyn std::io::Write + Send> = if let Ok(dir) = env::var("SERIAL_LOG_DIR") {
+        let config = SerialLogConfig {
+            dir: dir.into(),
+            max_bytes: env::var("SERIAL_LOG_MAX_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_BYTES),
+            max_files: DEFAULT_MAX_FILES,
         };
+        let vm_id = env::var("VM_ID").unwrap_or_else(|_| "run-vm".to_string());
+        println!(
+            "Serial output will be written to: {}",
+            config.path(&vm_id).display()
+        );
+        Box::new(SerialLog::open(&config, &vm_id).expect("Failed to open serial log"))
+    } else {
+        Box::new(std::io::stdout())
+    };
 
-    // Configure stdin in raw mode
-    let stdin = std::io::stdin();
-    let stdin_lock: std::io::StdinLock<'_> = stdin.lock();
-    stdin_lock
-        .set_raw_mode()
-        .expect("Failed to set stdin to raw mode");
-    let stdin_box: Box<dyn VMInput> = Box::new(stdin_lock);
+    // The VMM puts stdin in raw mode when it is a terminal, and restores it on exit.
+    // HEADLESS=1 leaves the guest without input, for services run in the background.
+    let headless = env::var("HEADLESS").is_ok_and(|val| val == "1");
+    let stdin_box: Option<Box<dyn VMInput>> = if headless {
+        None
+    } else {
+        Some(Box::new(std::io::stdin()))
+    };
 
     // Create VMM
     let mut vmm = match VMM::new(stdin_box, writer, memory) {
@@ -85,8 +210,22 @@ async fn main() {
         let guest_ip = get_env_ip("GUEST_IP").unwrap();
         let host_ip = get_env_ip("HOST_IP").unwrap();
         let netmask = get_env_ip("NETMASK").unwrap(); // in the form 255.255.255.0
+        let mtu = match get_mtu() {
+            Ok(mtu) => mtu,
+            Err(e) => return eprintln!("Error: {}", e),
+        };
 
-        if let Err(e) = vmm.add_net_device(tap_name.clone(), guest_ip, host_ip, netmask) {
+        // Opening the TAP needs CAP_NET_ADMIN, which a helper can hold instead
+        let backend = match env::var("TAP_FD_SOCKET") {
+            Ok(path) => match UnixStream::connect(&path).and_then(|s| vmm::fd_passing::recv_fd(&s))
+            {
+                Ok(fd) => NetBackend::TapFd(fd.into_raw_fd()),
+                Err(e) => return eprintln!("Error receiving the TAP from {}: {}", path, e),
+            },
+            Err(_) => NetBackend::Tap(tap_name.clone()),
+        };
+        let net = NetConfig { backend, mtu };
+        if let Err(e) = vmm.add_net_device(net, guest_ip, host_ip, netmask) {
             return eprintln!("Error adding net device: {:?}", e);
         }
 
@@ -95,6 +234,11 @@ async fn main() {
             virt::network::setup_bridge("cloudebrtest".to_string(), host_ip, 24)
                 .await
                 .expect("Failed to set up bridge");
+            if let Some(mtu) = mtu {
+                virt::network::set_link_mtu("cloudebrtest", mtu)
+                    .await
+                    .expect("Failed to set the MTU of the bridge");
+            }
 
             let prefix = u32::from(netmask).leading_ones() as u8;
 
@@ -112,12 +256,77 @@ async fn main() {
         }
     }
 
+    if let Some(root_disk) = root_disk {
+        let read_only = env::var("ROOT_DISK_READONLY").is_ok_and(|val| val == "1");
+        if let Err(e) = vmm.add_block_device(Path::new(&root_disk), read_only) {
+            return eprintln!("Error adding block device: {:?}", e);
+        }
+
+        // Stock cloud images configure themselves from the seed disk, /dev/vdb
+        let seed = match cloud_init_seed() {
+            Ok(seed) => seed,
+            Err(e) => return eprintln!("Error reading cloud-init settings: {}", e),
+        };
+        if let Some(seed) = seed {
+            let seed_path = env::temp_dir().join(format!("cloude-seed-{}.img", std::process::id()));
+            if let Err(e) = seed.write_image(&seed_path) {
+                return eprintln!("Error writing cloud-init seed: {}", e);
+            }
+            println!("cloud-init seed written to: {}", seed_path.display());
+            if let Err(e) = vmm.add_block_device(&seed_path, true) {
+                return eprintln!("Error adding cloud-init seed device: {:?}", e);
+            }
+        }
+    }
+
+    vmm.set_acpi(env::var("ACPI").is_ok_and(|val| val == "1"));
+
     let init_path = env::var("INIT_PATH").ok();
     // Configure VMM
-    if let Err(e) = vmm.configure(vcpus, &kernel_path, &initramfs_path, init_path.as_deref()) {
+    if let Err(e) = vmm.configure(
+        vcpus,
+        &kernel_path,
+        initramfs_path.as_deref(),
+        init_path.as_deref(),
+    ) {
         return eprintln!("Error configuring VMM: {:?}", e);
     }
 
+    // Forward the SSH port once everything else is set up, so that a failed
+    // start leaves no rule behind
+    let ssh_port = match (get_ssh_port(), get_env_ip("GUEST_IP")) {
+        (Ok(Some(port)), Ok(Some(guest_ip))) => {
+            if let Err(e) = virt::network::setup_port_forward(port, guest_ip, 22) {
+                return eprintln!("Error forwarding SSH port: {}", e);
+            }
+            println!("SSH access: {}", ssh_command(port));
+            Some(port)
+        }
+        (Ok(Some(_)), Ok(None)) => return eprintln!("SSH_PORT requires GUEST_IP"),
+        (Err(e), _) | (_, Err(e)) => return eprintln!("Error reading SSH settings: {}", e),
+        (Ok(None), _) => None,
+    };
+
     // Run VMM
     vmm.run();
+
+    if let Some(reason) = vmm.failure() {
+        eprintln!("The VM stopped on an error: {}", reason);
+    }
+    if let Some(stats) = vmm.net_notification_stats() {
+        println!(
+            "Network interrupts: {} for {} frames received, {} for {} frames sent",
+            stats.rx_signals, stats.rx_used, stats.tx_signals, stats.tx_used
+        );
+        if stats.rx_dropped + stats.rx_errors + stats.tx_dropped > 0 {
+            println!(
+                "Network errors: {} receive buffers and {} frames sent dropped, {} failed reads",
+                stats.rx_dropped, stats.tx_dropped, stats.rx_errors
+            );
+        }
+    }
+
+    if let Some(Err(e)) = ssh_port.map(virt::network::remove_port_forward) {
+        eprintln!("Error removing SSH port forward: {}", e);
+    }
 }
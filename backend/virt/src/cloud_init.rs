@@ -0,0 +1,374 @@
+//! cloud-init NoCloud seed, to configure a VM booted from a stock cloud image.
+//!
+//! cloud-init looks for a filesystem labelled `cidata` holding `meta-data`,
+//! `user-data` and, optionally, `network-config`. The seed is a small FAT12
+//! image written from scratch, attached to the VM as a read-only block device.
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+const SECTOR_SIZE: usize = 512;
+/// Size of every seed image: 1 MiB, room for about 1000 KiB of files.
+const TOTAL_SECTORS: usize = 2048;
+const RESERVED_SECTORS: usize = 1;
+const FAT_COUNT: usize = 2;
+const ROOT_ENTRIES: usize = 16;
+const ROOT_SECTORS: usize = ROOT_ENTRIES * DIR_ENTRY_SIZE / SECTOR_SIZE;
+/// Enough for a FAT12 entry per cluster, one sector per cluster.
+const FAT_SECTORS: usize = (TOTAL_SECTORS * 3 / 2).div_ceil(SECTOR_SIZE);
+const DATA_START: usize = RESERVED_SECTORS + FAT_COUNT * FAT_SECTORS + ROOT_SECTORS;
+const CLUSTER_COUNT: usize = TOTAL_SECTORS - DATA_START;
+const DIR_ENTRY_SIZE: usize = 32;
+/// Characters of a long file name held by each of its directory entries.
+const LFN_CHARS: usize = 13;
+const VOLUME_LABEL: &[u8; 11] = b"CIDATA     ";
+
+// Past 4084 clusters, the filesystem would be read as FAT16.
+const _: () = assert!(CLUSTER_COUNT < 4085);
+
+/// Everything cloud-init reads from the seed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoCloudSeed {
+    pub instance_id: String,
+    pub hostname: String,
+    /// `#cloud-config` document, or any other format cloud-init accepts.
+    pub user_data: String,
+    pub network: Option<StaticNetwork>,
+}
+
+/// Fixed address of the guest on its first virtio network interface.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaticNetwork {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Ipv4Addr,
+    pub nameservers: Vec<Ipv4Addr>,
+}
+
+/// Account created on first boot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub ssh_authorized_keys: Vec<String>,
+}
+
+/// `#cloud-config` user data creating `users`, with passwordless sudo and their SSH keys.
+pub fn cloud_config(users: &[User]) -> String {
+    let mut config = String::from("#cloud-config\nusers:\n");
+    for user in users {
+        config.push_str(&format!("  - name: {}\n", yaml_string(&user.name)));
+        config.push_str("    sudo: \"ALL=(ALL) NOPASSWD:ALL\"\n");
+        config.push_str("    shell: /bin/bash\n");
+        config.push_str("    lock_passwd: true\n");
+        config.push_str("    ssh_authorized_keys:\n");
+        for key in &user.ssh_authorized_keys {
+            config.push_str(&format!("      - {}\n", yaml_string(key)));
+        }
+    }
+    config
+}
+
+impl NoCloudSeed {
+    fn meta_data(&self) -> String {
+        format!(
+            "instance-id: {}\nlocal-hostname: {}\n",
+            yaml_string(&self.instance_id),
+            yaml_string(&self.hostname)
+        )
+    }
+
+    /// Network configuration version 2, for the virtio interface whatever its name.
+    fn network_config(network: &StaticNetwork) -> String {
+        let mut config = format!(
+            "version: 2\nethernets:\n  eth0:\n    match:\n      driver: virtio_net\n    \
+             set-name: eth0\n    addresses: [\"{}/{}\"]\n    routes:\n      - to: default\n        \
+             via: {}\n",
+            network.address, network.prefix_len, network.gateway
+        );
+        if !network.nameservers.is_empty() {
+            let addresses: Vec<String> = network
+                .nameservers
+                .iter()
+                .map(|ip| format!("\"{}\"", ip))
+                .collect();
+            config.push_str(&format!(
+                "    nameservers:\n      addresses: [{}]\n",
+                addresses.join(", ")
+            ));
+        }
+        config
+    }
+
+    /// Files of the seed, with their long and short (8.3) names.
+    fn files(&self) -> Vec<(&'static str, &'static [u8; 11], String)> {
+        let mut files = vec![
+            ("meta-data", b"META-D~1   ", self.meta_data()),
+            ("user-data", b"USER-D~1   ", self.user_data.clone()),
+        ];
+        if let Some(network) = &self.network {
+            files.push((
+                "network-config",
+                b"NETWOR~1   ",
+                Self::network_config(network),
+            ));
+        }
+        files
+    }
+
+    /// The seed as a FAT12 filesystem image labelled `CIDATA`.
+    pub fn image(&self) -> io::Result<Vec<u8>> {
+        let mut image = vec![0u8; TOTAL_SECTORS * SECTOR_SIZE];
+        write_boot_sector(&mut image[..SECTOR_SIZE]);
+
+        let mut fat = vec![0u8; FAT_SECTORS * SECTOR_SIZE];
+        set_fat_entry(&mut fat, 0, 0xff8);
+        set_fat_entry(&mut fat, 1, 0xfff);
+
+        let mut root = Vec::with_capacity(ROOT_ENTRIES * DIR_ENTRY_SIZE);
+        root.extend_from_slice(&dir_entry(VOLUME_LABEL, 0x08, 0, 0));
+
+        let mut next_cluster = 2;
+        for (long_name, short_name, content) in self.files() {
+            let clusters = content.len().div_ceil(SECTOR_SIZE);
+            if next_cluster - 2 + clusters > CLUSTER_COUNT {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "cloud-init seed does not fit in {} KiB",
+                        CLUSTER_COUNT * SECTOR_SIZE / 1024
+                    ),
+                ));
+            }
+            let first_cluster = if clusters == 0 { 0 } else { next_cluster };
+            for cluster in next_cluster..next_cluster + clusters {
+                let next = if cluster + 1 == next_cluster + clusters {
+                    0xfff
+                } else {
+                    cluster as u16 + 1
+                };
+                set_fat_entry(&mut fat, cluster, next);
+            }
+            let offset = (DATA_START + next_cluster - 2) * SECTOR_SIZE;
+            image[offset..offset + content.len()].copy_from_slice(content.as_bytes());
+            next_cluster += clusters;
+
+            for entry in lfn_entries(long_name, short_name) {
+                root.extend_from_slice(&entry);
+            }
+            root.extend_from_slice(&dir_entry(
+                short_name,
+                0x20,
+                first_cluster as u16,
+                content.len() as u32,
+            ));
+        }
+
+        for copy in 0..FAT_COUNT {
+            let offset = (RESERVED_SECTORS + copy * FAT_SECTORS) * SECTOR_SIZE;
+            image[offset..offset + fat.len()].copy_from_slice(&fat);
+        }
+        let offset = (RESERVED_SECTORS + FAT_COUNT * FAT_SECTORS) * SECTOR_SIZE;
+        image[offset..offset + root.len()].copy_from_slice(&root);
+        Ok(image)
+    }
+
+    /// Writes the seed image to `path`, to attach to the VM as a read-only disk.
+    pub fn write_image(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.image()?)
+    }
+}
+
+fn write_boot_sector(sector: &mut [u8]) {
+    sector[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+    sector[3..11].copy_from_slice(b"cloude  ");
+    sector[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    // Sectors per cluster.
+    sector[13] = 1;
+    sector[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    sector[16] = FAT_COUNT as u8;
+    sector[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+    sector[19..21].copy_from_slice(&(TOTAL_SECTORS as u16).to_le_bytes());
+    // Fixed disk.
+    sector[21] = 0xf8;
+    sector[22..24].copy_from_slice(&(FAT_SECTORS as u16).to_le_bytes());
+    // Sectors per track and heads, unused by Linux.
+    sector[24..26].copy_from_slice(&32u16.to_le_bytes());
+    sector[26..28].copy_from_slice(&64u16.to_le_bytes());
+    sector[36] = 0x80;
+    // Extended boot signature: the volume id, label and type follow.
+    sector[38] = 0x29;
+    sector[39..43].copy_from_slice(&0x636c_6f75u32.to_le_bytes());
+    sector[43..54].copy_from_slice(VOLUME_LABEL);
+    sector[54..62].copy_from_slice(b"FAT12   ");
+    sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+}
+
+/// Sets the 12-bit entry of `cluster`, two entries being packed in three bytes.
+fn set_fat_entry(fat: &mut [u8], cluster: usize, value: u16) {
+    let offset = cluster * 3 / 2;
+    if cluster.is_multiple_of(2) {
+        fat[offset] = value as u8;
+        fat[offset + 1] = (fat[offset + 1] & 0xf0) | ((value >> 8) as u8 & 0x0f);
+    } else {
+        fat[offset] = (fat[offset] & 0x0f) | ((value as u8 & 0x0f) << 4);
+        fat[offset + 1] = (value >> 4) as u8;
+    }
+}
+
+fn dir_entry(
+    short_name: &[u8; 11],
+    attributes: u8,
+    first_cluster: u16,
+    size: u32,
+) -> [u8; DIR_ENTRY_SIZE] {
+    let mut entry = [0u8; DIR_ENTRY_SIZE];
+    entry[0..11].copy_from_slice(short_name);
+    entry[11] = attributes;
+    entry[26..28].copy_from_slice(&first_cluster.to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// VFAT entries holding `long_name`, in the order they precede its short entry.
+fn lfn_entries(long_name: &str, short_name: &[u8; 11]) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let checksum = short_name
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b));
+    let mut chars: Vec<u16> = long_name.encode_utf16().collect();
+    if !chars.len().is_multiple_of(LFN_CHARS) {
+        chars.push(0);
+    }
+    chars.resize(chars.len().div_ceil(LFN_CHARS) * LFN_CHARS, 0xffff);
+
+    let pieces: Vec<&[u16]> = chars.chunks(LFN_CHARS).collect();
+    let mut entries = Vec::new();
+    for (index, piece) in pieces.iter().enumerate().rev() {
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[0] = index as u8 + 1;
+        if index + 1 == pieces.len() {
+            entry[0] |= 0x40;
+        }
+        entry[11] = 0x0f;
+        entry[13] = checksum;
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (offset, c) in offsets.zip(piece.iter()) {
+            entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+/// `value` as a double-quoted YAML string.
+fn yaml_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seed() -> NoCloudSeed {
+        NoCloudSeed {
+            instance_id: "vm-1".to_string(),
+            hostname: "vm-1".to_string(),
+            user_data: cloud_config(&[User {
+                name: "cloude".to_string(),
+                ssh_authorized_keys: vec!["ssh-ed25519 AAAA user@host".to_string()],
+            }]),
+            network: Some(StaticNetwork {
+                address: Ipv4Addr::new(10, 39, 1, 2),
+                prefix_len: 24,
+                gateway: Ipv4Addr::new(10, 39, 1, 1),
+                nameservers: vec![Ipv4Addr::new(1, 1, 1, 1)],
+            }),
+        }
+    }
+
+    /// Name, first cluster and size of the files in the root directory, read back
+    /// from the short entries.
+    fn root_files(image: &[u8]) -> Vec<([u8; 11], usize, usize)> {
+        let root = &image[(DATA_START - ROOT_SECTORS) * SECTOR_SIZE..DATA_START * SECTOR_SIZE];
+        root.chunks(DIR_ENTRY_SIZE)
+            .filter(|entry| entry[0] != 0 && entry[11] == 0x20)
+            .map(|entry| {
+                let mut name = [0u8; 11];
+                name.copy_from_slice(&entry[0..11]);
+                let cluster = u16::from_le_bytes([entry[26], entry[27]]) as usize;
+                let size = u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]);
+                (name, cluster, size as usize)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_image_layout() {
+        let seed = seed();
+        let image = seed.image().unwrap();
+        assert_eq!(image.len(), 1 << 20);
+        assert_eq!(&image[510..512], &[0x55, 0xaa]);
+        assert_eq!(&image[43..54], VOLUME_LABEL);
+
+        let files = root_files(&image);
+        assert_eq!(files.len(), 3);
+        for ((_, cluster, size), (_, _, content)) in files.iter().zip(seed.files()) {
+            let offset = (DATA_START + cluster - 2) * SECTOR_SIZE;
+            assert_eq!(&image[offset..offset + size], content.as_bytes());
+        }
+        // The clusters of user-data are chained after the ones of meta-data.
+        let fat = &image[RESERVED_SECTORS * SECTOR_SIZE..];
+        assert_eq!(fat[0..3], [0xf8, 0xff, 0xff]);
+        assert_eq!(files[1].1, files[0].1 + 1);
+    }
+
+    #[test]
+    fn test_long_names() {
+        let entries = lfn_entries("network-config", b"NETWOR~1   ");
+        assert_eq!(entries.len(), 2);
+        // The last piece comes first, flagged as such.
+        assert_eq!(entries[0][0], 0x42);
+        assert_eq!(entries[1][0], 0x01);
+        assert_eq!(&entries[1][1..3], &[b'n', 0]);
+        // "g" is the 14th character, then the terminator and padding.
+        assert_eq!(&entries[0][1..7], &[b'g', 0, 0, 0, 0xff, 0xff]);
+        assert!(entries.iter().all(|entry| entry[13] == entries[0][13]));
+    }
+
+    #[test]
+    fn test_documents() {
+        let seed = seed();
+        assert_eq!(
+            seed.meta_data(),
+            "instance-id: \"vm-1\"\nlocal-hostname: \"vm-1\"\n"
+        );
+        assert!(seed.user_data.starts_with("#cloud-config\n"));
+        assert!(
+            seed.user_data
+                .contains("      - \"ssh-ed25519 AAAA user@host\"\n")
+        );
+        let network = NoCloudSeed::network_config(seed.network.as_ref().unwrap());
+        assert!(network.contains("addresses: [\"10.39.1.2/24\"]"));
+        assert!(network.contains("via: 10.39.1.1"));
+        assert_eq!(yaml_string("a\"b\n"), "\"a\\\"b\\u000a\"");
+
+        let too_large = NoCloudSeed {
+            user_data: "x".repeat(2 << 20),
+            ..seed
+        };
+        assert!(too_large.image().is_err());
+    }
+}
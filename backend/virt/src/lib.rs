@@ -1 +1,5 @@
+pub mod cloud_init;
 pub mod network;
+pub mod packet_capture;
+pub mod serial_log;
+pub mod traffic;
@@ -3,15 +3,31 @@ use nftables::{
     batch::Batch,
     expr::{Expression, NamedExpression, Payload, PayloadField, Prefix},
     helper, schema,
-    stmt::{Match, Operator, Statement},
+    stmt::{JumpTarget, Match, NAT, NATFamily, Operator, Statement},
     types,
 };
-use rtnetlink::{Handle, LinkBridge, LinkUnspec, new_connection, packet_route::link::LinkMessage};
+use rtnetlink::{
+    Handle, LinkBridge, LinkUnspec, new_connection,
+    packet_route::link::{LinkFlags, LinkMessage},
+};
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::Ipv4Addr;
+use std::sync::{Mutex, OnceLock};
 use tracing::debug;
 
-const NAT_TABLE: &str = "cloude_nat";
-const NAT_CHAIN: &str = "cloude_postr";
+/// nftables tables owned by cloude, in the `ip` and `bridge` families.
+const DEFAULT_TABLE: &str = "cloude";
+/// Tables of earlier versions, dropped by the first change.
+const LEGACY_TABLES: [(types::NfFamily, &str); 3] = [
+    (types::NfFamily::IP, "cloude_nat"),
+    (types::NfFamily::IP, "cloude_tenants"),
+    (types::NfFamily::Bridge, "cloude_tenants"),
+];
+const POSTROUTING_CHAIN: &str = "postrouting";
+const PREROUTING_CHAIN: &str = "prerouting";
+const FORWARD_CHAIN: &str = "forward";
+
+static TABLE_NAME: OnceLock<&'static str> = OnceLock::new();
 
 /// Set up the bridge interface
 pub async fn setup_bridge(
@@ -69,6 +85,16 @@ pub async fn setup_bridge(
     Ok(())
 }
 
+/// Check that the bridge exists and is up
+pub async fn bridge_is_up(bridge_name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+
+    Ok(get_link_by_name(&handle, bridge_name)
+        .await?
+        .is_some_and(|link| link.header.flags.contains(LinkFlags::Up)))
+}
+
 /// Get a link by name, returns None if not found
 async fn get_link_by_name(
     handle: &Handle,
@@ -76,22 +102,86 @@ async fn get_link_by_name(
 ) -> Result<Option<LinkMessage>, rtnetlink::Error> {
     let mut links = handle.link().get().execute();
     while let Some(link) = links.try_next().await? {
-        if let Some(link_name) = link.attributes.iter().find_map(|attr| {
-            if let rtnetlink::packet_route::link::LinkAttribute::IfName(n) = attr {
-                Some(n.as_str())
-            } else {
-                None
-            }
-        }) {
-            if link_name == name {
-                return Ok(Some(link));
-            }
+        if link_name(&link) == Some(name) {
+            return Ok(Some(link));
         }
     }
 
     Ok(None)
 }
 
+fn link_name(link: &LinkMessage) -> Option<&str> {
+    link.attributes.iter().find_map(|attr| {
+        if let rtnetlink::packet_route::link::LinkAttribute::IfName(n) = attr {
+            Some(n.as_str())
+        } else {
+            None
+        }
+    })
+}
+
+fn link_mtu(link: &LinkMessage) -> Option<u32> {
+    link.attributes.iter().find_map(|attr| {
+        if let rtnetlink::packet_route::link::LinkAttribute::Mtu(mtu) = attr {
+            Some(*mtu)
+        } else {
+            None
+        }
+    })
+}
+
+/// Set the MTU of link `name`. Set on a bridge, it stays whatever the MTU of the
+/// ports added later, instead of following the smallest.
+pub async fn set_link_mtu(name: &str, mtu: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+
+    let index = get_link_by_name(&handle, name)
+        .await?
+        .ok_or_else(|| format!("Link {} not found", name))?
+        .header
+        .index;
+    debug!("Setting the MTU of {} to {}", name, mtu);
+    handle
+        .link()
+        .set(
+            LinkUnspec::new_with_index(index)
+                .mtu(u32::from(mtu))
+                .build(),
+        )
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Names of the links starting with `prefix`
+pub async fn links_with_prefix(prefix: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+
+    let mut names = Vec::new();
+    let mut links = handle.link().get().execute();
+    while let Some(link) = links.try_next().await? {
+        if let Some(name) = link_name(&link).filter(|name| name.starts_with(prefix)) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Delete link `name`, returns false when there is no such link
+pub async fn delete_link(name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+
+    let Some(link) = get_link_by_name(&handle, name).await? else {
+        return Ok(false);
+    };
+    debug!("Deleting link {}", name);
+    handle.link().del(link.header.index).execute().await?;
+    Ok(true)
+}
+
 /// Create a new bridge and return its index
 async fn create_bridge(handle: &Handle, name: &str) -> Result<u32, rtnetlink::Error> {
     // Create the bridge
@@ -139,128 +229,525 @@ fn ensure_ipv4_forwarding_enabled() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn nat_table_exists(ruleset: &schema::Nftables) -> bool {
-    ruleset.objects.iter().any(|object| match object {
-        schema::NfObject::ListObject(schema::NfListObject::Table(table)) => {
-            table.family == types::NfFamily::IP && table.name == NAT_TABLE
-        }
-        _ => false,
-    })
+/// Name of the nftables tables this process owns, see [`set_table_name`].
+fn table_name() -> &'static str {
+    TABLE_NAME.get().copied().unwrap_or(DEFAULT_TABLE)
 }
 
-fn nat_chain_exists(ruleset: &schema::Nftables) -> bool {
-    ruleset.objects.iter().any(|object| match object {
-        schema::NfObject::ListObject(schema::NfListObject::Chain(chain)) => {
-            chain.family == types::NfFamily::IP
-                && chain.table == NAT_TABLE
-                && chain.name == NAT_CHAIN
+/// Own the nftables tables named `name` rather than `cloude`. Processes sharing
+/// a host need tables of their own, as each replaces its tables whole. Returns
+/// false if the tables were already named, by an earlier call or a change.
+pub fn set_table_name(name: &'static str) -> bool {
+    TABLE_NAME.set(name).is_ok()
+}
+
+/// Everything in the tables this process owns. Every change renders it whole
+/// into them, in one transaction: the tables never hold half a change, and
+/// never a rule this process did not ask for.
+#[derive(Clone, Default)]
+struct Rules {
+    /// Ranges masqueraded on their way out of the host.
+    nat: BTreeSet<(Ipv4Addr, u8)>,
+    /// TCP ports of the host forwarded to a guest, by guest: host port -> guest port.
+    forwards: BTreeMap<Ipv4Addr, BTreeMap<u16, u16>>,
+    /// Segments kept apart from the rest of their supernet, see [`isolate_segment`].
+    segments: BTreeMap<(Ipv4Addr, u8), (Ipv4Addr, u8)>,
+    /// Guests whose packets are not forwarded anywhere, see [`restrict_egress`].
+    restricted: BTreeSet<Ipv4Addr>,
+}
+
+/// A chain of one of the tables, and its rules.
+struct ChainRules {
+    name: String,
+    /// Type, hook and priority of a base chain; `None` for a chain jumped to.
+    hook: Option<(types::NfChainType, types::NfHook, i32)>,
+    rules: Vec<Vec<Statement<'static>>>,
+}
+
+impl ChainRules {
+    fn base(name: &str, hook: (types::NfChainType, types::NfHook, i32)) -> Self {
+        Self {
+            name: name.to_string(),
+            hook: Some(hook),
+            rules: Vec::new(),
         }
-        _ => false,
-    })
+    }
+
+    fn regular(name: String) -> Self {
+        Self {
+            name,
+            hook: None,
+            rules: Vec::new(),
+        }
+    }
 }
 
-/// Check if NAT masquerade rule already exists for the given CIDR.
-fn nat_rule_exists(ruleset: &schema::Nftables, cidr_base: Ipv4Addr, prefix_len: u8) -> bool {
-    ruleset.objects.iter().any(|object| match object {
-        schema::NfObject::ListObject(schema::NfListObject::Rule(rule))
-            if rule.family == types::NfFamily::IP
-                && rule.table == NAT_TABLE
-                && rule.chain == NAT_CHAIN =>
+impl Rules {
+    /// Chains of the table of `family`, or `None` if there is no need for the table.
+    fn chains(&self, family: types::NfFamily) -> Option<Vec<ChainRules>> {
+        if family == types::NfFamily::Bridge
+            && self.segments.is_empty()
+            && self.restricted.is_empty()
         {
-            let mut has_ip_match = false;
-            let mut has_masquerade = false;
-
-            for stmt in rule.expr.iter() {
-                match stmt {
-                    Statement::Match(m) => {
-                        if let Expression::Named(NamedExpression::Prefix(prefix)) = &m.right {
-                            if let Expression::String(addr) = &*prefix.addr {
-                                if addr.as_ref() == cidr_base.to_string()
-                                    && prefix.len == u32::from(prefix_len)
-                                {
-                                    has_ip_match = true;
-                                }
-                            }
-                        }
-                    }
-                    Statement::Masquerade(_) => has_masquerade = true,
-                    _ => {}
+            return None;
+        }
+        let mut chains = Vec::new();
+
+        if family == types::NfFamily::IP {
+            let mut postrouting = ChainRules::base(
+                POSTROUTING_CHAIN,
+                (types::NfChainType::NAT, types::NfHook::Postrouting, 1),
+            );
+            for &(network, len) in &self.nat {
+                postrouting.rules.push(vec![
+                    Statement::Match(Match {
+                        left: ip_field("saddr"),
+                        right: prefix(network, len),
+                        op: Operator::EQ,
+                    }),
+                    Statement::Masquerade(None),
+                ]);
+            }
+            chains.push(postrouting);
+
+            // One chain per guest with forwarded ports, jumped to from prerouting
+            let mut prerouting = ChainRules::base(
+                PREROUTING_CHAIN,
+                (types::NfChainType::NAT, types::NfHook::Prerouting, -100),
+            );
+            for (guest_ip, ports) in &self.forwards {
+                let mut guest = ChainRules::regular(guest_chain(*guest_ip));
+                for (&host_port, &guest_port) in ports {
+                    guest.rules.push(vec![
+                        Statement::Match(Match {
+                            left: Expression::Named(NamedExpression::Payload(
+                                Payload::PayloadField(PayloadField {
+                                    protocol: "tcp".into(),
+                                    field: "dport".into(),
+                                }),
+                            )),
+                            right: Expression::Number(u32::from(host_port)),
+                            op: Operator::EQ,
+                        }),
+                        Statement::DNAT(Some(NAT {
+                            addr: Some(Expression::String(guest_ip.to_string().into())),
+                            family: Some(NATFamily::IP),
+                            port: Some(Expression::Number(u32::from(guest_port))),
+                            flags: None,
+                        })),
+                    ]);
+                }
+                prerouting.rules.push(vec![jump(&guest.name)]);
+                chains.push(guest);
+            }
+            chains.push(prerouting);
+        }
+
+        // One chain per segment, jumped to from forward for what the segment sends.
+        // The `ip` family sees what the host routes between segments, the `bridge`
+        // family what the bridge switches from one VM to another.
+        let mut forward = ChainRules::base(
+            FORWARD_CHAIN,
+            (types::NfChainType::Filter, types::NfHook::Forward, 0),
+        );
+        for guest_ip in &self.restricted {
+            forward.rules.push(vec![
+                Statement::Match(Match {
+                    left: ip_field("saddr"),
+                    right: Expression::String(guest_ip.to_string().into()),
+                    op: Operator::EQ,
+                }),
+                Statement::Drop(None),
+            ]);
+        }
+        for (&(segment, segment_len), &(supernet, supernet_len)) in &self.segments {
+            let mut isolation = ChainRules::regular(segment_chain(segment, segment_len));
+            isolation.rules.push(vec![
+                Statement::Match(Match {
+                    left: ip_field("daddr"),
+                    right: prefix(supernet, supernet_len),
+                    op: Operator::EQ,
+                }),
+                Statement::Match(Match {
+                    left: ip_field("daddr"),
+                    right: prefix(segment, segment_len),
+                    op: Operator::NEQ,
+                }),
+                Statement::Drop(None),
+            ]);
+            forward.rules.push(vec![
+                Statement::Match(Match {
+                    left: ip_field("saddr"),
+                    right: prefix(segment, segment_len),
+                    op: Operator::EQ,
+                }),
+                jump(&isolation.name),
+            ]);
+            chains.push(isolation);
+        }
+        chains.push(forward);
+
+        Some(chains)
+    }
+
+    /// Rules in the table of `family`, once rendered.
+    fn rule_count(&self, family: types::NfFamily) -> usize {
+        self.chains(family).map_or(0, |chains| {
+            chains.iter().map(|chain| chain.rules.len()).sum()
+        })
+    }
+}
+
+/// What the tables hold, as last rendered; `None` before the first change.
+static RULES: Mutex<Option<Rules>> = Mutex::new(None);
+
+/// Applies `change` to the rules, and replaces the tables with the result in
+/// one transaction. The rules are left as they were if nftables refuses it.
+fn update_rules(change: impl FnOnce(&mut Rules)) -> Result<(), Box<dyn std::error::Error>> {
+    let mut current = RULES.lock().unwrap_or_else(|e| e.into_inner());
+    let mut rules = current.clone().unwrap_or_default();
+    change(&mut rules);
+
+    let table = table_name();
+    let mut batch = Batch::new();
+    for family in [types::NfFamily::IP, types::NfFamily::Bridge] {
+        let previous = match current.as_ref() {
+            Some(previous) => previous.chains(family),
+            None => {
+                // First change: whatever an earlier run left goes, along with
+                // the tables of earlier versions. Adding a table before deleting
+                // it makes the deletion work whether it exists or not.
+                let legacy = LEGACY_TABLES
+                    .iter()
+                    .filter(|(legacy_family, _)| *legacy_family == family)
+                    .map(|(_, name)| *name);
+                for name in legacy.chain([table]) {
+                    batch.add(table_object(family, name));
+                    batch.delete(table_object(family, name));
                 }
+                None
             }
+        };
 
-            has_ip_match && has_masquerade
+        let Some(chains) = rules.chains(family) else {
+            if previous.is_some() {
+                batch.add(table_object(family, table));
+                batch.delete(table_object(family, table));
+            }
+            continue;
+        };
+
+        batch.add(table_object(family, table));
+        batch.add_cmd(schema::NfCmd::Flush(schema::FlushObject::Table(
+            schema::Table {
+                family,
+                name: table.into(),
+                ..Default::default()
+            },
+        )));
+        // Flushed, the chains of guests and segments that went away are
+        // referenced by no jump anymore, and can go.
+        for gone in previous
+            .iter()
+            .flatten()
+            .filter(|chain| !chains.iter().any(|kept| kept.name == chain.name))
+        {
+            batch.delete(schema::NfListObject::Chain(schema::Chain {
+                family,
+                table: table.into(),
+                name: gone.name.clone().into(),
+                ..Default::default()
+            }));
         }
-        _ => false,
+        for chain in &chains {
+            let (chain_type, hook, prio) = match chain.hook {
+                Some((chain_type, hook, prio)) => (Some(chain_type), Some(hook), Some(prio)),
+                None => (None, None, None),
+            };
+            batch.add(schema::NfListObject::Chain(schema::Chain {
+                family,
+                table: table.into(),
+                name: chain.name.clone().into(),
+                _type: chain_type,
+                hook,
+                prio,
+                policy: chain.hook.map(|_| types::NfChainPolicy::Accept),
+                ..Default::default()
+            }));
+        }
+        // Chains jumped to are added before the rules jumping to them.
+        for chain in chains {
+            for expr in chain.rules {
+                batch.add(schema::NfListObject::Rule(schema::Rule {
+                    family,
+                    table: table.into(),
+                    chain: chain.name.clone().into(),
+                    expr: expr.into(),
+                    ..Default::default()
+                }));
+            }
+        }
+    }
+
+    helper::apply_ruleset(&batch.to_nftables())?;
+    *current = Some(rules);
+    Ok(())
+}
+
+fn table_object(family: types::NfFamily, name: &str) -> schema::NfListObject<'static> {
+    schema::NfListObject::Table(schema::Table {
+        family,
+        name: name.to_string().into(),
+        ..Default::default()
     })
 }
 
+/// Chain of the port forwards to `guest_ip`.
+fn guest_chain(guest_ip: Ipv4Addr) -> String {
+    format!("vm_{}", guest_ip.to_string().replace('.', "_"))
+}
+
+/// Chain of the isolation of segment `segment`/`segment_len`.
+fn segment_chain(segment: Ipv4Addr, segment_len: u8) -> String {
+    format!(
+        "segment_{}_{}",
+        segment.to_string().replace('.', "_"),
+        segment_len
+    )
+}
+
+fn jump(chain: &str) -> Statement<'static> {
+    Statement::Jump(JumpTarget {
+        target: chain.to_string().into(),
+    })
+}
+
+fn ip_field(field: &'static str) -> Expression<'static> {
+    Expression::Named(NamedExpression::Payload(Payload::PayloadField(
+        PayloadField {
+            protocol: "ip".into(),
+            field: field.into(),
+        },
+    )))
+}
+
+fn prefix(addr: Ipv4Addr, len: u8) -> Expression<'static> {
+    Expression::Named(NamedExpression::Prefix(Prefix {
+        addr: Box::new(Expression::String(addr.to_string().into())),
+        len: u32::from(len),
+    }))
+}
+
+/// Check that IPv4 forwarding is enabled, the range is masqueraded, and the `ip`
+/// table holds the rules last rendered into it: none was removed from outside.
+pub fn nat_is_configured(
+    ip_range: Ipv4Addr,
+    ip_mask: u8,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let cidr_base = network_addr(ip_range, ip_mask)?;
+    let forwarding = std::fs::read_to_string("/proc/sys/net/ipv4/ip_forward")?;
+    if forwarding.trim() != "1" {
+        return Ok(false);
+    }
+
+    let expected = match RULES.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(rules) if rules.nat.contains(&(cidr_base, ip_mask)) => {
+            rules.rule_count(types::NfFamily::IP)
+        }
+        _ => return Ok(false),
+    };
+    let table = table_name();
+    let ruleset = helper::get_current_ruleset()?;
+    let installed = ruleset
+        .objects
+        .iter()
+        .filter(|object| {
+            matches!(
+                object,
+                schema::NfObject::ListObject(schema::NfListObject::Rule(rule))
+                    if rule.family == types::NfFamily::IP && rule.table == table
+            )
+        })
+        .count();
+    Ok(installed == expected)
+}
+
 /// Set up NAT rules using nftables
 pub fn setup_nat(ip_range: Ipv4Addr, ip_mask: u8) -> Result<(), Box<dyn std::error::Error>> {
     let cidr_base = network_addr(ip_range, ip_mask)?;
     ensure_ipv4_forwarding_enabled()?;
 
-    let ruleset = helper::get_current_ruleset()?;
-    let table_exists = nat_table_exists(&ruleset);
-    let chain_exists = nat_chain_exists(&ruleset);
-    let rule_exists = nat_rule_exists(&ruleset, cidr_base, ip_mask);
+    debug!("Setting up NAT rules for {}/{}", cidr_base, ip_mask);
+    update_rules(|rules| {
+        rules.nat.insert((cidr_base, ip_mask));
+    })?;
+    debug!("NAT rules setup complete for {}/{}", cidr_base, ip_mask);
+    Ok(())
+}
+
+/// Forward connections to TCP `host_port` of the host to `guest_port` of `guest_ip`,
+/// replacing any earlier forward of `host_port`.
+///
+/// The rule is a DNAT in prerouting, so it applies to connections coming from
+/// other machines: from the host itself, connect to the guest IP directly.
+pub fn setup_port_forward(
+    host_port: u16,
+    guest_ip: Ipv4Addr,
+    guest_port: u16,
+) -> Result<(), Box<dyn std::error::Error>> {
+    ensure_ipv4_forwarding_enabled()?;
 
-    if table_exists && chain_exists && rule_exists {
-        debug!("NAT rules already exist for {}/{}", cidr_base, ip_mask);
+    debug!(
+        "Forwarding host port {} to {}:{}",
+        host_port, guest_ip, guest_port
+    );
+    update_rules(|rules| {
+        remove_forward(rules, host_port);
+        rules
+            .forwards
+            .entry(guest_ip)
+            .or_default()
+            .insert(host_port, guest_port);
+    })
+}
+
+/// Remove the forward of TCP `host_port` set up by [`setup_port_forward`], if any.
+pub fn remove_port_forward(host_port: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let forwarded = RULES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .is_some_and(|rules| {
+            rules
+                .forwards
+                .values()
+                .any(|ports| ports.contains_key(&host_port))
+        });
+    if !forwarded {
         return Ok(());
     }
 
-    debug!("Setting up NAT rules for {}/{}", cidr_base, ip_mask);
-    let mut batch = Batch::new();
+    update_rules(|rules| remove_forward(rules, host_port))?;
+    debug!("Removed forward of host port {}", host_port);
+    Ok(())
+}
+
+/// Drops the forward of `host_port`, and the guest it went to if it has no other.
+fn remove_forward(rules: &mut Rules, host_port: u16) {
+    for ports in rules.forwards.values_mut() {
+        ports.remove(&host_port);
+    }
+    rules.forwards.retain(|_, ports| !ports.is_empty());
+}
+
+/// Give the bridge the gateway address of a tenant segment, so that the VMs of
+/// the segment reach the host on-link. Does nothing if it already has it.
+pub async fn setup_segment_gateway(
+    bridge_name: &str,
+    gateway: Ipv4Addr,
+    segment_len: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+
+    let bridge_index = get_link_by_name(&handle, bridge_name)
+        .await?
+        .ok_or_else(|| format!("Bridge {} not found", bridge_name))?
+        .header
+        .index;
 
-    if !table_exists {
-        batch.add(schema::NfListObject::Table(schema::Table {
-            family: types::NfFamily::IP,
-            name: NAT_TABLE.into(),
-            ..Default::default()
-        }));
+    debug!(
+        "Adding segment gateway {}/{} to bridge",
+        gateway, segment_len
+    );
+    match handle
+        .address()
+        .add(bridge_index, gateway.into(), segment_len)
+        .execute()
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) if e.to_string().contains("File exists") => Ok(()),
+        Err(e) => Err(e.into()),
     }
+}
 
-    if !chain_exists {
-        batch.add(schema::NfListObject::Chain(schema::Chain {
-            family: types::NfFamily::IP,
-            table: NAT_TABLE.into(),
-            name: NAT_CHAIN.into(),
-            _type: Some(types::NfChainType::NAT),
-            hook: Some(types::NfHook::Postrouting),
-            prio: Some(1),
-            policy: Some(types::NfChainPolicy::Accept),
-            ..Default::default()
-        }));
+/// Drop the packets of segment `segment`/`segment_len` to the rest of `supernet`,
+/// where the other segments are. Traffic within the segment, to the host and
+/// beyond the supernet is left alone.
+///
+/// Rules go in the forward hook of both the `ip` family, for packets the host
+/// routes between segments, and the `bridge` family, for frames the bridge
+/// switches from one VM to another. They are kept once added: a segment given
+/// to another tenant later needs the same ones.
+pub fn isolate_segment(
+    segment: Ipv4Addr,
+    segment_len: u8,
+    supernet: Ipv4Addr,
+    supernet_len: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let segment = network_addr(segment, segment_len)?;
+    let supernet = network_addr(supernet, supernet_len)?;
+
+    let isolated = RULES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .is_some_and(|rules| rules.segments.contains_key(&(segment, segment_len)));
+    if isolated {
+        debug!("Segment {}/{} is already isolated", segment, segment_len);
+        return Ok(());
     }
 
-    if !rule_exists {
-        batch.add(schema::NfListObject::Rule(schema::Rule {
-            family: types::NfFamily::IP,
-            table: NAT_TABLE.into(),
-            chain: NAT_CHAIN.into(),
-            expr: vec![
-                Statement::Match(Match {
-                    left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(
-                        PayloadField {
-                            protocol: "ip".into(),
-                            field: "saddr".into(),
-                        },
-                    ))),
-                    right: Expression::Named(NamedExpression::Prefix(Prefix {
-                        addr: Box::new(Expression::String(cidr_base.to_string().into())),
-                        len: u32::from(ip_mask),
-                    })),
-                    op: Operator::EQ,
-                }),
-                Statement::Masquerade(None),
-            ]
-            .into(),
-            ..Default::default()
-        }));
+    update_rules(|rules| {
+        rules
+            .segments
+            .insert((segment, segment_len), (supernet, supernet_len));
+    })?;
+    debug!(
+        "Isolated segment {}/{} from the rest of {}/{}",
+        segment, segment_len, supernet, supernet_len
+    );
+    Ok(())
+}
+
+/// Drop everything `guest_ip` sends beyond the host: the host routes none of its
+/// packets, and the bridge switches none of its frames to other VMs. What it sends
+/// to the host itself, like requests to a proxy there, still arrives.
+///
+/// Replies to the ports forwarded to the guest are dropped as well.
+pub fn restrict_egress(guest_ip: Ipv4Addr) -> Result<(), Box<dyn std::error::Error>> {
+    let restricted = RULES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .is_some_and(|rules| rules.restricted.contains(&guest_ip));
+    if restricted {
+        return Ok(());
     }
 
-    helper::apply_ruleset(&batch.to_nftables())?;
-    debug!("NAT rules setup complete for {}/{}", cidr_base, ip_mask);
+    update_rules(|rules| {
+        rules.restricted.insert(guest_ip);
+    })?;
+    debug!("Restricted the egress of {}", guest_ip);
+    Ok(())
+}
+
+/// Forward the packets of `guest_ip` again, undoing [`restrict_egress`].
+pub fn lift_egress_restriction(guest_ip: Ipv4Addr) -> Result<(), Box<dyn std::error::Error>> {
+    let restricted = RULES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .is_some_and(|rules| rules.restricted.contains(&guest_ip));
+    if !restricted {
+        return Ok(());
+    }
+
+    update_rules(|rules| {
+        rules.restricted.remove(&guest_ip);
+    })?;
+    debug!("Lifted the egress restriction of {}", guest_ip);
     Ok(())
 }
 
@@ -274,17 +761,27 @@ pub async fn setup_guest_iface(
     tokio::spawn(connection);
 
     // Get bridge index
-    let bridge_index = get_link_by_name(&handle, &bridge_name)
+    let bridge = get_link_by_name(&handle, &bridge_name)
         .await?
-        .ok_or_else(|| format!("Bridge {} not found", bridge_name))?
-        .header
-        .index;
+        .ok_or_else(|| format!("Bridge {} not found", bridge_name))?;
+    let bridge_index = bridge.header.index;
 
-    let guest_iface_index = get_link_by_name(&handle, guest_iface_name)
+    let guest_iface = get_link_by_name(&handle, guest_iface_name)
         .await?
-        .ok_or_else(|| format!("Guest interface {} not found", guest_iface_name))?
-        .header
-        .index;
+        .ok_or_else(|| format!("Guest interface {} not found", guest_iface_name))?;
+    let guest_iface_index = guest_iface.header.index;
+
+    // A port with a smaller MTU would have the bridge drop the larger frames of
+    // the others to it, or lower the MTU of the bridge for all of them.
+    if let (Some(iface_mtu), Some(bridge_mtu)) = (link_mtu(&guest_iface), link_mtu(&bridge))
+        && iface_mtu != bridge_mtu
+    {
+        return Err(format!(
+            "Guest interface {} has MTU {}, bridge {} has MTU {}",
+            guest_iface_name, iface_mtu, bridge_name, bridge_mtu
+        )
+        .into());
+    }
 
     // Set iface created by VMM to be slave of bridge
     debug!(
@@ -311,3 +808,67 @@ pub async fn setup_guest_iface(
     debug!("Guest interface {} setup complete", guest_iface_name);
     Ok(())
 }
+
+/// Give the guest iface a host-side address and enable it, without any bridge:
+/// the guest can only reach the host through this link.
+pub async fn setup_host_link(
+    guest_iface_name: &str,
+    ip_host: Ipv4Addr,
+    ip_mask: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Create rtnetlink connection
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+
+    let guest_iface_index = get_link_by_name(&handle, guest_iface_name)
+        .await?
+        .ok_or_else(|| format!("Guest interface {} not found", guest_iface_name))?
+        .header
+        .index;
+
+    debug!("Adding IP address {} to {}", ip_host, guest_iface_name);
+    handle
+        .address()
+        .add(guest_iface_index, ip_host.into(), ip_mask)
+        .execute()
+        .await?;
+
+    debug!("Enabling guest interface: {}", guest_iface_name);
+    handle
+        .link()
+        .set(LinkUnspec::new_with_index(guest_iface_index).up().build())
+        .execute()
+        .await?;
+    debug!("Host link {} setup complete", guest_iface_name);
+    Ok(())
+}
+
+/// Take `ip_host`/`ip_mask` back from the interface of a host-only link, see
+/// [`setup_host_link`]. Does nothing if it does not have it.
+pub async fn remove_host_link_address(
+    guest_iface_name: &str,
+    ip_host: Ipv4Addr,
+    ip_mask: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+
+    let guest_iface_index = get_link_by_name(&handle, guest_iface_name)
+        .await?
+        .ok_or_else(|| format!("Guest interface {} not found", guest_iface_name))?
+        .header
+        .index;
+
+    let mut addresses = handle
+        .address()
+        .get()
+        .set_link_index_filter(guest_iface_index)
+        .set_address_filter(ip_host.into())
+        .set_prefix_length_filter(ip_mask)
+        .execute();
+    while let Some(address) = addresses.try_next().await? {
+        debug!("Removing IP address {} from {}", ip_host, guest_iface_name);
+        handle.address().del(address).execute().await?;
+    }
+    Ok(())
+}
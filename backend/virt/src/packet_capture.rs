@@ -0,0 +1,180 @@
+//! Capture of the frames crossing the TAP device of a VM, in pcap format.
+//!
+//! A packet socket bound to the TAP sees every frame it carries in both
+//! directions, as tcpdump would, with no need for promiscuous mode: a TAP only
+//! carries the frames of its VM. The records are written in the classic pcap
+//! format, which Wireshark and tcpdump read.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Magic number of pcap files with microsecond timestamps, in host byte order.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// What a TAP device carries.
+const LINKTYPE_ETHERNET: u32 = 1;
+/// Length of the global header of a pcap file.
+pub const PCAP_HEADER_LEN: usize = 24;
+/// Length of the header of each pcap record.
+pub const RECORD_HEADER_LEN: usize = 16;
+/// Most bytes of a frame a capture keeps.
+pub const MAX_SNAPLEN: u32 = 65535;
+
+/// Packet socket bound to a TAP device, closed on drop.
+pub struct PacketCapture {
+    socket: OwnedFd,
+    snaplen: u32,
+    buf: Vec<u8>,
+}
+
+impl PacketCapture {
+    /// Starts capturing the frames of TAP `interface`, keeping the first
+    /// `snaplen` bytes of each. Needs `CAP_NET_RAW`.
+    pub fn open(interface: &str, snaplen: u32) -> io::Result<Self> {
+        let name = CString::new(interface).map_err(io::Error::other)?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Protocol 0 receives nothing until the bind, so no frame of another
+        // interface gets queued in between.
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        addr.sll_ifindex = ifindex as libc::c_int;
+        let ret = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let snaplen = snaplen.clamp(1, MAX_SNAPLEN);
+        Ok(Self {
+            socket,
+            snaplen,
+            buf: vec![0; snaplen as usize],
+        })
+    }
+
+    /// Global header of the pcap file the records of this capture go in.
+    pub fn header(&self) -> [u8; PCAP_HEADER_LEN] {
+        pcap_header(self.snaplen)
+    }
+
+    /// Waits up to `timeout` for the next frame and returns it as a pcap
+    /// record, or `None` if none came.
+    pub fn next_record(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let mut pollfd = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::Interrupted => Ok(None),
+                _ => Err(e),
+            };
+        }
+        if ready == 0 {
+            return Ok(None);
+        }
+
+        // With `MSG_TRUNC`, the length of the whole frame, even past the buffer.
+        let len = unsafe {
+            libc::recv(
+                self.socket.as_raw_fd(),
+                self.buf.as_mut_ptr() as *mut libc::c_void,
+                self.buf.len(),
+                libc::MSG_TRUNC | libc::MSG_DONTWAIT,
+            )
+        };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(None),
+                _ => Err(e),
+            };
+        }
+        let len = len as usize;
+        let captured = &self.buf[..len.min(self.buf.len())];
+        Ok(Some(pcap_record(SystemTime::now(), captured, len)))
+    }
+}
+
+/// Global header of a pcap file of Ethernet frames cut to `snaplen` bytes.
+pub fn pcap_header(snaplen: u32) -> [u8; PCAP_HEADER_LEN] {
+    let mut header = [0; PCAP_HEADER_LEN];
+    header[0..4].copy_from_slice(&PCAP_MAGIC.to_ne_bytes());
+    header[4..6].copy_from_slice(&2u16.to_ne_bytes());
+    header[6..8].copy_from_slice(&4u16.to_ne_bytes());
+    // Bytes 8..16, the time zone and accuracy of the timestamps, stay zero.
+    header[16..20].copy_from_slice(&snaplen.to_ne_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_ne_bytes());
+    header
+}
+
+/// Pcap record of the `captured` bytes of a frame of `len` bytes seen at `time`.
+pub fn pcap_record(time: SystemTime, captured: &[u8], len: usize) -> Vec<u8> {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + captured.len());
+    record.extend_from_slice(&(since_epoch.as_secs() as u32).to_ne_bytes());
+    record.extend_from_slice(&since_epoch.subsec_micros().to_ne_bytes());
+    record.extend_from_slice(&(captured.len() as u32).to_ne_bytes());
+    record.extend_from_slice(&(len.min(u32::MAX as usize) as u32).to_ne_bytes());
+    record.extend_from_slice(captured);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(bytes: &[u8], at: usize) -> u32 {
+        u32::from_ne_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_pcap_header() {
+        let header = pcap_header(1500);
+        assert_eq!(word(&header, 0), PCAP_MAGIC);
+        assert_eq!(u16::from_ne_bytes([header[4], header[5]]), 2);
+        assert_eq!(u16::from_ne_bytes([header[6], header[7]]), 4);
+        assert_eq!(word(&header, 8), 0);
+        assert_eq!(word(&header, 12), 0);
+        assert_eq!(word(&header, 16), 1500);
+        assert_eq!(word(&header, 20), LINKTYPE_ETHERNET);
+    }
+
+    #[test]
+    fn test_pcap_record() {
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let record = pcap_record(time, b"frame", 1514);
+        assert_eq!(record.len(), RECORD_HEADER_LEN + 5);
+        assert_eq!(word(&record, 0), 1_700_000_000);
+        assert_eq!(word(&record, 4), 123_456);
+        assert_eq!(word(&record, 8), 5);
+        assert_eq!(word(&record, 12), 1514);
+        assert_eq!(&record[RECORD_HEADER_LEN..], b"frame");
+    }
+
+    #[test]
+    fn test_open_unknown_interface() {
+        assert!(PacketCapture::open("cloude-no-such-tap", 128).is_err());
+    }
+}
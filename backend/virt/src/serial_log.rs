@@ -0,0 +1,160 @@
+//! Serial output of a VM written to `{dir}/{vm_id}.log`, rotated by size.
+//!
+//! Once the log reaches `max_bytes`, it is compressed to `{vm_id}.log.1.gz` and
+//! started over; older rotations move to `.2.gz`, `.3.gz`... and the ones beyond
+//! `max_files` are deleted.
+
+use flate2::{Compression, write::GzEncoder};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_MAX_FILES: usize = 5;
+
+/// Where and how much serial output of each VM is kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerialLogConfig {
+    pub dir: PathBuf,
+    /// Size of the log that triggers a rotation.
+    pub max_bytes: u64,
+    /// Compressed rotations kept per VM.
+    pub max_files: usize,
+}
+
+impl SerialLogConfig {
+    /// Current log of VM `vm_id`.
+    pub fn path(&self, vm_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.log", vm_id))
+    }
+
+    /// Rotation `n` of the log of VM `vm_id`, 1 being the most recent.
+    pub fn rotated_path(&self, vm_id: &str, n: usize) -> PathBuf {
+        self.dir.join(format!("{}.log.{}.gz", vm_id, n))
+    }
+}
+
+/// Serial sink appending to the log of one VM.
+pub struct SerialLog {
+    config: SerialLogConfig,
+    vm_id: String,
+    file: File,
+    /// Bytes in the current log.
+    written: u64,
+}
+
+impl SerialLog {
+    /// Opens the log of VM `vm_id`, appending to what a previous run left.
+    pub fn open(config: &SerialLogConfig, vm_id: &str) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(config.path(vm_id))?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            config: config.clone(),
+            vm_id: vm_id.to_string(),
+            file,
+            written,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = self.config.path(&self.vm_id);
+        let _ = fs::remove_file(self.config.rotated_path(&self.vm_id, self.config.max_files));
+        for n in (1..self.config.max_files).rev() {
+            let from = self.config.rotated_path(&self.vm_id, n);
+            if from.exists() {
+                fs::rename(&from, self.config.rotated_path(&self.vm_id, n + 1))?;
+            }
+        }
+        if self.config.max_files > 0 {
+            compress(&path, &self.config.rotated_path(&self.vm_id, 1))?;
+        }
+        self.file = File::create(&path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SerialLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.config.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes `from` gzipped to `to`, through a temporary file so that `to` is never partial.
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let tmp = to.with_extension("gz.tmp");
+    let mut encoder = GzEncoder::new(File::create(&tmp)?, Compression::default());
+    io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&tmp, to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn gunzip(path: &Path) -> String {
+        let mut text = String::new();
+        GzDecoder::new(File::open(path).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SerialLogConfig {
+            dir: dir.path().join("logs"),
+            max_bytes: 8,
+            max_files: 2,
+        };
+        let mut log = SerialLog::open(&config, "vm-1").unwrap();
+        for line in ["boot 1\n", "boot 2\n", "boot 3\n", "boot 4\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(config.path("vm-1")).unwrap(), "boot 4\n");
+        assert_eq!(gunzip(&config.rotated_path("vm-1", 1)), "boot 3\n");
+        assert_eq!(gunzip(&config.rotated_path("vm-1", 2)), "boot 2\n");
+        // Beyond `max_files`.
+        assert!(!config.rotated_path("vm-1", 3).exists());
+    }
+
+    #[test]
+    fn test_reopen_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SerialLogConfig {
+            dir: dir.path().to_path_buf(),
+            max_bytes: 1024,
+            max_files: 0,
+        };
+        SerialLog::open(&config, "vm-1")
+            .unwrap()
+            .write_all(b"first\n")
+            .unwrap();
+        SerialLog::open(&config, "vm-1")
+            .unwrap()
+            .write_all(b"second\n")
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(config.path("vm-1")).unwrap(),
+            "first\nsecond\n"
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Traffic of a VM counted by eBPF programs on its TAP device.
+//!
+//! A program on each direction of the TAP adds the length of every packet to a
+//! counter in an array map, which the backend reads whenever it likes: no
+//! ruleset to list and parse, and no packet goes uncounted. Programs attach with
+//! tcx links (Linux 6.6 or later), which go away with their file descriptors or
+//! with the TAP.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+// From `include/uapi/linux/bpf.h`.
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_LOOKUP_ELEM: libc::c_int = 1;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_LINK_CREATE: libc::c_int = 28;
+const BPF_MAP_TYPE_ARRAY: u32 = 2;
+const BPF_PROG_TYPE_SCHED_CLS: u32 = 3;
+const BPF_TCX_INGRESS: u32 = 46;
+const BPF_TCX_EGRESS: u32 = 47;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+const BPF_ADD: i32 = 0;
+/// Lets the packet go on to the next program, if any.
+const TCX_NEXT: i32 = -1;
+
+/// Slots of the map: what enters the host from the TAP was sent by the VM.
+const TX_SLOT: u32 = 0;
+const RX_SLOT: u32 = 1;
+
+/// Bytes and packets counted so far on a TAP, from the VM's point of view.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+}
+
+/// Counting programs attached to both directions of a TAP, detached on drop.
+pub struct TrafficCounter {
+    map: OwnedFd,
+    _programs: [OwnedFd; 2],
+    _links: [OwnedFd; 2],
+}
+
+impl TrafficCounter {
+    /// Starts counting the traffic of TAP `interface`. Needs `CAP_BPF` and
+    /// `CAP_NET_ADMIN`.
+    pub fn attach(interface: &str) -> io::Result<Self> {
+        let name = CString::new(interface).map_err(io::Error::other)?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let map = bpf_fd(
+            BPF_MAP_CREATE,
+            &mut MapCreateAttr {
+                map_type: BPF_MAP_TYPE_ARRAY,
+                key_size: 4,
+                value_size: 16,
+                max_entries: 2,
+                map_flags: 0,
+            },
+        )?;
+        let tx = load_program(&counter_program(map.as_raw_fd(), TX_SLOT))?;
+        let rx = load_program(&counter_program(map.as_raw_fd(), RX_SLOT))?;
+        let tx_link = attach_program(&tx, ifindex, BPF_TCX_INGRESS)?;
+        let rx_link = attach_program(&rx, ifindex, BPF_TCX_EGRESS)?;
+        Ok(Self {
+            map,
+            _programs: [tx, rx],
+            _links: [tx_link, rx_link],
+        })
+    }
+
+    /// Traffic since the counter was attached.
+    pub fn read(&self) -> io::Result<Counters> {
+        let [tx_bytes, tx_packets] = self.slot(TX_SLOT)?;
+        let [rx_bytes, rx_packets] = self.slot(RX_SLOT)?;
+        Ok(Counters {
+            rx_bytes,
+            rx_packets,
+            tx_bytes,
+            tx_packets,
+        })
+    }
+
+    /// Bytes and packets of one direction.
+    fn slot(&self, slot: u32) -> io::Result<[u64; 2]> {
+        let mut value = [0u64; 2];
+        bpf(
+            BPF_MAP_LOOKUP_ELEM,
+            &mut MapLookupAttr {
+                map_fd: self.map.as_raw_fd() as u32,
+                _pad: 0,
+                key: &slot as *const u32 as u64,
+                value: value.as_mut_ptr() as u64,
+                flags: 0,
+            },
+        )?;
+        Ok(value)
+    }
+}
+
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+struct MapLookupAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+}
+
+#[repr(C)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+    relative_fd: u32,
+    _pad: u32,
+    expected_revision: u64,
+}
+
+/// One eBPF instruction, as the kernel reads it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Insn {
+    code: u8,
+    /// Destination register in the low 4 bits, source register in the high ones.
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn {
+        code,
+        regs: (src << 4) | dst,
+        off,
+        imm,
+    }
+}
+
+/// Program adding the length of each packet, and 1, to the bytes and packets
+/// of slot `slot` of map `map_fd`.
+fn counter_program(map_fd: RawFd, slot: u32) -> Vec<Insn> {
+    vec![
+        insn(0xbf, 6, 1, 0, 0),                      // r6 = r1 (the __sk_buff)
+        insn(0x62, 10, 0, -4, slot as i32),          // *(u32 *)(r10 - 4) = slot
+        insn(0xbf, 2, 10, 0, 0),                     // r2 = r10
+        insn(0x07, 2, 0, 0, -4),                     // r2 += -4
+        insn(0x18, 1, BPF_PSEUDO_MAP_FD, 0, map_fd), // r1 = map, over two instructions
+        insn(0x00, 0, 0, 0, 0),
+        insn(0x85, 0, 0, 0, BPF_FUNC_MAP_LOOKUP_ELEM), // r0 = bpf_map_lookup_elem(r1, r2)
+        insn(0x15, 0, 0, 4, 0),                        // if r0 == 0 goto out
+        insn(0x61, 1, 6, 0, 0),                        // r1 = skb->len
+        insn(0xdb, 0, 1, 0, BPF_ADD),                  // lock *(u64 *)(r0 + 0) += r1
+        insn(0xb7, 1, 0, 0, 1),                        // r1 = 1
+        insn(0xdb, 0, 1, 8, BPF_ADD),                  // lock *(u64 *)(r0 + 8) += r1
+        insn(0xb7, 0, 0, 0, TCX_NEXT),                 // out: r0 = TCX_NEXT
+        insn(0x95, 0, 0, 0, 0),                        // exit
+    ]
+}
+
+fn load_program(insns: &[Insn]) -> io::Result<OwnedFd> {
+    let license = c"GPL";
+    let mut prog_name = [0u8; 16];
+    prog_name[..14].copy_from_slice(b"cloude_traffic");
+    bpf_fd(
+        BPF_PROG_LOAD,
+        &mut ProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_SCHED_CLS,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            log_level: 0,
+            log_size: 0,
+            log_buf: 0,
+            kern_version: 0,
+            prog_flags: 0,
+            prog_name,
+        },
+    )
+}
+
+fn attach_program(program: &OwnedFd, ifindex: u32, attach_type: u32) -> io::Result<OwnedFd> {
+    bpf_fd(
+        BPF_LINK_CREATE,
+        &mut LinkCreateAttr {
+            prog_fd: program.as_raw_fd() as u32,
+            target_ifindex: ifindex,
+            attach_type,
+            flags: 0,
+            relative_fd: 0,
+            _pad: 0,
+            expected_revision: 0,
+        },
+    )
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> io::Result<libc::c_long> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T,
+            std::mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+/// Runs a command returning a new file descriptor.
+fn bpf_fd<T>(cmd: libc::c_int, attr: &mut T) -> io::Result<OwnedFd> {
+    let fd = bpf(cmd, attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_program() {
+        let program = counter_program(42, RX_SLOT);
+
+        // The map is loaded by file descriptor, into r1.
+        assert_eq!(program[4], insn(0x18, 1, BPF_PSEUDO_MAP_FD, 0, 42));
+        assert_eq!(program[1].imm, RX_SLOT as i32);
+        // A failed lookup skips the counting, to the end of the program.
+        let jump = 7;
+        let target = jump + 1 + program[jump].off as usize;
+        assert_eq!(program[target], insn(0xb7, 0, 0, 0, TCX_NEXT));
+        assert_eq!(program.last(), Some(&insn(0x95, 0, 0, 0, 0)));
+    }
+}
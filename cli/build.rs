@@ -0,0 +1,30 @@
+//! Renders a man page for the CLI and one per subcommand into `$OUT_DIR/man`.
+//!
+//! Packagers can set `CLOUDE_MAN_DIR` to have them copied there as well.
+
+use clap::CommandFactory;
+use std::path::PathBuf;
+
+#[allow(dead_code)]
+mod args {
+    include!("src/args.rs");
+}
+
+fn main() -> std::io::Result<()> {
+    println!("cargo:rerun-if-changed=src/args.rs");
+    println!("cargo:rerun-if-env-changed=CLOUDE_MAN_DIR");
+
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    let man_dir = out_dir.join("man");
+    std::fs::create_dir_all(&man_dir)?;
+    clap_mangen::generate_to(args::Cli::command(), &man_dir)?;
+
+    if let Some(dest) = std::env::var_os("CLOUDE_MAN_DIR") {
+        std::fs::create_dir_all(&dest)?;
+        for page in std::fs::read_dir(&man_dir)? {
+            let page = page?;
+            std::fs::copy(page.path(), PathBuf::from(&dest).join(page.file_name()))?;
+        }
+    }
+    Ok(())
+}
@@ -21,7 +21,7 @@ use axum::{
     response::IntoResponse,
     routing::{get, post},
 };
-use serde::{Deserialize, Serialize};
+use cloude_client::{FunctionSpec, RunResponse};
 use serde_json::json;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -43,19 +43,6 @@ struct Job {
 
 type Store = Arc<RwLock<HashMap<String, Job>>>;
 
-// ── DTOs ─────────────────────────────────────────────────────────────
-
-#[derive(Deserialize)]
-struct RunRequest {
-    language: String,
-    code: String,
-}
-
-#[derive(Serialize)]
-struct RunResponse {
-    id: String,
-}
-
 // ── Static jobs (always available, no submission needed) ─────────────
 
 fn static_status(id: &str) -> Option<serde_json::Value> {
@@ -109,7 +96,10 @@ async fn health() -> &'static str {
     "ok"
 }
 
-async fn run_job(State(store): State<Store>, Json(payload): Json<RunRequest>) -> impl IntoResponse {
+async fn run_job(
+    State(store): State<Store>,
+    Json(payload): Json<FunctionSpec>,
+) -> impl IntoResponse {
     // Generate a short readable ID
     let id = format!(
         "mock-{:x}",
@@ -119,11 +109,16 @@ async fn run_job(State(store): State<Store>, Json(payload): Json<RunRequest>) ->
             .subsec_nanos()
     );
 
-    let preview = payload.code.chars().take(80).collect::<String>();
+    let preview = payload
+        .code
+        .unwrap_or_default()
+        .chars()
+        .take(80)
+        .collect::<String>();
     let finish_after_secs = 3;
 
     println!(
-        "[mock] POST /run  → id={id}  language={}  finish_in={finish_after_secs}s",
+        "[mock] POST /v1/run  → id={id}  language={}  finish_in={finish_after_secs}s",
         payload.language
     );
     println!("[mock]   code preview: {preview:?}");
@@ -138,11 +133,14 @@ async fn run_job(State(store): State<Store>, Json(payload): Json<RunRequest>) ->
 
     store.write().await.insert(id.clone(), job);
 
-    (StatusCode::ACCEPTED, Json(RunResponse { id }))
+    (
+        StatusCode::ACCEPTED,
+        Json(RunResponse { id, cached: false }),
+    )
 }
 
 async fn get_status(State(store): State<Store>, Path(id): Path<String>) -> impl IntoResponse {
-    println!("[mock] GET /status/{id}");
+    println!("[mock] GET /v1/status/{id}");
 
     // Static pre-built scenarios
     if let Some(v) = static_status(&id) {
@@ -210,12 +208,14 @@ async fn main() {
     let addr = std::env::var("MOCK_ADDR").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
     let store: Store = Arc::new(RwLock::new(HashMap::new()));
 
-    let app = Router::new()
-        .route("/", get(root))
-        .route("/health", get(health))
+    let api = Router::new()
         .route("/run", post(run_job))
         .route("/status/{id}", get(get_status))
         .with_state(store);
+    let app = Router::new()
+        .route("/", get(root))
+        .route("/health", get(health))
+        .nest("/v1", api);
 
     println!("╔══════════════════════════════════════════════╗");
     println!("║         Cloude mock backend running          ║");
@@ -0,0 +1,162 @@
+//! `apply`: makes the functions of the backend those of a spec file.
+//!
+//! The backend plans the changes first, without making them, and they are
+//! printed for confirmation; only then is the spec applied for real.
+
+use crate::args::ApplyArgs;
+use crate::deploy::confirm;
+use cloude_client::{ApplyAction, ApplySpec, Client, PlannedChange};
+use std::error::Error;
+use std::path::Path;
+
+pub async fn run(client: &Client, args: &ApplyArgs) -> Result<(), Box<dyn Error>> {
+    let spec = load(&args.file)?;
+
+    let plan = client.apply(&spec, true).await?;
+    for change in &plan.changes {
+        if let Some(line) = describe(change) {
+            println!("{line}");
+        }
+    }
+    let changes = plan
+        .changes
+        .iter()
+        .filter(|change| change.action != ApplyAction::Unchanged)
+        .count();
+    let unchanged = plan.changes.len() - changes;
+    if changes == 0 {
+        println!("No changes, {unchanged} functions are up to date");
+        return Ok(());
+    }
+    println!("{changes} changes, {unchanged} functions unchanged");
+    if args.dry_run {
+        return Ok(());
+    }
+    if !args.yes && !confirm("Apply?")? {
+        println!("Apply cancelled");
+        return Ok(());
+    }
+
+    let applied = client.apply(&spec, false).await?;
+    let changes = applied
+        .changes
+        .iter()
+        .filter(|change| change.action != ApplyAction::Unchanged)
+        .count();
+    println!("Applied {changes} changes");
+    Ok(())
+}
+
+/// The spec in `path`, TOML unless its extension is `.json`.
+fn load(path: &Path) -> Result<ApplySpec, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+    let spec = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&contents).map_err(|e| format!("{}: {e}", path.display()))?
+    } else {
+        toml::from_str(&contents).map_err(|e| format!("{}: {e}", path.display()))?
+    };
+    Ok(spec)
+}
+
+/// A line for `change`, `None` when it changes nothing.
+fn describe(change: &PlannedChange) -> Option<String> {
+    let short = |artifact: &str| artifact.chars().take(12).collect::<String>();
+    match (change.action, &change.before, &change.after) {
+        (ApplyAction::Create, _, Some(after)) => Some(format!(
+            "+ {} ({}, artifact {})",
+            change.name,
+            after.language,
+            short(&after.artifact)
+        )),
+        (ApplyAction::Update, Some(before), Some(after)) => {
+            let mut changed = Vec::new();
+            if before.language != after.language {
+                changed.push(format!("runtime {} -> {}", before.language, after.language));
+            }
+            if before.artifact != after.artifact {
+                changed.push(format!(
+                    "artifact {} -> {}",
+                    short(&before.artifact),
+                    short(&after.artifact)
+                ));
+            }
+            if before.entrypoint != after.entrypoint {
+                changed.push(format!(
+                    "entrypoint {} -> {}",
+                    before.entrypoint.as_deref().unwrap_or("-"),
+                    after.entrypoint.as_deref().unwrap_or("-")
+                ));
+            }
+            Some(format!(
+                "~ {} version {} -> {} ({})",
+                change.name,
+                before.version,
+                after.version,
+                changed.join(", ")
+            ))
+        }
+        (ApplyAction::Delete, Some(before), _) => {
+            Some(format!("- {} (version {})", change.name, before.version))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloude_client::FunctionInfo;
+
+    fn function(version: u32, artifact: &str) -> FunctionInfo {
+        FunctionInfo {
+            name: "resize".to_string(),
+            language: "node".to_string(),
+            artifact: artifact.to_string(),
+            entrypoint: Some("index.js".to_string()),
+            version,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_load_toml_spec() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("functions.toml");
+        std::fs::write(
+            &path,
+            "prune = true\n\n[[functions]]\nname = \"resize\"\nlanguage = \"node\"\nartifact = \"ab12\"\nentrypoint = \"index.js\"\n",
+        )
+        .unwrap();
+        let spec = load(&path).unwrap();
+        assert!(spec.prune);
+        assert_eq!(spec.functions[0].entrypoint.as_deref(), Some("index.js"));
+
+        std::fs::write(
+            &path,
+            "[[functions]]\nname = \"resize\"\nschedule = \"daily\"\n",
+        )
+        .unwrap();
+        assert!(load(&path).is_err());
+    }
+
+    #[test]
+    fn test_describe() {
+        let update = PlannedChange {
+            name: "resize".to_string(),
+            action: ApplyAction::Update,
+            before: Some(function(3, "0123456789abcdef")),
+            after: Some(function(4, "fedcba9876543210")),
+        };
+        assert_eq!(
+            describe(&update).unwrap(),
+            "~ resize version 3 -> 4 (artifact 0123456789ab -> fedcba987654)"
+        );
+        let unchanged = PlannedChange {
+            action: ApplyAction::Unchanged,
+            ..update
+        };
+        assert_eq!(describe(&unchanged), None);
+    }
+}
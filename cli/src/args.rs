@@ -0,0 +1,313 @@
+// Command-line interface of the CLI.
+//
+// Kept free of `crate::` paths: `build.rs` includes this file to render the man pages.
+
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Cloude CLI – run code in micro-VMs
+#[derive(Parser, Debug)]
+#[command(name = "cloude", version, about, long_about = None)]
+pub struct Cli {
+    /// URL of the backend
+    #[arg(short, long, default_value = "http://127.0.0.1:8080")]
+    pub backend: String,
+
+    /// API key sent to the backend
+    #[arg(long, env = "CLOUDE_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+
+    /// Namespace of the functions and jobs to act on [default: default]
+    #[arg(long, env = "CLOUDE_NAMESPACE")]
+    pub namespace: Option<String>,
+
+    /// CLI configuration file [default: ~/.config/cloude/config.toml]
+    #[arg(long, env = "CLOUDE_CLI_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Send a source file
+    ///
+    /// Exits with the exit code of the code, 128 plus the signal number when a signal
+    /// killed it, and 1 when it could not run.
+    Go {
+        /// Programming language (python, javascript, rust, …)
+        #[arg(short, long)]
+        language: String,
+        /// Source file to run
+        #[arg(short, long)]
+        file: PathBuf,
+        /// Run without network, with a fixed clock and seeds, for reproducible output
+        #[arg(long)]
+        deterministic: bool,
+        /// Sandbox profile of the backend to run with, like `strict`
+        #[arg(long)]
+        profile: Option<String>,
+        /// Print only the output of the code, without the status lines
+        #[arg(short, long)]
+        quiet: bool,
+    },
+
+    /// Run a source file and wait for its output
+    ///
+    /// Its standard output goes to stdout and its standard error to stderr. Exits with the
+    /// exit code of the code, 128 plus the signal number when a signal killed it, and 1
+    /// when it could not run.
+    Run {
+        /// Source file to run
+        file: PathBuf,
+        /// Programming language, detected from the file extension when omitted
+        #[arg(short, long)]
+        language: Option<String>,
+        /// Run in a micro-VM on this machine instead of sending to the backend
+        #[arg(long)]
+        local: bool,
+        /// Run without network, with a fixed clock and seeds, for reproducible output
+        #[arg(long)]
+        deterministic: bool,
+        /// Sandbox profile of the backend to run with, like `strict`
+        #[arg(long, conflicts_with = "local")]
+        profile: Option<String>,
+        /// Print only the output of the code, without the status lines
+        #[arg(short, long)]
+        quiet: bool,
+        #[command(flatten)]
+        local_args: LocalArgs,
+    },
+
+    /// Package a directory and deploy it as a function
+    ///
+    /// Files matching the patterns of a `.cloudeignore` (gitignore syntax) are left out.
+    /// The runtime and the file to run are detected from package.json, Cargo.toml or
+    /// requirements.txt, and shown for confirmation before anything is uploaded.
+    Deploy(DeployArgs),
+
+    /// Make the functions of the backend those of a spec file
+    ///
+    /// The spec, in TOML or JSON, lists the functions with their runtime and artifact;
+    /// `prune = true` deletes the functions it does not list. The changes are shown for
+    /// confirmation before they are made, and applying the same spec again changes nothing.
+    Apply(ApplyArgs),
+
+    /// Run a file or directory again every time it is saved
+    ///
+    /// After the first run, only what changed in the output is printed, as a diff.
+    /// A directory is packaged like `deploy` does, and any change in it triggers a run.
+    Dev(DevArgs),
+
+    /// Run a script once per file of a directory, with the file as its standard input
+    ///
+    /// Each input runs as a job of its own, `--jobs` at a time. The standard output and
+    /// error of each go to `<input>.out` and `<input>.err` in `--out-dir`, and a table sums
+    /// up the runs. Exits 0 when the script exited 0 on every input, 1 otherwise.
+    Batch(BatchArgs),
+
+    /// Query the status / result of a job
+    Status {
+        /// Job ID
+        id: String,
+    },
+
+    /// Print the logs of a job: output of its code and console of its VM
+    Logs {
+        /// Job ID
+        id: String,
+        /// Keep printing new lines until the job finishes
+        #[arg(short, long)]
+        follow: bool,
+        /// Prefix lines with the time the backend received them
+        #[arg(short, long)]
+        timestamps: bool,
+        /// Prefix lines with their stream: stdout, stderr or kernel
+        #[arg(long)]
+        labels: bool,
+    },
+
+    /// Attach to the serial console of a VM, detach with Ctrl-]
+    Console {
+        /// ID of the job running in the VM, or with --local the language of the VM to boot
+        vm_id: String,
+        /// Boot a micro-VM on this machine and attach to it, it stops on detach
+        #[arg(long)]
+        local: bool,
+        #[command(flatten)]
+        local_args: LocalArgs,
+    },
+
+    /// Install a guest kernel and pre-build runtime images for local mode and the backend
+    Setup(SetupArgs),
+
+    /// Print the completion script of a shell
+    ///
+    /// For example `cloude completions bash > /etc/bash_completion.d/cloude`.
+    Completions {
+        /// Shell to complete for
+        shell: clap_complete::Shell,
+    },
+}
+
+/// What runtime images are built from, as the backend is configured.
+/// Defaults match the backend's, so running from `backend/` reuses its files.
+#[derive(Args, Debug)]
+pub struct RuntimeArgs {
+    /// Runtime manifest
+    #[arg(
+        long,
+        env = "LANGUAGES_CONFIG_PATH",
+        default_value = "./config/languages.json"
+    )]
+    pub languages_config: String,
+
+    /// Agent binary embedded in the images
+    #[arg(long, env = "AGENT_BINARY_PATH", default_value = "./cloude-agentd")]
+    pub agent_binary: String,
+
+    /// Init script embedded in the images
+    #[arg(long, env = "INIT_SCRIPT_PATH", default_value = "./init.sh")]
+    pub init_script: String,
+}
+
+/// Where local mode finds the files the backend is normally configured with.
+#[derive(Args, Debug)]
+pub struct LocalArgs {
+    /// Guest kernel [default: from `setup`, else ./vmlinux]
+    #[arg(long, env = "VM_KERNEL_PATH")]
+    pub kernel: Option<PathBuf>,
+
+    /// Directory where runtime images are built and cached [default: from `setup`, else ./tmp]
+    #[arg(long, env = "VM_INITRAMFS_DIR")]
+    pub initramfs_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub runtime: RuntimeArgs,
+
+    /// Number of vCPUs of the VM
+    #[arg(long, default_value_t = 1)]
+    pub vcpus: u8,
+
+    /// Memory of the VM, in MiB
+    #[arg(long, default_value_t = 512)]
+    pub memory_mb: usize,
+
+    /// Print the guest console
+    #[arg(long, env = "VM_LOG_GUEST_CONSOLE")]
+    pub log_guest_console: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct DeployArgs {
+    /// Directory to deploy
+    pub dir: PathBuf,
+
+    /// Name of the function [default: the directory name]
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// Programming language [default: detected from the manifest files]
+    #[arg(short, long)]
+    pub language: Option<String>,
+
+    /// File to run, relative to the directory [default: detected from the manifest files]
+    #[arg(short, long)]
+    pub entrypoint: Option<String>,
+
+    /// Deploy without asking for confirmation
+    #[arg(short, long)]
+    pub yes: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ApplyArgs {
+    /// Spec file, `.toml` or `.json`
+    pub file: PathBuf,
+
+    /// Only show the changes
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Apply without asking for confirmation
+    #[arg(short, long)]
+    pub yes: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct BatchArgs {
+    /// Script to run
+    pub script: PathBuf,
+
+    /// Runtime, detected from the script extension when omitted
+    #[arg(short, long)]
+    pub runtime: Option<String>,
+
+    /// Directory of the inputs, one run per file
+    #[arg(long)]
+    pub stdin_dir: PathBuf,
+
+    /// Directory the output of each run is written to
+    #[arg(short, long, default_value = "results")]
+    pub out_dir: PathBuf,
+
+    /// Runs at the same time
+    #[arg(short, long, default_value_t = 4)]
+    pub jobs: usize,
+
+    /// Longest a run may take, from its submission to its result
+    #[arg(long, default_value_t = 300)]
+    pub timeout_secs: u64,
+
+    /// Run without network, with a fixed clock and seeds, for reproducible output
+    #[arg(long)]
+    pub deterministic: bool,
+
+    /// Sandbox profile of the backend to run with, like `strict`
+    #[arg(long)]
+    pub profile: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct DevArgs {
+    /// Source file, or directory to package
+    pub path: PathBuf,
+
+    /// Programming language [default: detected from the file extension or the manifest files]
+    #[arg(short, long)]
+    pub language: Option<String>,
+
+    /// File to run in a directory [default: detected from the manifest files]
+    #[arg(short, long)]
+    pub entrypoint: Option<String>,
+
+    /// Run in a micro-VM on this machine instead of sending to the backend
+    #[arg(long)]
+    pub local: bool,
+
+    #[command(flatten)]
+    pub local_args: LocalArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct SetupArgs {
+    /// Kernel manifest [default: the one shipped with the CLI]
+    #[arg(long)]
+    pub manifest: Option<PathBuf>,
+
+    /// Where the kernel and runtime images are installed [default: $XDG_DATA_HOME/cloude]
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Install the kernel again even if one is already installed
+    #[arg(long)]
+    pub force: bool,
+
+    /// Do not pre-build the runtime images
+    #[arg(long)]
+    pub skip_runtimes: bool,
+
+    #[command(flatten)]
+    pub runtime: RuntimeArgs,
+}
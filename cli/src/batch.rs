@@ -0,0 +1,256 @@
+//! `batch`: runs a script once per file of a directory, with the file as its
+//! standard input, as graders do with test cases.
+//!
+//! Each input is a job of its own, and a few run at the same time. The output of
+//! each goes to files named after its input, and a table sums up the runs.
+
+use crate::args::BatchArgs;
+use cloude_client::{Client, FunctionSpec, Isolation, Network, StatusResponse};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// How the script ran on one input.
+struct Outcome {
+    /// File name of the input.
+    input: String,
+    /// The finished job, or why there is none.
+    result: Result<StatusResponse, String>,
+    job_id: Option<String>,
+    duration: Duration,
+}
+
+impl Outcome {
+    fn passed(&self) -> bool {
+        self.result
+            .as_ref()
+            .is_ok_and(|st| crate::exit_status(st) == 0)
+    }
+}
+
+/// Runs the batch, and returns whether the script exited 0 on every input.
+pub async fn run(client: &Client, args: &BatchArgs) -> Result<bool, Box<dyn Error>> {
+    let language = match &args.runtime {
+        Some(runtime) => runtime.clone(),
+        None => crate::language_from_path(&args.script)
+            .ok_or_else(|| {
+                format!(
+                    "Cannot detect the runtime of {}, use --runtime",
+                    args.script.display()
+                )
+            })?
+            .to_string(),
+    };
+    let code = std::fs::read_to_string(&args.script)
+        .map_err(|e| format!("Cannot read file {}: {e}", args.script.display()))?;
+    let inputs = inputs(&args.stdin_dir)?;
+    if inputs.is_empty() {
+        return Err(format!("No input files in {}", args.stdin_dir.display()).into());
+    }
+    std::fs::create_dir_all(&args.out_dir)
+        .map_err(|e| format!("Cannot create {}: {e}", args.out_dir.display()))?;
+
+    let permits = Arc::new(Semaphore::new(args.jobs.max(1)));
+    let timeout = Duration::from_secs(args.timeout_secs);
+    let mut tasks = tokio::task::JoinSet::new();
+    for path in inputs {
+        let client = client.clone();
+        let permits = Arc::clone(&permits);
+        let spec = FunctionSpec {
+            language: language.clone(),
+            code: Some(code.clone()),
+            artifact: None,
+            entrypoint: None,
+            deterministic: args.deterministic,
+            cache: None,
+            isolation: Isolation::Vm,
+            resources: None,
+            stdin: None,
+            profile: args.profile.clone(),
+            network: Network::Open,
+            identity_audience: None,
+        };
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            run_input(&client, spec, &path, timeout).await
+        });
+    }
+
+    let mut outcomes = Vec::new();
+    while let Some(outcome) = tasks.join_next().await {
+        let outcome = outcome.expect("Batch run panicked");
+        write_result(&args.out_dir, &outcome)?;
+        outcomes.push(outcome);
+    }
+    outcomes.sort_by(|a, b| a.input.cmp(&b.input));
+
+    let width = outcomes
+        .iter()
+        .map(|outcome| outcome.input.len())
+        .max()
+        .unwrap_or(0)
+        .max("input".len());
+    println!(
+        "{:<width$}  {:<11}{:>5}{:>9}  job",
+        "input", "status", "exit", "time"
+    );
+    for outcome in &outcomes {
+        println!("{}", row(outcome, width));
+    }
+    let passed = outcomes.iter().filter(|outcome| outcome.passed()).count();
+    println!(
+        "{passed} of {} inputs exited 0, results in {}",
+        outcomes.len(),
+        args.out_dir.display()
+    );
+    Ok(passed == outcomes.len())
+}
+
+/// Files of `dir` to run the script on, by name, hidden files left out.
+fn inputs(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("Cannot read {}: {e}", dir.display()))?;
+    let mut inputs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') || !entry.file_type()?.is_file() {
+            continue;
+        }
+        inputs.push(entry.path());
+    }
+    inputs.sort();
+    Ok(inputs)
+}
+
+/// Runs `spec` with the content of `input` as its standard input, and waits at
+/// most `timeout` for its result.
+async fn run_input(
+    client: &Client,
+    mut spec: FunctionSpec,
+    input: &Path,
+    timeout: Duration,
+) -> Outcome {
+    let started = Instant::now();
+    let mut job_id = None;
+    let result = match std::fs::read_to_string(input) {
+        Err(e) => Err(format!("cannot read the input: {e}")),
+        Ok(stdin) => {
+            spec.stdin = Some(stdin);
+            match client.submit(&spec).await {
+                Err(e) => Err(e.to_string()),
+                Ok(run) => {
+                    job_id = Some(run.id.clone());
+                    match tokio::time::timeout(timeout, client.wait(&run.id)).await {
+                        Err(_) => Err(format!("no result after {}s", timeout.as_secs())),
+                        Ok(result) => result.map_err(|e| e.to_string()),
+                    }
+                }
+            }
+        }
+    };
+    Outcome {
+        input: input
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        result,
+        job_id,
+        duration: started.elapsed(),
+    }
+}
+
+/// Writes the standard output and error of a run to `<input>.out` and
+/// `<input>.err` in `dir`; a run without a result gets the reason as its error.
+fn write_result(dir: &Path, outcome: &Outcome) -> Result<(), Box<dyn Error>> {
+    let (stdout, stderr) = match &outcome.result {
+        Ok(st) => (
+            st.stdout.clone().unwrap_or_default(),
+            st.stderr.clone().unwrap_or_default(),
+        ),
+        Err(e) => (String::new(), format!("{e}\n")),
+    };
+    for (extension, content) in [("out", stdout), ("err", stderr)] {
+        let path = dir.join(format!("{}.{extension}", outcome.input));
+        std::fs::write(&path, content)
+            .map_err(|e| format!("Cannot write {}: {e}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Line of the summary table for `outcome`, its input padded to `width`.
+fn row(outcome: &Outcome, width: usize) -> String {
+    let (status, exit) = match &outcome.result {
+        Ok(st) => (st.status.to_string(), crate::exit_status(st).to_string()),
+        Err(_) => ("failed".to_string(), "-".to_string()),
+    };
+    let mut row = format!(
+        "{:<width$}  {status:<11}{exit:>5}{:>8.1}s  {}",
+        outcome.input,
+        outcome.duration.as_secs_f64(),
+        outcome.job_id.as_deref().unwrap_or("-"),
+    );
+    if let Err(e) = &outcome.result {
+        row.push_str(&format!("  {e}"));
+    }
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloude_client::JobStatus;
+
+    #[test]
+    fn test_inputs() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for name in ["case-2.txt", "case-1.txt", ".hidden"] {
+            std::fs::write(dir.path().join(name), "1 2\n").unwrap();
+        }
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+
+        let names: Vec<_> = inputs(dir.path())
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["case-1.txt", "case-2.txt"]);
+    }
+
+    #[test]
+    fn test_row() {
+        let done = Outcome {
+            input: "case-1.txt".to_string(),
+            result: Ok(StatusResponse {
+                id: "job-1".to_string(),
+                status: JobStatus::Done,
+                exit_code: Some(0),
+                stdout: Some("3\n".to_string()),
+                stderr: Some(String::new()),
+                isolation: Some(Isolation::Vm),
+                traffic: None,
+                oom_killed: false,
+                signal: None,
+                core_dump: false,
+            }),
+            job_id: Some("job-1".to_string()),
+            duration: Duration::from_millis(1300),
+        };
+        assert!(done.passed());
+        assert_eq!(
+            row(&done, 12),
+            "case-1.txt    done           0     1.3s  job-1"
+        );
+
+        let lost = Outcome {
+            result: Err("no result after 300s".to_string()),
+            ..done
+        };
+        assert!(!lost.passed());
+        assert_eq!(
+            row(&lost, 12),
+            "case-1.txt    failed         -     1.3s  job-1  no result after 300s"
+        );
+    }
+}
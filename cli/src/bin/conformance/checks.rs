@@ -0,0 +1,482 @@
+//! What each conformance check runs, and what it expects back.
+//!
+//! Every runtime gets the same checks, each with a program written for it. A
+//! runtime without programs fails them all: a new runtime is only enabled once
+//! its programs are added here and pass.
+
+use clap::ValueEnum;
+use cloude_client::{FunctionSpec, Isolation, JobStatus, Network, StatusResponse};
+use serde::Serialize;
+
+/// What the stdin check sends, and reads back.
+const STDIN: &str = "cloude conformance\nsecond line, no newline at the end";
+/// Line the unicode check prints: accents, symbols, CJK and a character outside the BMP.
+const UNICODE: &str = "héllo wörld ✓ 日本語 🚀";
+/// Exit code of the nonzero-exit check.
+const EXIT_CODE: i32 = 3;
+/// Line the large-output check prints [`LARGE_LINES`] times, 768 KiB in all:
+/// large, yet under the 1 MiB the agent keeps of an output.
+const LARGE_LINE: &str = "0123456789abcde\n";
+const LARGE_LINES: usize = 49152;
+/// Longest excerpt of an output shown in a failure.
+const EXCERPT_CHARS: usize = 80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Check {
+    /// Prints a line and exits 0.
+    HelloWorld,
+    /// Copies its standard input to its standard output.
+    StdinEcho,
+    /// Prints `CLOUDE_SEED`, which the agent sets for deterministic jobs.
+    Env,
+    /// Exits with code 3.
+    NonzeroExit,
+    /// Prints 768 KiB.
+    LargeOutput,
+    /// Never exits, and must be stopped by the agent.
+    Timeout,
+    /// Prints a line of non-ASCII characters.
+    Unicode,
+}
+
+impl Check {
+    pub const ALL: [Check; 7] = [
+        Check::HelloWorld,
+        Check::StdinEcho,
+        Check::Env,
+        Check::NonzeroExit,
+        Check::LargeOutput,
+        Check::Timeout,
+        Check::Unicode,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Check::HelloWorld => "hello-world",
+            Check::StdinEcho => "stdin-echo",
+            Check::Env => "env",
+            Check::NonzeroExit => "nonzero-exit",
+            Check::LargeOutput => "large-output",
+            Check::Timeout => "timeout",
+            Check::Unicode => "unicode",
+        }
+    }
+
+    /// Job running the check in `language`, `None` without a program for it.
+    pub fn spec(self, language: &str) -> Option<FunctionSpec> {
+        let programs = programs(language)?;
+        let code = match self {
+            Check::HelloWorld => programs.hello_world,
+            Check::StdinEcho => programs.stdin_echo,
+            Check::Env => programs.env,
+            Check::NonzeroExit => programs.nonzero_exit,
+            Check::LargeOutput => programs.large_output,
+            Check::Timeout => programs.timeout,
+            Check::Unicode => programs.unicode,
+        };
+        Some(FunctionSpec {
+            language: language.to_string(),
+            code: Some(code.to_string()),
+            artifact: None,
+            entrypoint: None,
+            // Deterministic jobs are the ones the agent sets variables for.
+            deterministic: self == Check::Env,
+            cache: None,
+            isolation: Isolation::Vm,
+            resources: None,
+            stdin: (self == Check::StdinEcho).then(|| STDIN.to_string()),
+            profile: None,
+            network: Network::Open,
+            identity_audience: None,
+        })
+    }
+
+    /// Whether the finished job `status` passes the check, or why it does not.
+    pub fn verify(self, status: &StatusResponse) -> Result<(), String> {
+        let stdout = status.stdout.as_deref().unwrap_or_default();
+        if self == Check::Timeout {
+            let stderr = status.stderr.as_deref().unwrap_or_default();
+            if status.status == JobStatus::Error && stderr.contains("timed out") {
+                return Ok(());
+            }
+            return Err(format!(
+                "expected the job to time out, it ended {} with exit code {:?}",
+                status.status, status.exit_code
+            ));
+        }
+        if status.status != JobStatus::Done {
+            return Err(format!(
+                "job ended {}: {}",
+                status.status,
+                excerpt(status.stderr.as_deref().unwrap_or_default())
+            ));
+        }
+        let exit_code = if self == Check::NonzeroExit {
+            EXIT_CODE
+        } else {
+            0
+        };
+        if status.exit_code != Some(exit_code) {
+            return Err(format!(
+                "expected exit code {}, got {:?}: {}",
+                exit_code,
+                status.exit_code,
+                excerpt(status.stderr.as_deref().unwrap_or_default())
+            ));
+        }
+        let expected = match self {
+            Check::HelloWorld => "hello, world\n".to_string(),
+            Check::StdinEcho => STDIN.to_string(),
+            Check::Env => "0\n".to_string(),
+            Check::LargeOutput => LARGE_LINE.repeat(LARGE_LINES),
+            Check::Unicode => format!("{UNICODE}\n"),
+            Check::NonzeroExit | Check::Timeout => return Ok(()),
+        };
+        if stdout == expected {
+            return Ok(());
+        }
+        if stdout.len() != expected.len() {
+            return Err(format!(
+                "expected {} bytes of stdout, got {}: {}",
+                expected.len(),
+                stdout.len(),
+                excerpt(stdout)
+            ));
+        }
+        Err(format!(
+            "expected stdout {}, got {}",
+            excerpt(&expected),
+            excerpt(stdout)
+        ))
+    }
+}
+
+/// Start of `output`, quoted.
+fn excerpt(output: &str) -> String {
+    match output.char_indices().nth(EXCERPT_CHARS) {
+        Some((end, _)) => format!("{:?}…", &output[..end]),
+        None => format!("{:?}", output),
+    }
+}
+
+/// Code of each check in a runtime.
+struct Programs {
+    hello_world: &'static str,
+    stdin_echo: &'static str,
+    env: &'static str,
+    nonzero_exit: &'static str,
+    large_output: &'static str,
+    timeout: &'static str,
+    unicode: &'static str,
+}
+
+fn programs(language: &str) -> Option<&'static Programs> {
+    let programs = match language {
+        "python" => &PYTHON,
+        "node" => &NODE,
+        "rust" => &RUST,
+        "c" => &C,
+        "cpp" => &CPP,
+        "go" => &GO,
+        "java" => &JAVA,
+        _ => return None,
+    };
+    Some(programs)
+}
+
+const PYTHON: Programs = Programs {
+    hello_world: r#"print("hello, world")"#,
+    stdin_echo: "import sys\nsys.stdout.write(sys.stdin.read())\n",
+    env: "import os\nprint(os.environ.get(\"CLOUDE_SEED\", \"\"))\n",
+    nonzero_exit: "import sys\nsys.exit(3)\n",
+    large_output: "import sys\nsys.stdout.write(\"0123456789abcde\\n\" * 49152)\n",
+    timeout: "import time\nwhile True:\n    time.sleep(1)\n",
+    unicode: r#"print("héllo wörld ✓ 日本語 🚀")"#,
+};
+
+const NODE: Programs = Programs {
+    hello_world: r#"console.log("hello, world");"#,
+    stdin_echo: "process.stdin.pipe(process.stdout);\n",
+    env: r#"console.log(process.env.CLOUDE_SEED ?? "");"#,
+    nonzero_exit: "process.exitCode = 3;\n",
+    large_output: r#"process.stdout.write("0123456789abcde\n".repeat(49152));"#,
+    timeout: "setInterval(() => {}, 1000);\n",
+    unicode: r#"console.log("héllo wörld ✓ 日本語 🚀");"#,
+};
+
+const RUST: Programs = Programs {
+    hello_world: "fn main() {\n    println!(\"hello, world\");\n}\n",
+    stdin_echo: r#"use std::io::Read;
+
+fn main() {
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input).unwrap();
+    print!("{}", input);
+}
+"#,
+    env: r#"fn main() {
+    println!("{}", std::env::var("CLOUDE_SEED").unwrap_or_default());
+}
+"#,
+    nonzero_exit: "fn main() {\n    std::process::exit(3);\n}\n",
+    large_output: r#"fn main() {
+    print!("{}", "0123456789abcde\n".repeat(49152));
+}
+"#,
+    timeout: r#"fn main() {
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
+"#,
+    unicode: "fn main() {\n    println!(\"héllo wörld ✓ 日本語 🚀\");\n}\n",
+};
+
+const C: Programs = Programs {
+    hello_world: "#include <stdio.h>\n\nint main(void) {\n    puts(\"hello, world\");\n    return 0;\n}\n",
+    stdin_echo: r#"#include <stdio.h>
+
+int main(void) {
+    int c;
+    while ((c = getchar()) != EOF)
+        putchar(c);
+    return 0;
+}
+"#,
+    env: r#"#include <stdio.h>
+#include <stdlib.h>
+
+int main(void) {
+    const char *seed = getenv("CLOUDE_SEED");
+    puts(seed ? seed : "");
+    return 0;
+}
+"#,
+    nonzero_exit: "int main(void) {\n    return 3;\n}\n",
+    large_output: r#"#include <stdio.h>
+
+int main(void) {
+    for (int i = 0; i < 49152; i++)
+        fputs("0123456789abcde\n", stdout);
+    return 0;
+}
+"#,
+    timeout: "#include <unistd.h>\n\nint main(void) {\n    for (;;)\n        sleep(1);\n}\n",
+    unicode: "#include <stdio.h>\n\nint main(void) {\n    puts(\"héllo wörld ✓ 日本語 🚀\");\n    return 0;\n}\n",
+};
+
+const CPP: Programs = Programs {
+    hello_world: "#include <iostream>\n\nint main() {\n    std::cout << \"hello, world\\n\";\n}\n",
+    stdin_echo: "#include <iostream>\n\nint main() {\n    std::cout << std::cin.rdbuf();\n}\n",
+    env: r#"#include <cstdlib>
+#include <iostream>
+
+int main() {
+    const char *seed = std::getenv("CLOUDE_SEED");
+    std::cout << (seed ? seed : "") << "\n";
+}
+"#,
+    nonzero_exit: "int main() {\n    return 3;\n}\n",
+    large_output: r#"#include <iostream>
+
+int main() {
+    for (int i = 0; i < 49152; i++)
+        std::cout << "0123456789abcde\n";
+}
+"#,
+    timeout: r#"#include <chrono>
+#include <thread>
+
+int main() {
+    for (;;)
+        std::this_thread::sleep_for(std::chrono::seconds(1));
+}
+"#,
+    unicode: "#include <iostream>\n\nint main() {\n    std::cout << \"héllo wörld ✓ 日本語 🚀\\n\";\n}\n",
+};
+
+const GO: Programs = Programs {
+    hello_world: "package main\n\nimport \"fmt\"\n\nfunc main() {\n\tfmt.Println(\"hello, world\")\n}\n",
+    stdin_echo: r#"package main
+
+import (
+	"io"
+	"os"
+)
+
+func main() {
+	io.Copy(os.Stdout, os.Stdin)
+}
+"#,
+    env: r#"package main
+
+import (
+	"fmt"
+	"os"
+)
+
+func main() {
+	fmt.Println(os.Getenv("CLOUDE_SEED"))
+}
+"#,
+    nonzero_exit: "package main\n\nimport \"os\"\n\nfunc main() {\n\tos.Exit(3)\n}\n",
+    large_output: r#"package main
+
+import (
+	"fmt"
+	"strings"
+)
+
+func main() {
+	fmt.Print(strings.Repeat("0123456789abcde\n", 49152))
+}
+"#,
+    timeout: r#"package main
+
+import "time"
+
+func main() {
+	for {
+		time.Sleep(time.Second)
+	}
+}
+"#,
+    unicode: "package main\n\nimport \"fmt\"\n\nfunc main() {\n\tfmt.Println(\"héllo wörld ✓ 日本語 🚀\")\n}\n",
+};
+
+const JAVA: Programs = Programs {
+    hello_world: r#"public class Main {
+    public static void main(String[] args) {
+        System.out.println("hello, world");
+    }
+}
+"#,
+    stdin_echo: r#"public class Main {
+    public static void main(String[] args) throws Exception {
+        System.in.transferTo(System.out);
+        System.out.flush();
+    }
+}
+"#,
+    env: r#"public class Main {
+    public static void main(String[] args) {
+        String seed = System.getenv("CLOUDE_SEED");
+        System.out.println(seed == null ? "" : seed);
+    }
+}
+"#,
+    nonzero_exit: r#"public class Main {
+    public static void main(String[] args) {
+        System.exit(3);
+    }
+}
+"#,
+    large_output: r#"public class Main {
+    public static void main(String[] args) {
+        System.out.print("0123456789abcde\n".repeat(49152));
+        System.out.flush();
+    }
+}
+"#,
+    timeout: r#"public class Main {
+    public static void main(String[] args) throws Exception {
+        while (true) {
+            Thread.sleep(1000);
+        }
+    }
+}
+"#,
+    unicode: r#"import java.io.PrintStream;
+import java.nio.charset.StandardCharsets;
+
+public class Main {
+    public static void main(String[] args) {
+        PrintStream out = new PrintStream(System.out, true, StandardCharsets.UTF_8);
+        out.println("héllo wörld ✓ 日本語 🚀");
+    }
+}
+"#,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finished(status: JobStatus, exit_code: i32, stdout: &str, stderr: &str) -> StatusResponse {
+        StatusResponse {
+            id: "job".to_string(),
+            status,
+            exit_code: Some(exit_code),
+            stdout: Some(stdout.to_string()),
+            stderr: Some(stderr.to_string()),
+            isolation: None,
+            traffic: None,
+            oom_killed: false,
+            signal: None,
+            core_dump: false,
+        }
+    }
+
+    #[test]
+    fn test_programs_match_expectations() {
+        for language in ["python", "node", "rust", "c", "cpp", "go", "java"] {
+            for check in Check::ALL {
+                let code = check.spec(language).unwrap().code.unwrap();
+                // The constants the checks expect are spelled out in every program.
+                match check {
+                    Check::Unicode => assert!(code.contains(UNICODE), "{language}"),
+                    Check::LargeOutput => {
+                        assert!(code.contains(&LARGE_LINES.to_string()), "{language}")
+                    }
+                    Check::NonzeroExit => {
+                        assert!(code.contains(&EXIT_CODE.to_string()), "{language}")
+                    }
+                    _ => {}
+                }
+            }
+        }
+        assert!(Check::HelloWorld.spec("cobol").is_none());
+        assert_eq!(
+            Check::StdinEcho.spec("python").unwrap().stdin.as_deref(),
+            Some(STDIN)
+        );
+        assert!(Check::Env.spec("python").unwrap().deterministic);
+    }
+
+    #[test]
+    fn test_verify() {
+        let done = |exit_code, stdout: &str| finished(JobStatus::Done, exit_code, stdout, "");
+        assert_eq!(Check::HelloWorld.verify(&done(0, "hello, world\n")), Ok(()));
+        assert_eq!(
+            Check::HelloWorld.verify(&done(0, "hello\n")),
+            Err(r#"expected 13 bytes of stdout, got 6: "hello\n""#.to_string())
+        );
+        assert_eq!(Check::StdinEcho.verify(&done(0, STDIN)), Ok(()));
+        assert_eq!(Check::NonzeroExit.verify(&done(3, "")), Ok(()));
+        assert!(Check::NonzeroExit.verify(&done(0, "")).is_err());
+        assert!(Check::Env.verify(&done(1, "0\n")).is_err());
+        assert_eq!(
+            Check::LargeOutput.verify(&done(0, &LARGE_LINE.repeat(LARGE_LINES))),
+            Ok(())
+        );
+        assert_eq!(
+            Check::Unicode.verify(&done(0, "héllo wörld ✓ 日本人 🚀\n")),
+            Err(format!(
+                "expected stdout {:?}, got {:?}",
+                format!("{UNICODE}\n"),
+                "héllo wörld ✓ 日本人 🚀\n"
+            ))
+        );
+
+        let timed_out = finished(
+            JobStatus::Error,
+            1,
+            "",
+            "Process timed out after 30s: python3",
+        );
+        assert_eq!(Check::Timeout.verify(&timed_out), Ok(()));
+        assert!(Check::HelloWorld.verify(&timed_out).is_err());
+        assert!(Check::Timeout.verify(&done(0, "")).is_err());
+    }
+}
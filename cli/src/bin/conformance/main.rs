@@ -0,0 +1,244 @@
+//! `cloude-conformance`: runs the same checks against every runtime of a backend,
+//! through its API, and reports which pass.
+//!
+//! Meant as the gate before a runtime is enabled: exits 0 only when every check
+//! of every runtime passed, 1 when one failed and 2 when the backend could not
+//! be asked for its runtimes.
+
+mod checks;
+
+use checks::Check;
+use clap::Parser;
+use cloude_client::{Client, RuntimeInfo};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// Run the conformance checks of Cloude runtimes against a backend
+#[derive(Parser, Debug)]
+#[command(name = "cloude-conformance", version, about, long_about = None)]
+struct Args {
+    /// URL of the backend
+    #[arg(short, long, default_value = "http://127.0.0.1:8080")]
+    backend: String,
+
+    /// API key sent to the backend
+    #[arg(long, env = "CLOUDE_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Runtime to check, can be repeated [default: every runtime of the backend]
+    #[arg(short, long = "runtime")]
+    runtimes: Vec<String>,
+
+    /// Check to run, can be repeated [default: all of them]
+    #[arg(short, long = "check", value_enum)]
+    checks: Vec<Check>,
+
+    /// Checks running at the same time
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+
+    /// Longest a check may take, from its submission to its result
+    #[arg(long, default_value_t = 300)]
+    timeout_secs: u64,
+
+    /// Also write the report as JSON to this file
+    #[arg(long)]
+    json: Option<PathBuf>,
+}
+
+/// Results of a run, written with `--json`.
+#[derive(Serialize, Debug)]
+struct Report {
+    passed: bool,
+    runtimes: Vec<RuntimeReport>,
+}
+
+#[derive(Serialize, Debug)]
+struct RuntimeReport {
+    name: String,
+    /// `None` for runtimes asked for with `--runtime` that the backend does not list.
+    version: Option<String>,
+    checks: Vec<CheckReport>,
+}
+
+#[derive(Serialize, Debug)]
+struct CheckReport {
+    check: Check,
+    passed: bool,
+    /// Why the check failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    /// Job that ran the check, to look into with `cloude status` or `cloude logs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job_id: Option<String>,
+    duration_ms: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let mut builder = Client::builder(args.backend.clone());
+    if let Some(api_key) = &args.api_key {
+        builder = builder.api_key(api_key.clone());
+    }
+    let client = builder.build().expect("Failed to build HTTP client");
+
+    let listed = match client.runtimes().await {
+        Ok(runtimes) => runtimes,
+        Err(e) => {
+            eprintln!("Error: cannot list the runtimes of the backend: {e}");
+            std::process::exit(2);
+        }
+    };
+    let runtimes: Vec<(String, Option<String>)> = if args.runtimes.is_empty() {
+        listed
+            .into_iter()
+            .map(|RuntimeInfo { name, version, .. }| (name, Some(version)))
+            .collect()
+    } else {
+        args.runtimes
+            .iter()
+            .map(|name| {
+                let version = listed
+                    .iter()
+                    .find(|runtime| runtime.name == *name)
+                    .map(|runtime| runtime.version.clone());
+                (name.clone(), version)
+            })
+            .collect()
+    };
+    let checks = if args.checks.is_empty() {
+        Check::ALL.to_vec()
+    } else {
+        args.checks.clone()
+    };
+
+    let report = run(
+        &client,
+        &runtimes,
+        &checks,
+        args.jobs.max(1),
+        Duration::from_secs(args.timeout_secs),
+    )
+    .await;
+    print_report(&report);
+    if let Some(path) = &args.json {
+        let written = serde_json::to_vec_pretty(&report)
+            .map_err(|e| e.to_string())
+            .and_then(|json| std::fs::write(path, json).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            eprintln!("Error: cannot write the report to {}: {e}", path.display());
+            std::process::exit(2);
+        }
+    }
+    if !report.passed {
+        std::process::exit(1);
+    }
+}
+
+/// Runs `checks` against each of `runtimes`, at most `jobs` at a time.
+async fn run(
+    client: &Client,
+    runtimes: &[(String, Option<String>)],
+    checks: &[Check],
+    jobs: usize,
+    timeout: Duration,
+) -> Report {
+    let permits = Arc::new(Semaphore::new(jobs));
+    let mut tasks = tokio::task::JoinSet::new();
+    for (runtime, _) in runtimes {
+        for &check in checks {
+            let client = client.clone();
+            let runtime = runtime.clone();
+            let permits = Arc::clone(&permits);
+            tasks.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                let report = run_check(&client, &runtime, check, timeout).await;
+                (runtime, report)
+            });
+        }
+    }
+
+    let mut reports: Vec<RuntimeReport> = runtimes
+        .iter()
+        .map(|(name, version)| RuntimeReport {
+            name: name.clone(),
+            version: version.clone(),
+            checks: Vec::new(),
+        })
+        .collect();
+    while let Some(result) = tasks.join_next().await {
+        let (runtime, report) = result.expect("Conformance check panicked");
+        if let Some(runtime) = reports.iter_mut().find(|r| r.name == runtime) {
+            runtime.checks.push(report);
+        }
+    }
+    for runtime in &mut reports {
+        runtime.checks.sort_by_key(|report| report.check);
+    }
+    Report {
+        passed: reports
+            .iter()
+            .all(|runtime| runtime.checks.iter().all(|check| check.passed)),
+        runtimes: reports,
+    }
+}
+
+/// Runs `check` in `runtime`, and waits at most `timeout` for its result.
+async fn run_check(client: &Client, runtime: &str, check: Check, timeout: Duration) -> CheckReport {
+    let started = Instant::now();
+    let mut job_id = None;
+    let outcome = match check.spec(runtime) {
+        None => Err("no conformance program for this runtime".to_string()),
+        Some(spec) => match client.submit(&spec).await {
+            Err(e) => Err(e.to_string()),
+            Ok(run) => {
+                job_id = Some(run.id.clone());
+                match tokio::time::timeout(timeout, client.wait(&run.id)).await {
+                    Err(_) => Err(format!("no result after {}s", timeout.as_secs())),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Ok(Ok(status)) => check.verify(&status),
+                }
+            }
+        },
+    };
+    CheckReport {
+        check,
+        passed: outcome.is_ok(),
+        detail: outcome.err(),
+        job_id,
+        duration_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+fn print_report(report: &Report) {
+    let mut passed = 0;
+    let mut total = 0;
+    for runtime in &report.runtimes {
+        match &runtime.version {
+            Some(version) => println!("{} {}", runtime.name, version),
+            None => println!("{} (not listed by the backend)", runtime.name),
+        }
+        for check in &runtime.checks {
+            total += 1;
+            let secs = check.duration_ms as f64 / 1000.0;
+            if check.passed {
+                passed += 1;
+                println!("  ok    {:<14}{:>7.1}s", check.check.name(), secs);
+                continue;
+            }
+            print!("  FAIL  {:<14}{:>7.1}s", check.check.name(), secs);
+            if let Some(detail) = &check.detail {
+                print!("  {detail}");
+            }
+            if let Some(id) = &check.job_id {
+                print!(" (job {id})");
+            }
+            println!();
+        }
+    }
+    println!("{passed} of {total} checks passed");
+}
@@ -0,0 +1,92 @@
+//! CLI configuration file, written by `setup` and read by local mode.
+//!
+//! Lives at `$XDG_CONFIG_HOME/cloude/config.toml` (`~/.config/cloude/config.toml`).
+//! Command-line flags and environment variables take precedence over it.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CliConfig {
+    #[serde(default)]
+    pub local: LocalConfig,
+}
+
+/// Files used to boot VMs on this machine.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LocalConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initramfs_dir: Option<PathBuf>,
+}
+
+impl CliConfig {
+    /// Reads the configuration, empty when the file does not exist.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|e| format!("Invalid config {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Cannot read config {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Cannot create {}: {}", parent.display(), e))?;
+        }
+        let content = toml::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, content)
+            .map_err(|e| format!("Cannot write config {}: {}", path.display(), e))
+    }
+}
+
+/// Default location of the configuration file.
+pub fn default_path() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config").join("config.toml")
+}
+
+/// Default directory for downloaded kernels and built runtime images.
+pub fn default_data_dir() -> PathBuf {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// `$<var>/cloude`, falling back to `~/<fallback>/cloude`.
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    let base = std::env::var_os(var)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            PathBuf::from(std::env::var_os("HOME").unwrap_or_else(|| ".".into())).join(fallback)
+        });
+    base.join("cloude")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir()
+            .join(format!("cloude-cli-config-{}", std::process::id()))
+            .join("config.toml");
+
+        assert_eq!(CliConfig::load(&path).unwrap(), CliConfig::default());
+
+        let config = CliConfig {
+            local: LocalConfig {
+                kernel: Some(PathBuf::from("/data/vmlinux")),
+                initramfs_dir: None,
+            },
+        };
+        config.save(&path).unwrap();
+        assert_eq!(CliConfig::load(&path).unwrap(), config);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+//! `console`: interactive access to the serial console of a VM.
+//!
+//! The terminal is switched to raw mode so that every key, `Ctrl-C` included,
+//! reaches the guest. `Ctrl-]` detaches, and the terminal settings are restored
+//! however the session ends.
+
+use std::error::Error;
+use std::io::{self, Read, Write};
+use tokio::sync::mpsc;
+
+/// `Ctrl-]`, as with telnet and `virsh console`.
+const DETACH_KEY: u8 = 0x1d;
+
+/// A console the terminal can be attached to.
+pub trait Session {
+    /// Types `input` into the guest.
+    async fn send(&mut self, input: Vec<u8>) -> Result<(), Box<dyn Error>>;
+    /// Next chunk of output, or `None` once the console is gone.
+    async fn recv(&mut self) -> Option<Result<Vec<u8>, Box<dyn Error>>>;
+    async fn close(self) -> Result<(), Box<dyn Error>>;
+}
+
+impl Session for cloude_client::Console {
+    async fn send(&mut self, input: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        Ok(cloude_client::Console::send(self, input).await?)
+    }
+
+    async fn recv(&mut self) -> Option<Result<Vec<u8>, Box<dyn Error>>> {
+        let output = cloude_client::Console::recv(self).await?;
+        Some(output.map(|data| data.to_vec()).map_err(Into::into))
+    }
+
+    async fn close(self) -> Result<(), Box<dyn Error>> {
+        Ok(cloude_client::Console::close(self).await?)
+    }
+}
+
+enum End {
+    Detached,
+    Closed,
+}
+
+/// Connects the terminal to `session` until the user detaches or the console closes.
+pub async fn attach(mut session: impl Session, name: &str) -> Result<(), Box<dyn Error>> {
+    eprintln!("Connected to the console of {name}, press Ctrl-] to detach");
+
+    // A plain thread rather than `tokio::io::stdin`, whose pending read would
+    // keep the runtime from shutting down after a detach.
+    let (keys_tx, mut keys) = mpsc::channel(16);
+    std::thread::spawn(move || read_stdin(keys_tx));
+
+    let end = {
+        let _raw = RawTerminal::enable()?;
+        forward(&mut session, &mut keys).await
+    };
+    match end? {
+        End::Detached => eprintln!("Detached from {name}"),
+        End::Closed => eprintln!("Console of {name} closed"),
+    }
+    session.close().await
+}
+
+async fn forward(
+    session: &mut impl Session,
+    keys: &mut mpsc::Receiver<Vec<u8>>,
+) -> Result<End, Box<dyn Error>> {
+    let mut stdin_open = true;
+    loop {
+        tokio::select! {
+            output = session.recv() => {
+                let Some(output) = output else {
+                    return Ok(End::Closed);
+                };
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&output?)?;
+                stdout.flush()?;
+            }
+            input = keys.recv(), if stdin_open => {
+                let Some(input) = input else {
+                    // Piped input ran out, keep showing output until detached or closed.
+                    stdin_open = false;
+                    continue;
+                };
+                let (input, detach) = split_at_detach(&input);
+                if !input.is_empty() {
+                    session.send(input.to_vec()).await?;
+                }
+                if detach {
+                    return Ok(End::Detached);
+                }
+            }
+        }
+    }
+}
+
+/// Keys typed before the detach key, and whether it was typed.
+fn split_at_detach(input: &[u8]) -> (&[u8], bool) {
+    match input.iter().position(|&key| key == DETACH_KEY) {
+        Some(pos) => (&input[..pos], true),
+        None => (input, false),
+    }
+}
+
+fn read_stdin(keys: mpsc::Sender<Vec<u8>>) {
+    let mut stdin = io::stdin().lock();
+    let mut buf = [0u8; 1024];
+    loop {
+        match stdin.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => {
+                if keys.blocking_send(buf[..n].to_vec()).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Raw mode on the terminal of stdin, restored when dropped.
+struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    /// `None` when stdin is not a terminal, e.g. when input is piped.
+    fn enable() -> io::Result<Option<Self>> {
+        // SAFETY: termios is plain data, filled by tcgetattr before it is read.
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return Ok(None);
+            }
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Some(Self { original }))
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        // SAFETY: `original` was returned by tcgetattr on the same descriptor.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+        // Raw output does not return the carriage, start the next message on a clean line.
+        eprintln!("\r");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_at_detach() {
+        assert_eq!(split_at_detach(b"ls\r"), (&b"ls\r"[..], false));
+        assert_eq!(split_at_detach(b"exit\x1d\r"), (&b"exit"[..], true));
+        assert_eq!(split_at_detach(b"\x1d"), (&b""[..], true));
+    }
+}
@@ -0,0 +1,372 @@
+//! `deploy`: packages a directory and deploys it as a function.
+//!
+//! The bundle is a gzipped tar of every file not matched by a `.cloudeignore`.
+//! It is built without timestamps or owners, so deploying unchanged files
+//! uploads the same artifact and leaves the function version as it is.
+
+use crate::args::DeployArgs;
+use cloude_client::{Client, DeployRequest, Error as ClientError};
+use flate2::{Compression, write::GzEncoder};
+use ignore::WalkBuilder;
+use reqwest::StatusCode;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Patterns, in `.gitignore` syntax, of files left out of the bundle.
+const IGNORE_FILE: &str = ".cloudeignore";
+
+/// Runtime of a project, derived from its manifest file.
+#[derive(Debug, PartialEq)]
+struct Detected {
+    language: &'static str,
+    manifest: &'static str,
+    /// `None` when neither the manifest nor the usual file names tell what to run.
+    entrypoint: Option<String>,
+}
+
+pub async fn run(client: &Client, args: &DeployArgs) -> Result<(), Box<dyn Error>> {
+    let dir = args
+        .dir
+        .canonicalize()
+        .map_err(|e| format!("Cannot read {}: {e}", args.dir.display()))?;
+    if !dir.is_dir() {
+        return Err(format!("{} is not a directory", args.dir.display()).into());
+    }
+
+    let name = match &args.name {
+        Some(name) => name.clone(),
+        None => dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(function_name)
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| format!("Cannot name a function after {}, use --name", dir.display()))?,
+    };
+
+    let package = package(&dir, args.language.as_deref(), args.entrypoint.as_deref())?;
+
+    let current = match client.function(&name).await {
+        Ok(function) => Some(function),
+        Err(ClientError::Api { status, .. }) if status == StatusCode::NOT_FOUND => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    match &current {
+        Some(function) => println!("Function:   {name} (updates version {})", function.version),
+        None => println!("Function:   {name} (new)"),
+    }
+    println!("Runtime:    {} (from {})", package.language, package.origin);
+    println!("Entrypoint: {}", package.entrypoint);
+    println!(
+        "Files:      {}, {} packaged",
+        package.files,
+        format_size(package.bundle.len() as u64)
+    );
+    if !args.yes && !confirm("Deploy?")? {
+        println!("Deploy cancelled");
+        return Ok(());
+    }
+
+    let artifact = client.deploy(package.bundle).await?;
+    let function = client
+        .deploy_function(
+            &name,
+            &DeployRequest {
+                language: package.language,
+                artifact: artifact.id,
+                entrypoint: Some(package.entrypoint),
+            },
+        )
+        .await?;
+
+    match current {
+        None => println!("Created {name}, version {}", function.version),
+        Some(previous) if previous.version == function.version => {
+            println!("{name} is unchanged, still version {}", function.version)
+        }
+        Some(_) => println!("Updated {name} to version {}", function.version),
+    }
+    Ok(())
+}
+
+/// A directory packaged as a bundle, with the runtime settings derived for it.
+pub struct Package {
+    pub language: String,
+    /// Where `language` comes from: a manifest file or `--language`.
+    pub origin: String,
+    pub entrypoint: String,
+    /// Number of files in the bundle.
+    pub files: usize,
+    pub bundle: Vec<u8>,
+}
+
+/// Packages `dir`, with `language` and `entrypoint` detected from its manifest when not given.
+pub fn package(
+    dir: &Path,
+    language: Option<&str>,
+    entrypoint: Option<&str>,
+) -> Result<Package, Box<dyn Error>> {
+    let detected = detect_runtime(dir)?;
+    let (language, origin) = match (language, &detected) {
+        (Some(language), _) => (language.to_string(), "--language".to_string()),
+        (None, Some(detected)) => (detected.language.to_string(), detected.manifest.to_string()),
+        (None, None) => {
+            return Err(format!(
+                "Cannot detect the runtime of {}: no package.json, Cargo.toml or requirements.txt, use --language",
+                dir.display()
+            )
+            .into());
+        }
+    };
+    let entrypoint = entrypoint
+        .map(str::to_string)
+        .or_else(|| detected.and_then(|detected| detected.entrypoint))
+        .ok_or("Cannot tell which file to run, use --entrypoint")?;
+
+    let files = collect_files(dir)?;
+    if !files.iter().any(|file| file == Path::new(&entrypoint)) {
+        return Err(format!(
+            "Entrypoint {entrypoint} is not in the bundle: it does not exist or is listed in {IGNORE_FILE}"
+        )
+        .into());
+    }
+    let bundle = build_bundle(dir, &files)?;
+
+    Ok(Package {
+        language,
+        origin,
+        entrypoint,
+        files: files.len(),
+        bundle,
+    })
+}
+
+/// Runtime from the first manifest found, in the order `package.json`,
+/// `Cargo.toml`, `requirements.txt`.
+fn detect_runtime(dir: &Path) -> Result<Option<Detected>, Box<dyn Error>> {
+    if let Some(manifest) = read_manifest(dir, "package.json")? {
+        let package: serde_json::Value =
+            serde_json::from_str(&manifest).map_err(|e| format!("Invalid package.json: {e}"))?;
+        // npm runs `index.js` when `main` is not set.
+        let main = package
+            .get("main")
+            .and_then(|main| main.as_str())
+            .unwrap_or("index.js");
+        return Ok(Some(Detected {
+            language: "node",
+            manifest: "package.json",
+            entrypoint: Some(main.trim_start_matches("./").to_string()),
+        }));
+    }
+
+    if let Some(manifest) = read_manifest(dir, "Cargo.toml")? {
+        let cargo: toml::Table =
+            toml::from_str(&manifest).map_err(|e| format!("Invalid Cargo.toml: {e}"))?;
+        let bin_path = cargo
+            .get("bin")
+            .and_then(|bins| bins.as_array()?.first()?.get("path")?.as_str());
+        return Ok(Some(Detected {
+            language: "rust",
+            manifest: "Cargo.toml",
+            entrypoint: Some(bin_path.unwrap_or("src/main.rs").to_string()),
+        }));
+    }
+
+    if read_manifest(dir, "requirements.txt")?.is_some() {
+        let entrypoint = ["main.py", "app.py", "__main__.py"]
+            .into_iter()
+            .find(|file| dir.join(file).is_file())
+            .map(str::to_string);
+        return Ok(Some(Detected {
+            language: "python",
+            manifest: "requirements.txt",
+            entrypoint,
+        }));
+    }
+
+    Ok(None)
+}
+
+fn read_manifest(dir: &Path, name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    match std::fs::read_to_string(dir.join(name)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Cannot read {name}: {e}").into()),
+    }
+}
+
+/// Files to package, relative to `dir` and sorted. `.git` and the files matched
+/// by a `.cloudeignore`, in `dir` or below, are skipped.
+fn collect_files(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    let walk = WalkBuilder::new(dir)
+        .standard_filters(false)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build();
+    for entry in walk {
+        let entry = entry?;
+        if entry.file_type().is_some_and(|kind| kind.is_file()) {
+            files.push(entry.path().strip_prefix(dir)?.to_path_buf());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Gzipped tar of `files`. Only their content and executable bit are kept.
+fn build_bundle(dir: &Path, files: &[PathBuf]) -> io::Result<Vec<u8>> {
+    let mut bundle = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for file in files {
+        let source = File::open(dir.join(file))?;
+        let metadata = source.metadata()?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(metadata.len());
+        header.set_mode(if metadata.permissions().mode() & 0o111 != 0 {
+            0o755
+        } else {
+            0o644
+        });
+        header.set_mtime(0);
+        bundle.append_data(&mut header, file, source)?;
+    }
+    bundle.into_inner()?.finish()
+}
+
+/// Function name derived from a directory name: lowercase, with every
+/// character the backend does not accept replaced by `-`.
+fn function_name(dir_name: &str) -> String {
+    let name: String = dir_name
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    name.trim_start_matches(['-', '_']).to_string()
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..1_048_576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+/// Asks on the terminal; without one, deploying needs `--yes`.
+/// Asks `question` on the terminal, for commands that change the backend.
+pub fn confirm(question: &str) -> Result<bool, Box<dyn Error>> {
+    if !io::stdin().is_terminal() {
+        return Err("stdin is not a terminal, pass --yes to go on without confirmation".into());
+    }
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn project(files: &[(&str, &str)]) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        for (path, contents) in files {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_detect_runtime() {
+        let node = project(&[("package.json", r#"{ "main": "./src/server.js" }"#)]);
+        assert_eq!(
+            detect_runtime(node.path()).unwrap(),
+            Some(Detected {
+                language: "node",
+                manifest: "package.json",
+                entrypoint: Some("src/server.js".to_string()),
+            })
+        );
+
+        let rust = project(&[("Cargo.toml", "[package]\nname = \"hello\"\n")]);
+        let rust = detect_runtime(rust.path()).unwrap().unwrap();
+        assert_eq!(rust.language, "rust");
+        assert_eq!(rust.entrypoint.as_deref(), Some("src/main.rs"));
+
+        let python = project(&[("requirements.txt", "requests\n"), ("app.py", "")]);
+        let python = detect_runtime(python.path()).unwrap().unwrap();
+        assert_eq!(python.language, "python");
+        assert_eq!(python.entrypoint.as_deref(), Some("app.py"));
+
+        let unknown = project(&[("main.py", "")]);
+        assert_eq!(detect_runtime(unknown.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_cloudeignore_is_respected() {
+        let dir = project(&[
+            (".cloudeignore", "node_modules/\n*.log\n"),
+            ("index.js", ""),
+            ("lib/util.js", ""),
+            ("lib/debug.log", ""),
+            ("lib/.cloudeignore", "fixtures/\n"),
+            ("lib/fixtures/big.json", ""),
+            ("node_modules/left-pad/index.js", ""),
+            (".git/HEAD", ""),
+        ]);
+
+        let files = collect_files(dir.path()).unwrap();
+        assert_eq!(
+            files,
+            [
+                ".cloudeignore",
+                "index.js",
+                "lib/.cloudeignore",
+                "lib/util.js"
+            ]
+            .map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn test_bundle_is_reproducible() {
+        let dir = project(&[("index.js", "console.log(1)"), ("lib/a.js", "")]);
+        let files = collect_files(dir.path()).unwrap();
+        let bundle = build_bundle(dir.path(), &files).unwrap();
+
+        fs::write(dir.path().join("lib/a.js"), "").unwrap();
+        assert_eq!(build_bundle(dir.path(), &files).unwrap(), bundle);
+
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(bundle.as_slice()));
+        let paths = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, files);
+    }
+
+    #[test]
+    fn test_function_name() {
+        assert_eq!(function_name("my-fn"), "my-fn");
+        assert_eq!(function_name("My App.v2"), "my-app-v2");
+        assert_eq!(function_name("_private"), "private");
+    }
+}
@@ -0,0 +1,265 @@
+//! `dev`: an edit-run loop. The file or directory runs once, then again every
+//! time it changes on disk, and only what changed in the output is printed.
+
+use crate::args::DevArgs;
+use crate::config::CliConfig;
+use crate::{Code, deploy};
+use cloude_client::{Client, FunctionSpec, Isolation, Network, StatusResponse};
+use notify::{Event, RecursiveMode, Watcher};
+use similar::TextDiff;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Editors save in several steps (write, rename, chmod); a run starts once they are done.
+const SETTLE_DELAY: Duration = Duration::from_millis(200);
+
+pub async fn run(
+    client: &Client,
+    config: &CliConfig,
+    args: &DevArgs,
+) -> Result<(), Box<dyn Error>> {
+    let path = args
+        .path
+        .canonicalize()
+        .map_err(|e| format!("Cannot read {}: {e}", args.path.display()))?;
+    if args.entrypoint.is_some() && !path.is_dir() {
+        return Err("--entrypoint only applies to directories".into());
+    }
+
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // The receiver only goes away when `dev` returns.
+        let _ = events_tx.send(event);
+    })?;
+    match path.parent() {
+        // A file is watched through its directory: many editors save by writing
+        // a new file and renaming it over the old one.
+        Some(dir) if !path.is_dir() => watcher.watch(dir, RecursiveMode::NonRecursive)?,
+        _ => watcher.watch(&path, RecursiveMode::Recursive)?,
+    }
+    eprintln!("Watching {}, press Ctrl-C to stop", args.path.display());
+
+    let mut last_code = None;
+    let mut last_result = None;
+    loop {
+        match load(&path, args) {
+            // Only ignored files changed, or the file was saved as it was.
+            Ok((_, code)) if last_code.as_ref() == Some(&code) => {}
+            Ok((language, code)) => {
+                if last_result.is_some() {
+                    eprintln!("\n── {} changed, running again", args.path.display());
+                }
+                match execute(client, config, args, &language, code.clone()).await {
+                    Ok(result) => {
+                        match &last_result {
+                            Some(previous) => print!("{}", describe_changes(previous, &result)),
+                            None => crate::print_result(&result),
+                        }
+                        last_code = Some(code);
+                        last_result = Some(result);
+                    }
+                    Err(e) => eprintln!("Error: {e}"),
+                }
+            }
+            Err(e) => eprintln!("Error: {e}"),
+        }
+
+        wait_for_change(&mut events, &path).await?;
+    }
+}
+
+/// What to run for the current content of `path`.
+fn load(path: &Path, args: &DevArgs) -> Result<(String, Code), Box<dyn Error>> {
+    if path.is_dir() {
+        let package = deploy::package(path, args.language.as_deref(), args.entrypoint.as_deref())?;
+        return Ok((
+            package.language,
+            Code::Bundle {
+                archive: package.bundle,
+                entrypoint: package.entrypoint,
+            },
+        ));
+    }
+
+    let language = match &args.language {
+        Some(language) => language.clone(),
+        None => crate::language_from_path(path)
+            .ok_or_else(|| {
+                format!(
+                    "Cannot detect the language of {}, use --language",
+                    path.display()
+                )
+            })?
+            .to_string(),
+    };
+    let code = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read file {}: {e}", path.display()))?;
+    Ok((language, Code::Source(code)))
+}
+
+async fn execute(
+    client: &Client,
+    config: &CliConfig,
+    args: &DevArgs,
+    language: &str,
+    code: Code,
+) -> Result<StatusResponse, Box<dyn Error>> {
+    if args.local {
+        return crate::run_local(&args.local_args, config, language, code, false).await;
+    }
+
+    let spec = match code {
+        Code::Source(code) => FunctionSpec {
+            language: language.to_string(),
+            code: Some(code),
+            artifact: None,
+            entrypoint: None,
+            deterministic: false,
+            cache: None,
+            isolation: Isolation::Vm,
+            resources: None,
+            stdin: None,
+            profile: None,
+            network: Network::Open,
+            identity_audience: None,
+        },
+        Code::Bundle {
+            archive,
+            entrypoint,
+        } => {
+            // Artifacts are content-addressed, so a bundle sent again is not stored twice.
+            let artifact = client.deploy(archive).await?;
+            FunctionSpec {
+                language: language.to_string(),
+                code: None,
+                artifact: Some(artifact.id),
+                entrypoint: Some(entrypoint),
+                deterministic: false,
+                cache: None,
+                isolation: Isolation::Vm,
+                resources: None,
+                stdin: None,
+                profile: None,
+                network: Network::Open,
+                identity_audience: None,
+            }
+        }
+    };
+    Ok(client.execute(&spec).await?)
+}
+
+/// Waits for a change under `path`, then for the other events of the same save.
+async fn wait_for_change(
+    events: &mut mpsc::UnboundedReceiver<notify::Result<Event>>,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        match events.recv().await.ok_or("File watcher stopped")? {
+            Ok(event) if is_relevant(&event, path) => break,
+            Ok(_) => {}
+            Err(e) => eprintln!("Watch error: {e}"),
+        }
+    }
+    while let Ok(Some(_)) = tokio::time::timeout(SETTLE_DELAY, events.recv()).await {}
+    Ok(())
+}
+
+/// Whether `event` changed `path`, or a file under it outside of `.git`.
+fn is_relevant(event: &Event, path: &Path) -> bool {
+    let kind = event.kind;
+    if !(kind.is_create() || kind.is_modify() || kind.is_remove()) {
+        return false;
+    }
+    event.paths.iter().any(|changed| {
+        changed
+            .strip_prefix(path)
+            .is_ok_and(|relative| !relative.components().any(|c| c.as_os_str() == ".git"))
+    })
+}
+
+/// Status of a run, and a diff of each output against the previous run.
+fn describe_changes(previous: &StatusResponse, current: &StatusResponse) -> String {
+    let mut text = format!("Status: {}\n", current.status);
+    match (previous.exit_code, current.exit_code) {
+        (Some(before), Some(now)) if before != now => {
+            text.push_str(&format!("Exit code: {before} -> {now}\n"))
+        }
+        (_, Some(now)) => text.push_str(&format!("Exit code: {now}\n")),
+        (_, None) => {}
+    }
+
+    for (name, before, now) in [
+        ("stdout", &previous.stdout, &current.stdout),
+        ("stderr", &previous.stderr, &current.stderr),
+    ] {
+        let before = before.as_deref().unwrap_or_default();
+        let now = now.as_deref().unwrap_or_default();
+        if before == now {
+            text.push_str(&format!("{name}: unchanged\n"));
+        } else {
+            let diff = TextDiff::from_lines(before, now);
+            text.push_str(&format!("{name}:\n{}", diff.unified_diff()));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloude_client::JobStatus;
+    use notify::EventKind;
+    use notify::event::{AccessKind, CreateKind};
+
+    fn result(exit_code: i32, stdout: &str) -> StatusResponse {
+        StatusResponse {
+            id: "job-1".to_string(),
+            status: JobStatus::Done,
+            exit_code: Some(exit_code),
+            stdout: Some(stdout.to_string()),
+            stderr: Some(String::new()),
+            isolation: Some(Isolation::Vm),
+            traffic: None,
+            oom_killed: false,
+            signal: None,
+            core_dump: false,
+        }
+    }
+
+    #[test]
+    fn test_describe_changes() {
+        let previous = result(0, "a\nb\nc\n");
+        assert_eq!(
+            describe_changes(&previous, &result(1, "a\nB\nc\n")),
+            "Status: done\nExit code: 0 -> 1\nstdout:\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\nstderr: unchanged\n"
+        );
+        assert_eq!(
+            describe_changes(&previous, &previous),
+            "Status: done\nExit code: 0\nstdout: unchanged\nstderr: unchanged\n"
+        );
+    }
+
+    #[test]
+    fn test_is_relevant() {
+        let dir = Path::new("/src/my-fn");
+        let event = |kind, path: &str| Event::new(kind).add_path(dir.join(path));
+        let created = EventKind::Create(CreateKind::File);
+
+        assert!(is_relevant(&event(created, "index.js"), dir));
+        assert!(is_relevant(
+            &event(created, "index.js"),
+            &dir.join("index.js")
+        ));
+        assert!(!is_relevant(
+            &event(created, "other.js"),
+            &dir.join("index.js")
+        ));
+        assert!(!is_relevant(&event(created, ".git/index"), dir));
+        assert!(!is_relevant(
+            &event(EventKind::Access(AccessKind::Read), "index.js"),
+            dir
+        ));
+    }
+}
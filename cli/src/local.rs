@@ -0,0 +1,314 @@
+//! Local mode: runs code in a micro-VM on this machine, without a backend.
+//!
+//! Runtime images are built exactly like the backend builds them, and the VM
+//! is linked to the host only, so neither a bridge nor NAT is needed. The
+//! guest cannot reach anything beyond the host.
+
+use crate::Code;
+use crate::args::LocalArgs;
+use crate::config::LocalConfig;
+use crate::console::Session;
+use backend::console::SerialConsole;
+use backend::initramfs_manager::get_languages_config;
+use backend::ip_manager::IpManager;
+use backend::redaction::Redactor;
+use backend::vm_lifecycle::{CpuPolicy, DEFAULT_JOB_PIDS_MAX, VmConfig, VmHandle, job_limits};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use cloude_client::{Isolation, JobStatus, StatusResponse};
+use cloude_types::{ExecuteRequest, ExecutionResult, JobLimits};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::net::Ipv4Addr;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+impl LocalArgs {
+    /// Guest kernel: flag or environment, then the CLI config, then the backend default.
+    fn kernel(&self, config: &LocalConfig) -> PathBuf {
+        self.kernel
+            .clone()
+            .or_else(|| config.kernel.clone())
+            .unwrap_or_else(|| PathBuf::from("./vmlinux"))
+    }
+
+    fn initramfs_dir(&self, config: &LocalConfig) -> PathBuf {
+        self.initramfs_dir
+            .clone()
+            .or_else(|| config.initramfs_dir.clone())
+            .unwrap_or_else(|| PathBuf::from("./tmp"))
+    }
+}
+
+/// Local runs get a /24 each from 10.39.192.0/18: the host side of the link of
+/// a VM takes the whole /24, so two VMs in the same one would clash.
+const FIRST_LOCAL_SUBNET: u8 = 192;
+const LOCAL_SUBNETS: u8 = 64;
+
+/// Address of the guest of a local run, `.2` of its /24, the host side of its
+/// link being `.1`. No other local run gets it until this is dropped.
+struct GuestIp {
+    ip: Ipv4Addr,
+    /// Locked while the run holds the address. The lock goes away with the
+    /// process, however it ends.
+    _lock: File,
+}
+
+impl GuestIp {
+    /// Takes the first address no other local run holds.
+    fn take() -> Result<Self, Box<dyn Error>> {
+        for subnet in 0..LOCAL_SUBNETS {
+            let ip = Ipv4Addr::new(10, 39, FIRST_LOCAL_SUBNET + subnet, 2);
+            let path = std::env::temp_dir().join(format!("cloude-local-{}.lock", ip));
+            let lock = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+            // SAFETY: the descriptor is open for as long as `lock` lives.
+            if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+                return Ok(Self { ip, _lock: lock });
+            }
+            let error = std::io::Error::last_os_error();
+            if error.kind() != std::io::ErrorKind::WouldBlock {
+                return Err(format!("Cannot lock {}: {}", path.display(), error).into());
+            }
+        }
+        Err(format!("{} local runs are active already", LOCAL_SUBNETS).into())
+    }
+}
+
+/// Builds the runtime image if needed, boots a VM, runs `code` and tears the VM down.
+pub async fn run(
+    args: &LocalArgs,
+    config: &LocalConfig,
+    language: &str,
+    code: Code,
+    deterministic: bool,
+) -> Result<StatusResponse, Box<dyn Error>> {
+    let vm = LocalVm::boot(args, config, language, args.log_guest_console).await?;
+    // No blob store to keep a core in.
+    let limits = job_limits(
+        args.vcpus,
+        args.memory_mb,
+        DEFAULT_JOB_PIDS_MAX,
+        0,
+        &CpuPolicy::default(),
+    );
+    let result = execute(&vm.handle, language, code, deterministic, limits).await;
+    let vm_id = vm.handle.vm_id.clone();
+    vm.destroy().await;
+
+    let result = result?;
+    Ok(StatusResponse {
+        id: vm_id,
+        status: JobStatus::Done,
+        exit_code: Some(result.exit_code),
+        stdout: Some(result.stdout),
+        stderr: Some(result.stderr),
+        isolation: Some(Isolation::Vm),
+        traffic: None,
+        oom_killed: result.oom_killed,
+        signal: result.signal,
+        core_dump: false,
+    })
+}
+
+/// Boots a VM and attaches the terminal to its serial console.
+/// The VM is torn down once the user detaches.
+pub async fn console(
+    args: &LocalArgs,
+    config: &LocalConfig,
+    language: &str,
+) -> Result<(), Box<dyn Error>> {
+    // The console is the terminal itself, mirroring it on stdout would print everything twice.
+    let vm = LocalVm::boot(args, config, language, false).await?;
+    let session = match vm.handle.console().attach() {
+        Some((scrollback, output)) => Ok(LocalConsole {
+            console: Arc::clone(vm.handle.console()),
+            scrollback,
+            output,
+        }),
+        None => Err("VM console is already closed".into()),
+    };
+    let name = vm.handle.vm_id.clone();
+    let result = match session {
+        Ok(session) => crate::console::attach(session, &name).await,
+        Err(e) => Err(e),
+    };
+    vm.destroy().await;
+    result
+}
+
+/// A VM booted on this machine.
+struct LocalVm {
+    handle: VmHandle,
+    ip_state: PathBuf,
+    _guest_ip: GuestIp,
+}
+
+impl LocalVm {
+    /// Builds the runtime image if needed and boots a VM running it.
+    async fn boot(
+        args: &LocalArgs,
+        config: &LocalConfig,
+        language: &str,
+        log_guest_console: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let kernel = args.kernel(config);
+        let initramfs_dir = args.initramfs_dir(config);
+        let sources = &args.runtime;
+
+        let runtime = get_languages_config(&sources.languages_config)?
+            .into_iter()
+            .find(|runtime| runtime.name == language)
+            .ok_or_else(|| {
+                format!(
+                    "Unsupported language: {} (not listed in {})",
+                    language, sources.languages_config
+                )
+            })?;
+        runtime
+            .setup_initramfs(
+                &sources.agent_binary,
+                &sources.init_script,
+                &initramfs_dir.to_string_lossy(),
+            )
+            .await
+            .map_err(|e| format!("Failed to setup initramfs for {}: {}", language, e))?;
+
+        let guest_ip = GuestIp::take()?;
+        let vm_id = format!("local-{}", std::process::id());
+        let ip_state = std::env::temp_dir().join(format!("cloude-{}-ips.json", vm_id));
+        let ip_manager = Arc::new(Mutex::new(IpManager::new(
+            &ip_state,
+            guest_ip.ip,
+            guest_ip.ip,
+        )?));
+
+        let vm_config = VmConfig {
+            kernel_path: kernel,
+            initramfs_dir,
+            bridge_name: None,
+            tenant: None,
+            vcpus: args.vcpus,
+            memory_mb: args.memory_mb,
+            log_guest_console,
+            serial_log: None,
+            redactor: Redactor::default(),
+            cmdline_extra: None,
+            acpi: false,
+            max_vcpus: 0,
+            max_memory_mb: 0,
+            traffic_accounting: false,
+            mtu: None,
+            merge_memory: false,
+        };
+        match VmHandle::create(vm_id, language, &vm_config, ip_manager).await {
+            Ok(handle) => Ok(Self {
+                handle,
+                ip_state,
+                _guest_ip: guest_ip,
+            }),
+            Err(e) => {
+                let _ = std::fs::remove_file(&ip_state);
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn destroy(mut self) {
+        self.handle.destroy().await;
+        let _ = std::fs::remove_file(&self.ip_state);
+    }
+}
+
+/// Serial console of a local VM, read directly rather than through a backend.
+struct LocalConsole {
+    console: Arc<SerialConsole>,
+    scrollback: Vec<u8>,
+    output: broadcast::Receiver<Vec<u8>>,
+}
+
+impl Session for LocalConsole {
+    async fn send(&mut self, input: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        match self.console.send_input(&input) {
+            // The guest is not reading, drop the keys like the backend does.
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    async fn recv(&mut self) -> Option<Result<Vec<u8>, Box<dyn Error>>> {
+        if !self.scrollback.is_empty() {
+            return Some(Ok(std::mem::take(&mut self.scrollback)));
+        }
+        loop {
+            match self.output.recv().await {
+                Ok(output) => return Some(Ok(output)),
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    async fn close(self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+async fn execute(
+    vm: &VmHandle,
+    language: &str,
+    code: Code,
+    deterministic: bool,
+    limits: JobLimits,
+) -> Result<ExecutionResult, Box<dyn Error>> {
+    let request = ExecuteRequest {
+        language: language.to_string(),
+        code: String::new(),
+        bundle: None,
+        entrypoint: None,
+        deterministic,
+        offline: false,
+        stdin: None,
+        stream: false,
+        binary: None,
+        keep_binary: false,
+        build_only: false,
+        limits: Some(limits),
+        identity_token: None,
+        egress_proxy: None,
+        concurrent: false,
+    };
+    let request = match code {
+        Code::Source(code) => ExecuteRequest { code, ..request },
+        Code::Bundle {
+            archive,
+            entrypoint,
+        } => ExecuteRequest {
+            bundle: Some(BASE64_STANDARD.encode(archive)),
+            entrypoint: Some(entrypoint),
+            ..request
+        },
+    };
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(300))
+        .build()?;
+    let resp = client
+        .post(format!("{}/execute", vm.agent_url()))
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| format!("Cannot reach VM agent: {}", e))?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        return Err(format!("Agent returned HTTP {}: {}", status, body).into());
+    }
+    Ok(resp.json().await?)
+}
@@ -1,185 +1,529 @@
-use clap::{Parser, Subcommand};
-use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
-use std::time::Duration;
-
-/// Cloude CLI – run code in micro-VMs
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Cli {
-    #[arg(short, long, default_value = "http://127.0.0.1:8080")]
-    backend: String,
-
-    #[command(subcommand)]
-    command: Commands,
-}
-
-#[derive(Subcommand, Debug)]
-enum Commands {
-    /// Send a source file
-    Go {
-        /// Programming language (python, javascript, rust, …)
-        #[arg(short, long)]
-        language: String,
-        /// Source file to run
-        #[arg(short, long)]
-        file: PathBuf,
-    },
+mod apply;
+mod args;
+mod batch;
+mod config;
+mod console;
+mod deploy;
+mod dev;
+#[cfg(feature = "local")]
+mod local;
+mod setup;
 
-    /// Query the status / result of a job
-    Status {
-        /// Job ID
-        id: String,
-    },
-}
-
-// ── Shared DTOs (mirror backend) ────────────────────────────────────
-
-#[derive(Serialize)]
-struct RunRequest {
-    language: String,
-    code: String,
-}
-
-#[derive(Deserialize)]
-struct RunResponse {
-    id: String,
-}
-
-#[derive(Deserialize)]
-struct StatusResponse {
-    id: String,
-    status: String,
-    #[serde(default)]
-    exit_code: Option<i32>,
-    #[serde(default)]
-    stdout: Option<String>,
-    #[serde(default)]
-    stderr: Option<String>,
-}
-
-#[derive(Deserialize, Debug)]
-struct ErrorBody {
-    error: String,
-}
+use args::{Cli, Commands, LocalArgs};
+use clap::{CommandFactory, Parser};
+use cloude_client::{
+    Client, FunctionSpec, Isolation, JobStatus, LogLine, LogSource, Network, StatusResponse,
+};
+use config::CliConfig;
+use std::io::Write;
+use std::path::Path;
 
 // ── Main ────────────────────────────────────────────────────────────
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
-    let backend = cli.backend.trim_end_matches('/').to_string();
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()
-        .expect("Failed to build HTTP client");
+    let config_path = cli.config.unwrap_or_else(config::default_path);
+    let mut builder = Client::builder(cli.backend);
+    if let Some(api_key) = cli.api_key {
+        builder = builder.api_key(api_key);
+    }
+    if let Some(namespace) = cli.namespace {
+        builder = builder.namespace(namespace);
+    }
+    let client = builder.build().expect("Failed to build HTTP client");
 
     match cli.command {
-        Commands::Go { language, file } => {
-            if let Err(e) = cmd_go(&client, &backend, &language, &file).await {
+        Commands::Go {
+            language,
+            file,
+            deterministic,
+            profile,
+            quiet,
+        } => match cmd_go(&client, &language, &file, deterministic, profile).await {
+            Ok(st) => finish(&st, quiet),
+            Err(e) => {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        },
+        Commands::Run {
+            file,
+            language,
+            local,
+            deterministic,
+            profile,
+            quiet,
+            local_args,
+        } => {
+            let result = match CliConfig::load(&config_path) {
+                Ok(config) => {
+                    cmd_run(
+                        &client,
+                        &config,
+                        &file,
+                        language,
+                        local.then_some(&local_args),
+                        deterministic,
+                        profile,
+                    )
+                    .await
+                }
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(st) => finish(&st, quiet),
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Deploy(args) => {
+            if let Err(e) = deploy::run(&client, &args).await {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+        Commands::Apply(args) => {
+            if let Err(e) = apply::run(&client, &args).await {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+        Commands::Batch(args) => match batch::run(&client, &args).await {
+            Ok(passed) => std::process::exit(if passed { 0 } else { 1 }),
+            Err(e) => {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        },
+        Commands::Dev(args) => {
+            let result = match CliConfig::load(&config_path) {
+                Ok(config) => dev::run(&client, &config, &args).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
                 eprintln!("Error: {e}");
                 std::process::exit(1);
             }
         }
         Commands::Status { id } => {
-            if let Err(e) = cmd_status(&client, &backend, &id).await {
+            if let Err(e) = cmd_status(&client, &id).await {
                 eprintln!("Error: {e}");
                 std::process::exit(1);
             }
         }
+        Commands::Logs {
+            id,
+            follow,
+            timestamps,
+            labels,
+        } => {
+            let format = LogFormat { timestamps, labels };
+            if let Err(e) = cmd_logs(&client, &id, follow, format).await {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+        Commands::Console {
+            vm_id,
+            local,
+            local_args,
+        } => {
+            let result = if local {
+                match CliConfig::load(&config_path) {
+                    Ok(config) => console_local(&local_args, &config, &vm_id).await,
+                    Err(e) => Err(e.into()),
+                }
+            } else {
+                cmd_console(&client, &vm_id).await
+            };
+            if let Err(e) = result {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+        Commands::Setup(args) => {
+            if let Err(e) = setup::run(&args, &config_path).await {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+        Commands::Completions { shell } => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        }
     }
 }
 
 // ── go: send code to backend ────────────────────────────────────────
 
 async fn cmd_go(
-    client: &reqwest::Client,
-    backend: &str,
+    client: &Client,
     language: &str,
     file: &Path,
-) -> Result<(), Box<dyn std::error::Error>> {
+    deterministic: bool,
+    profile: Option<String>,
+) -> Result<StatusResponse, Box<dyn std::error::Error>> {
     let code = std::fs::read_to_string(file)
         .map_err(|e| format!("Cannot read file {}: {e}", file.display()))?;
 
-    let url = format!("{backend}/run");
-    let body = RunRequest {
+    let spec = FunctionSpec {
         language: language.to_string(),
-        code,
+        code: Some(code),
+        artifact: None,
+        entrypoint: None,
+        deterministic,
+        cache: None,
+        isolation: Isolation::Vm,
+        resources: None,
+        stdin: None,
+        profile,
+        network: Network::Open,
+        identity_audience: None,
     };
+    Ok(client.execute(&spec).await?)
+}
 
-    let resp = client.post(&url).json(&body).send().await?;
+// ── run: send code to backend or run it locally ─────────────────────
 
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let err: ErrorBody = resp.json().await.unwrap_or(ErrorBody {
-            error: format!("HTTP {status}"),
-        });
-        return Err(format!("Backend error (HTTP {status}): {}", err.error).into());
-    }
+/// Runs `file` on the backend, or in a micro-VM on this machine with `local`.
+async fn cmd_run(
+    client: &Client,
+    config: &CliConfig,
+    file: &Path,
+    language: Option<String>,
+    local: Option<&LocalArgs>,
+    deterministic: bool,
+    profile: Option<String>,
+) -> Result<StatusResponse, Box<dyn std::error::Error>> {
+    let language = match language {
+        Some(language) => language,
+        None => language_from_path(file)
+            .ok_or_else(|| {
+                format!(
+                    "Cannot detect the language of {}, use --language",
+                    file.display()
+                )
+            })?
+            .to_string(),
+    };
 
-    let run: RunResponse = resp.json().await?;
-    let job_id = run.id.clone();
+    let Some(local_args) = local else {
+        return cmd_go(client, &language, file, deterministic, profile).await;
+    };
 
-    loop {
-        tokio::time::sleep(Duration::from_secs(1)).await;
+    let code = std::fs::read_to_string(file)
+        .map_err(|e| format!("Cannot read file {}: {e}", file.display()))?;
+    run_local(
+        local_args,
+        config,
+        &language,
+        Code::Source(code),
+        deterministic,
+    )
+    .await
+}
 
-        let status_url = format!("{backend}/status/{job_id}");
-        let status_resp = client.get(&status_url).send().await?;
+/// Code of a job, as handed to the agent.
+#[derive(Clone, Debug, PartialEq)]
+enum Code {
+    Source(String),
+    /// Gzipped tar of a directory, and the file to run in it.
+    Bundle {
+        archive: Vec<u8>,
+        entrypoint: String,
+    },
+}
 
-        if !status_resp.status().is_success() {
-            let status = status_resp.status();
-            let err: ErrorBody = status_resp.json().await.unwrap_or(ErrorBody {
-                error: format!("HTTP {status}"),
-            });
-            return Err(format!("Backend error (HTTP {status}): {}", err.error).into());
-        }
+#[cfg(feature = "local")]
+async fn run_local(
+    local_args: &LocalArgs,
+    config: &CliConfig,
+    language: &str,
+    code: Code,
+    deterministic: bool,
+) -> Result<StatusResponse, Box<dyn std::error::Error>> {
+    let language = backend::validation::normalize_language_alias(&language.to_ascii_lowercase());
+    local::run(local_args, &config.local, &language, code, deterministic).await
+}
 
-        let st: StatusResponse = status_resp.json().await?;
+#[cfg(not(feature = "local"))]
+async fn run_local(
+    _local_args: &LocalArgs,
+    _config: &CliConfig,
+    _language: &str,
+    _code: Code,
+    _deterministic: bool,
+) -> Result<StatusResponse, Box<dyn std::error::Error>> {
+    Err("this CLI was built without local mode (the `local` feature)".into())
+}
 
-        if st.status == "done" || st.status == "error" {
-            println!("Status: {}", st.status);
-            if let Some(code) = st.exit_code {
-                println!("Exit code: {code}");
-            }
-            if let Some(ref out) = st.stdout {
-                if !out.is_empty() {
-                    println!("{out}");
-                }
-            }
-            if let Some(ref err) = st.stderr {
-                if !err.is_empty() {
-                    println!("{err}");
-                }
-            }
-            return Ok(());
+/// Runtime of a source file, from its extension.
+fn language_from_path(file: &Path) -> Option<&'static str> {
+    let language = match file.extension()?.to_str()? {
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "node",
+        "rs" => "rust",
+        "go" => "go",
+        "c" => "c",
+        "cpp" | "cc" | "cxx" => "cpp",
+        "java" => "java",
+        _ => return None,
+    };
+    Some(language)
+}
+
+/// Prints the result of a job and exits with the exit status of its code.
+fn finish(st: &StatusResponse, quiet: bool) -> ! {
+    if quiet {
+        // The output as the code wrote it, for pipelines.
+        if let Some(out) = &st.stdout {
+            print!("{out}");
+        }
+        if let Some(err) = &st.stderr {
+            eprint!("{err}");
         }
+    } else {
+        print_result(st);
     }
+    let _ = std::io::stdout().flush();
+    std::process::exit(exit_status(st))
 }
 
-// ── status: query job result ────────────────────────────────────────
+/// Exit status of the CLI for a finished job, as a shell would give it for its code:
+/// the exit code of the code, 128 plus the signal number when a signal killed it, and 1
+/// when it did not run.
+fn exit_status(st: &StatusResponse) -> i32 {
+    if let Some(number) = st.signal.as_deref().and_then(signal_number) {
+        return 128 + number;
+    }
+    if st.oom_killed {
+        return 128 + 9;
+    }
+    match (st.exit_code, st.status) {
+        (Some(code), _) => code,
+        (None, JobStatus::Done) => 0,
+        (None, _) => 1,
+    }
+}
 
-async fn cmd_status(
-    client: &reqwest::Client,
-    backend: &str,
-    id: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let url = format!("{backend}/status/{id}");
-    let resp = client.get(&url).send().await?;
+/// Number of a Linux signal named like `SIGSEGV`, or `SIG64` for those without a name.
+fn signal_number(name: &str) -> Option<i32> {
+    let number = match name {
+        "SIGHUP" => 1,
+        "SIGINT" => 2,
+        "SIGQUIT" => 3,
+        "SIGILL" => 4,
+        "SIGTRAP" => 5,
+        "SIGABRT" => 6,
+        "SIGBUS" => 7,
+        "SIGFPE" => 8,
+        "SIGKILL" => 9,
+        "SIGUSR1" => 10,
+        "SIGSEGV" => 11,
+        "SIGUSR2" => 12,
+        "SIGPIPE" => 13,
+        "SIGALRM" => 14,
+        "SIGTERM" => 15,
+        "SIGXCPU" => 24,
+        "SIGXFSZ" => 25,
+        "SIGSYS" => 31,
+        _ => return name.strip_prefix("SIG")?.parse().ok(),
+    };
+    Some(number)
+}
 
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let err: ErrorBody = resp.json().await.unwrap_or(ErrorBody {
-            error: format!("HTTP {status}"),
-        });
-        return Err(format!("Backend error (HTTP {status}): {}", err.error).into());
+fn print_result(st: &StatusResponse) {
+    println!("Status: {}", st.status);
+    if let Some(code) = st.exit_code {
+        println!("Exit code: {code}");
+    }
+    if st.oom_killed {
+        println!("Killed: out of memory");
+    }
+    if let Some(signal) = &st.signal {
+        let core = if st.core_dump { " (core dumped)" } else { "" };
+        println!("Signal: {signal}{core}");
     }
+    if let Some(out) = &st.stdout
+        && !out.is_empty()
+    {
+        println!("{out}");
+    }
+    if let Some(err) = &st.stderr
+        && !err.is_empty()
+    {
+        eprintln!("{err}");
+    }
+}
+
+// ── status: query job result ────────────────────────────────────────
 
-    let st: StatusResponse = resp.json().await?;
+async fn cmd_status(client: &Client, id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let st = client.status(id).await?;
 
     println!("Job ID: {}", st.id);
     println!("Status: {}", st.status);
     if let Some(code) = st.exit_code {
         println!("Exit code: {code}");
     }
+    if st.oom_killed {
+        println!("Killed: out of memory");
+    }
+    if let Some(signal) = &st.signal {
+        let core = if st.core_dump { " (core dumped)" } else { "" };
+        println!("Signal: {signal}{core}");
+    }
     Ok(())
 }
+
+// ── logs: print or follow the logs of a job ─────────────────────────
+
+#[derive(Clone, Copy, Debug, Default)]
+struct LogFormat {
+    timestamps: bool,
+    labels: bool,
+}
+
+async fn cmd_logs(
+    client: &Client,
+    id: &str,
+    follow: bool,
+    format: LogFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !follow {
+        for line in client.logs(id).await? {
+            print_log_line(&line, format);
+        }
+        return Ok(());
+    }
+
+    let mut follower = client.follow_logs(id).await?;
+    while let Some(line) = follower.next().await {
+        print_log_line(&line?, format);
+    }
+    Ok(())
+}
+
+/// Output of the code goes to stdout, everything else to stderr, so `logs` can be piped.
+fn print_log_line(line: &LogLine, format: LogFormat) {
+    let text = format_log_line(line, format);
+    match line.source {
+        LogSource::Stdout => println!("{text}"),
+        LogSource::Stderr | LogSource::Kernel | LogSource::Egress => eprintln!("{text}"),
+    }
+}
+
+fn format_log_line(line: &LogLine, format: LogFormat) -> String {
+    let mut text = String::new();
+    let time = format
+        .timestamps
+        .then(|| chrono::DateTime::from_timestamp_millis(line.timestamp_ms as i64))
+        .flatten();
+    if let Some(time) = time {
+        text.push_str(&time.format("%Y-%m-%dT%H:%M:%S%.3fZ ").to_string());
+    }
+    if format.labels {
+        text.push_str(&format!("{} | ", line.source));
+    }
+    text.push_str(&line.line);
+    text
+}
+
+// ── console: attach to the serial console of a VM ───────────────────
+
+async fn cmd_console(client: &Client, id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let session = client.console(id).await?;
+    console::attach(session, id).await
+}
+
+#[cfg(feature = "local")]
+async fn console_local(
+    local_args: &LocalArgs,
+    config: &CliConfig,
+    language: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let language = backend::validation::normalize_language_alias(&language.to_ascii_lowercase());
+    local::console(local_args, &config.local, &language).await
+}
+
+#[cfg(not(feature = "local"))]
+async fn console_local(
+    _local_args: &LocalArgs,
+    _config: &CliConfig,
+    _language: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("this CLI was built without local mode (the `local` feature)".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_language_from_path() {
+        assert_eq!(language_from_path(Path::new("hello.py")), Some("python"));
+        assert_eq!(language_from_path(Path::new("src/index.mjs")), Some("node"));
+        assert_eq!(language_from_path(Path::new("main.cc")), Some("cpp"));
+        assert_eq!(language_from_path(Path::new("Makefile")), None);
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let mut st = StatusResponse {
+            id: "job-1".to_string(),
+            status: JobStatus::Done,
+            exit_code: Some(3),
+            stdout: Some(String::new()),
+            stderr: Some(String::new()),
+            isolation: Some(Isolation::Vm),
+            traffic: None,
+            oom_killed: false,
+            signal: None,
+            core_dump: false,
+        };
+        assert_eq!(exit_status(&st), 3);
+
+        st.signal = Some("SIGSEGV".to_string());
+        assert_eq!(exit_status(&st), 139);
+        st.signal = Some("SIG34".to_string());
+        assert_eq!(exit_status(&st), 162);
+
+        st.signal = None;
+        st.exit_code = None;
+        st.status = JobStatus::Error;
+        assert_eq!(exit_status(&st), 1);
+    }
+
+    #[test]
+    fn test_format_log_line() {
+        let line = LogLine {
+            timestamp_ms: 1_760_000_000_123,
+            source: LogSource::Kernel,
+            line: "Linux version 6.1".to_string(),
+        };
+
+        assert_eq!(
+            format_log_line(&line, LogFormat::default()),
+            "Linux version 6.1"
+        );
+        assert_eq!(
+            format_log_line(
+                &line,
+                LogFormat {
+                    timestamps: true,
+                    labels: true
+                }
+            ),
+            "2025-10-09T08:53:20.123Z kernel | Linux version 6.1"
+        );
+    }
+}
@@ -0,0 +1,310 @@
+//! `setup`: installs a known-good guest kernel and pre-builds the runtime
+//! images, then records their paths for local mode and the backend.
+
+use crate::args::{RuntimeArgs, SetupArgs};
+use crate::config::{self, CliConfig};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Manifest used when `--manifest` is not given.
+const DEFAULT_MANIFEST: &str = include_str!("../setup/default.toml");
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct SetupManifest {
+    kernel: KernelSource,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum KernelSource {
+    Prebuilt(PrebuiltKernel),
+    Source(KernelTarball),
+}
+
+/// A kernel image ready to boot.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct PrebuiltKernel {
+    url: String,
+    sha256: String,
+}
+
+/// Kernel sources built with `defconfig` plus `config`.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct KernelTarball {
+    source_url: String,
+    #[serde(default)]
+    sha256: Option<String>,
+    /// Checksum list published next to the tarball, used when `sha256` is absent.
+    #[serde(default)]
+    checksums_url: Option<String>,
+    /// `CONFIG_*` lines appended to the default configuration.
+    #[serde(default)]
+    config: Vec<String>,
+}
+
+pub async fn run(args: &SetupArgs, config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = match &args.manifest {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read manifest {}: {e}", path.display()))?,
+        None => DEFAULT_MANIFEST.to_string(),
+    };
+    let manifest: SetupManifest =
+        toml::from_str(&manifest).map_err(|e| format!("Invalid setup manifest: {e}"))?;
+
+    let data_dir = args
+        .data_dir
+        .clone()
+        .unwrap_or_else(config::default_data_dir);
+    std::fs::create_dir_all(&data_dir)
+        .map_err(|e| format!("Cannot create {}: {e}", data_dir.display()))?;
+    let data_dir = data_dir.canonicalize()?;
+
+    let kernel = data_dir.join("vmlinux");
+    if kernel.exists() && !args.force {
+        println!("Kernel already installed at {}", kernel.display());
+    } else {
+        let client = reqwest::Client::new();
+        match &manifest.kernel {
+            KernelSource::Prebuilt(prebuilt) => {
+                download_verified(&client, &prebuilt.url, &prebuilt.sha256, &kernel).await?
+            }
+            KernelSource::Source(tarball) => build_kernel(&client, tarball, &data_dir).await?,
+        }
+        println!("Kernel installed at {}", kernel.display());
+    }
+
+    let initramfs_dir = data_dir.join("initramfs");
+    if !args.skip_runtimes {
+        preseed_runtimes(&args.runtime, &initramfs_dir).await?;
+    }
+
+    let mut cli_config = CliConfig::load(config_path)?;
+    cli_config.local.kernel = Some(kernel.clone());
+    cli_config.local.initramfs_dir = Some(initramfs_dir.clone());
+    cli_config.save(config_path)?;
+    println!("Local mode configured in {}", config_path.display());
+
+    let backend_env = data_dir.join("backend.env");
+    std::fs::write(
+        &backend_env,
+        format!(
+            "VM_KERNEL_PATH={}\nVM_INITRAMFS_DIR={}\n",
+            kernel.display(),
+            initramfs_dir.display()
+        ),
+    )?;
+    println!(
+        "Backend environment written to {} (e.g. `env $(cat {}) backend`)",
+        backend_env.display(),
+        backend_env.display()
+    );
+    Ok(())
+}
+
+/// Downloads the sources, checks them, and builds `vmlinux` into `data_dir`.
+async fn build_kernel(
+    client: &reqwest::Client,
+    tarball: &KernelTarball,
+    data_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file_name = tarball
+        .source_url
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| format!("No file name in {}", tarball.source_url))?;
+    let source_dir_name = file_name
+        .strip_suffix(".tar.xz")
+        .or_else(|| file_name.strip_suffix(".tar.gz"))
+        .ok_or_else(|| format!("{file_name} is not a .tar.xz or .tar.gz archive"))?;
+
+    let expected = match (&tarball.sha256, &tarball.checksums_url) {
+        (Some(sha256), _) => sha256.clone(),
+        (None, Some(checksums_url)) => {
+            let sums = client
+                .get(checksums_url)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            find_checksum(&sums, file_name)
+                .ok_or_else(|| format!("{file_name} is not listed in {checksums_url}"))?
+        }
+        (None, None) => return Err("Kernel sources need `sha256` or `checksums_url`".into()),
+    };
+
+    let build_dir = data_dir.join("kernel-build");
+    std::fs::create_dir_all(&build_dir)?;
+    let archive = build_dir.join(file_name);
+    download_verified(client, &tarball.source_url, &expected, &archive).await?;
+
+    println!("Building kernel {source_dir_name}, this takes a while");
+    run_command(
+        Command::new("tar")
+            .arg("-xf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&build_dir),
+    )
+    .await?;
+    let source_dir = build_dir.join(source_dir_name);
+    run_command(
+        Command::new("make")
+            .arg("-C")
+            .arg(&source_dir)
+            .arg("defconfig"),
+    )
+    .await?;
+
+    let mut kconfig = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(source_dir.join(".config"))
+        .await?;
+    for option in &tarball.config {
+        kconfig.write_all(format!("{option}\n").as_bytes()).await?;
+    }
+    kconfig.flush().await?;
+    run_command(
+        Command::new("make")
+            .arg("-C")
+            .arg(&source_dir)
+            .arg("olddefconfig"),
+    )
+    .await?;
+
+    let jobs = std::thread::available_parallelism().map_or(1, |n| n.get());
+    run_command(
+        Command::new("make")
+            .arg("-C")
+            .arg(&source_dir)
+            .arg(format!("-j{jobs}"))
+            .arg("vmlinux"),
+    )
+    .await?;
+
+    std::fs::copy(source_dir.join("vmlinux"), data_dir.join("vmlinux"))?;
+    std::fs::remove_dir_all(&build_dir)?;
+    Ok(())
+}
+
+/// Downloads `url` to `dest`, which is only created when its SHA-256 is `expected`.
+async fn download_verified(
+    client: &reqwest::Client,
+    url: &str,
+    expected: &str,
+    dest: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Downloading {url}");
+    let mut resp = client.get(url).send().await?.error_for_status()?;
+
+    let partial = dest.with_extension("part");
+    let mut file = tokio::fs::File::create(&partial).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = resp.chunk().await? {
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    let digest = hex::encode(hasher.finalize());
+    if !digest.eq_ignore_ascii_case(expected.trim()) {
+        let _ = std::fs::remove_file(&partial);
+        return Err(
+            format!("Checksum mismatch for {url}: expected {expected}, got {digest}").into(),
+        );
+    }
+    std::fs::rename(&partial, dest)?;
+    Ok(())
+}
+
+/// Digest of `file_name` in a `sha256sum`-style list, as published by kernel.org.
+fn find_checksum(sums: &str, file_name: &str) -> Option<String> {
+    sums.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let digest = fields.next()?;
+        let name = fields.next()?;
+        (name == file_name && digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()))
+            .then(|| digest.to_ascii_lowercase())
+    })
+}
+
+async fn run_command(command: &mut Command) -> Result<(), Box<dyn std::error::Error>> {
+    let status = command
+        .status()
+        .await
+        .map_err(|e| format!("Cannot run {:?}: {e}", command.as_std().get_program()))?;
+    if !status.success() {
+        return Err(format!("{:?} failed with {status}", command.as_std()).into());
+    }
+    Ok(())
+}
+
+/// Builds the image of every runtime in the manifest, like the backend does at startup.
+#[cfg(feature = "local")]
+async fn preseed_runtimes(
+    runtime: &RuntimeArgs,
+    initramfs_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let languages = backend::initramfs_manager::get_languages_config(&runtime.languages_config)?;
+    for language in languages {
+        let name = language.name.clone();
+        language
+            .setup_initramfs(
+                &runtime.agent_binary,
+                &runtime.init_script,
+                &initramfs_dir.to_string_lossy(),
+            )
+            .await
+            .map_err(|e| format!("Failed to setup initramfs for {name}: {e}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "local"))]
+async fn preseed_runtimes(
+    _runtime: &RuntimeArgs,
+    _initramfs_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Skipping runtime images: this CLI was built without local mode");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_manifest() {
+        let manifest: SetupManifest = toml::from_str(DEFAULT_MANIFEST).unwrap();
+        let KernelSource::Source(tarball) = manifest.kernel else {
+            panic!("default kernel should be built from sources");
+        };
+        assert!(tarball.checksums_url.is_some());
+        assert!(tarball.config.contains(&"CONFIG_VIRTIO_MMIO=y".to_string()));
+
+        let prebuilt: SetupManifest =
+            toml::from_str("[kernel]\nurl = \"https://example.com/vmlinux\"\nsha256 = \"00\"\n")
+                .unwrap();
+        assert!(matches!(prebuilt.kernel, KernelSource::Prebuilt(_)));
+    }
+
+    #[test]
+    fn test_find_checksum() {
+        let digest = "a".repeat(64);
+        let sums = format!(
+            "-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA256\n\n{}  linux-6.1.101.tar.xz\n{}  linux-6.1.102.tar.xz\n",
+            "b".repeat(64),
+            digest.to_uppercase()
+        );
+
+        assert_eq!(find_checksum(&sums, "linux-6.1.102.tar.xz"), Some(digest));
+        assert_eq!(find_checksum(&sums, "linux-6.1.103.tar.xz"), None);
+    }
+}
@@ -0,0 +1,1187 @@
+//! Async client for the Cloude backend API.
+//!
+//! ```no_run
+//! use cloude_client::{Client, FunctionSpec, Isolation, Network};
+//!
+//! # async fn run() -> Result<(), cloude_client::Error> {
+//! let client = Client::builder("http://127.0.0.1:8080")
+//!     .api_key("my-key")
+//!     .build()?;
+//!
+//! let result = client
+//!     .execute(&FunctionSpec {
+//!         language: "python".to_string(),
+//!         code: Some("print('hello')".to_string()),
+//!         artifact: None,
+//!         entrypoint: None,
+//!         deterministic: false,
+//!         cache: None,
+//!         isolation: Isolation::Vm,
+//!         resources: None,
+//!         stdin: None,
+//!         profile: None,
+//!         network: Network::Open,
+//!         identity_audience: None,
+//!     })
+//!     .await?;
+//! println!("{}", result.stdout.unwrap_or_default());
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod webhook;
+
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use reqwest::StatusCode;
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::{self, Message, client::IntoClientRequest};
+
+pub use cloude_types::{
+    ApplyAction, ApplyResponse, ApplySpec, ArtifactInfo, Bundle, CacheControl, CacheStats, Change,
+    ChannelRequest, ChannelResponse, Dashboard, DashboardBucket, DashboardQuery, DeployRequest,
+    DesiredFunction, DiffLine, DiffOp, ErrorResponse, EventKind, ExecutionDiff, ExecutionSummary,
+    FunctionDashboard, FunctionInfo, FunctionSpec, ImportOutcome, ImportReport, ImportedFunction,
+    Isolation, JobStatus, LifecycleEvent, LogLine, LogSource, Network, OnConflict, PipelineRequest,
+    PipelineResponse, PipelineStage, PipelineStatus, PlannedChange, ResizeRequest, ResizeResponse,
+    ResourceUsage, Resources, RunResponse, RuntimeInfo, StatusResponse, UpdateVmRequest,
+    UpdateVmResponse, UsageDelta, VmInfo, VmLiveness,
+};
+
+/// Longest a followed log stream is kept open, instead of the usual request timeout.
+const LOG_FOLLOW_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Errors returned by the client.
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent or its response could not be read.
+    Http(reqwest::Error),
+    /// The backend answered with an error status.
+    Api {
+        status: StatusCode,
+        error: ErrorResponse,
+    },
+    InvalidApiKey,
+    InvalidNamespace,
+    /// A streamed response could not be decoded.
+    Decode(serde_json::Error),
+    /// The console connection failed.
+    WebSocket(Box<tungstenite::Error>),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "HTTP error: {}", e),
+            Error::Api { status, error } => {
+                write!(f, "Backend error (HTTP {}): {}", status, error.error)
+            }
+            Error::InvalidApiKey => write!(f, "API key is not a valid header value"),
+            Error::InvalidNamespace => write!(f, "Namespace is not a valid header value"),
+            Error::Decode(e) => write!(f, "Invalid response from backend: {}", e),
+            Error::WebSocket(e) => write!(f, "Console connection error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(err: tungstenite::Error) -> Self {
+        match err {
+            // The backend refused the upgrade, e.g. because the job has no running VM.
+            tungstenite::Error::Http(resp) => {
+                let status = resp.status();
+                let error = resp
+                    .body()
+                    .as_deref()
+                    .and_then(|body| serde_json::from_slice::<ErrorResponse>(body).ok())
+                    .unwrap_or_else(|| ErrorResponse::new(format!("HTTP {}", status)));
+                Error::Api { status, error }
+            }
+            err => Error::WebSocket(Box::new(err)),
+        }
+    }
+}
+
+/// How failed requests are retried, with exponential backoff between attempts.
+///
+/// Connection failures and `429`/`502`/`503`/`504` responses are retried for
+/// every request. Timeouts are only retried for requests that are safe to
+/// repeat, since a job submission that timed out may still have been accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Sends every request exactly once.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Delay before the attempt following `attempt` (starting at 1).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(150),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Builder for a [`Client`].
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    base_url: String,
+    api_key: Option<String>,
+    namespace: Option<String>,
+    retry: RetryPolicy,
+    timeout: Duration,
+    poll_interval: Duration,
+}
+
+impl ClientBuilder {
+    /// Key sent as a bearer token in the `Authorization` header of every request.
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Namespace of every request, sent in the `x-cloude-namespace` header. The
+    /// backend acts in its `default` namespace for clients without one.
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Timeout of a single HTTP request, 30 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Delay between two status checks while waiting for a job, 1 second by default.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn build(self) -> Result<Client, Error> {
+        let mut headers = HeaderMap::new();
+        let authorization = match &self.api_key {
+            Some(api_key) => {
+                let mut value = HeaderValue::from_str(&format!("Bearer {}", api_key))
+                    .map_err(|_| Error::InvalidApiKey)?;
+                value.set_sensitive(true);
+                headers.insert(AUTHORIZATION, value.clone());
+                Some(value)
+            }
+            None => None,
+        };
+        let namespace = match &self.namespace {
+            Some(namespace) => {
+                let value =
+                    HeaderValue::from_str(namespace).map_err(|_| Error::InvalidNamespace)?;
+                headers.insert(NAMESPACE_HEADER, value.clone());
+                Some(value)
+            }
+            None => None,
+        };
+
+        let http = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .default_headers(headers)
+            .build()?;
+
+        Ok(Client {
+            http,
+            authorization,
+            namespace,
+            base_url: format!("{}/{}", self.base_url.trim_end_matches('/'), API_VERSION),
+            retry: self.retry,
+            poll_interval: self.poll_interval,
+        })
+    }
+}
+
+/// Version of the backend API the client speaks.
+pub const API_VERSION: &str = "v1";
+
+/// Header naming the namespace of a request.
+const NAMESPACE_HEADER: &str = "x-cloude-namespace";
+
+/// Header of a page of a list with the cursor of the next one.
+const NEXT_CURSOR_HEADER: &str = "x-cloude-next-cursor";
+
+/// Client of a Cloude backend.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    /// Sent on console connections, which do not go through `http`.
+    authorization: Option<HeaderValue>,
+    namespace: Option<HeaderValue>,
+    /// URL of the version of the API the client speaks, e.g. `http://127.0.0.1:8080/v1`.
+    base_url: String,
+    retry: RetryPolicy,
+    poll_interval: Duration,
+}
+
+impl Client {
+    /// Client with the default settings and no API key.
+    pub fn new(base_url: impl Into<String>) -> Result<Self, Error> {
+        Self::builder(base_url).build()
+    }
+
+    pub fn builder(base_url: impl Into<String>) -> ClientBuilder {
+        ClientBuilder {
+            base_url: base_url.into(),
+            api_key: None,
+            namespace: None,
+            retry: RetryPolicy::default(),
+            timeout: Duration::from_secs(30),
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Submits a job and returns its id without waiting for it.
+    pub async fn submit(&self, spec: &FunctionSpec) -> Result<RunResponse, Error> {
+        let url = format!("{}/run", self.base_url);
+        let resp = self.send(|| self.http.post(&url).json(spec), false).await?;
+        Ok(resp.json().await?)
+    }
+
+    pub async fn status(&self, id: &str) -> Result<StatusResponse, Error> {
+        let url = format!("{}/status/{}", self.base_url, id);
+        let resp = self.send(|| self.http.get(&url), true).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Polls the status of a job until it is done or failed.
+    pub async fn wait(&self, id: &str) -> Result<StatusResponse, Error> {
+        loop {
+            tokio::time::sleep(self.poll_interval).await;
+            let status = self.status(id).await?;
+            if status.status.is_terminal() {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Runs a job and returns its final status, with its exit code and output.
+    pub async fn execute(&self, spec: &FunctionSpec) -> Result<StatusResponse, Error> {
+        let run = self.submit(spec).await?;
+        self.wait(&run.id).await
+    }
+
+    /// Uploads source code as an artifact, which jobs can then reference by id
+    /// in [`FunctionSpec::artifact`] instead of sending the code inline.
+    pub async fn deploy(&self, code: impl Into<Bytes>) -> Result<ArtifactInfo, Error> {
+        let url = format!("{}/artifacts", self.base_url);
+        let code = code.into();
+        // Artifacts are content-addressed, so uploading twice is harmless.
+        let resp = self
+            .send(|| self.http.post(&url).body(code.clone()), true)
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Creates the function `name`, or points it to the artifact in `request`.
+    pub async fn deploy_function(
+        &self,
+        name: &str,
+        request: &DeployRequest,
+    ) -> Result<FunctionInfo, Error> {
+        let url = format!("{}/functions/{}", self.base_url, name);
+        // Deploying the same settings again keeps the same version, so retrying is harmless.
+        let resp = self
+            .send(|| self.http.put(&url).json(request), true)
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Current version of the function `name`.
+    pub async fn function(&self, name: &str) -> Result<FunctionInfo, Error> {
+        let url = format!("{}/functions/{}", self.base_url, name);
+        let resp = self.send(|| self.http.get(&url), true).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Submits a job running the current version of the function `name`.
+    pub async fn run_function(&self, name: &str) -> Result<RunResponse, Error> {
+        let url = format!("{}/functions/{}/run", self.base_url, name);
+        let resp = self.send(|| self.http.post(&url), false).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Makes the functions of the namespace those of `spec`, or with `dry_run`
+    /// only returns the changes that would.
+    pub async fn apply(&self, spec: &ApplySpec, dry_run: bool) -> Result<ApplyResponse, Error> {
+        let url = format!("{}/apply", self.base_url);
+        // Applying the same spec again changes nothing, so retrying is harmless.
+        let resp = self
+            .send(
+                || {
+                    self.http
+                        .post(&url)
+                        .query(&[("dry_run", dry_run)])
+                        .json(spec)
+                },
+                true,
+            )
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Starts the stages of `pipeline`, each function fed the outputs of those
+    /// it comes after.
+    pub async fn run_pipeline(
+        &self,
+        pipeline: &PipelineRequest,
+    ) -> Result<PipelineResponse, Error> {
+        let url = format!("{}/pipelines", self.base_url);
+        let resp = self
+            .send(|| self.http.post(&url).json(pipeline), false)
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Status of a pipeline and of its stages.
+    pub async fn pipeline(&self, id: &str) -> Result<PipelineStatus, Error> {
+        let url = format!("{}/pipelines/{}", self.base_url, id);
+        let resp = self.send(|| self.http.get(&url), true).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Output of stage `stage` of a pipeline, once it is done.
+    pub async fn pipeline_output(&self, id: &str, stage: &str) -> Result<Bytes, Error> {
+        let url = format!("{}/pipelines/{}/stages/{}/output", self.base_url, id, stage);
+        let resp = self.send(|| self.http.get(&url), true).await?;
+        Ok(resp.bytes().await?)
+    }
+
+    /// Every function of the namespace, with the content of their artifacts
+    /// unless `artifacts` is false, to [`Client::import`] elsewhere.
+    pub async fn export(&self, artifacts: bool) -> Result<Bundle, Error> {
+        let url = format!("{}/export", self.base_url);
+        let resp = self
+            .send(
+                || self.http.get(&url).query(&[("artifacts", artifacts)]),
+                true,
+            )
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Deploys the functions of `bundle` in the namespace, resolving the names
+    /// taken by other functions with `on_conflict`.
+    pub async fn import(
+        &self,
+        bundle: &Bundle,
+        on_conflict: OnConflict,
+    ) -> Result<ImportReport, Error> {
+        let url = format!("{}/import", self.base_url);
+        // Importing again finds the functions of the first import, so retrying is harmless.
+        let resp = self
+            .send(
+                || {
+                    self.http
+                        .post(&url)
+                        .query(&[("on_conflict", on_conflict)])
+                        .json(bundle)
+                },
+                true,
+            )
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Grows the VM of the running job `id` to `vcpus` vCPUs.
+    pub async fn resize(&self, id: &str, vcpus: u8) -> Result<ResizeResponse, Error> {
+        let url = format!("{}/vcpus/{}", self.base_url, id);
+        let request = ResizeRequest { vcpus };
+        // The VM never shrinks and asking for the count it has adds nothing, so retrying is harmless.
+        let resp = self
+            .send(|| self.http.put(&url).json(&request), true)
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Runtimes jobs can ask for, by name.
+    pub async fn runtimes(&self) -> Result<Vec<RuntimeInfo>, Error> {
+        let url = format!("{}/runtimes", self.base_url);
+        let resp = self.send(|| self.http.get(&url), true).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// VMs running jobs, with the liveness their agent heartbeats give them.
+    pub async fn vms(&self) -> Result<Vec<VmInfo>, Error> {
+        let url = format!("{}/vms", self.base_url);
+        let mut vms = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let resp = self
+                .send(
+                    || {
+                        let request = self.http.get(&url);
+                        match &cursor {
+                            Some(cursor) => request.query(&[("cursor", cursor)]),
+                            None => request,
+                        }
+                    },
+                    true,
+                )
+                .await?;
+            // The backend lists VMs a page at a time, with the cursor of the next one.
+            cursor = resp
+                .headers()
+                .get(NEXT_CURSOR_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            vms.extend(resp.json::<Vec<VmInfo>>().await?);
+            if cursor.is_none() {
+                return Ok(vms);
+            }
+        }
+    }
+
+    /// Grows or shrinks the memory of the VM of the running job `id` to `memory_mb` MiB.
+    pub async fn update_vm(&self, id: &str, memory_mb: usize) -> Result<UpdateVmResponse, Error> {
+        let url = format!("{}/vms/{}", self.base_url, id);
+        let request = UpdateVmRequest { memory_mb };
+        // The request sets a size rather than changing it, so retrying is harmless.
+        let resp = self
+            .send(|| self.http.patch(&url).json(&request), true)
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Opens a channel between the VMs of the running jobs `from_vm` and `to_vm`: the
+    /// code of each job finds a socket at the returned `path`, its end of the stream.
+    pub async fn open_channel(&self, from_vm: &str, to_vm: &str) -> Result<ChannelResponse, Error> {
+        let url = format!("{}/channels", self.base_url);
+        let request = ChannelRequest {
+            from_vm: from_vm.to_string(),
+            to_vm: to_vm.to_string(),
+        };
+        // A retry would open a second channel.
+        let resp = self
+            .send(|| self.http.post(&url).json(&request), false)
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Captures the traffic of the VM of the running job `id` as a pcap file, until
+    /// `max_bytes` are received, `duration_secs` pass or the job finishes. `None`
+    /// leaves a bound to the backend's maximum.
+    pub async fn capture_packets(
+        &self,
+        id: &str,
+        max_bytes: Option<u64>,
+        duration_secs: Option<u64>,
+    ) -> Result<PacketCapture, Error> {
+        let url = format!("{}/vms/{}/pcap", self.base_url, id);
+        let mut query = Vec::new();
+        if let Some(max_bytes) = max_bytes {
+            query.push(("max_bytes", max_bytes));
+        }
+        if let Some(duration_secs) = duration_secs {
+            query.push(("duration_secs", duration_secs));
+        }
+        let resp = self
+            .send(
+                || {
+                    self.http
+                        .get(&url)
+                        .query(&query)
+                        .timeout(LOG_FOLLOW_TIMEOUT)
+                },
+                true,
+            )
+            .await?;
+        Ok(PacketCapture { resp })
+    }
+
+    /// Entries and hit/miss counters of the result cache.
+    pub async fn cache_stats(&self) -> Result<CacheStats, Error> {
+        let url = format!("{}/cache", self.base_url);
+        let resp = self.send(|| self.http.get(&url), true).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Latencies, errors, starts and concurrency of the functions of the namespace
+    /// over time, or of `function` alone.
+    pub async fn dashboard(
+        &self,
+        function: Option<&str>,
+        query: &DashboardQuery,
+    ) -> Result<Dashboard, Error> {
+        let url = match function {
+            Some(name) => format!("{}/dashboard/functions/{}", self.base_url, name),
+            None => format!("{}/dashboard/functions", self.base_url),
+        };
+        let resp = self.send(|| self.http.get(&url).query(query), true).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Core the code of job `id` dumped when it crashed, see [`StatusResponse::core_dump`].
+    pub async fn core_dump(&self, id: &str) -> Result<Bytes, Error> {
+        let url = format!("{}/core/{}", self.base_url, id);
+        let resp = self.send(|| self.http.get(&url), true).await?;
+        Ok(resp.bytes().await?)
+    }
+
+    /// How the finished jobs `a` and `b` differ, to tell why runs of the same code
+    /// ended differently.
+    pub async fn diff(&self, a: &str, b: &str) -> Result<ExecutionDiff, Error> {
+        let url = format!("{}/executions/{}/diff/{}", self.base_url, a, b);
+        let resp = self.send(|| self.http.get(&url), true).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Logs of a job so far: output of its code and console of its VM.
+    pub async fn logs(&self, id: &str) -> Result<Vec<LogLine>, Error> {
+        let url = format!("{}/logs/{}", self.base_url, id);
+        let resp = self.send(|| self.http.get(&url), true).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Logs of a job so far, then new lines as they arrive until the job finishes.
+    pub async fn follow_logs(&self, id: &str) -> Result<LogFollower, Error> {
+        let url = format!("{}/logs/{}", self.base_url, id);
+        let resp = self
+            .send(
+                || {
+                    self.http
+                        .get(&url)
+                        .query(&[("follow", "true")])
+                        .timeout(LOG_FOLLOW_TIMEOUT)
+                },
+                true,
+            )
+            .await?;
+        Ok(LogFollower {
+            resp,
+            buf: Vec::new(),
+            done: false,
+        })
+    }
+
+    /// Follows a job until it finishes, see [`LogStream`].
+    pub fn stream_logs(&self, id: &str) -> LogStream<'_> {
+        LogStream {
+            client: self,
+            id: id.to_string(),
+            status: None,
+            pending: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    /// Attaches to the serial console of the VM running job `id`, see [`Console`].
+    pub async fn console(&self, id: &str) -> Result<Console, Error> {
+        let base_url = match self.base_url.split_once("://") {
+            Some(("https", rest)) => format!("wss://{}", rest),
+            Some((_, rest)) => format!("ws://{}", rest),
+            None => format!("ws://{}", self.base_url),
+        };
+        let mut request = format!("{}/console/{}", base_url, id).into_client_request()?;
+        if let Some(authorization) = &self.authorization {
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, authorization.clone());
+        }
+        if let Some(namespace) = &self.namespace {
+            request
+                .headers_mut()
+                .insert(NAMESPACE_HEADER, namespace.clone());
+        }
+        let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+        Ok(Console { socket })
+    }
+
+    /// Sends the request built by `request`, retrying according to the policy.
+    /// Error statuses are turned into [`Error::Api`].
+    async fn send(
+        &self,
+        request: impl Fn() -> reqwest::RequestBuilder,
+        idempotent: bool,
+    ) -> Result<reqwest::Response, Error> {
+        let mut attempt = 1;
+        loop {
+            let retry = attempt < self.retry.max_attempts;
+            match request().send().await {
+                Ok(resp) if retry && is_retryable(resp.status()) => {}
+                Ok(resp) => return check_status(resp).await,
+                Err(e) if retry && (e.is_connect() || (idempotent && e.is_timeout())) => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            tokio::time::sleep(self.retry.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+async fn check_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    let error = resp
+        .json::<ErrorResponse>()
+        .await
+        .unwrap_or_else(|_| ErrorResponse::new(format!("HTTP {}", status)));
+    Err(Error::Api { status, error })
+}
+
+/// Live logs of a job, returned by [`Client::follow_logs`].
+#[derive(Debug)]
+pub struct LogFollower {
+    resp: reqwest::Response,
+    /// Received bytes not yet making up a whole line.
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl LogFollower {
+    /// Next log line, or `None` once the job finished.
+    pub async fn next(&mut self) -> Option<Result<LogLine, Error>> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                if line[..end].iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                return Some(serde_json::from_slice(&line[..end]).map_err(Error::Decode));
+            }
+            if self.done {
+                if self.buf.iter().all(u8::is_ascii_whitespace) {
+                    return None;
+                }
+                let line = std::mem::take(&mut self.buf);
+                return Some(serde_json::from_slice(&line).map_err(Error::Decode));
+            }
+
+            match self.resp.chunk().await {
+                Ok(Some(chunk)) => self.buf.extend_from_slice(&chunk),
+                Ok(None) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    self.buf.clear();
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+    }
+}
+
+/// Packet capture of a VM, see [`Client::capture_packets`].
+pub struct PacketCapture {
+    resp: reqwest::Response,
+}
+
+impl PacketCapture {
+    /// Next bytes of the pcap file, or `None` once the capture ended. Written one
+    /// after the other, they make up a file Wireshark and tcpdump read.
+    pub async fn next(&mut self) -> Option<Result<Bytes, Error>> {
+        self.resp.chunk().await.map_err(Error::from).transpose()
+    }
+}
+
+/// Something that happened to a followed job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogEvent {
+    /// The job moved to a new status.
+    Status(JobStatus),
+    Stdout(String),
+    Stderr(String),
+    /// The job exited with this code.
+    Exit(i32),
+}
+
+/// Events of a job, returned by [`Client::stream_logs`].
+///
+/// The stream polls the job status and emits its output once the job finished.
+/// [`Client::follow_logs`] streams output line by line instead.
+pub struct LogStream<'a> {
+    client: &'a Client,
+    id: String,
+    status: Option<JobStatus>,
+    pending: VecDeque<LogEvent>,
+    finished: bool,
+}
+
+impl LogStream<'_> {
+    /// Next event, or `None` once the job finished and every event was returned.
+    pub async fn next(&mut self) -> Option<Result<LogEvent, Error>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.finished {
+                return None;
+            }
+
+            if self.status.is_some() {
+                tokio::time::sleep(self.client.poll_interval).await;
+            }
+            let status = match self.client.status(&self.id).await {
+                Ok(status) => status,
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+            };
+
+            if self.status != Some(status.status) {
+                self.status = Some(status.status);
+                self.pending.push_back(LogEvent::Status(status.status));
+            }
+            if status.status.is_terminal() {
+                self.finished = true;
+                self.pending.extend(
+                    status
+                        .stdout
+                        .filter(|out| !out.is_empty())
+                        .map(LogEvent::Stdout),
+                );
+                self.pending.extend(
+                    status
+                        .stderr
+                        .filter(|err| !err.is_empty())
+                        .map(LogEvent::Stderr),
+                );
+                self.pending.extend(status.exit_code.map(LogEvent::Exit));
+            }
+        }
+    }
+}
+
+/// An attached serial console, returned by [`Client::console`].
+///
+/// Output starts with the recent console history, then follows the guest live.
+/// Input is typed into the guest serial port as is, so terminal escapes such as
+/// `Ctrl-C` reach the guest rather than the client.
+#[derive(Debug)]
+pub struct Console {
+    socket: tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >,
+}
+
+impl Console {
+    /// Types `input` into the guest.
+    pub async fn send(&mut self, input: impl Into<Bytes>) -> Result<(), Error> {
+        self.socket.send(Message::Binary(input.into())).await?;
+        Ok(())
+    }
+
+    /// Next chunk of console output, or `None` once the VM stopped.
+    pub async fn recv(&mut self) -> Option<Result<Bytes, Error>> {
+        loop {
+            match self.socket.next().await? {
+                Ok(Message::Binary(data)) => return Some(Ok(data)),
+                Ok(Message::Text(text)) => return Some(Ok(Bytes::from(text.as_str().to_owned()))),
+                Ok(Message::Close(_)) => return None,
+                Ok(_) => {}
+                Err(tungstenite::Error::ConnectionClosed) => return None,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+
+    /// Detaches from the console. The VM keeps running.
+    pub async fn close(mut self) -> Result<(), Error> {
+        match self.socket.close(None).await {
+            Ok(()) | Err(tungstenite::Error::ConnectionClosed) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::ws::{Message as WsMessage, WebSocketUpgrade};
+    use axum::extract::{Path, Query, State};
+    use axum::http::HeaderMap as AxumHeaderMap;
+    use axum::response::IntoResponse;
+    use axum::routing::{get, post, put};
+    use axum::{Json, Router};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[derive(Default)]
+    struct Mock {
+        run_calls: AtomicU32,
+        status_calls: AtomicU32,
+    }
+
+    async fn run(State(mock): State<Arc<Mock>>, headers: AxumHeaderMap) -> impl IntoResponse {
+        assert_eq!(headers["authorization"], "Bearer secret");
+        assert_eq!(headers[NAMESPACE_HEADER], "team-a");
+        // The first submission hits a saturated backend.
+        if mock.run_calls.fetch_add(1, Ordering::SeqCst) == 0 {
+            return axum::http::StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+        (
+            axum::http::StatusCode::ACCEPTED,
+            Json(RunResponse {
+                id: "job-1".to_string(),
+                cached: false,
+            }),
+        )
+            .into_response()
+    }
+
+    async fn status(State(mock): State<Arc<Mock>>) -> Json<StatusResponse> {
+        let done = mock.status_calls.fetch_add(1, Ordering::SeqCst) >= 1;
+        Json(StatusResponse {
+            id: "job-1".to_string(),
+            status: if done {
+                JobStatus::Done
+            } else {
+                JobStatus::Running
+            },
+            exit_code: done.then_some(0),
+            stdout: done.then(|| "hello\n".to_string()),
+            stderr: done.then(String::new),
+            isolation: done.then_some(Isolation::Vm),
+            traffic: None,
+            oom_killed: false,
+            signal: None,
+            core_dump: false,
+        })
+    }
+
+    /// Echoes input in upper case after a boot banner.
+    async fn console(Path(id): Path<String>, ws: WebSocketUpgrade) -> axum::response::Response {
+        if id != "job-1" {
+            return (
+                axum::http::StatusCode::CONFLICT,
+                Json(ErrorResponse::new(format!("Job {} has no running VM", id))),
+            )
+                .into_response();
+        }
+        ws.on_upgrade(|mut socket| async move {
+            socket
+                .send(WsMessage::Binary("boot\n".into()))
+                .await
+                .unwrap();
+            while let Some(Ok(WsMessage::Binary(input))) = socket.recv().await {
+                let output = input.to_ascii_uppercase();
+                socket.send(WsMessage::Binary(output.into())).await.unwrap();
+            }
+        })
+    }
+
+    async fn deploy_function(
+        Path(name): Path<String>,
+        Json(request): Json<DeployRequest>,
+    ) -> impl IntoResponse {
+        (
+            axum::http::StatusCode::CREATED,
+            Json(FunctionInfo {
+                name,
+                language: request.language,
+                artifact: request.artifact,
+                entrypoint: request.entrypoint,
+                version: 1,
+                created_at: 0,
+                updated_at: 0,
+            }),
+        )
+    }
+
+    /// Renames every function, and reports what it was asked for in a warning.
+    async fn import(
+        Query(query): Query<std::collections::HashMap<String, String>>,
+        Json(bundle): Json<Bundle>,
+    ) -> Json<ImportReport> {
+        Json(ImportReport {
+            functions: bundle
+                .functions
+                .into_iter()
+                .map(|function| ImportedFunction {
+                    name: function.name.clone(),
+                    outcome: ImportOutcome::Renamed,
+                    function: Some(FunctionInfo {
+                        name: format!("{}-2", function.name),
+                        ..function
+                    }),
+                    error: None,
+                })
+                .collect(),
+            warnings: vec![format!("on_conflict={}", query["on_conflict"])],
+        })
+    }
+
+    fn vm(id: &str) -> VmInfo {
+        VmInfo {
+            id: id.to_string(),
+            vm_id: id.to_string(),
+            ip: "10.39.1.2".to_string(),
+            liveness: VmLiveness::Running,
+            last_heartbeat_ms: 0,
+            traffic: None,
+        }
+    }
+
+    /// Two pages of one VM each.
+    async fn vms(
+        Query(query): Query<std::collections::HashMap<String, String>>,
+    ) -> axum::response::Response {
+        match query.get("cursor").map(String::as_str) {
+            None => ([(NEXT_CURSOR_HEADER, "page-2")], Json(vec![vm("job-1")])).into_response(),
+            Some("page-2") => Json(vec![vm("job-2")]).into_response(),
+            Some(_) => axum::http::StatusCode::UNPROCESSABLE_ENTITY.into_response(),
+        }
+    }
+
+    fn log_lines() -> Vec<LogLine> {
+        vec![
+            LogLine {
+                timestamp_ms: 1_000,
+                source: LogSource::Kernel,
+                line: "Linux version 6.1".to_string(),
+            },
+            LogLine {
+                timestamp_ms: 2_000,
+                source: LogSource::Stdout,
+                line: "hello".to_string(),
+            },
+        ]
+    }
+
+    async fn logs(
+        Query(query): Query<std::collections::HashMap<String, String>>,
+    ) -> axum::response::Response {
+        if query.get("follow").map(String::as_str) != Some("true") {
+            return Json(log_lines()).into_response();
+        }
+        // Chunks cut in the middle of a line, as a network would.
+        let body: String = log_lines()
+            .iter()
+            .map(|line| serde_json::to_string(line).unwrap() + "\n")
+            .collect();
+        let (first, second) = body.split_at(10);
+        let chunks = futures_util::stream::iter([first.to_string(), second.to_string()])
+            .map(Ok::<_, std::io::Error>);
+        axum::body::Body::from_stream(chunks).into_response()
+    }
+
+    async fn spawn_backend() -> Client {
+        let api = Router::new()
+            .route("/run", post(run))
+            .route("/status/{id}", get(status))
+            .route("/console/{id}", get(console))
+            .route("/logs/{id}", get(logs))
+            .route("/functions/{name}", put(deploy_function))
+            .route("/vms", get(vms))
+            .route("/import", post(import))
+            .with_state(Arc::new(Mock::default()));
+        let app = Router::new().nest("/v1", api);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        Client::builder(format!("http://{}/", addr))
+            .api_key("secret")
+            .namespace("team-a")
+            .poll_interval(Duration::from_millis(10))
+            .retry(RetryPolicy {
+                initial_backoff: Duration::from_millis(10),
+                ..RetryPolicy::default()
+            })
+            .build()
+            .unwrap()
+    }
+
+    fn spec() -> FunctionSpec {
+        FunctionSpec {
+            language: "python".to_string(),
+            code: Some("print('hello')".to_string()),
+            artifact: None,
+            entrypoint: None,
+            deterministic: false,
+            cache: None,
+            isolation: Isolation::Vm,
+            resources: None,
+            stdin: None,
+            profile: None,
+            network: Network::Open,
+            identity_audience: None,
+        }
+    }
+
+    #[test]
+    fn test_backoff_is_exponential_and_capped() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_millis(150));
+        assert_eq!(policy.backoff(2), Duration::from_millis(300));
+        assert_eq!(policy.backoff(5), Duration::from_secs(2));
+        assert_eq!(policy.backoff(64), Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_execute_retries_and_waits_for_result() {
+        let client = spawn_backend().await;
+
+        let result = client.execute(&spec()).await.unwrap();
+        assert_eq!(result.status, JobStatus::Done);
+        assert_eq!(result.stdout.as_deref(), Some("hello\n"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_logs() {
+        let client = spawn_backend().await;
+        let run = client.submit(&spec()).await.unwrap();
+
+        let mut events = Vec::new();
+        let mut stream = client.stream_logs(&run.id);
+        while let Some(event) = stream.next().await {
+            events.push(event.unwrap());
+        }
+        assert_eq!(
+            events,
+            vec![
+                LogEvent::Status(JobStatus::Running),
+                LogEvent::Status(JobStatus::Done),
+                LogEvent::Stdout("hello\n".to_string()),
+                LogEvent::Exit(0),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_logs() {
+        let client = spawn_backend().await;
+        assert_eq!(client.logs("job-1").await.unwrap(), log_lines());
+
+        let mut follower = client.follow_logs("job-1").await.unwrap();
+        let mut lines = Vec::new();
+        while let Some(line) = follower.next().await {
+            lines.push(line.unwrap());
+        }
+        assert_eq!(lines, log_lines());
+    }
+
+    #[tokio::test]
+    async fn test_console() {
+        let client = spawn_backend().await;
+
+        let mut console = client.console("job-1").await.unwrap();
+        assert_eq!(console.recv().await.unwrap().unwrap(), "boot\n");
+        console.send("ls\r").await.unwrap();
+        assert_eq!(console.recv().await.unwrap().unwrap(), "LS\r");
+        console.close().await.unwrap();
+
+        let err = client.console("job-2").await.unwrap_err();
+        assert!(
+            matches!(err, Error::Api { status, error } if status == StatusCode::CONFLICT && error.error == "Job job-2 has no running VM")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deploy_function() {
+        let client = spawn_backend().await;
+        let request = DeployRequest {
+            language: "node".to_string(),
+            artifact: "ab".repeat(32),
+            entrypoint: Some("index.js".to_string()),
+        };
+
+        let function = client.deploy_function("hello", &request).await.unwrap();
+        assert_eq!(function.name, "hello");
+        assert_eq!(function.entrypoint.as_deref(), Some("index.js"));
+    }
+
+    #[tokio::test]
+    async fn test_import() {
+        let client = spawn_backend().await;
+        let bundle = Bundle {
+            format: 1,
+            exported_at: 1760000000,
+            namespace: "default".to_string(),
+            functions: vec![FunctionInfo {
+                name: "hello".to_string(),
+                language: "node".to_string(),
+                artifact: "ab".repeat(32),
+                entrypoint: None,
+                version: 4,
+                created_at: 0,
+                updated_at: 0,
+            }],
+            runtimes: Vec::new(),
+            artifacts: Default::default(),
+        };
+
+        let report = client.import(&bundle, OnConflict::Rename).await.unwrap();
+        assert_eq!(report.warnings, ["on_conflict=rename"]);
+        let function = report.functions[0].function.as_ref().unwrap();
+        assert_eq!((function.name.as_str(), function.version), ("hello-2", 4));
+    }
+
+    #[tokio::test]
+    async fn test_vms_follows_pages() {
+        let client = spawn_backend().await;
+        let ids: Vec<String> = client
+            .vms()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|vm| vm.id)
+            .collect();
+        assert_eq!(ids, ["job-1", "job-2"]);
+    }
+
+    #[tokio::test]
+    async fn test_error_status_is_reported() {
+        let client = spawn_backend().await;
+
+        let err = client.deploy("print(1)").await.unwrap_err();
+        assert!(matches!(err, Error::Api { status, .. } if status == StatusCode::NOT_FOUND));
+    }
+}
@@ -0,0 +1,226 @@
+//! Verification of the lifecycle events a backend POSTs to a webhook.
+//!
+//! With `EVENTS_WEBHOOK_SECRET` set, every attempt to deliver an event is
+//! signed: `x-cloude-signature` is `v1=` and the hex HMAC-SHA256 of
+//! `{id}.{timestamp}.{nonce}.{body}`, with the values of the
+//! `x-cloude-webhook-id`, `x-cloude-webhook-timestamp` and
+//! `x-cloude-webhook-nonce` headers. A [`Verifier`] checks the signature, that
+//! the timestamp is recent, and that the nonce was not seen before, so that a
+//! captured request cannot be replayed.
+//!
+//! ```no_run
+//! use cloude_client::webhook::Verifier;
+//!
+//! # fn handle(headers: &reqwest::header::HeaderMap, body: &[u8]) {
+//! let verifier = Verifier::new(b"my-secret".to_vec());
+//! match verifier.verify(headers, body) {
+//!     Ok(event) => println!("{} {:?}", event.kind, event.job_id),
+//!     Err(e) => eprintln!("Rejected webhook: {}", e),
+//! }
+//! # }
+//! ```
+//!
+//! Retries of a delivery keep its id: receivers that must act once per event
+//! can skip the ids they already handled.
+
+use cloude_types::LifecycleEvent;
+use hmac::{Hmac, Mac};
+use reqwest::header::HeaderMap;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const ID_HEADER: &str = "x-cloude-webhook-id";
+pub const TIMESTAMP_HEADER: &str = "x-cloude-webhook-timestamp";
+pub const NONCE_HEADER: &str = "x-cloude-webhook-nonce";
+pub const SIGNATURE_HEADER: &str = "x-cloude-signature";
+
+/// How far the timestamp of a request may be from the clock of the receiver.
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(300);
+
+/// Why a request was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    MissingHeader(&'static str),
+    InvalidTimestamp,
+    /// The timestamp is further than the tolerance from now, by this many seconds.
+    Expired(u64),
+    InvalidSignature,
+    /// The nonce was already seen within the tolerance.
+    Replayed,
+    /// The body is not a lifecycle event.
+    Decode(String),
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::MissingHeader(name) => write!(f, "Missing {} header", name),
+            VerifyError::InvalidTimestamp => write!(f, "Invalid {} header", TIMESTAMP_HEADER),
+            VerifyError::Expired(secs) => {
+                write!(f, "Request timestamp is {}s too far from now", secs)
+            }
+            VerifyError::InvalidSignature => write!(f, "Signature does not match"),
+            VerifyError::Replayed => write!(f, "Request was already received"),
+            VerifyError::Decode(e) => write!(f, "Invalid event: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Checks the requests of a backend signing with `secret`, and remembers
+/// their nonces for as long as their timestamp is within the tolerance.
+pub struct Verifier {
+    secret: Vec<u8>,
+    tolerance: Duration,
+    /// Nonces seen, with their timestamp.
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+impl Verifier {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+            tolerance: DEFAULT_TOLERANCE,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accepts timestamps up to `tolerance` away from now, [`DEFAULT_TOLERANCE`] by default.
+    pub fn tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Checks a request with `headers` and `body`, and returns its event.
+    pub fn verify(&self, headers: &HeaderMap, body: &[u8]) -> Result<LifecycleEvent, VerifyError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.verify_at(headers, body, now)
+    }
+
+    fn verify_at(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+        now: u64,
+    ) -> Result<LifecycleEvent, VerifyError> {
+        let header = |name: &'static str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .ok_or(VerifyError::MissingHeader(name))
+        };
+        let id = header(ID_HEADER)?;
+        let nonce = header(NONCE_HEADER)?;
+        let signature = header(SIGNATURE_HEADER)?;
+        let timestamp: u64 = header(TIMESTAMP_HEADER)?
+            .parse()
+            .map_err(|_| VerifyError::InvalidTimestamp)?;
+
+        let off = now.abs_diff(timestamp);
+        if off > self.tolerance.as_secs() {
+            return Err(VerifyError::Expired(off - self.tolerance.as_secs()));
+        }
+        let expected = signature
+            .strip_prefix("v1=")
+            .and_then(|hex| hex::decode(hex).ok())
+            .ok_or(VerifyError::InvalidSignature)?;
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(format!("{id}.{timestamp}.{nonce}.").as_bytes());
+        mac.update(body);
+        mac.verify_slice(&expected)
+            .map_err(|_| VerifyError::InvalidSignature)?;
+
+        // Only signed requests are remembered, so that forged ones cannot fill the memory.
+        {
+            let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+            let tolerance = self.tolerance.as_secs();
+            seen.retain(|_, &mut at| now.abs_diff(at) <= tolerance);
+            if seen.insert(nonce.to_string(), timestamp).is_some() {
+                return Err(VerifyError::Replayed);
+            }
+        }
+        serde_json::from_slice(body).map_err(|e| VerifyError::Decode(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    const BODY: &[u8] =
+        br#"{"timestamp_ms":1760000000123,"kind":"execution.finished","job_id":"job-1"}"#;
+
+    fn headers(timestamp: u64, nonce: &str, signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ID_HEADER, HeaderValue::from_static("delivery-1"));
+        headers.insert(TIMESTAMP_HEADER, timestamp.into());
+        headers.insert(NONCE_HEADER, HeaderValue::from_str(nonce).unwrap());
+        headers.insert(SIGNATURE_HEADER, HeaderValue::from_str(signature).unwrap());
+        headers
+    }
+
+    fn sign(timestamp: u64, nonce: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(format!("delivery-1.{timestamp}.{nonce}.").as_bytes());
+        mac.update(body);
+        format!("v1={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn test_signature_of_the_backend_is_accepted() {
+        // Same vector as the signature of the backend.
+        let signed = headers(
+            1760000000,
+            "nonce-1",
+            "v1=912c4190f0b390964f5c2ba81b8daf68683f71eb1fe692cb0870c997cc0dc213",
+        );
+        let verifier = Verifier::new(b"secret".to_vec());
+        // Signed right, but not an event.
+        assert!(matches!(
+            verifier.verify_at(&signed, b"{}", 1760000000),
+            Err(VerifyError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify() {
+        let verifier = Verifier::new(b"secret".to_vec());
+        let now = 1760000000;
+        let signed = headers(now, "nonce-1", &sign(now, "nonce-1", BODY));
+
+        let event = verifier.verify_at(&signed, BODY, now + 10).unwrap();
+        assert_eq!(event.job_id.as_deref(), Some("job-1"));
+        // The same request again is a replay.
+        assert_eq!(
+            verifier.verify_at(&signed, BODY, now + 20),
+            Err(VerifyError::Replayed)
+        );
+
+        let tampered =
+            br#"{"timestamp_ms":1760000000123,"kind":"execution.failed","job_id":"job-1"}"#;
+        let retry = headers(now, "nonce-2", &sign(now, "nonce-2", BODY));
+        assert_eq!(
+            verifier.verify_at(&retry, tampered, now),
+            Err(VerifyError::InvalidSignature)
+        );
+        assert_eq!(
+            verifier.verify_at(&retry, BODY, now + 400),
+            Err(VerifyError::Expired(100))
+        );
+        let mut unsigned = retry.clone();
+        unsigned.remove(SIGNATURE_HEADER);
+        assert_eq!(
+            verifier.verify_at(&unsigned, BODY, now),
+            Err(VerifyError::MissingHeader(SIGNATURE_HEADER))
+        );
+        assert!(verifier.verify_at(&retry, BODY, now).is_ok());
+    }
+}
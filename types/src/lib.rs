@@ -0,0 +1,1477 @@
+//! Request and response types exchanged between the CLI, the backend and the guest agent.
+//!
+//! Every component serializes these structs instead of its own copy, so field
+//! names and enum values cannot drift apart.
+
+use serde::{Deserialize, Serialize};
+
+// ── CLI → backend ───────────────────────────────────────────────────
+
+/// Code to run: a runtime and its source, inline or as an uploaded artifact.
+/// Body of `POST /run`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FunctionSpec {
+    pub language: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// Id of a previously uploaded artifact, used instead of inline `code`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact: Option<String>,
+    /// File to run when `artifact` is a gzipped tar of a directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<String>,
+    /// Run without network, with a fixed clock and seeds, for reproducible output.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deterministic: bool,
+    /// Reuse the result of an identical earlier run, and keep this one for later runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheControl>,
+    /// What the VM of the job may be shared with.
+    #[serde(default, skip_serializing_if = "Isolation::is_vm")]
+    pub isolation: Isolation,
+    /// VM shape of the job; what is left out comes from the runtime's profile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<Resources>,
+    /// Standard input of the code; without it, the code reads an empty input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<String>,
+    /// Sandbox profile of the backend to run the job with: its isolation, network,
+    /// resources and runtimes. The backend's `default` profile, if any, when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// What the code reaches on the network.
+    #[serde(default, skip_serializing_if = "Network::is_open")]
+    pub network: Network,
+    /// `aud` of the identity tokens of the job: the service the code presents
+    /// them to. Tokens without one are meant for anyone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_audience: Option<String>,
+}
+
+/// vCPUs and memory a job asks for, or a runtime gives its jobs by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct Resources {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vcpus: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<usize>,
+}
+
+/// Isolation level of a job.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Isolation {
+    /// A fresh VM, destroyed once the job ends.
+    #[default]
+    Vm,
+    /// A warm VM reused across the jobs of the same tenant, booted when none is idle.
+    PooledVm,
+    /// A host process, on backends without VMs. Only for trusted code.
+    Process,
+}
+
+impl Isolation {
+    pub fn is_vm(&self) -> bool {
+        *self == Isolation::Vm
+    }
+}
+
+impl std::fmt::Display for Isolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Isolation::Vm => "vm",
+            Isolation::PooledVm => "pooled-vm",
+            Isolation::Process => "process",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What the code of a job reaches on the network.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Network {
+    /// Anything, straight from the VM.
+    #[default]
+    Open,
+    /// Only the domains the backend allows its tenant, through its egress proxy.
+    EgressRestricted,
+}
+
+impl Network {
+    pub fn is_open(&self) -> bool {
+        *self == Network::Open
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Network::Open => "open",
+            Network::EgressRestricted => "egress-restricted",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How a run uses the result cache, see `FunctionSpec::cache`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CacheControl {
+    /// Only reuse a result stored less than this long ago, and keep this one
+    /// this long. The backend caps it, and uses its maximum when it is not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+    /// Reuse an earlier result, but do not keep the result of this run.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub no_store: bool,
+}
+
+/// Response of `POST /run`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RunResponse {
+    pub id: String,
+    /// The job is already done: its result is the one of an identical earlier run.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub cached: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Error,
+    /// The backend restarted while the job was pending or running.
+    Interrupted,
+}
+
+impl JobStatus {
+    /// Whether the job reached a final state.
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Done | JobStatus::Error | JobStatus::Interrupted
+        )
+    }
+}
+
+impl std::fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Error => "error",
+            JobStatus::Interrupted => "interrupted",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Response of `GET /status/{id}`. Output fields are `null` until the job finished.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StatusResponse {
+    pub id: String,
+    pub status: JobStatus,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub stdout: Option<String>,
+    #[serde(default)]
+    pub stderr: Option<String>,
+    /// Isolation level the job ran with, `None` when it was answered from the result cache.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isolation: Option<Isolation>,
+    /// Network traffic of the job's VM while it ran the job, once the job finished.
+    /// `None` without a VM, or when the backend cannot count it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic: Option<TrafficStats>,
+    /// The code went over the memory of the job and was killed, its exit code
+    /// is that of a killed process.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub oom_killed: bool,
+    /// Signal that killed the code, like `SIGSEGV`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    /// The code dumped a core as it crashed, downloaded with `GET /core/{id}`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub core_dump: bool,
+}
+
+/// A job, listed by `GET /executions`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionSummary {
+    pub id: String,
+    pub status: JobStatus,
+    pub language: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isolation: Option<Isolation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// Caller the job runs for, see `X-Cloude-User`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// From the submission of the job to its result, once it finished.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+/// Network traffic of a VM, counted on its TAP device. `rx` is what the VM
+/// received, `tx` what it sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+}
+
+impl TrafficStats {
+    /// Traffic since `earlier`, a previous reading of the same counters.
+    pub fn since(&self, earlier: &TrafficStats) -> TrafficStats {
+        TrafficStats {
+            rx_bytes: self.rx_bytes.saturating_sub(earlier.rx_bytes),
+            rx_packets: self.rx_packets.saturating_sub(earlier.rx_packets),
+            tx_bytes: self.tx_bytes.saturating_sub(earlier.tx_bytes),
+            tx_packets: self.tx_packets.saturating_sub(earlier.tx_packets),
+        }
+    }
+}
+
+/// Response of `GET /executions/{a}/diff/{b}`: how two executions differ, for
+/// runs of the same code that should have behaved the same.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionDiff {
+    pub a: String,
+    pub b: String,
+    /// Both ran the same code in the same runtime; `false` when the code of one
+    /// is unknown, like for jobs answered from the result cache.
+    pub same_code: bool,
+    /// Same status, exit code, signal, standard output and standard error.
+    pub same_result: bool,
+    pub status: Change<JobStatus>,
+    pub exit_code: Change<Option<i32>>,
+    pub signal: Change<Option<String>>,
+    /// Lines of the standard output of `a` that `b` lacks, and those it added.
+    pub stdout: Vec<DiffLine>,
+    pub stderr: Vec<DiffLine>,
+    /// Digest of the runtime image each ran in, `None` when it ran in no VM.
+    pub image_digest: Change<Option<String>>,
+    pub usage: Change<ResourceUsage>,
+    /// Usage of `b` minus that of `a`, for what both have.
+    pub usage_delta: UsageDelta,
+}
+
+/// A field of two executions, `a` then `b`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change<T> {
+    pub a: T,
+    pub b: T,
+}
+
+impl<T: PartialEq> Change<T> {
+    pub fn changed(&self) -> bool {
+        self.a != self.b
+    }
+}
+
+/// A line only one of two outputs has.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiffLine {
+    pub op: DiffOp,
+    /// Number of the line, from 1, in the output of `a` for removed lines and
+    /// in that of `b` for added ones.
+    pub line: usize,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffOp {
+    /// Only in `a`.
+    Removed,
+    /// Only in `b`.
+    Added,
+}
+
+/// What an execution used, as far as the backend knows.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vcpus: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<usize>,
+    /// From the submission of the job to its result.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic: Option<TrafficStats>,
+}
+
+/// Difference of two [`ResourceUsage`], `None` where one of them is unknown.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageDelta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rx_bytes: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_bytes: Option<i64>,
+}
+
+/// Where a log line of a job comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogSource {
+    Stdout,
+    Stderr,
+    /// Serial console of the VM: kernel messages and init output.
+    Kernel,
+    /// Requests of the code through the egress proxy, and whether they were allowed.
+    Egress,
+}
+
+impl std::fmt::Display for LogSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            LogSource::Stdout => "stdout",
+            LogSource::Stderr => "stderr",
+            LogSource::Kernel => "kernel",
+            LogSource::Egress => "egress",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One line of the logs of a job, returned by `GET /logs/{id}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogLine {
+    /// When the backend received the line, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub source: LogSource,
+    pub line: String,
+}
+
+/// vCPUs the VM of a running job should have. Body of `PUT /vcpus/{id}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ResizeRequest {
+    pub vcpus: u8,
+}
+
+/// Response of `PUT /vcpus/{id}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResizeResponse {
+    /// vCPUs the VM has now.
+    pub vcpus: u8,
+    /// vCPUs the VM can grow to.
+    pub max_vcpus: u8,
+}
+
+/// Memory the VM of a running job should have, in MiB. Body of `PATCH /vms/{id}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateVmRequest {
+    pub memory_mb: usize,
+}
+
+/// Response of `PATCH /vms/{id}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateVmResponse {
+    /// Memory the guest was asked to have.
+    pub memory_mb: usize,
+    /// Memory the guest has so far; it catches up with `memory_mb` in the background.
+    pub plugged_memory_mb: usize,
+    /// Memory the VM can grow to.
+    pub max_memory_mb: usize,
+}
+
+/// Liveness of the VM of a running job, from the heartbeats of its agent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VmLiveness {
+    /// The agent answered its last heartbeat in time.
+    Running,
+    /// The agent missed its heartbeats for longer than the timeout.
+    Unresponsive,
+    /// The VMM stopped: the guest powered off or crashed.
+    Exited,
+}
+
+/// A VM running a job, listed by `GET /vms`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VmInfo {
+    /// Id of the job the VM runs, the `{id}` of `/vms/{id}`.
+    pub id: String,
+    /// Id of the VM itself, which a pooled VM keeps across jobs.
+    pub vm_id: String,
+    pub ip: String,
+    pub liveness: VmLiveness,
+    /// Milliseconds since the agent last answered a heartbeat.
+    pub last_heartbeat_ms: u64,
+    /// Network traffic of the VM during the job, as of the last heartbeat.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic: Option<TrafficStats>,
+}
+
+/// Directory of the guest where the code of a job finds the sockets of its channels.
+pub const CHANNEL_DIR: &str = "/run/cloude/channels";
+
+/// Two VMs of running jobs of one tenant to relay a byte stream between, by the
+/// `{id}` of `/vms/{id}`. Body of `POST /channels`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelRequest {
+    pub from_vm: String,
+    pub to_vm: String,
+}
+
+/// Response of `POST /channels`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChannelResponse {
+    pub id: String,
+    pub from_vm: String,
+    pub to_vm: String,
+    /// Unix socket the code connects to in either VM: what it writes comes out of
+    /// the socket in the other.
+    pub path: String,
+}
+
+/// What happened to a job or a VM, in a [`LifecycleEvent`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// A job was accepted and waits for its VM.
+    #[serde(rename = "execution.queued")]
+    ExecutionQueued,
+    /// A job is being run.
+    #[serde(rename = "execution.started")]
+    ExecutionStarted,
+    /// A job's code ran to its end, whatever its exit code.
+    #[serde(rename = "execution.finished")]
+    ExecutionFinished,
+    /// A job could not run its code to the end.
+    #[serde(rename = "execution.failed")]
+    ExecutionFailed,
+    #[serde(rename = "vm.created")]
+    VmCreated,
+    #[serde(rename = "vm.destroyed")]
+    VmDestroyed,
+}
+
+impl std::fmt::Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            EventKind::ExecutionQueued => "execution.queued",
+            EventKind::ExecutionStarted => "execution.started",
+            EventKind::ExecutionFinished => "execution.finished",
+            EventKind::ExecutionFailed => "execution.failed",
+            EventKind::VmCreated => "vm.created",
+            EventKind::VmDestroyed => "vm.destroyed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An event of `GET /events`, also POSTed to the events webhooks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LifecycleEvent {
+    /// When it happened, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub kind: EventKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vm_id: Option<String>,
+    /// Details such as `language=python` or `exit_code=0`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Result of an invocation of a deployed function, published to the message
+/// queues bound to the function.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionCompleted {
+    pub function: String,
+    pub version: u32,
+    pub job_id: String,
+    /// `done` or `error`.
+    pub status: JobStatus,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub stdout: Option<String>,
+    #[serde(default)]
+    pub stderr: Option<String>,
+    /// The output was too large for a message and is left out, read it with
+    /// `GET /status/{job_id}`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub result_omitted: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub oom_killed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    /// The code dumped a core, downloaded with `GET /core/{job_id}`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub core_dump: bool,
+    /// When the job finished, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+}
+
+/// Source of a [`TriggerEvent`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerKind {
+    /// A request to `/f/{name}`.
+    Http,
+    /// A message of a NATS subject.
+    Nats,
+    /// A file dropped in a watched directory.
+    Directory,
+    /// An object put under a watched prefix of the blob store.
+    Bucket,
+}
+
+impl std::fmt::Display for TriggerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TriggerKind::Http => "http",
+            TriggerKind::Nats => "nats",
+            TriggerKind::Directory => "directory",
+            TriggerKind::Bucket => "bucket",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What made a trigger run a function, given to the function as JSON on its
+/// standard input.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TriggerEvent {
+    pub trigger: TriggerKind,
+    /// The path after `/f/{name}`, the NATS subject, or the file or object key.
+    pub source: String,
+    /// HTTP method, for `http` triggers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// Query string, for `http` triggers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// Request headers, lowercased, for `http` triggers.
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub headers: std::collections::BTreeMap<String, String>,
+    /// Request body, message or file content, when it is UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Base64 of the body, when it is not UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_base64: Option<String>,
+}
+
+/// Delivery of lifecycle events to a webhook, listed by `GET /admin/webhooks`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WebhookStatus {
+    pub url: String,
+    /// Events the webhook accepted.
+    pub delivered: u64,
+    /// Events given up on after their last attempt.
+    pub failed: u64,
+    /// Attempts made again after a failed one.
+    pub retries: u64,
+    /// Events missed before they were queued, counted in `failed`. Unlike
+    /// the others, they are not in `failures`.
+    #[serde(default)]
+    pub dropped: u64,
+    /// Milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_delivered_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// The latest events given up on, the oldest first.
+    #[serde(default)]
+    pub failures: Vec<FailedDelivery>,
+}
+
+/// An event a webhook did not accept, even after retries.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FailedDelivery {
+    /// Id of the delivery, the `x-cloude-webhook-id` of all its attempts.
+    pub id: String,
+    pub event: LifecycleEvent,
+    pub attempts: u32,
+    /// Why the last attempt failed.
+    pub error: String,
+    /// Milliseconds since the Unix epoch.
+    pub failed_at_ms: u64,
+}
+
+/// What the holder of an API key may do.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Everything, API keys and the `/admin` endpoints included.
+    Admin,
+    /// Uploads artifacts and deploys functions, and reads.
+    Deployer,
+    /// Runs jobs and functions and works with their VMs, and reads.
+    Invoker,
+    /// Only reads.
+    Viewer,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Role::Admin => "admin",
+            Role::Deployer => "deployer",
+            Role::Invoker => "invoker",
+            Role::Viewer => "viewer",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Body of `POST /admin/keys`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CreateApiKeyRequest {
+    pub role: Role,
+    /// What the key is for, like `ci`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Sandbox profiles the jobs of the key may run with, any when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profiles: Option<Vec<String>>,
+    /// Tenant the key acts as, anonymous when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Namespace the key acts in, `default` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+/// Body of `PUT /admin/keys/{id}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AssignRoleRequest {
+    pub role: Role,
+    /// Sandbox profiles the jobs of the key may run with, any when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profiles: Option<Vec<String>>,
+}
+
+/// An API key, without its secret. Listed by `GET /admin/keys`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub role: Role,
+    /// Sandbox profiles the jobs of the key may run with, any when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profiles: Option<Vec<String>>,
+    /// Tenant the key acts as, anonymous when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Namespace the key acts in, `default` when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+/// Response of `POST /admin/keys`, the only one with the secret of the key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+    /// Sent as `Authorization: Bearer <key>`.
+    pub key: String,
+}
+
+/// Response of `GET /cache`: entries and counters of the result cache.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Results stored, expired ones included until they are evicted.
+    pub entries: usize,
+    /// Runs answered with a stored result.
+    pub hits: u64,
+    /// Runs that asked for a stored result and found none.
+    pub misses: u64,
+}
+
+/// Query of `GET /dashboard/functions`, in seconds since the Unix epoch. The
+/// backend takes the last hour, in buckets of a minute, for what is not set.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DashboardQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<u64>,
+    /// A multiple of 60.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket_secs: Option<u64>,
+}
+
+/// Response of `GET /dashboard/functions`: the invocations of the functions
+/// of the namespace that ended between `from` and `to`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Dashboard {
+    /// Start of the first bucket, `from` rounded down to `bucket_secs`.
+    pub from: u64,
+    pub to: u64,
+    pub bucket_secs: u64,
+    /// Functions with invocations in the range, by name.
+    pub functions: Vec<FunctionDashboard>,
+}
+
+/// The invocations of a function, over the whole range and bucket by bucket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionDashboard {
+    pub function: String,
+    pub total: DashboardBucket,
+    /// Every bucket of the range, empty ones included.
+    pub buckets: Vec<DashboardBucket>,
+}
+
+/// Aggregates of the invocations of a function that ended in a time bucket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DashboardBucket {
+    /// Seconds since the Unix epoch.
+    pub start: u64,
+    pub invocations: u64,
+    /// Invocations that failed to run or exited with another code than 0.
+    pub errors: u64,
+    /// `errors` over `invocations`, 0 without invocations.
+    pub error_rate: f64,
+    /// Percentiles of the time from submission to result, `None` without
+    /// invocations. Past the raw window of the backend they are approximate,
+    /// rounded up by at most a fifth.
+    #[serde(default)]
+    pub p50_ms: Option<u64>,
+    #[serde(default)]
+    pub p95_ms: Option<u64>,
+    #[serde(default)]
+    pub p99_ms: Option<u64>,
+    /// Invocations that booted a VM of their own.
+    pub cold_starts: u64,
+    /// Invocations that got an idle pooled VM, or joined the VM of another.
+    pub warm_starts: u64,
+    /// `cold_starts` over both kinds of starts, `None` without VM starts.
+    #[serde(default)]
+    pub cold_start_ratio: Option<f64>,
+    /// Most invocations running at once, counted as they start.
+    pub max_concurrency: u32,
+}
+
+/// A function to create, or to point to new code. Body of `PUT /functions/{name}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DeployRequest {
+    pub language: String,
+    /// Id of an uploaded artifact: a source file, or a gzipped tar of a whole directory.
+    pub artifact: String,
+    /// File to run inside a directory artifact, relative to its root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<String>,
+}
+
+/// A deployed function, returned by `PUT /functions/{name}` and `GET /functions/{name}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub language: String,
+    pub artifact: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<String>,
+    /// Starts at 1 and goes up every time the function is deployed with different settings.
+    pub version: u32,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// The functions a namespace should have. Body of `POST /apply`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ApplySpec {
+    #[serde(default)]
+    pub functions: Vec<DesiredFunction>,
+    /// Deletes the functions of the namespace the spec does not name.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub prune: bool,
+}
+
+/// A function of an [`ApplySpec`]: a [`DeployRequest`] with its name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DesiredFunction {
+    pub name: String,
+    pub language: String,
+    pub artifact: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ApplyAction {
+    Create,
+    /// Deploys other settings as the next version.
+    Update,
+    Delete,
+    Unchanged,
+}
+
+impl std::fmt::Display for ApplyAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ApplyAction::Create => "create",
+            ApplyAction::Update => "update",
+            ApplyAction::Delete => "delete",
+            ApplyAction::Unchanged => "unchanged",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A change `POST /apply` makes, or would make with `?dry_run=true`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlannedChange {
+    pub name: String,
+    pub action: ApplyAction,
+    /// The function before the change, absent for a creation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<FunctionInfo>,
+    /// The function after the change, absent for a deletion.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<FunctionInfo>,
+}
+
+/// Response of `POST /apply`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApplyResponse {
+    /// Whether the changes were made, false for a dry run.
+    pub applied: bool,
+    /// The functions of the spec in its order, then those deleted by name.
+    pub changes: Vec<PlannedChange>,
+}
+
+/// Functions run as a DAG, outputs feeding inputs. Body of `POST /pipelines`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineRequest {
+    pub stages: Vec<PipelineStage>,
+    /// Standard input of the stages that come after no other.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+}
+
+/// A stage of a [`PipelineRequest`], running a deployed function.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineStage {
+    /// Unique within the pipeline.
+    pub name: String,
+    pub function: String,
+    /// Stages whose outputs, in this order, make up the standard input of this one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+}
+
+/// Response of `POST /pipelines`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PipelineResponse {
+    pub id: String,
+}
+
+/// Where a stage of a pipeline is.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StageStatus {
+    Pending,
+    Running,
+    Done,
+    Error,
+    /// A stage it comes after failed.
+    Skipped,
+}
+
+impl std::fmt::Display for StageStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            StageStatus::Pending => "pending",
+            StageStatus::Running => "running",
+            StageStatus::Done => "done",
+            StageStatus::Error => "error",
+            StageStatus::Skipped => "skipped",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A stage of a pipeline, as `GET /pipelines/{id}` reports it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StageResult {
+    pub name: String,
+    pub function: String,
+    pub status: StageStatus,
+    /// Job running the function, see `GET /status/{id}`, once started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// Size of the output kept for `GET /pipelines/{id}/stages/{name}/output`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_bytes: Option<u64>,
+    /// Why the stage failed, when its job does not tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Response of `GET /pipelines/{id}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PipelineStatus {
+    pub id: String,
+    /// `done` once every stage is, `error` once a stage failed and the others stopped.
+    pub status: JobStatus,
+    /// In the order of the request.
+    pub stages: Vec<StageResult>,
+}
+
+/// Version of the format of [`Bundle`]s this build writes and reads.
+pub const BUNDLE_FORMAT: u32 = 1;
+
+/// The functions of a namespace and what they run, from `GET /export`. Body of
+/// `POST /import`, on the same backend or on another one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Bundle {
+    /// [`BUNDLE_FORMAT`] of the backend that wrote it.
+    pub format: u32,
+    /// Seconds since the Unix epoch.
+    pub exported_at: u64,
+    /// Namespace the functions were exported from.
+    pub namespace: String,
+    /// With their version and dates.
+    pub functions: Vec<FunctionInfo>,
+    /// Runtimes the functions ran on, to compare with those of the backend
+    /// importing them.
+    #[serde(default)]
+    pub runtimes: Vec<RuntimeInfo>,
+    /// Base64 content of the artifacts of the functions, by id. Left out with
+    /// `?artifacts=false`, for backends that share their blob store.
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub artifacts: std::collections::BTreeMap<String, String>,
+}
+
+/// What `POST /import` does with a function whose name is taken by one with
+/// other settings.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// Keeps the function already there.
+    #[default]
+    Skip,
+    /// Deploys the settings of the bundle as a new version of the function.
+    Overwrite,
+    /// Imports the function under a free name, its own with `-2`, `-3`…
+    Rename,
+}
+
+/// What `POST /import` did with a function of the bundle.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportOutcome {
+    Created,
+    /// A function of that name already had the same settings.
+    Unchanged,
+    Overwritten,
+    Renamed,
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImportedFunction {
+    /// Name of the function in the bundle.
+    pub name: String,
+    pub outcome: ImportOutcome,
+    /// The function as it is now, absent when it was skipped or failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<FunctionInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Response of `POST /import`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Every function of the bundle, in its order.
+    pub functions: Vec<ImportedFunction>,
+    /// Runtimes that differ from those of the bundle.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// A runtime jobs can ask for, listed by `GET /runtimes`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeInfo {
+    /// Name of the runtime, the `language` of a job.
+    pub name: String,
+    pub version: String,
+    /// Architectures its images are built for, empty when they run on any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arches: Vec<Arch>,
+}
+
+/// CPU architecture of a guest kernel, of a runtime image, or of the VMs a
+/// backend runs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+impl Arch {
+    /// Architecture of the machine this runs on, `None` when VMs cannot run there.
+    pub fn host() -> Option<Arch> {
+        Arch::from_name(std::env::consts::ARCH)
+    }
+
+    /// Architecture named `name`, as Rust and the Linux kernel name it.
+    pub fn from_name(name: &str) -> Option<Arch> {
+        match name {
+            "x86_64" => Some(Arch::X86_64),
+            "aarch64" => Some(Arch::Aarch64),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Resources of a guest VM.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmConfig {
+    pub vcpus: u8,
+    pub memory_mb: usize,
+}
+
+/// Metadata returned once an artifact is stored, by `POST /artifacts`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactInfo {
+    /// Hex-encoded SHA-256 of the artifact content, used as its id.
+    pub id: String,
+    pub size: u64,
+}
+
+/// Public keys of the VM identity tokens, served at `GET /.well-known/jwks.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+/// An Ed25519 public key, as a JSON Web Key (RFC 8037).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Jwk {
+    /// Always `OKP`.
+    pub kty: String,
+    /// Always `Ed25519`.
+    pub crv: String,
+    /// Always `EdDSA`.
+    pub alg: String,
+    /// Always `sig`.
+    #[serde(rename = "use")]
+    pub use_: String,
+    /// Key id, the `kid` in the header of the tokens it signed.
+    pub kid: String,
+    /// Base64url of the public key.
+    pub x: String,
+}
+
+// ── Backend → agent ─────────────────────────────────────────────────
+
+/// Body of the agent's `POST /execute`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExecuteRequest {
+    pub language: String,
+    pub code: String,
+    /// Base64 of a gzipped tar unpacked in the job directory, run instead of `code`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<String>,
+    /// File of `bundle` to run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<String>,
+    /// Cut the job off the network and fix its clock and seeds, see `FunctionSpec::deterministic`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deterministic: bool,
+    /// Cut the job off the network, as its sandbox profile asks.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub offline: bool,
+    /// Standard input of the code; it reads end of file without one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<String>,
+    /// Answer with [`ExecuteChunk`] lines, sending the standard output as the
+    /// code writes it, rather than with one [`ExecutionResult`] once it exited.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub stream: bool,
+    /// Base64 of the binary an earlier run built from the same code with the same
+    /// toolchain, run instead of compiling the code again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary: Option<String>,
+    /// Send back the binary the compile step built, in [`ExecutionResult::binary`].
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub keep_binary: bool,
+    /// Only compile the code and send back the binary, for runtimes whose code
+    /// runs in a VM of another image.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub build_only: bool,
+    /// Limits of the processes of the code inside the guest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<JobLimits>,
+    /// Identity token of the VM, handed to the code in `CLOUDE_IDENTITY_TOKEN`
+    /// and the file of `CLOUDE_IDENTITY_TOKEN_FILE`, which `PUT /identity` renews.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_token: Option<String>,
+    /// URL of the proxy the code reaches the network through, handed to it in
+    /// `HTTP_PROXY` and `HTTPS_PROXY`, for `egress-restricted` jobs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress_proxy: Option<String>,
+    /// Run beside the other concurrent jobs of the VM, up to
+    /// [`MAX_CONCURRENT_EXECUTIONS`], rather than alone. For jobs of one
+    /// function, which trust each other.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub concurrent: bool,
+}
+
+/// Most `concurrent` jobs a guest agent runs at once.
+pub const MAX_CONCURRENT_EXECUTIONS: u32 = 64;
+
+/// Memory, process and CPU limits of the code of a job, enforced inside the guest
+/// so that going over them kills the code rather than the guest.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JobLimits {
+    /// Memory of all the processes of the code together, in MiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<usize>,
+    /// Processes and threads the code can run at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids: Option<u32>,
+    /// Largest core dump kept when the code crashes, cut to that size; none without.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub core_dump_bytes: Option<u64>,
+    /// CPU time the code gets per second, in milliseconds: 1000 is one vCPU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_millis: Option<u32>,
+    /// How long the code runs on every vCPU first, unthrottled, in milliseconds,
+    /// before it is held to `cpu_millis`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_burst_ms: Option<u64>,
+}
+
+/// Response of the agent's `POST /execute`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionResult {
+    pub job_id: String,
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+    /// Base64 of the compiled binary, with `keep_binary` and a compile step that succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary: Option<String>,
+    /// The code went over `limits.memory_mb` and was killed.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub oom_killed: bool,
+    /// Signal that killed the code, like `SIGSEGV`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    /// Base64 of the core the code dumped, with `limits.core_dump_bytes`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub core_dump: Option<String>,
+}
+
+/// A line of the response of the agent's `POST /execute` with `stream`, which
+/// ends with a `result` or an `error` line.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecuteChunk {
+    /// Base64 of bytes the code wrote to its standard output.
+    Stdout(String),
+    /// The code exited; `stdout` holds everything sent before.
+    Result(ExecutionResult),
+    /// The code could not run to the end.
+    Error(String),
+}
+
+/// Response of the agent's `POST /reset`, sent before each job of a pooled VM.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResetResponse {
+    /// Time the agent took to kill leftover processes and swap the overlay upper layer.
+    pub duration_ms: u64,
+}
+
+/// Response of the agent's `POST /entropy`, sent before each job of a pooled VM.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntropyResponse {
+    /// Bytes of the host RNG the guest kernel RNG was reseeded with.
+    pub bytes: u32,
+}
+
+/// Request of the agent's `PUT /identity`: a new identity token for the running
+/// job its `job_id` claim names.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IdentityRequest {
+    pub token: String,
+}
+
+/// Request of the agent's `POST /clock`: the host time to step the guest clock to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockRequest {
+    /// Milliseconds since the Unix epoch.
+    pub unix_ms: u64,
+}
+
+/// Response of the agent's `POST /clock`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockResponse {
+    /// How far the guest clock was behind the host, negative when it was ahead.
+    pub offset_ms: i64,
+}
+
+/// Request of the agent's `POST /network`: the address a VM restored from a template
+/// snapshot takes instead of the one the template was baked with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkRequest {
+    pub ip: std::net::Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: std::net::Ipv4Addr,
+}
+
+/// Protocol the agent upgrades `POST /channels/{id}` to: the byte stream of the
+/// channel, to and from the socket `{CHANNEL_DIR}/{id}` of the guest.
+pub const CHANNEL_PROTOCOL: &str = "cloude-channel";
+
+// ── Errors ──────────────────────────────────────────────────────────
+
+/// A single problem found in a request field.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Body of every error response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ErrorResponse {
+    pub error: String,
+    /// Every invalid field, for `422 Unprocessable Entity` responses.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<FieldError>,
+}
+
+impl ErrorResponse {
+    pub fn new(error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            details: Vec::new(),
+        }
+    }
+}
+
+/// Leaves flags that are off out of the wire format.
+fn is_false(flag: &bool) -> bool {
+    !flag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_wire_format() {
+        let spec = FunctionSpec {
+            language: "python".to_string(),
+            code: Some("print(1)".to_string()),
+            artifact: None,
+            entrypoint: None,
+            deterministic: false,
+            cache: None,
+            isolation: Isolation::Vm,
+            resources: None,
+            stdin: None,
+            profile: None,
+            network: Network::Open,
+            identity_audience: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&spec).unwrap(),
+            json!({ "language": "python", "code": "print(1)" })
+        );
+        let spec: FunctionSpec = serde_json::from_value(
+            json!({ "language": "rust", "code": "fn main() {}", "resources": { "vcpus": 2 } }),
+        )
+        .unwrap();
+        assert_eq!(
+            spec.resources,
+            Some(Resources {
+                vcpus: Some(2),
+                memory_mb: None,
+            })
+        );
+        let spec: FunctionSpec = serde_json::from_value(
+            json!({ "language": "python", "code": "print(1)", "deterministic": true }),
+        )
+        .unwrap();
+        assert!(spec.deterministic);
+        let spec: FunctionSpec = serde_json::from_value(
+            json!({ "language": "python", "code": "print(1)", "cache": { "ttl_secs": 60 } }),
+        )
+        .unwrap();
+        assert_eq!(
+            spec.cache,
+            Some(CacheControl {
+                ttl_secs: Some(60),
+                no_store: false,
+            })
+        );
+
+        let spec: FunctionSpec = serde_json::from_value(
+            json!({ "language": "python", "code": "print(1)", "isolation": "pooled-vm" }),
+        )
+        .unwrap();
+        assert_eq!(spec.isolation, Isolation::PooledVm);
+
+        let status: StatusResponse =
+            serde_json::from_value(json!({ "id": "job-1", "status": "running" })).unwrap();
+        assert_eq!(status.status, JobStatus::Running);
+        assert_eq!(status.exit_code, None);
+        assert_eq!(status.status.to_string(), "running");
+        assert_eq!(status.traffic, None);
+
+        // Counters of a pooled VM carry over from job to job.
+        let before = TrafficStats {
+            rx_bytes: 100,
+            rx_packets: 2,
+            tx_bytes: 50,
+            tx_packets: 1,
+        };
+        let after = TrafficStats {
+            rx_bytes: 160,
+            tx_packets: 3,
+            ..before
+        };
+        assert_eq!(
+            serde_json::to_value(after.since(&before)).unwrap(),
+            json!({ "rx_bytes": 60, "rx_packets": 0, "tx_bytes": 0, "tx_packets": 2 })
+        );
+
+        assert_eq!(
+            serde_json::to_value(LogLine {
+                timestamp_ms: 1_760_000_000_000,
+                source: LogSource::Kernel,
+                line: "Linux version 6.1".to_string(),
+            })
+            .unwrap(),
+            json!({ "timestamp_ms": 1_760_000_000_000u64, "source": "kernel", "line": "Linux version 6.1" })
+        );
+
+        let deploy: DeployRequest = serde_json::from_value(
+            json!({ "language": "node", "artifact": "ab", "entrypoint": "index.js" }),
+        )
+        .unwrap();
+        assert_eq!(deploy.entrypoint.as_deref(), Some("index.js"));
+
+        assert_eq!(
+            serde_json::to_value(ErrorResponse::new("boom")).unwrap(),
+            json!({ "error": "boom" })
+        );
+
+        assert_eq!(
+            serde_json::to_value(ExecuteChunk::Stdout("aGk=".to_string())).unwrap(),
+            json!({ "stdout": "aGk=" })
+        );
+        assert_eq!(
+            serde_json::to_value(ExecuteChunk::Error("timed out".to_string())).unwrap(),
+            json!({ "error": "timed out" })
+        );
+
+        // Arches are spelled like `std::env::consts::ARCH` on the wire and in manifests.
+        for arch in [Arch::X86_64, Arch::Aarch64] {
+            assert_eq!(serde_json::to_value(arch).unwrap(), json!(arch.to_string()));
+            assert_eq!(Arch::from_name(&arch.to_string()), Some(arch));
+        }
+        assert_eq!(Arch::from_name("arm64"), None);
+
+        let pipeline: PipelineRequest = serde_json::from_value(json!({
+            "input": "a b",
+            "stages": [
+                { "name": "split", "function": "split" },
+                { "name": "count", "function": "wc", "after": ["split"] }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(pipeline.stages[0].after, Vec::<String>::new());
+        assert_eq!(pipeline.stages[1].after, ["split"]);
+        assert_eq!(
+            serde_json::to_value(StageResult {
+                name: "count".to_string(),
+                function: "wc".to_string(),
+                status: StageStatus::Skipped,
+                job_id: None,
+                exit_code: None,
+                output_bytes: None,
+                error: None,
+            })
+            .unwrap(),
+            json!({ "name": "count", "function": "wc", "status": "skipped" })
+        );
+
+        let completed: ExecutionCompleted = serde_json::from_value(json!({
+            "function": "resize",
+            "version": 3,
+            "job_id": "job-1",
+            "status": "done",
+            "exit_code": 0,
+            "stdout": null,
+            "stderr": null,
+            "result_omitted": true,
+            "timestamp_ms": 1_760_000_000_000u64
+        }))
+        .unwrap();
+        assert!(completed.result_omitted);
+        assert!(!completed.core_dump);
+        assert_eq!(completed.status, JobStatus::Done);
+
+        assert_eq!(
+            serde_json::to_value(DashboardQuery {
+                bucket_secs: Some(300),
+                ..Default::default()
+            })
+            .unwrap(),
+            json!({ "bucket_secs": 300 })
+        );
+    }
+
+    #[test]
+    fn test_function_spec_rejects_unknown_fields() {
+        let spec = serde_json::from_value::<FunctionSpec>(
+            json!({ "language": "python", "code": "print(1)", "timeout": 5 }),
+        );
+        assert!(spec.is_err());
+    }
+}
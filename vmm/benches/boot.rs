@@ -0,0 +1,79 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! VMM boot path benchmarks: guest memory setup and kernel loading.
+//!
+//! They need `/dev/kvm`. Kernel loading also needs a kernel and an initramfs:
+//! `VMM_BENCH_KERNEL` (default `../backend/vmlinux`) and `VMM_BENCH_INITRAMFS`.
+//!
+//! Compare two commits with criterion baselines:
+//! `cargo bench --bench boot -- --save-baseline main`, then `-- --baseline main`.
+
+use std::fs::File;
+use std::path::Path;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use vmm::VMM;
+
+const MEMORY_SIZES_MB: [usize; 3] = [128, 512, 2048];
+
+fn new_vmm(memory_mb: usize) -> VMM {
+    VMM::new(None, Box::new(std::io::sink()), memory_mb << 20).expect("VMM should be created")
+}
+
+fn kvm_available() -> bool {
+    let available = File::open("/dev/kvm").is_ok();
+    if !available {
+        eprintln!("Skipping VMM benchmarks: /dev/kvm is not accessible");
+    }
+    available
+}
+
+/// KVM VM creation and guest memory mapping, per memory size.
+fn memory_setup(c: &mut Criterion) {
+    if !kvm_available() {
+        return;
+    }
+
+    let mut group = c.benchmark_group("memory_setup");
+    for memory_mb in MEMORY_SIZES_MB {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{}M", memory_mb)),
+            &memory_mb,
+            |b, &memory_mb| b.iter(|| new_vmm(memory_mb)),
+        );
+    }
+    group.finish();
+}
+
+/// Kernel and initramfs loading, boot parameters and vCPU setup.
+fn kernel_load(c: &mut Criterion) {
+    if !kvm_available() {
+        return;
+    }
+
+    let kernel = std::env::var("VMM_BENCH_KERNEL").unwrap_or_else(|_| "../backend/vmlinux".into());
+    let initramfs = match std::env::var("VMM_BENCH_INITRAMFS") {
+        Ok(path) if Path::new(&kernel).exists() => path,
+        _ => {
+            eprintln!(
+                "Skipping kernel load benchmark: set VMM_BENCH_KERNEL and VMM_BENCH_INITRAMFS"
+            );
+            return;
+        }
+    };
+
+    c.bench_function("kernel_load", |b| {
+        b.iter_batched(
+            || new_vmm(512),
+            |mut vmm| {
+                vmm.configure(1, &kernel, Some(&initramfs), None)
+                    .expect("kernel should load");
+                vmm
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+criterion_group!(benches, memory_setup, kernel_load);
+criterion_main!(benches);
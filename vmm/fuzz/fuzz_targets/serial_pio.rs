@@ -0,0 +1,21 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vmm::fuzzing::SerialPorts;
+use vmm_fuzz::{decode, Access};
+
+// `in`/`out` move at most 4 bytes.
+const MAX_PIO_LEN: usize = 4;
+
+fuzz_target!(|input: &[u8]| {
+    let mut serial = SerialPorts::new(Box::new(std::io::sink())).unwrap();
+
+    for access in decode(input, MAX_PIO_LEN) {
+        match access {
+            Access::Read { addr, len } => serial.read(addr, &mut vec![0; len]),
+            Access::Write { addr, data } => serial.write(addr, &data),
+        }
+    }
+});
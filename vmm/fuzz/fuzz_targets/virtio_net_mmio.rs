@@ -0,0 +1,32 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vmm::VMM;
+use vmm_fuzz::{decode, Access};
+
+// KVM reports MMIO accesses of at most 8 bytes.
+const MAX_MMIO_LEN: usize = 8;
+// Size of the register window allocated to the device.
+const MMIO_WINDOW: u64 = 0x1000;
+const GUEST_MEMORY: usize = 16 << 20;
+
+fuzz_target!(|input: &[u8]| {
+    // A fresh device for every input keeps runs reproducible.
+    let stdin = Box::new(std::fs::File::open("/dev/null").unwrap());
+    let mut vmm = VMM::new(stdin, Box::new(std::io::sink()), GUEST_MEMORY).unwrap();
+    let tap = std::env::var("FUZZ_TAP").unwrap_or_else(|_| "fuzztap0".to_string());
+    vmm.add_net_device(tap, None, None, None).unwrap();
+
+    for access in decode(input, MAX_MMIO_LEN) {
+        match access {
+            Access::Read { addr, len } => {
+                vmm.net_mmio_read(u64::from(addr) % MMIO_WINDOW, &mut vec![0; len])
+            }
+            Access::Write { addr, data } => {
+                vmm.net_mmio_write(u64::from(addr) % MMIO_WINDOW, &data)
+            }
+        }
+    }
+});
@@ -0,0 +1,27 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Turns the driver traces in `traces/<target>/` into seed inputs in `corpus/<target>/`.
+//!
+//! Run from `vmm/fuzz` before `cargo fuzz run <target>`.
+
+use std::fs;
+use std::path::Path;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    for target in fs::read_dir("traces")? {
+        let target = target?.path();
+        let name = target.file_name().unwrap();
+        let corpus = Path::new("corpus").join(name);
+        fs::create_dir_all(&corpus)?;
+
+        for trace in fs::read_dir(&target)? {
+            let trace = trace?.path();
+            let accesses = vmm_fuzz::parse_trace(&fs::read_to_string(&trace)?)
+                .map_err(|e| format!("{}: {}", trace.display(), e))?;
+            let seed = corpus.join(trace.file_stem().unwrap());
+            fs::write(&seed, vmm_fuzz::encode(&accesses))?;
+            println!("{} -> {}", trace.display(), seed.display());
+        }
+    }
+    Ok(())
+}
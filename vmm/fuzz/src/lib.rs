@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Input format shared by the fuzz targets: a sequence of register accesses.
+//!
+//! Each access is encoded as `[kind, addr_lo, addr_hi, len]` followed, for
+//! writes, by the bytes written. `kind` is a read when its low bit is clear,
+//! `len` is reduced to `1..=max_len`. Decoding stops at the first truncated access.
+
+/// One guest access to a device register.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    Read { addr: u16, len: usize },
+    Write { addr: u16, data: Vec<u8> },
+}
+
+/// Decodes fuzzer input into accesses of at most `max_len` bytes.
+pub fn decode(input: &[u8], max_len: usize) -> Vec<Access> {
+    let mut accesses = Vec::new();
+    let mut rest = input;
+
+    while rest.len() >= 4 {
+        let is_write = rest[0] & 1 == 1;
+        let addr = u16::from_le_bytes([rest[1], rest[2]]);
+        let len = 1 + rest[3] as usize % max_len;
+        rest = &rest[4..];
+
+        if !is_write {
+            accesses.push(Access::Read { addr, len });
+            continue;
+        }
+        if rest.len() < len {
+            break;
+        }
+        accesses.push(Access::Write {
+            addr,
+            data: rest[..len].to_vec(),
+        });
+        rest = &rest[len..];
+    }
+
+    accesses
+}
+
+/// Encodes accesses so that [`decode`] gives them back.
+pub fn encode(accesses: &[Access]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for access in accesses {
+        match access {
+            Access::Read { addr, len } => {
+                out.push(0);
+                out.extend_from_slice(&addr.to_le_bytes());
+                out.push((len - 1) as u8);
+            }
+            Access::Write { addr, data } => {
+                out.push(1);
+                out.extend_from_slice(&addr.to_le_bytes());
+                out.push((data.len() - 1) as u8);
+                out.extend_from_slice(data);
+            }
+        }
+    }
+    out
+}
+
+/// Parses a driver trace: one access per line, `R <addr> <len>` or
+/// `W <addr> <len> <value>`, with the value stored little-endian.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_trace(trace: &str) -> Result<Vec<Access>, String> {
+    let mut accesses = Vec::new();
+
+    for (index, line) in trace.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let err = |msg: &str| format!("line {}: {}: {}", index + 1, msg, line);
+        let number = |field: &str| -> Result<u64, String> {
+            match field.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => field.parse(),
+            }
+            .map_err(|_| err("invalid number"))
+        };
+
+        let addr = number(fields.get(1).ok_or_else(|| err("missing address"))?)?;
+        let len = number(fields.get(2).ok_or_else(|| err("missing length"))?)? as usize;
+        if addr > u16::MAX as u64 || !(1..=8).contains(&len) {
+            return Err(err("address or length out of range"));
+        }
+        let addr = addr as u16;
+
+        match (fields[0], fields.get(3)) {
+            ("R", None) => accesses.push(Access::Read { addr, len }),
+            ("W", Some(value)) => accesses.push(Access::Write {
+                addr,
+                data: number(value)?.to_le_bytes()[..len].to_vec(),
+            }),
+            _ => return Err(err("expected `R <addr> <len>` or `W <addr> <len> <value>`")),
+        }
+    }
+
+    Ok(accesses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_round_trip() {
+        let accesses = parse_trace("# probe\nR 0x000 4\nW 0x070 4 0xf\n\nW 0x3fb 1 128\n").unwrap();
+        assert_eq!(
+            accesses,
+            vec![
+                Access::Read { addr: 0, len: 4 },
+                Access::Write {
+                    addr: 0x70,
+                    data: vec![0xf, 0, 0, 0]
+                },
+                Access::Write {
+                    addr: 0x3fb,
+                    data: vec![0x80]
+                },
+            ]
+        );
+        assert_eq!(decode(&encode(&accesses), 8), accesses);
+    }
+
+    #[test]
+    fn test_decode_stops_at_truncated_write() {
+        assert_eq!(decode(&[1, 0x70, 0, 3, 0xf], 8), Vec::new());
+        assert_eq!(decode(&[2, 0x00, 0x01, 9], 8).len(), 1);
+    }
+}
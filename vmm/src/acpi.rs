@@ -0,0 +1,710 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal ACPI tables describing the VM: RSDP, XSDT, FADT, DSDT and MADT.
+//!
+//! The platform is "hardware-reduced": there is no fixed-feature hardware
+//! (PM timer, SCI, legacy power button), the MADT lists the vCPUs and the
+//! IOAPIC, and the DSDT declares a control-method power button. A generic
+//! event device (GED) turns an interrupt from the VMM into a press of the
+//! button, and powering off (S5) is a write to [`SLEEP_CONTROL_PORT`]. The
+//! tables are written where the kernel scans for the RSDP, in the BIOS area
+//! below 1 MiB which the E820 map leaves out of RAM.
+//!
+//! When vCPUs can be hotplugged, the MADT lists them all, those added later
+//! as "online capable", and the DSDT declares a processor device for each.
+//! Their `_STA` reads [`CpuStatus`] through [`CPU_STATUS_PORT`], and a second
+//! GED interrupt makes the guest check the ones that were not present yet.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Where the tables are written. The RSDP comes first, on a 16-byte boundary
+/// inside the 0xe0000-0xfffff range the kernel scans.
+pub(crate) const ACPI_START: u64 = 0x000e_0000;
+/// Space available to the tables, up to the start of high memory.
+const ACPI_SIZE: usize = 0x2_0000;
+
+const OEM_ID: [u8; 6] = *b"CLOUDE";
+const OEM_TABLE_ID: [u8; 8] = *b"CLOUDEVM";
+const OEM_REVISION: u32 = 1;
+const CREATOR_ID: [u8; 4] = *b"CLDE";
+const CREATOR_REVISION: u32 = 1;
+
+/// Size of the header shared by the system description tables.
+const HEADER_SIZE: usize = 36;
+/// Size of the ACPI 2.0+ RSDP.
+const RSDP_SIZE: usize = 36;
+/// Tables start on 16-byte boundaries, like the RSDP must.
+const TABLE_ALIGNMENT: usize = 16;
+
+// Same addresses and IOAPIC ID as the MP table.
+const APIC_DEFAULT_PHYS_BASE: u32 = 0xfee0_0000;
+const IO_APIC_DEFAULT_PHYS_BASE: u32 = 0xfec0_0000;
+
+// MADT.
+const MADT_PCAT_COMPAT: u32 = 1; // The dual 8259 PICs are present.
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_REVISION: u8 = 6;
+const MADT_LOCAL_APIC_ENABLED: u32 = 1;
+const MADT_LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1; // Can be enabled at runtime, MADT revision 5+.
+
+/// I/O port of the sleep control and status registers.
+pub(crate) const SLEEP_CONTROL_PORT: u16 = 0x3c0;
+/// Sleep type of S5 in `\_S5_`, written by the guest in bits 2-4 of the sleep control register.
+const S5_SLEEP_TYPE: u8 = 5;
+const SLEEP_TYPE_SHIFT: u8 = 2;
+const SLEEP_ENABLE: u8 = 1 << 5;
+
+/// First of the two I/O ports of [`CpuStatus`]: the vCPU selector, then its status.
+pub(crate) const CPU_STATUS_PORT: u16 = 0x3c4;
+const CPU_STATUS_PORT_COUNT: u16 = 2;
+/// `_STA` of a present vCPU: present, enabled, shown in the UI and functioning.
+const STA_PRESENT: u8 = 0x0f;
+
+// FADT.
+const FADT_SIZE: usize = 276;
+const FADT_REVISION: u8 = 6;
+const FADT_MINOR_REVISION: u8 = 5;
+const FADT_F_PWR_BUTTON: u32 = 1 << 4; // The power button, if any, is a control method device.
+const FADT_F_SLP_BUTTON: u32 = 1 << 5; // Same for the sleep button.
+const FADT_F_HW_REDUCED_ACPI: u32 = 1 << 20;
+const IAPC_BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+const IAPC_BOOT_ARCH_MSI_NOT_SUPPORTED: u16 = 1 << 3;
+const GAS_SYSTEM_IO: u8 = 1;
+const GAS_ACCESS_BYTE: u8 = 1;
+
+// AML opcodes used by the DSDT.
+const AML_ZERO_OP: u8 = 0x00;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+const AML_STRING_PREFIX: u8 = 0x0d;
+const AML_SCOPE_OP: u8 = 0x10;
+const AML_BUFFER_OP: u8 = 0x11;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_METHOD_OP: u8 = 0x14;
+const AML_DUAL_NAME_PREFIX: u8 = 0x2e;
+const AML_MULTI_NAME_PREFIX: u8 = 0x2f;
+const AML_ROOT_CHAR: u8 = b'\\';
+const AML_ARG0_OP: u8 = 0x68;
+const AML_STORE_OP: u8 = 0x70;
+const AML_NOTIFY_OP: u8 = 0x86;
+const AML_LEQUAL_OP: u8 = 0x93;
+const AML_IF_OP: u8 = 0xa0;
+const AML_RETURN_OP: u8 = 0xa4;
+const AML_OPERATION_REGION_OP: [u8; 2] = [0x5b, 0x80];
+const AML_FIELD_OP: [u8; 2] = [0x5b, 0x81];
+const AML_DEVICE_OP: [u8; 2] = [0x5b, 0x82];
+const AML_METHOD_SERIALIZED: u8 = 1 << 3;
+const AML_REGION_SYSTEM_IO: u8 = 1;
+const AML_FIELD_BYTE_ACCESS: u8 = 1; // No lock, preserve the bits not written.
+/// Notification of a power button press.
+const NOTIFY_BUTTON_PRESSED: u8 = 0x80;
+/// Notification asking the OS to check whether a device appeared.
+const NOTIFY_DEVICE_CHECK: u8 = 0x01;
+
+// Resource descriptors of the GED interrupt.
+const EXTENDED_INTERRUPT: u8 = 0x89;
+const EXTENDED_INTERRUPT_CONSUMER_EDGE: u8 = 0b11; // Consumer, edge triggered, active high.
+const END_TAG: [u8; 2] = [0x79, 0x00];
+
+/// vCPUs that can be added once the VM runs.
+pub(crate) struct CpuHotplug {
+    /// vCPUs the VM can have, those booted included.
+    pub max_cpus: u8,
+    /// An edge on this GSI makes the guest look for new vCPUs.
+    pub gsi: u32,
+}
+
+/// The tables for a VM booting with `num_cpus` vCPUs, to be written at [`ACPI_START`].
+/// An edge on `power_button_gsi` presses the power button.
+pub(crate) fn tables(num_cpus: u8, power_button_gsi: u32, hotplug: Option<&CpuHotplug>) -> Vec<u8> {
+    let max_cpus = hotplug.map_or(num_cpus, |hotplug| hotplug.max_cpus);
+    // The RSDP points to the XSDT, which comes last: keep room for it.
+    let mut out = vec![0; RSDP_SIZE];
+    let dsdt = push_table(&mut out, dsdt(num_cpus, power_button_gsi, hotplug));
+    let fadt = push_table(&mut out, fadt(dsdt));
+    let madt = push_table(&mut out, madt(num_cpus, max_cpus));
+    let xsdt = push_table(&mut out, xsdt(&[fadt, madt]));
+    out[..RSDP_SIZE].copy_from_slice(&rsdp(xsdt));
+
+    assert!(out.len() <= ACPI_SIZE, "ACPI tables overflow their area");
+    out
+}
+
+/// Whether the guest wrote `data` to the sleep control register to enter S5, i.e. power off.
+pub(crate) fn is_poweroff(data: &[u8]) -> bool {
+    match data.first() {
+        Some(&value) => {
+            value & SLEEP_ENABLE != 0 && (value >> SLEEP_TYPE_SHIFT) & 0b111 == S5_SLEEP_TYPE
+        }
+        None => false,
+    }
+}
+
+/// Registers behind [`CPU_STATUS_PORT`], read by the `_STA` method of the processor devices:
+/// the guest writes the index of a vCPU to the first port, then reads its status from the second.
+/// The method is serialized, so the two accesses of one evaluation never interleave with another's.
+pub(crate) struct CpuStatus {
+    selected: AtomicU8,
+    /// vCPUs 0 to `present - 1` exist.
+    present: AtomicU8,
+}
+
+impl CpuStatus {
+    pub fn new(present: u8) -> Self {
+        CpuStatus {
+            selected: AtomicU8::new(0),
+            present: AtomicU8::new(present),
+        }
+    }
+
+    /// Reports vCPUs 0 to `present - 1` as present from now on.
+    pub fn set_present(&self, present: u8) {
+        self.present.store(present, Ordering::SeqCst);
+    }
+
+    /// Whether `port` is one of the registers.
+    pub fn handles(port: u16) -> bool {
+        (CPU_STATUS_PORT..CPU_STATUS_PORT + CPU_STATUS_PORT_COUNT).contains(&port)
+    }
+
+    pub fn pio_write(&self, port: u16, data: &[u8]) {
+        if port == CPU_STATUS_PORT {
+            if let Some(&index) = data.first() {
+                self.selected.store(index, Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub fn pio_read(&self, port: u16, data: &mut [u8]) {
+        let value = if port == CPU_STATUS_PORT {
+            self.selected.load(Ordering::SeqCst)
+        } else if self.selected.load(Ordering::SeqCst) < self.present.load(Ordering::SeqCst) {
+            STA_PRESENT
+        } else {
+            0
+        };
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+        if let Some(byte) = data.first_mut() {
+            *byte = value;
+        }
+    }
+}
+
+/// Appends `table` at the next aligned offset and returns its guest address.
+fn push_table(out: &mut Vec<u8>, table: Vec<u8>) -> u64 {
+    let offset = out.len().div_ceil(TABLE_ALIGNMENT) * TABLE_ALIGNMENT;
+    out.resize(offset, 0);
+    out.extend(table);
+    ACPI_START + offset as u64
+}
+
+/// Value of the checksum byte so that all the bytes of `bytes` sum to 0.
+fn checksum(bytes: &[u8]) -> u8 {
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    0u8.wrapping_sub(sum)
+}
+
+/// Header of a system description table, its length and checksum are set by [`finish`].
+fn header(signature: &[u8; 4], revision: u8) -> Vec<u8> {
+    let mut table = Vec::with_capacity(HEADER_SIZE);
+    table.extend(signature);
+    table.extend(0u32.to_le_bytes()); // length
+    table.push(revision);
+    table.push(0); // checksum
+    table.extend(OEM_ID);
+    table.extend(OEM_TABLE_ID);
+    table.extend(OEM_REVISION.to_le_bytes());
+    table.extend(CREATOR_ID);
+    table.extend(CREATOR_REVISION.to_le_bytes());
+    table
+}
+
+/// Sets the length and the checksum of `table`, once complete.
+fn finish(mut table: Vec<u8>) -> Vec<u8> {
+    let length = table.len() as u32;
+    table[4..8].copy_from_slice(&length.to_le_bytes());
+    table[9] = checksum(&table);
+    table
+}
+
+fn rsdp(xsdt: u64) -> Vec<u8> {
+    let mut rsdp = Vec::with_capacity(RSDP_SIZE);
+    rsdp.extend(b"RSD PTR ");
+    rsdp.push(0); // checksum of the ACPI 1.0 part
+    rsdp.extend(OEM_ID);
+    rsdp.push(2); // revision: ACPI 2.0+, the XSDT is used
+    rsdp.extend(0u32.to_le_bytes()); // no RSDT
+    rsdp.extend((RSDP_SIZE as u32).to_le_bytes());
+    rsdp.extend(xsdt.to_le_bytes());
+    rsdp.push(0); // extended checksum
+    rsdp.extend([0; 3]);
+
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(&rsdp);
+    rsdp
+}
+
+fn xsdt(entries: &[u64]) -> Vec<u8> {
+    let mut xsdt = header(b"XSDT", 1);
+    for entry in entries {
+        xsdt.extend(entry.to_le_bytes());
+    }
+    finish(xsdt)
+}
+
+fn fadt(dsdt: u64) -> Vec<u8> {
+    let mut fadt = header(b"FACP", FADT_REVISION);
+    fadt.resize(FADT_SIZE, 0);
+    // The 32-bit DSDT address stays 0, X_DSDT is used.
+    fadt[109..111].copy_from_slice(
+        &(IAPC_BOOT_ARCH_VGA_NOT_PRESENT | IAPC_BOOT_ARCH_MSI_NOT_SUPPORTED).to_le_bytes(),
+    );
+    fadt[112..116].copy_from_slice(
+        &(FADT_F_HW_REDUCED_ACPI | FADT_F_PWR_BUTTON | FADT_F_SLP_BUTTON).to_le_bytes(),
+    );
+    fadt[131] = FADT_MINOR_REVISION;
+    fadt[140..148].copy_from_slice(&dsdt.to_le_bytes());
+    // Status reads return 0, the guest does not wait for a wake-up from S5.
+    let sleep_register = sleep_register();
+    fadt[244..256].copy_from_slice(&sleep_register);
+    fadt[256..268].copy_from_slice(&sleep_register);
+    fadt[268..276].copy_from_slice(b"CLOUDEVM"); // hypervisor vendor identity
+    finish(fadt)
+}
+
+/// Generic address of a one-byte register at [`SLEEP_CONTROL_PORT`].
+fn sleep_register() -> [u8; 12] {
+    let mut gas = [0; 12];
+    gas[0] = GAS_SYSTEM_IO;
+    gas[1] = 8; // bit width
+    gas[3] = GAS_ACCESS_BYTE;
+    gas[4..].copy_from_slice(&u64::from(SLEEP_CONTROL_PORT).to_le_bytes());
+    gas
+}
+
+fn madt(num_cpus: u8, max_cpus: u8) -> Vec<u8> {
+    let mut madt = header(b"APIC", MADT_REVISION);
+    madt.extend(APIC_DEFAULT_PHYS_BASE.to_le_bytes());
+    madt.extend(MADT_PCAT_COMPAT.to_le_bytes());
+
+    for cpu_id in 0..max_cpus {
+        let flags = if cpu_id < num_cpus {
+            MADT_LOCAL_APIC_ENABLED
+        } else {
+            MADT_LOCAL_APIC_ONLINE_CAPABLE
+        };
+        madt.extend([MADT_LOCAL_APIC, 8, cpu_id, cpu_id]);
+        madt.extend(flags.to_le_bytes());
+    }
+    // The MP table gives the IOAPIC the ID after the CPUs.
+    madt.extend([MADT_IO_APIC, 12, max_cpus + 1, 0]);
+    madt.extend(IO_APIC_DEFAULT_PHYS_BASE.to_le_bytes());
+    // GSIs start at its pin 0, ISA IRQs are identity mapped as in KVM's default routing.
+    madt.extend(0u32.to_le_bytes());
+    // NMIs reach every local APIC on LINT1, like in the MP table.
+    madt.extend([MADT_LOCAL_APIC_NMI, 6, 0xff, 0, 0, 1]);
+
+    finish(madt)
+}
+
+fn dsdt(num_cpus: u8, power_button_gsi: u32, hotplug: Option<&CpuHotplug>) -> Vec<u8> {
+    let mut dsdt = header(b"DSDT", 2);
+
+    // Name (_S5, Package () { 5, 0 })
+    dsdt.push(AML_NAME_OP);
+    dsdt.extend(b"_S5_");
+    dsdt.push(AML_PACKAGE_OP);
+    dsdt.extend(package(
+        &[2],
+        &[AML_BYTE_PREFIX, S5_SLEEP_TYPE, AML_ZERO_OP],
+    ));
+
+    // Scope (\_SB) { PWRB, CPUS, GED_ }
+    let mut devices = power_button();
+    if let Some(hotplug) = hotplug {
+        devices.extend(processors(num_cpus, hotplug.max_cpus));
+    }
+    devices.extend(generic_event_device(
+        power_button_gsi,
+        hotplug.map(|hotplug| hotplug.gsi),
+    ));
+    dsdt.push(AML_SCOPE_OP);
+    dsdt.extend(package(&root_path(&[b"_SB_"]), &devices));
+
+    finish(dsdt)
+}
+
+/// Device (PWRB) { Name (_HID, EisaId ("PNP0C0C")) }
+fn power_button() -> Vec<u8> {
+    let mut hid = vec![AML_NAME_OP];
+    hid.extend(b"_HID");
+    hid.push(AML_DWORD_PREFIX);
+    hid.extend(eisa_id(*b"PNP0C0C"));
+
+    let mut device = AML_DEVICE_OP.to_vec();
+    device.extend(package(b"PWRB", &hid));
+    device
+}
+
+/// Device (CPUS) {
+///     Name (_HID, "ACPI0010")
+///     OperationRegion (PRST, SystemIO, CPU_STATUS_PORT, 2)
+///     Field (PRST, ByteAcc, NoLock, Preserve) { CSEL, 8, CSTS, 8 }
+///     Method (CSTA, 1, Serialized) { Store (Arg0, CSEL) Return (CSTS) }
+///     Device (C000) { ... }, one per vCPU
+///     Method (CSCN) { Notify (C001, 1) ... }, for the vCPUs added after boot
+/// }
+fn processors(num_cpus: u8, max_cpus: u8) -> Vec<u8> {
+    let mut body = vec![AML_NAME_OP];
+    body.extend(b"_HID");
+    body.push(AML_STRING_PREFIX);
+    body.extend(b"ACPI0010\0");
+
+    body.extend(AML_OPERATION_REGION_OP);
+    body.extend(b"PRST");
+    body.push(AML_REGION_SYSTEM_IO);
+    body.push(AML_WORD_PREFIX);
+    body.extend(CPU_STATUS_PORT.to_le_bytes());
+    body.extend([AML_BYTE_PREFIX, CPU_STATUS_PORT_COUNT as u8]);
+
+    let mut fields = b"PRST".to_vec();
+    fields.push(AML_FIELD_BYTE_ACCESS);
+    fields.extend(b"CSEL");
+    fields.push(8); // bits, as a one-byte PkgLength
+    fields.extend(b"CSTS");
+    fields.push(8);
+    body.extend(AML_FIELD_OP);
+    body.extend(package(&[], &fields));
+
+    let mut select = vec![AML_STORE_OP, AML_ARG0_OP];
+    select.extend(b"CSEL");
+    select.push(AML_RETURN_OP);
+    select.extend(b"CSTS");
+    let mut name = b"CSTA".to_vec();
+    name.push(1 | AML_METHOD_SERIALIZED); // one argument
+    body.push(AML_METHOD_OP);
+    body.extend(package(&name, &select));
+
+    for cpu_id in 0..max_cpus {
+        body.extend(processor(cpu_id));
+    }
+
+    let mut notify = Vec::new();
+    for cpu_id in num_cpus..max_cpus {
+        notify.push(AML_NOTIFY_OP);
+        notify.extend(processor_name(cpu_id));
+        notify.extend([AML_BYTE_PREFIX, NOTIFY_DEVICE_CHECK]);
+    }
+    body.push(AML_METHOD_OP);
+    body.extend(package(b"CSCN\0", &notify));
+
+    let mut device = AML_DEVICE_OP.to_vec();
+    device.extend(package(b"CPUS", &body));
+    device
+}
+
+/// Device (Cxxx) {
+///     Name (_HID, "ACPI0007")
+///     Name (_UID, cpu_id)
+///     Name (_MAT, Buffer () { local APIC entry of the MADT, enabled })
+///     Method (_STA) { Return (CSTA (cpu_id)) }
+/// }
+fn processor(cpu_id: u8) -> Vec<u8> {
+    let mut body = vec![AML_NAME_OP];
+    body.extend(b"_HID");
+    body.push(AML_STRING_PREFIX);
+    body.extend(b"ACPI0007\0");
+
+    body.push(AML_NAME_OP);
+    body.extend(b"_UID");
+    body.extend([AML_BYTE_PREFIX, cpu_id]);
+
+    // The processor UID and APIC ID are the same, as in the MADT.
+    let mut entry = vec![MADT_LOCAL_APIC, 8, cpu_id, cpu_id];
+    entry.extend(MADT_LOCAL_APIC_ENABLED.to_le_bytes());
+    body.push(AML_NAME_OP);
+    body.extend(b"_MAT");
+    body.push(AML_BUFFER_OP);
+    body.extend(package(&[AML_BYTE_PREFIX, entry.len() as u8], &entry));
+
+    let mut status = vec![AML_RETURN_OP];
+    status.extend(b"CSTA");
+    status.extend([AML_BYTE_PREFIX, cpu_id]);
+    body.push(AML_METHOD_OP);
+    body.extend(package(b"_STA\0", &status));
+
+    let mut device = AML_DEVICE_OP.to_vec();
+    device.extend(package(&processor_name(cpu_id), &body));
+    device
+}
+
+fn processor_name(cpu_id: u8) -> [u8; 4] {
+    let mut name = [0; 4];
+    name.copy_from_slice(format!("C{:03X}", cpu_id).as_bytes());
+    name
+}
+
+/// Device (GED_) {
+///     Name (_HID, "ACPI0013")
+///     Name (_CRS, ResourceTemplate () {
+///         Interrupt (ResourceConsumer, Edge, ActiveHigh) { power_button_gsi }
+///         Interrupt (ResourceConsumer, Edge, ActiveHigh) { cpu_hotplug_gsi }
+///     })
+///     Method (_EVT, 1) {
+///         If (LEqual (Arg0, power_button_gsi)) { Notify (\_SB.PWRB, 0x80) }
+///         If (LEqual (Arg0, cpu_hotplug_gsi)) { \_SB.CPUS.CSCN () }
+///     }
+/// }
+fn generic_event_device(power_button_gsi: u32, cpu_hotplug_gsi: Option<u32>) -> Vec<u8> {
+    let mut body = vec![AML_NAME_OP];
+    body.extend(b"_HID");
+    body.push(AML_STRING_PREFIX);
+    body.extend(b"ACPI0013\0");
+
+    let mut resources = Vec::new();
+    for gsi in std::iter::once(power_button_gsi).chain(cpu_hotplug_gsi) {
+        resources.extend([
+            EXTENDED_INTERRUPT,
+            6,
+            0,
+            EXTENDED_INTERRUPT_CONSUMER_EDGE,
+            1,
+        ]);
+        resources.extend(gsi.to_le_bytes());
+    }
+    resources.extend(END_TAG);
+    body.push(AML_NAME_OP);
+    body.extend(b"_CRS");
+    body.push(AML_BUFFER_OP);
+    body.extend(package(
+        &[AML_BYTE_PREFIX, resources.len() as u8],
+        &resources,
+    ));
+
+    // The GED calls _EVT with the GSI that fired.
+    let mut notify = vec![AML_NOTIFY_OP];
+    notify.extend(root_path(&[b"_SB_", b"PWRB"]));
+    notify.extend([AML_BYTE_PREFIX, NOTIFY_BUTTON_PRESSED]);
+    let mut events = if_gsi(power_button_gsi, &notify);
+    if let Some(gsi) = cpu_hotplug_gsi {
+        events.extend(if_gsi(gsi, &root_path(&[b"_SB_", b"CPUS", b"CSCN"])));
+    }
+    let mut name = b"_EVT".to_vec();
+    name.push(1); // one argument, not serialized
+    body.push(AML_METHOD_OP);
+    body.extend(package(&name, &events));
+
+    let mut device = AML_DEVICE_OP.to_vec();
+    device.extend(package(b"GED_", &body));
+    device
+}
+
+/// If (LEqual (Arg0, gsi)) { body }
+fn if_gsi(gsi: u32, body: &[u8]) -> Vec<u8> {
+    let mut predicate = vec![AML_LEQUAL_OP, AML_ARG0_OP, AML_DWORD_PREFIX];
+    predicate.extend(gsi.to_le_bytes());
+    let mut condition = vec![AML_IF_OP];
+    condition.extend(package(&predicate, body));
+    condition
+}
+
+/// Absolute path made of one or more name segments.
+fn root_path(segments: &[&[u8; 4]]) -> Vec<u8> {
+    let mut path = vec![AML_ROOT_CHAR];
+    match segments.len() {
+        1 => {}
+        2 => path.push(AML_DUAL_NAME_PREFIX),
+        count => path.extend([AML_MULTI_NAME_PREFIX, count as u8]),
+    }
+    for segment in segments {
+        path.extend(*segment);
+    }
+    path
+}
+
+/// `name` then `body`, preceded by their length (AML `PkgLength`), which counts itself.
+fn package(name: &[u8], body: &[u8]) -> Vec<u8> {
+    let len = name.len() + body.len();
+    let mut package = if len + 1 < 1 << 6 {
+        vec![(len + 1) as u8]
+    } else {
+        // More bytes: the low nibble in the lead byte, with their count, then 8 bits per byte.
+        let count = if len + 2 < 1 << 12 { 1 } else { 2 };
+        let len = len + 1 + count;
+        assert!(len < 1 << (4 + 8 * count), "AML package too long");
+        let mut package = vec![((count as u8) << 6) | (len & 0xf) as u8];
+        package.extend((0..count).map(|i| (len >> (4 + 8 * i)) as u8));
+        package
+    };
+    package.extend(name);
+    package.extend(body);
+    package
+}
+
+/// Compressed EISA ID: three letters on 5 bits each, then the product number.
+fn eisa_id(id: [u8; 7]) -> [u8; 4] {
+    let letter = |c: u8| u16::from(c - b'@');
+    let vendor = (letter(id[0]) << 10) | (letter(id[1]) << 5) | letter(id[2]);
+    let hex = |c: u8| (c as char).to_digit(16).unwrap() as u8;
+    let [vendor_high, vendor_low] = vendor.to_be_bytes();
+    [
+        vendor_high,
+        vendor_low,
+        (hex(id[3]) << 4) | hex(id[4]),
+        (hex(id[5]) << 4) | hex(id[6]),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// The table at guest address `addr`, checked against its signature and checksum.
+    fn table<'a>(tables: &'a [u8], addr: u64, signature: &[u8]) -> &'a [u8] {
+        let offset = (addr - ACPI_START) as usize;
+        assert_eq!(offset % TABLE_ALIGNMENT, 0);
+        let length = read_u32(tables, offset + 4) as usize;
+        let table = &tables[offset..offset + length];
+        assert_eq!(&table[..4], signature);
+        assert_eq!(checksum(table), 0);
+        table
+    }
+
+    #[test]
+    fn test_tables() {
+        let tables = tables(4, 5, None);
+
+        let rsdp = &tables[..RSDP_SIZE];
+        assert_eq!(&rsdp[..8], b"RSD PTR ");
+        assert_eq!(checksum(&rsdp[..20]), 0);
+        assert_eq!(checksum(rsdp), 0);
+
+        let xsdt = table(&tables, read_u64(rsdp, 24), b"XSDT");
+        assert_eq!(xsdt.len(), HEADER_SIZE + 16);
+        let fadt = table(&tables, read_u64(xsdt, HEADER_SIZE), b"FACP");
+        assert_eq!(fadt.len(), FADT_SIZE);
+        assert_ne!(read_u32(fadt, 112) & FADT_F_HW_REDUCED_ACPI, 0);
+        let dsdt = table(&tables, read_u64(fadt, 140), b"DSDT");
+        assert!(dsdt.windows(4).any(|name| name == b"PWRB"));
+        assert!(dsdt.windows(8).any(|name| name == b"ACPI0013"));
+        assert!(dsdt.windows(4).any(|name| name == b"_S5_"));
+        assert!(!dsdt.windows(8).any(|name| name == b"ACPI0007"));
+        assert_eq!(&fadt[244..256], sleep_register());
+
+        let madt = table(&tables, read_u64(xsdt, HEADER_SIZE + 8), b"APIC");
+        // 4 local APICs, the IOAPIC (ID 5) and the NMI entry.
+        assert_eq!(madt.len(), HEADER_SIZE + 8 + 4 * 8 + 12 + 6);
+        assert_eq!(&madt[HEADER_SIZE + 8 + 3 * 8..][..4], [0, 8, 3, 3]);
+        assert_eq!(madt[HEADER_SIZE + 8 + 4 * 8 + 2], 5);
+
+        let hotplug = CpuHotplug {
+            max_cpus: 254,
+            gsi: 6,
+        };
+        assert!(super::tables(254, 23, Some(&hotplug)).len() <= ACPI_SIZE);
+    }
+
+    #[test]
+    fn test_hotplug_tables() {
+        let hotplug = CpuHotplug {
+            max_cpus: 4,
+            gsi: 6,
+        };
+        let tables = tables(2, 5, Some(&hotplug));
+        let xsdt = table(&tables, read_u64(&tables, 24), b"XSDT");
+        let fadt = table(&tables, read_u64(xsdt, HEADER_SIZE), b"FACP");
+        let madt = table(&tables, read_u64(xsdt, HEADER_SIZE + 8), b"APIC");
+
+        // All 4 local APICs are listed, the last 2 disabled but online capable.
+        let lapic = |cpu_id: usize| &madt[HEADER_SIZE + 8 + cpu_id * 8..][..8];
+        assert_eq!(read_u32(lapic(1), 4), MADT_LOCAL_APIC_ENABLED);
+        assert_eq!(lapic(2)[..4], [MADT_LOCAL_APIC, 8, 2, 2]);
+        assert_eq!(read_u32(lapic(3), 4), MADT_LOCAL_APIC_ONLINE_CAPABLE);
+        assert_eq!(madt[HEADER_SIZE + 8 + 4 * 8 + 2], 5);
+
+        let dsdt = table(&tables, read_u64(fadt, 140), b"DSDT");
+        for name in [b"CPUS", b"C000", b"C003", b"CSTA", b"CSCN"] {
+            assert!(dsdt.windows(4).any(|window| window == name));
+        }
+        assert!(!dsdt.windows(4).any(|window| window == b"C004"));
+        // Both GSIs are GED interrupts.
+        let interrupt = |gsi: u32| {
+            let mut descriptor = vec![
+                EXTENDED_INTERRUPT,
+                6,
+                0,
+                EXTENDED_INTERRUPT_CONSUMER_EDGE,
+                1,
+            ];
+            descriptor.extend(gsi.to_le_bytes());
+            descriptor
+        };
+        let mut resources = interrupt(5);
+        resources.extend(interrupt(6));
+        assert!(dsdt
+            .windows(resources.len())
+            .any(|window| window == resources.as_slice()));
+    }
+
+    #[test]
+    fn test_cpu_status() {
+        let status = CpuStatus::new(2);
+        let read = |port: u16| {
+            let mut data = [0xff];
+            status.pio_read(port, &mut data);
+            data[0]
+        };
+        assert!(CpuStatus::handles(CPU_STATUS_PORT + 1));
+        assert!(!CpuStatus::handles(SLEEP_CONTROL_PORT));
+
+        status.pio_write(CPU_STATUS_PORT, &[1]);
+        assert_eq!(read(CPU_STATUS_PORT), 1);
+        assert_eq!(read(CPU_STATUS_PORT + 1), STA_PRESENT);
+        status.pio_write(CPU_STATUS_PORT, &[2]);
+        assert_eq!(read(CPU_STATUS_PORT + 1), 0);
+        status.set_present(3);
+        assert_eq!(read(CPU_STATUS_PORT + 1), STA_PRESENT);
+    }
+
+    #[test]
+    fn test_is_poweroff() {
+        assert!(is_poweroff(&[
+            SLEEP_ENABLE | (S5_SLEEP_TYPE << SLEEP_TYPE_SHIFT)
+        ]));
+        // Clearing the wake status, or a sleep type without the enable bit.
+        assert!(!is_poweroff(&[0x80]));
+        assert!(!is_poweroff(&[S5_SLEEP_TYPE << SLEEP_TYPE_SHIFT]));
+        assert!(!is_poweroff(&[]));
+    }
+
+    #[test]
+    fn test_aml_encoding() {
+        assert_eq!(eisa_id(*b"PNP0C0C"), [0x41, 0xd0, 0x0c, 0x0c]);
+        assert_eq!(package(b"AB", &[0; 3])[0], 6);
+        let long = package(b"", &[0; 100]);
+        assert_eq!(long.len(), 102);
+        assert_eq!(long[..2], [0x46, 0x06]);
+        let longer = package(b"", &[0; 5000]);
+        assert_eq!(longer.len(), 5003);
+        assert_eq!(longer[..3], [0x8b, 0x38, 0x01]);
+        assert_eq!(
+            root_path(&[b"_SB_", b"CPUS", b"CSCN"])[..3],
+            [b'\\', 0x2f, 3]
+        );
+    }
+}
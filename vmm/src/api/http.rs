@@ -0,0 +1,243 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Just enough HTTP/1.1 for the API clients: requests one after the other on a
+//! connection, bodies sized by `Content-Length`, no chunked encoding.
+
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+
+/// Largest request line and headers accepted, together.
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+/// Largest body accepted, API bodies are small JSON documents.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The request is not valid HTTP/1.x, or uses a feature left out.
+    Malformed(&'static str),
+    /// The head or the body is past its limit.
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Malformed(reason) => write!(f, "malformed request: {}", reason),
+            Error::TooLarge => write!(f, "request too large"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// Path, without the query string.
+    pub path: String,
+    pub body: Vec<u8>,
+    /// Whether the client sends more requests on the connection.
+    pub keep_alive: bool,
+}
+
+/// Reads the next request from `reader`, `None` when the client closed the
+/// connection instead.
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>, Error> {
+    let mut budget = MAX_HEAD_BYTES;
+    let request_line = match read_line(reader, &mut budget)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(Error::Malformed("invalid request line")),
+    };
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(Error::Malformed("unsupported HTTP version")),
+    };
+
+    let mut content_length = 0;
+    loop {
+        let line =
+            read_line(reader, &mut budget)?.ok_or(Error::Malformed("unexpected end of headers"))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(Error::Malformed("invalid header"))?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| Error::Malformed("invalid Content-Length"))?;
+            }
+            "transfer-encoding" => {
+                return Err(Error::Malformed("chunked bodies are not supported"))
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            _ => {}
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Err(Error::TooLarge);
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Some(Request {
+        method: method.to_string(),
+        path: target.split('?').next().unwrap_or(target).to_string(),
+        body,
+        keep_alive,
+    }))
+}
+
+/// Reads a line of the head, without its line ending. `None` at the end of the
+/// stream, an error past `budget` bytes in the head.
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Option<String>, Error> {
+    let mut line = Vec::new();
+    let read = reader
+        .by_ref()
+        .take(*budget as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read > *budget {
+        return Err(Error::TooLarge);
+    }
+    *budget -= read;
+    if line.last() != Some(&b'\n') {
+        return Err(Error::Malformed("unexpected end of request"));
+    }
+    let line = String::from_utf8(line).map_err(|_| Error::Malformed("head is not UTF-8"))?;
+    Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()))
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    /// JSON document, if any.
+    pub body: Option<String>,
+}
+
+impl Response {
+    pub fn no_content() -> Self {
+        Response {
+            status: 204,
+            body: None,
+        }
+    }
+
+    pub fn json(status: u16, body: String) -> Self {
+        Response {
+            status,
+            body: Some(body),
+        }
+    }
+
+    /// Writes the response, telling the client whether the connection stays open.
+    pub fn write_to<W: Write>(&self, writer: &mut W, keep_alive: bool) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        if let Some(body) = &self.body {
+            head.push_str("Content-Type: application/json\r\n");
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        if !keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+        if let Some(body) = &self.body {
+            writer.write_all(body.as_bytes())?;
+        }
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_requests() {
+        let mut stream = &b"PUT /drives/rootfs?x=1 HTTP/1.1\r\nHost: localhost\r\n\
+            content-length: 2\r\n\r\n{}GET / HTTP/1.0\r\n\r\n"[..];
+
+        assert_eq!(
+            read_request(&mut stream).unwrap(),
+            Some(Request {
+                method: "PUT".to_string(),
+                path: "/drives/rootfs".to_string(),
+                body: b"{}".to_vec(),
+                keep_alive: true,
+            })
+        );
+        let request = read_request(&mut stream).unwrap().unwrap();
+        assert_eq!(request.path, "/");
+        assert!(request.body.is_empty());
+        assert!(!request.keep_alive);
+        assert!(read_request(&mut stream).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let read = |raw: &str| read_request(&mut raw.as_bytes());
+        assert!(matches!(read("GET /\r\n\r\n"), Err(Error::Malformed(_))));
+        assert!(matches!(
+            read("PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            Err(Error::Malformed(_))
+        ));
+        assert!(matches!(
+            read("PUT / HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n"),
+            Err(Error::TooLarge)
+        ));
+        let long_header = format!(
+            "GET / HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_HEAD_BYTES)
+        );
+        assert!(matches!(read(&long_header), Err(Error::TooLarge)));
+        assert!(matches!(
+            read("GET / HTTP/1.1\r\nHost"),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut out = Vec::new();
+        Response::json(400, "{}".to_string())
+            .write_to(&mut out, false)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\n\
+             Content-Length: 2\r\nConnection: close\r\n\r\n{}"
+        );
+
+        let mut out = Vec::new();
+        Response::no_content().write_to(&mut out, true).unwrap();
+        assert_eq!(out, b"HTTP/1.1 204 No Content\r\n\r\n");
+    }
+}
@@ -0,0 +1,302 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Optional API compatible with a subset of Firecracker's, served on a Unix
+//! socket of the VM, so that Firecracker tooling can drive the VMM.
+//!
+//! The VM is described with `PUT /machine-config`, `/boot-source`,
+//! `/drives/{id}` and `/network-interfaces/{id}`, then booted with the
+//! `InstanceStart` action of `PUT /actions`. Once it runs, only `GET` requests
+//! and the `SendCtrlAltDel` action are served. Errors are `400` responses with a
+//! `fault_message`, like Firecracker's.
+
+mod http;
+mod models;
+
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use log::{error, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use self::http::{Request, Response};
+use self::models::{
+    ActionType, BootPlan, BootSource, Drive, Fault, InstanceActionInfo, InstanceInfo,
+    MachineConfig, NetworkInterface, VmSpec,
+};
+use crate::{NetBackend, PowerButton, VMM};
+
+/// Name the API reports in `GET /`.
+const APP_NAME: &str = "cloude";
+/// Id the API reports in `GET /`, the one Firecracker uses without `--id`.
+const INSTANCE_ID: &str = "anonymous-instance";
+
+#[derive(Debug)]
+pub enum Error {
+    /// The socket cannot be created, or it already exists.
+    Bind(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bind(e) => write!(f, "cannot bind the API socket: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Serves the API of a single VM on a Unix socket.
+pub struct ApiServer {
+    socket_path: PathBuf,
+}
+
+impl ApiServer {
+    pub fn new(socket_path: &Path) -> Self {
+        ApiServer {
+            socket_path: socket_path.to_path_buf(),
+        }
+    }
+
+    /// Serve the API until the VM it booted stops, then remove the socket.
+    ///
+    /// Blocks the calling thread. The VM console is the standard input and output
+    /// of the process, as with Firecracker.
+    pub fn run(self) -> Result<(), Error> {
+        let listener = UnixListener::bind(&self.socket_path).map_err(Error::Bind)?;
+        let (started_tx, started_rx) = mpsc::channel();
+        let mut state = ApiState {
+            spec: VmSpec::default(),
+            vm: None,
+            started: started_tx,
+        };
+        thread::Builder::new()
+            .name("api".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => state.serve(stream),
+                        Err(e) => warn!("Cannot accept an API connection: {}", e),
+                    }
+                }
+            })
+            .map_err(Error::Bind)?;
+
+        // Connections are served until `InstanceStart`, then the VM runs here.
+        if let Ok(vm_thread) = started_rx.recv() {
+            let _ = vm_thread.join();
+        }
+        let _ = fs::remove_file(&self.socket_path);
+        Ok(())
+    }
+}
+
+/// The VM, before and after it starts.
+struct ApiState {
+    spec: VmSpec,
+    /// Set once the VM runs.
+    vm: Option<RunningVm>,
+    /// Where the thread of the VM goes once it is started.
+    started: mpsc::Sender<thread::JoinHandle<()>>,
+}
+
+struct RunningVm {
+    power_button: Option<PowerButton>,
+}
+
+impl ApiState {
+    /// Answers the requests of a connection, one after the other.
+    fn serve(&mut self, stream: UnixStream) {
+        let mut reader = BufReader::new(&stream);
+        let mut writer = &stream;
+        loop {
+            let request = match http::read_request(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => return,
+                Err(e) => {
+                    let _ = fault(e.to_string()).write_to(&mut writer, false);
+                    return;
+                }
+            };
+            let response = self.handle(&request);
+            if response.write_to(&mut writer, request.keep_alive).is_err() || !request.keep_alive {
+                return;
+            }
+        }
+    }
+
+    fn handle(&mut self, request: &Request) -> Response {
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", [""]) => json(&InstanceInfo {
+                app_name: APP_NAME.to_string(),
+                id: INSTANCE_ID.to_string(),
+                state: match self.vm {
+                    Some(_) => "Running",
+                    None => "Not started",
+                }
+                .to_string(),
+                vmm_version: env!("CARGO_PKG_VERSION").to_string(),
+            }),
+            ("GET", ["machine-config"]) => json(&self.spec.machine_config),
+            ("PUT", ["machine-config"]) => self
+                .configure(request, |spec, config: MachineConfig| {
+                    spec.set_machine_config(config)
+                }),
+            ("PUT", ["boot-source"]) => self.configure(request, |spec, source: BootSource| {
+                spec.set_boot_source(source)
+            }),
+            ("PUT", ["drives", id]) => {
+                self.configure(request, |spec, drive: Drive| spec.put_drive(id, drive))
+            }
+            ("PUT", ["network-interfaces", id]) => self
+                .configure(request, |spec, interface: NetworkInterface| {
+                    spec.put_network_interface(id, interface)
+                }),
+            ("PUT", ["actions"]) => match parse::<InstanceActionInfo>(&request.body) {
+                Ok(action) => self.act(action.action_type),
+                Err(response) => response,
+            },
+            _ => fault(format!(
+                "Invalid request method and/or path: {} {}",
+                request.method, request.path
+            )),
+        }
+    }
+
+    /// Applies a request describing the VM, which is only possible before it starts.
+    fn configure<T, F>(&mut self, request: &Request, apply: F) -> Response
+    where
+        T: DeserializeOwned,
+        F: FnOnce(&mut VmSpec, T) -> Result<(), models::ConfigError>,
+    {
+        if self.vm.is_some() {
+            return fault(
+                "The requested operation is not supported after starting the microVM.".to_string(),
+            );
+        }
+        let body = match parse(&request.body) {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+        match apply(&mut self.spec, body) {
+            Ok(()) => Response::no_content(),
+            Err(e) => fault(e.to_string()),
+        }
+    }
+
+    fn act(&mut self, action: ActionType) -> Response {
+        let power_button = self.vm.as_ref().map(|vm| vm.power_button.as_ref());
+        let result = match (action, power_button) {
+            (ActionType::InstanceStart, None) => self.start(),
+            (ActionType::InstanceStart, Some(_)) => {
+                Err("The microVM is already running".to_string())
+            }
+            (ActionType::SendCtrlAltDel, Some(Some(power_button))) => power_button
+                .press()
+                .map_err(|e| format!("Cannot press the power button: {}", e)),
+            (ActionType::SendCtrlAltDel, Some(None)) => {
+                Err("The microVM has no power button".to_string())
+            }
+            (ActionType::SendCtrlAltDel, None) => Err("The microVM is not running".to_string()),
+            (ActionType::FlushMetrics, _) => Err("Metrics are not supported".to_string()),
+        };
+        match result {
+            Ok(()) => Response::no_content(),
+            Err(e) => fault(e),
+        }
+    }
+
+    /// Boots the VM on a thread of its own, and hands the thread to `run`.
+    fn start(&mut self) -> Result<(), String> {
+        let plan = self.spec.boot_plan().map_err(|e| e.to_string())?;
+        let (setup_tx, setup_rx) = mpsc::channel();
+        let vm_thread = thread::Builder::new()
+            .name("vmm".to_string())
+            .spawn(move || {
+                let mut vmm = match boot(plan) {
+                    Ok(vmm) => vmm,
+                    Err(e) => {
+                        let _ = setup_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = setup_tx.send(Ok(vmm.power_button_handle()));
+                vmm.run();
+                if let Some(reason) = vmm.failure() {
+                    error!("The VM stopped on an error: {}", reason);
+                }
+            })
+            .map_err(|e| format!("Cannot start the VM thread: {}", e))?;
+
+        match setup_rx.recv() {
+            Ok(Ok(power_button)) => {
+                self.vm = Some(RunningVm { power_button });
+                let _ = self.started.send(vm_thread);
+                Ok(())
+            }
+            Ok(Err(e)) => Err(format!("Cannot start the microVM: {:?}", e)),
+            Err(_) => Err("The VM thread exited while starting the microVM".to_string()),
+        }
+    }
+}
+
+/// Creates and configures the VMM as `plan` says. ACPI is on, for `SendCtrlAltDel`.
+fn boot(plan: BootPlan) -> crate::Result<VMM> {
+    let mut vmm = VMM::new(
+        Some(Box::new(io::stdin())),
+        Box::new(io::stdout()),
+        plan.memory_mib << 20,
+    )?;
+    if let Some(tap_name) = plan.tap_name {
+        let ip = plan.ip;
+        vmm.add_net_device(
+            NetBackend::Tap(tap_name).into(),
+            ip.as_ref().map(|ip| ip.guest),
+            ip.as_ref().map(|ip| ip.gateway),
+            ip.as_ref().map(|ip| ip.netmask),
+        )?;
+    }
+    for drive in &plan.drives {
+        vmm.add_block_device(&drive.path_on_host, drive.is_read_only)?;
+    }
+    if let Some(extra) = &plan.cmdline_extra {
+        vmm.append_cmdline(extra)?;
+    }
+    vmm.set_acpi(true);
+    vmm.configure(
+        plan.vcpus,
+        &plan.kernel_path.to_string_lossy(),
+        plan.initrd_path
+            .as_ref()
+            .map(|path| path.to_string_lossy())
+            .as_deref(),
+        plan.init_path.as_deref(),
+    )?;
+    Ok(vmm)
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, Response> {
+    serde_json::from_slice(body).map_err(|e| fault(format!("Invalid request body: {}", e)))
+}
+
+fn json<T: Serialize>(body: &T) -> Response {
+    match serde_json::to_string(body) {
+        Ok(body) => Response::json(200, body),
+        Err(e) => Response::json(500, fault_body(e.to_string())),
+    }
+}
+
+fn fault(fault_message: String) -> Response {
+    Response::json(400, fault_body(fault_message))
+}
+
+fn fault_body(fault_message: String) -> String {
+    serde_json::to_string(&Fault { fault_message }).unwrap_or_default()
+}
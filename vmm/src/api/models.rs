@@ -0,0 +1,515 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bodies of the Firecracker requests the API understands, and the VM they
+//! describe until it starts.
+
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmdline;
+
+/// vCPUs and memory of a VM before `PUT /machine-config`, as in Firecracker.
+const DEFAULT_VCPUS: u8 = 1;
+const DEFAULT_MEM_SIZE_MIB: usize = 128;
+/// Most vCPUs Firecracker lets a VM have.
+const MAX_VCPUS: u8 = 32;
+
+/// Body of `PUT /machine-config`, and response of `GET /machine-config`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineConfig {
+    pub vcpu_count: u8,
+    pub mem_size_mib: usize,
+    /// Simultaneous multithreading, not supported.
+    #[serde(default)]
+    pub smt: bool,
+    /// Dirty page tracking for diff snapshots, not supported.
+    #[serde(default)]
+    pub track_dirty_pages: bool,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            vcpu_count: DEFAULT_VCPUS,
+            mem_size_mib: DEFAULT_MEM_SIZE_MIB,
+            smt: false,
+            track_dirty_pages: false,
+        }
+    }
+}
+
+/// Body of `PUT /boot-source`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BootSource {
+    pub kernel_image_path: PathBuf,
+    #[serde(default)]
+    pub initrd_path: Option<PathBuf>,
+    /// Kernel parameters. `root=` is derived from the root drive, `init=` and
+    /// `rdinit=` give the init path, `ip=` the guest address.
+    #[serde(default)]
+    pub boot_args: Option<String>,
+}
+
+/// Body of `PUT /drives/{drive_id}`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Drive {
+    pub drive_id: String,
+    pub path_on_host: PathBuf,
+    pub is_root_device: bool,
+    #[serde(default)]
+    pub is_read_only: bool,
+}
+
+/// Body of `PUT /network-interfaces/{iface_id}`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkInterface {
+    pub iface_id: String,
+    /// TAP device, created if it does not exist.
+    pub host_dev_name: String,
+    /// Not supported: the device has no MAC address, the guest picks one.
+    #[serde(default)]
+    pub guest_mac: Option<String>,
+}
+
+/// Body of `PUT /actions`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceActionInfo {
+    pub action_type: ActionType,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum ActionType {
+    InstanceStart,
+    /// Presses the ACPI power button, where Firecracker sends Ctrl+Alt+Del.
+    SendCtrlAltDel,
+    FlushMetrics,
+}
+
+/// Response of `GET /`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct InstanceInfo {
+    pub app_name: String,
+    pub id: String,
+    /// `Not started` or `Running`.
+    pub state: String,
+    pub vmm_version: String,
+}
+
+/// Body of every error response.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Fault {
+    pub fault_message: String,
+}
+
+/// A request that cannot be applied to the VM.
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    /// The id in the path is not the one in the body.
+    IdMismatch {
+        path: String,
+        body: String,
+    },
+    /// A Firecracker feature the VMM does not have.
+    Unsupported(&'static str),
+    VcpuCount(u8),
+    MemorySize,
+    /// Only one drive can be the root device.
+    SecondRootDevice(String),
+    /// The VMM has a single network device.
+    SecondNetworkInterface(String),
+    /// A file given as a path cannot be read.
+    File {
+        path: PathBuf,
+        error: String,
+    },
+    NoBootSource,
+    BootArgs(cmdline::Error),
+    /// An `ip=` other than `<guest>::<gateway>:<netmask>:...`.
+    IpConfig(String),
+    IpWithoutNetwork,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::IdMismatch { path, body } => {
+                write!(
+                    f,
+                    "The id from the path [{}] does not match the id from the body [{}]",
+                    path, body
+                )
+            }
+            ConfigError::Unsupported(feature) => write!(f, "{} is not supported", feature),
+            ConfigError::VcpuCount(count) => {
+                write!(
+                    f,
+                    "Invalid vcpu_count {}, it must be 1 to {}",
+                    count, MAX_VCPUS
+                )
+            }
+            ConfigError::MemorySize => write!(f, "mem_size_mib must not be 0"),
+            ConfigError::SecondRootDevice(id) => {
+                write!(f, "Drive {} is already the root device", id)
+            }
+            ConfigError::SecondNetworkInterface(id) => {
+                write!(
+                    f,
+                    "Network interface {} is already set, only one is supported",
+                    id
+                )
+            }
+            ConfigError::File { path, error } => {
+                write!(f, "Cannot open {}: {}", path.display(), error)
+            }
+            ConfigError::NoBootSource => {
+                write!(f, "Cannot start the microVM without a boot source")
+            }
+            ConfigError::BootArgs(e) => write!(f, "Invalid boot_args: {}", e),
+            ConfigError::IpConfig(param) => write!(
+                f,
+                "Unsupported {}, only ip=<guest>::<gateway>:<netmask>::eth0:off is",
+                param
+            ),
+            ConfigError::IpWithoutNetwork => {
+                write!(
+                    f,
+                    "boot_args set ip= but no network interface is configured"
+                )
+            }
+        }
+    }
+}
+
+/// Address of the guest, and its gateway on the host, from an `ip=` parameter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuestIp {
+    pub guest: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+/// Everything the VMM needs to boot the VM.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootPlan {
+    pub vcpus: u8,
+    pub memory_mib: usize,
+    pub kernel_path: PathBuf,
+    pub initrd_path: Option<PathBuf>,
+    pub init_path: Option<String>,
+    /// Kernel parameters left once the ones the VMM derives are taken out.
+    pub cmdline_extra: Option<String>,
+    /// Drives in the order the guest names them, the root device first.
+    pub drives: Vec<Drive>,
+    pub tap_name: Option<String>,
+    pub ip: Option<GuestIp>,
+}
+
+/// The VM described by the requests so far.
+#[derive(Default)]
+pub struct VmSpec {
+    pub machine_config: MachineConfig,
+    boot_source: Option<BootSource>,
+    drives: Vec<Drive>,
+    network_interface: Option<NetworkInterface>,
+}
+
+impl VmSpec {
+    pub fn set_machine_config(&mut self, config: MachineConfig) -> Result<(), ConfigError> {
+        if config.vcpu_count == 0 || config.vcpu_count > MAX_VCPUS {
+            return Err(ConfigError::VcpuCount(config.vcpu_count));
+        }
+        if config.mem_size_mib == 0 {
+            return Err(ConfigError::MemorySize);
+        }
+        if config.smt {
+            return Err(ConfigError::Unsupported("smt"));
+        }
+        if config.track_dirty_pages {
+            return Err(ConfigError::Unsupported("track_dirty_pages"));
+        }
+        self.machine_config = config;
+        Ok(())
+    }
+
+    pub fn set_boot_source(&mut self, source: BootSource) -> Result<(), ConfigError> {
+        check_file(&source.kernel_image_path)?;
+        if let Some(initrd_path) = &source.initrd_path {
+            check_file(initrd_path)?;
+        }
+        self.boot_source = Some(source);
+        Ok(())
+    }
+
+    /// Adds the drive `id`, or replaces it.
+    pub fn put_drive(&mut self, id: &str, drive: Drive) -> Result<(), ConfigError> {
+        if drive.drive_id != id {
+            return Err(ConfigError::IdMismatch {
+                path: id.to_string(),
+                body: drive.drive_id,
+            });
+        }
+        if let Some(root) = self
+            .drives
+            .iter()
+            .find(|other| other.is_root_device && other.drive_id != id)
+            .filter(|_| drive.is_root_device)
+        {
+            return Err(ConfigError::SecondRootDevice(root.drive_id.clone()));
+        }
+        check_file(&drive.path_on_host)?;
+        match self.drives.iter_mut().find(|other| other.drive_id == id) {
+            Some(other) => *other = drive,
+            None => self.drives.push(drive),
+        }
+        Ok(())
+    }
+
+    /// Sets the network interface `id`, or replaces it.
+    pub fn put_network_interface(
+        &mut self,
+        id: &str,
+        interface: NetworkInterface,
+    ) -> Result<(), ConfigError> {
+        if interface.iface_id != id {
+            return Err(ConfigError::IdMismatch {
+                path: id.to_string(),
+                body: interface.iface_id,
+            });
+        }
+        if interface.guest_mac.is_some() {
+            return Err(ConfigError::Unsupported("guest_mac"));
+        }
+        if let Some(other) = self
+            .network_interface
+            .as_ref()
+            .filter(|other| other.iface_id != id)
+        {
+            return Err(ConfigError::SecondNetworkInterface(other.iface_id.clone()));
+        }
+        self.network_interface = Some(interface);
+        Ok(())
+    }
+
+    /// What to boot, once the VM is fully described.
+    pub fn boot_plan(&self) -> Result<BootPlan, ConfigError> {
+        let boot_source = self.boot_source.as_ref().ok_or(ConfigError::NoBootSource)?;
+
+        let mut init_path = None;
+        let mut ip = None;
+        let mut extra = Vec::new();
+        let params = match boot_source.boot_args.as_deref() {
+            Some(args) if !args.trim().is_empty() => {
+                cmdline::split(args).map_err(ConfigError::BootArgs)?
+            }
+            _ => Vec::new(),
+        };
+        for param in params {
+            let value = param.split_once('=').map(|(_, value)| value);
+            match (cmdline::key(&param), value) {
+                // The VMM sets the root device from the drives.
+                ("root", _) => {}
+                ("init", Some(path)) | ("rdinit", Some(path)) => init_path = Some(path.to_string()),
+                ("ip", Some(config)) => {
+                    ip = Some(parse_ip(config).ok_or_else(|| ConfigError::IpConfig(param.clone()))?)
+                }
+                _ => extra.push(param),
+            }
+        }
+        if ip.is_some() && self.network_interface.is_none() {
+            return Err(ConfigError::IpWithoutNetwork);
+        }
+        let cmdline_extra = (!extra.is_empty()).then(|| extra.join(" "));
+        if let Some(extra) = &cmdline_extra {
+            cmdline::parse(extra).map_err(ConfigError::BootArgs)?;
+        }
+
+        let mut drives = self.drives.clone();
+        drives.sort_by_key(|drive| !drive.is_root_device);
+
+        Ok(BootPlan {
+            vcpus: self.machine_config.vcpu_count,
+            memory_mib: self.machine_config.mem_size_mib,
+            kernel_path: boot_source.kernel_image_path.clone(),
+            initrd_path: boot_source.initrd_path.clone(),
+            init_path,
+            cmdline_extra,
+            drives,
+            tap_name: self
+                .network_interface
+                .as_ref()
+                .map(|interface| interface.host_dev_name.clone()),
+            ip,
+        })
+    }
+}
+
+/// The static configuration of `ip=<guest>:<server>:<gateway>:<netmask>:<hostname>:<device>:<autoconf>`.
+fn parse_ip(config: &str) -> Option<GuestIp> {
+    let fields: Vec<&str> = config.split(':').collect();
+    let field = |index: usize| fields.get(index)?.parse().ok();
+    Some(GuestIp {
+        guest: field(0)?,
+        gateway: field(2)?,
+        netmask: field(3)?,
+    })
+}
+
+fn check_file(path: &Path) -> Result<(), ConfigError> {
+    std::fs::File::open(path)
+        .map(|_| ())
+        .map_err(|e| ConfigError::File {
+            path: path.to_path_buf(),
+            error: e.to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file every test host has, to stand for a kernel or a disk.
+    const FILE: &str = "/proc/self/status";
+
+    fn drive(id: &str, is_root_device: bool) -> Drive {
+        Drive {
+            drive_id: id.to_string(),
+            path_on_host: PathBuf::from(FILE),
+            is_root_device,
+            is_read_only: false,
+        }
+    }
+
+    fn boot_source(boot_args: &str) -> BootSource {
+        BootSource {
+            kernel_image_path: PathBuf::from(FILE),
+            initrd_path: None,
+            boot_args: Some(boot_args.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_parse_bodies() {
+        let config: MachineConfig =
+            serde_json::from_str(r#"{"vcpu_count": 2, "mem_size_mib": 1024}"#).unwrap();
+        assert_eq!(config.vcpu_count, 2);
+        assert!(!config.smt);
+
+        let action: InstanceActionInfo =
+            serde_json::from_str(r#"{"action_type": "InstanceStart"}"#).unwrap();
+        assert_eq!(action.action_type, ActionType::InstanceStart);
+
+        let unknown = serde_json::from_str::<Drive>(
+            r#"{"drive_id": "a", "path_on_host": "/a", "is_root_device": true, "rate_limiter": {}}"#,
+        );
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn test_machine_config_limits() {
+        let mut spec = VmSpec::default();
+        let config = |vcpu_count, smt| MachineConfig {
+            vcpu_count,
+            mem_size_mib: 256,
+            smt,
+            track_dirty_pages: false,
+        };
+        assert_eq!(
+            spec.set_machine_config(config(0, false)),
+            Err(ConfigError::VcpuCount(0))
+        );
+        assert_eq!(
+            spec.set_machine_config(config(2, true)),
+            Err(ConfigError::Unsupported("smt"))
+        );
+        assert_eq!(spec.machine_config, MachineConfig::default());
+        assert!(spec.set_machine_config(config(2, false)).is_ok());
+        assert_eq!(spec.machine_config.vcpu_count, 2);
+    }
+
+    #[test]
+    fn test_drives_and_interfaces() {
+        let mut spec = VmSpec::default();
+        assert!(spec.put_drive("scratch", drive("scratch", false)).is_ok());
+        assert!(spec.put_drive("rootfs", drive("rootfs", true)).is_ok());
+        assert_eq!(
+            spec.put_drive("other", drive("other", true)),
+            Err(ConfigError::SecondRootDevice("rootfs".to_string()))
+        );
+        assert!(matches!(
+            spec.put_drive("a", drive("b", false)),
+            Err(ConfigError::IdMismatch { .. })
+        ));
+        // Putting a drive again replaces it.
+        assert!(spec.put_drive("rootfs", drive("rootfs", true)).is_ok());
+
+        let interface = |id: &str| NetworkInterface {
+            iface_id: id.to_string(),
+            host_dev_name: "tap0".to_string(),
+            guest_mac: None,
+        };
+        assert!(spec
+            .put_network_interface("eth0", interface("eth0"))
+            .is_ok());
+        assert_eq!(
+            spec.put_network_interface("eth1", interface("eth1")),
+            Err(ConfigError::SecondNetworkInterface("eth0".to_string()))
+        );
+
+        spec.set_boot_source(boot_source("console=ttyS0")).unwrap();
+        let plan = spec.boot_plan().unwrap();
+        let ids: Vec<&str> = plan.drives.iter().map(|d| d.drive_id.as_str()).collect();
+        assert_eq!(ids, vec!["rootfs", "scratch"]);
+        assert_eq!(plan.tap_name.as_deref(), Some("tap0"));
+    }
+
+    #[test]
+    fn test_boot_args() {
+        let mut spec = VmSpec::default();
+        assert_eq!(spec.boot_plan(), Err(ConfigError::NoBootSource));
+
+        spec.set_boot_source(boot_source(
+            "console=ttyS0 reboot=k root=/dev/vda init=/sbin/init \
+             ip=172.16.0.2::172.16.0.1:255.255.255.0::eth0:off",
+        ))
+        .unwrap();
+        assert_eq!(spec.boot_plan(), Err(ConfigError::IpWithoutNetwork));
+
+        spec.put_network_interface(
+            "eth0",
+            NetworkInterface {
+                iface_id: "eth0".to_string(),
+                host_dev_name: "tap0".to_string(),
+                guest_mac: None,
+            },
+        )
+        .unwrap();
+        let plan = spec.boot_plan().unwrap();
+        assert_eq!(
+            plan.cmdline_extra.as_deref(),
+            Some("console=ttyS0 reboot=k")
+        );
+        assert_eq!(plan.init_path.as_deref(), Some("/sbin/init"));
+        assert_eq!(
+            plan.ip,
+            Some(GuestIp {
+                guest: Ipv4Addr::new(172, 16, 0, 2),
+                gateway: Ipv4Addr::new(172, 16, 0, 1),
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+            })
+        );
+
+        spec.set_boot_source(boot_source("ip=dhcp")).unwrap();
+        assert!(matches!(spec.boot_plan(), Err(ConfigError::IpConfig(_))));
+        spec.set_boot_source(boot_source("virtio_mmio.device=4K@0x1000:5"))
+            .unwrap();
+        assert!(matches!(spec.boot_plan(), Err(ConfigError::BootArgs(_))));
+    }
+}
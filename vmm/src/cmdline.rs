@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Kernel parameters given by VMM users, checked before they are merged with
+//! the ones the VMM generates for its devices and root filesystem.
+
+use std::fmt;
+
+/// Size of the x86 kernel command line buffer (`COMMAND_LINE_SIZE`), NUL included.
+/// The kernel silently truncates longer command lines.
+pub const MAX_LEN: usize = 2048;
+
+/// Parameters the VMM derives from its own configuration: devices, guest address and root filesystem.
+const RESERVED: &[&str] = &["virtio_mmio.device", "ip", "root", "rdinit", "init"];
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The fragment holds no parameter.
+    Empty,
+    /// Only printable ASCII is allowed, parameters are separated by spaces.
+    InvalidCharacter(char),
+    /// A double quote is never closed.
+    UnterminatedQuote,
+    /// The VMM sets this parameter itself.
+    Reserved(String),
+    /// The command line would be this many bytes long, NUL included.
+    TooLong(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Empty => write!(f, "no kernel parameter given"),
+            Error::InvalidCharacter(c) => write!(f, "invalid character {:?}", c),
+            Error::UnterminatedQuote => write!(f, "unterminated double quote"),
+            Error::Reserved(key) => write!(f, "`{}` is set by the VMM", key),
+            Error::TooLong(len) => write!(
+                f,
+                "command line is {} bytes long, the limit is {}",
+                len, MAX_LEN
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Splits `fragment` into parameters like the kernel does: on spaces, except
+/// between double quotes. Parameters the VMM sets itself are rejected.
+pub fn parse(fragment: &str) -> Result<Vec<String>, Error> {
+    let params = split(fragment)?;
+    if let Some(param) = params.iter().find(|param| RESERVED.contains(&key(param))) {
+        return Err(Error::Reserved(key(param).to_string()));
+    }
+    Ok(params)
+}
+
+/// Splits `fragment` like [`parse`], parameters the VMM sets itself included.
+pub fn split(fragment: &str) -> Result<Vec<String>, Error> {
+    if let Some(c) = fragment
+        .chars()
+        .find(|c| !(c.is_ascii_graphic() || *c == ' '))
+    {
+        return Err(Error::InvalidCharacter(c));
+    }
+
+    let mut params = Vec::new();
+    let mut param = String::new();
+    let mut quoted = false;
+    for c in fragment.chars() {
+        match c {
+            ' ' if !quoted => {
+                if !param.is_empty() {
+                    params.push(std::mem::take(&mut param));
+                }
+            }
+            '"' => {
+                quoted = !quoted;
+                param.push(c);
+            }
+            _ => param.push(c),
+        }
+    }
+    if quoted {
+        return Err(Error::UnterminatedQuote);
+    }
+    if !param.is_empty() {
+        params.push(param);
+    }
+
+    if params.is_empty() {
+        return Err(Error::Empty);
+    }
+    Ok(params)
+}
+
+/// Name of a parameter, without its value.
+pub fn key(param: &str) -> &str {
+    param.split('=').next().unwrap_or(param)
+}
+
+/// Command line made of `defaults`, the `generated` device and root parameters,
+/// then the `extra` parameters of the user. An extra parameter replaces the
+/// defaults of the same name, and a parameter given twice is kept once.
+pub(crate) fn merge(
+    defaults: &str,
+    generated: &[String],
+    extra: &[String],
+) -> Result<String, Error> {
+    let mut params: Vec<&str> = Vec::new();
+    let overridden = |param: &&str| extra.iter().any(|extra| key(extra) == key(param));
+    let generated = generated
+        .iter()
+        .flat_map(|fragment| fragment.split_whitespace());
+    let all = defaults
+        .split_whitespace()
+        .filter(|param| !overridden(param))
+        .chain(generated)
+        .chain(extra.iter().map(String::as_str));
+    for param in all {
+        if !params.contains(&param) {
+            params.push(param);
+        }
+    }
+
+    let line = params.join(" ");
+    if line.len() + 1 > MAX_LEN {
+        return Err(Error::TooLong(line.len() + 1));
+    }
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("  quiet  loglevel=3 dyndbg=\"file x.c +p\" ").unwrap(),
+            vec!["quiet", "loglevel=3", "dyndbg=\"file x.c +p\""]
+        );
+        assert_eq!(parse(" "), Err(Error::Empty));
+        assert_eq!(
+            parse("quiet\nroot=/dev/sda"),
+            Err(Error::InvalidCharacter('\n'))
+        );
+        assert_eq!(parse("dyndbg=\"file"), Err(Error::UnterminatedQuote));
+        assert_eq!(
+            parse("quiet root=/dev/sda"),
+            Err(Error::Reserved("root".to_string()))
+        );
+        assert!(parse("rootwait").is_ok());
+        assert_eq!(
+            split("quiet root=/dev/sda").unwrap(),
+            vec!["quiet", "root=/dev/sda"]
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let generated = vec![
+            " virtio_mmio.device=4K@0xd0000000:5".to_string(),
+            " root=/dev/vda rw".to_string(),
+        ];
+        let extra = vec![
+            "panic=5".to_string(),
+            "quiet".to_string(),
+            "quiet".to_string(),
+        ];
+
+        assert_eq!(
+            merge("console=ttyS0 panic=1", &generated, &extra).unwrap(),
+            "console=ttyS0 virtio_mmio.device=4K@0xd0000000:5 root=/dev/vda rw panic=5 quiet"
+        );
+
+        let long = vec!["x".repeat(MAX_LEN)];
+        assert_eq!(
+            merge("console=ttyS0", &[], &long),
+            Err(Error::TooLong(MAX_LEN + 15))
+        );
+    }
+}
@@ -15,6 +15,12 @@ const ECX_TSC_DEADLINE_TIMER_SHIFT: u32 = 24; // TSC deadline mode of APIC timer
 const ECX_HYPERVISOR_SHIFT: u32 = 31; // Flag to be set when the cpu is running on a hypervisor.
 const EDX_HTT_SHIFT: u32 = 28; // Hyper Threading Enabled.
 
+// KVM paravirtual features, in eax.
+const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+const KVM_FEATURE_CLOCKSOURCE_SHIFT: u32 = 0; // kvmclock, legacy MSRs.
+const KVM_FEATURE_CLOCKSOURCE2_SHIFT: u32 = 3; // kvmclock.
+const KVM_FEATURE_CLOCKSOURCE_STABLE_SHIFT: u32 = 24; // kvmclock may be reported stable across vCPUs.
+
 pub(crate) fn filter_cpuid(kvm: &Kvm, vcpu_id: usize, cpu_count: usize, cpuid: &mut CpuId) {
     for entry in cpuid.as_mut_slice().iter_mut() {
         match entry.function {
@@ -37,6 +43,13 @@ pub(crate) fn filter_cpuid(kvm: &Kvm, vcpu_id: usize, cpu_count: usize, cpuid: &
                 // Clear X86 EPB feature. No frequency selection in the hypervisor.
                 entry.ecx &= !(1 << ECX_EPB_SHIFT);
             }
+            KVM_CPUID_FEATURES => {
+                // Expose kvm-clock: the guest reads the time and the TSC frequency
+                // from the host instead of calibrating against emulated timers.
+                entry.eax |= (1 << KVM_FEATURE_CLOCKSOURCE_SHIFT)
+                    | (1 << KVM_FEATURE_CLOCKSOURCE2_SHIFT)
+                    | (1 << KVM_FEATURE_CLOCKSOURCE_STABLE_SHIFT);
+            }
             _ => (),
         }
     }
@@ -1,17 +1,26 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use std::convert::TryInto;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::ops::ControlFlow;
 use std::sync::{Arc, Mutex};
 use std::{result, u64};
 
-use crate::devices::serial::{LumperSerial, SERIAL_PORT_BASE, SERIAL_PORT_LAST};
+use crate::acpi;
+use crate::devices::serial::LumperSerial;
+use crate::devices::virtio::block::device::VirtioBlockDevice;
+use crate::devices::virtio::mem::device::VirtioMemDevice;
+use crate::devices::virtio::mmio::MmioTransport;
 use crate::devices::virtio::net::device::VirtioNetDevice;
+use crate::devices::virtio::rng::device::VirtioRngDevice;
+use crate::StopHandle;
 use kvm_bindings::{kvm_fpu, kvm_regs, CpuId};
 use kvm_ioctls::{VcpuExit, VcpuFd, VmFd};
+use libc::c_ulong;
+use log::debug;
 use virtio_device::VirtioMmioDevice;
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_val};
+use vmm_sys_util::ioctl_io_nr;
 
 pub(crate) mod cpuid;
 mod gdt;
@@ -35,6 +44,11 @@ const X86_CR0_PE: u64 = 0x1;
 const X86_CR0_PG: u64 = 0x8000_0000;
 const X86_CR4_PAE: u64 = 0x20;
 
+// TSC frequency ioctls, on vCPU file descriptors.
+const KVMIO: u32 = 0xAE;
+ioctl_io_nr!(KVM_SET_TSC_KHZ, KVMIO, 0xa2);
+ioctl_io_nr!(KVM_GET_TSC_KHZ, KVMIO, 0xa3);
+
 /// Errors encountered during vCPU operation.
 #[derive(Debug)]
 pub enum Error {
@@ -50,6 +64,10 @@ pub enum Error {
     SetModelSpecificRegistersCount,
     /// Failed to configure MSRs.
     CreateMsr(msrs::Error),
+    /// `KVM_RUN` failed.
+    Run(kvm_ioctls::Error),
+    /// A VM-exit the VMM does not emulate.
+    UnhandledExit(String),
 }
 
 /// Dedicated Result type.
@@ -67,7 +85,11 @@ pub(crate) struct Vcpu {
 
     serial: Arc<Mutex<LumperSerial>>,
     virtio_net: Option<Arc<Mutex<VirtioNetDevice>>>,
-    running: Arc<AtomicBool>,
+    virtio_blocks: Vec<Arc<Mutex<VirtioBlockDevice>>>,
+    virtio_mem: Option<Arc<Mutex<VirtioMemDevice>>>,
+    virtio_rng: Option<Arc<Mutex<VirtioRngDevice>>>,
+    running: StopHandle,
+    cpu_status: Arc<acpi::CpuStatus>,
 }
 
 impl Vcpu {
@@ -77,14 +99,22 @@ impl Vcpu {
         index: u64,
         serial: Arc<Mutex<LumperSerial>>,
         virtio_net: Option<Arc<Mutex<VirtioNetDevice>>>,
-        running: Arc<AtomicBool>,
+        virtio_blocks: Vec<Arc<Mutex<VirtioBlockDevice>>>,
+        virtio_mem: Option<Arc<Mutex<VirtioMemDevice>>>,
+        virtio_rng: Option<Arc<Mutex<VirtioRngDevice>>>,
+        running: StopHandle,
+        cpu_status: Arc<acpi::CpuStatus>,
     ) -> Result<Self> {
         Ok(Vcpu {
             index,
             vcpu_fd: vm_fd.create_vcpu(index).map_err(Error::KvmIoctl)?,
             serial,
             virtio_net,
+            virtio_blocks,
+            virtio_mem,
+            virtio_rng,
             running,
+            cpu_status,
         })
     }
 
@@ -93,6 +123,26 @@ impl Vcpu {
         self.vcpu_fd.set_cpuid2(cpuid).map_err(Error::KvmIoctl)
     }
 
+    /// TSC frequency of the vCPU in kHz, the host's unless it was set.
+    pub fn tsc_khz(&self) -> Result<u32> {
+        // SAFETY: KVM_GET_TSC_KHZ takes no argument and returns the frequency.
+        let ret = unsafe { ioctl(&self.vcpu_fd, KVM_GET_TSC_KHZ()) };
+        if ret < 0 {
+            return Err(Error::KvmIoctl(kvm_ioctls::Error::last()));
+        }
+        Ok(ret as u32)
+    }
+
+    /// Set the TSC frequency of the vCPU, when KVM supports `Cap::TscControl`.
+    pub fn set_tsc_khz(&self, khz: u32) -> Result<()> {
+        // SAFETY: KVM_SET_TSC_KHZ takes the frequency by value.
+        let ret = unsafe { ioctl_with_val(&self.vcpu_fd, KVM_SET_TSC_KHZ(), c_ulong::from(khz)) };
+        if ret < 0 {
+            return Err(Error::KvmIoctl(kvm_ioctls::Error::last()));
+        }
+        Ok(())
+    }
+
     /// Configure MSRs.
     pub fn configure_msrs(&self) -> Result<()> {
         let msrs = msrs::create_boot_msr_entries().map_err(Error::CreateMsr)?;
@@ -224,8 +274,11 @@ impl Vcpu {
         self.vcpu_fd.set_lapic(&klapic).map_err(Error::KvmIoctl)
     }
 
-    /// vCPU emulation loop.
-    pub fn run(&mut self) {
+    /// Run the vCPU until its next VM-exit and emulate it.
+    ///
+    /// Breaks once the guest shut down, and fails when the vCPU cannot go on: the
+    /// caller must stop the VM rather than run it again.
+    pub fn run(&mut self) -> Result<ControlFlow<()>> {
         // Call into KVM to launch (VMLAUNCH) or resume (VMRESUME) the virtual CPU.
         // This is a blocking function, it only returns for either an error or a
         // VM-Exit. In the latter case, we can inspect the exit reason.
@@ -234,76 +287,127 @@ impl Vcpu {
                 // The VM stopped (Shutdown ot HLT).
                 VcpuExit::Shutdown | VcpuExit::Hlt => {
                     println!("Guest shutdown: {:?}. Bye!", exit_reason);
-                    self.running.store(false, Ordering::SeqCst);
-                    return;
+                    self.running.stop();
+                    return Ok(ControlFlow::Break(()));
+                }
+
+                // The guest entered ACPI S5 (power off).
+                VcpuExit::IoOut(acpi::SLEEP_CONTROL_PORT, data) if acpi::is_poweroff(data) => {
+                    println!("Guest powered off. Bye!");
+                    self.running.stop();
+                    return Ok(ControlFlow::Break(()));
+                }
+
+                // The ACPI processor devices query which vCPUs exist.
+                VcpuExit::IoOut(addr, data) if acpi::CpuStatus::handles(addr) => {
+                    self.cpu_status.pio_write(addr, data);
+                }
+                VcpuExit::IoIn(addr, data) if acpi::CpuStatus::handles(addr) => {
+                    self.cpu_status.pio_read(addr, data);
                 }
 
                 // This is a PIO write, i.e. the guest is trying to write
                 // something to an I/O port.
                 VcpuExit::IoOut(addr, data) => {
-                    // Check if the address is within the serial port range
-                    if addr < SERIAL_PORT_BASE || addr > SERIAL_PORT_LAST {
-                        return;
-                    }
-
-                    self.serial
-                        .lock()
-                        .unwrap()
-                        .serial
-                        .write(
-                            (addr - SERIAL_PORT_BASE)
-                                .try_into()
-                                .expect("Invalid serial register offset"),
-                            data[0],
-                        )
-                        .unwrap();
+                    self.serial.lock().unwrap().pio_write(addr, data);
                 }
 
                 // This is a PIO read, i.e. the guest is trying to read
                 // from an I/O port.
                 VcpuExit::IoIn(addr, data) => {
-                    // Check if the address is within the serial port range
-                    if addr < SERIAL_PORT_BASE || addr > SERIAL_PORT_LAST {
-                        return;
-                    }
-
-                    data[0] = self.serial.lock().unwrap().serial.read(
-                        (addr - SERIAL_PORT_BASE)
-                            .try_into()
-                            .expect("Invalid serial register offset"),
-                    );
+                    self.serial.lock().unwrap().pio_read(addr, data);
                 }
 
                 VcpuExit::MmioRead(addr, data) => {
                     if let Some(ref net) = self.virtio_net {
                         let net = net.lock().unwrap();
-                        if net.mmio_range.start() <= addr && addr < net.mmio_range.end() {
+                        if net.handles(addr, data.len()) {
                             net.read(addr - net.mmio_range.start(), data);
                         }
                     }
+                    for block in &self.virtio_blocks {
+                        let block = block.lock().unwrap();
+                        if block.handles(addr, data.len()) {
+                            block.read(addr - block.mmio_range.start(), data);
+                        }
+                    }
+                    if let Some(ref mem) = self.virtio_mem {
+                        let mut mem = mem.lock().unwrap();
+                        if mem.handles(addr, data.len()) {
+                            mem.refresh_config();
+                            mem.read(addr - mem.mmio_range.start(), data);
+                        }
+                    }
+                    if let Some(ref rng) = self.virtio_rng {
+                        let rng = rng.lock().unwrap();
+                        if rng.handles(addr, data.len()) {
+                            rng.read(addr - rng.mmio_range.start(), data);
+                        }
+                    }
                 }
 
                 VcpuExit::MmioWrite(addr, data) => {
                     if let Some(ref net) = self.virtio_net {
                         let mut net = net.lock().unwrap();
-                        if net.mmio_range.start() <= addr && addr < net.mmio_range.end() {
+                        if net.handles(addr, data.len()) {
                             let start = net.mmio_range.start();
                             net.write(addr - start, data);
                         }
                     }
+                    for block in &self.virtio_blocks {
+                        let mut block = block.lock().unwrap();
+                        if block.handles(addr, data.len()) {
+                            let start = block.mmio_range.start();
+                            block.write(addr - start, data);
+                        }
+                    }
+                    if let Some(ref mem) = self.virtio_mem {
+                        let mut mem = mem.lock().unwrap();
+                        if mem.handles(addr, data.len()) {
+                            let start = mem.mmio_range.start();
+                            mem.write(addr - start, data);
+                        }
+                    }
+                    if let Some(ref rng) = self.virtio_rng {
+                        let mut rng = rng.lock().unwrap();
+                        if rng.handles(addr, data.len()) {
+                            let start = rng.mmio_range.start();
+                            rng.write(addr - start, data);
+                        }
+                    }
                 }
 
                 _ => {
-                    eprintln!("Unhandled VM-Exit: {:?}", exit_reason);
+                    let exit = format!("{:?}", exit_reason);
+                    debug!("vCPU {} exited with {}", self.index, exit);
+                    return Err(Error::UnhandledExit(exit));
                 }
             },
             Err(e) => {
-                // EINTR is expected when we send a signal to interrupt KVM_RUN
-                if e.errno() == libc::EINTR {
-                    return;
+                // EINTR is expected when we send a signal to interrupt KVM_RUN,
+                // EAGAIN when KVM wants it issued again.
+                if e.errno() == libc::EINTR || e.errno() == libc::EAGAIN {
+                    return Ok(ControlFlow::Continue(()));
                 }
-                eprintln!("Emulation error: {}", e);
+                return Err(Error::Run(e));
             }
         }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Log the registers of the vCPU at debug level, to tell where the guest was when
+    /// it failed.
+    pub fn dump_registers(&self) {
+        if !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        match self.vcpu_fd.get_regs() {
+            Ok(regs) => debug!("vCPU {} registers: {:x?}", self.index, regs),
+            Err(e) => debug!("vCPU {} registers unavailable: {}", self.index, e),
+        }
+        match self.vcpu_fd.get_sregs() {
+            Ok(sregs) => debug!("vCPU {} special registers: {:x?}", self.index, sregs),
+            Err(e) => debug!("vCPU {} special registers unavailable: {}", self.index, e),
+        }
     }
 }
@@ -125,19 +125,20 @@ fn compute_mp_size(num_cpus: u8) -> usize {
         + mem::size_of::<MpcLintsrcWrapper>() * 2
 }
 
-/// Performs setup of the MP table for the given `num_cpus`.
-pub fn setup_mptable(mem: &GuestMemoryMmap, num_cpus: u8) -> Result<()> {
-    if u32::from(num_cpus) > MAX_SUPPORTED_CPUS {
+/// Performs setup of the MP table for `max_cpus` CPUs, of which the first `num_cpus` are
+/// enabled. The others are listed disabled, so that the guest keeps room to hotplug them.
+pub fn setup_mptable(mem: &GuestMemoryMmap, num_cpus: u8, max_cpus: u8) -> Result<()> {
+    if u32::from(max_cpus) > MAX_SUPPORTED_CPUS {
         return Err(Error::TooManyCpus);
     }
 
     // Used to keep track of the next base pointer into the MP table.
     let mut base_mp = GuestAddress(MPTABLE_START);
 
-    let mp_size = compute_mp_size(num_cpus);
+    let mp_size = compute_mp_size(max_cpus);
 
     let mut checksum: u8 = 0;
-    let ioapicid: u8 = num_cpus + 1;
+    let ioapicid: u8 = max_cpus + 1;
 
     // The checked_add here ensures the all of the following base_mp.unchecked_add's will be without
     // overflow.
@@ -172,17 +173,17 @@ pub fn setup_mptable(mem: &GuestMemoryMmap, num_cpus: u8) -> Result<()> {
 
     {
         let size = mem::size_of::<MpcCpuWrapper>() as u64;
-        for cpu_id in 0..num_cpus {
+        for cpu_id in 0..max_cpus {
             let mut mpc_cpu = MpcCpuWrapper(mpspec::mpc_cpu::default());
             mpc_cpu.0.type_ = mpspec::MP_PROCESSOR as u8;
             mpc_cpu.0.apicid = cpu_id;
             mpc_cpu.0.apicver = APIC_VERSION;
-            mpc_cpu.0.cpuflag = mpspec::CPU_ENABLED as u8
-                | if cpu_id == 0 {
-                    mpspec::CPU_BOOTPROCESSOR as u8
-                } else {
-                    0
-                };
+            if cpu_id < num_cpus {
+                mpc_cpu.0.cpuflag |= mpspec::CPU_ENABLED as u8;
+            }
+            if cpu_id == 0 {
+                mpc_cpu.0.cpuflag |= mpspec::CPU_BOOTPROCESSOR as u8;
+            }
             mpc_cpu.0.cpufeature = CPU_STEPPING;
             mpc_cpu.0.featureflag = CPU_FEATURE_APIC | CPU_FEATURE_FPU;
             mem.write_obj(mpc_cpu, base_mp)
@@ -309,7 +310,7 @@ mod tests {
         )])
         .unwrap();
 
-        setup_mptable(&mem, num_cpus).unwrap();
+        setup_mptable(&mem, num_cpus, num_cpus).unwrap();
     }
 
     #[test]
@@ -321,7 +322,7 @@ mod tests {
         )])
         .unwrap();
 
-        assert!(setup_mptable(&mem, num_cpus).is_err());
+        assert!(setup_mptable(&mem, num_cpus, num_cpus).is_err());
     }
 
     #[test]
@@ -333,7 +334,7 @@ mod tests {
         )])
         .unwrap();
 
-        setup_mptable(&mem, num_cpus).unwrap();
+        setup_mptable(&mem, num_cpus, num_cpus).unwrap();
 
         let mpf_intel: MpfIntelWrapper = mem.read_obj(GuestAddress(MPTABLE_START)).unwrap();
 
@@ -352,7 +353,7 @@ mod tests {
         )])
         .unwrap();
 
-        setup_mptable(&mem, num_cpus).unwrap();
+        setup_mptable(&mem, num_cpus, num_cpus).unwrap();
 
         let mpf_intel: MpfIntelWrapper = mem.read_obj(GuestAddress(MPTABLE_START)).unwrap();
         let mpc_offset = GuestAddress(u64::from(mpf_intel.0.physptr));
@@ -386,7 +387,7 @@ mod tests {
         .unwrap();
 
         for i in 0..MAX_SUPPORTED_CPUS as u8 {
-            setup_mptable(&mem, i).unwrap();
+            setup_mptable(&mem, i, i).unwrap();
 
             let mpf_intel: MpfIntelWrapper = mem.read_obj(GuestAddress(MPTABLE_START)).unwrap();
             let mpc_offset = GuestAddress(u64::from(mpf_intel.0.physptr));
@@ -413,6 +414,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn cpu_entry_disabled() {
+        let mem =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(MPTABLE_START), compute_mp_size(4))])
+                .unwrap();
+        setup_mptable(&mem, 2, 4).unwrap();
+
+        let mpf_intel: MpfIntelWrapper = mem.read_obj(GuestAddress(MPTABLE_START)).unwrap();
+        let first_cpu = GuestAddress(u64::from(mpf_intel.0.physptr))
+            .unchecked_add(mem::size_of::<MpcTableWrapper>() as u64);
+        let enabled: Vec<bool> = (0..4)
+            .map(|i| {
+                let entry = first_cpu.unchecked_add(i * mem::size_of::<MpcCpuWrapper>() as u64);
+                let cpu: MpcCpuWrapper = mem.read_obj(entry).unwrap();
+                cpu.0.cpuflag & mpspec::CPU_ENABLED as u8 != 0
+            })
+            .collect();
+        assert_eq!(enabled, [true, true, false, false]);
+    }
+
     #[test]
     fn cpu_entry_count_max() {
         let cpus = MAX_SUPPORTED_CPUS + 1;
@@ -422,7 +443,7 @@ mod tests {
         )])
         .unwrap();
 
-        let result = setup_mptable(&mem, cpus as u8).unwrap_err();
+        let result = setup_mptable(&mem, cpus as u8, cpus as u8).unwrap_err();
         assert_eq!(result, Error::TooManyCpus);
     }
 }
@@ -6,11 +6,18 @@ use std::default::Default;
 use kvm_bindings::{kvm_msr_entry, Msrs};
 
 use crate::cpu::msr_index::{
-    MSR_CSTAR, MSR_IA32_MISC_ENABLE, MSR_IA32_MISC_ENABLE_FAST_STRING, MSR_IA32_SYSENTER_CS,
-    MSR_IA32_SYSENTER_EIP, MSR_IA32_SYSENTER_ESP, MSR_IA32_TSC, MSR_KERNEL_GS_BASE, MSR_LSTAR,
-    MSR_STAR, MSR_SYSCALL_MASK,
+    MSR_CSTAR, MSR_IA32_CR_PAT, MSR_IA32_MISC_ENABLE, MSR_IA32_MISC_ENABLE_FAST_STRING,
+    MSR_IA32_SYSENTER_CS, MSR_IA32_SYSENTER_EIP, MSR_IA32_SYSENTER_ESP, MSR_IA32_TSC,
+    MSR_IA32_TSC_DEADLINE, MSR_KERNEL_GS_BASE, MSR_LSTAR, MSR_STAR, MSR_SYSCALL_MASK,
 };
 
+// Paravirtual MSRs of KVM the guest writes the address of its kvm-clock pages and
+// of its EOI flag to, see Documentation/virt/kvm/x86/msr.rst in Linux.
+const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+const MSR_KVM_STEAL_TIME: u32 = 0x4b56_4d03;
+const MSR_KVM_PV_EOI_EN: u32 = 0x4b56_4d04;
+
 // Errors associated with operations on MSRs.
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -48,3 +55,26 @@ pub fn create_boot_msr_entries() -> Result<Msrs> {
 
     Msrs::from_entries(&raw_msrs).map_err(|_| Error::CreateMsrs)
 }
+
+/// MSRs a snapshot keeps: those set up at boot, and those the guest sets up once it runs.
+/// Ordered as they must be restored: the TSC before its deadline.
+pub fn create_snapshot_msr_entries() -> Result<Msrs> {
+    let mut entries = create_boot_msr_entries()?.as_slice().to_vec();
+    entries.extend(
+        [
+            MSR_IA32_CR_PAT,
+            MSR_IA32_TSC_DEADLINE,
+            MSR_KVM_WALL_CLOCK_NEW,
+            MSR_KVM_SYSTEM_TIME_NEW,
+            MSR_KVM_STEAL_TIME,
+            MSR_KVM_PV_EOI_EN,
+        ]
+        .iter()
+        .map(|&index| kvm_msr_entry {
+            index,
+            ..Default::default()
+        }),
+    );
+
+    Msrs::from_entries(&entries).map_err(|_| Error::CreateMsrs)
+}
@@ -1,5 +1,6 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub(crate) mod serial;
+pub(crate) mod serial_sinks;
 pub(crate) mod stdin;
 pub(crate) mod virtio;
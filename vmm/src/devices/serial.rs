@@ -1,17 +1,36 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use std::fs::{File, OpenOptions};
-use std::io::{stdout, Error, Result, Write};
+use std::io::{Error, Result};
 use std::ops::Deref;
-use std::path::Path;
 
+use log::warn;
 use vm_superio::serial::NoEvents;
 use vm_superio::{Serial, Trigger};
 use vmm_sys_util::eventfd::EventFd;
 
+use crate::devices::serial_sinks::{SerialHandle, SerialWriter, SERIAL_BUFFER_BYTES};
+
 pub const SERIAL_PORT_BASE: u16 = 0x3f8;
 pub const SERIAL_PORT_LAST: u16 = 0x3ff;
 
+// Offsets of the registers a snapshot keeps, and the bit of LCR that turns the first two
+// registers into the baud rate divisor.
+const IER_OFFSET: u8 = 1;
+const LCR_OFFSET: u8 = 3;
+const MCR_OFFSET: u8 = 4;
+const SCR_OFFSET: u8 = 7;
+const LCR_DLAB_BIT: u8 = 0x80;
+
+/// Registers of the serial port the guest driver set up. The rest is the FIFOs, which are
+/// empty when nothing is typed on the console.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SerialState {
+    pub ier: u8,
+    pub lcr: u8,
+    pub mcr: u8,
+    pub scr: u8,
+}
+
 pub struct EventFdTrigger(EventFd);
 
 impl Trigger for EventFdTrigger {
@@ -43,20 +62,85 @@ pub(crate) struct LumperSerial {
     eventfd: EventFdTrigger,
 
     // serial is the actual serial device.
-    pub serial: Serial<EventFdTrigger, NoEvents, Box<dyn std::io::Write + Send>>,
+    pub serial: Serial<EventFdTrigger, NoEvents, SerialWriter>,
+
+    // output holds the sinks the serial output is drained to, shared with the VMM.
+    output: SerialHandle,
 }
 
 impl LumperSerial {
+    /// New serial device writing to `output`, the first of its sinks.
     pub fn new(output: Box<dyn std::io::Write + Send>) -> Result<Self> {
         let eventfd = EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap();
+        let (sinks, writer) = SerialHandle::new(SERIAL_BUFFER_BYTES)?;
+        sinks.add_sink(output);
 
         Ok(LumperSerial {
             eventfd: eventfd.try_clone()?,
-            serial: Serial::new(eventfd.try_clone()?, output),
+            serial: Serial::new(eventfd.try_clone()?, writer),
+            output: sinks,
         })
     }
 
+    pub fn output(&self) -> SerialHandle {
+        self.output.clone()
+    }
+
     pub fn eventfd(&self) -> Result<EventFd> {
         Ok(self.eventfd.try_clone()?.0)
     }
+
+    /// State of the registers, read while the vCPUs are parked.
+    pub fn state(&mut self) -> SerialState {
+        let lcr = self.serial.read(LCR_OFFSET);
+        self.write_register(LCR_OFFSET, lcr & !LCR_DLAB_BIT);
+        let state = SerialState {
+            ier: self.serial.read(IER_OFFSET),
+            lcr,
+            mcr: self.serial.read(MCR_OFFSET),
+            scr: self.serial.read(SCR_OFFSET),
+        };
+        self.write_register(LCR_OFFSET, lcr);
+        state
+    }
+
+    /// Sets the registers of a port the guest has not used yet to `state`.
+    pub fn restore(&mut self, state: &SerialState) {
+        self.write_register(LCR_OFFSET, state.lcr & !LCR_DLAB_BIT);
+        self.write_register(IER_OFFSET, state.ier);
+        self.write_register(MCR_OFFSET, state.mcr);
+        self.write_register(SCR_OFFSET, state.scr);
+        self.write_register(LCR_OFFSET, state.lcr);
+    }
+
+    fn write_register(&mut self, offset: u8, value: u8) {
+        if let Err(e) = self.serial.write(offset, value) {
+            warn!("Failed to write to serial register {}: {:?}", offset, e);
+        }
+    }
+
+    /// Handles a guest read from I/O `port`. Ports outside the serial range are ignored.
+    pub fn pio_read(&mut self, port: u16, data: &mut [u8]) {
+        if let (Some(offset), Some(byte)) = (register_offset(port), data.first_mut()) {
+            *byte = self.serial.read(offset);
+        }
+    }
+
+    /// Handles a guest write to I/O `port`. Ports outside the serial range are ignored.
+    pub fn pio_write(&mut self, port: u16, data: &[u8]) {
+        if let (Some(offset), Some(&byte)) = (register_offset(port), data.first()) {
+            // The guest must not be able to bring the vCPU down, e.g. when the console
+            // output is closed: drop the byte instead.
+            self.write_register(offset, byte);
+        }
+    }
+}
+
+/// Offset of `port` in the serial register block, `None` for other ports.
+fn register_offset(port: u16) -> Option<u8> {
+    if (SERIAL_PORT_BASE..=SERIAL_PORT_LAST).contains(&port) {
+        Some((port - SERIAL_PORT_BASE) as u8)
+    } else {
+        None
+    }
 }
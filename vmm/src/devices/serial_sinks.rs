@@ -0,0 +1,254 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Destinations of the guest serial output. Sinks can be added or removed while
+//! the VM runs.
+//!
+//! The serial device only appends to a bounded buffer, and a drain thread writes
+//! it to every sink in turn, so a slow sink never stalls the vCPU. When the sinks
+//! fall too far behind, the oldest output is dropped and counted.
+
+use std::collections::VecDeque;
+use std::io::{self, Result, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use log::warn;
+
+/// Guest output kept for sinks that are behind, the oldest goes past this.
+pub const SERIAL_BUFFER_BYTES: usize = 1 << 20;
+
+/// Identifies a sink, to remove it later.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SerialSinkId(u64);
+
+#[derive(Default)]
+struct Sinks {
+    next_id: u64,
+    sinks: Vec<(SerialSinkId, Box<dyn Write + Send>)>,
+}
+
+impl Sinks {
+    /// A sink that fails (a closed file or connection) is dropped so that it
+    /// cannot hold up the other ones.
+    fn write_all(&mut self, buf: &[u8]) {
+        self.sinks.retain_mut(|(id, sink)| {
+            let result = sink.write_all(buf).and_then(|_| sink.flush());
+            if let Err(e) = &result {
+                warn!("Removing serial sink {:?}: {}", id, e);
+            }
+            result.is_ok()
+        });
+    }
+}
+
+/// Output written by the guest and not drained yet.
+struct Buffer {
+    bytes: VecDeque<u8>,
+    capacity: usize,
+    /// Bytes dropped so far because the sinks could not keep up.
+    dropped: u64,
+    /// Set when the serial device is gone, the drain thread stops once the buffer is empty.
+    closed: bool,
+}
+
+impl Buffer {
+    fn new(capacity: usize) -> Self {
+        Buffer {
+            bytes: VecDeque::new(),
+            capacity,
+            dropped: 0,
+            closed: false,
+        }
+    }
+
+    /// Appends `data`, dropping the oldest bytes past the capacity.
+    fn push(&mut self, data: &[u8]) {
+        self.bytes.extend(data);
+        let excess = self.bytes.len().saturating_sub(self.capacity);
+        self.bytes.drain(..excess);
+        self.dropped += excess as u64;
+    }
+}
+
+struct Shared {
+    buffer: Mutex<Buffer>,
+    /// Signalled when the buffer gets output or is closed.
+    ready: Condvar,
+    sinks: Mutex<Sinks>,
+}
+
+/// Handle on the serial output of a VM, usable from any thread while `run()` is executing.
+#[derive(Clone)]
+pub struct SerialHandle(Arc<Shared>);
+
+impl SerialHandle {
+    /// New output buffering up to `capacity` bytes, and the writer the serial device appends to.
+    pub(crate) fn new(capacity: usize) -> Result<(Self, SerialWriter)> {
+        let shared = Arc::new(Shared {
+            buffer: Mutex::new(Buffer::new(capacity)),
+            ready: Condvar::new(),
+            sinks: Mutex::new(Sinks::default()),
+        });
+        let drained = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("serial-drain".to_string())
+            .spawn(move || drain(&drained))?;
+        Ok((SerialHandle(Arc::clone(&shared)), SerialWriter(shared)))
+    }
+
+    /// Sends the guest output that follows to `sink` as well.
+    pub fn add_sink(&self, sink: Box<dyn Write + Send>) -> SerialSinkId {
+        let mut sinks = self.0.sinks.lock().unwrap();
+        let id = SerialSinkId(sinks.next_id);
+        sinks.next_id += 1;
+        sinks.sinks.push((id, sink));
+        id
+    }
+
+    /// Stops writing to the sink `id` and hands it back, `None` if it is not there anymore.
+    pub fn remove_sink(&self, id: SerialSinkId) -> Option<Box<dyn Write + Send>> {
+        let mut sinks = self.0.sinks.lock().unwrap();
+        let index = sinks.sinks.iter().position(|(sink_id, _)| *sink_id == id)?;
+        Some(sinks.sinks.remove(index).1)
+    }
+
+    /// Number of sinks the output currently goes to.
+    pub fn sink_count(&self) -> usize {
+        self.0.sinks.lock().unwrap().sinks.len()
+    }
+
+    /// Bytes of guest output dropped so far because the sinks were too slow.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.0.buffer.lock().unwrap().dropped
+    }
+}
+
+/// Writes the buffered output to the sinks until the serial device is gone.
+fn drain(shared: &Shared) {
+    let mut reported = 0;
+    loop {
+        let (chunk, dropped) = {
+            let mut buffer = shared.buffer.lock().unwrap();
+            while buffer.bytes.is_empty() && !buffer.closed {
+                buffer = shared.ready.wait(buffer).unwrap();
+            }
+            if buffer.bytes.is_empty() {
+                return;
+            }
+            (buffer.bytes.drain(..).collect::<Vec<u8>>(), buffer.dropped)
+        };
+
+        if dropped > reported {
+            warn!(
+                "Serial sinks are too slow, {} bytes of guest output dropped",
+                dropped - reported
+            );
+            reported = dropped;
+        }
+        shared.sinks.lock().unwrap().write_all(&chunk);
+    }
+}
+
+/// Output of the serial device. Writing only appends to the buffer and never fails.
+pub(crate) struct SerialWriter(Arc<Shared>);
+
+impl Write for SerialWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.buffer.lock().unwrap().push(buf);
+        self.0.ready.notify_one();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SerialWriter {
+    fn drop(&mut self) {
+        self.0.buffer.lock().unwrap().closed = true;
+        self.0.ready.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+    use std::time::{Duration, Instant};
+
+    /// Sink keeping what it gets where the test can read it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Shared {
+        /// Waits for the drain thread to deliver `expected`.
+        fn wait_for(&self, expected: &[u8]) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while *self.0.lock().unwrap() != expected {
+                assert!(Instant::now() < deadline, "sink never got {:?}", expected);
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+            Err(Error::new(ErrorKind::BrokenPipe, "closed"))
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sinks() {
+        let (output, mut writer) = SerialHandle::new(SERIAL_BUFFER_BYTES).unwrap();
+        let (first, second) = (Shared::default(), Shared::default());
+        let first_id = output.add_sink(Box::new(first.clone()));
+        writer.write_all(b"boot ").unwrap();
+        first.wait_for(b"boot ");
+
+        output.add_sink(Box::new(second.clone()));
+        output.add_sink(Box::new(Closed));
+        writer.write_all(b"login: ").unwrap();
+        second.wait_for(b"login: ");
+        assert_eq!(output.sink_count(), 2);
+
+        assert!(output.remove_sink(first_id).is_some());
+        assert!(output.remove_sink(first_id).is_none());
+        writer.write_all(b"root").unwrap();
+        drop(writer);
+
+        second.wait_for(b"login: root");
+        assert_eq!(*first.0.lock().unwrap(), b"boot login: ");
+        assert_eq!(output.dropped_bytes(), 0);
+    }
+
+    #[test]
+    fn test_buffer_drops_oldest() {
+        let mut buffer = Buffer::new(4);
+        buffer.push(b"abc");
+        buffer.push(b"de");
+        assert_eq!(buffer.bytes, b"bcde");
+        assert_eq!(buffer.dropped, 1);
+
+        buffer.push(b"0123456");
+        assert_eq!(buffer.bytes, b"3456");
+        assert_eq!(buffer.dropped, 8);
+    }
+}
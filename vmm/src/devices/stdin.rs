@@ -1,13 +1,19 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
+use std::io;
 use std::os::fd::AsRawFd;
 use std::sync::{Arc, Mutex};
 
-use event_manager::{EventOps, Events, MutEventSubscriber};
+use event_manager::{
+    EventOps, Events, MutEventSubscriber, RemoteEndpoint, SubscriberId, SubscriberOps,
+};
 use vmm_sys_util::epoll::EventSet;
 
 use crate::devices::serial::LumperSerial;
-use crate::VMInput;
+use crate::terminal::RawTerminal;
+use crate::{Error, Result, VMInput};
+
+type Subscriber = Arc<Mutex<dyn MutEventSubscriber>>;
 
 const STDIN_DATA: u32 = 0;
 
@@ -19,6 +25,84 @@ impl AsRawFd for FdWrapper {
     }
 }
 
+/// The console input attached to a VM, if any.
+#[derive(Default)]
+pub(crate) struct ConsoleInput {
+    subscriber: Option<SubscriberId>,
+    /// Raw mode of the input, when it is a terminal. Restored on drop.
+    terminal: Option<RawTerminal>,
+}
+
+impl ConsoleInput {
+    /// Puts `input` in raw mode if it is a terminal, once the terminal of the
+    /// input it replaces is restored.
+    pub(crate) fn set_terminal(&mut self, input: Option<&dyn VMInput>) -> io::Result<()> {
+        self.terminal.take();
+        if let Some(input) = input {
+            self.terminal = RawTerminal::enable(input.as_raw_fd())?;
+        }
+        Ok(())
+    }
+}
+
+/// Subscribes `handler` to the event loop in place of the input of `console`.
+pub(crate) fn swap_handler(
+    ops: &mut dyn SubscriberOps<Subscriber = Subscriber>,
+    console: &Mutex<ConsoleInput>,
+    handler: Option<Subscriber>,
+) {
+    let mut console = console.lock().unwrap();
+    if let Some(id) = console.subscriber.take() {
+        if let Err(e) = ops.remove_subscriber(id) {
+            eprintln!("Failed to remove the previous console input: {:?}", e);
+        }
+    }
+    console.subscriber = handler.map(|handler| ops.add_subscriber(handler));
+}
+
+/// Attaches console input to a VM while `run()` is executing on another thread.
+/// Obtained with [`VMM::input_handle`](crate::VMM::input_handle).
+#[derive(Clone)]
+pub struct InputHandle {
+    pub(crate) serial: Arc<Mutex<LumperSerial>>,
+    pub(crate) endpoint: RemoteEndpoint<Subscriber>,
+    pub(crate) console: Arc<Mutex<ConsoleInput>>,
+}
+
+impl InputHandle {
+    /// Feed `input` to the serial port from now on, instead of the current input.
+    /// The event loop picks it up on its next iteration.
+    pub fn attach(&self, input: Box<dyn VMInput>) -> Result<()> {
+        self.console
+            .lock()
+            .unwrap()
+            .set_terminal(Some(&*input))
+            .map_err(Error::TerminalConfigure)?;
+        self.swap(Some(StdinHandler::new(input, self.serial.clone())))
+    }
+
+    /// Stop reading the current input, if any, and restore its terminal.
+    pub fn detach(&self) -> Result<()> {
+        self.console
+            .lock()
+            .unwrap()
+            .set_terminal(None)
+            .map_err(Error::TerminalConfigure)?;
+        self.swap(None)
+    }
+
+    fn swap(&self, handler: Option<StdinHandler>) -> Result<()> {
+        // Swaps run in order on the event loop, each replacing the one before.
+        let console = self.console.clone();
+        self.endpoint
+            .fire(move |ops| {
+                let handler = handler.map(|h| Arc::new(Mutex::new(h)) as Subscriber);
+                swap_handler(ops, &console, handler)
+            })
+            .map_err(Error::InputAttach)
+    }
+}
+
 pub struct StdinHandler {
     input: Box<dyn VMInput>,
     serial: Arc<Mutex<LumperSerial>>,
@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::borrow::{Borrow, BorrowMut};
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use event_manager::RemoteEndpoint;
+use kvm_ioctls::VmFd;
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
+use virtio_queue::Queue;
+use vm_allocator::RangeInclusive;
+use vm_device::bus::MmioAddress;
+use vm_device::MutDeviceMmio;
+use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::block::handler::{BlockHandler, QueueHandler};
+use crate::devices::virtio::block::SECTOR_SIZE;
+use crate::devices::virtio::mmio::{
+    register_queue_event, start_handler, MmioTransport, Subscriber,
+};
+use crate::devices::virtio::net::device::VIRTIO_F_VERSION_1;
+use crate::devices::virtio::state::{QueueState, VirtioSnapshot};
+use crate::devices::virtio::{Error, SingleFdSignalQueue};
+
+pub const VIRTIO_BLK_F_RO: u64 = 5;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 9;
+
+pub const VIRTIO_BLK_QUEUE_SIZE: u16 = 256;
+
+pub struct VirtioBlockDevice {
+    vm_fd: Arc<VmFd>,
+    /// File backing the disk, handed to the handler on activation.
+    disk: Option<File>,
+    read_only: bool,
+    /// Usable size of the disk in bytes, a whole number of sectors.
+    capacity: u64,
+    /// addresses where the device lives in the guest
+    pub mmio_range: RangeInclusive,
+    // IRQ (id on the guest side), for signaling the driver (guest)
+    irq: u32,
+    /// IRQ eventfd (id on the VMM side) for signaling the driver (guest).
+    irqfd: Arc<EventFd>,
+    virtio_cfg: VirtioConfig<Arc<GuestMemoryMmap>>,
+    pub handler: Option<Arc<Mutex<QueueHandler<Arc<GuestMemoryMmap>>>>>,
+    endpoint: RemoteEndpoint<Subscriber>,
+}
+
+impl VirtioBlockDevice {
+    /// Exposes the file or host block device at `path` as a disk.
+    /// A trailing partial sector is not visible to the guest.
+    pub fn new(
+        vm_fd: Arc<VmFd>,
+        irq: u32,
+        irqfd: EventFd,
+        path: &Path,
+        read_only: bool,
+        guest_memory: Arc<GuestMemoryMmap>,
+        mmio_range: RangeInclusive,
+        endpoint: RemoteEndpoint<Subscriber>,
+    ) -> Result<Self, Error> {
+        let disk = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)
+            .map_err(Error::Io)?;
+        // `metadata().len()` is 0 for block devices, their size is where their end is.
+        let size = (&disk).seek(SeekFrom::End(0)).map_err(Error::Io)?;
+        let capacity = size / SECTOR_SIZE * SECTOR_SIZE;
+
+        let queues = vec![Queue::new(guest_memory, VIRTIO_BLK_QUEUE_SIZE)];
+
+        let mut features = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_BLK_F_FLUSH);
+        if read_only {
+            features |= 1 << VIRTIO_BLK_F_RO;
+        }
+        // The config space starts with `capacity`, in sectors.
+        let config_space = (capacity / SECTOR_SIZE).to_le_bytes().to_vec();
+        let virtio_cfg = VirtioConfig::new(features, queues, config_space);
+
+        Ok(VirtioBlockDevice {
+            vm_fd,
+            disk: Some(disk),
+            read_only,
+            capacity,
+            mmio_range,
+            irq,
+            irqfd: Arc::new(irqfd),
+            virtio_cfg,
+            handler: None,
+            endpoint,
+        })
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn cmdline_string(&self) -> String {
+        format!(
+            " virtio_mmio.device={}K@{:#x}:{}",
+            self.mmio_range.len() >> 10,
+            self.mmio_range.start(),
+            self.irq
+        )
+    }
+}
+
+type MyVirtioConfig = VirtioConfig<Arc<GuestMemoryMmap>>;
+
+impl VirtioDeviceType for VirtioBlockDevice {
+    fn device_type(&self) -> u32 {
+        2 // BLOCK_DEVICE_ID
+    }
+}
+
+impl Borrow<MyVirtioConfig> for VirtioBlockDevice {
+    fn borrow(&self) -> &MyVirtioConfig {
+        &self.virtio_cfg
+    }
+}
+
+impl BorrowMut<MyVirtioConfig> for VirtioBlockDevice {
+    fn borrow_mut(&mut self) -> &mut MyVirtioConfig {
+        &mut self.virtio_cfg
+    }
+}
+
+impl VirtioDeviceActions for VirtioBlockDevice {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<(), Error> {
+        // Like the net device, the disk and queue only go to the first handler.
+        let disk = self.disk.take().ok_or(Error::AlreadyActivated)?;
+
+        let ioevent = register_queue_event(&self.vm_fd, &self.mmio_range, 0)?;
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.irqfd.clone(),
+            interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+        };
+        let handler = Arc::new(Mutex::new(QueueHandler {
+            inner: BlockHandler {
+                driver_notify,
+                queue: self.virtio_cfg.queues.remove(0),
+                disk,
+                read_only: self.read_only,
+                capacity: self.capacity,
+            },
+            ioevent,
+        }));
+        self.handler = Some(handler.clone());
+
+        start_handler(&self.endpoint, handler)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl VirtioMmioDevice<Arc<GuestMemoryMmap>> for VirtioBlockDevice {}
+
+impl MmioTransport for VirtioBlockDevice {
+    fn mmio_range(&self) -> &RangeInclusive {
+        &self.mmio_range
+    }
+}
+
+impl VirtioSnapshot for VirtioBlockDevice {
+    fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    fn handler_queues(&self) -> Option<Vec<QueueState>> {
+        self.handler
+            .as_ref()
+            .map(|handler| vec![QueueState::of(&handler.lock().unwrap().inner.queue)])
+    }
+}
+
+impl MutDeviceMmio for VirtioBlockDevice {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        self.write(offset, data);
+    }
+}
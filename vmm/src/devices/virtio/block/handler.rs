@@ -0,0 +1,255 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::result;
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::{error, warn};
+use virtio_queue::{Descriptor, DescriptorChain, Queue};
+use vm_memory::{Address, Bytes, GuestAddressSpace, GuestMemory};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::block::{
+    REQUEST_QUEUE_INDEX, SECTOR_SIZE, VIRTIO_BLK_OUTHDR_SIZE, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK,
+    VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+};
+use crate::devices::virtio::{SignalUsedQueue, SingleFdSignalQueue};
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Why a request failed, reported to the driver as its status.
+enum RequestError {
+    Io(io::Error),
+    GuestMemory(vm_memory::GuestMemoryError),
+    /// The request reads or writes past the end of the disk.
+    OutOfRange,
+    /// The guest wrote to a read-only disk.
+    ReadOnly,
+    /// A data buffer has the wrong direction for the request.
+    InvalidBuffer,
+    Unsupported(u32),
+}
+
+impl RequestError {
+    fn status(&self) -> u8 {
+        match self {
+            RequestError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+            _ => VIRTIO_BLK_S_IOERR,
+        }
+    }
+}
+
+/// Serves the requests of the single queue of a block device from a file on the host.
+pub struct BlockHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
+    pub driver_notify: S,
+    pub queue: Queue<M>,
+    pub disk: File,
+    pub read_only: bool,
+    /// Usable size of the disk in bytes, a whole number of sectors.
+    pub capacity: u64,
+}
+
+impl<M: GuestAddressSpace, S: SignalUsedQueue> BlockHandler<M, S> {
+    pub fn process_queue(&mut self) -> result::Result<(), Error> {
+        loop {
+            self.queue.disable_notification()?;
+
+            while let Some(mut chain) = self.queue.iter()?.next() {
+                let used = self.process_chain(&mut chain)?;
+
+                self.queue.add_used(chain.head_index(), used)?;
+
+                if self.queue.needs_notification()? {
+                    self.driver_notify.signal_used_queue(REQUEST_QUEUE_INDEX);
+                }
+            }
+
+            if !self.queue.enable_notification()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs the request in `chain` and returns how many bytes were written to the guest.
+    fn process_chain(&mut self, chain: &mut DescriptorChain<M::T>) -> result::Result<u32, Error> {
+        let descriptors = chain.by_ref().collect::<Vec<Descriptor>>();
+        let memory = chain.memory();
+
+        // A request is a header, data buffers, and a status byte written by the device.
+        let (header, status, data) = match descriptors.as_slice() {
+            [header, data @ .., status]
+                if !header.is_write_only()
+                    && header.len() >= VIRTIO_BLK_OUTHDR_SIZE
+                    && status.is_write_only()
+                    && status.len() >= 1 =>
+            {
+                (header, status, data)
+            }
+            _ => {
+                // There is nowhere to write a status, drop the request.
+                warn!("malformed block request");
+                return Ok(0);
+            }
+        };
+
+        let request_type: u32 = memory.read_obj(header.addr()).map_err(Error::GuestMemory)?;
+        let sector: u64 = memory
+            .read_obj(header.addr().unchecked_add(8))
+            .map_err(Error::GuestMemory)?;
+
+        let (status_byte, written) = match self.execute(memory, request_type, sector, data) {
+            Ok(written) => (VIRTIO_BLK_S_OK, written),
+            Err(e) => {
+                match &e {
+                    RequestError::Io(e) => warn!("block request failed: {}", e),
+                    RequestError::GuestMemory(e) => warn!("block request buffer error: {}", e),
+                    RequestError::OutOfRange => warn!("block request past the end of the disk"),
+                    RequestError::ReadOnly => warn!("write to a read-only disk"),
+                    RequestError::InvalidBuffer => warn!("block request with a misdirected buffer"),
+                    RequestError::Unsupported(t) => warn!("unsupported block request type {}", t),
+                }
+                (e.status(), 0)
+            }
+        };
+        memory
+            .write_obj(status_byte, status.addr())
+            .map_err(Error::GuestMemory)?;
+
+        Ok(written + 1)
+    }
+
+    fn execute<G: GuestMemory>(
+        &mut self,
+        memory: &G,
+        request_type: u32,
+        sector: u64,
+        data: &[Descriptor],
+    ) -> result::Result<u32, RequestError> {
+        match request_type {
+            VIRTIO_BLK_T_IN => {
+                let mut offset = self.offset(sector, data)?;
+                let mut written = 0;
+                for desc in data {
+                    if !desc.is_write_only() {
+                        return Err(RequestError::InvalidBuffer);
+                    }
+                    let mut buf = vec![0u8; desc.len() as usize];
+                    self.disk
+                        .read_exact_at(&mut buf, offset)
+                        .map_err(RequestError::Io)?;
+                    memory
+                        .write_slice(&buf, desc.addr())
+                        .map_err(RequestError::GuestMemory)?;
+                    offset += u64::from(desc.len());
+                    written += desc.len();
+                }
+                Ok(written)
+            }
+            VIRTIO_BLK_T_OUT => {
+                if self.read_only {
+                    return Err(RequestError::ReadOnly);
+                }
+                let mut offset = self.offset(sector, data)?;
+                for desc in data {
+                    if desc.is_write_only() {
+                        return Err(RequestError::InvalidBuffer);
+                    }
+                    let mut buf = vec![0u8; desc.len() as usize];
+                    memory
+                        .read_slice(&mut buf, desc.addr())
+                        .map_err(RequestError::GuestMemory)?;
+                    self.disk
+                        .write_all_at(&buf, offset)
+                        .map_err(RequestError::Io)?;
+                    offset += u64::from(desc.len());
+                }
+                Ok(0)
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                self.disk.sync_data().map_err(RequestError::Io)?;
+                Ok(0)
+            }
+            other => Err(RequestError::Unsupported(other)),
+        }
+    }
+
+    /// Byte offset of `sector`, once checked that the buffers of the request fit on the disk.
+    fn offset(&self, sector: u64, data: &[Descriptor]) -> result::Result<u64, RequestError> {
+        let len = data.iter().map(|desc| u64::from(desc.len())).sum();
+        request_range(sector, len, self.capacity).ok_or(RequestError::OutOfRange)
+    }
+}
+
+/// Byte offset of a request of `len` bytes at `sector`, if it fits in `capacity` bytes.
+fn request_range(sector: u64, len: u64, capacity: u64) -> Option<u64> {
+    let start = sector.checked_mul(SECTOR_SIZE)?;
+    let end = start.checked_add(len)?;
+    (end <= capacity).then_some(start)
+}
+
+pub struct QueueHandler<M: GuestAddressSpace> {
+    pub inner: BlockHandler<M, SingleFdSignalQueue>,
+    pub ioevent: EventFd,
+}
+
+impl<M: GuestAddressSpace> QueueHandler<M> {
+    fn handle_error<S: AsRef<str>>(&self, s: S, ops: &mut EventOps) {
+        error!("{}", s.as_ref());
+        ops.remove(Events::empty(&self.ioevent))
+            .expect("Failed to remove block ioevent");
+    }
+}
+
+impl<M: GuestAddressSpace> MutEventSubscriber for QueueHandler<M> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        if events.event_set() != EventSet::IN {
+            self.handle_error("Unexpected event_set", ops);
+            return;
+        }
+
+        if self.ioevent.read().is_err() {
+            self.handle_error("Block ioevent read", ops);
+        } else if let Err(e) = self.inner.process_queue() {
+            self.handle_error(format!("Process block queue error {:?}", e), ops);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::new(&self.ioevent, EventSet::IN))
+            .expect("Unable to add block ioevent");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_range() {
+        let capacity = 4 * SECTOR_SIZE;
+
+        assert_eq!(
+            request_range(1, 2 * SECTOR_SIZE, capacity),
+            Some(SECTOR_SIZE)
+        );
+        assert_eq!(
+            request_range(3, SECTOR_SIZE, capacity),
+            Some(3 * SECTOR_SIZE)
+        );
+        assert_eq!(request_range(3, SECTOR_SIZE + 1, capacity), None);
+        assert_eq!(request_range(u64::MAX, 0, capacity), None);
+    }
+}
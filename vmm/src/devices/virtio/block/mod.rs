@@ -0,0 +1,22 @@
+// SPDX-License-Identifier: Apache-2.0
+
+pub mod device;
+pub mod handler;
+
+/// Size of a sector, the unit of `capacity` and of request offsets, whatever the disk block size.
+pub const SECTOR_SIZE: u64 = 512;
+
+// Request types and statuses, see "Device Operation" in the block device section of the standard.
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// Every request starts with a `virtio_blk_outhdr`: type, reserved, sector.
+pub const VIRTIO_BLK_OUTHDR_SIZE: u32 = 16;
+
+// A block device has a single request queue.
+const REQUEST_QUEUE_INDEX: u16 = 0;
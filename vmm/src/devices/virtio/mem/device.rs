@@ -0,0 +1,226 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::borrow::{Borrow, BorrowMut};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+
+use event_manager::RemoteEndpoint;
+use kvm_ioctls::VmFd;
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
+use virtio_queue::Queue;
+use vm_allocator::RangeInclusive;
+use vm_device::bus::MmioAddress;
+use vm_device::MutDeviceMmio;
+use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::mem::handler::{MemHandler, QueueHandler};
+use crate::devices::virtio::mem::state::MemoryState;
+use crate::devices::virtio::mmio::{
+    register_queue_event, start_handler, MmioTransport, Subscriber,
+};
+use crate::devices::virtio::net::device::VIRTIO_F_VERSION_1;
+use crate::devices::virtio::{Error, SingleFdSignalQueue, VIRTIO_MMIO_INT_CONFIG};
+
+pub const VIRTIO_MEM_QUEUE_SIZE: u16 = 128;
+
+pub struct VirtioMemDevice {
+    vm_fd: Arc<VmFd>,
+    /// Plugged blocks, shared with the handler and the resize handle.
+    state: Arc<Mutex<MemoryState>>,
+    /// addresses where the device lives in the guest
+    pub mmio_range: RangeInclusive,
+    // IRQ (id on the guest side), for signaling the driver (guest)
+    irq: u32,
+    /// IRQ eventfd (id on the VMM side) for signaling the driver (guest).
+    irqfd: Arc<EventFd>,
+    virtio_cfg: VirtioConfig<Arc<GuestMemoryMmap>>,
+    pub handler: Option<Arc<Mutex<QueueHandler<Arc<GuestMemoryMmap>>>>>,
+    endpoint: RemoteEndpoint<Subscriber>,
+}
+
+impl VirtioMemDevice {
+    /// Lets the guest plug memory into the `region_size` bytes at `region_addr`, which
+    /// must be part of `guest_memory` but not of the memory it boots with.
+    pub fn new(
+        vm_fd: Arc<VmFd>,
+        irq: u32,
+        irqfd: EventFd,
+        region_addr: u64,
+        region_size: u64,
+        guest_memory: Arc<GuestMemoryMmap>,
+        mmio_range: RangeInclusive,
+        endpoint: RemoteEndpoint<Subscriber>,
+    ) -> Result<Self, Error> {
+        let state = MemoryState::new(region_addr, region_size);
+        let queues = vec![Queue::new(guest_memory, VIRTIO_MEM_QUEUE_SIZE)];
+
+        let features = 1 << VIRTIO_F_VERSION_1;
+        let virtio_cfg = VirtioConfig::new(features, queues, state.config_space());
+
+        Ok(VirtioMemDevice {
+            vm_fd,
+            state: Arc::new(Mutex::new(state)),
+            mmio_range,
+            irq,
+            irqfd: Arc::new(irqfd),
+            virtio_cfg,
+            handler: None,
+            endpoint,
+        })
+    }
+
+    pub fn cmdline_string(&self) -> String {
+        format!(
+            " virtio_mmio.device={}K@{:#x}:{}",
+            self.mmio_range.len() >> 10,
+            self.mmio_range.start(),
+            self.irq
+        )
+    }
+
+    /// A handle changing the size requested from the guest, for a VM that boots
+    /// with `boot_size` bytes of memory.
+    pub fn resize_handle(&self, boot_size: u64) -> MemoryResize {
+        MemoryResize {
+            state: self.state.clone(),
+            boot_size,
+            irqfd: self.irqfd.clone(),
+            interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+        }
+    }
+
+    /// Brings the config space up to date with the plugged and requested sizes before
+    /// the driver reads it, with a new generation when they changed.
+    pub fn refresh_config(&mut self) {
+        let config_space = self.state.lock().unwrap().config_space();
+        if config_space != self.virtio_cfg.config_space {
+            self.virtio_cfg.config_space = config_space;
+            self.virtio_cfg.config_generation = self.virtio_cfg.config_generation.wrapping_add(1);
+        }
+    }
+}
+
+type MyVirtioConfig = VirtioConfig<Arc<GuestMemoryMmap>>;
+
+impl VirtioDeviceType for VirtioMemDevice {
+    fn device_type(&self) -> u32 {
+        24 // MEM_DEVICE_ID
+    }
+}
+
+impl Borrow<MyVirtioConfig> for VirtioMemDevice {
+    fn borrow(&self) -> &MyVirtioConfig {
+        &self.virtio_cfg
+    }
+}
+
+impl BorrowMut<MyVirtioConfig> for VirtioMemDevice {
+    fn borrow_mut(&mut self) -> &mut MyVirtioConfig {
+        &mut self.virtio_cfg
+    }
+}
+
+impl VirtioDeviceActions for VirtioMemDevice {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<(), Error> {
+        // Like the other devices, the queue only goes to the first handler.
+        if self.handler.is_some() {
+            return Err(Error::AlreadyActivated);
+        }
+
+        let ioevent = register_queue_event(&self.vm_fd, &self.mmio_range, 0)?;
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.irqfd.clone(),
+            interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+        };
+        let handler = Arc::new(Mutex::new(QueueHandler {
+            inner: MemHandler {
+                driver_notify,
+                queue: self.virtio_cfg.queues.remove(0),
+                state: self.state.clone(),
+            },
+            ioevent,
+        }));
+        self.handler = Some(handler.clone());
+
+        start_handler(&self.endpoint, handler)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl VirtioMmioDevice<Arc<GuestMemoryMmap>> for VirtioMemDevice {}
+
+impl MmioTransport for VirtioMemDevice {
+    fn mmio_range(&self) -> &RangeInclusive {
+        &self.mmio_range
+    }
+}
+
+impl MutDeviceMmio for VirtioMemDevice {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.refresh_config();
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        self.write(offset, data);
+    }
+}
+
+/// Grows and shrinks the memory of a VM from any thread.
+/// Obtained with [`crate::VMM::memory_resize_handle`].
+#[derive(Clone)]
+pub struct MemoryResize {
+    state: Arc<Mutex<MemoryState>>,
+    /// Memory outside of the device region, in bytes.
+    boot_size: u64,
+    irqfd: Arc<EventFd>,
+    interrupt_status: Arc<AtomicU8>,
+}
+
+impl MemoryResize {
+    /// Memory the VM boots with, in MiB; it cannot shrink below it.
+    pub fn boot_mib(&self) -> usize {
+        (self.boot_size >> 20) as usize
+    }
+
+    /// Memory the VM can grow to, in MiB.
+    pub fn max_mib(&self) -> usize {
+        ((self.boot_size + self.state.lock().unwrap().region_size()) >> 20) as usize
+    }
+
+    /// Memory the guest was last asked to have, in MiB.
+    pub fn requested_mib(&self) -> usize {
+        ((self.boot_size + self.state.lock().unwrap().requested_size()) >> 20) as usize
+    }
+
+    /// Memory the guest has, in MiB. It follows the requested size as the driver
+    /// plugs and unplugs blocks, and may stay above it when the guest cannot free enough.
+    pub fn plugged_mib(&self) -> usize {
+        ((self.boot_size + self.state.lock().unwrap().plugged_size()) >> 20) as usize
+    }
+
+    /// Ask the guest for `mib` MiB of memory in total, see [`crate::VMM::resize_memory`].
+    pub fn resize(&self, mib: usize) -> crate::Result<()> {
+        if mib < self.boot_mib() || mib > self.max_mib() {
+            return Err(crate::Error::MemorySize {
+                min_mib: self.boot_mib(),
+                max_mib: self.max_mib(),
+            });
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .set_requested_size(((mib as u64) << 20) - self.boot_size);
+
+        // Tell the driver the config space changed.
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_CONFIG, Ordering::SeqCst);
+        self.irqfd.write(1).map_err(crate::Error::IO)
+    }
+}
@@ -0,0 +1,190 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+use std::sync::{Arc, Mutex};
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::{error, warn};
+use virtio_queue::{Descriptor, DescriptorChain, Queue};
+use vm_memory::{Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::mem::state::MemoryState;
+use crate::devices::virtio::mem::{
+    BLOCK_SIZE, REQUEST_QUEUE_INDEX, VIRTIO_MEM_REQ_PLUG, VIRTIO_MEM_REQ_SIZE,
+    VIRTIO_MEM_REQ_STATE, VIRTIO_MEM_REQ_UNPLUG, VIRTIO_MEM_REQ_UNPLUG_ALL, VIRTIO_MEM_RESP_ACK,
+    VIRTIO_MEM_RESP_ERROR, VIRTIO_MEM_RESP_SIZE,
+};
+use crate::devices::virtio::{SignalUsedQueue, SingleFdSignalQueue};
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Serves the plug, unplug and state requests of the single queue of a memory device.
+pub struct MemHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
+    pub driver_notify: S,
+    pub queue: Queue<M>,
+    pub state: Arc<Mutex<MemoryState>>,
+}
+
+impl<M: GuestAddressSpace, S: SignalUsedQueue> MemHandler<M, S> {
+    pub fn process_queue(&mut self) -> result::Result<(), Error> {
+        loop {
+            self.queue.disable_notification()?;
+
+            while let Some(mut chain) = self.queue.iter()?.next() {
+                let used = self.process_chain(&mut chain)?;
+
+                self.queue.add_used(chain.head_index(), used)?;
+
+                if self.queue.needs_notification()? {
+                    self.driver_notify.signal_used_queue(REQUEST_QUEUE_INDEX);
+                }
+            }
+
+            if !self.queue.enable_notification()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs the request in `chain` and returns how many bytes were written to the guest.
+    fn process_chain(&mut self, chain: &mut DescriptorChain<M::T>) -> result::Result<u32, Error> {
+        let descriptors = chain.by_ref().collect::<Vec<Descriptor>>();
+        let memory = chain.memory();
+
+        // A request is followed by the response the device writes.
+        let (request, response) = match descriptors.as_slice() {
+            [request, response]
+                if !request.is_write_only()
+                    && request.len() >= VIRTIO_MEM_REQ_SIZE
+                    && response.is_write_only()
+                    && response.len() >= VIRTIO_MEM_RESP_SIZE =>
+            {
+                (request, response)
+            }
+            _ => {
+                warn!("malformed memory request");
+                return Ok(0);
+            }
+        };
+
+        let request_type: u16 = memory
+            .read_obj(request.addr())
+            .map_err(Error::GuestMemory)?;
+        let addr: u64 = memory
+            .read_obj(request.addr().unchecked_add(8))
+            .map_err(Error::GuestMemory)?;
+        let nb_blocks: u16 = memory
+            .read_obj(request.addr().unchecked_add(16))
+            .map_err(Error::GuestMemory)?;
+
+        let (response_type, range_state) = self.execute(memory, request_type, addr, nb_blocks);
+        memory
+            .write_obj(response_type, response.addr())
+            .map_err(Error::GuestMemory)?;
+        memory
+            .write_obj(range_state, response.addr().unchecked_add(8))
+            .map_err(Error::GuestMemory)?;
+
+        Ok(VIRTIO_MEM_RESP_SIZE)
+    }
+
+    /// Returns the response type, and the state of the range for state requests.
+    fn execute<G: GuestMemory>(
+        &mut self,
+        memory: &G,
+        request_type: u16,
+        addr: u64,
+        nb_blocks: u16,
+    ) -> (u16, u16) {
+        let mut state = self.state.lock().unwrap();
+        match request_type {
+            VIRTIO_MEM_REQ_PLUG => (state.plug(addr, nb_blocks), 0),
+            VIRTIO_MEM_REQ_UNPLUG => {
+                let response = state.unplug(addr, nb_blocks);
+                if response == VIRTIO_MEM_RESP_ACK {
+                    discard(memory, addr, u64::from(nb_blocks) * BLOCK_SIZE);
+                }
+                (response, 0)
+            }
+            VIRTIO_MEM_REQ_UNPLUG_ALL => {
+                state.unplug_all();
+                discard(memory, state.addr(), state.region_size());
+                (VIRTIO_MEM_RESP_ACK, 0)
+            }
+            VIRTIO_MEM_REQ_STATE => match state.state(addr, nb_blocks) {
+                Some(range_state) => (VIRTIO_MEM_RESP_ACK, range_state),
+                None => (VIRTIO_MEM_RESP_ERROR, 0),
+            },
+            other => {
+                warn!("unsupported memory request type {}", other);
+                (VIRTIO_MEM_RESP_ERROR, 0)
+            }
+        }
+    }
+}
+
+/// Gives the host memory behind `len` bytes of the region at `addr` back to the host.
+/// The guest reads zeroes there if it ever plugs the range again.
+fn discard<G: GuestMemory>(memory: &G, addr: u64, len: u64) {
+    let host_addr = match memory.get_host_address(GuestAddress(addr)) {
+        Ok(host_addr) => host_addr,
+        Err(e) => {
+            warn!("cannot discard unplugged memory: {}", e);
+            return;
+        }
+    };
+    // SAFETY: the range is in a single region, the one of the memory device, and the
+    // guest no longer uses it.
+    let ret = unsafe { libc::madvise(host_addr.cast(), len as usize, libc::MADV_DONTNEED) };
+    if ret != 0 {
+        warn!(
+            "cannot discard unplugged memory: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+pub struct QueueHandler<M: GuestAddressSpace> {
+    pub inner: MemHandler<M, SingleFdSignalQueue>,
+    pub ioevent: EventFd,
+}
+
+impl<M: GuestAddressSpace> QueueHandler<M> {
+    fn handle_error<S: AsRef<str>>(&self, s: S, ops: &mut EventOps) {
+        error!("{}", s.as_ref());
+        ops.remove(Events::empty(&self.ioevent))
+            .expect("Failed to remove memory ioevent");
+    }
+}
+
+impl<M: GuestAddressSpace> MutEventSubscriber for QueueHandler<M> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        if events.event_set() != EventSet::IN {
+            self.handle_error("Unexpected event_set", ops);
+            return;
+        }
+
+        if self.ioevent.read().is_err() {
+            self.handle_error("Memory ioevent read", ops);
+        } else if let Err(e) = self.inner.process_queue() {
+            self.handle_error(format!("Process memory queue error {:?}", e), ops);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::new(&self.ioevent, EventSet::IN))
+            .expect("Unable to add memory ioevent");
+    }
+}
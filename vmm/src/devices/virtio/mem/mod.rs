@@ -0,0 +1,34 @@
+// SPDX-License-Identifier: Apache-2.0
+
+pub mod device;
+pub mod handler;
+pub mod state;
+
+/// Granularity of plugs and unplugs. Linux needs at least a pageblock, 2 MiB on x86.
+pub const BLOCK_SIZE: u64 = 2 << 20;
+/// Size of a Linux memory block, the unit the guest adds the region to its memory in.
+pub const MEMORY_BLOCK_SIZE: u64 = 128 << 20;
+
+// Request types, response types and block states, see "Device Operation" in the
+// memory device section of the standard.
+pub const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+pub const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+pub const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+pub const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+pub const VIRTIO_MEM_RESP_ACK: u16 = 0;
+pub const VIRTIO_MEM_RESP_NACK: u16 = 1;
+pub const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+pub const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+pub const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+pub const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+
+// A `virtio_mem_req`: type, padding, then the address and number of blocks of
+// the range, padding.
+pub const VIRTIO_MEM_REQ_SIZE: u32 = 24;
+// A `virtio_mem_resp`: type, padding, then the state of the range for state requests.
+pub const VIRTIO_MEM_RESP_SIZE: u32 = 10;
+
+// A memory device has a single request queue.
+const REQUEST_QUEUE_INDEX: u16 = 0;
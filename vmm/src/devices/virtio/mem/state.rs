@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ops::Range;
+
+use super::{
+    BLOCK_SIZE, VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_RESP_ERROR, VIRTIO_MEM_RESP_NACK,
+    VIRTIO_MEM_STATE_MIXED, VIRTIO_MEM_STATE_PLUGGED, VIRTIO_MEM_STATE_UNPLUGGED,
+};
+
+/// Size of the `virtio_mem_config` structure.
+const CONFIG_SPACE_SIZE: usize = 56;
+
+/// The blocks of the region the guest plugged, and how much the host wants plugged.
+pub struct MemoryState {
+    /// Guest address of the region.
+    addr: u64,
+    /// Size the driver should plug, a whole number of blocks.
+    requested_size: u64,
+    plugged: Vec<bool>,
+}
+
+impl MemoryState {
+    /// A region of `region_size` bytes at `addr`, all unplugged. Both are multiples of [`BLOCK_SIZE`].
+    pub fn new(addr: u64, region_size: u64) -> Self {
+        MemoryState {
+            addr,
+            requested_size: 0,
+            plugged: vec![false; (region_size / BLOCK_SIZE) as usize],
+        }
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    pub fn region_size(&self) -> u64 {
+        self.plugged.len() as u64 * BLOCK_SIZE
+    }
+
+    pub fn plugged_size(&self) -> u64 {
+        self.plugged.iter().filter(|&&plugged| plugged).count() as u64 * BLOCK_SIZE
+    }
+
+    pub fn requested_size(&self) -> u64 {
+        self.requested_size
+    }
+
+    /// Ask the driver for `size` bytes, rounded down to a whole number of blocks.
+    /// Sizes past the region are capped to it.
+    pub fn set_requested_size(&mut self, size: u64) {
+        self.requested_size = size.min(self.region_size()) / BLOCK_SIZE * BLOCK_SIZE;
+    }
+
+    /// The `virtio_mem_config` the driver reads: block size, node, region address
+    /// and size, usable size, plugged size and requested size.
+    pub fn config_space(&self) -> Vec<u8> {
+        let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
+        config.extend_from_slice(&BLOCK_SIZE.to_le_bytes());
+        // node_id, then padding.
+        config.extend_from_slice(&[0u8; 8]);
+        config.extend_from_slice(&self.addr.to_le_bytes());
+        config.extend_from_slice(&self.region_size().to_le_bytes());
+        // The whole region is usable.
+        config.extend_from_slice(&self.region_size().to_le_bytes());
+        config.extend_from_slice(&self.plugged_size().to_le_bytes());
+        config.extend_from_slice(&self.requested_size.to_le_bytes());
+        config
+    }
+
+    /// Plug `nb_blocks` blocks at `addr`, and return the response type.
+    ///
+    /// Plugging past the requested size is refused, plugging a block twice is an error.
+    pub fn plug(&mut self, addr: u64, nb_blocks: u16) -> u16 {
+        let blocks = match self.blocks(addr, nb_blocks) {
+            Some(blocks) => blocks,
+            None => return VIRTIO_MEM_RESP_ERROR,
+        };
+        if self.plugged[blocks.clone()].iter().any(|&plugged| plugged) {
+            return VIRTIO_MEM_RESP_ERROR;
+        }
+        if self.plugged_size() + u64::from(nb_blocks) * BLOCK_SIZE > self.requested_size {
+            return VIRTIO_MEM_RESP_NACK;
+        }
+        self.plugged[blocks]
+            .iter_mut()
+            .for_each(|plugged| *plugged = true);
+        VIRTIO_MEM_RESP_ACK
+    }
+
+    /// Unplug `nb_blocks` blocks at `addr`, and return the response type.
+    /// Unplugging a block that is not plugged is an error.
+    pub fn unplug(&mut self, addr: u64, nb_blocks: u16) -> u16 {
+        let blocks = match self.blocks(addr, nb_blocks) {
+            Some(blocks) => blocks,
+            None => return VIRTIO_MEM_RESP_ERROR,
+        };
+        if !self.plugged[blocks.clone()].iter().all(|&plugged| plugged) {
+            return VIRTIO_MEM_RESP_ERROR;
+        }
+        self.plugged[blocks]
+            .iter_mut()
+            .for_each(|plugged| *plugged = false);
+        VIRTIO_MEM_RESP_ACK
+    }
+
+    pub fn unplug_all(&mut self) {
+        self.plugged.iter_mut().for_each(|plugged| *plugged = false);
+    }
+
+    /// Whether the `nb_blocks` blocks at `addr` are plugged, unplugged or mixed;
+    /// `None` when they are not all in the region.
+    pub fn state(&self, addr: u64, nb_blocks: u16) -> Option<u16> {
+        let blocks = &self.plugged[self.blocks(addr, nb_blocks)?];
+        Some(if blocks.iter().all(|&plugged| plugged) {
+            VIRTIO_MEM_STATE_PLUGGED
+        } else if blocks.iter().any(|&plugged| plugged) {
+            VIRTIO_MEM_STATE_MIXED
+        } else {
+            VIRTIO_MEM_STATE_UNPLUGGED
+        })
+    }
+
+    /// Indexes of the `nb_blocks` blocks at `addr`, if there are some and they are in the region.
+    fn blocks(&self, addr: u64, nb_blocks: u16) -> Option<Range<usize>> {
+        let offset = addr.checked_sub(self.addr)?;
+        if nb_blocks == 0 || offset % BLOCK_SIZE != 0 {
+            return None;
+        }
+        let first = (offset / BLOCK_SIZE) as usize;
+        let end = first.checked_add(nb_blocks.into())?;
+        (end <= self.plugged.len()).then_some(first..end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: u64 = 1 << 32;
+
+    #[test]
+    fn test_plug_unplug() {
+        let mut state = MemoryState::new(ADDR, 8 * BLOCK_SIZE);
+        // Nothing is plugged before the host asks for it.
+        assert_eq!(state.plug(ADDR, 1), VIRTIO_MEM_RESP_NACK);
+
+        state.set_requested_size(3 * BLOCK_SIZE + 1);
+        assert_eq!(state.requested_size(), 3 * BLOCK_SIZE);
+        assert_eq!(state.plug(ADDR + BLOCK_SIZE, 2), VIRTIO_MEM_RESP_ACK);
+        assert_eq!(state.plug(ADDR + 2 * BLOCK_SIZE, 1), VIRTIO_MEM_RESP_ERROR);
+        assert_eq!(state.plug(ADDR + 4 * BLOCK_SIZE, 2), VIRTIO_MEM_RESP_NACK);
+        assert_eq!(state.plug(ADDR, 1), VIRTIO_MEM_RESP_ACK);
+        assert_eq!(state.plugged_size(), 3 * BLOCK_SIZE);
+
+        assert_eq!(state.state(ADDR, 3), Some(VIRTIO_MEM_STATE_PLUGGED));
+        assert_eq!(state.state(ADDR, 4), Some(VIRTIO_MEM_STATE_MIXED));
+        assert_eq!(
+            state.state(ADDR + 3 * BLOCK_SIZE, 5),
+            Some(VIRTIO_MEM_STATE_UNPLUGGED)
+        );
+
+        assert_eq!(
+            state.unplug(ADDR + 2 * BLOCK_SIZE, 2),
+            VIRTIO_MEM_RESP_ERROR
+        );
+        assert_eq!(state.unplug(ADDR + BLOCK_SIZE, 2), VIRTIO_MEM_RESP_ACK);
+        assert_eq!(state.plugged_size(), BLOCK_SIZE);
+        state.unplug_all();
+        assert_eq!(state.plugged_size(), 0);
+    }
+
+    #[test]
+    fn test_out_of_region() {
+        let mut state = MemoryState::new(ADDR, 8 * BLOCK_SIZE);
+        state.set_requested_size(u64::MAX);
+        assert_eq!(state.requested_size(), 8 * BLOCK_SIZE);
+
+        assert_eq!(state.plug(ADDR - BLOCK_SIZE, 1), VIRTIO_MEM_RESP_ERROR);
+        assert_eq!(state.plug(ADDR + 1, 1), VIRTIO_MEM_RESP_ERROR);
+        assert_eq!(state.plug(ADDR, 0), VIRTIO_MEM_RESP_ERROR);
+        assert_eq!(state.plug(ADDR + 7 * BLOCK_SIZE, 2), VIRTIO_MEM_RESP_ERROR);
+        assert_eq!(state.state(ADDR + 8 * BLOCK_SIZE, 1), None);
+        assert_eq!(state.plug(ADDR, 8), VIRTIO_MEM_RESP_ACK);
+    }
+
+    #[test]
+    fn test_config_space() {
+        let mut state = MemoryState::new(ADDR, 8 * BLOCK_SIZE);
+        state.set_requested_size(2 * BLOCK_SIZE);
+        state.plug(ADDR, 1);
+
+        let config = state.config_space();
+        assert_eq!(config.len(), CONFIG_SPACE_SIZE);
+        let field = |offset: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&config[offset..offset + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        assert_eq!(field(0), BLOCK_SIZE);
+        assert_eq!(field(16), ADDR);
+        assert_eq!(field(24), 8 * BLOCK_SIZE);
+        assert_eq!(field(32), 8 * BLOCK_SIZE);
+        assert_eq!(field(40), BLOCK_SIZE);
+        assert_eq!(field(48), 2 * BLOCK_SIZE);
+    }
+}
@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! What the virtio devices share of the MMIO transport: the registers they answer on the
+//! bus, the eventfds of their queue notifications, and the handlers they start on activation.
+
+use std::sync::{Arc, Mutex};
+
+use event_manager::{MutEventSubscriber, RemoteEndpoint};
+use kvm_ioctls::{IoEventAddress, VmFd};
+use libc::EFD_NONBLOCK;
+use vm_allocator::RangeInclusive;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::{Error, VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET};
+
+/// Handlers of the event loop, which the devices hand theirs to.
+pub type Subscriber = Arc<Mutex<dyn MutEventSubscriber>>;
+
+/// A virtio device on the MMIO bus.
+pub trait MmioTransport {
+    /// Addresses of the device registers in the guest.
+    fn mmio_range(&self) -> &RangeInclusive;
+
+    /// Whether an access of `len` bytes at guest address `addr` falls inside the device registers.
+    fn handles(&self, addr: u64, len: usize) -> bool {
+        let range = self.mmio_range();
+        let last = match addr.checked_add(len.saturating_sub(1) as u64) {
+            Some(last) => last,
+            None => return false,
+        };
+        range.start() <= addr && last <= range.end()
+    }
+}
+
+/// An eventfd KVM signals when the driver notifies queue `index` of the device at
+/// `mmio_range`, without an exit to the vCPU thread.
+pub fn register_queue_event(
+    vm_fd: &VmFd,
+    mmio_range: &RangeInclusive,
+    index: u16,
+) -> Result<EventFd, Error> {
+    let fd = EventFd::new(EFD_NONBLOCK).map_err(Error::Io)?;
+    vm_fd
+        .register_ioevent(
+            &fd,
+            &IoEventAddress::Mmio(mmio_range.start() + VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET),
+            u32::from(index),
+        )
+        .map_err(Error::Kvm)?;
+    Ok(fd)
+}
+
+/// Hands `handler`, built when the driver activated the device, to the event loop.
+pub fn start_handler<H: MutEventSubscriber + Send + 'static>(
+    endpoint: &RemoteEndpoint<Subscriber>,
+    handler: Arc<Mutex<H>>,
+) -> Result<(), Error> {
+    // Activation runs on a vCPU thread: don't wait for the event loop to pick it up.
+    endpoint
+        .fire(move |mgr| {
+            mgr.add_subscriber(handler);
+        })
+        .map_err(Error::EventManager)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Device(RangeInclusive);
+
+    impl MmioTransport for Device {
+        fn mmio_range(&self) -> &RangeInclusive {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn test_handles() {
+        let device = Device(RangeInclusive::new(0x1000, 0x1fff).unwrap());
+        assert!(device.handles(0x1000, 4));
+        assert!(device.handles(0x1ffc, 4));
+        assert!(!device.handles(0x1ffd, 4));
+        assert!(!device.handles(0xffc, 8));
+        assert!(!device.handles(u64::MAX, 2));
+    }
+}
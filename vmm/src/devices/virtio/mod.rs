@@ -10,14 +10,23 @@ use vmm_sys_util::eventfd::EventFd;
 
 use crate::devices::virtio::net::tap;
 
+pub mod block;
+pub mod mem;
+pub mod mmio;
 pub mod net;
+pub mod rng;
+pub mod state;
 
 #[derive(Debug)]
 pub enum Error {
     Kvm(kvm_ioctls::Error),
     Io(io::Error),
-    RegisterIrqfd(kvm_ioctls::Error),
     Tap(tap::Error),
+    EventManager(event_manager::Error),
+    /// The driver set `DRIVER_OK` again: the queues already belong to the running handler.
+    AlreadyActivated,
+    /// The state to restore is the one of another device, or of one at other addresses.
+    StateMismatch,
 }
 
 // This bit is set on the device interrupt status when notifying the driver about used
@@ -26,6 +35,9 @@ pub enum Error {
 // disabled. Let's figure out at some point if having MMIO as part of the name is necessary.
 const VIRTIO_MMIO_INT_VRING: u8 = 0x01;
 
+// This bit is set on the device interrupt status when the configuration space changed.
+const VIRTIO_MMIO_INT_CONFIG: u8 = 0x02;
+
 // The driver will write to the register at this offset in the MMIO region to notify the device
 // about available queue events.
 const VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET: u64 = 0x50;
@@ -4,9 +4,8 @@ use std::borrow::{Borrow, BorrowMut};
 use std::convert::{TryFrom, TryInto};
 use std::sync::{Arc, Mutex};
 
-use event_manager::{MutEventSubscriber, RemoteEndpoint, SubscriberId};
-use kvm_ioctls::{IoEventAddress, VmFd};
-use libc::EFD_NONBLOCK;
+use event_manager::RemoteEndpoint;
+use kvm_ioctls::VmFd;
 use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
 use virtio_queue::Queue;
 use vm_allocator::RangeInclusive;
@@ -15,11 +14,17 @@ use vm_device::MutDeviceMmio;
 use vm_memory::{GuestMemoryMmap, GuestUsize};
 use vmm_sys_util::eventfd::EventFd;
 
+use crate::devices::virtio::mmio::{
+    register_queue_event, start_handler, MmioTransport, Subscriber,
+};
 use crate::devices::virtio::net::queue_handler::QueueHandler;
-use crate::devices::virtio::net::simple_handler::SimpleHandler;
+use crate::devices::virtio::net::simple_handler::{
+    NotificationCounters, NotificationStats, SimpleHandler,
+};
 use crate::devices::virtio::net::tap::Tap;
 use crate::devices::virtio::net::VIRTIO_NET_HDR_SIZE;
-use crate::devices::virtio::{Error, SingleFdSignalQueue, VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET};
+use crate::devices::virtio::state::{QueueState, VirtioSnapshot};
+use crate::devices::virtio::{Error, SingleFdSignalQueue};
 
 pub const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
 pub const VIRTIO_F_VERSION_1: u64 = 32;
@@ -27,6 +32,7 @@ pub const VIRTIO_F_IN_ORDER: u64 = 35;
 
 pub const VIRTIO_NET_F_CSUM: u64 = 0;
 pub const VIRTIO_NET_F_GUEST_CSUM: u64 = 1;
+pub const VIRTIO_NET_F_MTU: u64 = 3;
 pub const VIRTIO_NET_F_GUEST_TSO4: u64 = 7;
 pub const VIRTIO_NET_F_GUEST_TSO6: u64 = 8;
 pub const VIRTIO_NET_F_GUEST_UFO: u64 = 10;
@@ -48,11 +54,58 @@ pub const VIRTIO_NET_DEVICE_FEATURES: u64 = (1 << VIRTIO_F_VERSION_1)
 
 pub const VIRTIO_NET_QUEUE_SIZE: u16 = 256;
 
+/// Smallest MTU an IPv4 link may have.
+pub const MIN_MTU: u16 = 68;
+/// Largest MTU of a TAP: 64 KiB frames, Ethernet header included. Such frames
+/// fit the buffers of the handler, with their `virtio_net_hdr`.
+pub const MAX_MTU: u16 = 65521;
+/// Offset of `mtu` in the configuration space, after `mac`, `status` and
+/// `max_virtqueue_pairs`.
+const CONFIG_MTU_OFFSET: usize = 10;
+
+/// Configuration space of a device giving the driver `mtu`, if any. The fields
+/// before it are only read with features the device does not offer.
+pub fn net_config_space(mtu: Option<u16>) -> Vec<u8> {
+    let Some(mtu) = mtu else {
+        return Vec::new();
+    };
+    let mut config = vec![0; CONFIG_MTU_OFFSET + 2];
+    config[CONFIG_MTU_OFFSET..].copy_from_slice(&mtu.to_le_bytes());
+    config
+}
+
 pub const TUN_F_CSUM: ::std::os::raw::c_uint = 1;
 pub const TUN_F_TSO4: ::std::os::raw::c_uint = 2;
 pub const TUN_F_TSO6: ::std::os::raw::c_uint = 4;
 pub const TUN_F_UFO: ::std::os::raw::c_uint = 16;
 
+/// Offload flags of the TAP for the features the driver acked. They say what the
+/// kernel may hand the device: partially checksummed packets with `TUN_F_CSUM`,
+/// and packets up to 64 KiB with the segmentation flags. A driver that did not ack
+/// the matching `VIRTIO_NET_F_GUEST_*` feature posts buffers for a 1514 byte frame,
+/// and would get such packets truncated, or with a checksum it does not fill in.
+///
+/// Segmentation needs checksum offload, in the standard as in the kernel, which
+/// rejects the segmentation flags without `TUN_F_CSUM`.
+pub fn tap_offload_flags(driver_features: u64) -> ::std::os::raw::c_uint {
+    let acked = |feature: u64| driver_features & (1 << feature) != 0;
+    if !acked(VIRTIO_NET_F_GUEST_CSUM) {
+        return 0;
+    }
+
+    let mut flags = TUN_F_CSUM;
+    for (feature, flag) in [
+        (VIRTIO_NET_F_GUEST_TSO4, TUN_F_TSO4),
+        (VIRTIO_NET_F_GUEST_TSO6, TUN_F_TSO6),
+        (VIRTIO_NET_F_GUEST_UFO, TUN_F_UFO),
+    ] {
+        if acked(feature) {
+            flags |= flag;
+        }
+    }
+    flags
+}
+
 pub struct VirtioNetDevice {
     vm_fd: Arc<VmFd>,
     tap: Option<Tap>,
@@ -67,42 +120,46 @@ pub struct VirtioNetDevice {
     /// handler for tx/rx/tap events
     pub handler: Option<Arc<Mutex<QueueHandler<Arc<GuestMemoryMmap>>>>>,
     endpoint: RemoteEndpoint<Subscriber>,
+    /// Used buffers and interrupts of the handler, see [`VirtioNetDevice::notification_stats`].
+    counters: Arc<NotificationCounters>,
 }
 
-type Subscriber = Arc<Mutex<dyn MutEventSubscriber>>;
-
 impl VirtioNetDevice {
     pub fn new(
         vm_fd: Arc<VmFd>,
         irq: u32,
-        tap_name: String,
+        irqfd: EventFd,
+        tap: Tap,
         guest_memory: Arc<GuestMemoryMmap>,
         mmio_range: RangeInclusive,
         endpoint: RemoteEndpoint<Subscriber>,
+        mtu: Option<u16>,
     ) -> Result<Self, Error> {
-        let tap = Self::setup_tap(&tap_name)?;
+        let tap = Self::setup_tap(tap)?;
 
         let queues = vec![
             Queue::new(guest_memory.clone(), VIRTIO_NET_QUEUE_SIZE),
             Queue::new(guest_memory.clone(), VIRTIO_NET_QUEUE_SIZE),
         ];
 
-        let irqfd = Arc::new(EventFd::new(EFD_NONBLOCK).map_err(Error::Io)?);
-        vm_fd
-            .register_irqfd(&irqfd, irq)
-            .map_err(Error::RegisterIrqfd)?;
-
-        let virtio_cfg = VirtioConfig::new(VIRTIO_NET_DEVICE_FEATURES as u64, queues, Vec::new());
+        // With `VIRTIO_NET_F_MTU`, the driver sizes its interface, and its receive
+        // buffers, from the configuration space rather than from Ethernet.
+        let mut features = VIRTIO_NET_DEVICE_FEATURES;
+        if mtu.is_some() {
+            features |= 1 << VIRTIO_NET_F_MTU;
+        }
+        let virtio_cfg = VirtioConfig::new(features, queues, net_config_space(mtu));
 
         Ok(VirtioNetDevice {
             vm_fd,
             irq,
-            irqfd,
+            irqfd: Arc::new(irqfd),
             tap: Some(tap),
             mmio_range,
             virtio_cfg,
             handler: None,
             endpoint,
+            counters: Arc::default(),
         })
     }
     // Converts a `GuestUsize` to a concise string representation, with multiplier suffixes.
@@ -123,6 +180,11 @@ impl VirtioNetDevice {
         size.to_string()
     }
 
+    /// Used buffers and the interrupts signaling them since the driver started the device.
+    pub fn notification_stats(&self) -> NotificationStats {
+        self.counters.stats()
+    }
+
     pub fn cmdline_string(&self) -> String {
         format!(
             " virtio_mmio.device={}@{:#x}:{}",
@@ -154,13 +216,10 @@ impl BorrowMut<MyVirtioConfig> for VirtioNetDevice {
 }
 
 impl VirtioNetDevice {
-    fn setup_tap(tap_name: &str) -> Result<Tap, Error> {
-        let tap = Tap::open_named(tap_name).map_err(Error::Tap)?;
-
-        // Set offload flags to match the relevant virtio features of the device (for now,
-        // statically set in the constructor.
-        tap.set_offload(TUN_F_CSUM | TUN_F_UFO | TUN_F_TSO4 | TUN_F_TSO6)
-            .map_err(Error::Tap)?;
+    fn setup_tap(tap: Tap) -> Result<Tap, Error> {
+        // No offload until the driver acks its features, see `activate`: frames the TAP
+        // queues before then must fit any guest.
+        tap.set_offload(0).map_err(Error::Tap)?;
 
         // The layout of the header is specified in the standard and is 12 bytes in size. We
         // should define this somewhere.
@@ -184,9 +243,13 @@ impl VirtioNetDevice {
         let [rx_ioevent, tx_ioevent] = queue_eventfds;
 
         // Create handler
-        let rxq = self.virtio_cfg.queues.remove(0);
-        let txq = self.virtio_cfg.queues.remove(0);
-        let inner = SimpleHandler::new(driver_notify, rxq, txq, tap);
+        let mut rxq = self.virtio_cfg.queues.remove(0);
+        let mut txq = self.virtio_cfg.queues.remove(0);
+        // The driver then tells, in each ring, after which buffer it wants an interrupt.
+        let event_idx = self.virtio_cfg.driver_features & (1 << VIRTIO_F_RING_EVENT_IDX) != 0;
+        rxq.set_event_idx(event_idx);
+        txq.set_event_idx(event_idx);
+        let inner = SimpleHandler::new(driver_notify, rxq, txq, tap, self.counters.clone());
         let handler = QueueHandler {
             inner,
             rx_ioevent,
@@ -196,34 +259,14 @@ impl VirtioNetDevice {
         Ok(handler)
     }
 
-    fn register_handler(&mut self, handler: Arc<Mutex<QueueHandler<Arc<GuestMemoryMmap>>>>) {
-        self.endpoint
-            .call_blocking(|mgr| -> event_manager::Result<SubscriberId> {
-                Ok(mgr.add_subscriber(handler))
-            })
-            .unwrap();
-    }
-
     fn register_queue_events(&self) -> Result<Vec<EventFd>, Error> {
         let mut ioevents = Vec::new();
 
         for i in 0..self.virtio_cfg.queues.len() {
-            let fd = EventFd::new(EFD_NONBLOCK).map_err(Error::Io)?;
-
-            // Register the queue event fd.
-            self.vm_fd
-                .register_ioevent(
-                    &fd,
-                    &IoEventAddress::Mmio(
-                        self.mmio_range.start() + VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET,
-                    ),
-                    // The maximum number of queues should fit within an `u16` according to the
-                    // standard, so the conversion below is always expected to succeed.
-                    u32::try_from(i).unwrap(),
-                )
-                .map_err(Error::Kvm)?;
-
-            ioevents.push(fd);
+            // The maximum number of queues should fit within an `u16` according to the
+            // standard, so the conversion below is always expected to succeed.
+            let index = u16::try_from(i).unwrap();
+            ioevents.push(register_queue_event(&self.vm_fd, &self.mmio_range, index)?);
         }
 
         Ok(ioevents)
@@ -234,10 +277,11 @@ impl VirtioDeviceActions for VirtioNetDevice {
     type E = Error;
 
     fn activate(&mut self) -> Result<(), Error> {
-        let tap: Tap = self
-            .tap
-            .take()
-            .expect("Tap should be set up in the constructor");
+        // The tap and queues are handed to the handler on the first activation,
+        // a guest resetting the device and setting DRIVER_OK again gets an error.
+        let tap: Tap = self.tap.take().ok_or(Error::AlreadyActivated)?;
+        tap.set_offload(tap_offload_flags(self.virtio_cfg.driver_features))
+            .map_err(Error::Tap)?;
 
         let queue_eventfds = self.register_queue_events()?;
         let handler = self.setup_handler(
@@ -247,9 +291,7 @@ impl VirtioDeviceActions for VirtioNetDevice {
         let handler = Arc::new(Mutex::new(handler));
         self.handler = Some(handler.clone());
 
-        self.register_handler(handler);
-
-        Ok(())
+        start_handler(&self.endpoint, handler)
     }
 
     fn reset(&mut self) -> Result<(), Error> {
@@ -259,6 +301,24 @@ impl VirtioDeviceActions for VirtioNetDevice {
 
 impl VirtioMmioDevice<Arc<GuestMemoryMmap>> for VirtioNetDevice {}
 
+impl MmioTransport for VirtioNetDevice {
+    fn mmio_range(&self) -> &RangeInclusive {
+        &self.mmio_range
+    }
+}
+
+impl VirtioSnapshot for VirtioNetDevice {
+    fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    fn handler_queues(&self) -> Option<Vec<QueueState>> {
+        self.handler
+            .as_ref()
+            .map(|handler| handler.lock().unwrap().inner.queue_states())
+    }
+}
+
 impl MutDeviceMmio for VirtioNetDevice {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
         self.read(offset, data);
@@ -268,3 +328,35 @@ impl MutDeviceMmio for VirtioNetDevice {
         self.write(offset, data);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tap_offload_flags() {
+        assert_eq!(
+            tap_offload_flags(VIRTIO_NET_DEVICE_FEATURES),
+            TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6 | TUN_F_UFO
+        );
+
+        // Without TSO, the guest posts buffers for one frame: no large packets.
+        let csum_only = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_NET_F_GUEST_CSUM);
+        assert_eq!(tap_offload_flags(csum_only), TUN_F_CSUM);
+
+        // TSO without checksum offload is not valid, and gets nothing.
+        let tso_only = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_NET_F_GUEST_TSO4);
+        assert_eq!(tap_offload_flags(tso_only), 0);
+        assert_eq!(tap_offload_flags(1 << VIRTIO_F_VERSION_1), 0);
+    }
+
+    #[test]
+    fn test_net_config_space() {
+        assert!(net_config_space(None).is_empty());
+
+        let config = net_config_space(Some(9000));
+        assert_eq!(config.len(), 12);
+        assert_eq!(u16::from_le_bytes([config[10], config[11]]), 9000);
+        assert!(config[..10].iter().all(|&b| b == 0));
+    }
+}
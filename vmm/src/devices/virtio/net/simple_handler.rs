@@ -1,16 +1,20 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use std::cmp;
-use std::io::{self, Read, Write};
+use std::io;
 use std::result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use log::warn;
 use virtio_queue::{DescriptorChain, Queue};
-use vm_memory::{Bytes, GuestAddressSpace};
+use vm_memory::{
+    GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryError, GuestMemoryRegion,
+};
 
 use crate::devices::virtio::net::tap::Tap;
 use crate::devices::virtio::net::{RXQ_INDEX, TXQ_INDEX};
+use crate::devices::virtio::state::QueueState;
 use crate::devices::virtio::SignalUsedQueue;
 
 // According to the standard: "If the VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6 or
@@ -22,11 +26,17 @@ use crate::devices::virtio::SignalUsedQueue;
 // We assume the TX frame will not exceed this size either.
 const MAX_BUFFER_SIZE: usize = 65562;
 
+// `readv` and `writev` fail with EINVAL when given more buffers than this. A chain that needs
+// more is not a frame a driver would send or offer, and is returned unused.
+const MAX_IOVECS: usize = libc::UIO_MAXIOV as usize;
+
 #[derive(Debug)]
 pub enum Error {
     GuestMemory(vm_memory::GuestMemoryError),
     Queue(virtio_queue::Error),
     Tap(io::Error),
+    /// A chain is made of more than [`MAX_IOVECS`] buffers.
+    TooManyBuffers,
 }
 
 impl From<virtio_queue::Error> for Error {
@@ -35,97 +45,249 @@ impl From<virtio_queue::Error> for Error {
     }
 }
 
+/// Used buffers of a network device and the interrupts that told the driver about them.
+/// With `VIRTIO_F_RING_EVENT_IDX`, the driver asks to be signaled only past a given used
+/// buffer, so there are far fewer interrupts than buffers.
+#[derive(Default)]
+pub struct NotificationCounters {
+    rx_used: AtomicU64,
+    rx_signals: AtomicU64,
+    rx_dropped: AtomicU64,
+    rx_errors: AtomicU64,
+    tx_used: AtomicU64,
+    tx_signals: AtomicU64,
+    tx_dropped: AtomicU64,
+}
+
+/// Counts of [`NotificationCounters`] at some point.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NotificationStats {
+    /// Frames received by the guest.
+    pub rx_used: u64,
+    /// Interrupts for the RX queue.
+    pub rx_signals: u64,
+    /// RX chains returned unused, made of too many buffers.
+    pub rx_dropped: u64,
+    /// Reads of the TAP that failed for another reason than it having no frame.
+    pub rx_errors: u64,
+    /// Frames sent by the guest.
+    pub tx_used: u64,
+    /// Interrupts for the TX queue.
+    pub tx_signals: u64,
+    /// TX chains not sent, made of too many buffers.
+    pub tx_dropped: u64,
+}
+
+impl NotificationCounters {
+    pub fn stats(&self) -> NotificationStats {
+        NotificationStats {
+            rx_used: self.rx_used.load(Ordering::Relaxed),
+            rx_signals: self.rx_signals.load(Ordering::Relaxed),
+            rx_dropped: self.rx_dropped.load(Ordering::Relaxed),
+            rx_errors: self.rx_errors.load(Ordering::Relaxed),
+            tx_used: self.tx_used.load(Ordering::Relaxed),
+            tx_signals: self.tx_signals.load(Ordering::Relaxed),
+            tx_dropped: self.tx_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Buffers of guest memory making up one frame, handed to `readv` or `writev` on the TAP.
+/// Reused from frame to frame, so that moving a frame allocates nothing.
+#[derive(Default)]
+pub struct Iovecs(Vec<libc::iovec>);
+
+// SAFETY: the pointers are into the guest memory of the queues, mapped for as long as the
+// handler owning them lives, and only used by the thread running the handler.
+unsafe impl Send for Iovecs {}
+
+impl Iovecs {
+    /// Adds the `len` bytes of `memory` at `addr`, one buffer per memory region they span.
+    /// Fails with [`Error::TooManyBuffers`] past [`MAX_IOVECS`] buffers.
+    fn push<G: GuestMemory>(
+        &mut self,
+        memory: &G,
+        addr: GuestAddress,
+        len: usize,
+    ) -> result::Result<(), Error> {
+        let iovecs = &mut self.0;
+        let done = memory
+            .try_access(len, addr, |_, count, region_addr, region| {
+                if iovecs.len() == MAX_IOVECS {
+                    return Ok(0);
+                }
+                let slice = region.get_slice(region_addr, count)?;
+                iovecs.push(libc::iovec {
+                    iov_base: slice.as_ptr() as *mut libc::c_void,
+                    iov_len: count,
+                });
+                Ok(count)
+            })
+            .map_err(Error::GuestMemory)?;
+        if done != len && iovecs.len() == MAX_IOVECS {
+            return Err(Error::TooManyBuffers);
+        }
+        if done != len {
+            return Err(Error::GuestMemory(GuestMemoryError::PartialBuffer {
+                expected: len,
+                completed: done,
+            }));
+        }
+        Ok(())
+    }
+
+    fn as_slice(&self) -> &[libc::iovec] {
+        &self.0
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
 // A simple handler implementation for a RX/TX queue pair, which does not make assumptions about
 // the way queue notification is implemented. The backend is not yet generic (we always assume a
 // `Tap` object), but we're looking at improving that going forward.
+//
+// Frames move between descriptor chains and the TAP with `readv`/`writev`, without a copy in
+// between. Used buffers are all added before the driver is signaled, once per batch.
 // TODO: Find a better name.
 pub struct SimpleHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
     pub driver_notify: S,
     pub rxq: Queue<M>,
-    pub rxbuf_current: usize,
-    pub rxbuf: [u8; MAX_BUFFER_SIZE],
+    /// RX chain taken from the queue with no frame read into it yet: its head index and
+    /// size, with its buffers in `rx_iovecs`.
+    rx_pending: Option<(u16, usize)>,
+    rx_iovecs: Iovecs,
     pub txq: Queue<M>,
-    pub txbuf: [u8; MAX_BUFFER_SIZE],
+    tx_iovecs: Iovecs,
     pub tap: Tap,
+    counters: Arc<NotificationCounters>,
+}
+
+/// What became of the next frame of the TAP.
+enum Rx {
+    Delivered,
+    /// The next chain could not take a frame, and was returned unused.
+    Dropped,
+    /// There is no frame to read.
+    NoFrame,
+    /// There is no chain to read it into.
+    NoBuffer,
 }
 
 impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
-    pub fn new(driver_notify: S, rxq: Queue<M>, txq: Queue<M>, tap: Tap) -> Self {
+    pub fn new(
+        driver_notify: S,
+        rxq: Queue<M>,
+        txq: Queue<M>,
+        tap: Tap,
+        counters: Arc<NotificationCounters>,
+    ) -> Self {
         SimpleHandler {
             driver_notify,
             rxq,
-            rxbuf_current: 0,
-            rxbuf: [0u8; MAX_BUFFER_SIZE],
+            rx_pending: None,
+            rx_iovecs: Iovecs::default(),
             txq,
-            txbuf: [0u8; MAX_BUFFER_SIZE],
+            tx_iovecs: Iovecs::default(),
             tap,
+            counters,
         }
     }
 
+    /// States of the RX and TX queues. An RX chain taken with no frame read into it yet is
+    /// given back: it is the last one taken, and the device takes it again once restored.
+    pub fn queue_states(&self) -> Vec<QueueState> {
+        let mut rx = QueueState::of(&self.rxq);
+        if self.rx_pending.is_some() {
+            rx.next_avail = rx.next_avail.wrapping_sub(1);
+        }
+        vec![rx, QueueState::of(&self.txq)]
+    }
+
     // Have to see how to approach error handling for the `Queue` implementation in particular,
     // because many situations are not really recoverable. We should consider reporting them based
     // on the  metrics/events solution when they appear, and not propagate them further unless
     // it's really useful/necessary.
-    fn write_frame_to_guest(&mut self) -> result::Result<bool, Error> {
-        let num_bytes = self.rxbuf_current;
-
-        let mut chain = match self.rxq.iter()?.next() {
-            Some(c) => c,
-            _ => return Ok(false),
+    //
+    // Reads the next frame of the TAP straight into the next RX chain. A chain taken when
+    // there was no frame is kept for the next one.
+    fn read_frame_to_guest(&mut self) -> result::Result<Rx, Error> {
+        let (head_index, capacity) = match self.rx_pending.take() {
+            Some(pending) => pending,
+            None => {
+                let mut chain = match self.rxq.iter()?.next() {
+                    Some(c) => c,
+                    _ => return Ok(Rx::NoBuffer),
+                };
+                self.rx_iovecs.clear();
+                let mut capacity = 0;
+                while let Some(desc) = chain.next() {
+                    let len = desc.len() as usize;
+                    match self.rx_iovecs.push(chain.memory(), desc.addr(), len) {
+                        Ok(()) => capacity += len,
+                        Err(Error::TooManyBuffers) => {
+                            warn!("rx chain of more than {} buffers dropped", MAX_IOVECS);
+                            self.rxq.add_used(chain.head_index(), 0)?;
+                            return Ok(Rx::Dropped);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                (chain.head_index(), capacity)
+            }
         };
 
-        let mut count = 0;
-        let buf = &mut self.rxbuf[..num_bytes];
-
-        while let Some(desc) = chain.next() {
-            let left = buf.len() - count;
-
-            if left == 0 {
-                break;
+        let count = match self.tap.readv(self.rx_iovecs.as_slice()) {
+            Ok(n) => n,
+            Err(e) => {
+                // The TAP is non-blocking: EAGAIN means it has no frame left. Other errors,
+                // like a TAP whose link is down, are counted; the chain is kept either way,
+                // and the TAP read again on its next event.
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    self.counters.rx_errors.fetch_add(1, Ordering::Relaxed);
+                    warn!("Failed to read a frame from the tap: {}", e);
+                }
+                self.rx_pending = Some((head_index, capacity));
+                return Ok(Rx::NoFrame);
             }
+        };
 
-            let len = cmp::min(left, desc.len() as usize);
-            chain
-                .memory()
-                .write_slice(&buf[count..count + len], desc.addr())
-                .map_err(Error::GuestMemory)?;
-
-            count += len;
-        }
-
-        if count != buf.len() {
-            // The frame was too large for the chain.
-            warn!("rx frame too large");
+        if count == capacity && capacity < MAX_BUFFER_SIZE {
+            // The frame may have been too large for the chain, the rest of it is lost.
+            warn!("rx frame may be truncated");
         }
 
-        self.rxq.add_used(chain.head_index(), count as u32)?;
+        self.rxq.add_used(head_index, count as u32)?;
 
-        self.rxbuf_current = 0;
-
-        Ok(true)
+        Ok(Rx::Delivered)
     }
 
     pub fn process_tap(&mut self) -> result::Result<(), Error> {
+        let mut used = 0;
         loop {
-            if self.rxbuf_current == 0 {
-                match self.tap.read(&mut self.rxbuf) {
-                    Ok(n) => self.rxbuf_current = n,
-                    Err(_) => {
-                        // TODO: Do something (logs, metrics, etc.) in response to an error when
-                        // reading from tap. EAGAIN means there's nothing available to read anymore
-                        // (because we open the TAP as non-blocking).
-                        break;
-                    }
+            match self.read_frame_to_guest()? {
+                Rx::Delivered => used += 1,
+                // Returned to the driver all the same.
+                Rx::Dropped => {
+                    self.counters.rx_dropped.fetch_add(1, Ordering::Relaxed);
+                    used += 1;
                 }
-            }
-
-            if !self.write_frame_to_guest()? && !self.rxq.enable_notification()? {
-                break;
+                Rx::NoFrame => break,
+                // The TAP is read again once the driver adds chains and kicks the RX queue.
+                Rx::NoBuffer if !self.rxq.enable_notification()? => break,
+                Rx::NoBuffer => {}
             }
         }
 
-        if self.rxq.needs_notification()? {
-            self.driver_notify.signal_used_queue(RXQ_INDEX);
+        if used > 0 {
+            self.counters.rx_used.fetch_add(used, Ordering::Relaxed);
+            // With event idx, only when the driver asked for it within this batch.
+            if self.rxq.needs_notification()? {
+                self.counters.rx_signals.fetch_add(1, Ordering::Relaxed);
+                self.driver_notify.signal_used_queue(RXQ_INDEX);
+            }
         }
 
         Ok(())
@@ -135,31 +297,37 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
         &mut self,
         chain: &mut DescriptorChain<M::T>,
     ) -> result::Result<u32, Error> {
+        self.tx_iovecs.clear();
         let mut count = 0;
 
         while let Some(desc) = chain.next() {
-            let left = self.txbuf.len() - count;
             let len = desc.len() as usize;
 
-            if len > left {
+            if len > MAX_BUFFER_SIZE - count {
                 warn!("tx frame too large");
                 break;
             }
 
-            chain
-                .memory()
-                .read_slice(&mut self.txbuf[count..count + len], desc.addr())
-                .map_err(Error::GuestMemory)?;
-
-            count += len;
+            match self.tx_iovecs.push(chain.memory(), desc.addr(), len) {
+                Ok(()) => count += len,
+                Err(Error::TooManyBuffers) => {
+                    warn!("tx chain of more than {} buffers dropped", MAX_IOVECS);
+                    self.counters.tx_dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(0);
+                }
+                Err(e) => return Err(e),
+            }
         }
 
-        self.tap.write(&self.txbuf[..count]).map_err(Error::Tap)?;
+        self.tap
+            .writev(self.tx_iovecs.as_slice())
+            .map_err(Error::Tap)?;
 
         Ok(count as u32)
     }
 
     pub fn process_txq(&mut self) -> result::Result<(), Error> {
+        let mut used = 0;
         loop {
             self.txq.disable_notification()?;
 
@@ -167,16 +335,23 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
                 self.send_frame_from_chain(&mut chain)?;
 
                 self.txq.add_used(chain.head_index(), 0)?;
-
-                if self.txq.needs_notification()? {
-                    self.driver_notify.signal_used_queue(TXQ_INDEX);
-                }
+                used += 1;
             }
 
             if !self.txq.enable_notification()? {
-                return Ok(());
+                break;
             }
         }
+
+        if used > 0 {
+            self.counters.tx_used.fetch_add(used, Ordering::Relaxed);
+            if self.txq.needs_notification()? {
+                self.counters.tx_signals.fetch_add(1, Ordering::Relaxed);
+                self.driver_notify.signal_used_queue(TXQ_INDEX);
+            }
+        }
+
+        Ok(())
     }
 
     pub fn process_rxq(&mut self) -> result::Result<(), Error> {
@@ -184,3 +359,55 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
         self.process_tap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestMemoryMmap;
+
+    #[test]
+    fn test_iovecs_span_regions() {
+        let memory = GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+        ])
+        .unwrap();
+        let mut iovecs = Iovecs::default();
+
+        iovecs.push(&memory, GuestAddress(0x100), 0x200).unwrap();
+        iovecs.push(&memory, GuestAddress(0xf00), 0x200).unwrap();
+        let lens: Vec<usize> = iovecs.as_slice().iter().map(|iov| iov.iov_len).collect();
+        assert_eq!(lens, [0x200, 0x100, 0x100]);
+        assert_eq!(
+            iovecs.as_slice()[0].iov_base as usize,
+            memory.get_host_address(GuestAddress(0x100)).unwrap() as usize
+        );
+
+        // Past the end of guest memory.
+        assert!(iovecs.push(&memory, GuestAddress(0x1f00), 0x200).is_err());
+    }
+
+    #[test]
+    fn test_iovecs_stop_at_the_limit_of_readv() {
+        let memory = GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+        ])
+        .unwrap();
+        let mut iovecs = Iovecs::default();
+        for _ in 0..MAX_IOVECS - 1 {
+            iovecs.push(&memory, GuestAddress(0), 1).unwrap();
+        }
+
+        // The last buffer fits, the second region of this one does not.
+        assert!(matches!(
+            iovecs.push(&memory, GuestAddress(0xf00), 0x200),
+            Err(Error::TooManyBuffers)
+        ));
+        assert_eq!(iovecs.as_slice().len(), MAX_IOVECS);
+        assert!(matches!(
+            iovecs.push(&memory, GuestAddress(0), 1),
+            Err(Error::TooManyBuffers)
+        ));
+    }
+}
@@ -11,11 +11,11 @@
 use std::fs::File;
 use std::io::{Error as IoError, Read, Result as IoResult, Write};
 use std::os::raw::{c_char, c_int, c_uint, c_ulong};
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 
 use libc::{__c_anonymous_ifr_ifru, ifreq};
 use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
-use vmm_sys_util::ioctl_iow_nr;
+use vmm_sys_util::{ioctl_ior_nr, ioctl_iow_nr};
 
 // As defined in the Linux UAPI:
 // https://elixir.bootlin.com/linux/v4.17/source/include/uapi/linux/if.h#L33
@@ -38,6 +38,8 @@ pub enum Error {
     IoctlError(IoError),
     /// Couldn't open /dev/net/tun.
     OpenTun(IoError),
+    /// The file descriptor is not a TAP opened with `IFF_TAP | IFF_NO_PI | IFF_VNET_HDR`.
+    NotATap,
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -46,6 +48,7 @@ const TUNTAP: ::std::os::raw::c_uint = 84;
 ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, ::std::os::raw::c_int);
 ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
+ioctl_ior_nr!(TUNGETIFF, TUNTAP, 210, ::std::os::raw::c_uint);
 
 /// Handle for a network tap interface.
 ///
@@ -99,6 +102,11 @@ impl IfReqBuilder {
         self
     }
 
+    pub(crate) fn mtu(mut self, mtu: u16) -> Self {
+        self.0.ifr_ifru.ifru_mtu = c_int::from(mtu);
+        self
+    }
+
     pub(crate) fn execute<F: AsRawFd>(mut self, socket: &F, ioctl: u64) -> Result<ifreq> {
         // ioctl is safe. Called with a valid socket fd, and we check the return.
         let ret = unsafe { ioctl_with_mut_ref(socket, ioctl, &mut self.0) };
@@ -145,6 +153,30 @@ impl Tap {
         Ok(Tap { tap_file: tuntap })
     }
 
+    /// Wrap a TAP opened elsewhere, e.g. by a privileged helper that passed it over a Unix
+    /// socket. Takes ownership of `fd`, which must have been opened with
+    /// `IFF_TAP | IFF_NO_PI | IFF_VNET_HDR`, as [`Tap::open_named`] does.
+    pub fn from_fd(fd: RawFd) -> Result<Tap> {
+        // The caller hands us the fd, closed with the File from now on.
+        let tap_file = unsafe { File::from_raw_fd(fd) };
+
+        let ifreq = IfReqBuilder::new().execute(&tap_file, TUNGETIFF())?;
+        // Reading the flags is safe, TUNGETIFF sets them.
+        let flags = unsafe { ifreq.ifr_ifru.ifru_flags } as u16 as c_uint;
+        let expected = IFF_TAP | IFF_NO_PI | IFF_VNET_HDR;
+        if flags & expected != expected {
+            return Err(Error::NotATap);
+        }
+
+        // The handler reads until EAGAIN. fcntl is safe, called with a valid fd.
+        let status = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if status < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, status | libc::O_NONBLOCK) } < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(Tap { tap_file })
+    }
+
     /// Set the offload flags for the tap interface.
     pub fn set_offload(&self, flags: c_uint) -> Result<()> {
         // ioctl is safe. Called with a valid tap fd, and we check the return.
@@ -166,6 +198,79 @@ impl Tap {
 
         Ok(())
     }
+
+    /// MTU of the interface of the TAP.
+    pub fn mtu(&self) -> Result<u16> {
+        let ifreq = self
+            .if_request()?
+            .execute(&control_socket()?, libc::SIOCGIFMTU as u64)?;
+        // Reading the MTU is safe, SIOCGIFMTU sets it.
+        Ok(unsafe { ifreq.ifr_ifru.ifru_mtu } as u16)
+    }
+
+    /// Set the MTU of the interface of the TAP. Needs `CAP_NET_ADMIN`.
+    pub fn set_mtu(&self, mtu: u16) -> Result<()> {
+        self.if_request()?
+            .mtu(mtu)
+            .execute(&control_socket()?, libc::SIOCSIFMTU as u64)?;
+        Ok(())
+    }
+
+    /// Request on the interface of the TAP, whose name TUNGETIFF tells: a TAP
+    /// received as a descriptor comes without it.
+    fn if_request(&self) -> Result<IfReqBuilder> {
+        let ifreq = IfReqBuilder::new().execute(&self.tap_file, TUNGETIFF())?;
+        let mut if_name = [0u8; IFACE_NAME_MAX_LEN];
+        for (dst, src) in if_name.iter_mut().zip(ifreq.ifr_name.iter()) {
+            *dst = *src as u8;
+        }
+        Ok(IfReqBuilder::new().if_name(&if_name))
+    }
+
+    /// Read one frame into the buffers of `iovecs`, in order.
+    pub fn readv(&self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        // Safe because the buffers are valid for writes of their lengths, and we check the
+        // return.
+        let ret = unsafe {
+            libc::readv(
+                self.tap_file.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as c_int,
+            )
+        };
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    /// Write the buffers of `iovecs` as one frame.
+    pub fn writev(&self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        // Safe because the buffers are valid for reads of their lengths, and we check the
+        // return.
+        let ret = unsafe {
+            libc::writev(
+                self.tap_file.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as c_int,
+            )
+        };
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+}
+
+/// Socket for the interface ioctls, which the TAP file does not take.
+fn control_socket() -> Result<File> {
+    // socket is safe, we check the return.
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(Error::IoctlError(IoError::last_os_error()));
+    }
+    // We just checked that the fd is valid.
+    Ok(unsafe { File::from_raw_fd(fd) })
 }
 
 impl Read for Tap {
@@ -184,6 +289,12 @@ impl Write for Tap {
     }
 }
 
+impl IntoRawFd for Tap {
+    fn into_raw_fd(self) -> RawFd {
+        self.tap_file.into_raw_fd()
+    }
+}
+
 impl AsRawFd for Tap {
     fn as_raw_fd(&self) -> RawFd {
         self.tap_file.as_raw_fd()
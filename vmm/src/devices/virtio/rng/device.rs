@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::borrow::{Borrow, BorrowMut};
+use std::sync::{Arc, Mutex};
+
+use event_manager::RemoteEndpoint;
+use kvm_ioctls::VmFd;
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
+use virtio_queue::Queue;
+use vm_allocator::RangeInclusive;
+use vm_device::bus::MmioAddress;
+use vm_device::MutDeviceMmio;
+use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::mmio::{
+    register_queue_event, start_handler, MmioTransport, Subscriber,
+};
+use crate::devices::virtio::net::device::VIRTIO_F_VERSION_1;
+use crate::devices::virtio::rng::handler::{QueueHandler, RngHandler};
+use crate::devices::virtio::state::{QueueState, VirtioSnapshot};
+use crate::devices::virtio::{Error, SingleFdSignalQueue};
+
+pub const VIRTIO_RNG_QUEUE_SIZE: u16 = 64;
+
+pub struct VirtioRngDevice {
+    vm_fd: Arc<VmFd>,
+    /// addresses where the device lives in the guest
+    pub mmio_range: RangeInclusive,
+    // IRQ (id on the guest side), for signaling the driver (guest)
+    irq: u32,
+    /// IRQ eventfd (id on the VMM side) for signaling the driver (guest).
+    irqfd: Arc<EventFd>,
+    virtio_cfg: VirtioConfig<Arc<GuestMemoryMmap>>,
+    pub handler: Option<Arc<Mutex<QueueHandler<Arc<GuestMemoryMmap>>>>>,
+    endpoint: RemoteEndpoint<Subscriber>,
+}
+
+impl VirtioRngDevice {
+    /// Hands the guest random bytes read from the host RNG, as many as it asks for.
+    pub fn new(
+        vm_fd: Arc<VmFd>,
+        irq: u32,
+        irqfd: EventFd,
+        guest_memory: Arc<GuestMemoryMmap>,
+        mmio_range: RangeInclusive,
+        endpoint: RemoteEndpoint<Subscriber>,
+    ) -> Result<Self, Error> {
+        let queues = vec![Queue::new(guest_memory, VIRTIO_RNG_QUEUE_SIZE)];
+
+        // An entropy device has no feature and an empty config space.
+        let features = 1 << VIRTIO_F_VERSION_1;
+        let virtio_cfg = VirtioConfig::new(features, queues, Vec::new());
+
+        Ok(VirtioRngDevice {
+            vm_fd,
+            mmio_range,
+            irq,
+            irqfd: Arc::new(irqfd),
+            virtio_cfg,
+            handler: None,
+            endpoint,
+        })
+    }
+
+    pub fn cmdline_string(&self) -> String {
+        format!(
+            " virtio_mmio.device={}K@{:#x}:{}",
+            self.mmio_range.len() >> 10,
+            self.mmio_range.start(),
+            self.irq
+        )
+    }
+}
+
+type MyVirtioConfig = VirtioConfig<Arc<GuestMemoryMmap>>;
+
+impl VirtioDeviceType for VirtioRngDevice {
+    fn device_type(&self) -> u32 {
+        4 // RNG_DEVICE_ID
+    }
+}
+
+impl Borrow<MyVirtioConfig> for VirtioRngDevice {
+    fn borrow(&self) -> &MyVirtioConfig {
+        &self.virtio_cfg
+    }
+}
+
+impl BorrowMut<MyVirtioConfig> for VirtioRngDevice {
+    fn borrow_mut(&mut self) -> &mut MyVirtioConfig {
+        &mut self.virtio_cfg
+    }
+}
+
+impl VirtioDeviceActions for VirtioRngDevice {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<(), Error> {
+        // Like the other devices, the queue only goes to the first handler.
+        if self.handler.is_some() {
+            return Err(Error::AlreadyActivated);
+        }
+
+        let ioevent = register_queue_event(&self.vm_fd, &self.mmio_range, 0)?;
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.irqfd.clone(),
+            interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+        };
+        let handler = Arc::new(Mutex::new(QueueHandler {
+            inner: RngHandler {
+                driver_notify,
+                queue: self.virtio_cfg.queues.remove(0),
+            },
+            ioevent,
+        }));
+        self.handler = Some(handler.clone());
+
+        start_handler(&self.endpoint, handler)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl VirtioMmioDevice<Arc<GuestMemoryMmap>> for VirtioRngDevice {}
+
+impl MmioTransport for VirtioRngDevice {
+    fn mmio_range(&self) -> &RangeInclusive {
+        &self.mmio_range
+    }
+}
+
+impl VirtioSnapshot for VirtioRngDevice {
+    fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    fn handler_queues(&self) -> Option<Vec<QueueState>> {
+        self.handler
+            .as_ref()
+            .map(|handler| vec![QueueState::of(&handler.lock().unwrap().inner.queue)])
+    }
+}
+
+impl MutDeviceMmio for VirtioRngDevice {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        self.write(offset, data);
+    }
+}
@@ -0,0 +1,150 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+use std::result;
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::{error, warn};
+use virtio_queue::{Descriptor, DescriptorChain, Queue};
+use vm_memory::{Bytes, GuestAddressSpace};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::rng::{MAX_REQUEST_BYTES, REQUEST_QUEUE_INDEX};
+use crate::devices::virtio::{SignalUsedQueue, SingleFdSignalQueue};
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+    /// The host RNG could not be read.
+    Random(io::Error),
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Fills the buffers of the single queue of an entropy device from the host RNG.
+pub struct RngHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
+    pub driver_notify: S,
+    pub queue: Queue<M>,
+}
+
+impl<M: GuestAddressSpace, S: SignalUsedQueue> RngHandler<M, S> {
+    pub fn process_queue(&mut self) -> result::Result<(), Error> {
+        loop {
+            self.queue.disable_notification()?;
+
+            while let Some(mut chain) = self.queue.iter()?.next() {
+                let used = self.process_chain(&mut chain)?;
+
+                self.queue.add_used(chain.head_index(), used)?;
+
+                if self.queue.needs_notification()? {
+                    self.driver_notify.signal_used_queue(REQUEST_QUEUE_INDEX);
+                }
+            }
+
+            if !self.queue.enable_notification()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Fills the buffers in `chain` and returns how many bytes were written to the guest.
+    fn process_chain(&mut self, chain: &mut DescriptorChain<M::T>) -> result::Result<u32, Error> {
+        let descriptors = chain.by_ref().collect::<Vec<Descriptor>>();
+        let memory = chain.memory();
+
+        let mut written = 0;
+        for desc in descriptors {
+            if !desc.is_write_only() {
+                // The device only writes: the request is returned empty.
+                warn!("entropy request with a read-only buffer");
+                return Ok(0);
+            }
+            let len = desc.len().min(MAX_REQUEST_BYTES - written);
+            if len == 0 {
+                break;
+            }
+            let mut buf = vec![0u8; len as usize];
+            fill_random(&mut buf).map_err(Error::Random)?;
+            memory
+                .write_slice(&buf, desc.addr())
+                .map_err(Error::GuestMemory)?;
+            written += len;
+        }
+        Ok(written)
+    }
+}
+
+/// Fills `buf` from the host kernel RNG, which only blocks until it is seeded.
+fn fill_random(buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        // SAFETY: the kernel writes at most `rest.len()` bytes into `rest`.
+        let ret = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        filled += ret as usize;
+    }
+    Ok(())
+}
+
+pub struct QueueHandler<M: GuestAddressSpace> {
+    pub inner: RngHandler<M, SingleFdSignalQueue>,
+    pub ioevent: EventFd,
+}
+
+impl<M: GuestAddressSpace> QueueHandler<M> {
+    fn handle_error<S: AsRef<str>>(&self, s: S, ops: &mut EventOps) {
+        error!("{}", s.as_ref());
+        ops.remove(Events::empty(&self.ioevent))
+            .expect("Failed to remove rng ioevent");
+    }
+}
+
+impl<M: GuestAddressSpace> MutEventSubscriber for QueueHandler<M> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        if events.event_set() != EventSet::IN {
+            self.handle_error("Unexpected event_set", ops);
+            return;
+        }
+
+        if self.ioevent.read().is_err() {
+            self.handle_error("Rng ioevent read", ops);
+        } else if let Err(e) = self.inner.process_queue() {
+            self.handle_error(format!("Process rng queue error {:?}", e), ops);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::new(&self.ioevent, EventSet::IN))
+            .expect("Unable to add rng ioevent");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_random() {
+        let mut first = vec![0u8; 4096];
+        let mut second = vec![0u8; 4096];
+        fill_random(&mut first).unwrap();
+        fill_random(&mut second).unwrap();
+
+        assert!(first.iter().any(|&byte| byte != 0));
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,11 @@
+// SPDX-License-Identifier: Apache-2.0
+
+pub mod device;
+pub mod handler;
+
+/// Most bytes given for a single request, so that a driver asking for a lot at
+/// once cannot hold the event loop.
+pub const MAX_REQUEST_BYTES: u32 = 64 << 10;
+
+// An entropy device has a single request queue.
+const REQUEST_QUEUE_INDEX: u16 = 0;
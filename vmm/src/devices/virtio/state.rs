@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! What a snapshot keeps of a virtio device: the features and status the driver set,
+//! where its rings are, and how far the device went in them. The rest of a device, its
+//! TAP or its disk, is the one of the VM it is restored into.
+
+use std::borrow::{Borrow, BorrowMut};
+use std::num::Wrapping;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType};
+use virtio_queue::Queue;
+use vm_memory::{GuestAddress, GuestAddressSpace, GuestMemoryMmap};
+
+use crate::devices::virtio::mmio::MmioTransport;
+use crate::devices::virtio::Error;
+
+/// State of a virtqueue.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueState {
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    /// Index of the next chain the device takes from the available ring.
+    pub next_avail: u16,
+    /// Index of the next entry the device fills in the used ring.
+    pub next_used: u16,
+}
+
+impl QueueState {
+    pub fn of<M: GuestAddressSpace>(queue: &Queue<M>) -> Self {
+        QueueState {
+            size: queue.state.size,
+            ready: queue.state.ready,
+            desc_table: queue.state.desc_table.0,
+            avail_ring: queue.state.avail_ring.0,
+            used_ring: queue.state.used_ring.0,
+            next_avail: queue.state.next_avail.0,
+            next_used: queue.state.next_used.0,
+        }
+    }
+
+    fn apply<M: GuestAddressSpace>(&self, queue: &mut Queue<M>) {
+        queue.state.size = self.size;
+        queue.state.ready = self.ready;
+        queue.state.desc_table = GuestAddress(self.desc_table);
+        queue.state.avail_ring = GuestAddress(self.avail_ring);
+        queue.state.used_ring = GuestAddress(self.used_ring);
+        queue.state.next_avail = Wrapping(self.next_avail);
+        queue.state.next_used = Wrapping(self.next_used);
+    }
+}
+
+/// State of a virtio device on the MMIO transport.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VirtioState {
+    /// Device ID, first register address and GSI: where the guest found the device.
+    /// A device is only restored from the state of one found at the same place.
+    pub device_type: u32,
+    pub mmio_start: u64,
+    pub irq: u32,
+    pub driver_features: u64,
+    pub device_status: u8,
+    pub config_generation: u8,
+    pub interrupt_status: u8,
+    /// Whether the driver set `DRIVER_OK`, and the device handed its queues to a handler.
+    pub activated: bool,
+    pub queues: Vec<QueueState>,
+}
+
+type DeviceConfig = VirtioConfig<Arc<GuestMemoryMmap>>;
+
+/// A virtio device that can be snapshotted and restored.
+pub trait VirtioSnapshot:
+    MmioTransport + VirtioDeviceType + VirtioDeviceActions<E = Error> + BorrowMut<DeviceConfig>
+{
+    /// GSI of the device.
+    fn irq(&self) -> u32;
+
+    /// States of the queues the handler took on activation, if the device was activated.
+    fn handler_queues(&self) -> Option<Vec<QueueState>>;
+
+    /// State of the device, with the queues where they are: in the handler once activated.
+    /// The handler must not be running, see [`crate::snapshot`].
+    fn state(&self) -> VirtioState {
+        let config = Borrow::<DeviceConfig>::borrow(self);
+        let queues = self
+            .handler_queues()
+            .unwrap_or_else(|| config.queues.iter().map(QueueState::of).collect());
+
+        VirtioState {
+            device_type: self.device_type(),
+            mmio_start: self.mmio_range().start(),
+            irq: self.irq(),
+            driver_features: config.driver_features,
+            device_status: config.device_status,
+            config_generation: config.config_generation,
+            interrupt_status: config.interrupt_status.load(Ordering::SeqCst),
+            activated: config.device_activated,
+            queues,
+        }
+    }
+
+    /// Puts a device the guest has not seen yet in `state`, and starts its handler if
+    /// the driver had activated it.
+    fn restore(&mut self, state: &VirtioState) -> Result<(), Error> {
+        let device_type = self.device_type();
+        let mmio_start = self.mmio_range().start();
+        let irq = self.irq();
+        let config = BorrowMut::<DeviceConfig>::borrow_mut(self);
+        if state.device_type != device_type
+            || state.mmio_start != mmio_start
+            || state.irq != irq
+            || state.queues.len() != config.queues.len()
+        {
+            return Err(Error::StateMismatch);
+        }
+
+        config.driver_features = state.driver_features;
+        config.device_status = state.device_status;
+        config.config_generation = state.config_generation;
+        config
+            .interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        for (queue, queue_state) in config.queues.iter_mut().zip(&state.queues) {
+            queue_state.apply(queue);
+        }
+
+        if state.activated {
+            // The driver sees a running device: setting DRIVER_OK again is an error.
+            config.device_activated = true;
+            self.activate()?;
+        }
+        Ok(())
+    }
+}
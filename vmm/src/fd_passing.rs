@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Passing file descriptors over Unix sockets (`SCM_RIGHTS`).
+//!
+//! A privileged helper opens what the VMM may not, a TAP for instance, and sends
+//! the descriptor to the VMM, which can then run without capabilities. Each
+//! message carries one descriptor along with a single byte of data, since a
+//! message without data is not delivered on a stream socket.
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+/// Room for the control message of one descriptor, aligned as `cmsghdr` wants.
+type ControlBuffer = [u64; 4];
+
+/// Send `fd` over `socket`. The receiver gets its own copy of the descriptor.
+pub fn send_fd(socket: &UnixStream, fd: RawFd) -> io::Result<()> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut control: ControlBuffer = [0; 4];
+
+    // Safe because the message points to buffers that outlive the call, and the control
+    // message is written within the room CMSG_SPACE gives.
+    let sent = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+
+        libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive a descriptor sent with [`send_fd`] over `socket`, close-on-exec.
+pub fn recv_fd(socket: &UnixStream) -> io::Result<OwnedFd> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut control: ControlBuffer = [0; 4];
+
+    // Safe because the message points to buffers that outlive the call, and control
+    // messages are only read within the length the kernel reports.
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of::<ControlBuffer>() as _;
+
+        let received = libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut fds = Vec::new();
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / mem::size_of::<RawFd>();
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 || fds.len() > 1 {
+            // Any descriptor received is closed with `fds`.
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a single file descriptor",
+            ));
+        }
+        match fds.pop() {
+            Some(fd) => Ok(fd),
+            None if received == 0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the socket closed before a file descriptor was sent",
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the message carries no file descriptor",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_pass_fd() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let (pipe_read, pipe_write) = {
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+        };
+
+        send_fd(&sender, pipe_write.as_raw_fd()).unwrap();
+        drop(pipe_write);
+        let mut received = File::from(recv_fd(&receiver).unwrap());
+        received.write_all(b"frame").unwrap();
+        drop(received);
+
+        let mut read = String::new();
+        (&pipe_read).read_to_string(&mut read).unwrap();
+        assert_eq!(read, "frame");
+
+        // Plain data is not a descriptor, and a closed socket has none to give.
+        (&sender).write_all(b"x").unwrap();
+        let err = recv_fd(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        drop(sender);
+        let err = recv_fd(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
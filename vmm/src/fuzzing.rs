@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Entry points for the fuzz targets in `vmm/fuzz`, only built with the `fuzzing` feature.
+//!
+//! They reach the device emulation through the same handlers as vCPU exits,
+//! so anything the fuzzer finds is reachable by guest code.
+
+use std::io;
+
+use vm_device::bus::MmioAddress;
+use vm_device::MutDeviceMmio;
+
+use crate::devices::serial::LumperSerial;
+use crate::VMM;
+
+/// The serial console, as seen by the guest through port I/O.
+pub struct SerialPorts(LumperSerial);
+
+impl SerialPorts {
+    pub fn new(output: Box<dyn io::Write + Send>) -> io::Result<Self> {
+        Ok(SerialPorts(LumperSerial::new(output)?))
+    }
+
+    /// Guest `in` from `port`.
+    pub fn read(&mut self, port: u16, data: &mut [u8]) {
+        self.0.pio_read(port, data);
+    }
+
+    /// Guest `out` to `port`.
+    pub fn write(&mut self, port: u16, data: &[u8]) {
+        self.0.pio_write(port, data);
+    }
+}
+
+impl VMM {
+    /// Guest read at `offset` in the virtio-net register window. No-op without a net device.
+    pub fn net_mmio_read(&self, offset: u64, data: &mut [u8]) {
+        if let Some(net) = &self.virtio_net {
+            let mut net = net.lock().unwrap();
+            let base = MmioAddress(net.mmio_range.start());
+            net.mmio_read(base, offset, data);
+        }
+    }
+
+    /// Guest write at `offset` in the virtio-net register window. No-op without a net device.
+    pub fn net_mmio_write(&self, offset: u64, data: &[u8]) {
+        if let Some(net) = &self.virtio_net {
+            let mut net = net.lock().unwrap();
+            let base = MmioAddress(net.mmio_range.start());
+            net.mmio_write(base, offset, data);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checked reads and writes of guest memory by guest physical address.
+//!
+//! Tools poking at a guest (a debugger stub, snapshots, tests) get an address and a
+//! length from somewhere else, and must not touch the MMIO gap, where device
+//! registers live rather than memory, nor run past the end of guest memory.
+
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
+
+use crate::{MMIO_GAP_END, MMIO_GAP_START};
+
+/// Why guest memory cannot be accessed.
+#[derive(Debug)]
+pub enum GuestAccessError {
+    /// Part of the range is not guest memory.
+    OutOfBounds { addr: u64, len: usize },
+    /// The range overlaps the MMIO gap.
+    MmioGap { addr: u64, len: usize },
+    /// The access itself failed.
+    Memory(GuestMemoryError),
+}
+
+/// Copy the guest memory at `addr` into `buf`.
+pub(crate) fn read(
+    memory: &GuestMemoryMmap,
+    addr: u64,
+    buf: &mut [u8],
+) -> Result<(), GuestAccessError> {
+    let addr = check(memory, addr, buf.len())?;
+    memory
+        .read_slice(buf, addr)
+        .map_err(GuestAccessError::Memory)
+}
+
+/// Copy `buf` to the guest memory at `addr`.
+pub(crate) fn write(
+    memory: &GuestMemoryMmap,
+    addr: u64,
+    buf: &[u8],
+) -> Result<(), GuestAccessError> {
+    let addr = check(memory, addr, buf.len())?;
+    memory
+        .write_slice(buf, addr)
+        .map_err(GuestAccessError::Memory)
+}
+
+/// Whether the `len` bytes at `addr` are all guest memory, outside of the MMIO gap.
+fn check(
+    memory: &GuestMemoryMmap,
+    addr: u64,
+    len: usize,
+) -> Result<GuestAddress, GuestAccessError> {
+    let end = match addr.checked_add(len as u64) {
+        Some(end) => end,
+        None => return Err(GuestAccessError::OutOfBounds { addr, len }),
+    };
+    if len > 0 && addr < MMIO_GAP_END && end > MMIO_GAP_START {
+        return Err(GuestAccessError::MmioGap { addr, len });
+    }
+    if len > 0 && !memory.check_range(GuestAddress(addr), len) {
+        return Err(GuestAccessError::OutOfBounds { addr, len });
+    }
+    Ok(GuestAddress(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+
+    fn memory() -> GuestMemoryMmap {
+        // 1 MiB on each side of the gap, the gap is checked before the regions.
+        GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(MMIO_GAP_START - MIB), MIB as usize),
+            (GuestAddress(MMIO_GAP_END), MIB as usize),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_read_write() {
+        let memory = memory();
+        write(&memory, MMIO_GAP_END + 0x1000, b"cloude").unwrap();
+        let mut buf = [0; 6];
+        read(&memory, MMIO_GAP_END + 0x1000, &mut buf).unwrap();
+        assert_eq!(&buf, b"cloude");
+
+        // The last bytes of both regions.
+        write(&memory, MMIO_GAP_START - 2, b"ab").unwrap();
+        write(&memory, MMIO_GAP_END + MIB - 2, b"cd").unwrap();
+        read(&memory, MMIO_GAP_END + MIB - 2, &mut buf[..2]).unwrap();
+        assert_eq!(&buf[..2], b"cd");
+    }
+
+    #[test]
+    fn test_rejects_mmio_gap() {
+        let memory = memory();
+        let mut buf = [0; 4];
+        for addr in [MMIO_GAP_START - 2, MMIO_GAP_START, MMIO_GAP_END - 2] {
+            assert!(matches!(
+                read(&memory, addr, &mut buf),
+                Err(GuestAccessError::MmioGap { len: 4, .. })
+            ));
+        }
+        assert!(matches!(
+            write(&memory, MMIO_GAP_START - 2, &buf),
+            Err(GuestAccessError::MmioGap { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rejects_out_of_bounds() {
+        let memory = memory();
+        let mut buf = [0; 4];
+        for addr in [0, MMIO_GAP_END + MIB - 2, MMIO_GAP_END + MIB, u64::MAX - 1] {
+            assert!(matches!(
+                read(&memory, addr, &mut buf),
+                Err(GuestAccessError::OutOfBounds { len: 4, .. })
+            ));
+        }
+        assert!(matches!(
+            write(&memory, MMIO_GAP_END + MIB, &buf),
+            Err(GuestAccessError::OutOfBounds { .. })
+        ));
+    }
+}
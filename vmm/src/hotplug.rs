@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! vCPUs added to a running VM.
+//!
+//! The MP table and the ACPI tables declare up to `max_vcpus` processors, the
+//! ones not created at boot being absent. Adding one creates its KVM vCPU and
+//! thread, reports it present to the `_STA` of its processor device, then
+//! raises the GED interrupt: the guest rescans its processors, and starts the
+//! new one with INIT and SIPI once it is brought online.
+
+use std::sync::{Arc, Mutex};
+
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::acpi::CpuStatus;
+use crate::{Error, Result, VcpuFactory, VcpuThreads};
+
+/// Adds vCPUs to a VM from any thread. Obtained with [`crate::VMM::vcpu_hotplug_handle`].
+#[derive(Clone)]
+pub struct VcpuHotplug(Arc<Inner>);
+
+struct Inner {
+    factory: VcpuFactory,
+    threads: VcpuThreads,
+    cpu_status: Arc<CpuStatus>,
+    /// Raises the GSI of the GED telling the guest about new vCPUs.
+    interrupt: EventFd,
+    /// vCPUs created so far; held while one is added.
+    vcpu_count: Mutex<u8>,
+}
+
+impl VcpuHotplug {
+    pub(crate) fn new(
+        factory: VcpuFactory,
+        threads: VcpuThreads,
+        cpu_status: Arc<CpuStatus>,
+        interrupt: EventFd,
+        vcpu_count: u8,
+    ) -> Self {
+        VcpuHotplug(Arc::new(Inner {
+            factory,
+            threads,
+            cpu_status,
+            interrupt,
+            vcpu_count: Mutex::new(vcpu_count),
+        }))
+    }
+
+    /// Number of vCPUs of the VM, hotplugged ones included.
+    pub fn vcpu_count(&self) -> u8 {
+        *self.0.vcpu_count.lock().unwrap()
+    }
+
+    /// Number of vCPUs the VM can have.
+    pub fn max_vcpus(&self) -> u8 {
+        self.0.factory.max_vcpus
+    }
+
+    /// Add a vCPU, see [`crate::VMM::hotplug_vcpu`].
+    pub fn add_vcpu(&self) -> Result<u8> {
+        let mut vcpu_count = self.0.vcpu_count.lock().unwrap();
+        let index = *vcpu_count;
+        if index >= self.0.factory.max_vcpus {
+            return Err(Error::VcpuLimit(self.0.factory.max_vcpus));
+        }
+
+        let vcpu = self.0.factory.create(index)?;
+        self.0.threads.spawn(vcpu).map_err(Error::IO)?;
+        *vcpu_count += 1;
+
+        self.0.cpu_status.set_present(*vcpu_count);
+        self.0.interrupt.write(1).map_err(Error::IO)?;
+        Ok(index)
+    }
+}
@@ -1,40 +1,230 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Last GSI routed by the in-kernel IOAPIC (24 pins).
+pub const IOAPIC_LAST_GSI: u32 = 23;
+/// GSI of the serial port (COM1).
+pub const SERIAL_GSI: u32 = 4;
+/// GSIs of the legacy devices, which guests expect at fixed lines whether or not
+/// the VMM emulates them.
+pub const LEGACY_GSIS: [(u32, &str); 5] = [
+    (0, "timer"),
+    (1, "keyboard"),
+    (2, "PIC cascade"),
+    (3, "COM2"),
+    (SERIAL_GSI, "serial"),
+];
+
+/// Owner of the GSIs handed out by [`IrqAllocator::allocate`].
+const DEVICE: &str = "device";
+
+/// Why a GSI cannot be handed out or wired.
+#[derive(Debug, PartialEq, Eq)]
+pub enum IrqError {
+    /// Every GSI of the range is in use.
+    Exhausted,
+    /// The GSI is outside of the range of the allocator.
+    OutOfRange(u32),
+    /// The GSI already belongs to `owner`.
+    Conflict { gsi: u32, owner: &'static str },
+    /// An irqfd for a GSI that was neither allocated nor reserved.
+    Unallocated(u32),
+    /// A second irqfd for the same GSI.
+    AlreadyWired(u32),
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IrqError::Exhausted => write!(f, "every GSI is in use"),
+            IrqError::OutOfRange(gsi) => write!(f, "GSI {} is out of range", gsi),
+            IrqError::Conflict { gsi, owner } => write!(f, "GSI {} belongs to the {}", gsi, owner),
+            IrqError::Unallocated(gsi) => write!(f, "GSI {} was not allocated", gsi),
+            IrqError::AlreadyWired(gsi) => write!(f, "GSI {} already has an irqfd", gsi),
+        }
+    }
+}
+
+/// Hands out GSIs from a fixed range, in increasing order, skipping the reserved ones.
 pub struct IrqAllocator {
-    next: u32,
+    first: u32,
+    next: Option<u32>,
+    last: u32,
+    /// GSIs handed out or reserved, by owner.
+    owners: BTreeMap<u32, &'static str>,
+    /// GSIs with an irqfd registered, see [`IrqAllocator::wire`].
+    wired: BTreeSet<u32>,
 }
 
 impl IrqAllocator {
-    pub fn new(start: u32) -> Self {
-        Self { next: start }
+    /// Allocator for the GSIs in `first..=last`.
+    pub fn new(first: u32, last: u32) -> Self {
+        Self {
+            first,
+            next: Some(first),
+            last,
+            owners: BTreeMap::new(),
+            wired: BTreeSet::new(),
+        }
+    }
+
+    /// Allocator for the lines of the IOAPIC, with those of the legacy devices reserved.
+    pub fn with_legacy_irqs() -> Self {
+        let mut allocator = Self::new(0, IOAPIC_LAST_GSI);
+        for (gsi, owner) in LEGACY_GSIS {
+            allocator
+                .reserve(gsi, owner)
+                .expect("legacy GSIs are distinct IOAPIC lines");
+        }
+        allocator
+    }
+
+    /// Returns `None` once every GSI of the range has been handed out.
+    pub fn allocate(&mut self) -> Option<u32> {
+        let irq = self.peek()?;
+        self.owners.insert(irq, DEVICE);
+        self.next = irq.checked_add(1);
+        Some(irq)
+    }
+
+    /// GSI returned by the next `allocate`.
+    pub fn peek(&self) -> Option<u32> {
+        let mut irq = self.next?;
+        while self.owners.contains_key(&irq) {
+            irq = irq.checked_add(1)?;
+        }
+        Some(irq).filter(|irq| *irq <= self.last)
+    }
+
+    /// How many GSIs `allocate` can still hand out.
+    pub fn available(&self) -> usize {
+        match self.next.filter(|next| *next <= self.last) {
+            Some(next) => (next..=self.last)
+                .filter(|irq| !self.owners.contains_key(irq))
+                .count(),
+            None => 0,
+        }
     }
 
-    pub fn allocate(&mut self) -> u32 {
-        let irq = self.next;
-        self.next = self.next.checked_add(1).expect("IRQ overflow");
-        irq
+    /// Sets `gsi` aside for `owner`, a device that needs a fixed line.
+    pub fn reserve(&mut self, gsi: u32, owner: &'static str) -> Result<(), IrqError> {
+        if gsi < self.first || gsi > self.last {
+            return Err(IrqError::OutOfRange(gsi));
+        }
+        if let Some(&owner) = self.owners.get(&gsi) {
+            return Err(IrqError::Conflict { gsi, owner });
+        }
+        self.owners.insert(gsi, owner);
+        Ok(())
     }
 
-    pub fn peek(&self) -> u32 {
-        self.next
+    /// Records that an irqfd raises `gsi`, which must have been allocated or reserved,
+    /// so that no two devices share a line.
+    pub fn wire(&mut self, gsi: u32) -> Result<(), IrqError> {
+        if !self.owners.contains_key(&gsi) {
+            return Err(IrqError::Unallocated(gsi));
+        }
+        if !self.wired.insert(gsi) {
+            return Err(IrqError::AlreadyWired(gsi));
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::irq_allocator::IrqAllocator;
+    use crate::irq_allocator::{IrqAllocator, IrqError, SERIAL_GSI};
+    use proptest::prelude::*;
 
     #[test]
     fn allocates_incrementing_irqs() {
-        let mut alloc = IrqAllocator::new(32);
-        assert_eq!(alloc.allocate(), 32);
-        assert_eq!(alloc.allocate(), 33);
-        assert_eq!(alloc.allocate(), 34);
+        let mut alloc = IrqAllocator::new(32, 40);
+        assert_eq!(alloc.allocate(), Some(32));
+        assert_eq!(alloc.allocate(), Some(33));
+        assert_eq!(alloc.allocate(), Some(34));
     }
 
     #[test]
     fn peek_returns_next() {
-        let mut alloc = IrqAllocator::new(10);
-        assert_eq!(alloc.peek(), 10);
+        let mut alloc = IrqAllocator::new(10, 40);
+        assert_eq!(alloc.peek(), Some(10));
         alloc.allocate();
-        assert_eq!(alloc.peek(), 11);
+        assert_eq!(alloc.peek(), Some(11));
+    }
+
+    #[test]
+    fn stops_at_end_of_range() {
+        let mut alloc = IrqAllocator::new(u32::MAX - 1, u32::MAX);
+        assert_eq!(alloc.allocate(), Some(u32::MAX - 1));
+        assert_eq!(alloc.allocate(), Some(u32::MAX));
+        assert_eq!(alloc.allocate(), None);
+        assert_eq!(alloc.peek(), None);
+    }
+
+    #[test]
+    fn skips_legacy_irqs() {
+        let mut alloc = IrqAllocator::with_legacy_irqs();
+        assert_eq!(alloc.available(), 19);
+        assert_eq!(alloc.allocate(), Some(5));
+        assert_eq!(alloc.available(), 18);
+
+        let mut alloc = IrqAllocator::new(0, 10);
+        alloc.reserve(1, "timer").unwrap();
+        alloc.reserve(3, "serial").unwrap();
+        let irqs: Vec<u32> = std::iter::from_fn(|| alloc.allocate()).collect();
+        assert_eq!(irqs, [0, 2, 4, 5, 6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn detects_conflicts() {
+        let mut alloc = IrqAllocator::with_legacy_irqs();
+        assert_eq!(
+            alloc.reserve(SERIAL_GSI, "COM1"),
+            Err(IrqError::Conflict {
+                gsi: SERIAL_GSI,
+                owner: "serial"
+            })
+        );
+        assert_eq!(alloc.reserve(24, "device"), Err(IrqError::OutOfRange(24)));
+
+        let irq = alloc.allocate().unwrap();
+        assert_eq!(
+            alloc.reserve(irq, "power button"),
+            Err(IrqError::Conflict {
+                gsi: irq,
+                owner: "device"
+            })
+        );
+        // A line past the allocated ones is still free for a fixed device.
+        alloc.reserve(23, "power button").unwrap();
+        assert_eq!(alloc.available(), 17);
+
+        alloc.wire(SERIAL_GSI).unwrap();
+        alloc.wire(irq).unwrap();
+        assert_eq!(alloc.wire(irq), Err(IrqError::AlreadyWired(irq)));
+        assert_eq!(alloc.wire(22), Err(IrqError::Unallocated(22)));
+    }
+
+    proptest! {
+        #[test]
+        fn allocations_stay_in_range(
+            first in any::<u32>(),
+            span in 0u32..64,
+            count in 0usize..128,
+        ) {
+            let last = first.saturating_add(span);
+            let mut alloc = IrqAllocator::new(first, last);
+
+            let irqs: Vec<u32> = (0..count).filter_map(|_| alloc.allocate()).collect();
+
+            let capacity = (last - first) as usize + 1;
+            prop_assert_eq!(irqs.len(), count.min(capacity));
+            prop_assert!(irqs.iter().all(|irq| (first..=last).contains(irq)));
+            // Strictly increasing, so no GSI is handed out twice.
+            prop_assert!(irqs.windows(2).all(|pair| pair[0] < pair[1]));
+            if count >= capacity {
+                prop_assert_eq!(alloc.allocate(), None);
+            }
+        }
     }
 }
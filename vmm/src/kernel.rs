@@ -13,7 +13,7 @@ use linux_loader::configurator::{linux::LinuxBootConfigurator, BootConfigurator,
 use linux_loader::loader::{elf::Elf, load_cmdline, KernelLoader, KernelLoaderResult};
 use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
-use crate::{Error, Result};
+use crate::{cmdline, Error, Result};
 
 // x86_64 boot constants. See https://www.kernel.org/doc/Documentation/x86/boot.txt for the full
 // documentation.
@@ -47,10 +47,8 @@ const INITRAMFS_START: u64 = 0x0800_0000; // 128 MB
 
 /// Address where the kernel command line is written.
 const CMDLINE_START: u64 = 0x0002_0000;
-/// Maximum size for kernel command line
-const CMDLINE_MAX_SIZE: usize = 4096;
 // Default command line
-const CMDLINE: &str = "console=ttyS0 i8042.nokbd reboot=t panic=1 pci=off";
+pub(crate) const CMDLINE: &str = "console=ttyS0 i8042.nokbd reboot=t panic=1 pci=off";
 
 fn add_e820_entry(
     params: &mut boot_params,
@@ -92,8 +90,12 @@ pub fn build_bootparams(
     // Add an entry for EBDA itself.
     add_e820_entry(&mut params, 0, EBDA_START, E820_RAM)?;
 
-    // Add entries for the usable RAM regions.
-    let last_addr = guest_memory.last_addr();
+    // Add an entry for the RAM the VM boots with. The region of the memory device, if
+    // any, is left to its driver.
+    let last_addr = guest_memory
+        .find_region(himem_start)
+        .ok_or(Error::HimemStartPastMemEnd)?
+        .last_addr();
     add_e820_entry(
         &mut params,
         himem_start.raw_value() as u64,
@@ -106,19 +108,31 @@ pub fn build_bootparams(
     Ok(params)
 }
 
+/// Where the kernel finds its root filesystem.
+pub enum RootFs {
+    /// An initramfs image, loaded in guest memory.
+    Initramfs(PathBuf),
+    /// A disk, such as `/dev/vda` for the first virtio block device.
+    Disk { device: String, read_only: bool },
+}
+
 /// Set guest kernel up.
 ///
 /// # Arguments
 ///
 /// * `guest_memory` - Guest memory
 /// * `kernel_path` - Path to the kernel image
-/// * `initramfs_path` - Optional path to the initramfs image
+/// * `root` - Root filesystem
+/// * `init_path` - Program started as init, the kernel default when `None`
+/// * `cmdline_components` - Kernel parameters generated for the devices
+/// * `cmdline_extra` - Kernel parameters given by the user, see [`crate::cmdline::merge`]
 pub fn configure_kernel(
     guest_memory: &GuestMemoryMmap,
     kernel_path: PathBuf,
-    initramfs_path: Option<PathBuf>,
+    root: RootFs,
     init_path: Option<&str>,
-    cmdline_components: Vec<String>,
+    mut cmdline_components: Vec<String>,
+    cmdline_extra: &[String],
 ) -> Result<KernelLoaderResult> {
     let mut kernel_image = File::open(kernel_path).map_err(Error::IO)?;
     let zero_page_addr = GuestAddress(ZEROPG_START);
@@ -135,34 +149,34 @@ pub fn configure_kernel(
     // Generate boot parameters.
     let mut bootparams = build_bootparams(guest_memory, GuestAddress(HIMEM_START))?;
 
-    // Build the kernel command line
-    let mut cmdline = Cmdline::new(CMDLINE_MAX_SIZE);
-    cmdline.insert_str(CMDLINE).map_err(Error::Cmdline)?;
-    for cmdline_str in cmdline_components {
-        cmdline.insert_str(&cmdline_str).map_err(Error::Cmdline)?;
-        cmdline.insert_str(" ").map_err(Error::Cmdline)?;
-    }
-
-    // Load initramfs if provided
-    if let Some(initramfs_path) = initramfs_path {
-        let (initramfs_addr, initramfs_size) = load_initramfs(guest_memory, initramfs_path)?;
+    match root {
+        RootFs::Initramfs(initramfs_path) => {
+            let (initramfs_addr, initramfs_size) = load_initramfs(guest_memory, initramfs_path)?;
 
-        // Add initramfs location to boot parameters
-        bootparams.hdr.ramdisk_image = initramfs_addr.raw_value() as u32;
-        bootparams.hdr.ramdisk_size = initramfs_size as u32;
+            // Add initramfs location to boot parameters
+            bootparams.hdr.ramdisk_image = initramfs_addr.raw_value() as u32;
+            bootparams.hdr.ramdisk_size = initramfs_size as u32;
 
-        // Add rdinit to command line
-        cmdline
-            .insert_str(format!(" rdinit={}", init_path.unwrap_or("/init")))
-            .map_err(Error::Cmdline)?;
+            // Add rdinit to command line
+            cmdline_components.push(format!(" rdinit={}", init_path.unwrap_or("/init")));
 
-        println!(
-            "Initramfs loaded: {} bytes at 0x{:x}",
-            initramfs_size,
-            initramfs_addr.raw_value()
-        );
+            println!(
+                "Initramfs loaded: {} bytes at 0x{:x}",
+                initramfs_size,
+                initramfs_addr.raw_value()
+            );
+        }
+        RootFs::Disk { device, read_only } => {
+            cmdline_components.push(disk_root_cmdline(&device, read_only, init_path));
+        }
     }
 
+    // Build the kernel command line
+    let line =
+        cmdline::merge(CMDLINE, &cmdline_components, cmdline_extra).map_err(Error::CmdlineParam)?;
+    let mut cmdline = Cmdline::new(cmdline::MAX_LEN);
+    cmdline.insert_str(line).map_err(Error::Cmdline)?;
+
     // Add the kernel command line to the boot parameters.
     bootparams.hdr.cmd_line_ptr = CMDLINE_START as u32;
     bootparams.hdr.cmdline_size = cmdline.as_str().len() as u32 + 1;
@@ -180,6 +194,15 @@ pub fn configure_kernel(
     Ok(kernel_load)
 }
 
+/// Kernel parameters mounting `device` as root filesystem.
+fn disk_root_cmdline(device: &str, read_only: bool, init_path: Option<&str>) -> String {
+    let mut params = format!(" root={} {}", device, if read_only { "ro" } else { "rw" });
+    if let Some(init_path) = init_path {
+        params.push_str(&format!(" init={}", init_path));
+    }
+    params
+}
+
 /// Load an initramfs image into guest memory at [`INITRAMFS_START`].
 ///
 /// Reads the whole file into a host buffer, then walks the guest memory regions
@@ -238,3 +261,20 @@ fn load_initramfs(
 
     Ok((initramfs_addr, initramfs_size))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_root_cmdline() {
+        assert_eq!(
+            disk_root_cmdline("/dev/vda", false, None),
+            " root=/dev/vda rw"
+        );
+        assert_eq!(
+            disk_root_cmdline("/dev/vda", true, Some("/sbin/agent-init")),
+            " root=/dev/vda ro init=/sbin/agent-init"
+        );
+    }
+}
@@ -8,30 +8,72 @@ extern crate linux_loader;
 extern crate vm_memory;
 extern crate vm_superio;
 
+use std::fs::File;
+use std::io;
 use std::net::Ipv4Addr;
-use std::os::fd::AsRawFd;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::ops::ControlFlow;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::{io, path::PathBuf};
 
-use event_manager::{EventManager, MutEventSubscriber, SubscriberOps};
-use kvm_bindings::{kvm_userspace_memory_region, KVM_MAX_CPUID_ENTRIES};
-use kvm_ioctls::{Kvm, VmFd};
+use event_manager::{EventManager, MutEventSubscriber};
+use kvm_bindings::{
+    kvm_pit_config, kvm_userspace_memory_region, CpuId, KVM_MAX_CPUID_ENTRIES,
+    KVM_PIT_SPEAKER_DUMMY,
+};
+use kvm_ioctls::{Cap, Kvm, VmFd};
 use linux_loader::loader::{self, KernelLoaderResult};
-use vm_allocator::{AddressAllocator, AllocPolicy, RangeInclusive};
-use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use log::error;
+use vm_memory::{
+    Address, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+    GuestRegionMmap, MmapRegion,
+};
+use vmm_sys_util::eventfd::EventFd;
 mod cpu;
 use cpu::{cpuid, mptable, Vcpu};
 mod devices;
 use devices::serial::LumperSerial;
-use devices::stdin::StdinHandler;
+pub use devices::serial_sinks::{SerialHandle, SerialSinkId};
+pub use devices::stdin::InputHandle;
+use devices::stdin::{swap_handler, ConsoleInput, StdinHandler};
+pub use devices::virtio::mem::device::MemoryResize;
+pub use devices::virtio::net::device::{MAX_MTU, MIN_MTU};
+pub use devices::virtio::net::simple_handler::NotificationStats as NetNotificationStats;
 
+use crate::devices::virtio::block::device::VirtioBlockDevice;
+use crate::devices::virtio::mem::device::VirtioMemDevice;
+use crate::devices::virtio::mem::MEMORY_BLOCK_SIZE;
 use crate::devices::virtio::net::device::VirtioNetDevice;
-use crate::irq_allocator::IrqAllocator;
+use crate::devices::virtio::net::tap::Tap;
+use crate::devices::virtio::rng::device::VirtioRngDevice;
+use crate::devices::virtio::state::VirtioSnapshot;
+use crate::irq_allocator::{IrqAllocator, IrqError, SERIAL_GSI};
+use crate::mmio_allocator::MmioAllocator;
 
+mod acpi;
+pub mod api;
+pub mod cmdline;
+pub mod fd_passing;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod guest_access;
+pub use guest_access::GuestAccessError;
+mod hotplug;
+pub use hotplug::VcpuHotplug;
 mod irq_allocator;
+pub use irq_allocator::IrqError;
 mod kernel;
+mod mmio_allocator;
+mod paging;
+pub use paging::MemoryPaging;
+mod snapshot;
+mod stop;
+pub use stop::StopHandle;
+mod terminal;
+pub use snapshot::{Error as SnapshotError, SnapshotHandle, MEMORY_FILE, STATE_FILE};
+use snapshot::{LoopPause, VcpuPause, VmState};
 
 #[cfg(target_arch = "x86_64")]
 pub(crate) const MMIO_GAP_END: u64 = 1 << 32;
@@ -69,7 +111,9 @@ pub enum Error {
     /// IRQ registration error
     IrqRegister(io::Error),
     /// Terminal configuration error
-    TerminalConfigure(kvm_ioctls::Error),
+    TerminalConfigure(io::Error),
+    /// Failed to hand the console input over to the event loop.
+    InputAttach(event_manager::Error),
     /// epoll creation error
     EpollError(io::Error),
     /// STDIN read error
@@ -81,6 +125,49 @@ pub enum Error {
     /// Address allocation error
     AddressAllocation(vm_allocator::Error),
     Virtio(devices::virtio::Error),
+    /// Every register window of the MMIO gap is in use.
+    MmioExhausted,
+    /// No GSI left for a device, or two interrupts on the same GSI.
+    Irq(IrqError),
+    /// Booting without an initramfs needs a block device to use as root filesystem.
+    NoRootDevice,
+    /// Invalid kernel parameters, or a command line too long for the kernel.
+    CmdlineParam(cmdline::Error),
+    /// Failed to write the ACPI tables to guest memory.
+    AcpiTables(GuestAccessError),
+    /// The VM has no power button, ACPI is disabled.
+    NoPowerButton,
+    /// Adding vCPUs after boot needs ACPI.
+    VcpuHotplugWithoutAcpi,
+    /// The VM cannot get more vCPUs, see [`VMM::set_max_vcpus`].
+    NoVcpuHotplug,
+    /// The VM already has its maximum number of vCPUs.
+    VcpuLimit(u8),
+    /// The memory device must be added once, before the other virtio devices, which
+    /// keep their own view of guest memory.
+    MemoryDeviceAfterDevices,
+    /// The VM has no memory device, see [`VMM::add_memory_device`].
+    NoMemoryHotplug,
+    /// A memory size out of `min_mib..=max_mib`: the boot memory and the reserved region.
+    MemorySize {
+        min_mib: usize,
+        max_mib: usize,
+    },
+    /// Guest memory read or write out of guest memory, or in the MMIO gap.
+    GuestAccess(GuestAccessError),
+    /// An MTU out of [`MIN_MTU`]`..=`[`MAX_MTU`].
+    InvalidMtu(u16),
+    /// The TAP passed as a descriptor has another MTU than the one the guest would get.
+    MtuMismatch {
+        mtu: u16,
+        tap_mtu: u16,
+    },
+    /// The kernel refused to let KSM merge guest memory, e.g. built without `CONFIG_KSM`.
+    Mergeable(io::Error),
+    /// Failed to read a snapshot, or to put the VM back in its state.
+    Snapshot(snapshot::Error),
+    /// [`VMM::restore`] on a VMM not created with [`VMM::from_snapshot`], or twice.
+    NotFromSnapshot,
 }
 
 /// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
@@ -88,33 +175,140 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct VMM {
     vm_fd: Arc<VmFd>,
-    kvm: Kvm,
+    kvm: Arc<Kvm>,
     guest_memory: Arc<GuestMemoryMmap>,
     vcpus: Vec<Vcpu>,
     serial: Arc<Mutex<LumperSerial>>,
+    serial_output: SerialHandle,
     virtio_net: Option<Arc<Mutex<VirtioNetDevice>>>,
+    virtio_blocks: Vec<Arc<Mutex<VirtioBlockDevice>>>,
+    virtio_mem: Option<Arc<Mutex<VirtioMemDevice>>>,
+    virtio_rng: Option<Arc<Mutex<VirtioRngDevice>>>,
+    /// Set up with the memory device, see [`VMM::add_memory_device`].
+    memory_resize: Option<MemoryResize>,
+    /// Whether KSM can merge guest memory, see [`VMM::set_mergeable`].
+    mergeable: bool,
     cmdline_components: Vec<String>,
+    /// Kernel parameters given with [`VMM::append_cmdline`].
+    cmdline_extra: Vec<String>,
+    /// Whether ACPI tables are written next to the MP table, see [`VMM::set_acpi`].
+    acpi: bool,
+    /// Set up with the ACPI tables.
+    power_button: Option<PowerButton>,
+    /// vCPUs the VM can have, see [`VMM::set_max_vcpus`].
+    max_vcpus: u8,
+    /// Set up with the ACPI tables when `max_vcpus` leaves room for more vCPUs.
+    vcpu_hotplug: Option<VcpuHotplug>,
     event_manager: EventManager<Arc<Mutex<dyn MutEventSubscriber>>>,
-    virtio_mmio_allocator: AddressAllocator,
+    virtio_mmio_allocator: MmioAllocator,
     irq_allocator: IrqAllocator,
-    running: Arc<AtomicBool>,
-    vcpu_handles: Vec<thread::JoinHandle<()>>,
+    running: StopHandle,
+    vcpu_handles: Arc<Mutex<Vec<thread::JoinHandle<()>>>>,
     vcpu_thread_ids: Arc<Mutex<Vec<libc::pthread_t>>>,
+    vcpu_pause: Arc<VcpuPause>,
+    /// Where the event loop waits while a snapshot is taken.
+    loop_pause: Arc<LoopPause>,
+    /// State of the snapshot the VMM was created from, until [`VMM::restore`].
+    restored: Option<VmState>,
+    /// Console input, see [`VMM::attach_input`].
+    console_input: Arc<Mutex<ConsoleInput>>,
 }
 
-pub trait VMInput: std::io::Read + AsRawFd {}
-impl<T: std::io::Read + AsRawFd> VMInput for T {}
+/// Network device of a VM, see [`VMM::add_net_device`].
+#[derive(Debug)]
+pub struct NetConfig {
+    pub backend: NetBackend,
+    /// MTU of the link, given to the guest with `VIRTIO_NET_F_MTU`; `None` leaves
+    /// it to Ethernet's 1500. A TAP opened by name is set to it, one passed as a
+    /// descriptor must have it already. Between [`MIN_MTU`] and [`MAX_MTU`].
+    pub mtu: Option<u16>,
+}
+
+impl From<NetBackend> for NetConfig {
+    fn from(backend: NetBackend) -> Self {
+        NetConfig { backend, mtu: None }
+    }
+}
+
+/// Backend of a network device.
+#[derive(Debug)]
+pub enum NetBackend {
+    /// TAP opened by name, created if missing. Needs `CAP_NET_ADMIN`.
+    Tap(String),
+    /// TAP already open, e.g. received from a privileged helper with
+    /// [`fd_passing::recv_fd`], so that the VMM can run without capabilities. The device
+    /// takes ownership of the descriptor.
+    TapFd(RawFd),
+}
+
+/// Open the TAP `if_name`, creating it if missing, for a VMM given it as
+/// [`NetBackend::TapFd`]. Needs `CAP_NET_ADMIN`, unlike the VMM.
+pub fn open_tap(if_name: &str) -> Result<OwnedFd> {
+    let tap =
+        Tap::open_named(if_name).map_err(|e| Error::Virtio(devices::virtio::Error::Tap(e)))?;
+    // The fd comes straight from the Tap, which no longer owns it.
+    Ok(unsafe { OwnedFd::from_raw_fd(tap.into_raw_fd()) })
+}
+
+/// Presses the ACPI power button of a VM from any thread.
+/// Obtained with [`VMM::power_button_handle`].
+#[derive(Clone)]
+pub struct PowerButton(Arc<EventFd>);
+
+impl PowerButton {
+    /// Raise the interrupt of the ACPI event device, which notifies a press to the guest.
+    pub fn press(&self) -> io::Result<()> {
+        self.0.write(1)
+    }
+}
+
+/// Console input of a VM, fed to its serial port.
+pub trait VMInput: std::io::Read + AsRawFd + Send {}
+impl<T: std::io::Read + AsRawFd + Send> VMInput for T {}
 impl VMM {
-    /// Create a new VMM.
+    /// Create a new VMM. Without `input`, the guest serial port gets no input until
+    /// one is attached, as services running headless need.
     pub fn new(
-        input: Box<dyn VMInput>,
+        input: Option<Box<dyn VMInput>>,
         output: Box<dyn std::io::Write + Send>,
         memory_size: usize,
     ) -> Result<Self> {
         // Create a KVM VM object.
         let kvm = Kvm::new().map_err(Error::KvmIoctl)?;
         let vm_fd = kvm.create_vm().map_err(Error::KvmIoctl)?;
+        let guest_memory = Self::configure_memory(&vm_fd, memory_size)?;
+
+        Self::with_memory(kvm, vm_fd, guest_memory, input, output)
+    }
+
+    /// Create a VMM whose guest memory is the one of the snapshot in `dir`, mapped
+    /// copy-on-write: the VMs restored from a snapshot share the pages none of them
+    /// wrote to. Add the devices the VM snapshotted had, then call [`VMM::restore`]
+    /// instead of `configure`.
+    pub fn from_snapshot(
+        input: Option<Box<dyn VMInput>>,
+        output: Box<dyn std::io::Write + Send>,
+        dir: &Path,
+    ) -> Result<Self> {
+        let state = VmState::load(dir).map_err(Error::Snapshot)?;
 
+        let kvm = Kvm::new().map_err(Error::KvmIoctl)?;
+        let vm_fd = kvm.create_vm().map_err(Error::KvmIoctl)?;
+        let guest_memory =
+            Self::map_snapshot_memory(&vm_fd, &dir.join(MEMORY_FILE), state.memory_size)?;
+
+        let mut vmm = Self::with_memory(kvm, vm_fd, guest_memory, input, output)?;
+        vmm.restored = Some(state);
+        Ok(vmm)
+    }
+
+    fn with_memory(
+        kvm: Kvm,
+        vm_fd: VmFd,
+        guest_memory: GuestMemoryMmap,
+        input: Option<Box<dyn VMInput>>,
+        output: Box<dyn std::io::Write + Send>,
+    ) -> Result<Self> {
         // Create event manager
         let mut event_manager: EventManager<Arc<Mutex<dyn MutEventSubscriber>>> =
             EventManager::new().map_err(|e| {
@@ -124,37 +318,51 @@ impl VMM {
                 ))
             })?;
 
-        let virtio_mmio_allocator =
-            AddressAllocator::new(MMIO_GAP_START, 0x2000).map_err(Error::AddressAllocation)?;
-
-        let guest_memory = Self::configure_memory(&vm_fd, memory_size)?;
+        let virtio_mmio_allocator = MmioAllocator::new().map_err(Error::AddressAllocation)?;
 
-        let serial = Arc::new(Mutex::new(
-            LumperSerial::new(output).map_err(Error::SerialCreation)?,
-        ));
+        let serial = LumperSerial::new(output).map_err(Error::SerialCreation)?;
+        let serial_output = serial.output();
+        let serial = Arc::new(Mutex::new(serial));
 
-        // Create stdin handler and add it to event manager
-        let stdin_handler: Arc<Mutex<dyn MutEventSubscriber>> =
-            Arc::new(Mutex::new(StdinHandler::new(input, serial.clone())));
-        event_manager.add_subscriber(stdin_handler);
+        // Wakes the event loop of `run()` when the VM is stopped.
+        let running = StopHandle::new().map_err(Error::IO)?;
+        event_manager.add_subscriber(Arc::new(Mutex::new(running.exit_handler())));
 
         let mut vmm = VMM {
             vm_fd: Arc::new(vm_fd),
-            kvm,
+            kvm: Arc::new(kvm),
             guest_memory: Arc::new(guest_memory),
             vcpus: vec![],
             serial,
+            serial_output,
             virtio_net: None,
+            virtio_blocks: Vec::new(),
+            virtio_mem: None,
+            virtio_rng: None,
+            memory_resize: None,
+            mergeable: false,
             virtio_mmio_allocator,
             cmdline_components: Vec::new(),
+            cmdline_extra: Vec::new(),
+            acpi: false,
+            power_button: None,
+            max_vcpus: 0,
+            vcpu_hotplug: None,
             event_manager,
-            irq_allocator: IrqAllocator::new(5),
-            running: Arc::new(AtomicBool::new(true)),
-            vcpu_handles: Vec::new(),
+            irq_allocator: IrqAllocator::with_legacy_irqs(),
+            running,
+            vcpu_handles: Arc::new(Mutex::new(Vec::new())),
             vcpu_thread_ids: Arc::new(Mutex::new(Vec::new())),
+            vcpu_pause: Arc::new(VcpuPause::default()),
+            loop_pause: Arc::new(LoopPause::default()),
+            restored: None,
+            console_input: Arc::new(Mutex::new(ConsoleInput::default())),
         };
 
         vmm.configure_io()?;
+        if let Some(input) = input {
+            vmm.attach_input(input)?;
+        }
 
         Ok(vmm)
     }
@@ -164,22 +372,86 @@ impl VMM {
             .map_err(Error::Memory)?;
 
         for (index, region) in guest_memory.iter().enumerate() {
-            let kvm_memory_region = kvm_userspace_memory_region {
-                slot: index as u32,
-                guest_phys_addr: region.start_addr().raw_value(),
-                memory_size: region.len() as u64,
-                // It's safe to unwrap because the guest address is valid.
-                userspace_addr: guest_memory.get_host_address(region.start_addr()).unwrap() as u64,
-                flags: 0,
-            };
+            Self::register_memory_region(vm_fd, &guest_memory, index as u32, region)?;
+        }
 
-            // Register the KVM memory region with KVM.
-            unsafe { vm_fd.set_user_memory_region(kvm_memory_region) }.map_err(Error::KvmIoctl)?;
+        Ok(guest_memory)
+    }
+
+    /// Map the memory dump at `path` as guest memory, privately: the pages the guest
+    /// writes to become its own, the file stays as it is.
+    fn map_snapshot_memory(vm_fd: &VmFd, path: &Path, size: u64) -> Result<GuestMemoryMmap> {
+        let file = File::open(path).map_err(Error::IO)?;
+        let mapping = MmapRegion::build(
+            Some(FileOffset::new(file, 0)),
+            size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_NORESERVE | libc::MAP_PRIVATE,
+        )
+        .map_err(|e| Error::Memory(vm_memory::Error::MmapRegion(e)))?;
+        let region = GuestRegionMmap::new(mapping, GuestAddress(0)).map_err(Error::Memory)?;
+        let guest_memory = GuestMemoryMmap::from_regions(vec![region]).map_err(Error::Memory)?;
+
+        for (index, region) in guest_memory.iter().enumerate() {
+            Self::register_memory_region(vm_fd, &guest_memory, index as u32, region)?;
         }
 
         Ok(guest_memory)
     }
 
+    /// Map `region` of `guest_memory` into the guest with the KVM memory slot `slot`.
+    fn register_memory_region(
+        vm_fd: &VmFd,
+        guest_memory: &GuestMemoryMmap,
+        slot: u32,
+        region: &GuestRegionMmap,
+    ) -> Result<()> {
+        let kvm_memory_region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: region.start_addr().raw_value(),
+            memory_size: region.len() as u64,
+            // It's safe to unwrap because the guest address is valid.
+            userspace_addr: guest_memory.get_host_address(region.start_addr()).unwrap() as u64,
+            flags: 0,
+        };
+
+        // Register the KVM memory region with KVM.
+        unsafe { vm_fd.set_user_memory_region(kvm_memory_region) }.map_err(Error::KvmIoctl)
+    }
+
+    /// Let KSM merge the pages of `region` of `guest_memory`.
+    fn mark_mergeable(guest_memory: &GuestMemoryMmap, region: &GuestRegionMmap) -> Result<()> {
+        // It's safe to unwrap because the guest address is valid.
+        let addr = guest_memory.get_host_address(region.start_addr()).unwrap();
+        // SAFETY: the range is the mapping of the region, which outlives the call;
+        // the advice changes no content.
+        let ret = unsafe {
+            libc::madvise(
+                addr as *mut libc::c_void,
+                region.len() as usize,
+                libc::MADV_MERGEABLE,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::Mergeable(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Let the host kernel merge the pages of guest memory that are identical to
+    /// pages of other VMs, with kernel samepage merging (KSM).
+    ///
+    /// Applies to the boot memory and to the region of the memory device, added
+    /// before or after. Pages are only merged while the host runs KSM, see
+    /// `/sys/kernel/mm/ksm/run`.
+    pub fn set_mergeable(&mut self) -> Result<()> {
+        for region in self.guest_memory.iter() {
+            Self::mark_mergeable(&self.guest_memory, region)?;
+        }
+        self.mergeable = true;
+        Ok(())
+    }
+
     pub fn configure_io(&mut self) -> Result<()> {
         // First, create the irqchip.
         // On `x86_64`, this _must_ be created _before_ the vCPUs.
@@ -188,45 +460,78 @@ impl VMM {
         // https://elixir.bootlin.com/linux/latest/source/arch/x86/kvm/x86.c
         self.vm_fd.create_irq_chip().map_err(Error::KvmIoctl)?;
 
+        // The in-kernel PIT is the legacy timer (IRQ 0) the guest expects at boot, before it
+        // moves to the local APIC timer. Like the irqchip, it must exist before the vCPUs.
+        // Port 0x61 (PC speaker) reads go to a dummy, there is no speaker to drive.
+        let pit_config = kvm_pit_config {
+            flags: KVM_PIT_SPEAKER_DUMMY,
+            ..Default::default()
+        };
         self.vm_fd
-            .register_irqfd(
-                &self
-                    .serial
-                    .lock()
-                    .unwrap()
-                    .eventfd()
-                    .map_err(Error::IrqRegister)?,
-                4,
-            )
+            .create_pit2(pit_config)
             .map_err(Error::KvmIoctl)?;
 
+        let serial_evt = self
+            .serial
+            .lock()
+            .unwrap()
+            .eventfd()
+            .map_err(Error::IrqRegister)?;
+        self.register_irqfd(&serial_evt, SERIAL_GSI)?;
+
         Ok(())
     }
 
     /// Add a VirtIO network device with TAP backend
     pub fn add_net_device(
         &mut self,
-        tap_name: String,
+        net: NetConfig,
         guest_ip: Option<Ipv4Addr>,
         host_ip: Option<Ipv4Addr>,
         netmask: Option<Ipv4Addr>,
     ) -> Result<()> {
-        let allocated_range: RangeInclusive = self
+        let allocated_range = self
             .virtio_mmio_allocator
-            .allocate(0x1000, 0x1000, AllocPolicy::FirstMatch)
-            .map_err(Error::AddressAllocation)?;
+            .allocate()
+            .ok_or(Error::MmioExhausted)?;
+
+        let NetConfig { backend, mtu } = net;
+        if let Some(mtu) = mtu.filter(|mtu| !(MIN_MTU..=MAX_MTU).contains(mtu)) {
+            return Err(Error::InvalidMtu(mtu));
+        }
+        let tap_error = |e| Error::Virtio(devices::virtio::Error::Tap(e));
+        let opened_by_name = matches!(backend, NetBackend::Tap(_));
+        let tap = match backend {
+            NetBackend::Tap(name) => Tap::open_named(&name),
+            NetBackend::TapFd(fd) => Tap::from_fd(fd),
+        }
+        .map_err(tap_error)?;
+        // Every hop must take the frames the guest sends: the TAP gets the MTU of the
+        // guest, or must have it already when the VMM cannot set it.
+        if let Some(mtu) = mtu {
+            if opened_by_name {
+                tap.set_mtu(mtu).map_err(tap_error)?;
+            } else {
+                let tap_mtu = tap.mtu().map_err(tap_error)?;
+                if tap_mtu != mtu {
+                    return Err(Error::MtuMismatch { mtu, tap_mtu });
+                }
+            }
+        }
 
-        let irq = self.irq_allocator.allocate();
+        let (irq, irqfd) = self.device_interrupt()?;
 
         let endpoint = self.event_manager.remote_endpoint();
 
         let net = VirtioNetDevice::new(
             self.vm_fd.clone(),
             irq,
-            tap_name,
+            irqfd,
+            tap,
             self.guest_memory.clone(),
             allocated_range,
             endpoint,
+            mtu,
         )
         .map_err(Error::Virtio)?;
 
@@ -243,41 +548,258 @@ impl VMM {
         Ok(())
     }
 
+    /// Add a VirtIO block device backed by a file or a host block device.
+    /// Guests name disks in the order they are added: the first one is `/dev/vda`.
+    pub fn add_block_device(&mut self, path: &Path, read_only: bool) -> Result<()> {
+        let allocated_range = self
+            .virtio_mmio_allocator
+            .allocate()
+            .ok_or(Error::MmioExhausted)?;
+
+        let (irq, irqfd) = self.device_interrupt()?;
+
+        let endpoint = self.event_manager.remote_endpoint();
+
+        let block = VirtioBlockDevice::new(
+            self.vm_fd.clone(),
+            irq,
+            irqfd,
+            path,
+            read_only,
+            self.guest_memory.clone(),
+            allocated_range,
+            endpoint,
+        )
+        .map_err(Error::Virtio)?;
+
+        self.cmdline_components.push(block.cmdline_string());
+        self.virtio_blocks.push(Arc::new(Mutex::new(block)));
+
+        Ok(())
+    }
+
+    /// Add a virtio-rng device, which gives the guest random bytes of the host RNG.
+    /// The guest kernel mixes them into its own pool, and `/dev/hwrng` reads them
+    /// straight from the host: clones of a snapshot reseed from it rather than share
+    /// the RNG state they were saved with.
+    pub fn add_rng_device(&mut self) -> Result<()> {
+        let allocated_range = self
+            .virtio_mmio_allocator
+            .allocate()
+            .ok_or(Error::MmioExhausted)?;
+
+        let (irq, irqfd) = self.device_interrupt()?;
+
+        let endpoint = self.event_manager.remote_endpoint();
+
+        let rng = VirtioRngDevice::new(
+            self.vm_fd.clone(),
+            irq,
+            irqfd,
+            self.guest_memory.clone(),
+            allocated_range,
+            endpoint,
+        )
+        .map_err(Error::Virtio)?;
+
+        self.cmdline_components.push(rng.cmdline_string());
+        self.virtio_rng = Some(Arc::new(Mutex::new(rng)));
+
+        Ok(())
+    }
+
+    /// Add a virtio-mem device, so that the memory of the VM can be resized up to
+    /// `max_memory_mib` MiB once it runs, with [`VMM::resize_memory`].
+    ///
+    /// The room for the memory past the boot size is reserved above the MMIO gap, without
+    /// host memory behind it until the guest plugs some. Must be added before the other
+    /// virtio devices, since guest memory changes.
+    pub fn add_memory_device(&mut self, max_memory_mib: usize) -> Result<()> {
+        if self.virtio_mem.is_some()
+            || self.virtio_net.is_some()
+            || self.virtio_rng.is_some()
+            || !self.virtio_blocks.is_empty()
+        {
+            return Err(Error::MemoryDeviceAfterDevices);
+        }
+        let boot_size = self.guest_memory.last_addr().raw_value() + 1;
+        let max_size = (max_memory_mib as u64) << 20;
+        if max_size <= boot_size {
+            return Err(Error::MemorySize {
+                min_mib: (boot_size >> 20) as usize + 1,
+                max_mib: usize::MAX,
+            });
+        }
+
+        // Linux adds the region to its memory in whole memory blocks.
+        let region_size = (max_size - boot_size).next_multiple_of(MEMORY_BLOCK_SIZE);
+        let region_addr = boot_size
+            .max(MMIO_GAP_END)
+            .next_multiple_of(MEMORY_BLOCK_SIZE);
+        let mapping = MmapRegion::new(region_size as usize)
+            .map_err(|e| Error::Memory(vm_memory::Error::MmapRegion(e)))?;
+        let region = Arc::new(
+            GuestRegionMmap::new(mapping, GuestAddress(region_addr)).map_err(Error::Memory)?,
+        );
+        let guest_memory = self
+            .guest_memory
+            .insert_region(Arc::clone(&region))
+            .map_err(Error::Memory)?;
+        Self::register_memory_region(
+            &self.vm_fd,
+            &guest_memory,
+            self.guest_memory.num_regions() as u32,
+            &region,
+        )?;
+        if self.mergeable {
+            Self::mark_mergeable(&guest_memory, &region)?;
+        }
+        self.guest_memory = Arc::new(guest_memory);
+
+        let allocated_range = self
+            .virtio_mmio_allocator
+            .allocate()
+            .ok_or(Error::MmioExhausted)?;
+
+        let (irq, irqfd) = self.device_interrupt()?;
+
+        let endpoint = self.event_manager.remote_endpoint();
+
+        let mem = VirtioMemDevice::new(
+            self.vm_fd.clone(),
+            irq,
+            irqfd,
+            region_addr,
+            region_size,
+            self.guest_memory.clone(),
+            allocated_range,
+            endpoint,
+        )
+        .map_err(Error::Virtio)?;
+
+        self.cmdline_components.push(mem.cmdline_string());
+        // Memory the guest can unplug again must not hold kernel allocations.
+        self.cmdline_components
+            .push(" memhp_default_state=online_movable".to_string());
+        self.memory_resize = Some(mem.resize_handle(boot_size));
+        self.virtio_mem = Some(Arc::new(Mutex::new(mem)));
+
+        Ok(())
+    }
+
+    /// Ask the guest to grow or shrink its memory to `mib` MiB, boot memory included.
+    ///
+    /// The guest plugs or unplugs blocks of the region reserved by [`VMM::add_memory_device`]
+    /// in the background; unplugged blocks are given back to the host. See
+    /// [`MemoryResize::plugged_mib`] for how far it got.
+    pub fn resize_memory(&self, mib: usize) -> Result<()> {
+        self.memory_resize
+            .as_ref()
+            .ok_or(Error::NoMemoryHotplug)?
+            .resize(mib)
+    }
+
+    /// Return a handle to resize the memory while `run()` is executing on another
+    /// thread, `None` without a memory device.
+    pub fn memory_resize_handle(&self) -> Option<MemoryResize> {
+        self.memory_resize.clone()
+    }
+
+    /// Copy the guest memory at guest physical address `addr` into `buf`.
+    ///
+    /// Fails with [`Error::GuestAccess`] if part of the range is not guest memory or
+    /// falls in the MMIO gap. Safe to call while the VM runs, the guest may change the
+    /// memory under the copy.
+    pub fn read_guest(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        guest_access::read(&self.guest_memory, addr, buf).map_err(Error::GuestAccess)
+    }
+
+    /// Copy `buf` to the guest memory at guest physical address `addr`, with the same
+    /// checks as [`VMM::read_guest`].
+    pub fn write_guest(&self, addr: u64, buf: &[u8]) -> Result<()> {
+        guest_access::write(&self.guest_memory, addr, buf).map_err(Error::GuestAccess)
+    }
+
+    /// Add kernel parameters to the ones generated for the devices and root filesystem.
+    ///
+    /// `fragment` is split on spaces outside of double quotes. A parameter replaces the
+    /// defaults of the same name (`console=`, `panic=`, ...), and one that is already
+    /// there is ignored. Parameters the VMM sets itself (`root=`, `ip=`, ...) are rejected.
+    pub fn append_cmdline(&mut self, fragment: &str) -> Result<()> {
+        let mut extra = self.cmdline_extra.clone();
+        for param in cmdline::parse(fragment).map_err(Error::CmdlineParam)? {
+            if !extra.contains(&param) {
+                extra.push(param);
+            }
+        }
+        // Catch a command line that is already too long now rather than in `configure`.
+        cmdline::merge(kernel::CMDLINE, &self.cmdline_components, &extra)
+            .map_err(Error::CmdlineParam)?;
+
+        self.cmdline_extra = extra;
+        Ok(())
+    }
+
+    /// Describe the vCPUs, interrupt controllers and power button with ACPI tables too.
+    ///
+    /// Kernels that find ACPI tables prefer them to the MP table, which is still written
+    /// for the others. Off by default; must be set before `configure`.
+    pub fn set_acpi(&mut self, enabled: bool) {
+        self.acpi = enabled;
+    }
+
+    /// Let the VM grow to `max_vcpus` vCPUs once it runs, with [`VMM::hotplug_vcpu`].
+    ///
+    /// The vCPUs past the ones `configure` boots are declared to the guest but absent.
+    /// Needs ACPI; must be set before `configure`.
+    pub fn set_max_vcpus(&mut self, max_vcpus: u8) {
+        self.max_vcpus = max_vcpus;
+    }
+
     pub fn configure_vcpus(
         &mut self,
         num_vcpus: u8,
         kernel_load: KernelLoaderResult,
     ) -> Result<()> {
-        mptable::setup_mptable(&self.guest_memory, num_vcpus)
-            .map_err(|e| Error::Vcpu(cpu::Error::Mptable(e)))?;
-
-        let base_cpuid = self
-            .kvm
-            .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
-            .map_err(Error::KvmIoctl)?;
+        let max_vcpus = self.max_vcpus.max(num_vcpus);
+        if max_vcpus > num_vcpus && !self.acpi {
+            return Err(Error::VcpuHotplugWithoutAcpi);
+        }
+        let cpu_status = Arc::new(acpi::CpuStatus::new(num_vcpus));
 
-        for index in 0..num_vcpus {
-            let vcpu = Vcpu::new(
-                &self.vm_fd,
-                index.into(),
-                Arc::clone(&self.serial),
-                self.virtio_net.clone(),
-                Arc::clone(&self.running),
+        mptable::setup_mptable(&self.guest_memory, num_vcpus, max_vcpus)
+            .map_err(|e| Error::Vcpu(cpu::Error::Mptable(e)))?;
+        let mut hotplug_interrupt = None;
+        if self.acpi {
+            let (gsi, power_button) = self.device_interrupt()?;
+            let hotplug = if max_vcpus > num_vcpus {
+                let (gsi, interrupt) = self.device_interrupt()?;
+                hotplug_interrupt = Some(interrupt);
+                Some(acpi::CpuHotplug {
+                    max_cpus: max_vcpus,
+                    gsi,
+                })
+            } else {
+                None
+            };
+            guest_access::write(
+                &self.guest_memory,
+                acpi::ACPI_START,
+                &acpi::tables(num_vcpus, gsi, hotplug.as_ref()),
             )
-            .map_err(Error::Vcpu)?;
+            .map_err(Error::AcpiTables)?;
+            self.power_button = Some(PowerButton(Arc::new(power_button)));
+        }
 
-            // Set CPUID.
-            let mut vcpu_cpuid = base_cpuid.clone();
-            cpuid::filter_cpuid(
-                &self.kvm,
-                index as usize,
-                num_vcpus as usize,
-                &mut vcpu_cpuid,
-            );
-            vcpu.configure_cpuid(&vcpu_cpuid).map_err(Error::Vcpu)?;
+        let mut factory = self.vcpu_factory(max_vcpus, Arc::clone(&cpu_status))?;
+        let tsc_control = self.kvm.check_extension(Cap::TscControl);
 
-            // Configure MSRs (model specific registers).
-            vcpu.configure_msrs().map_err(Error::Vcpu)?;
+        for index in 0..num_vcpus {
+            let vcpu = factory.create(index)?;
+            // The other vCPUs run their TSC at the frequency of the first one.
+            if tsc_control && factory.tsc_khz.is_none() {
+                factory.tsc_khz = Some(vcpu.tsc_khz().map_err(Error::Vcpu)?);
+            }
 
             // Configure regs, sregs and fpu.
             vcpu.configure_regs(kernel_load.kernel_load)
@@ -286,33 +808,140 @@ impl VMM {
                 .map_err(Error::Vcpu)?;
             vcpu.configure_fpu().map_err(Error::Vcpu)?;
 
-            // Configure LAPICs.
-            vcpu.configure_lapic().map_err(Error::Vcpu)?;
+            self.vcpus.push(vcpu);
+        }
+
+        self.vcpu_hotplug = hotplug_interrupt.map(|interrupt| {
+            VcpuHotplug::new(
+                factory,
+                self.vcpu_threads(),
+                cpu_status,
+                interrupt,
+                num_vcpus,
+            )
+        });
+
+        Ok(())
+    }
+
+    /// Put the VM back in the state of the snapshot it was created from, see
+    /// [`VMM::from_snapshot`]; takes the place of `configure`.
+    ///
+    /// The devices must be added as they were to the VM snapshotted, in the same
+    /// order: the guest finds them where they were, with its drivers set up. Their
+    /// TAP or disk are the ones given now. ACPI must be as it was; the kernel command
+    /// line and the ACPI tables are the ones in guest memory.
+    pub fn restore(&mut self) -> Result<()> {
+        let state = self.restored.take().ok_or(Error::NotFromSnapshot)?;
+        let unsupported = |what| Error::Snapshot(snapshot::Error::Unsupported(what));
+        if self.virtio_mem.is_some() {
+            return Err(unsupported("memory device"));
+        }
+        let num_vcpus = state.vcpus.len() as u8;
+        if self.max_vcpus > num_vcpus {
+            return Err(unsupported("vCPU hotplug"));
+        }
+
+        // The power button gets the GSI after those of the devices, as at boot.
+        if self.acpi {
+            let (_, power_button) = self.device_interrupt()?;
+            self.power_button = Some(PowerButton(Arc::new(power_button)));
+        }
+
+        let mismatch = || Error::Virtio(devices::virtio::Error::StateMismatch);
+        let mut saved = state.devices.iter();
+        if let Some(net) = &self.virtio_net {
+            let saved = saved.next().ok_or_else(mismatch)?;
+            net.lock().unwrap().restore(saved).map_err(Error::Virtio)?;
+        }
+        for block in &self.virtio_blocks {
+            let saved = saved.next().ok_or_else(mismatch)?;
+            block
+                .lock()
+                .unwrap()
+                .restore(saved)
+                .map_err(Error::Virtio)?;
+        }
+        if let Some(rng) = &self.virtio_rng {
+            let saved = saved.next().ok_or_else(mismatch)?;
+            rng.lock().unwrap().restore(saved).map_err(Error::Virtio)?;
+        }
+        if saved.next().is_some() {
+            return Err(mismatch());
+        }
 
+        self.serial.lock().unwrap().restore(&state.serial);
+        state.apply_to_vm(&self.vm_fd).map_err(Error::Snapshot)?;
+
+        let mut factory =
+            self.vcpu_factory(num_vcpus, Arc::new(acpi::CpuStatus::new(num_vcpus)))?;
+        // The guest calibrated its clocks against the TSC frequency it had.
+        if self.kvm.check_extension(Cap::TscControl) {
+            factory.tsc_khz = Some(state.vcpus[0].tsc_khz());
+        }
+        for (index, saved) in state.vcpus.iter().enumerate() {
+            let vcpu = factory.create(index as u8)?;
+            saved.apply(&vcpu).map_err(Error::Snapshot)?;
             self.vcpus.push(vcpu);
         }
 
         Ok(())
     }
 
+    fn vcpu_factory(&self, max_vcpus: u8, cpu_status: Arc<acpi::CpuStatus>) -> Result<VcpuFactory> {
+        Ok(VcpuFactory {
+            kvm: Arc::clone(&self.kvm),
+            vm_fd: Arc::clone(&self.vm_fd),
+            cpuid: self
+                .kvm
+                .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+                .map_err(Error::KvmIoctl)?,
+            max_vcpus,
+            tsc_khz: None,
+            serial: Arc::clone(&self.serial),
+            virtio_net: self.virtio_net.clone(),
+            virtio_blocks: self.virtio_blocks.clone(),
+            virtio_mem: self.virtio_mem.clone(),
+            virtio_rng: self.virtio_rng.clone(),
+            running: self.running.clone(),
+            cpu_status,
+        })
+    }
+
+    /// A GSI for a device, with the eventfd raising it when written to.
+    fn device_interrupt(&mut self) -> Result<(u32, EventFd)> {
+        let gsi = self
+            .irq_allocator
+            .allocate()
+            .ok_or(Error::Irq(IrqError::Exhausted))?;
+        let eventfd = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::IrqRegister)?;
+        self.register_irqfd(&eventfd, gsi)?;
+        Ok((gsi, eventfd))
+    }
+
+    /// Have `eventfd` raise `gsi`. Every irqfd goes through here, so that the allocator
+    /// catches a GSI that was not handed out, or that two devices would share.
+    fn register_irqfd(&mut self, eventfd: &EventFd, gsi: u32) -> Result<()> {
+        self.irq_allocator.wire(gsi).map_err(Error::Irq)?;
+        self.vm_fd
+            .register_irqfd(eventfd, gsi)
+            .map_err(Error::KvmIoctl)
+    }
+
+    fn vcpu_threads(&self) -> VcpuThreads {
+        VcpuThreads {
+            running: self.running.clone(),
+            thread_ids: Arc::clone(&self.vcpu_thread_ids),
+            pause: Arc::clone(&self.vcpu_pause),
+            handles: Arc::clone(&self.vcpu_handles),
+        }
+    }
+
     fn start_vcpus(&mut self) {
-        for mut vcpu in self.vcpus.drain(..) {
+        let threads = self.vcpu_threads();
+        for vcpu in self.vcpus.drain(..) {
             println!("Starting vCPU {:?}", vcpu.index);
-            let vcpu_running = Arc::clone(&self.running);
-            let thread_ids = Arc::clone(&self.vcpu_thread_ids);
-            let handle = thread::Builder::new()
-                .spawn(move || {
-                    thread_ids
-                        .lock()
-                        .unwrap()
-                        .push(unsafe { libc::pthread_self() });
-
-                    while vcpu_running.load(Ordering::SeqCst) {
-                        vcpu.run();
-                    }
-                })
-                .expect("Failed to spawn vCPU thread");
-            self.vcpu_handles.push(handle);
+            threads.spawn(vcpu).expect("Failed to spawn vCPU thread");
         }
     }
 
@@ -327,7 +956,8 @@ impl VMM {
         }
         drop(tids);
 
-        for handle in self.vcpu_handles.drain(..) {
+        let handles: Vec<_> = self.vcpu_handles.lock().unwrap().drain(..).collect();
+        for handle in handles {
             let _ = handle.join();
         }
         self.vcpu_thread_ids.lock().unwrap().clear();
@@ -335,7 +965,7 @@ impl VMM {
 
     /// Run the VM: start vCPUs, run event loop, and wait for shutdown.
     pub fn run(&mut self) {
-        self.running.store(true, Ordering::SeqCst);
+        self.running.running.store(true, Ordering::SeqCst);
 
         // Install a no-op SIGUSR1 handler so pthread_kill interrupts KVM_RUN
         // with EINTR instead of terminating the process.
@@ -348,10 +978,10 @@ impl VMM {
 
         self.start_vcpus();
 
-        let running = Arc::clone(&self.running);
-        while running.load(Ordering::SeqCst) {
+        // Blocks until there is work for a device, or the VM is stopped.
+        while self.running.is_running() {
             self.event_manager
-                .run_with_timeout(100)
+                .run()
                 .expect("event manager loop should live forever");
         }
 
@@ -360,28 +990,171 @@ impl VMM {
 
     /// Stop the VM by signaling all threads to exit.
     pub fn stop(&self) {
-        self.running.store(false, Ordering::SeqCst);
+        self.running.stop();
+    }
+
+    /// Why the VM stopped, if a vCPU failed rather than the guest shutting down or
+    /// [`VMM::stop`] being called.
+    pub fn failure(&self) -> Option<String> {
+        self.running.failure()
+    }
+
+    /// Return a handle to stop the VM while `run()` is executing on another thread,
+    /// and to tell whether it still runs.
+    pub fn stop_handle(&self) -> StopHandle {
+        self.running.clone()
+    }
+
+    /// Send the guest serial output to `sink` as well, from now on.
+    pub fn add_serial_sink(&self, sink: Box<dyn std::io::Write + Send>) -> SerialSinkId {
+        self.serial_output.add_sink(sink)
+    }
+
+    /// Stop sending the guest serial output to the sink `id`, and hand it back.
+    pub fn remove_serial_sink(&self, id: SerialSinkId) -> Option<Box<dyn std::io::Write + Send>> {
+        self.serial_output.remove_sink(id)
+    }
+
+    /// Feed `input` to the guest serial port from now on, instead of the current input.
+    ///
+    /// When `input` is a terminal, keys typed go to the guest one by one, unechoed: it is
+    /// in raw mode until another input replaces it or the VMM is dropped.
+    pub fn attach_input(&mut self, input: Box<dyn VMInput>) -> Result<()> {
+        self.console_input
+            .lock()
+            .unwrap()
+            .set_terminal(Some(&*input))
+            .map_err(Error::TerminalConfigure)?;
+        let handler: Arc<Mutex<dyn MutEventSubscriber>> =
+            Arc::new(Mutex::new(StdinHandler::new(input, self.serial.clone())));
+        swap_handler(&mut self.event_manager, &self.console_input, Some(handler));
+        Ok(())
+    }
+
+    /// Return a handle to attach or detach the console input while `run()` is
+    /// executing on another thread, for interactive sessions.
+    pub fn input_handle(&self) -> InputHandle {
+        InputHandle {
+            serial: Arc::clone(&self.serial),
+            endpoint: self.event_manager.remote_endpoint(),
+            console: Arc::clone(&self.console_input),
+        }
+    }
+
+    /// Used buffers of the network device and the interrupts that signaled them, to check
+    /// how many `VIRTIO_F_RING_EVENT_IDX` saves. `None` without a network device.
+    pub fn net_notification_stats(&self) -> Option<NetNotificationStats> {
+        self.virtio_net
+            .as_ref()
+            .map(|net| net.lock().unwrap().notification_stats())
+    }
+
+    /// Return a handle to add or remove serial sinks while `run()` is executing
+    /// on another thread.
+    pub fn serial_handle(&self) -> SerialHandle {
+        self.serial_output.clone()
+    }
+
+    /// Return a handle that can snapshot the VM while `run()` is executing
+    /// on another thread. Snapshots fail for a VM with a memory device or room
+    /// for more vCPUs, which [`VMM::restore`] could not give back.
+    pub fn snapshot_handle(&self) -> SnapshotHandle {
+        let unsupported = if self.virtio_mem.is_some() {
+            Some("memory device")
+        } else if self.vcpu_hotplug.is_some() {
+            Some("vCPU hotplug")
+        } else {
+            None
+        };
+
+        SnapshotHandle {
+            vm_fd: Arc::clone(&self.vm_fd),
+            guest_memory: Arc::clone(&self.guest_memory),
+            pause: Arc::clone(&self.vcpu_pause),
+            loop_pause: Arc::clone(&self.loop_pause),
+            endpoint: Arc::new(Mutex::new(self.event_manager.remote_endpoint())),
+            running: Arc::clone(&self.running.running),
+            vcpu_thread_ids: Arc::clone(&self.vcpu_thread_ids),
+            serial: Arc::clone(&self.serial),
+            virtio_net: self.virtio_net.clone(),
+            virtio_blocks: self.virtio_blocks.clone(),
+            virtio_rng: self.virtio_rng.clone(),
+            unsupported,
+        }
+    }
+
+    /// Return a handle to page the guest memory out to swap and back in while
+    /// `run()` is executing on another thread. Covers the memory device only if
+    /// [`VMM::add_memory_device`] was called first.
+    pub fn paging_handle(&self) -> MemoryPaging {
+        MemoryPaging::new(Arc::clone(&self.guest_memory))
+    }
+
+    /// Press the ACPI power button, asking the guest to shut down cleanly.
+    ///
+    /// The guest decides what to do; if it powers off, `run()` returns. Fails with
+    /// [`Error::NoPowerButton`] unless ACPI was enabled before `configure`.
+    pub fn send_power_button(&self) -> Result<()> {
+        self.power_button
+            .as_ref()
+            .ok_or(Error::NoPowerButton)?
+            .press()
+            .map_err(Error::IO)
+    }
+
+    /// Return a handle to press the power button while `run()` is executing
+    /// on another thread, `None` without ACPI.
+    pub fn power_button_handle(&self) -> Option<PowerButton> {
+        self.power_button.clone()
     }
 
-    /// Return a handle to the internal running flag used by `run()`/vCPU loops.
-    /// Setting this flag to `false` from another thread requests a graceful stop.
-    pub fn stop_handle(&self) -> Arc<AtomicBool> {
-        Arc::clone(&self.running)
+    /// Add a vCPU to the VM, and return its index.
+    ///
+    /// The guest is notified through ACPI and brings the vCPU online itself (Linux
+    /// needs `echo 1 > /sys/devices/system/cpu/cpuN/online`, or a udev rule).
+    pub fn hotplug_vcpu(&self) -> Result<u8> {
+        self.vcpu_hotplug
+            .as_ref()
+            .ok_or(Error::NoVcpuHotplug)?
+            .add_vcpu()
     }
 
+    /// Return a handle to add vCPUs while `run()` is executing on another thread,
+    /// `None` unless [`VMM::set_max_vcpus`] left room for more.
+    pub fn vcpu_hotplug_handle(&self) -> Option<VcpuHotplug> {
+        self.vcpu_hotplug.clone()
+    }
+
+    /// Load the kernel and set the vCPUs up to boot it.
+    ///
+    /// Without `initramfs_path`, the root filesystem is the first block device, mounted
+    /// read-only if the device is. `init_path` defaults to `/init` in an initramfs, and
+    /// to the kernel's own search (`/sbin/init`, ...) on a disk.
     pub fn configure(
         &mut self,
         num_vcpus: u8,
         kernel_path: &str,
-        initramfs_path: &str,
+        initramfs_path: Option<&str>,
         init_path: Option<&str>,
     ) -> Result<()> {
+        let root = match initramfs_path {
+            Some(initramfs_path) => kernel::RootFs::Initramfs(PathBuf::from(initramfs_path)),
+            None => {
+                let disk = self.virtio_blocks.first().ok_or(Error::NoRootDevice)?;
+                kernel::RootFs::Disk {
+                    device: "/dev/vda".to_string(),
+                    read_only: disk.lock().unwrap().read_only(),
+                }
+            }
+        };
+
         let kernel_load = kernel::configure_kernel(
             &self.guest_memory,
             PathBuf::from(kernel_path),
-            Some(PathBuf::from(initramfs_path)),
+            root,
             init_path,
             self.cmdline_components.clone(),
+            &self.cmdline_extra,
         )?;
 
         self.configure_vcpus(num_vcpus, kernel_load)?;
@@ -390,5 +1163,111 @@ impl VMM {
     }
 }
 
+/// Creates vCPUs the same way at boot and when they are hotplugged.
+#[derive(Clone)]
+struct VcpuFactory {
+    kvm: Arc<Kvm>,
+    vm_fd: Arc<VmFd>,
+    /// CPUID supported by KVM, adjusted for each vCPU.
+    cpuid: CpuId,
+    /// vCPUs the guest can have, the CPU count announced in CPUID.
+    max_vcpus: u8,
+    /// TSC frequency of every vCPU when KVM can scale it, so that the guest sees a
+    /// single stable clock source.
+    tsc_khz: Option<u32>,
+    serial: Arc<Mutex<LumperSerial>>,
+    virtio_net: Option<Arc<Mutex<VirtioNetDevice>>>,
+    virtio_blocks: Vec<Arc<Mutex<VirtioBlockDevice>>>,
+    virtio_mem: Option<Arc<Mutex<VirtioMemDevice>>>,
+    virtio_rng: Option<Arc<Mutex<VirtioRngDevice>>>,
+    running: StopHandle,
+    cpu_status: Arc<acpi::CpuStatus>,
+}
+
+impl VcpuFactory {
+    /// Create the vCPU `index`, with its CPUID, TSC frequency, MSRs and LAPIC set up.
+    /// Registers are left to the boot code, or to the INIT and SIPI of the guest.
+    fn create(&self, index: u8) -> Result<Vcpu> {
+        let vcpu = Vcpu::new(
+            &self.vm_fd,
+            index.into(),
+            Arc::clone(&self.serial),
+            self.virtio_net.clone(),
+            self.virtio_blocks.clone(),
+            self.virtio_mem.clone(),
+            self.virtio_rng.clone(),
+            self.running.clone(),
+            Arc::clone(&self.cpu_status),
+        )
+        .map_err(Error::Vcpu)?;
+
+        // Set CPUID.
+        let mut vcpu_cpuid = self.cpuid.clone();
+        cpuid::filter_cpuid(
+            &self.kvm,
+            index as usize,
+            self.max_vcpus as usize,
+            &mut vcpu_cpuid,
+        );
+        vcpu.configure_cpuid(&vcpu_cpuid).map_err(Error::Vcpu)?;
+
+        if let Some(khz) = self.tsc_khz {
+            vcpu.set_tsc_khz(khz).map_err(Error::Vcpu)?;
+        }
+
+        // Configure MSRs (model specific registers).
+        vcpu.configure_msrs().map_err(Error::Vcpu)?;
+
+        // Configure LAPICs.
+        vcpu.configure_lapic().map_err(Error::Vcpu)?;
+
+        Ok(vcpu)
+    }
+}
+
+/// State the vCPU threads share with the VMM.
+#[derive(Clone)]
+struct VcpuThreads {
+    running: StopHandle,
+    thread_ids: Arc<Mutex<Vec<libc::pthread_t>>>,
+    pause: Arc<VcpuPause>,
+    handles: Arc<Mutex<Vec<thread::JoinHandle<()>>>>,
+}
+
+impl VcpuThreads {
+    /// Run `vcpu` on a new thread until the VM stops.
+    fn spawn(&self, mut vcpu: Vcpu) -> io::Result<()> {
+        let vcpu_running = self.running.clone();
+        let thread_ids = Arc::clone(&self.thread_ids);
+        let pause = Arc::clone(&self.pause);
+        let handle = thread::Builder::new().spawn(move || {
+            thread_ids
+                .lock()
+                .unwrap()
+                .push(unsafe { libc::pthread_self() });
+
+            while vcpu_running.is_running() {
+                if pause.is_requested() {
+                    pause.park(&vcpu, &vcpu_running.running);
+                    continue;
+                }
+                match vcpu.run() {
+                    Ok(ControlFlow::Continue(())) => {}
+                    Ok(ControlFlow::Break(())) => break,
+                    Err(e) => {
+                        let reason = format!("vCPU {} failed: {:?}", vcpu.index, e);
+                        error!("{}", reason);
+                        vcpu.dump_registers();
+                        vcpu_running.fail(reason);
+                        break;
+                    }
+                }
+            }
+        })?;
+        self.handles.lock().unwrap().push(handle);
+        Ok(())
+    }
+}
+
 /// No-op signal handler used to interrupt vCPU threads blocked in KVM_RUN.
 extern "C" fn empty_signal_handler(_: libc::c_int) {}
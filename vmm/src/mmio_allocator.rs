@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Register windows of the virtio-mmio devices.
+//!
+//! Windows are handed out from the start of the MMIO gap up to the IOAPIC, whose
+//! registers and those of the local APICs take the top of the gap.
+
+use vm_allocator::{AddressAllocator, AllocPolicy, RangeInclusive};
+
+use crate::MMIO_GAP_START;
+
+/// Size of the register window of a virtio-mmio device, also its alignment.
+pub(crate) const MMIO_DEVICE_SIZE: u64 = 0x1000;
+/// Base of the IOAPIC registers, followed by the local APICs at 0xfee0_0000.
+const IO_APIC_START: u64 = 0xfec0_0000;
+
+/// Hands out 4 KiB aligned register windows, one per virtio device.
+pub(crate) struct MmioAllocator(AddressAllocator);
+
+impl MmioAllocator {
+    /// Allocator for the MMIO gap, below the IOAPIC.
+    pub fn new() -> Result<Self, vm_allocator::Error> {
+        Self::with_range(MMIO_GAP_START, IO_APIC_START - MMIO_GAP_START)
+    }
+
+    /// Allocator for the `size` bytes at `base`, which must be aligned on a window.
+    fn with_range(base: u64, size: u64) -> Result<Self, vm_allocator::Error> {
+        debug_assert_eq!(base % MMIO_DEVICE_SIZE, 0);
+        AddressAllocator::new(base, size).map(MmioAllocator)
+    }
+
+    /// Returns `None` once every window is in use.
+    pub fn allocate(&mut self) -> Option<RangeInclusive> {
+        self.0
+            .allocate(MMIO_DEVICE_SIZE, MMIO_DEVICE_SIZE, AllocPolicy::FirstMatch)
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sixteen_devices() {
+        let mut allocator = MmioAllocator::new().unwrap();
+        let windows: Vec<RangeInclusive> = (0..16).map(|_| allocator.allocate().unwrap()).collect();
+
+        for window in &windows {
+            assert_eq!(window.start() % MMIO_DEVICE_SIZE, 0);
+            assert_eq!(window.len(), MMIO_DEVICE_SIZE);
+            assert!(window.start() >= MMIO_GAP_START && window.end() < IO_APIC_START);
+        }
+        for (i, window) in windows.iter().enumerate() {
+            assert!(windows[i + 1..].iter().all(|other| !window.overlaps(other)));
+        }
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let mut allocator =
+            MmioAllocator::with_range(MMIO_GAP_START, 2 * MMIO_DEVICE_SIZE).unwrap();
+        assert_eq!(allocator.allocate().unwrap().start(), MMIO_GAP_START);
+        assert_eq!(
+            allocator.allocate().unwrap().start(),
+            MMIO_GAP_START + MMIO_DEVICE_SIZE
+        );
+        assert!(allocator.allocate().is_none());
+    }
+}
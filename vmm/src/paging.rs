@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Paging the memory of an idle VM out to swap, and back in.
+//!
+//! Guest memory is anonymous memory of the VMM process: the host kernel can
+//! reclaim it to swap, or zswap, like any other. An idle VM touches little of
+//! it, so paging it all out at once frees most of its memory until it runs
+//! again. Paging it back in before then, in one call, spares the guest a fault
+//! per page on its first steps.
+//!
+//! Both are advice: a host without swap keeps the pages, and the guest can
+//! touch any page at any time, paged out or not.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+/// Pages the memory of a VM out and back in from any thread.
+/// Obtained with [`crate::VMM::paging_handle`].
+#[derive(Clone)]
+pub struct MemoryPaging {
+    guest_memory: Arc<GuestMemoryMmap>,
+    /// Whether the memory was paged out since it was last paged in. Held while
+    /// paging, so that paging in waits for paging out to be over.
+    paged_out: Arc<Mutex<bool>>,
+}
+
+impl MemoryPaging {
+    pub(crate) fn new(guest_memory: Arc<GuestMemoryMmap>) -> Self {
+        MemoryPaging {
+            guest_memory,
+            paged_out: Arc::default(),
+        }
+    }
+
+    /// Ask the host kernel to reclaim every page of guest memory now, with
+    /// `MADV_PAGEOUT` (Linux 5.4). Pages shared with other processes stay.
+    pub fn page_out(&self) -> io::Result<()> {
+        let mut paged_out = self.paged_out.lock().unwrap();
+        self.advise(libc::MADV_PAGEOUT)?;
+        *paged_out = true;
+        Ok(())
+    }
+
+    /// Read every page of guest memory back in, with `MADV_POPULATE_READ`
+    /// (Linux 5.14), if it was paged out. Returns whether it was.
+    ///
+    /// Pages the guest never wrote map the shared zero page and take no memory.
+    pub fn page_in(&self) -> io::Result<bool> {
+        let mut paged_out = self.paged_out.lock().unwrap();
+        if !*paged_out {
+            return Ok(false);
+        }
+        self.advise(libc::MADV_POPULATE_READ)?;
+        *paged_out = false;
+        Ok(true)
+    }
+
+    fn advise(&self, advice: libc::c_int) -> io::Result<()> {
+        for region in self.guest_memory.iter() {
+            // It's safe to unwrap because the guest address is valid.
+            let addr = self
+                .guest_memory
+                .get_host_address(region.start_addr())
+                .unwrap();
+            // SAFETY: the range is the mapping of the region, which the handle
+            // keeps alive; neither advice changes its content.
+            let ret =
+                unsafe { libc::madvise(addr as *mut libc::c_void, region.len() as usize, advice) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,797 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Point-in-time snapshots of a running VM, and VMs restored from them.
+//!
+//! A snapshot directory holds two files:
+//! - [`MEMORY_FILE`]: a raw dump of guest memory, region after region;
+//! - [`STATE_FILE`]: the vCPUs (registers, extended state, LAPIC, MSRs, pending
+//!   events), the in-kernel irqchip and PIT, the KVM clock, the serial port and
+//!   the virtio devices with their queues.
+//!
+//! The state is written field by field in little endian, never as the memory of
+//! the KVM structures: those have padding, which holds whatever was on the stack.
+//!
+//! vCPUs are parked while the snapshot is taken, and so is the event loop, which
+//! runs the device handlers: the queues saved are those of the memory saved.
+//! VMs with a memory device or room for more vCPUs are not snapshotted, a restore
+//! could not give them back the same devices.
+//!
+//! The KVM clock follows the host clock while vCPUs are parked, so the guest
+//! wakes up with the right time; each vCPU tells the guest it was stopped, so
+//! that its watchdogs do not count the pause as a lockup. A restored VM goes on
+//! with the clock of its snapshot instead, which keeps its monotonic time free of
+//! jumps: the caller sets the wall clock of the guest once it runs.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use event_manager::RemoteEndpoint;
+use kvm_bindings::{
+    kvm_clock_data, kvm_dtable, kvm_irqchip, kvm_lapic_state, kvm_mp_state, kvm_msr_entry,
+    kvm_pit_channel_state, kvm_pit_state2, kvm_regs, kvm_segment, kvm_sregs, kvm_vcpu_events,
+    kvm_xcr, kvm_xcrs, kvm_xsave, Msrs, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER,
+    KVM_IRQCHIP_PIC_SLAVE,
+};
+use kvm_ioctls::VmFd;
+use vm_memory::{Bytes, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion};
+use vmm_sys_util::ioctl::ioctl;
+use vmm_sys_util::ioctl_io_nr;
+
+use crate::cpu::{self, msrs, Vcpu};
+use crate::devices::serial::{LumperSerial, SerialState};
+use crate::devices::virtio::block::device::VirtioBlockDevice;
+use crate::devices::virtio::mmio::Subscriber;
+use crate::devices::virtio::net::device::VirtioNetDevice;
+use crate::devices::virtio::rng::device::VirtioRngDevice;
+use crate::devices::virtio::state::{QueueState, VirtioSnapshot, VirtioState};
+
+/// Name of the guest memory dump inside a snapshot directory.
+pub const MEMORY_FILE: &str = "memory";
+/// Name of the CPU, interrupt controller and device state inside a snapshot directory.
+pub const STATE_FILE: &str = "state";
+
+/// Identifies the layout of [`STATE_FILE`]; bump it when the layout changes.
+const STATE_MAGIC: &[u8; 8] = b"CLDSNAP3";
+
+/// How often parked vCPUs are waited for before kicking them again.
+const PAUSE_KICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Largest count of entries of a list in [`STATE_FILE`]: more is a corrupted file,
+/// not something to allocate for.
+const MAX_LIST_LEN: u32 = 4096;
+
+// Tells the guest its vCPU was stopped by the host, on vCPU file descriptors.
+const KVMIO: u32 = 0xAE;
+ioctl_io_nr!(KVM_KVMCLOCK_CTRL, KVMIO, 0xad);
+
+/// Snapshot errors.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to read or write the snapshot files.
+    IO(io::Error),
+    /// Error issuing an ioctl to KVM.
+    KvmIoctl(kvm_ioctls::Error),
+    /// Failed to read guest memory.
+    Memory(GuestMemoryError),
+    /// Failed to build the list of MSRs to save or restore.
+    Msrs(msrs::Error),
+    /// KVM took fewer of the MSRs of the snapshot than it holds.
+    MsrsNotRestored,
+    /// Failed to read the TSC frequency of a vCPU.
+    Vcpu(cpu::Error),
+    /// Failed to hold the event loop.
+    EventManager(event_manager::Error),
+    /// The VM is not running (not started yet, or already stopped).
+    NotRunning,
+    /// The VM has something a snapshot does not keep, e.g. a memory device.
+    Unsupported(&'static str),
+    /// [`STATE_FILE`] is not a state of this version, or is corrupted.
+    InvalidState,
+    /// The memory dump does not have the size the state says.
+    MemorySize { expected: u64, actual: u64 },
+}
+
+/// Dedicated Result type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A value of [`STATE_FILE`], written and read field by field in little endian.
+pub(crate) trait Field: Sized {
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()>;
+    fn read_from<R: Read>(input: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! int_fields {
+    ($($int:ty),*) => {
+        $(
+            impl Field for $int {
+                fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+                    out.write_all(&self.to_le_bytes())
+                }
+
+                fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+                    let mut bytes = [0; std::mem::size_of::<$int>()];
+                    input.read_exact(&mut bytes)?;
+                    Ok(<$int>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+int_fields!(u8, i8, u16, u32, u64, i64);
+
+/// Implements [`Field`] for a structure with the fields listed, nested ones with their
+/// path. Fields left out, like padding, are left to their default when read.
+macro_rules! struct_fields {
+    ($type:ty { $($($field:ident).+),* $(,)? }) => {
+        impl Field for $type {
+            fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+                $(self.$($field).+.write_to(out)?;)*
+                Ok(())
+            }
+
+            fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+                let mut value = <$type>::default();
+                $(value.$($field).+ = Field::read_from(input)?;)*
+                Ok(value)
+            }
+        }
+    };
+}
+
+impl Field for bool {
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        u8::from(*self).write_to(out)
+    }
+
+    fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        match u8::read_from(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data()),
+        }
+    }
+}
+
+impl<T: Field + Copy + Default, const N: usize> Field for [T; N] {
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.iter().try_for_each(|value| value.write_to(out))
+    }
+
+    fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut values = [T::default(); N];
+        for value in values.iter_mut() {
+            *value = T::read_from(input)?;
+        }
+        Ok(values)
+    }
+}
+
+impl<T: Field> Field for Vec<T> {
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        (self.len() as u32).write_to(out)?;
+        self.iter().try_for_each(|value| value.write_to(out))
+    }
+
+    fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let len = u32::read_from(input)?;
+        if len > MAX_LIST_LEN {
+            return Err(invalid_data());
+        }
+        (0..len).map(|_| T::read_from(input)).collect()
+    }
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid snapshot state")
+}
+
+struct_fields!(kvm_regs {
+    rax,
+    rbx,
+    rcx,
+    rdx,
+    rsi,
+    rdi,
+    rsp,
+    rbp,
+    r8,
+    r9,
+    r10,
+    r11,
+    r12,
+    r13,
+    r14,
+    r15,
+    rip,
+    rflags,
+});
+struct_fields!(kvm_segment {
+    base,
+    limit,
+    selector,
+    type_,
+    present,
+    dpl,
+    db,
+    s,
+    l,
+    g,
+    avl,
+    unusable,
+});
+struct_fields!(kvm_dtable { base, limit });
+struct_fields!(kvm_sregs {
+    cs,
+    ds,
+    es,
+    fs,
+    gs,
+    ss,
+    tr,
+    ldt,
+    gdt,
+    idt,
+    cr0,
+    cr2,
+    cr3,
+    cr4,
+    cr8,
+    efer,
+    apic_base,
+    interrupt_bitmap,
+});
+struct_fields!(kvm_xsave { region });
+struct_fields!(kvm_xcr { xcr, value });
+struct_fields!(kvm_xcrs {
+    nr_xcrs,
+    flags,
+    xcrs
+});
+struct_fields!(kvm_lapic_state { regs });
+struct_fields!(kvm_mp_state { mp_state });
+struct_fields!(kvm_msr_entry { index, data });
+struct_fields!(kvm_vcpu_events {
+    exception.injected,
+    exception.nr,
+    exception.has_error_code,
+    exception.pending,
+    exception.error_code,
+    interrupt.injected,
+    interrupt.nr,
+    interrupt.soft,
+    interrupt.shadow,
+    nmi.injected,
+    nmi.pending,
+    nmi.masked,
+    sipi_vector,
+    flags,
+    smi.smm,
+    smi.pending,
+    smi.smm_inside_nmi,
+    smi.latched_init,
+    exception_has_payload,
+    exception_payload,
+});
+struct_fields!(kvm_pit_channel_state {
+    count,
+    latched_count,
+    count_latched,
+    status_latched,
+    status,
+    read_state,
+    write_state,
+    write_latch,
+    rw_mode,
+    mode,
+    bcd,
+    gate,
+    count_load_time,
+});
+struct_fields!(kvm_pit_state2 { channels, flags });
+struct_fields!(kvm_clock_data { clock });
+struct_fields!(SerialState { ier, lcr, mcr, scr });
+struct_fields!(QueueState {
+    size,
+    ready,
+    desc_table,
+    avail_ring,
+    used_ring,
+    next_avail,
+    next_used,
+});
+struct_fields!(VirtioState {
+    device_type,
+    mmio_start,
+    irq,
+    driver_features,
+    device_status,
+    config_generation,
+    interrupt_status,
+    activated,
+    queues,
+});
+
+impl Field for kvm_irqchip {
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.chip_id.write_to(out)?;
+        // SAFETY: the union is plain bytes, all of them set by KVM_GET_IRQCHIP over a
+        // zeroed structure.
+        unsafe { self.chip.dummy }.write_to(out)
+    }
+
+    fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut irqchip = kvm_irqchip {
+            chip_id: Field::read_from(input)?,
+            ..Default::default()
+        };
+        irqchip.chip.dummy = Field::read_from(input)?;
+        Ok(irqchip)
+    }
+}
+
+/// Architectural state of a single vCPU.
+#[derive(Default)]
+pub(crate) struct VcpuState {
+    index: u64,
+    tsc_khz: u32,
+    mp_state: kvm_mp_state,
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+    xsave: kvm_xsave,
+    xcrs: kvm_xcrs,
+    lapic: kvm_lapic_state,
+    msrs: Vec<kvm_msr_entry>,
+    events: kvm_vcpu_events,
+}
+
+struct_fields!(VcpuState {
+    index,
+    tsc_khz,
+    mp_state,
+    regs,
+    sregs,
+    xsave,
+    xcrs,
+    lapic,
+    msrs,
+    events,
+});
+
+impl VcpuState {
+    fn capture(vcpu: &Vcpu) -> Result<Self> {
+        let vcpu_fd = &vcpu.vcpu_fd;
+        let mut msrs = msrs::create_snapshot_msr_entries().map_err(Error::Msrs)?;
+        let read = vcpu_fd.get_msrs(&mut msrs).map_err(Error::KvmIoctl)?;
+
+        Ok(VcpuState {
+            index: vcpu.index,
+            tsc_khz: vcpu.tsc_khz().map_err(Error::Vcpu)?,
+            mp_state: vcpu_fd.get_mp_state().map_err(Error::KvmIoctl)?,
+            regs: vcpu_fd.get_regs().map_err(Error::KvmIoctl)?,
+            sregs: vcpu_fd.get_sregs().map_err(Error::KvmIoctl)?,
+            xsave: vcpu_fd.get_xsave().map_err(Error::KvmIoctl)?,
+            xcrs: vcpu_fd.get_xcrs().map_err(Error::KvmIoctl)?,
+            lapic: vcpu_fd.get_lapic().map_err(Error::KvmIoctl)?,
+            msrs: msrs.as_slice()[..read].to_vec(),
+            events: vcpu_fd.get_vcpu_events().map_err(Error::KvmIoctl)?,
+        })
+    }
+
+    pub fn tsc_khz(&self) -> u32 {
+        self.tsc_khz
+    }
+
+    /// Sets the state of `vcpu`, created with the CPUID and TSC frequency of this one.
+    pub fn apply(&self, vcpu: &Vcpu) -> Result<()> {
+        let vcpu_fd = &vcpu.vcpu_fd;
+        vcpu_fd
+            .set_mp_state(self.mp_state)
+            .map_err(Error::KvmIoctl)?;
+        vcpu_fd.set_regs(&self.regs).map_err(Error::KvmIoctl)?;
+        vcpu_fd.set_sregs(&self.sregs).map_err(Error::KvmIoctl)?;
+        vcpu_fd.set_xsave(&self.xsave).map_err(Error::KvmIoctl)?;
+        vcpu_fd.set_xcrs(&self.xcrs).map_err(Error::KvmIoctl)?;
+        // After the APIC base of `sregs`, before the TSC deadline among the MSRs.
+        vcpu_fd.set_lapic(&self.lapic).map_err(Error::KvmIoctl)?;
+
+        let msrs =
+            Msrs::from_entries(&self.msrs).map_err(|_| Error::Msrs(msrs::Error::CreateMsrs))?;
+        let written = vcpu_fd.set_msrs(&msrs).map_err(Error::KvmIoctl)?;
+        if written != self.msrs.len() {
+            return Err(Error::MsrsNotRestored);
+        }
+
+        // Last: an interrupt the vCPU was being given when it parked is given again.
+        vcpu_fd
+            .set_vcpu_events(&self.events)
+            .map_err(Error::KvmIoctl)
+    }
+}
+
+/// Everything [`STATE_FILE`] holds.
+#[derive(Default)]
+pub(crate) struct VmState {
+    pub memory_size: u64,
+    pub vcpus: Vec<VcpuState>,
+    /// PIC master, PIC slave and IOAPIC, in this order.
+    pub irqchips: Vec<kvm_irqchip>,
+    pub pit: kvm_pit_state2,
+    pub clock: kvm_clock_data,
+    pub serial: SerialState,
+    /// Network device, block devices and entropy device, in this order, as there are.
+    pub devices: Vec<VirtioState>,
+}
+
+struct_fields!(VmState {
+    memory_size,
+    vcpus,
+    irqchips,
+    pit,
+    clock,
+    serial,
+    devices,
+});
+
+impl VmState {
+    /// Reads the state of the snapshot in `dir`, and checks its memory dump has the
+    /// size it says.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut input = BufReader::new(File::open(dir.join(STATE_FILE)).map_err(Error::IO)?);
+        let mut magic = [0; STATE_MAGIC.len()];
+        input.read_exact(&mut magic).map_err(Error::IO)?;
+        if &magic != STATE_MAGIC {
+            return Err(Error::InvalidState);
+        }
+        let state = VmState::read_from(&mut input).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Error::InvalidState,
+            _ => Error::IO(e),
+        })?;
+        if state.vcpus.is_empty() || state.irqchips.len() != IRQCHIP_IDS.len() {
+            return Err(Error::InvalidState);
+        }
+
+        let actual = std::fs::metadata(dir.join(MEMORY_FILE))
+            .map_err(Error::IO)?
+            .len();
+        if actual != state.memory_size {
+            return Err(Error::MemorySize {
+                expected: state.memory_size,
+                actual,
+            });
+        }
+        Ok(state)
+    }
+
+    /// Sets the irqchip, PIT and clock of a VM with no vCPU running yet.
+    pub fn apply_to_vm(&self, vm_fd: &VmFd) -> Result<()> {
+        for irqchip in &self.irqchips {
+            vm_fd.set_irqchip(irqchip).map_err(Error::KvmIoctl)?;
+        }
+        vm_fd.set_pit2(&self.pit).map_err(Error::KvmIoctl)?;
+        vm_fd.set_clock(&self.clock).map_err(Error::KvmIoctl)
+    }
+}
+
+const IRQCHIP_IDS: [u32; 3] = [
+    KVM_IRQCHIP_PIC_MASTER,
+    KVM_IRQCHIP_PIC_SLAVE,
+    KVM_IRQCHIP_IOAPIC,
+];
+
+/// Rendezvous point between the vCPU threads and a snapshot in progress.
+#[derive(Default)]
+pub(crate) struct VcpuPause {
+    requested: AtomicBool,
+    parked: Mutex<Vec<Result<VcpuState>>>,
+    resumed: Condvar,
+}
+
+impl VcpuPause {
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Called from a vCPU thread once it is out of `KVM_RUN`: records the vCPU
+    /// state and blocks until the snapshot is done or the VM stops.
+    pub fn park(&self, vcpu: &Vcpu, running: &AtomicBool) {
+        let state = VcpuState::capture(vcpu);
+
+        let mut parked = self.parked.lock().unwrap();
+        parked.push(state);
+        self.resumed.notify_all();
+
+        while self.is_requested() && running.load(Ordering::SeqCst) {
+            parked = self
+                .resumed
+                .wait_timeout(parked, PAUSE_KICK_INTERVAL)
+                .unwrap()
+                .0;
+        }
+        drop(parked);
+
+        // Sets the flag the guest checks before reporting a soft lockup. Fails
+        // when the guest does not use kvm-clock, which then has nothing to skip.
+        // SAFETY: KVM_KVMCLOCK_CTRL takes no argument.
+        unsafe { ioctl(&vcpu.vcpu_fd, KVM_KVMCLOCK_CTRL()) };
+    }
+
+    fn resume(&self) {
+        self.requested.store(false, Ordering::SeqCst);
+        self.parked.lock().unwrap().clear();
+        self.resumed.notify_all();
+    }
+}
+
+/// Rendezvous point between the event loop and a snapshot in progress: the loop runs
+/// [`LoopPause::park`] and blocks there, with no device handler running.
+#[derive(Default)]
+pub(crate) struct LoopPause {
+    /// Whether the loop should stay parked, and whether it is.
+    state: Mutex<(bool, bool)>,
+    changed: Condvar,
+}
+
+impl LoopPause {
+    fn park(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.1 = true;
+        self.changed.notify_all();
+        while state.0 {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.1 = false;
+    }
+
+    fn resume(&self) {
+        self.state.lock().unwrap().0 = false;
+        self.changed.notify_all();
+    }
+}
+
+/// Takes snapshots of a running VM from any thread.
+/// Obtained with [`crate::VMM::snapshot_handle`].
+#[derive(Clone)]
+pub struct SnapshotHandle {
+    pub(crate) vm_fd: Arc<VmFd>,
+    pub(crate) guest_memory: Arc<GuestMemoryMmap>,
+    pub(crate) pause: Arc<VcpuPause>,
+    pub(crate) loop_pause: Arc<LoopPause>,
+    pub(crate) endpoint: Arc<Mutex<RemoteEndpoint<Subscriber>>>,
+    pub(crate) running: Arc<AtomicBool>,
+    pub(crate) vcpu_thread_ids: Arc<Mutex<Vec<libc::pthread_t>>>,
+    pub(crate) serial: Arc<Mutex<LumperSerial>>,
+    pub(crate) virtio_net: Option<Arc<Mutex<VirtioNetDevice>>>,
+    pub(crate) virtio_blocks: Vec<Arc<Mutex<VirtioBlockDevice>>>,
+    pub(crate) virtio_rng: Option<Arc<Mutex<VirtioRngDevice>>>,
+    /// What the VM has that a snapshot does not keep, if anything.
+    pub(crate) unsupported: Option<&'static str>,
+}
+
+impl SnapshotHandle {
+    /// Pauses every vCPU and the device handlers, writes the snapshot files into
+    /// `dir` and resumes the VM.
+    pub fn snapshot(&self, dir: &Path) -> Result<()> {
+        if let Some(what) = self.unsupported {
+            return Err(Error::Unsupported(what));
+        }
+        let vcpu_count = self.vcpu_thread_ids.lock().unwrap().len();
+        if vcpu_count == 0 || !self.running.load(Ordering::SeqCst) {
+            return Err(Error::NotRunning);
+        }
+
+        self.pause.requested.store(true, Ordering::SeqCst);
+        let result = self.wait_for_parked_vcpus(vcpu_count).and_then(|vcpus| {
+            let result = self
+                .wait_for_parked_loop()
+                .and_then(|()| self.write_snapshot(dir, vcpus));
+            self.loop_pause.resume();
+            result
+        });
+        self.pause.resume();
+
+        result
+    }
+
+    /// Kicks vCPUs out of `KVM_RUN` until all of them have parked, then
+    /// returns their states ordered by index.
+    fn wait_for_parked_vcpus(&self, vcpu_count: usize) -> Result<Vec<VcpuState>> {
+        let mut parked = self.pause.parked.lock().unwrap();
+
+        while parked.len() < vcpu_count {
+            if !self.running.load(Ordering::SeqCst) {
+                return Err(Error::NotRunning);
+            }
+
+            // A signal sent right before a vCPU enters KVM_RUN is lost, so keep
+            // kicking until every thread has reported in.
+            for &tid in self.vcpu_thread_ids.lock().unwrap().iter() {
+                unsafe {
+                    libc::pthread_kill(tid, libc::SIGUSR1);
+                }
+            }
+
+            parked = self
+                .pause
+                .resumed
+                .wait_timeout(parked, PAUSE_KICK_INTERVAL)
+                .unwrap()
+                .0;
+        }
+
+        let mut vcpus = parked.drain(..).collect::<Result<Vec<_>>>()?;
+        vcpus.sort_by_key(|vcpu| vcpu.index);
+        Ok(vcpus)
+    }
+
+    /// Has the event loop park, once done with the handler it may be running.
+    fn wait_for_parked_loop(&self) -> Result<()> {
+        self.loop_pause.state.lock().unwrap().0 = true;
+        let loop_pause = Arc::clone(&self.loop_pause);
+        self.endpoint
+            .lock()
+            .unwrap()
+            .fire(move |_| loop_pause.park())
+            .map_err(Error::EventManager)?;
+
+        let mut state = self.loop_pause.state.lock().unwrap();
+        while !state.1 {
+            // A loop that stopped drops the closure instead of running it.
+            if !self.running.load(Ordering::SeqCst) {
+                return Err(Error::NotRunning);
+            }
+            state = self
+                .loop_pause
+                .changed
+                .wait_timeout(state, PAUSE_KICK_INTERVAL)
+                .unwrap()
+                .0;
+        }
+        Ok(())
+    }
+
+    fn device_states(&self) -> Vec<VirtioState> {
+        let mut devices = Vec::new();
+        if let Some(net) = &self.virtio_net {
+            devices.push(net.lock().unwrap().state());
+        }
+        for block in &self.virtio_blocks {
+            devices.push(block.lock().unwrap().state());
+        }
+        if let Some(rng) = &self.virtio_rng {
+            devices.push(rng.lock().unwrap().state());
+        }
+        devices
+    }
+
+    fn write_snapshot(&self, dir: &Path, vcpus: Vec<VcpuState>) -> Result<()> {
+        std::fs::create_dir_all(dir).map_err(Error::IO)?;
+
+        let mut memory = BufWriter::new(File::create(dir.join(MEMORY_FILE)).map_err(Error::IO)?);
+        let mut memory_size = 0u64;
+        for region in self.guest_memory.iter() {
+            self.guest_memory
+                .write_all_to(region.start_addr(), &mut memory, region.len() as usize)
+                .map_err(Error::Memory)?;
+            memory_size += region.len();
+        }
+        memory.flush().map_err(Error::IO)?;
+        memory.get_ref().sync_all().map_err(Error::IO)?;
+
+        let mut irqchips = Vec::new();
+        for chip_id in IRQCHIP_IDS {
+            let mut irqchip = kvm_irqchip {
+                chip_id,
+                ..Default::default()
+            };
+            self.vm_fd
+                .get_irqchip(&mut irqchip)
+                .map_err(Error::KvmIoctl)?;
+            irqchips.push(irqchip);
+        }
+
+        let vm = VmState {
+            memory_size,
+            vcpus,
+            irqchips,
+            pit: self.vm_fd.get_pit2().map_err(Error::KvmIoctl)?,
+            clock: self.vm_fd.get_clock().map_err(Error::KvmIoctl)?,
+            serial: self.serial.lock().unwrap().state(),
+            devices: self.device_states(),
+        };
+
+        let mut state = BufWriter::new(File::create(dir.join(STATE_FILE)).map_err(Error::IO)?);
+        state.write_all(STATE_MAGIC).map_err(Error::IO)?;
+        vm.write_to(&mut state).map_err(Error::IO)?;
+        state.flush().map_err(Error::IO)?;
+        state.get_ref().sync_all().map_err(Error::IO)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut vcpu = VcpuState {
+            index: 1,
+            tsc_khz: 2_500_000,
+            msrs: vec![kvm_msr_entry {
+                index: 0x10,
+                data: 42,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        vcpu.regs.rip = 0xffff_ffff_8100_0000;
+        vcpu.sregs.cs.selector = 0x10;
+        vcpu.lapic.regs[0x20] = -1;
+        vcpu.events.interrupt.injected = 1;
+        vcpu.events.interrupt.nr = 0xec;
+
+        let mut irqchip = kvm_irqchip {
+            chip_id: KVM_IRQCHIP_IOAPIC,
+            ..Default::default()
+        };
+        let mut chip = [0; 512];
+        chip[3] = 7;
+        irqchip.chip.dummy = chip;
+
+        let vm = VmState {
+            memory_size: 128 << 20,
+            vcpus: vec![vcpu],
+            irqchips: vec![irqchip],
+            serial: SerialState {
+                ier: 0x0f,
+                lcr: 0x03,
+                mcr: 0x0b,
+                scr: 0,
+            },
+            devices: vec![VirtioState {
+                device_type: 1,
+                mmio_start: 0xd000_0000,
+                irq: 5,
+                activated: true,
+                queues: vec![QueueState {
+                    size: 256,
+                    ready: true,
+                    next_avail: 300,
+                    next_used: 299,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let mut bytes = Vec::new();
+        vm.write_to(&mut bytes).unwrap();
+        let read = VmState::read_from(&mut bytes.as_slice()).unwrap();
+
+        let vcpu = &read.vcpus[0];
+        assert_eq!((vcpu.index, vcpu.tsc_khz), (1, 2_500_000));
+        assert_eq!(vcpu.regs.rip, 0xffff_ffff_8100_0000);
+        assert_eq!(vcpu.sregs.cs.selector, 0x10);
+        assert_eq!(vcpu.lapic.regs[0x20], -1);
+        assert_eq!(
+            (vcpu.events.interrupt.injected, vcpu.events.interrupt.nr),
+            (1, 0xec)
+        );
+        assert_eq!((vcpu.msrs[0].index, vcpu.msrs[0].data), (0x10, 42));
+        assert_eq!(read.irqchips[0].chip_id, KVM_IRQCHIP_IOAPIC);
+        assert_eq!(unsafe { read.irqchips[0].chip.dummy[3] }, 7);
+        assert_eq!(read.serial, vm.serial);
+        assert_eq!(read.devices, vm.devices);
+
+        // A state cut short is not read as a shorter one.
+        bytes.truncate(bytes.len() - 1);
+        assert!(VmState::read_from(&mut bytes.as_slice()).is_err());
+    }
+}
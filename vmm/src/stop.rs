@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Stopping a VM from any thread.
+//!
+//! The event loop of `run()` blocks until a device or a remote endpoint has work
+//! for it, so clearing the `running` flag alone would go unnoticed. Stopping
+//! also writes to an eventfd the loop watches, which wakes it right away.
+//!
+//! A vCPU that cannot go on stops the VM the same way, and leaves the reason
+//! behind for whoever ran it.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Stops a VM from any thread, and tells whether it still runs.
+/// Obtained with [`crate::VMM::stop_handle`].
+#[derive(Clone)]
+pub struct StopHandle {
+    pub(crate) running: Arc<AtomicBool>,
+    exit_evt: Arc<EventFd>,
+    /// Why a vCPU stopped the VM, see [`StopHandle::failure`].
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl StopHandle {
+    pub(crate) fn new() -> io::Result<Self> {
+        Ok(StopHandle {
+            running: Arc::new(AtomicBool::new(true)),
+            exit_evt: Arc::new(EventFd::new(libc::EFD_NONBLOCK)?),
+            failure: Arc::default(),
+        })
+    }
+
+    /// Ask the VM to stop: `run()` returns once the vCPUs are joined.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        // The loop checks the flag once woken, whatever the count.
+        if let Err(e) = self.exit_evt.write(1) {
+            eprintln!("Failed to wake the event loop: {:?}", e);
+        }
+    }
+
+    /// Stop the VM because of a fatal error. Only the first reason is kept, the
+    /// other vCPUs mostly fail as a consequence.
+    pub(crate) fn fail(&self, reason: String) {
+        self.failure.lock().unwrap().get_or_insert(reason);
+        self.stop();
+    }
+
+    /// Why the VM stopped, if a vCPU hit an error it could not recover from.
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+
+    /// Whether the VM runs, false once it stopped, whoever stopped it.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Subscriber waking the event loop when the VM is stopped.
+    pub(crate) fn exit_handler(&self) -> ExitHandler {
+        ExitHandler(Arc::clone(&self.exit_evt))
+    }
+}
+
+/// Drains the exit eventfd, so that the loop blocks again until the next stop.
+pub(crate) struct ExitHandler(Arc<EventFd>);
+
+impl MutEventSubscriber for ExitHandler {
+    fn process(&mut self, events: Events, _ops: &mut EventOps) {
+        if events.event_set() == EventSet::IN {
+            let _ = self.0.read();
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        if let Err(e) = ops.add(Events::new(self.0.as_ref(), EventSet::IN)) {
+            eprintln!("Unable to add the exit event: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_wakes_the_loop() {
+        let stop = StopHandle::new().unwrap();
+        let handler = stop.exit_handler();
+        assert!(stop.is_running());
+        assert!(handler.0.read().is_err(), "nothing to wake the loop yet");
+
+        stop.clone().stop();
+        assert!(!stop.is_running());
+        assert_eq!(handler.0.read().unwrap(), 1);
+        assert_eq!(stop.failure(), None);
+    }
+
+    #[test]
+    fn test_fail_keeps_the_first_reason() {
+        let stop = StopHandle::new().unwrap();
+        stop.fail("vCPU 1 failed".to_string());
+        stop.clone().fail("vCPU 0 failed".to_string());
+        assert!(!stop.is_running());
+        assert_eq!(stop.failure().as_deref(), Some("vCPU 1 failed"));
+    }
+}
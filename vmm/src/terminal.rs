@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Raw mode for a terminal attached to the guest serial port.
+//!
+//! Keys go to the guest as they are typed, without echo or line editing, and
+//! Ctrl-C reaches the guest instead of killing the VMM. The original settings
+//! are restored when the VMM is dropped, when the process exits, and when a
+//! thread panics, so the shell is usable again whatever way the VMM ends.
+
+use std::io;
+use std::os::fd::RawFd;
+use std::panic;
+use std::sync::{Mutex, Once};
+
+/// Terminal put in raw mode, with the settings to restore.
+static SAVED: Mutex<Option<(RawFd, libc::termios)>> = Mutex::new(None);
+static RESTORE_HOOKS: Once = Once::new();
+
+/// Keeps a terminal in raw mode while alive.
+pub(crate) struct RawTerminal(());
+
+impl RawTerminal {
+    /// Puts the terminal `fd` in raw mode, `None` when `fd` is not a terminal.
+    pub fn enable(fd: RawFd) -> io::Result<Option<Self>> {
+        // SAFETY: isatty only inspects the descriptor.
+        if unsafe { libc::isatty(fd) } != 1 {
+            return Ok(None);
+        }
+
+        let original = get_attr(fd)?;
+        let mut raw = original;
+        // Output processing stays on so that host logs still start on a new line.
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+
+        install_restore_hooks();
+        *SAVED.lock().unwrap() = Some((fd, original));
+        if let Err(e) = set_attr(fd, &raw) {
+            SAVED.lock().unwrap().take();
+            return Err(e);
+        }
+        Ok(Some(RawTerminal(())))
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Puts back the settings of the terminal in raw mode, if any.
+fn restore() {
+    // A panic while holding the lock must not keep the terminal raw.
+    let saved = match SAVED.lock() {
+        Ok(mut saved) => saved.take(),
+        Err(poisoned) => poisoned.into_inner().take(),
+    };
+    if let Some((fd, original)) = saved {
+        let _ = set_attr(fd, &original);
+    }
+}
+
+extern "C" fn restore_at_exit() {
+    restore();
+}
+
+/// Restores the terminal on `exit()` and before a panic message is printed,
+/// where no destructor runs or runs too late.
+fn install_restore_hooks() {
+    RESTORE_HOOKS.call_once(|| {
+        // SAFETY: `restore_at_exit` is a plain function that never unwinds.
+        unsafe { libc::atexit(restore_at_exit) };
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore();
+            previous(info);
+        }));
+    });
+}
+
+fn get_attr(fd: RawFd) -> io::Result<libc::termios> {
+    // SAFETY: termios is plain data, filled by tcgetattr before it is read.
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(termios)
+    }
+}
+
+fn set_attr(fd: RawFd, termios: &libc::termios) -> io::Result<()> {
+    // SAFETY: tcsetattr only reads `termios`.
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    /// Opens a pseudo-terminal and returns its master and slave sides.
+    fn open_pty() -> (File, File) {
+        // SAFETY: the descriptors returned are checked and owned by the files.
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(master >= 0);
+            assert_eq!(libc::grantpt(master), 0);
+            assert_eq!(libc::unlockpt(master), 0);
+            let name = libc::ptsname(master);
+            assert!(!name.is_null());
+            let slave = libc::open(name, libc::O_RDWR | libc::O_NOCTTY);
+            assert!(slave >= 0);
+            (File::from_raw_fd(master), File::from_raw_fd(slave))
+        }
+    }
+
+    #[test]
+    fn test_raw_terminal() {
+        let (_master, slave) = open_pty();
+        let fd = slave.as_raw_fd();
+        let cooked = get_attr(fd).unwrap();
+        assert_ne!(cooked.c_lflag & libc::ICANON, 0);
+
+        let terminal = RawTerminal::enable(fd).unwrap().unwrap();
+        let raw = get_attr(fd).unwrap();
+        assert_eq!(raw.c_lflag & (libc::ICANON | libc::ECHO | libc::ISIG), 0);
+        assert_eq!(raw.c_oflag, cooked.c_oflag);
+
+        drop(terminal);
+        assert_eq!(get_attr(fd).unwrap().c_lflag, cooked.c_lflag);
+
+        let null = File::open("/dev/null").unwrap();
+        assert!(RawTerminal::enable(null.as_raw_fd()).unwrap().is_none());
+    }
+}